@@ -1,6 +1,6 @@
 extern crate clash_lib as clash;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use clash::TokioRuntime;
 use std::{
     path::{Path, PathBuf},
@@ -31,10 +31,85 @@ struct Cli {
         help = "Test configuration and exit"
     )]
     test_config: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a rule-set between the yaml, text and binary formats
+    ConvertRuleset {
+        #[clap(value_parser, value_name = "INPUT")]
+        input: PathBuf,
+        #[clap(value_parser, value_name = "OUTPUT")]
+        output: PathBuf,
+        #[clap(
+            long,
+            value_name = "FORMAT",
+            help = "Input format: yaml, text or binary, detected if omitted"
+        )]
+        from: Option<String>,
+        #[clap(long, value_name = "FORMAT", help = "Output format: yaml, text or binary")]
+        to: String,
+        #[clap(
+            short,
+            long,
+            value_name = "BEHAVIOR",
+            help = "Rule-set behavior: domain, ipcidr or classical, optional for binary input"
+        )]
+        behavior: Option<String>,
+    },
+    /// Convert between the share links and the proxies of the config
+    Link {
+        #[clap(subcommand)]
+        command: LinkCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum LinkCommand {
+    /// Print the proxy of a ss, vmess, vless, trojan or hysteria2 link
+    Import {
+        #[clap(value_name = "URL")]
+        url: String,
+    },
+    /// Print the share link of a proxy of the configuration file
+    Export {
+        #[clap(value_name = "PROXY")]
+        name: String,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
+
+    if let Some(Command::ConvertRuleset {
+        input,
+        output,
+        from,
+        to,
+        behavior,
+    }) = cli.command
+    {
+        match clash::convert_ruleset(
+            &input,
+            &output,
+            from.as_deref(),
+            &to,
+            behavior.as_deref(),
+        ) {
+            Ok(n) => {
+                println!("converted {} rules to {}", n, output.display());
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("failed to convert rule-set {}: {}", input.display(), e);
+                exit(1);
+            }
+        }
+    }
+
     let file = cli
         .directory
         .as_ref()
@@ -43,6 +118,26 @@ fn main() {
         .to_string_lossy()
         .to_string();
 
+    if let Some(Command::Link { command }) = cli.command {
+        let result = match &command {
+            LinkCommand::Import { url } => clash::import_link(url),
+            LinkCommand::Export { name } => clash::export_link(Path::new(&file), name),
+        };
+        match result {
+            Ok(x) => {
+                println!("{}", x.trim_end());
+                exit(0);
+            }
+            Err(e) => {
+                match command {
+                    LinkCommand::Import { url } => eprintln!("failed to import {}: {}", url, e),
+                    LinkCommand::Export { name } => eprintln!("failed to export {}: {}", name, e),
+                }
+                exit(1);
+            }
+        }
+    }
+
     if !Path::new(&file).exists() {
         // TODO: offer a internal default config, to compatible with clash behavior
         panic!("config file not found: {}", file);
@@ -64,6 +159,7 @@ fn main() {
         cwd: cli.directory.map(|x| x.to_string_lossy().to_string()),
         rt: Some(TokioRuntime::MultiThread),
         log_file: None,
+        protect_socket: None,
     }) {
         Ok(_) => {}
         Err(_) => {
@@ -0,0 +1,67 @@
+//! `cargo bench -p clash_lib --features bench --bench cidr_trie`
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use clash_lib::CidrTrie;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ipnet::{IpNet, Ipv4Net};
+
+/// deterministic pseudo random numbers, xorshift
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// About the size and shape of a country CIDR list
+fn nets(n: usize, rng: &mut Rng) -> Vec<IpNet> {
+    (0..n)
+        .map(|_| {
+            let len = 12 + (rng.next() % 13) as u8;
+            Ipv4Net::new(Ipv4Addr::from(rng.next()), len)
+                .unwrap()
+                .trunc()
+                .into()
+        })
+        .collect()
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ip-cidr lookup");
+    let mut rng = Rng(0x2545_f491);
+    let ips: Vec<IpAddr> = (0..1024)
+        .map(|_| Ipv4Addr::from(rng.next()).into())
+        .collect();
+
+    for n in [100, 1_000, 10_000] {
+        let nets = nets(n, &mut rng);
+        let mut trie = CidrTrie::new();
+        for (i, net) in nets.iter().enumerate() {
+            trie.insert(*net, i);
+        }
+
+        group.bench_with_input(BenchmarkId::new("linear", n), &nets, |b, nets| {
+            b.iter(|| {
+                for ip in &ips {
+                    black_box(nets.iter().position(|x| x.contains(ip)));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("trie", n), &trie, |b, trie| {
+            b.iter(|| {
+                for ip in &ips {
+                    black_box(trie.matches(*ip).min());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookup);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{get, put},
+    Json, Router,
+};
+use http::StatusCode;
+
+use crate::{
+    app::{api::AppState, outbound::manager::ThreadSafeOutboundManager},
+    config::def::ChaosFault,
+};
+
+pub fn routes(outbound_manager: ThreadSafeOutboundManager) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_faults))
+        .route("/:name", put(set_fault).delete(clear_fault))
+        .with_state(outbound_manager)
+}
+
+fn disabled() -> axum::response::Response {
+    (StatusCode::NOT_FOUND, "chaos mode is not enabled").into_response()
+}
+
+async fn get_faults(
+    State(outbound_manager): State<ThreadSafeOutboundManager>,
+) -> impl IntoResponse {
+    match outbound_manager.chaos() {
+        Some(chaos) => Json(chaos.faults()).into_response(),
+        None => disabled(),
+    }
+}
+
+async fn set_fault(
+    State(outbound_manager): State<ThreadSafeOutboundManager>,
+    Path(name): Path<String>,
+    Json(fault): Json<ChaosFault>,
+) -> impl IntoResponse {
+    let chaos = match outbound_manager.chaos() {
+        Some(chaos) => chaos,
+        None => return disabled(),
+    };
+    if outbound_manager.get_outbound(&name).is_none() {
+        return (StatusCode::NOT_FOUND, format!("proxy {} not found", name)).into_response();
+    }
+    if fault.drop > 100 {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("drop is a percentage, got {}", fault.drop),
+        )
+            .into_response();
+    }
+
+    chaos.set(&name, fault);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn clear_fault(
+    State(outbound_manager): State<ThreadSafeOutboundManager>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match outbound_manager.chaos() {
+        Some(chaos) if chaos.clear(&name) => StatusCode::NO_CONTENT.into_response(),
+        Some(_) => (StatusCode::NOT_FOUND, format!("no fault set for {}", name)).into_response(),
+        None => disabled(),
+    }
+}
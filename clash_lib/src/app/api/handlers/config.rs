@@ -1,4 +1,4 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
 
 use axum::{
     extract::{Query, State},
@@ -7,7 +7,7 @@ use axum::{
     Json, Router,
 };
 
-use http::StatusCode;
+use http::{header, StatusCode};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::warn;
@@ -19,7 +19,7 @@ use crate::{
         dns::ThreadSafeDNSResolver,
         inbound::manager::{Ports, ThreadSafeInboundManager},
     },
-    config::{def, internal::config::BindAddress},
+    config::{def, internal::config::BindAddress, redact},
     GlobalState,
 };
 
@@ -42,6 +42,7 @@ pub fn routes(
             "/",
             get(get_configs).put(update_configs).patch(patch_configs),
         )
+        .route("/export", get(export_config))
         .with_state(ConfigState {
             inbound_manager,
             dispatcher,
@@ -58,27 +59,96 @@ async fn get_configs(State(state): State<ConfigState>) -> impl IntoResponse {
 
     let ports = inbound_manager.get_ports();
 
-    axum::response::Json(PatchConfigRequest {
-        port: ports.port,
-        socks_port: ports.socks_port,
-        redir_port: ports.redir_port,
-        tproxy_port: ports.tproxy_port,
-        mixed_port: ports.mixed_port,
-        bind_address: Some(inbound_manager.get_bind_address().to_string()),
-
-        mode: Some(run_mode),
-        log_level: Some(global_state.log_level),
-        ipv6: Some(dns_resolver.ipv6()),
-        allow_lan: Some(match inbound_manager.get_bind_address() {
-            BindAddress::Any => true,
-            BindAddress::One(one) => match one {
-                crate::proxy::utils::Interface::IpAddr(ip) => !ip.is_loopback(),
-                crate::proxy::utils::Interface::Name(iface) => iface != "lo",
-            },
-        }),
+    axum::response::Json(ConfigResponse {
+        tun: TunStatus {
+            enable: global_state.tun_enable,
+            device: global_state.tun_device.clone(),
+        },
+        dns: DnsStatus {
+            enable: global_state.dns_enable,
+        },
+        config_home: global_state.cwd.clone(),
+        paths: global_state.paths.clone(),
+        general: PatchConfigRequest {
+            port: ports.port,
+            socks_port: ports.socks_port,
+            redir_port: ports.redir_port,
+            tproxy_port: ports.tproxy_port,
+            mixed_port: ports.mixed_port,
+            bind_address: Some(inbound_manager.get_bind_address().to_string()),
+
+            mode: Some(run_mode),
+            log_level: Some(global_state.log_level),
+            ipv6: Some(dns_resolver.ipv6()),
+            allow_lan: Some(match inbound_manager.get_bind_address() {
+                BindAddress::Any => true,
+                BindAddress::One(one) => match one {
+                    crate::proxy::utils::Interface::IpAddr(ip) => !ip.is_loopback(),
+                    crate::proxy::utils::Interface::Name(iface) => iface != "lo",
+                },
+            }),
+        },
     })
 }
 
+/// The effective runtime config, including the changes made by PATCH
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigResponse {
+    #[serde(flatten)]
+    general: PatchConfigRequest,
+    tun: TunStatus,
+    dns: DnsStatus,
+    /// the directory relative paths in the config resolve against
+    config_home: String,
+    paths: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct TunStatus {
+    enable: bool,
+    device: String,
+}
+
+#[derive(Serialize)]
+struct DnsStatus {
+    enable: bool,
+}
+
+/// Also read by the auth middleware, to let only the admins turn the
+/// masking off.
+#[derive(Deserialize)]
+pub(crate) struct ExportConfigQuery {
+    /// true by default
+    pub(crate) redact: Option<bool>,
+}
+
+/// The running config as YAML, its secrets masked unless `redact=false`.
+async fn export_config(
+    State(state): State<ConfigState>,
+    Query(q): Query<ExportConfigQuery>,
+) -> impl IntoResponse {
+    let Some(mut source) = state.global_state.lock().await.source.clone() else {
+        return (
+            StatusCode::NOT_FOUND,
+            "the running config wasn't loaded from yaml",
+        )
+            .into_response();
+    };
+    if q.redact.unwrap_or(true) {
+        redact::redact(&mut source);
+    }
+
+    match serde_yaml::to_string(&source) {
+        Ok(yaml) => ([(header::CONTENT_TYPE, "application/yaml")], yaml).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to export the config: {}", e),
+        )
+            .into_response(),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct UpdateConfigRequest {
@@ -215,12 +285,12 @@ async fn patch_configs(
 
         inbound_manager.rebuild_listeners(ports);
 
+        let r = inbound_manager.get_runner().unwrap();
+
         if let Some(h) = global_state.inbound_listener_handle.take() {
             h.abort()
         }
 
-        let r = inbound_manager.get_runner().unwrap();
-
         global_state.inbound_listener_handle = Some(tokio::spawn(r));
     }
 
@@ -1,36 +1,72 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
     body::Body,
     extract::{ws::Message, FromRequest, Path, Query, Request, State, WebSocketUpgrade},
     response::IntoResponse,
-    routing::{delete, get},
+    routing::{delete, get, post},
     Json, Router,
 };
-use http::HeaderMap;
-use serde::Deserialize;
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
-use crate::app::{
-    api::{handlers::utils::is_request_websocket, AppState},
-    dispatcher::StatisticsManager,
+use crate::{
+    app::{
+        api::{handlers::utils::is_request_websocket, AppState},
+        dispatcher::{ConnectionFilter, ConnectionSort, StatisticsManager},
+    },
+    session::Type,
 };
 
+const DEFAULT_CAPTURE_SECONDS: u64 = 10;
+const MAX_CAPTURE_SECONDS: u64 = 300;
+
 #[derive(Clone)]
 struct ConnectionState {
     statistics_manager: Arc<StatisticsManager>,
+    /// where the captures are written to
+    capture_dir: PathBuf,
 }
 
-pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+pub fn routes(statistics_manager: Arc<StatisticsManager>, cwd: &str) -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(get_connections).delete(close_all_connection))
+        .route("/clients", get(get_client_statistics))
         .route("/:id", delete(close_connection))
-        .with_state(ConnectionState { statistics_manager })
+        .route("/:id/capture", post(capture_connection))
+        .with_state(ConnectionState {
+            statistics_manager,
+            capture_dir: PathBuf::from(cwd).join("captures"),
+        })
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct GetConnectionsQuery {
     interval: Option<u64>,
+    host: Option<String>,
+    proxy: Option<String>,
+    inbound: Option<Type>,
+    /// seconds
+    min_duration: Option<u64>,
+    sort: Option<ConnectionSort>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl GetConnectionsQuery {
+    fn filter(&self) -> ConnectionFilter {
+        ConnectionFilter {
+            host: self.host.clone(),
+            proxy: self.proxy.clone(),
+            inbound: self.inbound,
+            min_duration: self.min_duration.map(Duration::from_secs),
+            sort: self.sort,
+            offset: self.offset.unwrap_or_default(),
+            limit: self.limit,
+        }
+    }
 }
 
 async fn get_connections(
@@ -41,7 +77,7 @@ async fn get_connections(
 ) -> impl IntoResponse {
     if !is_request_websocket(headers) {
         let mgr = state.statistics_manager.clone();
-        let snapshot = mgr.snapshot().await;
+        let snapshot = mgr.snapshot_filtered(&q.filter()).await;
         return Json(snapshot).into_response();
     }
 
@@ -58,11 +94,12 @@ async fn get_connections(
     })
     .on_upgrade(move |mut socket| async move {
         let interval = q.interval;
+        let filter = q.filter();
 
         let mgr = state.statistics_manager.clone();
 
         loop {
-            let snapshot = mgr.snapshot().await;
+            let snapshot = mgr.snapshot_filtered(&filter).await;
             let j = serde_json::to_vec(&snapshot).unwrap();
             let body = String::from_utf8(j).unwrap();
 
@@ -77,13 +114,81 @@ async fn get_connections(
     })
 }
 
+async fn get_client_statistics(State(state): State<ConnectionState>) -> impl IntoResponse {
+    let mgr = state.statistics_manager.clone();
+    Json(mgr.client_statistics().await)
+}
+
 async fn close_connection(
     State(state): State<ConnectionState>,
     Path(id): Path<uuid::Uuid>,
 ) -> impl IntoResponse {
     let mgr = state.statistics_manager;
-    mgr.close(id).await;
-    format!("connection {} closed", id).into_response()
+    if mgr.close(id).await {
+        format!("connection {} closed", id).into_response()
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("connection {} not found", id),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct CaptureQuery {
+    seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CaptureResponse {
+    path: String,
+}
+
+/// Write the plaintext the connection relays over the next seconds to a
+/// pcap file for debugging.
+async fn capture_connection(
+    State(state): State<ConnectionState>,
+    Path(id): Path<uuid::Uuid>,
+    Query(q): Query<CaptureQuery>,
+) -> impl IntoResponse {
+    let seconds = q.seconds.unwrap_or(DEFAULT_CAPTURE_SECONDS);
+    if seconds == 0 || seconds > MAX_CAPTURE_SECONDS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("seconds must be within 1 to {}", MAX_CAPTURE_SECONDS),
+        )
+            .into_response();
+    }
+
+    let path = state.capture_dir.join(format!(
+        "{}-{}.pcap",
+        id,
+        chrono::Utc::now().format("%Y%m%d%H%M%S")
+    ));
+    match state
+        .statistics_manager
+        .capture(id, path.clone(), Duration::from_secs(seconds))
+        .await
+    {
+        Ok(true) => Json(CaptureResponse {
+            path: path.to_string_lossy().into_owned(),
+        })
+        .into_response(),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            format!("connection {} not found", id),
+        )
+            .into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to start capture: {}", e),
+        )
+            .into_response(),
+    }
 }
 
 async fn close_all_connection(State(state): State<ConnectionState>) -> impl IntoResponse {
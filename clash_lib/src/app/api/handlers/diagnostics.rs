@@ -0,0 +1,145 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+use serde::Serialize;
+
+use crate::{
+    app::{
+        api::AppState, dns::ThreadSafeDNSResolver, outbound::manager::ThreadSafeOutboundManager,
+    },
+    proxy::{
+        transport::tls::{self, TlsHandshakeReport},
+        utils::new_tcp_stream,
+        OutboundType,
+    },
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how many groups deep a group is followed to the proxy it uses
+const MAX_GROUP_DEPTH: usize = 16;
+
+#[derive(Clone)]
+struct DiagnosticsState {
+    outbound_manager: ThreadSafeOutboundManager,
+    resolver: ThreadSafeDNSResolver,
+}
+
+pub fn routes(
+    outbound_manager: ThreadSafeOutboundManager,
+    resolver: ThreadSafeDNSResolver,
+) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/outbound/:name", get(diagnose_outbound))
+        .with_state(DiagnosticsState {
+            outbound_manager,
+            resolver,
+        })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboundReport {
+    name: String,
+    /// the members the groups use now, when `name` is a group
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    via: Vec<String>,
+    server: String,
+    port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<TlsHandshakeReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Do a live TLS handshake against the proxy server of the outbound,
+/// and report what was negotiated. A selector, url-test or fallback group
+/// is diagnosed through the member it uses now.
+async fn diagnose_outbound(
+    State(state): State<DiagnosticsState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let mut outbound = match state.outbound_manager.get_outbound(&name) {
+        Some(outbound) => outbound,
+        None => {
+            return (StatusCode::NOT_FOUND, format!("proxy {} not found", name)).into_response()
+        }
+    };
+
+    let mut via = vec![];
+    while let Some(member) = outbound.current_member().await {
+        if via.len() == MAX_GROUP_DEPTH {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("group {} nests too deep", name),
+            )
+                .into_response();
+        }
+        via.push(member.name().to_owned());
+        outbound = member;
+    }
+
+    let endpoint = match outbound.tls_endpoint() {
+        Some(endpoint) => endpoint,
+        None if matches!(
+            outbound.proto(),
+            OutboundType::LoadBalance | OutboundType::Relay
+        ) =>
+        {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "{} groups are not supported, {} has no single proxy",
+                    outbound.proto(),
+                    outbound.name()
+                ),
+            )
+                .into_response()
+        }
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("proxy {} doesn't use TLS", outbound.name()),
+            )
+                .into_response()
+        }
+    };
+
+    let probe = async {
+        let stream = new_tcp_stream(
+            state.resolver.clone(),
+            &endpoint.server,
+            endpoint.port,
+            endpoint.iface.as_ref(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await?;
+        tls::probe(stream, endpoint.tls.clone()).await
+    };
+
+    let result = match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+        Ok(result) => result.map_err(|x| x.to_string()),
+        Err(_) => Err("handshake timed out".to_owned()),
+    };
+
+    let (tls, error) = match result {
+        Ok(report) => (Some(report), None),
+        Err(e) => (None, Some(e)),
+    };
+
+    Json(OutboundReport {
+        name,
+        via,
+        server: endpoint.server,
+        port: endpoint.port,
+        tls,
+        error,
+    })
+    .into_response()
+}
@@ -1,23 +1,187 @@
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc};
 
-use axum::{response::IntoResponse, routing::get, Router};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use hickory_proto::{op, rr};
 use http::StatusCode;
+use serde::{Deserialize, Serialize};
 
-use crate::app::{api::AppState, dns::ThreadSafeDNSResolver};
+use crate::app::{
+    api::AppState,
+    dispatcher::Dispatcher,
+    dns::{self, ThreadSafeDNSResolver},
+};
 
 #[derive(Clone)]
 struct DNSState {
-    #[allow(dead_code)]
     resolver: ThreadSafeDNSResolver,
+    /// the domains its rules reject are answered NXDOMAIN, as by the DNS
+    /// server
+    dispatcher: Option<Arc<Dispatcher>>,
 }
 
-pub fn routes(resolver: ThreadSafeDNSResolver) -> Router<Arc<AppState>> {
-    let state = DNSState { resolver };
+pub fn routes(
+    resolver: ThreadSafeDNSResolver,
+    dispatcher: Arc<Dispatcher>,
+) -> Router<Arc<AppState>> {
+    let state = DNSState {
+        resolver,
+        dispatcher: Some(dispatcher),
+    };
     Router::new()
-        .route("/dns", get(query_dns))
+        .route("/query", get(query_dns))
         .with_state(state)
 }
 
-async fn query_dns() -> impl IntoResponse {
-    StatusCode::NOT_IMPLEMENTED
+#[derive(Deserialize)]
+struct DnsQueryRequest {
+    name: String,
+    #[serde(rename = "type")]
+    query_type: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DnsQueryResponse {
+    status: u16,
+    question: Vec<DnsQuestion>,
+    answer: Vec<DnsRecord>,
+    authority: Vec<DnsRecord>,
+    additional: Vec<DnsRecord>,
+    /// the nameserver that answered, `cache` for the cached answers and
+    /// `fakeip` for a fake IP
+    upstream: String,
+}
+
+#[derive(Serialize)]
+struct DnsQuestion {
+    name: String,
+    #[serde(rename = "type")]
+    query_type: u16,
+}
+
+#[derive(Serialize)]
+struct DnsRecord {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+impl From<&rr::Record> for DnsRecord {
+    fn from(r: &rr::Record) -> Self {
+        Self {
+            name: r.name().to_string(),
+            record_type: r.record_type().into(),
+            ttl: r.ttl(),
+            data: r.data().map(|x| x.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+async fn query_dns(
+    State(state): State<DNSState>,
+    Query(q): Query<DnsQueryRequest>,
+) -> impl IntoResponse {
+    let query_type = match q.query_type.as_deref() {
+        Some(t) => match rr::RecordType::from_str(&t.to_uppercase()) {
+            Ok(t) => t,
+            Err(_) => {
+                return (StatusCode::BAD_REQUEST, format!("invalid type: {}", t)).into_response()
+            }
+        },
+        None => rr::RecordType::A,
+    };
+    let name = match rr::Name::from_str_relaxed(&q.name)
+        .and_then(|x| x.append_domain(&rr::Name::root()))
+    {
+        Ok(name) => name,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("invalid name {}: {}", q.name, e),
+            )
+                .into_response()
+        }
+    };
+
+    let mut m = op::Message::new();
+    m.add_query(op::Query::query(name, query_type));
+    m.set_recursion_desired(true);
+
+    // answered as by the DNS server, fake IPs included
+    match dns::handle_query_upstream(&state.resolver, state.dispatcher.as_deref(), &m).await {
+        Ok((msg, upstream)) => Json(DnsQueryResponse {
+            status: msg.response_code().into(),
+            question: msg
+                .queries()
+                .iter()
+                .map(|x| DnsQuestion {
+                    name: x.name().to_string(),
+                    query_type: x.query_type().into(),
+                })
+                .collect(),
+            answer: msg.answers().iter().map(Into::into).collect(),
+            authority: msg.name_servers().iter().map(Into::into).collect(),
+            additional: msg.additionals().iter().map(Into::into).collect(),
+            upstream,
+        })
+        .into_response(),
+        Err(e) => (
+            StatusCode::BAD_GATEWAY,
+            format!("query {} failed: {}", q.name, e),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        extract::{Query, State},
+        response::IntoResponse,
+    };
+
+    use super::{query_dns, DNSState, DnsQueryRequest};
+    use crate::app::dns::MockClashResolver;
+
+    #[tokio::test]
+    async fn test_query_fake_ip() {
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_fake_ip_enabled().return_const(true);
+        resolver.expect_is_rewritten().return_const(false);
+        resolver
+            .expect_resolve_v4()
+            .returning(|_, _| Ok(Some("198.18.0.5".parse().unwrap())));
+        resolver.expect_is_fake_ip().return_const(true);
+        let state = DNSState {
+            resolver: Arc::new(resolver),
+            dispatcher: None,
+        };
+
+        let res = query_dns(
+            State(state),
+            Query(DnsQueryRequest {
+                name: "example.com".to_owned(),
+                query_type: None,
+            }),
+        )
+        .await
+        .into_response();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["Upstream"], "fakeip");
+        assert_eq!(body["Answer"][0]["data"], "198.18.0.5");
+        assert_eq!(body["Answer"][0]["name"], "example.com.");
+    }
 }
@@ -0,0 +1,39 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ws::Message, ConnectInfo, WebSocketUpgrade},
+    response::IntoResponse,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::app::events;
+
+pub async fn handle(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    ws.on_failed_upgrade(move |e| {
+        warn!("ws upgrade error: {} with {}", e, addr);
+    })
+    .on_upgrade(move |mut socket| async move {
+        let mut rx = events::subscribe();
+        loop {
+            let evt = match rx.recv().await {
+                Ok(evt) => evt,
+                // the ids tell the client how many were missed
+                Err(RecvError::Lagged(n)) => {
+                    warn!("events subscriber {} lagged by {}", addr, n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let res = serde_json::to_string(&evt).unwrap();
+
+            if let Err(e) = socket.send(Message::Text(res)).await {
+                warn!("ws send error: {}", e);
+                break;
+            }
+        }
+    })
+}
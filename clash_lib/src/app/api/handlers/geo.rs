@@ -0,0 +1,43 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use http::StatusCode;
+
+use crate::{app::api::AppState, common::mmdb::Mmdb};
+
+#[derive(Clone)]
+struct GeoState {
+    mmdb: Arc<Mmdb>,
+}
+
+pub fn routes(mmdb: Arc<Mmdb>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_geo))
+        .route("/update", post(update_geo))
+        .with_state(GeoState { mmdb })
+}
+
+/// The versions of the loaded geo databases
+async fn get_geo(State(state): State<GeoState>) -> impl IntoResponse {
+    let mut res = HashMap::new();
+    res.insert("mmdb", state.mmdb.info());
+    Json(res)
+}
+
+/// Download the geo databases again and swap them in, the connections
+/// are not interrupted.
+async fn update_geo(State(state): State<GeoState>) -> impl IntoResponse {
+    match state.mmdb.update().await {
+        Ok(info) => {
+            let mut res = HashMap::new();
+            res.insert("mmdb", info);
+            Json(res).into_response()
+        }
+        Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    }
+}
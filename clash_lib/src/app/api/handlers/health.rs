@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+use http::StatusCode;
+use tokio::sync::Mutex;
+
+use crate::{
+    app::{
+        api::AppState,
+        dns::ThreadSafeDNSResolver,
+        inbound::manager::ThreadSafeInboundManager,
+        selfcheck::{self, Report},
+    },
+    GlobalState,
+};
+
+#[derive(Clone)]
+struct HealthState {
+    global_state: Arc<Mutex<GlobalState>>,
+    inbound_manager: ThreadSafeInboundManager,
+    resolver: ThreadSafeDNSResolver,
+}
+
+/// The report of the startup self-check, it tells the listeners and the
+/// files of the config so it's only served with a token.
+pub fn report_routes(global_state: Arc<Mutex<GlobalState>>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_health))
+        .with_state(global_state)
+}
+
+/// The probes, served without a token unless `controller.health-auth` is
+/// set, for the container orchestration.
+pub fn routes(
+    global_state: Arc<Mutex<GlobalState>>,
+    inbound_manager: ThreadSafeInboundManager,
+    resolver: ThreadSafeDNSResolver,
+) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/ready", get(get_ready))
+        .route("/live", get(get_live))
+        .with_state(HealthState {
+            global_state,
+            inbound_manager,
+            resolver,
+        })
+}
+
+fn respond(report: Report) -> axum::response::Response {
+    if report.healthy {
+        (StatusCode::OK, Json(report)).into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(report)).into_response()
+    }
+}
+
+/// The report of the startup self-check, a 503 until it has passed.
+async fn get_health(State(global_state): State<Arc<Mutex<GlobalState>>>) -> impl IntoResponse {
+    match global_state.lock().await.self_check.clone() {
+        Some(report) => respond(report),
+        None => (StatusCode::SERVICE_UNAVAILABLE, "self-check pending").into_response(),
+    }
+}
+
+async fn get_ready(State(state): State<HealthState>) -> impl IntoResponse {
+    respond(selfcheck::ready(&state.inbound_manager, &state.global_state).await)
+}
+
+async fn get_live(State(state): State<HealthState>) -> impl IntoResponse {
+    respond(selfcheck::live(&state.resolver).await)
+}
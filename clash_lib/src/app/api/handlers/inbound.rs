@@ -0,0 +1,50 @@
+use std::{net::IpAddr, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use http::StatusCode;
+
+use crate::app::{api::AppState, inbound::manager::ThreadSafeInboundManager};
+
+pub fn routes(inbound_manager: ThreadSafeInboundManager) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/bans", get(get_bans).delete(clear_bans))
+        .route("/bans/:ip", delete(unban))
+        .with_state(inbound_manager)
+}
+
+fn disabled() -> axum::response::Response {
+    (StatusCode::NOT_FOUND, "inbound rate limit is not enabled").into_response()
+}
+
+async fn get_bans(State(inbound_manager): State<ThreadSafeInboundManager>) -> impl IntoResponse {
+    match inbound_manager.lock().await.rate_limiter() {
+        Some(limiter) => Json(limiter.bans()).into_response(),
+        None => disabled(),
+    }
+}
+
+async fn clear_bans(State(inbound_manager): State<ThreadSafeInboundManager>) -> impl IntoResponse {
+    match inbound_manager.lock().await.rate_limiter() {
+        Some(limiter) => {
+            limiter.clear();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => disabled(),
+    }
+}
+
+async fn unban(
+    State(inbound_manager): State<ThreadSafeInboundManager>,
+    Path(ip): Path<IpAddr>,
+) -> impl IntoResponse {
+    match inbound_manager.lock().await.rate_limiter() {
+        Some(limiter) if limiter.unban(ip) => StatusCode::NO_CONTENT.into_response(),
+        Some(_) => (StatusCode::NOT_FOUND, format!("{} is not banned", ip)).into_response(),
+        None => disabled(),
+    }
+}
@@ -1,11 +1,19 @@
+pub mod chaos;
 pub mod config;
 pub mod connection;
+pub mod diagnostics;
 pub mod dns;
+pub mod events;
+pub mod geo;
+pub mod health;
 pub mod hello;
+pub mod inbound;
 pub mod log;
 pub mod provider;
 pub mod proxy;
 pub mod rule;
+pub mod rule_provider;
+pub mod statistics;
 pub mod traffic;
 mod utils;
 pub mod version;
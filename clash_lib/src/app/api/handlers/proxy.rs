@@ -5,7 +5,7 @@ use axum::{
     http::Request,
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, put},
     Json, Router,
 };
 
@@ -16,7 +16,7 @@ use crate::{
     app::{
         api::AppState, outbound::manager::ThreadSafeOutboundManager, profile::ThreadSafeCacheFile,
     },
-    proxy::AnyOutboundHandler,
+    proxy::{AnyOutboundHandler, OutboundType},
 };
 
 #[derive(Clone)]
@@ -35,11 +35,14 @@ pub fn routes(
     };
     Router::new()
         .route("/", get(get_proxies))
+        .route("/graph", get(get_graph))
         .nest(
             "/:name",
             Router::new()
                 .route("/", get(get_proxy).put(update_proxy))
                 .route("/delay", get(get_proxy_delay))
+                .route("/history", get(get_proxy_history))
+                .route("/order", put(update_proxy_order))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
                     find_proxy_by_name,
@@ -57,6 +60,10 @@ async fn get_proxies(State(state): State<ProxyState>) -> impl IntoResponse {
     axum::response::Json(res)
 }
 
+async fn get_graph(State(state): State<ProxyState>) -> impl IntoResponse {
+    axum::response::Json(state.outbound_manager.graph().await)
+}
+
 async fn find_proxy_by_name(
     State(state): State<ProxyState>,
     Path(name): Path<String>,
@@ -120,6 +127,59 @@ async fn update_proxy(
     }
 }
 
+#[derive(Deserialize)]
+struct UpdateOrderRequest {
+    order: Vec<String>,
+}
+
+async fn update_proxy_order(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+    Json(payload): Json<UpdateOrderRequest>,
+) -> impl IntoResponse {
+    let outbound_manager = state.outbound_manager.clone();
+    if let Some(ctrl) = outbound_manager.get_selector_control(proxy.name()) {
+        match ctrl.lock().await.set_order(payload.order.clone()).await {
+            Ok(_) => {
+                state
+                    .cache_store
+                    .set_order(proxy.name(), payload.order)
+                    .await;
+                (
+                    StatusCode::ACCEPTED,
+                    format!("updated the order of {}", proxy.name()),
+                )
+            }
+            Err(err) => (
+                StatusCode::BAD_REQUEST,
+                format!("reorder {} failed with error: {}", proxy.name(), err),
+            ),
+        }
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("proxy {} is not a Select", proxy.name()),
+        )
+    }
+}
+
+async fn get_proxy_history(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+) -> impl IntoResponse {
+    match proxy.proto() {
+        OutboundType::Fallback | OutboundType::UrlTest => {
+            let history = state.outbound_manager.switch_history(proxy.name());
+            axum::response::Json(history).into_response()
+        }
+        _ => (
+            StatusCode::NOT_FOUND,
+            format!("proxy {} is not a fallback or url-test group", proxy.name()),
+        )
+            .into_response(),
+    }
+}
+
 #[derive(Deserialize)]
 struct DelayRequest {
     url: String,
@@ -0,0 +1,69 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+
+use crate::app::{api::AppState, router::ThreadSafeRouter};
+
+#[derive(Clone)]
+struct RuleProviderState {
+    router: ThreadSafeRouter,
+}
+
+pub fn routes(router: ThreadSafeRouter) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_providers))
+        .route("/:name", get(get_provider).put(update_provider))
+        .with_state(RuleProviderState { router })
+}
+
+fn not_found(name: &str) -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        format!("rule provider {} not found", name),
+    )
+        .into_response()
+}
+
+async fn get_providers(State(state): State<RuleProviderState>) -> impl IntoResponse {
+    let mut providers = HashMap::new();
+    for (name, p) in state.router.get_rule_providers() {
+        providers.insert(name.clone(), p.as_map().await);
+    }
+
+    let mut res = HashMap::new();
+    res.insert("providers", providers);
+    Json(res)
+}
+
+async fn get_provider(
+    State(state): State<RuleProviderState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match state.router.get_rule_providers().get(&name) {
+        Some(p) => Json(p.as_map().await).into_response(),
+        None => not_found(&name),
+    }
+}
+
+async fn update_provider(
+    State(state): State<RuleProviderState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let Some(p) = state.router.get_rule_providers().get(&name) else {
+        return not_found(&name);
+    };
+    match p.update().await {
+        Ok(_) => (StatusCode::ACCEPTED, "provider update started").into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("update rule provider {} failed with error {}", name, err),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,97 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+use serde::Deserialize;
+
+use crate::app::{
+    api::AppState,
+    dispatcher::{StatisticsManager, TopBy},
+};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(3600);
+const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Clone)]
+struct StatisticsState {
+    statistics_manager: Arc<StatisticsManager>,
+}
+
+pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/top", get(get_top))
+        .with_state(StatisticsState { statistics_manager })
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    by: String,
+    /// e.g. `30m`, `1h` or `1d`
+    window: Option<String>,
+    limit: Option<usize>,
+}
+
+/// The hosts, proxies or processes with the most traffic over the connections
+/// closed in the window, the last 24 hours at most.
+async fn get_top(
+    State(state): State<StatisticsState>,
+    Query(q): Query<TopQuery>,
+) -> impl IntoResponse {
+    let by = match q.by.as_str() {
+        "host" => TopBy::Host,
+        "proxy" => TopBy::Proxy,
+        "process" => TopBy::Process,
+        _ => return (StatusCode::BAD_REQUEST, format!("invalid by: {}", q.by)).into_response(),
+    };
+
+    let window = match q.window.as_deref().map(parse_window) {
+        None => DEFAULT_WINDOW,
+        Some(Some(window)) => window,
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("invalid window: {}", q.window.unwrap_or_default()),
+            )
+                .into_response()
+        }
+    };
+
+    let top = state
+        .statistics_manager
+        .top(by, window, q.limit.unwrap_or(DEFAULT_LIMIT))
+        .await;
+    Json(top).into_response()
+}
+
+fn parse_window(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 24 * 3600,
+        _ => return None,
+    };
+    let n: u64 = s[..s.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(n * unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parse_window;
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_window("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_window("1"), None);
+        assert_eq!(parse_window("h"), None);
+        assert_eq!(parse_window(""), None);
+    }
+}
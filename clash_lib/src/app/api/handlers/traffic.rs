@@ -1,8 +1,9 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
+    body::Body,
     extract::{ws::Message, ConnectInfo, State, WebSocketUpgrade},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 
 use serde::Serialize;
@@ -15,30 +16,48 @@ struct TrafficResponse {
     up: i64,
     down: i64,
 }
+
+/// The bytes relayed in the last second, every second, over a WebSocket,
+/// or as a JSON object per line of a chunked response for plain requests,
+/// like Clash does.
 pub async fn handle(
-    ws: WebSocketUpgrade,
+    ws: Option<WebSocketUpgrade>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
+) -> Response {
+    let mgr = state.statistics_manager.clone();
+    let traffic = move || {
+        let (up, down) = mgr.now();
+        serde_json::to_string(&TrafficResponse { up, down }).unwrap()
+    };
+
+    let Some(ws) = ws else {
+        let ticker = tokio::time::interval(Duration::from_secs(1));
+        let lines = futures::stream::unfold(ticker, move |mut ticker| {
+            let traffic = traffic.clone();
+            async move {
+                ticker.tick().await;
+                Some((Ok::<_, Infallible>(traffic() + "\n"), ticker))
+            }
+        });
+        return (
+            [(http::header::CONTENT_TYPE, "application/json")],
+            Body::from_stream(lines),
+        )
+            .into_response();
+    };
+
     ws.on_failed_upgrade(move |e| {
         warn!("ws upgrade error: {} with {}", e, addr);
     })
     .on_upgrade(move |mut socket| async move {
-        let mgr = state.statistics_manager.clone();
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
         loop {
-            let (up, down) = mgr.now();
-            let res = TrafficResponse { up, down };
-            let j = serde_json::to_vec(&res).unwrap();
-
-            if let Err(e) = socket
-                .send(Message::Text(String::from_utf8(j).unwrap()))
-                .await
-            {
+            ticker.tick().await;
+            if let Err(e) = socket.send(Message::Text(traffic())).await {
                 warn!("ws send error: {}", e);
                 break;
             }
-
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
         }
     })
 }
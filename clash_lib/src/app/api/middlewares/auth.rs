@@ -1,24 +1,36 @@
-use axum::extract::Query;
-use axum::http::Request;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{OriginalUri, Query};
+use axum::http::{Method, Request, Uri};
 use axum::{body::Body, response::Response};
 use futures::future::BoxFuture;
 
 use serde::Deserialize;
 use tower::{Layer, Service};
 
+use crate::app::api::handlers::config::ExportConfigQuery;
+use crate::config::def::ApiTokenScope;
+
 #[derive(Debug, Clone, Deserialize)]
 struct AuthQuery {
     token: String,
 }
 
+/// The scopes of the tokens, keyed by the token, no token is needed if
+/// there's none.
+type Tokens = Arc<HashMap<String, ApiTokenScope>>;
+
 #[derive(Debug, Clone)]
 pub struct AuthMiddlewareLayer {
-    pub token: String,
+    pub tokens: Tokens,
 }
 
 impl AuthMiddlewareLayer {
-    pub fn new(token: String) -> Self {
-        Self { token }
+    pub fn new(tokens: HashMap<String, ApiTokenScope>) -> Self {
+        Self {
+            tokens: Arc::new(tokens),
+        }
     }
 }
 
@@ -26,19 +38,19 @@ impl<S> Layer<S> for AuthMiddlewareLayer {
     type Service = AuthMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        AuthMiddleware::new(inner, self.token.clone())
+        AuthMiddleware::new(inner, self.tokens.clone())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct AuthMiddleware<S> {
     inner: S,
-    token: String,
+    tokens: Tokens,
 }
 
 impl<S> AuthMiddleware<S> {
-    pub fn new(inner: S, token: String) -> Self {
-        Self { inner, token }
+    pub fn new(inner: S, tokens: Tokens) -> Self {
+        Self { inner, tokens }
     }
 
     fn is_websocket(&self, req: &Request<Body>) -> bool {
@@ -68,36 +80,177 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if self.token.is_empty() {
+        if self.tokens.is_empty() {
             return Box::pin(self.inner.call(req));
         }
 
-        let unauthorised = Response::builder()
-            .status(http::StatusCode::UNAUTHORIZED)
-            .body("unauthorized".to_string().into())
-            .unwrap();
+        let token = if self.is_websocket(&req) {
+            Query::<AuthQuery>::try_from_uri(req.uri())
+                .map(|q| q.0.token)
+                .unwrap_or_default()
+        } else {
+            req.headers()
+                .get("authorization")
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.strip_prefix("Bearer "))
+                .unwrap_or_default()
+                .to_owned()
+        };
 
-        if self.is_websocket(&req) {
-            let q = Query::<AuthQuery>::try_from_uri(req.uri()).ok();
-            if let Some(q) = q {
-                if q.token == self.token {
+        let status = match self.tokens.get(&token) {
+            None => http::StatusCode::UNAUTHORIZED,
+            Some(scope) => {
+                // the path is stripped of the prefix of the nested routers
+                let path = req
+                    .extensions()
+                    .get::<OriginalUri>()
+                    .map_or(req.uri().path(), |x| x.path());
+                let secrets = exports_secrets(path, req.uri());
+                if allows(*scope, req.method(), path)
+                    && (*scope == ApiTokenScope::Admin || !secrets)
+                {
                     return Box::pin(self.inner.call(req));
                 }
+                http::StatusCode::FORBIDDEN
             }
+        };
 
-            return Box::pin(async move { Ok(unauthorised) });
+        let res = Response::builder()
+            .status(status)
+            .body(
+                status
+                    .canonical_reason()
+                    .unwrap_or_default()
+                    .to_lowercase()
+                    .into(),
+            )
+            .unwrap();
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+fn allows(scope: ApiTokenScope, method: &Method, path: &str) -> bool {
+    let get = method == Method::GET || method == Method::HEAD;
+    let segments = segments(path);
+    let read = get && !sends_traffic(&segments);
+    match scope {
+        ApiTokenScope::ReadOnly => read,
+        // PUT /proxies/:name selects the proxy of a group, not any deeper
+        // path, e.g. the order of a group kept in the cache file
+        ApiTokenScope::ProxySelect => {
+            get || (method == Method::PUT && matches!(segments.as_slice(), ["proxies", _]))
         }
+        ApiTokenScope::Admin => true,
+    }
+}
 
-        let header = req
-            .headers()
-            .get("authorization")
-            .map(|x| x.to_str().unwrap_or_default())
-            .unwrap_or_default();
+/// The segments of the path, a trailing slash ignored as the router does.
+fn segments(path: &str) -> Vec<&str> {
+    path.trim_matches('/').split('/').collect()
+}
 
-        if header == format!("Bearer {}", self.token) {
-            return Box::pin(self.inner.call(req));
+/// The GET requests sending traffic out through the outbounds or to the
+/// DNS servers.
+fn sends_traffic(segments: &[&str]) -> bool {
+    matches!(
+        segments,
+        ["proxies", _, "delay"]
+            | ["providers", "proxies", _, "healthcheck"]
+            | ["providers", "proxies", _, _, "healthcheck"]
+            | ["diagnostics", "outbound", _]
+            | ["dns", "query"]
+    )
+}
+
+/// The config export with its secrets, for the admins only. The query is
+/// decoded as the handler does, one it can't decode is taken as asking for
+/// them.
+fn exports_secrets(path: &str, uri: &Uri) -> bool {
+    path == "/configs/export"
+        && Query::<ExportConfigQuery>::try_from_uri(uri)
+            .map_or(true, |Query(q)| q.redact == Some(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use crate::config::def::ApiTokenScope;
+
+    use super::{allows, exports_secrets};
+
+    #[test]
+    fn test_allows() {
+        assert!(allows(
+            ApiTokenScope::ReadOnly,
+            &Method::GET,
+            "/connections"
+        ));
+        assert!(!allows(
+            ApiTokenScope::ReadOnly,
+            &Method::DELETE,
+            "/connections"
+        ));
+        assert!(!allows(
+            ApiTokenScope::ReadOnly,
+            &Method::PUT,
+            "/proxies/auto"
+        ));
+        assert!(allows(
+            ApiTokenScope::ProxySelect,
+            &Method::PUT,
+            "/proxies/auto"
+        ));
+        assert!(allows(
+            ApiTokenScope::ProxySelect,
+            &Method::PUT,
+            "/proxies/auto/"
+        ));
+        assert!(!allows(
+            ApiTokenScope::ProxySelect,
+            &Method::PUT,
+            "/proxies/auto/order"
+        ));
+        for path in [
+            "/proxies/auto/delay",
+            "/providers/proxies/sub/healthcheck",
+            "/providers/proxies/sub/hk/healthcheck",
+            "/diagnostics/outbound/hk",
+            "/dns/query",
+        ] {
+            assert!(
+                !allows(ApiTokenScope::ReadOnly, &Method::GET, path),
+                "{}",
+                path
+            );
+            assert!(
+                allows(ApiTokenScope::ProxySelect, &Method::GET, path),
+                "{}",
+                path
+            );
         }
+        assert!(allows(
+            ApiTokenScope::ReadOnly,
+            &Method::GET,
+            "/proxies/auto/history"
+        ));
+        assert!(!allows(
+            ApiTokenScope::ProxySelect,
+            &Method::PUT,
+            "/configs"
+        ));
+        assert!(allows(ApiTokenScope::Admin, &Method::PUT, "/configs"));
 
-        Box::pin(async move { Ok(unauthorised) })
+        let exports = |uri: &str| exports_secrets("/configs/export", &uri.parse().unwrap());
+        assert!(!exports("/configs/export"));
+        assert!(!exports("/configs/export?redact=true"));
+        assert!(exports("/configs/export?x=1&redact=false"));
+        // decoded as the handler does
+        assert!(exports("/configs/export?redact=%66alse"));
+        assert!(exports("/configs/export?redact=no"));
+        assert!(!exports_secrets(
+            "/configs",
+            &"/configs?redact=false".parse().unwrap()
+        ));
     }
 }
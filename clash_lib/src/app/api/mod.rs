@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -10,7 +11,11 @@ use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 use tracing::{error, info};
 
-use crate::{config::internal::config::Controller, GlobalState, Runner};
+use crate::{
+    common::mmdb::Mmdb,
+    config::{def::ApiTokenScope, internal::config::Controller},
+    GlobalState, Runner,
+};
 
 use super::dispatcher::StatisticsManager;
 use super::dns::ThreadSafeDNSResolver;
@@ -41,54 +46,94 @@ pub fn get_api_runner(
     statistics_manager: Arc<StatisticsManager>,
     cache_store: ThreadSafeCacheFile,
     router: ThreadSafeRouter,
+    mmdb: Arc<Mmdb>,
     cwd: String,
 ) -> Option<Runner> {
     if let Some(bind_addr) = controller_cfg.external_controller {
+        let mut tokens = controller_cfg.tokens;
+        if let Some(secret) = controller_cfg.secret.filter(|x| !x.is_empty()) {
+            tokens.insert(secret, ApiTokenScope::Admin);
+        }
+
         let app_state = Arc::new(AppState {
             log_source_tx: log_source,
             statistics_manager: statistics_manager.clone(),
         });
 
         let cors = CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH])
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
             .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
             .allow_origin(Any);
 
         let runner = async move {
             info!("Starting API server at {}", bind_addr);
+            let health = handlers::health::routes(
+                global_state.clone(),
+                inbound_manager.clone(),
+                dns_resolver.clone(),
+            );
             let mut app = Router::new()
                 .route("/", get(handlers::hello::handle))
+                .nest(
+                    "/health",
+                    handlers::health::report_routes(global_state.clone()),
+                )
                 .route("/logs", get(handlers::log::handle))
+                .route("/events", get(handlers::events::handle))
                 .route("/traffic", get(handlers::traffic::handle))
                 .route("/version", get(handlers::version::handle))
                 .nest(
                     "/configs",
                     handlers::config::routes(
-                        inbound_manager,
-                        dispatcher,
+                        inbound_manager.clone(),
+                        dispatcher.clone(),
                         global_state,
                         dns_resolver.clone(),
                     ),
                 )
-                .nest("/rules", handlers::rule::routes(router))
+                .nest("/inbound", handlers::inbound::routes(inbound_manager))
+                .nest("/rules", handlers::rule::routes(router.clone()))
                 .nest(
                     "/proxies",
                     handlers::proxy::routes(outbound_manager.clone(), cache_store),
                 )
                 .nest(
                     "/connections",
-                    handlers::connection::routes(statistics_manager),
+                    handlers::connection::routes(statistics_manager.clone(), &cwd),
+                )
+                .nest(
+                    "/statistics",
+                    handlers::statistics::routes(statistics_manager),
                 )
                 .nest(
                     "/providers/proxies",
-                    handlers::provider::routes(outbound_manager),
+                    handlers::provider::routes(outbound_manager.clone()),
+                )
+                .nest("/providers/rules", handlers::rule_provider::routes(router))
+                .nest(
+                    "/diagnostics",
+                    handlers::diagnostics::routes(outbound_manager.clone(), dns_resolver.clone()),
                 )
-                .nest("/dns", handlers::dns::routes(dns_resolver))
-                .route_layer(middlewares::auth::AuthMiddlewareLayer::new(
-                    controller_cfg.secret.unwrap_or_default(),
-                ))
-                .route_layer(cors)
-                .with_state(app_state);
+                .nest("/chaos", handlers::chaos::routes(outbound_manager))
+                .nest("/dns", handlers::dns::routes(dns_resolver, dispatcher))
+                .nest("/geo", handlers::geo::routes(mmdb));
+            if controller_cfg.health_auth {
+                app = app.nest("/health", health.clone());
+            }
+            app = app
+                .route_layer(middlewares::auth::AuthMiddlewareLayer::new(tokens))
+                .route_layer(cors);
+            // left open for the probes of the container orchestration
+            if !controller_cfg.health_auth {
+                app = app.nest("/health", health);
+            }
+            let mut app = app.with_state(app_state);
 
             if let Some(external_ui) = controller_cfg.external_ui {
                 app = app
@@ -98,7 +143,13 @@ pub fn get_api_runner(
 
             let listener = tokio::net::TcpListener::bind(&bind_addr).await.unwrap();
 
-            axum::serve(listener, app).await.map_err(|x| {
+            // the websocket handlers log the peer
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .map_err(|x| {
                 error!("API server error: {}", x);
                 crate::Error::Operation(format!("API server error: {}", x))
             })
@@ -0,0 +1,290 @@
+//! Dump the traffic of a single relay to a pcap file
+//!
+//! What's captured is the plaintext between the inbound and the outbound,
+//! before the proxy protocol encrypts anything. The TCP/IP headers are made
+//! up from the session addresses, so Wireshark can follow the stream and
+//! decode the application protocol on top.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::{debug, warn};
+
+/// raw IPv4 or IPv6 packets, no link layer
+const LINKTYPE_RAW: u32 = 101;
+/// what's relayed in one go is cut into segments no larger than this
+const MAX_SEGMENT: usize = 16384;
+/// the relayed chunks waiting to be written, the ones over it are dropped
+/// rather than holding up the relay
+const QUEUE_SIZE: usize = 256;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    /// client to remote
+    Upload,
+    /// remote to client
+    Download,
+}
+
+/// The sending end of a running capture.
+#[derive(Clone)]
+pub struct Capture(mpsc::Sender<(Direction, Vec<u8>)>);
+
+impl Capture {
+    /// Returns false once the capture is over.
+    pub fn push(&self, direction: Direction, data: &[u8]) -> bool {
+        if data.is_empty() {
+            return true;
+        }
+        match self.0.try_send((direction, data.to_vec())) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("capture queue full, dropping {} bytes", data.len());
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Start writing the traffic pushed in the following `duration` to `path`.
+pub async fn start(
+    path: PathBuf,
+    client: SocketAddr,
+    remote: SocketAddr,
+    duration: Duration,
+) -> io::Result<Capture> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut file = BufWriter::new(File::create(&path).await?);
+    let mut flow = Flow::new(client, remote);
+
+    file.write_all(&global_header()).await?;
+    for packet in flow.handshake() {
+        file.write_all(&record(&packet)).await?;
+    }
+
+    let (tx, mut rx) = mpsc::channel::<(Direction, Vec<u8>)>(QUEUE_SIZE);
+    tokio::spawn(async move {
+        let deadline = tokio::time::sleep(duration);
+        tokio::pin!(deadline);
+
+        let r: io::Result<()> = async {
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    data = rx.recv() => match data {
+                        Some((direction, data)) => {
+                            for packet in flow.data(direction, &data) {
+                                file.write_all(&record(&packet)).await?;
+                            }
+                        }
+                        // the connection is closed
+                        None => {
+                            file.write_all(&record(&flow.fin())).await?;
+                            break;
+                        }
+                    }
+                }
+            }
+            file.flush().await
+        }
+        .await;
+
+        match r {
+            Ok(_) => debug!("capture written to {}", path.display()),
+            Err(e) => warn!("failed to write capture {}: {}", path.display(), e),
+        }
+    });
+
+    Ok(Capture(tx))
+}
+
+fn global_header() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(24);
+    buf.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    buf.extend_from_slice(&2u16.to_le_bytes());
+    buf.extend_from_slice(&4u16.to_le_bytes());
+    // thiszone, sigfigs
+    buf.extend_from_slice(&[0u8; 8]);
+    // snaplen
+    buf.extend_from_slice(&65535u32.to_le_bytes());
+    buf.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    buf
+}
+
+fn record(packet: &[u8]) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut buf = Vec::with_capacity(16 + packet.len());
+    buf.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    buf.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    buf.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    buf.extend_from_slice(packet);
+    buf
+}
+
+/// The made up TCP connection the traffic is written as.
+struct Flow {
+    client: SocketAddr,
+    remote: SocketAddr,
+    client_seq: u32,
+    remote_seq: u32,
+}
+
+impl Flow {
+    fn new(client: SocketAddr, remote: SocketAddr) -> Self {
+        // both ends in the same family, IPv4 mapped if need be
+        let (client, remote) = match (client.ip(), remote.ip()) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => (client, remote),
+            (c, r) => (
+                SocketAddr::new(to_v6(c).into(), client.port()),
+                SocketAddr::new(to_v6(r).into(), remote.port()),
+            ),
+        };
+        Self {
+            client,
+            remote,
+            client_seq: 0,
+            remote_seq: 0,
+        }
+    }
+
+    fn handshake(&mut self) -> Vec<Vec<u8>> {
+        let syn = self.packet(Direction::Upload, TCP_SYN, &[]);
+        self.client_seq += 1;
+        let syn_ack = self.packet(Direction::Download, TCP_SYN | TCP_ACK, &[]);
+        self.remote_seq += 1;
+        let ack = self.packet(Direction::Upload, TCP_ACK, &[]);
+        vec![syn, syn_ack, ack]
+    }
+
+    fn data(&mut self, direction: Direction, data: &[u8]) -> Vec<Vec<u8>> {
+        data.chunks(MAX_SEGMENT)
+            .map(|chunk| {
+                let packet = self.packet(direction, TCP_PSH | TCP_ACK, chunk);
+                let seq = match direction {
+                    Direction::Upload => &mut self.client_seq,
+                    Direction::Download => &mut self.remote_seq,
+                };
+                *seq = seq.wrapping_add(chunk.len() as u32);
+                packet
+            })
+            .collect()
+    }
+
+    fn fin(&mut self) -> Vec<u8> {
+        self.packet(Direction::Upload, TCP_FIN | TCP_ACK, &[])
+    }
+
+    fn packet(&self, direction: Direction, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, seq, ack) = match direction {
+            Direction::Upload => (self.client, self.remote, self.client_seq, self.remote_seq),
+            Direction::Download => (self.remote, self.client, self.remote_seq, self.client_seq),
+        };
+        let tcp_len = 20 + payload.len();
+
+        let mut buf = Vec::with_capacity(40 + tcp_len);
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                buf.extend_from_slice(&[0x45, 0]);
+                buf.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+                // id, don't fragment
+                buf.extend_from_slice(&[0, 0, 0x40, 0]);
+                // ttl, tcp, checksum
+                buf.extend_from_slice(&[64, 6, 0, 0]);
+                buf.extend_from_slice(&s.octets());
+                buf.extend_from_slice(&d.octets());
+                let sum = checksum(&buf);
+                buf[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            (s, d) => {
+                buf.extend_from_slice(&[0x60, 0, 0, 0]);
+                buf.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+                // tcp, hop limit
+                buf.extend_from_slice(&[6, 64]);
+                buf.extend_from_slice(&to_v6(s).octets());
+                buf.extend_from_slice(&to_v6(d).octets());
+            }
+        }
+
+        buf.extend_from_slice(&src.port().to_be_bytes());
+        buf.extend_from_slice(&dst.port().to_be_bytes());
+        buf.extend_from_slice(&seq.to_be_bytes());
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+        buf.extend_from_slice(&ack.to_be_bytes());
+        // a 20 bytes header, the flags, the window
+        buf.extend_from_slice(&[0x50, flags, 0xff, 0xff]);
+        // the TCP checksum is left out, Wireshark doesn't check it by default
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(payload);
+        buf
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Direction, Flow};
+
+    #[test]
+    fn test_flow() {
+        let mut flow = Flow::new(
+            "192.168.1.2:50000".parse().unwrap(),
+            "1.1.1.1:80".parse().unwrap(),
+        );
+        let handshake = flow.handshake();
+        assert_eq!(handshake.len(), 3);
+        assert!(handshake.iter().all(|x| x.len() == 40));
+        // a valid IPv4 header sums up to zero
+        assert_eq!(checksum(&handshake[0][..20]), 0);
+
+        let packets = flow.data(Direction::Upload, &[0u8; 20000]);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].len(), 40 + 20000 - 16384);
+        assert_eq!(flow.client_seq, 20001);
+        assert_eq!(flow.remote_seq, 1);
+
+        let mut flow = Flow::new(
+            "192.168.1.2:50000".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+        );
+        let packets = flow.data(Direction::Download, b"hello");
+        assert_eq!(packets[0].len(), 40 + 20 + 5);
+        assert_eq!(packets[0][0] >> 4, 6);
+    }
+}
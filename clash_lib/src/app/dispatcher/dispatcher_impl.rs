@@ -1,25 +1,32 @@
 use crate::app::dispatcher::tracked::TrackedDatagram;
 use crate::app::dispatcher::tracked::TrackedStream;
+use crate::app::events::{self, EventKind};
 use crate::app::outbound::manager::ThreadSafeOutboundManager;
 use crate::app::router::ThreadSafeRouter;
-use crate::common::io::copy_buf_bidirectional_with_timeout;
+use crate::common::errors::{failed_member, DialError};
+use crate::common::io::{copy_buf_bidirectional_with_timeout, Reaped};
 use crate::config::def::RunMode;
+use crate::config::internal::config::{ShapingLimit, TcpTimeout};
 use crate::config::internal::proxy::PROXY_DIRECT;
 use crate::config::internal::proxy::PROXY_GLOBAL;
+use crate::config::internal::proxy::PROXY_REJECT;
 use crate::proxy::datagram::UdpPacket;
-use crate::proxy::AnyInboundDatagram;
-use crate::session::Session;
+use crate::proxy::utils::Interface;
+use crate::proxy::{AnyInboundDatagram, AnyOutboundHandler, OutboundType};
+use crate::session::{DnsResolveMode, Session};
 use futures::SinkExt;
 use futures::StreamExt;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::hash::BuildHasher;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
 use tracing::info_span;
 use tracing::instrument;
@@ -29,13 +36,23 @@ use tracing::{debug, error, info, warn};
 
 use crate::app::dns::ThreadSafeDNSResolver;
 
-use super::statistics_manager::Manager;
+use super::ftp::{FtpControlStream, FtpHelper};
+use super::shaping::{ShapedStream, Shaper};
+use super::statistics_manager::{Manager, ProxyChain, ReapReason};
+use super::{ChainedDatagram, ChainedStream, ChainedStreamWrapper};
 
 pub struct Dispatcher {
     outbound_manager: ThreadSafeOutboundManager,
     router: ThreadSafeRouter,
     resolver: ThreadSafeDNSResolver,
     mode: Arc<Mutex<RunMode>>,
+    /// the default outbound interface, could be a name pattern like `en*`
+    /// which is resolved on each dial
+    outbound_interface: Option<Interface>,
+    shaper: Shaper,
+    tcp_timeout: TcpTimeout,
+    /// `None` if the FTP helper is disabled
+    ftp: Option<FtpHelper>,
 
     manager: Arc<Manager>,
 }
@@ -47,11 +64,16 @@ impl Debug for Dispatcher {
 }
 
 impl Dispatcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         outbound_manager: ThreadSafeOutboundManager,
         router: ThreadSafeRouter,
         resolver: ThreadSafeDNSResolver,
         mode: RunMode,
+        outbound_interface: Option<Interface>,
+        shaping: HashMap<String, ShapingLimit>,
+        tcp_timeout: TcpTimeout,
+        ftp_helper: bool,
 
         statistics_manager: Arc<Manager>,
     ) -> Self {
@@ -60,10 +82,20 @@ impl Dispatcher {
             router,
             resolver,
             mode: Arc::new(Mutex::new(mode)),
+            outbound_interface,
+            shaper: Shaper::new(shaping),
+            tcp_timeout,
+            ftp: ftp_helper.then(FtpHelper::new),
             manager: statistics_manager,
         }
     }
 
+    /// For the inbounds replying from addresses, to turn the fake IP
+    /// domains of the replies back into their fake IPs.
+    pub fn resolver(&self) -> ThreadSafeDNSResolver {
+        self.resolver.clone()
+    }
+
     pub async fn set_mode(&self, mode: RunMode) {
         info!("run mode switched to {}", mode);
 
@@ -74,11 +106,43 @@ impl Dispatcher {
         *self.mode.lock().unwrap()
     }
 
+    /// The rule that would reject the session, for the inbounds that can
+    /// tell the client why, e.g. with a block page.
+    pub async fn rejected_by(&self, sess: &Session) -> Option<String> {
+        let mode = *self.mode.lock().unwrap();
+        if !matches!(mode, RunMode::Rule) {
+            return None;
+        }
+        match self.router.match_route(sess).await {
+            (outbound, Some(rule)) if outbound == PROXY_REJECT => {
+                let payload = rule.payload();
+                if payload.is_empty() {
+                    Some(rule.type_name().to_owned())
+                } else {
+                    Some(format!("{},{}", rule.type_name(), payload))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the domain rules route the host to REJECT, for the DNS
+    /// server to answer NXDOMAIN.
+    pub fn rejects_domain(&self, host: &str) -> bool {
+        let mode = *self.mode.lock().unwrap();
+        matches!(mode, RunMode::Rule) && self.router.match_domain(host) == Some(PROXY_REJECT)
+    }
+
     #[instrument(skip(self, sess, lhs))]
-    pub async fn dispatch_stream<S>(&self, sess: Session, mut lhs: S)
+    pub async fn dispatch_stream<S>(&self, sess: Session, lhs: S)
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
+        let mut sess = sess;
+        if sess.iface.is_none() {
+            sess.iface = self.outbound_interface.clone();
+        }
+
         let sess = if self.resolver.fake_ip_enabled() {
             match sess.destination {
                 crate::session::SocksAddr::Ip(addr) => {
@@ -107,36 +171,74 @@ impl Dispatcher {
             sess
         };
 
+        let mut sess = sess;
+
+        // an FTP data connection goes the way of its control connection
+        let pinned = self.ftp.as_ref().and_then(|x| x.take(&sess));
+
         let mode = *self.mode.lock().unwrap();
-        let (outbound_name, rule) = match mode {
-            RunMode::Global => (PROXY_GLOBAL, None),
-            RunMode::Rule => self.router.match_route(&sess).await,
-            RunMode::Direct => (PROXY_DIRECT, None),
+        let (outbound_name, rule) = match (&pinned, mode) {
+            (Some(name), _) => (name.as_str(), None),
+            (None, RunMode::Global) => (PROXY_GLOBAL, None),
+            (None, RunMode::Rule) => self.router.match_route(&sess).await,
+            (None, RunMode::Direct) => (PROXY_DIRECT, None),
         };
+        self.router.find_process(&mut sess);
 
         debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
+        let shaping_class = rule
+            .and_then(|r| r.shaping_class())
+            .and_then(|c| self.shaper.get(c));
+        let mut lhs = ShapedStream::new(lhs, shaping_class);
+
         let mgr = self.outbound_manager.clone();
-        let handler = mgr.get_outbound(outbound_name).unwrap_or_else(|| {
+        // the pinned proxy could be one from a provider
+        let handler = mgr.find_proxy(outbound_name).await.unwrap_or_else(|| {
             debug!("unknown rule: {}, fallback to direct", outbound_name);
             mgr.get_outbound(PROXY_DIRECT).unwrap()
         });
 
-        match handler
+        let connect = handler
             .connect_stream(&sess, self.resolver.clone())
-            .instrument(info_span!("connect_stream", outbound_name = outbound_name,))
-            .await
-        {
+            .instrument(info_span!("connect_stream", outbound_name = outbound_name,));
+        let connect = match self.tcp_timeout.handshake {
+            Some(handshake) => tokio::time::timeout(handshake, connect)
+                .await
+                .unwrap_or_else(|_| {
+                    self.manager.record_reaped(ReapReason::Handshake);
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "outbound handshake timed out",
+                    ))
+                }),
+            None => connect.await,
+        };
+
+        match connect {
             Ok(rhs) => {
                 debug!("remote connection established {}", sess);
-                let mut rhs =
-                    TrackedStream::new(rhs, self.manager.clone(), sess.clone(), rule).await;
+                let last_hop = rhs.chain().last_hop().await;
+                if let Some(name) = &last_hop {
+                    mgr.report_used(name);
+                }
+                let watch = match (&self.ftp, last_hop) {
+                    (Some(ftp), Some(name)) => ftp.watch(&sess, name),
+                    _ => None,
+                };
+                let mut lhs = FtpControlStream::new(lhs, watch);
+                let mut tracked = sess.clone();
+                if tracked.destination.is_domain() {
+                    tracked.dns_resolve_mode = Some(self.dns_resolve_mode(rhs.chain()).await);
+                }
+                let mut rhs = TrackedStream::new(rhs, self.manager.clone(), tracked, rule).await;
                 match copy_buf_bidirectional_with_timeout(
                     &mut lhs,
                     &mut rhs,
                     4096,
-                    Duration::from_secs(10),
-                    Duration::from_secs(10),
+                    self.tcp_timeout.half_open,
+                    self.tcp_timeout.half_open,
+                    self.tcp_timeout.idle,
                 )
                 .instrument(info_span!(
                     "copy_bidirectional",
@@ -144,12 +246,22 @@ impl Dispatcher {
                 ))
                 .await
                 {
-                    Ok((up, down)) => {
+                    Ok((up, down, None)) => {
                         debug!(
                             "connection {} closed with {} bytes up, {} bytes down",
                             sess, up, down
                         );
                     }
+                    Ok((up, down, Some(reaped))) => {
+                        debug!(
+                            "connection {} reaped: {}, with {} bytes up, {} bytes down",
+                            sess, reaped, up, down
+                        );
+                        self.manager.record_reaped(match reaped {
+                            Reaped::Idle => ReapReason::Idle,
+                            Reaped::HalfOpen => ReapReason::HalfOpen,
+                        });
+                    }
                     Err(err) => match err.kind() {
                         std::io::ErrorKind::UnexpectedEof
                         | std::io::ErrorKind::ConnectionReset
@@ -167,6 +279,7 @@ impl Dispatcher {
                     "failed to establish remote connection {}, error: {}",
                     sess, err
                 );
+                report_dial_error(&mgr, &sess, outbound_name, &err);
                 if let Err(e) = lhs.shutdown().await {
                     warn!("error closing local connection {}: {}", sess, e)
                 }
@@ -174,21 +287,108 @@ impl Dispatcher {
         }
     }
 
+    /// Whether the rules send the session out directly, a SOCKS BIND is
+    /// only served then as none of the proxies can take connections for us.
+    pub async fn allows_bind(&self, sess: &Session) -> bool {
+        let mode = *self.mode.lock().unwrap();
+        let outbound_name = match mode {
+            RunMode::Global => PROXY_GLOBAL,
+            RunMode::Rule => self.router.match_route(sess).await.0,
+            RunMode::Direct => PROXY_DIRECT,
+        };
+        self.outbound_manager
+            .get_outbound(outbound_name)
+            .is_some_and(|x| matches!(x.proto(), OutboundType::Direct))
+    }
+
+    /// Relay a connection the remote made to us, e.g. for a SOCKS BIND,
+    /// it's tracked as a DIRECT connection to `sess.destination`.
+    #[instrument(skip(self, sess, lhs, rhs))]
+    pub async fn dispatch_accepted<S>(&self, sess: Session, lhs: S, rhs: TcpStream)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mode = *self.mode.lock().unwrap();
+        let rule = match mode {
+            RunMode::Rule => self.router.match_route(&sess).await.1,
+            _ => None,
+        };
+
+        let rhs = ChainedStreamWrapper::new(rhs);
+        rhs.append_to_chain(PROXY_DIRECT).await;
+        let mut rhs =
+            TrackedStream::new(Box::new(rhs), self.manager.clone(), sess.clone(), rule).await;
+        let mut lhs = lhs;
+        match copy_buf_bidirectional_with_timeout(
+            &mut lhs,
+            &mut rhs,
+            4096,
+            self.tcp_timeout.half_open,
+            self.tcp_timeout.half_open,
+            self.tcp_timeout.idle,
+        )
+        .await
+        {
+            Ok((up, down, reaped)) => {
+                debug!(
+                    "accepted connection {} closed with {} bytes up, {} bytes down{}",
+                    sess,
+                    up,
+                    down,
+                    reaped.map(|x| format!(", {}", x)).unwrap_or_default()
+                );
+            }
+            Err(err) => {
+                debug!("accepted connection {} closed with error {}", sess, err);
+            }
+        }
+    }
+
+    /// Where the domain destination was resolved,
+    /// which is up to the proxy the connection finally goes through.
+    async fn dns_resolve_mode(&self, chain: &ProxyChain) -> DnsResolveMode {
+        let remote = match chain.last_hop().await {
+            Some(name) => self
+                .outbound_manager
+                .find_proxy(&name)
+                .await
+                .is_some_and(|x| x.remote_dns_resolve()),
+            None => false,
+        };
+        if remote {
+            DnsResolveMode::Remote
+        } else {
+            DnsResolveMode::Local
+        }
+    }
+
     /// Dispatch a UDP packet to outbound handler
     /// returns the close sender
+    ///
+    /// The packets are spread over shards by their source, each with its
+    /// own sessions, so that the flows of different local sockets don't
+    /// wait on one another. The flows of a local socket stay on the same
+    /// shard, which keeps one session per outbound for all its
+    /// destinations, i.e. the same external port. A shard that can't keep
+    /// up holds the packets back rather than dropping them.
     #[instrument]
     pub fn dispatch_datagram(
         &self,
         sess: Session,
         udp_inbound: AnyInboundDatagram,
     ) -> tokio::sync::oneshot::Sender<u8> {
-        let outbound_handle_guard = TimeoutUdpSessionManager::new();
+        let mut sess = sess;
+        if sess.iface.is_none() {
+            sess.iface = self.outbound_interface.clone();
+        }
 
-        let router = self.router.clone();
-        let outbound_manager = self.outbound_manager.clone();
-        let resolver = self.resolver.clone();
-        let mode = self.mode.clone();
-        let manager = self.manager.clone();
+        let ctx = Arc::new(UdpShardContext {
+            router: self.router.clone(),
+            outbound_manager: self.outbound_manager.clone(),
+            resolver: self.resolver.clone(),
+            mode: self.mode.clone(),
+            manager: self.manager.clone(),
+        });
 
         let (mut local_w, mut local_r) = udp_inbound.split();
         let (remote_receiver_w, mut remote_receiver_r) = tokio::sync::mpsc::channel(32);
@@ -196,154 +396,26 @@ impl Dispatcher {
         let s = sess.clone();
         let ss = sess.clone();
         let t1 = tokio::spawn(async move {
-            while let Some(packet) = local_r.next().await {
-                let mut sess = sess.clone();
-                sess.source = packet.src_addr.clone().must_into_socket_addr();
-                sess.destination = packet.dst_addr.clone();
-
-                // populate fake ip for route matching
-                let sess = if resolver.fake_ip_enabled() {
-                    trace!("looking up fake ip for {sess}");
-                    match sess.destination {
-                        crate::session::SocksAddr::Ip(addr) => {
-                            let ip = addr.ip();
-                            if resolver.is_fake_ip(ip).await {
-                                trace!("fake ip detected");
-                                let host = resolver.reverse_lookup(ip).await;
-                                match host {
-                                    Some(host) => {
-                                        trace!("fake ip resolved to {}", host);
-                                        let mut sess = sess;
-                                        sess.destination =
-                                            crate::session::SocksAddr::Domain(host, addr.port());
-                                        sess
-                                    }
-                                    None => {
-                                        error!("failed to reverse lookup fake ip: {}", ip);
-                                        continue;
-                                    }
-                                }
-                            } else {
-                                sess
-                            }
-                        }
-                        crate::session::SocksAddr::Domain(_, _) => sess,
-                    }
-                } else {
-                    sess
-                };
-
-                // mutate packet for fake ip
-                let mut packet = packet;
-                packet.dst_addr = sess.destination.clone();
-
-                let mode = *mode.lock().unwrap();
-
-                let (outbound_name, rule) = match mode {
-                    RunMode::Global => (PROXY_GLOBAL, None),
-                    RunMode::Rule => router.match_route(&sess).await,
-                    RunMode::Direct => (PROXY_DIRECT, None),
-                };
+            // started on their first packet
+            let mut shards: Vec<Option<UdpShard>> = (0..udp_shard_count()).map(|_| None).collect();
+            let hasher = RandomState::new();
 
-                let outbound_name = outbound_name.to_string();
-
-                debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
-
-                let remote_receiver_w = remote_receiver_w.clone();
-
-                let mgr = outbound_manager.clone();
-                let handler = mgr.get_outbound(&outbound_name).unwrap_or_else(|| {
-                    debug!("unknown rule: {}, fallback to direct", outbound_name);
-                    mgr.get_outbound(PROXY_DIRECT).unwrap()
+            while let Some(packet) = local_r.next().await {
+                let i = udp_shard_index(&hasher, &packet, shards.len());
+                let shard = shards[i].get_or_insert_with(|| {
+                    let (tx, rx) = tokio::sync::mpsc::channel(32);
+                    let handle = tokio::spawn(udp_shard(
+                        rx,
+                        sess.clone(),
+                        ctx.clone(),
+                        remote_receiver_w.clone(),
+                    ));
+                    UdpShard { tx, handle }
                 });
-
-                match outbound_handle_guard
-                    .get_outbound_sender_mut(
-                        &outbound_name,
-                        packet.src_addr.clone().must_into_socket_addr(), // this is only expected to be socket addr as it's from local udp
-                    )
-                    .await
-                {
-                    None => {
-                        debug!("building {} outbound datagram connecting", sess);
-                        let outbound_datagram =
-                            match handler.connect_datagram(&sess, resolver.clone()).await {
-                                Ok(v) => v,
-                                Err(err) => {
-                                    error!("failed to connect outbound: {}", err);
-                                    continue;
-                                }
-                            };
-
-                        debug!("{} outbound datagram connected", sess);
-
-                        let outbound_datagram = TrackedDatagram::new(
-                            outbound_datagram,
-                            manager.clone(),
-                            sess.clone(),
-                            rule,
-                        )
-                        .await;
-
-                        let (mut remote_w, mut remote_r) = outbound_datagram.split();
-                        let (remote_sender, mut remote_forwarder) =
-                            tokio::sync::mpsc::channel::<UdpPacket>(32);
-
-                        // remote -> local
-                        let r_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_r.next().await {
-                                // NAT
-                                let mut packet = packet;
-                                packet.src_addr = sess.destination.clone();
-                                packet.dst_addr = sess.source.into();
-
-                                debug!("UDP NAT for packet: {:?}, session: {}", packet, sess);
-                                match remote_receiver_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!("failed to send packet to local: {}", err);
-                                    }
-                                }
-                            }
-                        });
-                        // local -> remote
-                        let w_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_forwarder.recv().await {
-                                match remote_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!("failed to send packet to remote: {}", err);
-                                    }
-                                }
-                            }
-                        });
-
-                        outbound_handle_guard
-                            .insert(
-                                &outbound_name,
-                                packet.src_addr.clone().must_into_socket_addr(),
-                                r_handle,
-                                w_handle,
-                                remote_sender.clone(),
-                            )
-                            .await;
-
-                        match remote_sender.send(packet).await {
-                            Ok(_) => {}
-                            Err(err) => {
-                                error!("failed to send packet to remote: {}", err);
-                            }
-                        };
-                    }
-                    Some(handle) => match handle.send(packet).await {
-                        Ok(_) => {
-                            debug!("reusing {} sent to remote", sess);
-                        }
-                        Err(err) => {
-                            error!("failed to send packet to remote: {}", err);
-                        }
-                    },
-                };
+                if shard.tx.send(packet).await.is_err() {
+                    error!("UDP shard {} of {} is gone", i, sess);
+                    break;
+                }
             }
 
             trace!("UDP session local -> remote finished for {}", ss);
@@ -370,6 +442,7 @@ impl Dispatcher {
         tokio::spawn(async move {
             let _ = close_receiver.await;
             trace!("UDP close signal for {} received", s);
+            // the shards are stopped as they're dropped with t1
             t1.abort();
             t2.abort();
         });
@@ -378,99 +451,248 @@ impl Dispatcher {
     }
 }
 
-type OutboundPacketSender = tokio::sync::mpsc::Sender<UdpPacket>; // outbound packet sender
+/// Keep why the dial through the outbound failed for the API, under the
+/// member of the group it failed at, and tell the event subscribers about
+/// the failed connection.
+fn report_dial_error(
+    mgr: &ThreadSafeOutboundManager,
+    sess: &Session,
+    outbound_name: &str,
+    err: &std::io::Error,
+) {
+    let (proxy, err) = failed_member(err).unwrap_or((outbound_name, err));
+    let dial_error = DialError::new(err);
+    mgr.report_dial_error(proxy, dial_error.clone());
+    let mut chains = vec![proxy];
+    if proxy != outbound_name {
+        chains.push(outbound_name);
+    }
+    events::publish(
+        EventKind::DialFailed,
+        serde_json::json!({
+            "metadata": sess,
+            "chains": chains,
+            "error": dial_error,
+        }),
+    );
+}
+
+/// how long an outbound UDP session is kept without any packet sent
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_UDP_SHARDS: usize = 16;
+
+fn udp_shard_count() -> usize {
+    std::thread::available_parallelism()
+        .map_or(1, |x| x.get())
+        .min(MAX_UDP_SHARDS)
+}
+
+/// The shard of a packet, by its source alone, as the sessions are.
+fn udp_shard_index(hasher: &RandomState, packet: &UdpPacket, shards: usize) -> usize {
+    hasher.hash_one(&packet.src_addr) as usize % shards
+}
 
-struct TimeoutUdpSessionManager {
-    map: Arc<RwLock<OutboundHandleMap>>,
+/// What the shards of the UDP dispatch share
+struct UdpShardContext {
+    router: ThreadSafeRouter,
+    outbound_manager: ThreadSafeOutboundManager,
+    resolver: ThreadSafeDNSResolver,
+    mode: Arc<Mutex<RunMode>>,
+    manager: Arc<Manager>,
+}
 
-    cleaner: Option<JoinHandle<()>>,
+struct UdpShard {
+    tx: tokio::sync::mpsc::Sender<UdpPacket>,
+    handle: JoinHandle<()>,
 }
 
-impl Drop for TimeoutUdpSessionManager {
+impl Drop for UdpShard {
     fn drop(&mut self) {
-        trace!("dropping timeout udp session manager");
-        if let Some(x) = self.cleaner.take() {
-            x.abort()
-        }
+        self.handle.abort();
     }
 }
 
-impl TimeoutUdpSessionManager {
-    fn new() -> Self {
-        let map = Arc::new(RwLock::new(OutboundHandleMap::new()));
-        let timeout = Duration::from_secs(10);
-
-        let map_cloned = map.clone();
-
-        let cleaner = tokio::spawn(async move {
-            trace!("timeout udp session cleaner scanning");
-            let mut interval = tokio::time::interval(timeout);
-
-            loop {
-                interval.tick().await;
-                trace!("timeout udp session cleaner ticking");
-
-                let mut g = map_cloned.write().await;
-                let mut alived = 0;
-                let mut expired = 0;
-                g.0.retain(|k, x| {
-                    let (h1, h2, _, last) = x;
-                    let now = Instant::now();
-                    let alive = now.duration_since(*last) < timeout;
-                    if !alive {
-                        expired += 1;
-                        trace!("udp session expired: {:?}", k);
-                        h1.abort();
-                        h2.abort();
+/// Route the packets of a shard to their outbound sessions, owned by the
+/// shard alone.
+async fn udp_shard(
+    mut packets: tokio::sync::mpsc::Receiver<UdpPacket>,
+    sess: Session,
+    ctx: Arc<UdpShardContext>,
+    local: tokio::sync::mpsc::Sender<UdpPacket>,
+) {
+    let mut sessions = OutboundHandleMap::new();
+    let mut cleaner = tokio::time::interval(UDP_SESSION_TIMEOUT);
+
+    loop {
+        let packet = tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => packet,
+                None => break,
+            },
+            _ = cleaner.tick() => {
+                sessions.expire(UDP_SESSION_TIMEOUT);
+                continue;
+            }
+        };
+
+        let mut sess = sess.clone();
+        sess.source = packet.src_addr.clone().must_into_socket_addr();
+        sess.destination = packet.dst_addr.clone();
+
+        // populate fake ip for route matching
+        let sess = if ctx.resolver.fake_ip_enabled() {
+            trace!("looking up fake ip for {sess}");
+            match sess.destination {
+                crate::session::SocksAddr::Ip(addr) => {
+                    let ip = addr.ip();
+                    if ctx.resolver.is_fake_ip(ip).await {
+                        trace!("fake ip detected");
+                        let host = ctx.resolver.reverse_lookup(ip).await;
+                        match host {
+                            Some(host) => {
+                                trace!("fake ip resolved to {}", host);
+                                let mut sess = sess;
+                                sess.destination =
+                                    crate::session::SocksAddr::Domain(host, addr.port());
+                                sess
+                            }
+                            None => {
+                                error!("failed to reverse lookup fake ip: {}", ip);
+                                continue;
+                            }
+                        }
                     } else {
-                        alived += 1;
+                        sess
                     }
-                    alive
-                });
-                trace!(
-                    "timeout udp session cleaner finished, alived: {}, expired: {}",
-                    alived,
-                    expired
-                );
+                }
+                crate::session::SocksAddr::Domain(_, _) => sess,
             }
-        });
+        } else {
+            sess
+        };
 
-        Self {
-            map,
+        // mutate packet for fake ip
+        let mut packet = packet;
+        packet.dst_addr = sess.destination.clone();
+
+        let mut sess = sess;
+
+        let mode = *ctx.mode.lock().unwrap();
+
+        let (outbound_name, rule) = match mode {
+            RunMode::Global => (PROXY_GLOBAL, None),
+            RunMode::Rule => ctx.router.match_route(&sess).await,
+            RunMode::Direct => (PROXY_DIRECT, None),
+        };
+        ctx.router.find_process(&mut sess);
+
+        let outbound_name = outbound_name.to_string();
+
+        debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
+
+        let mgr = ctx.outbound_manager.clone();
+        let handler = mgr.get_outbound(&outbound_name).unwrap_or_else(|| {
+            debug!("unknown rule: {}, fallback to direct", outbound_name);
+            mgr.get_outbound(PROXY_DIRECT).unwrap()
+        });
 
-            cleaner: Some(cleaner),
+        // this is only expected to be socket addr as it's from local udp
+        let src = packet.src_addr.clone().must_into_socket_addr();
+        let sender = match sessions.get_outbound_sender_mut(&outbound_name, src) {
+            Some(sender) => {
+                debug!("reusing {} outbound datagram", sess);
+                sender
+            }
+            None => {
+                let (sender, packets) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+                let rule = rule.map(|x| (x.type_name().to_owned(), x.payload()));
+                let handle = tokio::spawn(udp_session(
+                    handler,
+                    outbound_name.clone(),
+                    sess,
+                    rule,
+                    ctx.clone(),
+                    packets,
+                    local.clone(),
+                ));
+                sessions.insert(&outbound_name, src, handle, sender.clone());
+                sender
+            }
+        };
+        if let Err(err) = sender.send(packet).await {
+            error!("failed to send packet to remote: {}", err);
         }
     }
+}
 
-    async fn insert(
-        &self,
-        outbound_name: &str,
-        src_addr: SocketAddr,
-        recv_handle: JoinHandle<()>,
-        send_handle: JoinHandle<()>,
-        sender: OutboundPacketSender,
-    ) {
-        let mut map = self.map.write().await;
-        map.insert(outbound_name, src_addr, recv_handle, send_handle, sender);
-    }
+/// Connect an outbound session, the packets sent to it meanwhile waiting in
+/// `packets`, then relay them both ways. It's off the shard so that the
+/// other sessions of the shard don't wait on the connect.
+async fn udp_session(
+    handler: AnyOutboundHandler,
+    outbound_name: String,
+    sess: Session,
+    rule: Option<(String, String)>,
+    ctx: Arc<UdpShardContext>,
+    mut packets: tokio::sync::mpsc::Receiver<UdpPacket>,
+    local: tokio::sync::mpsc::Sender<UdpPacket>,
+) {
+    let mgr = &ctx.outbound_manager;
+    debug!("building {} outbound datagram connecting", sess);
+    let outbound_datagram = match handler.connect_datagram(&sess, ctx.resolver.clone()).await {
+        Ok(v) => v,
+        Err(err) => {
+            error!("failed to connect outbound: {}", err);
+            report_dial_error(mgr, &sess, &outbound_name, &err);
+            return;
+        }
+    };
 
-    async fn get_outbound_sender_mut(
-        &self,
-        outbound_name: &str,
-        src_addr: SocketAddr,
-    ) -> Option<OutboundPacketSender> {
-        let mut map = self.map.write().await;
-        map.get_outbound_sender_mut(outbound_name, src_addr)
+    debug!("{} outbound datagram connected", sess);
+    // StreamExt has a chain too
+    let chain = ChainedDatagram::chain(outbound_datagram.as_ref());
+    if let Some(name) = chain.last_hop().await {
+        mgr.report_used(&name);
     }
+
+    let outbound_datagram =
+        TrackedDatagram::new(outbound_datagram, ctx.manager.clone(), sess.clone(), rule).await;
+
+    let (mut remote_w, mut remote_r) = outbound_datagram.split();
+
+    let remote_to_local = async {
+        while let Some(packet) = remote_r.next().await {
+            // NAT
+            let mut packet = packet;
+            packet.src_addr = sess.destination.clone();
+            packet.dst_addr = sess.source.into();
+
+            debug!("UDP NAT for packet: {:?}, session: {}", packet, sess);
+            match local.send(packet).await {
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("failed to send packet to local: {}", err);
+                }
+            }
+        }
+    };
+    let local_to_remote = async {
+        while let Some(packet) = packets.recv().await {
+            match remote_w.send(packet).await {
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("failed to send packet to remote: {}", err);
+                }
+            }
+        }
+    };
+    futures::future::join(remote_to_local, local_to_remote).await;
 }
 
+type OutboundPacketSender = tokio::sync::mpsc::Sender<UdpPacket>; // outbound packet sender
+
 type OutboundHandleKey = (String, SocketAddr);
-type OutboundHandleVal = (
-    JoinHandle<()>,
-    JoinHandle<()>,
-    OutboundPacketSender,
-    Instant,
-);
+type OutboundHandleVal = (JoinHandle<()>, OutboundPacketSender, Instant);
 
 struct OutboundHandleMap(HashMap<OutboundHandleKey, OutboundHandleVal>);
 
@@ -483,16 +705,42 @@ impl OutboundHandleMap {
         &mut self,
         outbound_name: &str,
         src_addr: SocketAddr,
-        recv_handle: JoinHandle<()>,
-        send_handle: JoinHandle<()>,
+        handle: JoinHandle<()>,
         sender: OutboundPacketSender,
     ) {
-        self.0.insert(
+        // replacing a session that failed to connect
+        if let Some((old, ..)) = self.0.insert(
             (outbound_name.to_string(), src_addr),
-            (recv_handle, send_handle, sender, Instant::now()),
+            (handle, sender, Instant::now()),
+        ) {
+            old.abort();
+        }
+    }
+
+    fn expire(&mut self, timeout: Duration) {
+        let mut alived = 0;
+        let mut expired = 0;
+        self.0.retain(|k, x| {
+            let (handle, _, last) = x;
+            let alive = last.elapsed() < timeout;
+            if !alive {
+                expired += 1;
+                trace!("udp session expired: {:?}", k);
+                handle.abort();
+            } else {
+                alived += 1;
+            }
+            alive
+        });
+        trace!(
+            "timeout udp session cleaner finished, alived: {}, expired: {}",
+            alived,
+            expired
         );
     }
 
+    /// The sender of the session, `None` if there's none or it failed to
+    /// connect, so that it's connected again.
     fn get_outbound_sender_mut(
         &mut self,
         outbound_name: &str,
@@ -500,7 +748,8 @@ impl OutboundHandleMap {
     ) -> Option<OutboundPacketSender> {
         self.0
             .get_mut(&(outbound_name.to_owned(), src_addr))
-            .map(|(_, _, sender, last)| {
+            .filter(|(_, sender, _)| !sender.is_closed())
+            .map(|(_, sender, last)| {
                 trace!(
                     "updating last access time for outbound {:?}",
                     (outbound_name, src_addr)
@@ -517,9 +766,45 @@ impl Drop for OutboundHandleMap {
             "dropping inner outbound handle map that has {} sessions",
             self.0.len()
         );
-        for (_, (recv_handle, send_handle, _, _)) in self.0.drain() {
-            recv_handle.abort();
-            send_handle.abort();
+        for (_, (handle, ..)) in self.0.drain() {
+            handle.abort();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{hash_map::RandomState, HashSet},
+        net::IpAddr,
+    };
+
+    use crate::proxy::datagram::UdpPacket;
+
+    use super::udp_shard_index;
+
+    #[test]
+    fn test_udp_shard_index() {
+        let hasher = RandomState::new();
+        let packet = |src: u16, dst: u16| {
+            UdpPacket::new(
+                vec![],
+                ("10.0.0.2".parse::<IpAddr>().unwrap(), src).into(),
+                ("1.1.1.1".parse::<IpAddr>().unwrap(), dst).into(),
+            )
+        };
+
+        // all the destinations of a socket are on one shard, with one
+        // session per outbound
+        let shards = (1..=64)
+            .map(|x| udp_shard_index(&hasher, &packet(5000, x), 16))
+            .collect::<HashSet<_>>();
+        assert_eq!(shards.len(), 1);
+        // while the sockets are spread
+        let shards = (1..=64)
+            .map(|x| udp_shard_index(&hasher, &packet(x, 53), 16))
+            .collect::<HashSet<_>>();
+        assert!(shards.len() > 1);
+        assert!(shards.iter().all(|x| *x < 16));
+    }
+}
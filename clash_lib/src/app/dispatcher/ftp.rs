@@ -0,0 +1,298 @@
+//! Passive mode FTP through the proxies
+//!
+//! The data connection of a passive mode session is a new connection to the
+//! address in the PASV/EPSV response, which the rules may well send out
+//! another way than the control connection, and most servers only accept
+//! data connections from the IP the control connection came from. So the
+//! responses on the control connections are watched, and the data connection
+//! announced goes through the same proxy as its control connection.
+//!
+//! A data connection is only pinned for the client of the control
+//! connection, and a PASV response announcing another IP than the server's
+//! is taken to be on the server, as most clients do.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+use crate::session::{Session, SocksAddr};
+
+const FTP_PORT: u16 = 21;
+/// how long an announced data connection is waited for
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_EXPECTED: usize = 1024;
+/// a response line longer than this is not a PASV/EPSV one
+const MAX_LINE_LEN: usize = 512;
+
+/// The data connections announced, keyed by the client and the destination.
+pub struct FtpHelper {
+    expected: Arc<Mutex<lru_time_cache::LruCache<String, String>>>,
+}
+
+impl Default for FtpHelper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FtpHelper {
+    pub fn new() -> Self {
+        Self {
+            expected: Arc::new(Mutex::new(
+                lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                    EXPECT_TIMEOUT,
+                    MAX_EXPECTED,
+                ),
+            )),
+        }
+    }
+
+    /// Watch the session if it looks like an FTP control connection
+    /// going through `proxy`.
+    pub fn watch(&self, sess: &Session, proxy: String) -> Option<Watch> {
+        if sess.destination.port() != FTP_PORT {
+            return None;
+        }
+        Some(Watch {
+            expected: self.expected.clone(),
+            client: sess.source.ip(),
+            control: sess.destination.clone(),
+            server: sess.destination_ip(),
+            proxy,
+            line: Vec::new(),
+        })
+    }
+
+    /// The proxy of the control connection, if the session is a data
+    /// connection announced to its client.
+    pub fn take(&self, sess: &Session) -> Option<String> {
+        self.expected
+            .lock()
+            .unwrap()
+            .remove(&key(sess.source.ip(), &sess.destination))
+    }
+}
+
+fn key(client: IpAddr, destination: &SocksAddr) -> String {
+    format!("{} {}", client, destination)
+}
+
+pub struct Watch {
+    expected: Arc<Mutex<lru_time_cache::LruCache<String, String>>>,
+    client: IpAddr,
+    control: SocksAddr,
+    /// the IP of the server, if known
+    server: Option<IpAddr>,
+    proxy: String,
+    line: Vec<u8>,
+}
+
+impl Watch {
+    /// Feed the bytes the server sent.
+    fn feed(&mut self, data: &[u8]) {
+        for b in data {
+            if *b != b'\n' {
+                if self.line.len() < MAX_LINE_LEN {
+                    self.line.push(*b);
+                }
+                continue;
+            }
+            if let Some(data) = parse_response(&self.line, &self.control, self.server) {
+                debug!(
+                    "ftp data connection to {} expected through {}",
+                    data, self.proxy
+                );
+                self.expected
+                    .lock()
+                    .unwrap()
+                    .insert(key(self.client, &data), self.proxy.clone());
+            }
+            self.line.clear();
+        }
+    }
+}
+
+/// The data address in a PASV or EPSV response line, on the control host
+/// unless the PASV one is the IP of the server.
+fn parse_response(line: &[u8], control: &SocksAddr, server: Option<IpAddr>) -> Option<SocksAddr> {
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    if let Some(text) = line.strip_prefix("227 ") {
+        // 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+        let n = text
+            .split(|c: char| !c.is_ascii_digit() && c != ',')
+            .find_map(|x| {
+                let n = x
+                    .split(',')
+                    .map(|x| x.parse::<u8>().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                (n.len() == 6).then_some(n)
+            })?;
+        let ip = IpAddr::from(Ipv4Addr::new(n[0], n[1], n[2], n[3]));
+        let port = u16::from_be_bytes([n[4], n[5]]);
+        if server == Some(ip) {
+            return Some(SocksAddr::Ip(SocketAddr::new(ip, port)));
+        }
+        debug!("ftp server {} announced another ip {}", control, ip);
+        Some(on_control(control, port))
+    } else if let Some(text) = line.strip_prefix("229 ") {
+        // 229 Entering Extended Passive Mode (|||port|)
+        let inner = &text[text.find('(')? + 1..text.rfind(')')?];
+        let delimiter = inner.chars().next()?;
+        let port = inner.split(delimiter).nth(3)?.parse::<u16>().ok()?;
+        Some(on_control(control, port))
+    } else {
+        None
+    }
+}
+
+fn on_control(control: &SocksAddr, port: u16) -> SocksAddr {
+    match control {
+        SocksAddr::Ip(addr) => SocksAddr::Ip(SocketAddr::new(addr.ip(), port)),
+        SocksAddr::Domain(host, _) => SocksAddr::Domain(host.clone(), port),
+    }
+}
+
+/// The client side of a relayed connection, watching what's written to the
+/// client if it's an FTP control connection.
+pub struct FtpControlStream<S> {
+    inner: S,
+    watch: Option<Watch>,
+}
+
+impl<S> FtpControlStream<S> {
+    pub fn new(inner: S, watch: Option<Watch>) -> Self {
+        Self { inner, watch }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FtpControlStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FtpControlStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(watch) = this.watch.as_mut() {
+            watch.feed(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::{Session, SocksAddr};
+
+    use super::{parse_response, FtpHelper};
+
+    #[test]
+    fn test_parse_response() {
+        let control = SocksAddr::Domain("ftp.example.com".to_owned(), 21);
+        let server = Some("1.2.3.4".parse().unwrap());
+        assert_eq!(
+            parse_response(
+                b"227 Entering Passive Mode (1,2,3,4,195,80).\r",
+                &control,
+                server
+            ),
+            Some(SocksAddr::Ip("1.2.3.4:50000".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_response(b"227 =1,2,3,4,0,21", &control, server),
+            Some(SocksAddr::Ip("1.2.3.4:21".parse().unwrap()))
+        );
+        // not the server, so on the control host
+        assert_eq!(
+            parse_response(b"227 =10,0,0,1,0,21", &control, server),
+            Some(SocksAddr::Domain("ftp.example.com".to_owned(), 21))
+        );
+        assert_eq!(
+            parse_response(b"227 =1,2,3,4,0,21", &control, None),
+            Some(SocksAddr::Domain("ftp.example.com".to_owned(), 21))
+        );
+        assert_eq!(
+            parse_response(
+                b"229 Entering Extended Passive Mode (|||6446|)\r",
+                &control,
+                server
+            ),
+            Some(SocksAddr::Domain("ftp.example.com".to_owned(), 6446))
+        );
+        assert_eq!(parse_response(b"220 ready\r", &control, server), None);
+    }
+
+    #[test]
+    fn test_watch() {
+        let helper = FtpHelper::new();
+        let control = Session {
+            source: "192.168.1.2:40000".parse().unwrap(),
+            destination: SocksAddr::Ip("1.2.3.4:21".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut watch = helper.watch(&control, "ss-hk".to_owned()).unwrap();
+
+        watch.feed(b"230 logged in\r\n229 Entering Extended ");
+        watch.feed(b"Passive Mode (|||6446|)\r\n");
+        watch.feed(b"227 Entering Passive Mode (5,6,7,8,0,80)\r\n");
+
+        let data = |source: &str, destination: &str| Session {
+            source: source.parse().unwrap(),
+            destination: SocksAddr::Ip(destination.parse().unwrap()),
+            ..Default::default()
+        };
+        // only for the client of the control connection
+        assert_eq!(
+            helper.take(&data("192.168.1.3:40001", "1.2.3.4:6446")),
+            None
+        );
+        assert_eq!(
+            helper
+                .take(&data("192.168.1.2:40001", "1.2.3.4:6446"))
+                .as_deref(),
+            Some("ss-hk")
+        );
+        assert_eq!(
+            helper.take(&data("192.168.1.2:40001", "1.2.3.4:6446")),
+            None
+        );
+        // another host announced
+        assert_eq!(helper.take(&data("192.168.1.2:40002", "5.6.7.8:80")), None);
+        assert_eq!(
+            helper
+                .take(&data("192.168.1.2:40002", "1.2.3.4:80"))
+                .as_deref(),
+            Some("ss-hk")
+        );
+
+        let mut http = control.clone();
+        http.destination = SocksAddr::Ip("1.2.3.4:80".parse().unwrap());
+        assert!(helper.watch(&http, "x".to_owned()).is_none());
+    }
+}
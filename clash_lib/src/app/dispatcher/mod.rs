@@ -1,9 +1,13 @@
+mod capture;
 mod dispatcher_impl;
+mod ftp;
+mod shaping;
 mod statistics_manager;
 mod tracked;
 
 pub use dispatcher_impl::Dispatcher;
 pub use statistics_manager::Manager as StatisticsManager;
+pub use statistics_manager::{ConnectionFilter, ConnectionSort, TopBy};
 pub use tracked::BoxedChainedDatagram;
 pub use tracked::BoxedChainedStream;
 pub use tracked::ChainedDatagram;
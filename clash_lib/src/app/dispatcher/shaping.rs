@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{ready, Future};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::internal::config::ShapingLimit;
+
+/// A token bucket shared by all the connections of a shaping class.
+/// Tokens may go negative after a read/write larger than the balance,
+/// which is paid back before the next one is allowed.
+struct TokenBucket {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Returns the number of bytes allowed now, or the time to wait.
+    fn allowance(&self) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        // allow bursts up to one second worth of traffic
+        state.0 = (state.0 + elapsed * self.rate as f64).min(self.rate as f64);
+        state.1 = now;
+
+        if state.0 >= 1.0 {
+            Ok(state.0 as usize)
+        } else {
+            Err(Duration::from_secs_f64((1.0 - state.0) / self.rate as f64))
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        self.state.lock().unwrap().0 -= n as f64;
+    }
+}
+
+#[derive(Default)]
+pub struct ShapingClass {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+/// The traffic shaping classes, looked up by the class attached to the rule
+#[derive(Default)]
+pub struct Shaper {
+    classes: HashMap<String, Arc<ShapingClass>>,
+}
+
+impl Shaper {
+    pub fn new(limits: HashMap<String, ShapingLimit>) -> Self {
+        Self {
+            classes: limits
+                .into_iter()
+                .map(|(name, limit)| {
+                    (
+                        name,
+                        Arc::new(ShapingClass {
+                            upload: limit.upload.map(TokenBucket::new),
+                            download: limit.download.map(TokenBucket::new),
+                        }),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, class: &str) -> Option<Arc<ShapingClass>> {
+        self.classes.get(class).cloned()
+    }
+}
+
+/// Wraps the inbound side of a connection, reading from it is the upload
+/// and writing to it is the download.
+pub struct ShapedStream<S> {
+    inner: S,
+    class: Option<Arc<ShapingClass>>,
+    read_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    write_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> ShapedStream<S> {
+    pub fn new(inner: S, class: Option<Arc<ShapingClass>>) -> Self {
+        Self {
+            inner,
+            class,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Wait for the bucket to have tokens, returns the number of bytes allowed.
+fn poll_allowance(
+    bucket: &TokenBucket,
+    delay: &mut Option<Pin<Box<tokio::time::Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(d) = delay.as_mut() {
+            ready!(d.as_mut().poll(cx));
+            *delay = None;
+        }
+        match bucket.allowance() {
+            Ok(n) => return Poll::Ready(n),
+            Err(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShapedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let bucket = match this.class.as_ref().and_then(|x| x.upload.as_ref()) {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+
+        let allowed = ready!(poll_allowance(bucket, &mut this.read_delay, cx));
+
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+
+        // SAFETY: the bytes were initialized by the inner read
+        unsafe {
+            buf.assume_init(n);
+        }
+        buf.advance(n);
+        bucket.consume(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShapedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let bucket = match this.class.as_ref().and_then(|x| x.download.as_ref()) {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+
+        let allowed = ready!(poll_allowance(bucket, &mut this.write_delay, cx));
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..buf.len().min(allowed)]))?;
+        bucket.consume(n);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.allowance(), Ok(1000));
+        bucket.consume(1500);
+        let wait = bucket.allowance().unwrap_err();
+        assert!(wait.as_millis() > 400 && wait.as_millis() <= 501);
+    }
+}
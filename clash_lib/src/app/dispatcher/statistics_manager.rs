@@ -1,18 +1,25 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     sync::{
-        atomic::{AtomicI64, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
-use chrono::Utc;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use tokio::sync::{oneshot::Sender, Mutex, RwLock};
 
-use crate::session::Session;
+use crate::session::{Network, Session, Type};
 
-use super::tracked::Tracked;
+use super::{
+    capture::{self, Capture, Direction},
+    tracked::Tracked,
+};
 
 #[derive(Default, Clone, Debug)]
 pub struct ProxyChain(Arc<RwLock<Vec<String>>>);
@@ -22,6 +29,12 @@ impl ProxyChain {
         let mut chain = self.0.write().await;
         chain.push(s);
     }
+
+    /// the proxy the connection finally goes through,
+    /// which is the first one appended
+    pub async fn last_hop(&self) -> Option<String> {
+        self.0.read().await.first().cloned()
+    }
 }
 
 #[derive(Serialize, Default)]
@@ -47,6 +60,25 @@ pub struct TrackerInfo {
     pub proxy_chain_holder: ProxyChain,
     #[serde(skip)]
     pub session_holder: Session,
+    #[serde(skip)]
+    pub capture: std::sync::Mutex<Option<Capture>>,
+    /// whether `capture` is set, so the relay doesn't lock it otherwise
+    #[serde(skip)]
+    pub capturing: AtomicBool,
+}
+
+impl TrackerInfo {
+    /// Hand the relayed data to the capture running on the connection, if any.
+    pub fn captured(&self, direction: Direction, data: &[u8]) {
+        if !self.capturing.load(Ordering::Acquire) {
+            return;
+        }
+        let mut capture = self.capture.lock().unwrap();
+        if capture.as_ref().is_some_and(|x| !x.push(direction, data)) {
+            *capture = None;
+            self.capturing.store(false, Ordering::Release);
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -55,30 +87,178 @@ pub struct Snapshot {
     download_total: i64,
     upload_total: i64,
     connections: Vec<TrackerInfo>,
+    /// the number of connections matching the filter, before the paging
+    total: usize,
+    reaped: ReapedStatistics,
+}
+
+/// Narrows down the connections of a snapshot, so a dashboard polling a
+/// busy instance doesn't get all of them each time.
+#[derive(Default, Debug)]
+pub struct ConnectionFilter {
+    /// part of the destination host
+    pub host: Option<String>,
+    /// a proxy in the chain
+    pub proxy: Option<String>,
+    /// the inbound type, as the `type` of the metadata, e.g. `Socks5`
+    pub inbound: Option<Type>,
+    pub min_duration: Option<Duration>,
+    pub sort: Option<ConnectionSort>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionSort {
+    /// the most uploaded first
+    Upload,
+    /// the most downloaded first
+    Download,
+    /// the newest first
+    Start,
+}
+
+impl ConnectionFilter {
+    fn matches(&self, t: &TrackerInfo, chain: &[String], now: DateTime<Utc>) -> bool {
+        let sess = &t.session_holder;
+        self.host.as_ref().map_or(true, |x| {
+            sess.destination
+                .host()
+                .to_ascii_lowercase()
+                .contains(&x.to_ascii_lowercase())
+        }) && self
+            .proxy
+            .as_ref()
+            .map_or(true, |x| chain.iter().any(|p| p == x))
+            && self.inbound.map_or(true, |x| sess.typ == x)
+            && self.min_duration.map_or(true, |x| {
+                (now - t.start_time)
+                    .to_std()
+                    .is_ok_and(|elapsed| elapsed >= x)
+            })
+    }
+}
+
+/// Number of connections closed by us because they were stuck
+#[derive(Serialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReapedStatistics {
+    /// no traffic for the idle timeout
+    pub idle: u64,
+    /// one side closed and the other didn't follow
+    pub half_open: u64,
+    /// the outbound handshake didn't complete in time
+    pub handshake: u64,
+}
+
+/// Why a connection was reaped, see [`ReapedStatistics`]
+#[derive(Debug, Clone, Copy)]
+pub enum ReapReason {
+    Idle,
+    HalfOpen,
+    Handshake,
+}
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct ClientStatistics {
+    pub upload: u64,
+    pub download: u64,
+    pub connections: u64,
+}
+
+impl ClientStatistics {
+    fn add(&mut self, t: &TrackerInfo) {
+        self.upload += t.upload_total.load(Ordering::Relaxed);
+        self.download += t.download_total.load(Ordering::Relaxed);
+        self.connections += 1;
+    }
 }
 
 type ConnectionMap = HashMap<uuid::Uuid, (Tracked, Sender<()>)>;
+type ClientMap = HashMap<IpAddr, ClientStatistics>;
+
+/// how long the closed connections are kept for the roll-ups
+const HISTORY_RETENTION: Duration = Duration::from_secs(24 * 3600);
+const HISTORY_MAX_LEN: usize = 100_000;
+/// the clients whose closed connections are kept, the ones not seen for
+/// the retention going first
+const MAX_CLIENTS: usize = 4096;
+
+struct ClosedConnection {
+    closed_at: DateTime<Utc>,
+    host: String,
+    /// the proxy it finally went through
+    proxy: String,
+    /// the process that opened it, if looked up
+    process: Option<String>,
+    upload: u64,
+    download: u64,
+}
+
+struct Closed {
+    /// traffic keyed by the source address
+    clients: lru_time_cache::LruCache<IpAddr, ClientStatistics>,
+    /// the most recent last
+    history: VecDeque<ClosedConnection>,
+}
+
+impl Default for Closed {
+    fn default() -> Self {
+        Self {
+            clients: lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                HISTORY_RETENTION,
+                MAX_CLIENTS,
+            ),
+            history: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TopBy {
+    Host,
+    Proxy,
+    /// the path of the process, the connections of an unknown one skipped
+    Process,
+}
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct TopEntry {
+    pub name: String,
+    pub upload: u64,
+    pub download: u64,
+    pub connections: u64,
+}
 
 pub struct Manager {
     connections: Arc<Mutex<ConnectionMap>>,
+    closed: Arc<Mutex<Closed>>,
     upload_temp: AtomicI64,
     download_temp: AtomicI64,
     upload_blip: AtomicI64,
     download_blip: AtomicI64,
     upload_total: AtomicI64,
     download_total: AtomicI64,
+    reaped_idle: AtomicU64,
+    reaped_half_open: AtomicU64,
+    reaped_handshake: AtomicU64,
 }
 
 impl Manager {
     pub fn new() -> Arc<Self> {
         let v = Arc::new(Self {
             connections: Arc::new(Mutex::new(HashMap::new())),
+            closed: Default::default(),
             upload_temp: AtomicI64::new(0),
             download_temp: AtomicI64::new(0),
             upload_blip: AtomicI64::new(0),
             download_blip: AtomicI64::new(0),
             upload_total: AtomicI64::new(0),
             download_total: AtomicI64::new(0),
+            reaped_idle: AtomicU64::new(0),
+            reaped_half_open: AtomicU64::new(0),
+            reaped_handshake: AtomicU64::new(0),
         });
         let c = v.clone();
         tokio::spawn(async move {
@@ -97,33 +277,164 @@ impl Manager {
     /// this method is not async because it is called in Drop.
     pub fn untrack(&self, id: uuid::Uuid) {
         let connections = self.connections.clone();
+        let closed = self.closed.clone();
 
         tokio::spawn(async move {
             let mut connections = connections.lock().await;
-            connections.remove(&id);
+            if let Some((tracked, _)) = connections.remove(&id) {
+                Self::record_closed(&closed, &tracked).await;
+            }
         });
     }
 
-    pub async fn close(&self, id: uuid::Uuid) {
-        let connections = self.connections.clone();
+    /// Write what a TCP connection relays in the next `duration` to a pcap
+    /// file at `path`, returns false if there's no such connection.
+    pub async fn capture(
+        &self,
+        id: uuid::Uuid,
+        path: PathBuf,
+        duration: Duration,
+    ) -> io::Result<bool> {
+        let t = match self.connections.lock().await.get(&id) {
+            Some((tracked, _)) => tracked.tracker_info(),
+            None => return Ok(false),
+        };
 
-        tokio::spawn(async move {
-            let mut connections = connections.lock().await;
-            if let Some((_, close_notify)) = connections.remove(&id) {
+        let sess = &t.session_holder;
+        if sess.network != Network::Tcp {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only TCP connections can be captured",
+            ));
+        }
+        // the remote of a domain destination is unknown here
+        let remote_ip = sess.destination.ip().unwrap_or(match sess.source {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let remote = SocketAddr::new(remote_ip, sess.destination.port());
+
+        let c = capture::start(path, sess.source, remote, duration).await?;
+        t.capture.lock().unwrap().replace(c);
+        t.capturing.store(true, Ordering::Release);
+        Ok(true)
+    }
+
+    /// Close a connection, false if it's not found.
+    pub async fn close(&self, id: uuid::Uuid) -> bool {
+        let mut connections = self.connections.lock().await;
+        match connections.remove(&id) {
+            Some((tracked, close_notify)) => {
+                Self::record_closed(&self.closed, &tracked).await;
                 let _ = close_notify.send(());
+                true
             }
-        });
+            None => false,
+        }
     }
 
     pub async fn close_all(&self) {
         let connections = self.connections.clone();
 
         let mut connections = connections.lock().await;
-        for (_, (_, close_notify)) in connections.drain() {
+        for (_, (tracked, close_notify)) in connections.drain() {
+            Self::record_closed(&self.closed, &tracked).await;
             let _ = close_notify.send(());
         }
     }
 
+    async fn record_closed(closed: &Mutex<Closed>, tracked: &Tracked) {
+        let t = tracked.tracker_info();
+        let proxy = t.proxy_chain_holder.last_hop().await.unwrap_or_default();
+        let now = Utc::now();
+
+        let mut closed = closed.lock().await;
+        closed
+            .clients
+            .entry(t.session_holder.source.ip())
+            .or_insert_with(Default::default)
+            .add(&t);
+
+        let history = &mut closed.history;
+        while history.front().is_some_and(|x| {
+            history.len() >= HISTORY_MAX_LEN
+                || (now - x.closed_at).to_std().unwrap_or_default() > HISTORY_RETENTION
+        }) {
+            history.pop_front();
+        }
+        history.push_back(ClosedConnection {
+            closed_at: now,
+            host: t.session_holder.destination.host(),
+            proxy,
+            process: t.session_holder.process_path.clone(),
+            upload: t.upload_total.load(Ordering::Relaxed),
+            download: t.download_total.load(Ordering::Relaxed),
+        });
+    }
+
+    /// The hosts, proxies or processes with the most traffic of the
+    /// connections closed in the last `window`, the most first.
+    pub async fn top(&self, by: TopBy, window: Duration, limit: usize) -> Vec<TopEntry> {
+        let now = Utc::now();
+        let mut entries: HashMap<&str, TopEntry> = HashMap::new();
+
+        let closed = self.closed.lock().await;
+        for c in closed
+            .history
+            .iter()
+            .rev()
+            .take_while(|x| (now - x.closed_at).to_std().unwrap_or_default() <= window)
+        {
+            let name = match by {
+                TopBy::Host => c.host.as_str(),
+                TopBy::Proxy => c.proxy.as_str(),
+                TopBy::Process => match &c.process {
+                    Some(process) => process.as_str(),
+                    None => continue,
+                },
+            };
+            let e = entries.entry(name).or_insert_with(|| TopEntry {
+                name: name.to_owned(),
+                ..Default::default()
+            });
+            e.upload += c.upload;
+            e.download += c.download;
+            e.connections += 1;
+        }
+
+        let mut entries: Vec<TopEntry> = entries.into_values().collect();
+        entries.sort_by_key(|x| std::cmp::Reverse(x.upload + x.download));
+        entries.truncate(limit);
+        entries
+    }
+
+    /// Traffic totals grouped by the client (source) address,
+    /// including both closed and live connections, the closed ones of the
+    /// clients seen in the last 24 hours.
+    pub async fn client_statistics(&self) -> HashMap<String, ClientStatistics> {
+        let mut clients: ClientMap = self
+            .closed
+            .lock()
+            .await
+            .clients
+            .peek_iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        let conns = self.connections.lock().await;
+        for (_, v) in conns.iter() {
+            let t = v.0.tracker_info();
+            clients
+                .entry(t.session_holder.source.ip())
+                .or_default()
+                .add(&t);
+        }
+
+        clients
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect()
+    }
+
     pub fn push_uploaded(&self, n: usize) {
         self.upload_temp
             .fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
@@ -147,12 +458,33 @@ impl Manager {
         )
     }
 
-    pub async fn snapshot(&self) -> Snapshot {
+    pub fn record_reaped(&self, reason: ReapReason) {
+        let counter = match reason {
+            ReapReason::Idle => &self.reaped_idle,
+            ReapReason::HalfOpen => &self.reaped_half_open,
+            ReapReason::Handshake => &self.reaped_handshake,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reaped_statistics(&self) -> ReapedStatistics {
+        ReapedStatistics {
+            idle: self.reaped_idle.load(Ordering::Relaxed),
+            half_open: self.reaped_half_open.load(Ordering::Relaxed),
+            handshake: self.reaped_handshake.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn snapshot_filtered(&self, filter: &ConnectionFilter) -> Snapshot {
         let mut connections = vec![];
+        let now = Utc::now();
         let conns = self.connections.lock().await;
         for (_, v) in conns.iter() {
             let t = v.0.tracker_info();
             let chain = t.proxy_chain_holder.0.read().await;
+            if !filter.matches(&t, &chain, now) {
+                continue;
+            }
             connections.push(TrackerInfo {
                 uuid: t.uuid,
                 upload_total: AtomicU64::new(t.upload_total.load(Ordering::Acquire)),
@@ -165,6 +497,27 @@ impl Manager {
                 ..Default::default()
             });
         }
+        drop(conns);
+
+        let total = connections.len();
+        // the oldest first, so the pages don't shift between the snapshots
+        // but by the connections opened and closed in the meantime
+        connections.sort_by_key(|x| (x.start_time, x.uuid));
+        match filter.sort {
+            Some(ConnectionSort::Upload) => connections
+                .sort_by_key(|x| std::cmp::Reverse(x.upload_total.load(Ordering::Relaxed))),
+            Some(ConnectionSort::Download) => connections
+                .sort_by_key(|x| std::cmp::Reverse(x.download_total.load(Ordering::Relaxed))),
+            Some(ConnectionSort::Start) => {
+                connections.sort_by_key(|x| std::cmp::Reverse(x.start_time))
+            }
+            None => {}
+        }
+        let connections = connections
+            .into_iter()
+            .skip(filter.offset)
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .collect();
 
         Snapshot {
             download_total: self
@@ -172,6 +525,8 @@ impl Manager {
                 .load(std::sync::atomic::Ordering::Relaxed),
             upload_total: self.upload_total.load(std::sync::atomic::Ordering::Relaxed),
             connections,
+            total,
+            reaped: self.reaped_statistics(),
         }
     }
 
@@ -200,3 +555,206 @@ impl Manager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use crate::{
+        app::dispatcher::tracked::{ChainedStream, ChainedStreamWrapper, TrackedStream},
+        session::{Session, SocksAddr, Type},
+    };
+
+    use super::{ClientStatistics, ConnectionFilter, ConnectionSort, Manager, TopBy, MAX_CLIENTS};
+
+    fn session(host: &str, typ: Type) -> Session {
+        Session {
+            typ,
+            destination: SocksAddr::try_from((host.to_owned(), 443)).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    async fn track(
+        mgr: &std::sync::Arc<Manager>,
+        sess: Session,
+        proxy: &str,
+    ) -> (TrackedStream, DuplexStream) {
+        let (stream, peer) = tokio::io::duplex(1024);
+        let stream = ChainedStreamWrapper::new(stream);
+        stream.append_to_chain(proxy).await;
+        let stream = TrackedStream::new(Box::new(stream), mgr.clone(), sess, None).await;
+        (stream, peer)
+    }
+
+    /// the hosts of the connections, as the API serves them
+    fn hosts(snapshot: &super::Snapshot) -> Vec<String> {
+        let snapshot = serde_json::to_value(snapshot).unwrap();
+        snapshot["connections"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["metadata"]["host"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_filtered() {
+        let mgr = Manager::new();
+        let mut streams = vec![];
+        for (host, typ, proxy) in [
+            ("a.example.com", Type::Socks5, "DIRECT"),
+            ("b.example.com", Type::Http, "proxy"),
+            ("c.example.org", Type::Socks5, "proxy"),
+            ("d.example.com", Type::Tun, "DIRECT"),
+        ] {
+            streams.push(track(&mgr, session(host, typ), proxy).await);
+            // distinct start times
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        streams[1].0.write_all(b"hello").await.unwrap();
+        streams[2].0.write_all(b"hi").await.unwrap();
+
+        // the oldest first, the same page each time
+        let filter = ConnectionFilter {
+            offset: 1,
+            limit: Some(2),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            let snapshot = mgr.snapshot_filtered(&filter).await;
+            assert_eq!(snapshot.total, 4);
+            assert_eq!(hosts(&snapshot), ["b.example.com", "c.example.org"]);
+        }
+
+        let filter = ConnectionFilter {
+            host: Some("EXAMPLE.COM".to_owned()),
+            sort: Some(ConnectionSort::Upload),
+            ..Default::default()
+        };
+        let snapshot = mgr.snapshot_filtered(&filter).await;
+        assert_eq!(snapshot.total, 3);
+        assert_eq!(
+            hosts(&snapshot),
+            ["b.example.com", "a.example.com", "d.example.com"]
+        );
+
+        let filter = ConnectionFilter {
+            inbound: Some(Type::Socks5),
+            sort: Some(ConnectionSort::Start),
+            ..Default::default()
+        };
+        let snapshot = mgr.snapshot_filtered(&filter).await;
+        assert_eq!(hosts(&snapshot), ["c.example.org", "a.example.com"]);
+
+        let filter = ConnectionFilter {
+            proxy: Some("proxy".to_owned()),
+            min_duration: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(mgr.snapshot_filtered(&filter).await.total, 0);
+        let filter = ConnectionFilter {
+            proxy: Some("proxy".to_owned()),
+            ..Default::default()
+        };
+        let snapshot = mgr.snapshot_filtered(&filter).await;
+        assert_eq!(hosts(&snapshot), ["b.example.com", "c.example.org"]);
+    }
+
+    #[tokio::test]
+    async fn test_top() {
+        let mgr = Manager::new();
+        let mut streams = vec![];
+        for (host, process, proxy, upload) in [
+            ("a.example.com", Some("/usr/bin/curl"), "proxy", 10),
+            ("b.example.com", Some("/usr/bin/curl"), "DIRECT", 20),
+            ("a.example.com", Some("/usr/bin/wget"), "proxy", 40),
+            ("c.example.com", None, "proxy", 5),
+        ] {
+            let sess = Session {
+                process_path: process.map(ToOwned::to_owned),
+                ..session(host, Type::Socks5)
+            };
+            let mut stream = track(&mgr, sess, proxy).await;
+            stream.0.write_all(&vec![0; upload]).await.unwrap();
+            streams.push(stream);
+        }
+        // only the closed connections count
+        assert!(mgr
+            .top(TopBy::Host, Duration::from_secs(60), 10)
+            .await
+            .is_empty());
+        mgr.close_all().await;
+
+        let top = |by, limit| {
+            let mgr = mgr.clone();
+            async move {
+                mgr.top(by, Duration::from_secs(60), limit)
+                    .await
+                    .into_iter()
+                    .map(|x| (x.name, x.upload, x.connections))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            top(TopBy::Host, 2).await,
+            [
+                ("a.example.com".to_owned(), 50, 2),
+                ("b.example.com".to_owned(), 20, 1)
+            ]
+        );
+        assert_eq!(
+            top(TopBy::Proxy, 10).await,
+            [("proxy".to_owned(), 55, 3), ("DIRECT".to_owned(), 20, 1)]
+        );
+        // the connection of an unknown process isn't counted
+        assert_eq!(
+            top(TopBy::Process, 10).await,
+            [
+                ("/usr/bin/wget".to_owned(), 40, 1),
+                ("/usr/bin/curl".to_owned(), 30, 2)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_statistics() {
+        let mgr = Manager::new();
+        let client = |source: &str| Session {
+            source: source.parse().unwrap(),
+            ..session("example.com", Type::Socks5)
+        };
+        let mut streams = vec![];
+        for source in ["192.168.1.2:1000", "192.168.1.2:1001"] {
+            let mut stream = track(&mgr, client(source), "DIRECT").await;
+            stream.0.write_all(b"hello").await.unwrap();
+            streams.push(stream);
+        }
+        mgr.close_all().await;
+        // still open
+        let _live = track(&mgr, client("192.168.1.3:1000"), "DIRECT").await;
+
+        let clients = mgr.client_statistics().await;
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients["192.168.1.2"].connections, 2);
+        assert_eq!(clients["192.168.1.2"].upload, 10);
+        assert_eq!(clients["192.168.1.3"].connections, 1);
+
+        // the closed ones of the clients not seen for the longest go first
+        {
+            let mut closed = mgr.closed.lock().await;
+            for i in 0..MAX_CLIENTS {
+                let ip = std::net::Ipv4Addr::from(0x0a00_0000 + i as u32);
+                closed
+                    .clients
+                    .insert(ip.into(), ClientStatistics::default());
+            }
+        }
+        let clients = mgr.client_statistics().await;
+        assert_eq!(clients.len(), MAX_CLIENTS + 1);
+        assert!(!clients.contains_key("192.168.1.2"));
+        assert!(clients.contains_key("192.168.1.3"));
+    }
+}
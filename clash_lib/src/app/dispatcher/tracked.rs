@@ -1,4 +1,4 @@
-use std::{fmt::Debug, pin::Pin, sync::Arc, task::Poll};
+use std::{fmt::Debug, future::Future, pin::Pin, sync::Arc, task::Poll};
 
 use async_trait::async_trait;
 use futures::{Sink, Stream};
@@ -11,7 +11,10 @@ use tracing::debug;
 
 use crate::{app::router::RuleMatcher, proxy::datagram::UdpPacket, session::Session};
 
-use super::statistics_manager::{Manager, ProxyChain, TrackerInfo};
+use super::{
+    capture::Direction,
+    statistics_manager::{Manager, ProxyChain, TrackerInfo},
+};
 
 pub struct Tracked(uuid::Uuid, Arc<TrackerInfo>);
 
@@ -93,6 +96,18 @@ where
         Pin::new(&mut self.inner).poll_write(cx, buf)
     }
 
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -185,13 +200,22 @@ impl AsyncRead for TrackedStream {
                 }
             },
         }
+        // waits for the close too, an idle connection closed through the API
+        // wouldn't notice it otherwise
+        if Pin::new(&mut self.close_notify).poll(cx).is_ready() {
+            debug!("connection closed by sig: {}", self.id());
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
 
+        let filled = buf.filled().len();
         let v = Pin::new(self.inner.as_mut()).poll_read(cx, buf);
-        let download = buf.filled().len();
+        let download = buf.filled().len() - filled;
         self.manager.push_downloaded(download);
         self.tracker
             .download_total
             .fetch_add(download as u64, std::sync::atomic::Ordering::Release);
+        self.tracker
+            .captured(Direction::Download, &buf.filled()[filled..]);
 
         v
     }
@@ -222,10 +246,52 @@ impl AsyncWrite for TrackedStream {
         self.tracker
             .upload_total
             .fetch_add(upload as u64, std::sync::atomic::Ordering::Release);
+        self.tracker.captured(Direction::Upload, &buf[..upload]);
 
         v
     }
 
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        match self.close_notify.try_recv() {
+            Ok(_) => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+            Err(e) => match e {
+                TryRecvError::Empty => {}
+                TryRecvError::Closed => {
+                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+                }
+            },
+        }
+
+        let v = Pin::new(self.inner.as_mut()).poll_write_vectored(cx, bufs);
+        let upload = match v {
+            Poll::Ready(Ok(n)) => n,
+            _ => return v,
+        };
+        self.manager.push_uploaded(upload);
+        self.tracker
+            .upload_total
+            .fetch_add(upload as u64, std::sync::atomic::Ordering::Release);
+        let mut left = upload;
+        for buf in bufs {
+            let n = buf.len().min(left);
+            self.tracker.captured(Direction::Upload, &buf[..n]);
+            left -= n;
+            if left == 0 {
+                break;
+            }
+        }
+
+        v
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -353,12 +419,13 @@ pub struct TrackedDatagram {
 }
 
 impl TrackedDatagram {
-    #[allow(clippy::borrowed_box)]
+    /// `rule` is the type and the payload of the rule matched, owned as the
+    /// session is connected apart from the routing.
     pub async fn new(
         inner: BoxedChainedDatagram,
         manager: Arc<Manager>,
         sess: Session,
-        rule: Option<&Box<dyn RuleMatcher>>,
+        rule: Option<(String, String)>,
     ) -> Self {
         let uuid = uuid::Uuid::new_v4();
         let chain = inner.chain().clone();
@@ -371,11 +438,8 @@ impl TrackedDatagram {
                 session_holder: sess,
 
                 start_time: chrono::Utc::now(),
-                rule: rule
-                    .as_ref()
-                    .map(|x| x.type_name().to_owned())
-                    .unwrap_or_default(),
-                rule_payload: rule.map(|x| x.payload().to_owned()).unwrap_or_default(),
+                rule: rule.as_ref().map(|x| x.0.clone()).unwrap_or_default(),
+                rule_payload: rule.map(|x| x.1).unwrap_or_default(),
                 proxy_chain_holder: chain.clone(),
                 ..Default::default()
             }),
@@ -417,6 +481,10 @@ impl Stream for TrackedDatagram {
                 TryRecvError::Closed => return Poll::Ready(None),
             },
         }
+        // see TrackedStream::poll_read
+        if Pin::new(&mut self.close_notify).poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
 
         let r = Pin::new(self.inner.as_mut()).poll_next(cx);
         if let Poll::Ready(Some(ref pkt)) = r {
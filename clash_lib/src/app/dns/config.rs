@@ -6,20 +6,25 @@ use std::{
     sync::Arc,
 };
 
+use indexmap::IndexMap;
 use ipnet::AddrParseError;
 use regex::Regex;
 use rustls::{Certificate, PrivateKey};
 use url::Url;
 
 use crate::{
+    app::gateway,
     common::trie,
-    config::def::{DNSListen, DNSMode},
+    config::def::{DNSListen, DNSListenTls, DNSMode, NameServerPolicy},
     Error,
 };
 
 use super::{
     dns_client::DNSNetMode,
     dummy_keys::{TEST_CERT, TEST_KEY},
+    http_proxy::HttpProxy,
+    nat64::Nat64Config,
+    rewrite::Rewrite,
 };
 
 #[derive(Clone, Debug)]
@@ -27,6 +32,14 @@ pub struct NameServer {
     pub net: DNSNetMode,
     pub address: String,
     pub interface: Option<String>,
+    /// the TCP based servers are reached through it if set
+    pub proxy: Option<HttpProxy>,
+    /// for the DoT, DoH and DoQ servers
+    pub skip_cert_verify: bool,
+    /// query plain DNS over TCP when a DoT or DoQ server fails
+    pub fallback_tcp: bool,
+    /// the path of a DoH server, `/dns-query` if not set
+    pub path: Option<String>,
 }
 impl Display for NameServer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -40,11 +53,49 @@ impl Display for NameServer {
     }
 }
 
+/// The domains of a `nameserver-policy` key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyDomains {
+    /// a domain pattern, e.g. `+.example.com`
+    Domain(String),
+    /// `geosite:<category>`
+    Geosite(String),
+    /// `rule-set:<name>`, the domains of a rule provider
+    RuleSet(String),
+}
+
+impl PolicyDomains {
+    fn parse(key: &str) -> Result<Self, Error> {
+        let (kind, value) = key.split_once(':').unwrap_or(("", key));
+        let domains = match kind {
+            "geosite" => Self::Geosite(value.to_owned()),
+            "rule-set" => Self::RuleSet(value.to_owned()),
+            _ => {
+                let (_, valid) = trie::valid_and_split_domain(key);
+                if !valid {
+                    return Err(Error::InvalidConfig(format!(
+                        "DNS ResolverRule invalid domain: {}",
+                        key
+                    )));
+                }
+                return Ok(Self::Domain(key.to_owned()));
+            }
+        };
+        if value.is_empty() {
+            return Err(Error::InvalidConfig(format!(
+                "DNS nameserver-policy key {} names nothing",
+                key
+            )));
+        }
+        Ok(domains)
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct FallbackFilter {
     pub geo_ip: bool,
     pub geo_ip_code: String,
-    pub ip_cidr: Option<Vec<ipnet::IpNet>>,
+    pub ip_cidr: Vec<ipnet::IpNet>,
     pub domain: Vec<String>,
 }
 
@@ -75,13 +126,25 @@ pub struct Config {
     pub fallback: Vec<NameServer>,
     pub fallback_filter: FallbackFilter,
     pub listen: DNSListenAddr,
+    /// replaces the test certificate of `listen`
+    pub listen_tls: Option<DNSListenTls>,
+    pub rule_aware: bool,
     pub enhance_mode: DNSMode,
     pub default_nameserver: Vec<NameServer>,
     pub fake_ip_range: ipnet::IpNet,
-    pub fake_ip_filter: Vec<String>,
+    pub fake_ip_range_v6: Option<ipnet::IpNet>,
+    /// the hosts answered with their real addresses in fake-ip mode
+    pub fake_ip_filter: Option<trie::StringTrie<bool>>,
     pub store_fake_ip: bool,
     pub hosts: Option<trie::StringTrie<IpAddr>>,
-    pub nameserver_policy: HashMap<String, NameServer>,
+    /// ordered by key, the domain patterns are matched first anyway
+    pub nameserver_policy: Vec<(PolicyDomains, Vec<NameServer>)>,
+    /// the proxies the addresses of the domains are routed to
+    pub route_hints: HashMap<String, String>,
+    pub fastest_ip: bool,
+    pub nat64: Option<Nat64Config>,
+    pub set_system_dns: bool,
+    pub rewrite: Rewrite,
 }
 
 impl Config {
@@ -91,6 +154,10 @@ impl Config {
         for (i, server) in servers.iter().enumerate() {
             let mut server = server.clone();
 
+            // a bare IPv6 address isn't a valid URL host
+            if server.parse::<std::net::Ipv6Addr>().is_ok() {
+                server = format!("[{}]", server);
+            }
             if !server.contains("://") {
                 server = "udp://".to_owned() + &server;
             }
@@ -101,8 +168,24 @@ impl Config {
             let host = url.host_str().expect("dns host must be valid");
 
             let iface = url.fragment();
+            let flag = |name: &str| url.query_pairs().any(|(k, v)| k == name && v == "true");
+            let skip_cert_verify = flag("skip-cert-verify");
+            if skip_cert_verify && !matches!(url.scheme(), "tls" | "https" | "quic") {
+                return Err(Error::InvalidConfig(format!(
+                    "DNS nameserver [{}]: skip-cert-verify is for the TLS based ones only",
+                    i
+                )));
+            }
+            let fallback_tcp = flag("fallback-tcp");
+            if fallback_tcp && !matches!(url.scheme(), "tls" | "quic") {
+                return Err(Error::InvalidConfig(format!(
+                    "DNS nameserver [{}]: fallback-tcp is for tls:// and quic:// only",
+                    i
+                )));
+            }
             let addr: String;
             let net: &str;
+            let mut path = None;
 
             match url.scheme() {
                 "udp" => {
@@ -117,7 +200,14 @@ impl Config {
                     addr = Config::host_with_default_port(host, "853")?;
                     net = "DoT";
                 }
+                "quic" => {
+                    addr = Config::host_with_default_port(host, "853")?;
+                    net = "DoQ";
+                }
                 "https" => {
+                    if !matches!(url.path(), "" | "/") {
+                        path = Some(url.path().to_owned());
+                    }
                     addr = Config::host_with_default_port(host, "443")?;
                     net = "DoH";
                 }
@@ -138,6 +228,10 @@ impl Config {
                 address: addr,
                 net: net.parse()?,
                 interface: iface.map(String::from),
+                proxy: None,
+                skip_cert_verify,
+                fallback_tcp,
+                path,
             });
         }
 
@@ -145,32 +239,42 @@ impl Config {
     }
 
     pub fn parse_nameserver_policy(
-        policy_map: &HashMap<String, String>,
-    ) -> Result<HashMap<String, NameServer>, Error> {
-        let mut policy = HashMap::new();
-
-        for (domain, server) in policy_map {
-            let nameservers = Config::parse_nameserver(&[server.to_owned()])?;
-
-            let (_, valid) = trie::valid_and_split_domain(domain);
-            if !valid {
+        policy_map: &IndexMap<String, NameServerPolicy>,
+    ) -> Result<Vec<(PolicyDomains, Vec<NameServer>)>, Error> {
+        let mut policy = vec![];
+        for (key, server) in policy_map {
+            let domains = PolicyDomains::parse(key)?;
+            let servers = match server {
+                NameServerPolicy::Server(server) => vec![server.to_owned()],
+                NameServerPolicy::Servers(servers) => servers.clone(),
+                NameServerPolicy::Route { server, .. } => {
+                    if !matches!(domains, PolicyDomains::Domain(_)) {
+                        return Err(Error::InvalidConfig(format!(
+                            "DNS nameserver-policy {}: route is for the domain patterns only",
+                            key
+                        )));
+                    }
+                    vec![server.to_owned()]
+                }
+            };
+            if servers.is_empty() {
                 return Err(Error::InvalidConfig(format!(
-                    "DNS ResolverRule invalid domain: {}",
-                    &domain
+                    "DNS nameserver-policy {} has no nameserver",
+                    key
                 )));
             }
-            policy.insert(domain.into(), nameservers[0].clone());
+            policy.push((domains, Config::parse_nameserver(&servers)?));
         }
         Ok(policy)
     }
 
-    pub fn parse_fallback_ip_cidr(ipcidr: &[String]) -> anyhow::Result<Vec<ipnet::IpNet>> {
+    pub fn parse_fallback_ip_cidr(ipcidr: &[String]) -> Result<Vec<ipnet::IpNet>, Error> {
         let mut output = vec![];
 
         for ip in ipcidr.iter() {
-            let net: ipnet::IpNet = ip
-                .parse()
-                .map_err(|x: AddrParseError| Error::InvalidConfig(x.to_string()))?;
+            let net: ipnet::IpNet = ip.parse().map_err(|x: AddrParseError| {
+                Error::InvalidConfig(format!("invalid dns fallback-filter ipcidr {}: {}", ip, x))
+            })?;
             output.push(net);
         }
 
@@ -179,7 +283,7 @@ impl Config {
 
     pub fn parse_hosts(
         hosts_mapping: &HashMap<String, String>,
-    ) -> anyhow::Result<trie::StringTrie<IpAddr>> {
+    ) -> Result<trie::StringTrie<IpAddr>, Error> {
         let mut tree = trie::StringTrie::new();
         tree.insert(
             "localhost",
@@ -187,13 +291,46 @@ impl Config {
         );
 
         for (host, ip_str) in hosts_mapping.iter() {
-            let ip = ip_str.parse::<IpAddr>()?;
-            tree.insert(host.as_str(), Arc::new(ip));
+            let ip = ip_str.parse::<IpAddr>().map_err(|_| {
+                Error::InvalidConfig(format!("invalid address of host {}: {}", host, ip_str))
+            })?;
+            if !tree.insert(host.as_str(), Arc::new(ip)) {
+                return Err(Error::InvalidConfig(format!("invalid host: {}", host)));
+            }
         }
 
         Ok(tree)
     }
 
+    pub fn parse_fake_ip_filter(
+        patterns: &[String],
+    ) -> Result<Option<trie::StringTrie<bool>>, Error> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut tree = trie::StringTrie::new();
+        for pattern in patterns {
+            if !tree.insert(pattern, Arc::new(true)) {
+                return Err(Error::InvalidConfig(format!(
+                    "invalid fake-ip-filter domain: {}",
+                    pattern
+                )));
+            }
+        }
+        Ok(Some(tree))
+    }
+
+    /// A fake-ip range needs room for the gateway and an address at least.
+    pub fn check_fake_ip_range(range: ipnet::IpNet) -> Result<ipnet::IpNet, Error> {
+        if range.max_prefix_len() - range.prefix_len() < 2 {
+            return Err(Error::InvalidConfig(format!(
+                "fake ip range {} is too small",
+                range
+            )));
+        }
+        Ok(range)
+    }
+
     pub fn host_with_default_port(host: &str, port: &str) -> Result<String, Error> {
         let has_port_suffix = Regex::new(r":\d+$").unwrap();
 
@@ -224,9 +361,9 @@ impl TryFrom<&crate::config::def::Config> for Config {
             )));
         }
 
-        let nameservers = Config::parse_nameserver(&dc.nameserver)?;
-        let fallback = Config::parse_nameserver(&dc.fallback)?;
-        let nameserver_policy = Config::parse_nameserver_policy(&dc.nameserver_policy)?;
+        let mut nameservers = Config::parse_nameserver(&dc.nameserver)?;
+        let mut fallback = Config::parse_nameserver(&dc.fallback)?;
+        let mut nameserver_policy = Config::parse_nameserver_policy(&dc.nameserver_policy)?;
 
         if dc.default_nameserver.is_empty() {
             return Err(Error::InvalidConfig(String::from(
@@ -239,104 +376,172 @@ impl TryFrom<&crate::config::def::Config> for Config {
                 Error::InvalidConfig(String::from("default dns must be ip address"))
             })?;
         }
-        let default_nameserver = Config::parse_nameserver(&dc.default_nameserver)?;
+        let mut default_nameserver = Config::parse_nameserver(&dc.default_nameserver)?;
+
+        if let Some(proxy) = dc.http_proxy.as_deref().map(HttpProxy::parse).transpose()? {
+            for ns in nameservers
+                .iter_mut()
+                .chain(fallback.iter_mut())
+                .chain(default_nameserver.iter_mut())
+                .chain(nameserver_policy.iter_mut().flat_map(|(_, x)| x.iter_mut()))
+            {
+                if ns.net == DNSNetMode::DoQ {
+                    return Err(Error::InvalidConfig(format!(
+                        "dns http-proxy can't carry the quic nameserver {}",
+                        ns.address
+                    )));
+                }
+                ns.proxy = Some(proxy.clone());
+            }
+        }
+
+        let nat64 = dc
+            .nat64
+            .as_deref()
+            .map(str::parse::<Nat64Config>)
+            .transpose()?;
+        if nat64.is_some() && !dc.ipv6 {
+            return Err(Error::InvalidConfig(String::from(
+                "dns nat64 needs ipv6 enabled",
+            )));
+        }
+
+        let mut listen = dc
+            .listen
+            .clone()
+            .map(|l| match l {
+                DNSListen::Udp(u) => {
+                    let addr = u.parse::<SocketAddr>().map_err(|_| {
+                        Error::InvalidConfig(format!("invalid dns udp listen address: {}", u))
+                    })?;
+                    Ok(DNSListenAddr {
+                        udp: Some(addr),
+                        ..Default::default()
+                    })
+                }
+                DNSListen::Multiple(map) => {
+                    let mut udp = None;
+                    let mut tcp = None;
+                    let mut doh = None;
+                    let mut dot = None;
+
+                    for (k, v) in map {
+                        let addr = v.parse::<SocketAddr>().map_err(|_| {
+                            Error::InvalidConfig(format!(
+                                "invalid DNS listen address: {} -> {}",
+                                k, v
+                            ))
+                        })?;
+                        match k.as_str() {
+                            "udp" => udp = Some(addr),
+                            "tcp" => tcp = Some(addr),
+                            "doh" => {
+                                let mut buf_read: Box<dyn std::io::BufRead> =
+                                    Box::new(BufReader::new(TEST_CERT.as_bytes()));
+                                let certs = rustls_pemfile::certs(&mut buf_read)
+                                    .unwrap()
+                                    .into_iter()
+                                    .map(Certificate)
+                                    .collect::<Vec<_>>();
+
+                                let mut buf_read: Box<dyn std::io::BufRead> =
+                                    Box::new(BufReader::new(TEST_KEY.as_bytes()));
+                                let mut keys =
+                                    rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
+                                let c = DoHConfig {
+                                    certificate_and_key: (certs, PrivateKey(keys.remove(0))),
+                                    dns_hostname: Some("dns.example.com".to_owned()),
+                                };
+                                doh = Some((addr, c))
+                            }
+                            "dot" => {
+                                let mut buf_read: Box<dyn std::io::BufRead> =
+                                    Box::new(BufReader::new(TEST_CERT.as_bytes()));
+                                let certs = rustls_pemfile::certs(&mut buf_read)
+                                    .unwrap()
+                                    .into_iter()
+                                    .map(Certificate)
+                                    .collect::<Vec<_>>();
+
+                                let mut buf_read: Box<dyn std::io::BufRead> =
+                                    Box::new(BufReader::new(TEST_KEY.as_bytes()));
+                                let mut keys =
+                                    rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
+                                let c = DoTConfig {
+                                    certificate_and_key: (certs, PrivateKey(keys.remove(0))),
+                                };
+                                dot = Some((addr, c))
+                            }
+                            _ => {
+                                return Err(Error::InvalidConfig(format!(
+                                    "invalid dns listen address: {}",
+                                    k
+                                )))
+                            }
+                        }
+                    }
+
+                    Ok(DNSListenAddr { udp, tcp, doh, dot })
+                }
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        if c.gateway.enable && c.gateway.serve_dns && dc.listen.is_none() {
+            match c.gateway.interface.as_deref().and_then(gateway::lan_ipv4) {
+                Some(ip) => {
+                    listen.udp = Some((ip, 53).into());
+                    listen.tcp = Some((ip, 53).into());
+                }
+                None => {
+                    return Err(Error::InvalidConfig(String::from(
+                        "gateway dns enabled, but no address found on the gateway interface",
+                    )))
+                }
+            }
+        }
+
+        if dc.set_system_dns && listen.udp.map(|x| x.port()) != Some(53) {
+            return Err(Error::InvalidConfig(String::from(
+                "dns set-system-dns needs the udp listener on port 53",
+            )));
+        }
 
         Ok(Self {
             enable: dc.enable,
             ipv6: dc.ipv6,
             nameserver: nameservers,
             fallback,
-            fallback_filter: dc.fallback_filter.clone().into(),
-            listen: dc
-                .listen
-                .clone()
-                .map(|l| match l {
-                    DNSListen::Udp(u) => {
-                        let addr = u.parse::<SocketAddr>().map_err(|_| {
-                            Error::InvalidConfig(format!("invalid dns udp listen address: {}", u))
-                        })?;
-                        Ok(DNSListenAddr {
-                            udp: Some(addr),
-                            ..Default::default()
-                        })
-                    }
-                    DNSListen::Multiple(map) => {
-                        let mut udp = None;
-                        let mut tcp = None;
-                        let mut doh = None;
-                        let mut dot = None;
-
-                        for (k, v) in map {
-                            let addr = v.parse::<SocketAddr>().map_err(|_| {
-                                Error::InvalidConfig(format!(
-                                    "invalid DNS listen address: {} -> {}",
-                                    k, v
-                                ))
-                            })?;
-                            match k.as_str() {
-                                "udp" => udp = Some(addr),
-                                "tcp" => tcp = Some(addr),
-                                "doh" => {
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_CERT.as_bytes()));
-                                    let certs = rustls_pemfile::certs(&mut buf_read)
-                                        .unwrap()
-                                        .into_iter()
-                                        .map(Certificate)
-                                        .collect::<Vec<_>>();
-
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_KEY.as_bytes()));
-                                    let mut keys =
-                                        rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
-                                    let c = DoHConfig {
-                                        certificate_and_key: (certs, PrivateKey(keys.remove(0))),
-                                        dns_hostname: Some("dns.example.com".to_owned()),
-                                    };
-                                    doh = Some((addr, c))
-                                }
-                                "dot" => {
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_CERT.as_bytes()));
-                                    let certs = rustls_pemfile::certs(&mut buf_read)
-                                        .unwrap()
-                                        .into_iter()
-                                        .map(Certificate)
-                                        .collect::<Vec<_>>();
-
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_KEY.as_bytes()));
-                                    let mut keys =
-                                        rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
-                                    let c = DoTConfig {
-                                        certificate_and_key: (certs, PrivateKey(keys.remove(0))),
-                                    };
-                                    dot = Some((addr, c))
-                                }
-                                _ => {
-                                    return Err(Error::InvalidConfig(format!(
-                                        "invalid dns listen address: {}",
-                                        k
-                                    )))
-                                }
-                            }
-                        }
-
-                        Ok(DNSListenAddr { udp, tcp, doh, dot })
-                    }
-                })
-                .transpose()?
-                .unwrap_or_default(),
+            fallback_filter: dc.fallback_filter.clone().try_into()?,
+            listen,
+            listen_tls: dc.listen_tls.clone(),
+            rule_aware: dc.rule_aware,
             enhance_mode: dc.enhanced_mode.clone(),
             default_nameserver,
             fake_ip_range: dc
                 .fake_ip_range
                 .parse::<ipnet::IpNet>()
-                .map_err(|_| Error::InvalidConfig(String::from("invalid fake ip range")))?,
-            fake_ip_filter: dc.fake_ip_filter.clone(),
+                .ok()
+                .filter(|x| matches!(x, ipnet::IpNet::V4(_)))
+                .ok_or_else(|| Error::InvalidConfig(String::from("invalid fake ip range")))
+                .and_then(Config::check_fake_ip_range)?,
+            fake_ip_range_v6: dc
+                .fake_ip_range_v6
+                .as_ref()
+                .map(|x| {
+                    x.parse::<ipnet::IpNet>()
+                        .ok()
+                        .filter(|x| matches!(x, ipnet::IpNet::V6(_)))
+                        .ok_or_else(|| {
+                            Error::InvalidConfig(String::from("invalid fake ip v6 range"))
+                        })
+                        .and_then(Config::check_fake_ip_range)
+                })
+                .transpose()?,
+            fake_ip_filter: Config::parse_fake_ip_filter(&dc.fake_ip_filter)?,
             store_fake_ip: c.profile.store_fake_ip,
             hosts: if dc.user_hosts && !c.hosts.is_empty() {
-                Config::parse_hosts(&c.hosts).ok()
+                Some(Config::parse_hosts(&c.hosts)?)
             } else {
                 let mut tree = trie::StringTrie::new();
                 tree.insert(
@@ -346,18 +551,144 @@ impl TryFrom<&crate::config::def::Config> for Config {
                 Some(tree)
             },
             nameserver_policy,
+            route_hints: dc
+                .nameserver_policy
+                .iter()
+                .filter_map(|(domain, policy)| match policy {
+                    NameServerPolicy::Route { route, .. } => {
+                        Some((domain.to_owned(), route.to_owned()))
+                    }
+                    NameServerPolicy::Server(_) | NameServerPolicy::Servers(_) => None,
+                })
+                .collect(),
+            fastest_ip: dc.fastest_ip,
+            nat64,
+            set_system_dns: dc.set_system_dns,
+            rewrite: Rewrite::parse(&dc.rewrite)?,
         })
     }
 }
 
-impl From<crate::config::def::FallbackFilter> for FallbackFilter {
-    fn from(c: crate::config::def::FallbackFilter) -> Self {
-        let ipcidr = Config::parse_fallback_ip_cidr(&c.ip_cidr);
-        Self {
+impl TryFrom<crate::config::def::FallbackFilter> for FallbackFilter {
+    type Error = Error;
+
+    fn try_from(c: crate::config::def::FallbackFilter) -> Result<Self, Self::Error> {
+        Ok(Self {
             geo_ip: c.geo_ip,
             geo_ip_code: c.geo_ip_code,
-            ip_cidr: ipcidr.ok(),
+            ip_cidr: Config::parse_fallback_ip_cidr(&c.ip_cidr)?,
             domain: c.domain,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::app::dns::dns_client::DNSNetMode;
+
+    use super::{Config, FallbackFilter};
+
+    #[test]
+    fn test_parse_fallback_filter() {
+        let filter: FallbackFilter = crate::config::def::FallbackFilter {
+            ip_cidr: vec!["240.0.0.0/4".to_owned()],
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        assert!(filter.geo_ip);
+        assert_eq!(filter.geo_ip_code, "CN");
+        assert_eq!(filter.ip_cidr, vec!["240.0.0.0/4".parse().unwrap()]);
+
+        assert!(
+            FallbackFilter::try_from(crate::config::def::FallbackFilter {
+                ip_cidr: vec!["240.0.0.0".to_owned()],
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_hosts() {
+        let mut hosts = HashMap::from([
+            ("*.clash.dev".to_owned(), "127.0.0.1".to_owned()),
+            ("alpha.clash.dev".to_owned(), "::1".to_owned()),
+        ]);
+        let tree = Config::parse_hosts(&hosts).unwrap();
+        let lookup = |host| tree.search(host).and_then(|x| x.get_data()).copied();
+        assert_eq!(lookup("foo.clash.dev"), Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(lookup("alpha.clash.dev"), Some("::1".parse().unwrap()));
+        assert_eq!(lookup("clash.dev"), None);
+
+        hosts.insert("beta.clash.dev".to_owned(), "beta".to_owned());
+        assert!(Config::parse_hosts(&hosts).is_err());
+    }
+
+    #[test]
+    fn test_check_fake_ip_range() {
+        for (range, ok) in [
+            ("198.18.0.1/16", true),
+            ("198.18.0.0/30", true),
+            ("198.18.0.0/31", false),
+            ("fdfe:dcba:9876::/127", false),
+        ] {
+            assert_eq!(
+                Config::check_fake_ip_range(range.parse().unwrap()).is_ok(),
+                ok,
+                "{}",
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn test_doq_behind_http_proxy() {
+        let mut c = crate::config::def::Config::default();
+        c.dns.enable = true;
+        c.dns.nameserver = vec!["tls://1.1.1.1".to_owned()];
+        c.dns.http_proxy = Some("http://10.0.0.1:3128".to_owned());
+        let dns = Config::try_from(&c).unwrap();
+        assert!(dns.nameserver[0].proxy.is_some());
+
+        c.dns.nameserver.push("quic://dns.adguard.com".to_owned());
+        assert!(Config::try_from(&c).is_err());
+    }
+
+    #[test]
+    fn test_parse_doh_nameserver() {
+        let ns = Config::parse_nameserver(&[
+            "https://dns.google/dns-query".to_owned(),
+            "https://10.0.0.53/dns-query?skip-cert-verify=true".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(ns[0].net, DNSNetMode::DoH);
+        assert_eq!(ns[0].address, "dns.google:443");
+        assert!(!ns[0].skip_cert_verify);
+        assert!(ns[1].skip_cert_verify);
+
+        // e.g. the profile of NextDNS
+        let ns = Config::parse_nameserver(&[
+            "https://dns.nextdns.io/abc123".to_owned(),
+            "https://dns.google".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(ns[0].path.as_deref(), Some("/abc123"));
+        assert_eq!(ns[1].path, None);
+
+        let ns = Config::parse_nameserver(&["quic://dns.adguard.com?fallback-tcp=true".to_owned()])
+            .unwrap();
+        assert_eq!(ns[0].net, DNSNetMode::DoQ);
+        assert_eq!(ns[0].address, "dns.adguard.com:853");
+        assert!(ns[0].fallback_tcp);
+
+        for invalid in [
+            "udp://8.8.8.8?skip-cert-verify=true",
+            "https://1.1.1.1/dns-query?fallback-tcp=true",
+        ] {
+            assert!(Config::parse_nameserver(&[invalid.to_owned()]).is_err());
         }
     }
 }
@@ -89,9 +89,14 @@ impl DhcpClient {
                         net: DNSNetMode::Udp,
                         address: format!("{}:53", s),
                         interface: None,
+                        proxy: None,
+                        skip_cert_verify: false,
+                        fallback_tcp: false,
+                        path: None,
                     })
                     .collect(),
                 None,
+                None,
             )
             .await;
         }
@@ -1,7 +1,9 @@
 use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::str::FromStr;
-use std::{net, sync::Arc, time::Duration};
+use std::{io, net, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 
@@ -18,9 +20,10 @@ use tracing::{info, warn};
 use crate::common::tls::{self, GLOBAL_ROOT_STORE};
 use crate::dns::dhcp::DhcpClient;
 use crate::dns::ThreadSafeDNSClient;
-use hickory_proto::h2::HttpsClientStreamBuilder;
 use hickory_proto::op::Message;
-use hickory_proto::rustls::tls_client_connect_with_bind_addr;
+use hickory_proto::op::NoopMessageFinalizer;
+use hickory_proto::quic::QuicClientStream;
+use hickory_proto::rustls::tls_client_stream::tls_client_connect_with_future;
 use hickory_proto::{
     xfer::{DnsRequest, DnsRequestOptions, FirstAnswer},
     DnsHandle,
@@ -28,10 +31,13 @@ use hickory_proto::{
 use tokio::net::TcpStream as TokioTcpStream;
 use tokio::net::UdpSocket as TokioUdpSocket;
 
-use crate::proxy::utils::Interface;
+use crate::proxy::utils::{new_tcp_stream_to, new_udp_socket, Interface};
 use crate::Error;
 
-use super::{ClashResolver, Client};
+use super::{doh::DohClient, http_proxy::HttpProxy, ClashResolver, Client, Nat64};
+
+/// RFC 9250
+const DOQ_ALPN: &[u8] = b"doq";
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DNSNetMode {
@@ -39,6 +45,7 @@ pub enum DNSNetMode {
     Tcp,
     DoT,
     DoH,
+    DoQ,
     Dhcp,
 }
 
@@ -49,6 +56,7 @@ impl Display for DNSNetMode {
             Self::Tcp => write!(f, "TCP"),
             Self::DoT => write!(f, "DoT"),
             Self::DoH => write!(f, "DoH"),
+            Self::DoQ => write!(f, "DoQ"),
             Self::Dhcp => write!(f, "DHCP"),
         }
     }
@@ -63,6 +71,7 @@ impl FromStr for DNSNetMode {
             "TCP" => Ok(Self::Tcp),
             "DoH" => Ok(Self::DoH),
             "DoT" => Ok(Self::DoT),
+            "DoQ" => Ok(Self::DoQ),
             "DHCP" => Ok(Self::Dhcp),
             _ => Err(Error::DNSError("unsupported protocol".into())),
         }
@@ -76,13 +85,35 @@ pub struct Opts {
     pub port: u16,
     pub net: DNSNetMode,
     pub iface: Option<Interface>,
+    /// tunnel the TCP, DoT and DoH queries through it, never set for DoQ
+    pub proxy: Option<HttpProxy>,
+    /// reach an IPv4 server through it
+    pub nat64: Option<Nat64>,
+    /// for the DoT, DoH and DoQ servers
+    pub skip_cert_verify: bool,
+    /// query the plain TCP server on port 53 of a DoT or DoQ server when it
+    /// fails
+    pub fallback_tcp: bool,
+    /// the path of a DoH server
+    pub path: Option<String>,
 }
 
 enum DnsConfig {
     Udp(net::SocketAddr, Option<Interface>),
     Tcp(net::SocketAddr, Option<Interface>),
-    Tls(net::SocketAddr, String, Option<Interface>),
-    Https(net::SocketAddr, String, Option<Interface>),
+    /// the TLS config is kept for the reconnections to resume the session
+    Tls(
+        net::SocketAddr,
+        String,
+        Option<Interface>,
+        Arc<ClientConfig>,
+    ),
+    Quic(
+        net::SocketAddr,
+        String,
+        Option<Interface>,
+        Arc<ClientConfig>,
+    ),
 }
 
 impl Display for DnsConfig {
@@ -102,15 +133,15 @@ impl Display for DnsConfig {
                 }
                 Ok(())
             }
-            DnsConfig::Tls(addr, host, iface) => {
+            DnsConfig::Tls(addr, host, iface, _) => {
                 write!(f, "TLS: {}:{} ", addr.ip(), addr.port())?;
                 if let Some(iface) = iface {
                     write!(f, "bind: {}", iface)?;
                 }
                 write!(f, "host: {}", host)
             }
-            DnsConfig::Https(addr, host, iface) => {
-                write!(f, "HTTPS: {}:{} ", addr.ip(), addr.port())?;
+            DnsConfig::Quic(addr, host, iface, _) => {
+                write!(f, "QUIC: {}:{} ", addr.ip(), addr.port())?;
                 if let Some(iface) = iface {
                     write!(f, "bind: {}", iface)?;
                 }
@@ -136,11 +167,14 @@ pub struct DnsClient {
     port: u16,
     net: DNSNetMode,
     iface: Option<Interface>,
+    proxy: Option<HttpProxy>,
+
+    /// the plain TCP client queried when this one fails
+    fallback: Option<ThreadSafeDNSClient>,
 }
 
 impl DnsClient {
     pub async fn new_client(opts: Opts) -> anyhow::Result<ThreadSafeDNSClient> {
-        // TODO: use proxy to connect?
         match &opts.net {
             DNSNetMode::Dhcp => Ok(Arc::new(DhcpClient::new(&opts.host).await)),
 
@@ -164,87 +198,83 @@ impl DnsClient {
                         Error::DNSError(format!("resolve DNS hostname error: {}, {}", x, opts.host))
                     })?
                 };
+                let ip = match opts.nat64 {
+                    Some(nat64) => nat64.map(ip),
+                    None => ip,
+                };
 
-                match other {
-                    DNSNetMode::Udp => {
-                        let cfg =
-                            DnsConfig::Udp(net::SocketAddr::new(ip, opts.port), opts.iface.clone());
-
-                        Ok(Arc::new(Self {
-                            inner: Arc::new(RwLock::new(Inner {
-                                c: None,
-                                bg_handle: None,
-                            })),
-
-                            cfg,
-
-                            host: opts.host,
-                            port: opts.port,
-                            net: opts.net,
-                            iface: opts.iface,
-                        }))
+                let addr = net::SocketAddr::new(ip, opts.port);
+                let cfg = match other {
+                    DNSNetMode::Udp => DnsConfig::Udp(addr, opts.iface.clone()),
+                    DNSNetMode::Tcp => DnsConfig::Tcp(addr, opts.iface.clone()),
+                    DNSNetMode::DoT => DnsConfig::Tls(
+                        addr,
+                        opts.host.clone(),
+                        opts.iface.clone(),
+                        Arc::new(tls_config(b"dot", opts.skip_cert_verify)),
+                    ),
+                    DNSNetMode::DoH => {
+                        let mut tls_config = tls_config(b"h2", opts.skip_cert_verify);
+                        if !opts.skip_cert_verify && opts.host == ip.to_string() {
+                            tls_config
+                                .dangerous()
+                                .set_certificate_verifier(Arc::new(tls::NoHostnameTlsVerifier));
+                        }
+                        return Ok(Arc::new(DohClient::new(
+                            addr,
+                            opts.host,
+                            opts.path,
+                            opts.iface,
+                            opts.proxy,
+                            Arc::new(tls_config),
+                        )));
                     }
-                    DNSNetMode::Tcp => {
-                        let cfg =
-                            DnsConfig::Tcp(net::SocketAddr::new(ip, opts.port), opts.iface.clone());
-
-                        Ok(Arc::new(Self {
-                            inner: Arc::new(RwLock::new(Inner {
-                                c: None,
-                                bg_handle: None,
-                            })),
-
-                            cfg,
+                    DNSNetMode::DoQ => DnsConfig::Quic(
+                        addr,
+                        opts.host.clone(),
+                        opts.iface.clone(),
+                        Arc::new(tls_config(DOQ_ALPN, opts.skip_cert_verify)),
+                    ),
+                    DNSNetMode::Dhcp => unreachable!("."),
+                };
 
-                            host: opts.host,
-                            port: opts.port,
-                            net: opts.net,
-                            iface: opts.iface,
-                        }))
-                    }
-                    DNSNetMode::DoT => {
-                        let cfg = DnsConfig::Tls(
-                            net::SocketAddr::new(ip, opts.port),
-                            opts.host.clone(),
-                            opts.iface.clone(),
-                        );
-
-                        Ok(Arc::new(Self {
+                // plain DNS on the same server
+                let fallback: Option<ThreadSafeDNSClient> = match other {
+                    DNSNetMode::DoT | DNSNetMode::DoQ if opts.fallback_tcp => {
+                        Some(Arc::new(Self {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
                             })),
 
-                            cfg,
+                            cfg: DnsConfig::Tcp(net::SocketAddr::new(ip, 53), opts.iface.clone()),
 
-                            host: opts.host,
-                            port: opts.port,
-                            net: opts.net,
-                            iface: opts.iface,
+                            host: opts.host.clone(),
+                            port: 53,
+                            net: DNSNetMode::Tcp,
+                            iface: opts.iface.clone(),
+                            proxy: opts.proxy.clone(),
+                            fallback: None,
                         }))
                     }
-                    DNSNetMode::DoH => {
-                        let cfg = DnsConfig::Https(
-                            net::SocketAddr::new(ip, opts.port),
-                            opts.host.clone(),
-                            opts.iface.clone(),
-                        );
-
-                        Ok(Arc::new(Self {
-                            inner: Arc::new(RwLock::new(Inner {
-                                c: None,
-                                bg_handle: None,
-                            })),
+                    _ => None,
+                };
 
-                            cfg,
-                            host: opts.host,
-                            port: opts.port,
-                            net: opts.net,
-                            iface: opts.iface,
-                        }))
-                    }
-                    _ => unreachable!("."),
-                }
+                Ok(Arc::new(Self {
+                    inner: Arc::new(RwLock::new(Inner {
+                        c: None,
+                        bg_handle: None,
+                    })),
+
+                    cfg,
+
+                    host: opts.host,
+                    port: opts.port,
+                    net: opts.net,
+                    iface: opts.iface,
+                    proxy: opts.proxy,
+                    fallback,
+                }))
             }
         }
     }
@@ -257,6 +287,7 @@ impl Debug for DnsClient {
             .field("port", &self.port)
             .field("net", &self.net)
             .field("iface", &self.iface)
+            .field("proxy", &self.proxy)
             .finish()
     }
 }
@@ -268,19 +299,39 @@ impl Client for DnsClient {
     }
 
     async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
+        match self.exchange_inner(msg).await {
+            Err(e) => match &self.fallback {
+                Some(fallback) => {
+                    warn!(
+                        "dns client {} failed: {}, falling back to {}",
+                        self.id(),
+                        e,
+                        fallback.id()
+                    );
+                    fallback.exchange(msg).await
+                }
+                None => Err(e),
+            },
+            rv => rv,
+        }
+    }
+}
+
+impl DnsClient {
+    async fn exchange_inner(&self, msg: &Message) -> anyhow::Result<Message> {
         let mut inner = self.inner.write().await;
 
         if let Some(bg) = &inner.bg_handle {
             if bg.is_finished() {
                 warn!("dns client background task is finished, likely connection closed, restarting a new one");
-                let (client, bg) = dns_stream_builder(&self.cfg).await?;
+                let (client, bg) = dns_stream_builder(&self.cfg, self.proxy.as_ref()).await?;
                 inner.c.replace(client);
                 inner.bg_handle.replace(bg);
             }
         } else {
             // initializing client
             info!("initializing dns client: {}", &self.cfg);
-            let (client, bg) = dns_stream_builder(&self.cfg).await?;
+            let (client, bg) = dns_stream_builder(&self.cfg, self.proxy.as_ref()).await?;
             inner.c.replace(client);
             inner.bg_handle.replace(bg);
         }
@@ -300,19 +351,64 @@ impl Client for DnsClient {
     }
 }
 
+type StreamFuture =
+    Pin<Box<dyn Future<Output = io::Result<AsyncIoTokioAsStd<TokioTcpStream>>> + Send>>;
+
+fn tunnel(proxy: &HttpProxy, host: String, port: u16) -> StreamFuture {
+    let proxy = proxy.clone();
+    Box::pin(async move { proxy.connect(&host, port).await.map(AsyncIoTokioAsStd) })
+}
+
+/// The stream to the nameserver, through the proxy if any, or through the
+/// socket protector and the source port range.
+pub(super) fn connect(
+    proxy: Option<&HttpProxy>,
+    addr: SocketAddr,
+    host: String,
+    iface: Option<Interface>,
+) -> StreamFuture {
+    if let Some(proxy) = proxy {
+        return tunnel(proxy, host, addr.port());
+    }
+    Box::pin(async move {
+        new_tcp_stream_to(
+            addr,
+            iface.as_ref(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .map(AsyncIoTokioAsStd)
+    })
+}
+
+/// A UDP socket to the nameserver at `addr`, as the TCP streams.
+async fn udp_socket(addr: SocketAddr, iface: Option<Interface>) -> io::Result<TokioUdpSocket> {
+    let unspecified: net::IpAddr = match addr {
+        SocketAddr::V4(_) => net::Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    new_udp_socket(
+        Some(&SocketAddr::new(unspecified, 0)),
+        iface.as_ref(),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        None,
+    )
+    .await
+}
+
 async fn dns_stream_builder(
     cfg: &DnsConfig,
+    proxy: Option<&HttpProxy>,
 ) -> Result<(AsyncClient, JoinHandle<Result<(), ProtoError>>), Error> {
     match cfg {
         DnsConfig::Udp(addr, iface) => {
-            let stream = UdpClientStream::<TokioUdpSocket>::with_bind_addr_and_timeout(
-                net::SocketAddr::new(addr.ip(), addr.port()),
-                // TODO: simplify this match
-                match iface {
-                    Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
-                    _ => None,
-                },
+            let iface = iface.clone();
+            let stream = UdpClientStream::<TokioUdpSocket, NoopMessageFinalizer>::with_creator(
+                *addr,
+                None,
                 Duration::from_secs(5),
+                Arc::new(move |_, server| Box::pin(udp_socket(server, iface.clone()))),
             );
             client::AsyncClient::connect(stream)
                 .await
@@ -320,71 +416,192 @@ async fn dns_stream_builder(
                 .map_err(|x| Error::DNSError(x.to_string()))
         }
         DnsConfig::Tcp(addr, iface) => {
-            let (stream, sender) =
-                TcpClientStream::<AsyncIoTokioAsStd<TokioTcpStream>>::with_bind_addr_and_timeout(
-                    net::SocketAddr::new(addr.ip(), addr.port()),
-                    match iface {
-                        Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
-                        _ => None,
-                    },
-                    Duration::from_secs(5),
-                );
+            let (stream, sender) = TcpClientStream::with_future(
+                connect(proxy, *addr, addr.ip().to_string(), iface.clone()),
+                *addr,
+                Duration::from_secs(5),
+            );
 
             client::AsyncClient::new(stream, sender, None)
                 .await
                 .map(|(x, y)| (x, tokio::spawn(y)))
                 .map_err(|x| Error::DNSError(x.to_string()))
         }
-        DnsConfig::Tls(addr, host, iface) => {
-            let mut tls_config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(GLOBAL_ROOT_STORE.clone())
-                .with_no_client_auth();
-            tls_config.alpn_protocols = vec!["dot".into()];
-
-            let (stream, sender) =
-                tls_client_connect_with_bind_addr::<AsyncIoTokioAsStd<TokioTcpStream>>(
-                    net::SocketAddr::new(addr.ip(), addr.port()),
-                    match iface {
-                        Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
-                        _ => None,
-                    },
-                    host.clone(),
-                    Arc::new(tls_config),
-                );
+        DnsConfig::Tls(addr, host, iface, tls_config) => {
+            let (stream, sender) = tls_client_connect_with_future(
+                connect(proxy, *addr, host.clone(), iface.clone()),
+                *addr,
+                host.clone(),
+                tls_config.clone(),
+            );
 
             client::AsyncClient::with_timeout(stream, sender, Duration::from_secs(5), None)
                 .await
                 .map(|(x, y)| (x, tokio::spawn(y)))
                 .map_err(|x| Error::DNSError(x.to_string()))
         }
-        DnsConfig::Https(addr, host, iface) => {
-            let mut tls_config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(GLOBAL_ROOT_STORE.clone())
-                .with_no_client_auth();
-            tls_config.alpn_protocols = vec!["h2".into()];
-
-            if host == &addr.ip().to_string() {
-                tls_config
-                    .dangerous()
-                    .set_certificate_verifier(Arc::new(tls::NoHostnameTlsVerifier));
-            }
+        DnsConfig::Quic(addr, host, iface, tls_config) => {
+            let mut stream_builder = QuicClientStream::builder();
+            stream_builder.crypto_config((**tls_config).clone());
 
-            let mut stream_builder =
-                HttpsClientStreamBuilder::with_client_config(Arc::new(tls_config));
-            if let Some(Interface::IpAddr(ip)) = iface {
-                stream_builder.bind_addr(net::SocketAddr::new(*ip, 0));
-            }
-            let stream = stream_builder.build::<AsyncIoTokioAsStd<TokioTcpStream>>(
-                net::SocketAddr::new(addr.ip(), addr.port()),
+            client::AsyncClient::connect(stream_builder.build_with_future(
+                udp_socket(*addr, iface.clone()),
+                *addr,
                 host.clone(),
-            );
+            ))
+            .await
+            .map(|(x, y)| (x, tokio::spawn(y)))
+            .map_err(|x| Error::DNSError(x.to_string()))
+        }
+    }
+}
 
-            client::AsyncClient::connect(stream)
-                .await
-                .map(|(x, y)| (x, tokio::spawn(y)))
-                .map_err(|x| Error::DNSError(x.to_string()))
+/// The TLS config of the DoT, DoH and DoQ clients, DoQ is TLS 1.3 only.
+fn tls_config(alpn: &[u8], skip_cert_verify: bool) -> ClientConfig {
+    let versions: &[&rustls::SupportedProtocolVersion] = if alpn == DOQ_ALPN {
+        &[&rustls::version::TLS13]
+    } else {
+        rustls::DEFAULT_VERSIONS
+    };
+    let mut tls_config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .expect("the versions are supported")
+        .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![alpn.to_vec()];
+    if skip_cert_verify {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(tls::DummyTlsVerifier));
+    }
+    tls_config
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hickory_proto::{
+        op::{Message, MessageType, Query},
+        rr::{rdata::A, RData, Record, RecordType},
+    };
+
+    use super::{DNSNetMode, DnsClient, Opts};
+
+    /// A self signed server config with the ALPN.
+    fn server_config(alpn: &[u8]) -> rustls::ServerConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        crypto.alpn_protocols = vec![alpn.to_vec()];
+        crypto
+    }
+
+    /// 1.2.3.4 to any query.
+    fn answer(req: &[u8]) -> Vec<u8> {
+        let req = Message::from_vec(req).unwrap();
+        assert_eq!(req.id(), 0);
+        let mut res = Message::new();
+        res.set_message_type(MessageType::Response)
+            .add_queries(req.queries().to_vec())
+            .add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                60,
+                RData::A(A::new(1, 2, 3, 4)),
+            ));
+        res.to_vec().unwrap()
+    }
+
+    fn opts(port: u16, net: DNSNetMode, path: Option<String>) -> Opts {
+        Opts {
+            r: None,
+            host: "127.0.0.1".to_owned(),
+            port,
+            net,
+            iface: None,
+            proxy: None,
+            nat64: None,
+            skip_cert_verify: true,
+            fallback_tcp: false,
+            path,
         }
     }
+
+    async fn query(opts: Opts) -> Message {
+        let client = DnsClient::new_client(opts).await.unwrap();
+        let mut msg = Message::new();
+        msg.set_id(42)
+            .add_query(Query::query("example.com.".parse().unwrap(), RecordType::A));
+        let res = client.exchange(&msg).await.unwrap();
+        assert_eq!(res.answers()[0].data(), Some(&RData::A(A::new(1, 2, 3, 4))));
+        res
+    }
+
+    #[tokio::test]
+    async fn test_doq() {
+        let server = quinn::Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(server_config(b"doq"))),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            let mut len = [0u8; 2];
+            recv.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            recv.read_exact(&mut buf).await.unwrap();
+
+            let res = answer(&buf);
+            send.write_all(&(res.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            send.write_all(&res).await.unwrap();
+            send.finish().await.unwrap();
+            conn.closed().await;
+        });
+
+        query(opts(port, DNSNetMode::DoQ, None)).await;
+    }
+
+    #[tokio::test]
+    async fn test_doh() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config(b"h2")));
+
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let s = acceptor.accept(s).await.unwrap();
+            let mut conn = h2::server::handshake(s).await.unwrap();
+            let (req, mut respond) = conn.accept().await.unwrap().unwrap();
+            assert_eq!(req.uri().path(), "/abc123");
+            assert_eq!(req.method(), http::Method::POST);
+
+            let mut body = req.into_body();
+            let mut buf = vec![];
+            while let Some(data) = body.data().await {
+                buf.extend_from_slice(&data.unwrap());
+            }
+            let mut send = respond
+                .send_response(http::Response::new(()), false)
+                .unwrap();
+            send.send_data(answer(&buf).into(), true).unwrap();
+            // drive the connection
+            while conn.accept().await.is_some() {}
+        });
+
+        let res = query(opts(port, DNSNetMode::DoH, Some("/abc123".to_owned()))).await;
+        assert_eq!(res.id(), 42);
+    }
 }
@@ -0,0 +1,186 @@
+//! DNS over HTTPS, RFC 8484, on any path of the server, e.g. the profile of
+//! a NextDNS user.
+
+use std::{
+    fmt::{Debug, Formatter},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use h2::client::SendRequest;
+use hickory_proto::op::Message;
+use http::{header, Method, Request, StatusCode};
+use rustls::ClientConfig;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{proxy::utils::Interface, Error};
+
+use super::{dns_client::connect, http_proxy::HttpProxy, Client};
+
+/// the path of RFC 8484
+const DEFAULT_PATH: &str = "/dns-query";
+const DNS_MESSAGE: &str = "application/dns-message";
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// a DNS message can't be larger
+const MAX_RESPONSE_LEN: usize = 65535;
+
+pub struct DohClient {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    iface: Option<Interface>,
+    proxy: Option<HttpProxy>,
+    tls_config: Arc<ClientConfig>,
+    /// the HTTP/2 connection the queries share
+    conn: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl DohClient {
+    pub fn new(
+        addr: SocketAddr,
+        host: String,
+        path: Option<String>,
+        iface: Option<Interface>,
+        proxy: Option<HttpProxy>,
+        tls_config: Arc<ClientConfig>,
+    ) -> Self {
+        Self {
+            addr,
+            host,
+            path: path.unwrap_or_else(|| DEFAULT_PATH.to_owned()),
+            iface,
+            proxy,
+            tls_config,
+            conn: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<SendRequest<Bytes>> {
+        let stream = connect(
+            self.proxy.as_ref(),
+            self.addr,
+            self.host.clone(),
+            self.iface.clone(),
+        )
+        .await?
+        .0;
+        let name = rustls::ServerName::try_from(self.host.as_str())?;
+        let stream = tokio_rustls::TlsConnector::from(self.tls_config.clone())
+            .connect(name, stream)
+            .await?;
+
+        let (client, conn) = h2::client::handshake(stream).await?;
+        let id = self.id();
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("dns client {} connection closed: {}", id, e);
+            }
+        });
+        Ok(client)
+    }
+
+    /// The connection ready for a new query, a new one if it's closed.
+    async fn ready(&self) -> anyhow::Result<SendRequest<Bytes>> {
+        let conn = self.conn.lock().await.clone();
+        if let Some(conn) = conn {
+            if let Ok(conn) = conn.ready().await {
+                return Ok(conn);
+            }
+        }
+
+        // the queries meanwhile wait on the new one
+        let mut conn = self.conn.lock().await;
+        let new = self.connect().await?;
+        conn.replace(new.clone());
+        drop(conn);
+        Ok(new.ready().await?)
+    }
+
+    fn uri(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match self.addr.port() {
+            443 => format!("https://{}{}", host, self.path),
+            port => format!("https://{}:{}{}", host, port, self.path),
+        }
+    }
+
+    async fn exchange_inner(&self, msg: &Message) -> anyhow::Result<Message> {
+        // a zero id for the caches on the way, RFC 8484 section 4.1
+        let mut query = msg.clone();
+        query.set_id(0);
+        let body = Bytes::from(query.to_vec()?);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri())
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(header::ACCEPT, DNS_MESSAGE)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(())?;
+
+        let (res, mut send) = self.ready().await?.send_request(req, false)?;
+        send.send_data(body, true)?;
+
+        let res = res.await?;
+        if res.status() != StatusCode::OK {
+            return Err(Error::DNSError(format!(
+                "dns client {} got status {}",
+                self.id(),
+                res.status()
+            ))
+            .into());
+        }
+
+        let mut body = res.into_body();
+        let mut buf = BytesMut::new();
+        while let Some(data) = body.data().await {
+            let data = data?;
+            body.flow_control().release_capacity(data.len())?;
+            buf.extend_from_slice(&data);
+            if buf.len() > MAX_RESPONSE_LEN {
+                return Err(Error::DNSError(format!(
+                    "dns client {} got a response too large",
+                    self.id()
+                ))
+                .into());
+            }
+        }
+
+        let mut res = Message::from_vec(&buf)?;
+        res.set_id(msg.id());
+        Ok(res)
+    }
+}
+
+impl Debug for DohClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DohClient")
+            .field("host", &self.host)
+            .field("port", &self.addr.port())
+            .field("path", &self.path)
+            .field("iface", &self.iface)
+            .field("proxy", &self.proxy)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Client for DohClient {
+    fn id(&self) -> String {
+        format!("DoH#{}:{}", &self.host, self.addr.port())
+    }
+
+    async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
+        tokio::time::timeout(TIMEOUT, self.exchange_inner(msg))
+            .await
+            .map_err(|_| Error::DNSError(format!("dns client {} timed out", self.id())))?
+    }
+}
@@ -4,45 +4,63 @@ use crate::app::profile::ThreadSafeCacheFile;
 
 use super::Store;
 
-pub struct FileStore(ThreadSafeCacheFile);
+/// the addresses a pool hands out at most, the cache file holds them all
+const CAPACITY: u128 = 8192;
+
+/// Keeps the mappings of a pool in the cache file, so they survive restarts.
+/// The pool is limited to [`CAPACITY`] addresses, reused in turn.
+pub struct FileStore {
+    store: ThreadSafeCacheFile,
+    /// the pool holds IPv6 addresses, their hosts are kept apart
+    v6: bool,
+}
 
 impl FileStore {
-    pub fn new(store: ThreadSafeCacheFile) -> Self {
-        Self(store)
+    pub fn new(store: ThreadSafeCacheFile, v6: bool) -> Self {
+        Self { store, v6 }
     }
 }
 
 #[async_trait]
 impl Store for FileStore {
     async fn get_by_host(&mut self, host: &str) -> Option<std::net::IpAddr> {
-        self.0
-            .get_fake_ip(host)
-            .await
-            .and_then(|ip| ip.parse().ok())
+        self.store.get_fake_ip(host, self.v6).await
     }
 
     async fn pub_by_host(&mut self, host: &str, ip: std::net::IpAddr) {
-        self.0.set_host_to_ip(host, &ip.to_string()).await;
+        self.store.set_host_to_ip(host, ip).await;
     }
 
     async fn get_by_ip(&mut self, ip: std::net::IpAddr) -> Option<String> {
-        self.0.get_fake_ip(&ip.to_string()).await
+        self.store.get_fake_ip_host(ip).await
     }
 
     async fn put_by_ip(&mut self, ip: std::net::IpAddr, host: &str) {
-        self.0.set_ip_to_host(&ip.to_string(), host).await;
+        self.store.set_ip_to_host(ip, host).await;
     }
 
     async fn del_by_ip(&mut self, ip: std::net::IpAddr) {
         let host = self.get_by_ip(ip).await.unwrap_or_default();
-        self.0.delete_fake_ip_pair(&ip.to_string(), &host).await;
+        self.store.delete_fake_ip_pair(ip, &host).await;
     }
 
     async fn exist(&mut self, ip: std::net::IpAddr) -> bool {
-        self.0.get_fake_ip(&ip.to_string()).await.is_some()
+        self.store.get_fake_ip_host(ip).await.is_some()
     }
 
     async fn copy_to(&self, #[allow(unused)] store: &mut Box<dyn Store>) {
         //NO-OP
     }
+
+    fn capacity(&self) -> Option<u128> {
+        Some(CAPACITY)
+    }
+
+    async fn get_offset(&mut self) -> Option<u128> {
+        Some(self.store.get_fake_ip_offset(self.v6).await as u128)
+    }
+
+    async fn put_offset(&mut self, offset: u128) {
+        self.store.set_fake_ip_offset(self.v6, offset as u64).await;
+    }
 }
@@ -30,32 +30,50 @@ pub trait Store: Sync + Send {
     async fn del_by_ip(&mut self, ip: net::IpAddr);
     async fn exist(&mut self, ip: net::IpAddr) -> bool;
     async fn copy_to(&self, store: &mut Box<dyn Store>);
+
+    /// The addresses the pool may hand out at most, for a store keeping
+    /// every one of them.
+    fn capacity(&self) -> Option<u128> {
+        None
+    }
+    /// Where the allocation stopped, for a store surviving restarts.
+    async fn get_offset(&mut self) -> Option<u128> {
+        None
+    }
+    async fn put_offset(&mut self, _offset: u128) {}
 }
 
 pub type ThreadSafeFakeDns = Arc<RwLock<FakeDns>>;
 
 pub struct FakeDns {
-    max: u32,
-    min: u32,
+    max: u128,
+    min: u128,
     #[allow(dead_code)]
-    gateway: u32,
-    offset: u32,
+    gateway: u128,
+    /// the next address handed out, read from the store first
+    offset: Option<u128>,
     skipped_hostnames: Option<trie::StringTrie<bool>>,
     ipnet: ipnet::IpNet,
     store: Box<dyn Store>,
 }
 
 impl FakeDns {
+    /// The pool could be either an IPv4 or an IPv6 subnet,
+    /// the first address is the gateway and the rest are allocated.
     pub fn new(opt: Opts) -> Result<Self, Error> {
-        let ip = match opt.ipnet.network() {
-            net::IpAddr::V4(ip) => ip,
-            _ => unreachable!("fakeip range must be valid ipv4 subnet"),
-        };
-        let min = Self::ip_to_uint(&ip) + 2;
-        let prefix_len = opt.ipnet.prefix_len();
-        let max_prefix_len = opt.ipnet.max_prefix_len();
-        debug_assert_eq!(max_prefix_len, 32, "v4 subnet");
-        let total = (1 << (max_prefix_len - prefix_len)) - 2;
+        let min = Self::ip_to_uint(&opt.ipnet.network()) + 2;
+        let host_bits = (opt.ipnet.max_prefix_len() - opt.ipnet.prefix_len()) as u32;
+        if host_bits < 2 {
+            return Err(Error::InvalidConfig(format!(
+                "fakeip range {} is too small",
+                opt.ipnet
+            )));
+        }
+        // huge v6 pools are capped, there is no way to exhaust them anyway
+        let mut total = (1u128 << host_bits.min(64)) - 2;
+        if let Some(capacity) = opt.store.capacity() {
+            total = total.min(capacity);
+        }
 
         let max = min + total - 1;
 
@@ -63,7 +81,7 @@ impl FakeDns {
             max,
             min,
             gateway: min - 1,
-            offset: 0,
+            offset: None,
             skipped_hostnames: opt.skipped_hostnames,
             ipnet: opt.ipnet,
             store: opt.store,
@@ -72,7 +90,9 @@ impl FakeDns {
 
     pub async fn lookup(&mut self, host: &str) -> net::IpAddr {
         if let Some(ip) = self.store.get_by_host(host).await {
-            return ip;
+            if self.ipnet.contains(&ip) {
+                return ip;
+            }
         }
 
         let ip = self.get(host).await;
@@ -81,7 +101,7 @@ impl FakeDns {
     }
 
     pub async fn reverse_lookup(&mut self, ip: net::IpAddr) -> Option<String> {
-        if !ip.is_ipv4() {
+        if !self.ipnet.contains(&ip) {
             None
         } else {
             self.store.get_by_ip(ip).await
@@ -96,7 +116,7 @@ impl FakeDns {
     }
 
     pub async fn exist(&mut self, ip: net::IpAddr) -> bool {
-        if !ip.is_ipv4() {
+        if !self.ipnet.contains(&ip) {
             false
         } else {
             self.store.exist(ip).await
@@ -104,16 +124,12 @@ impl FakeDns {
     }
 
     pub async fn is_fake_ip(&mut self, ip: net::IpAddr) -> bool {
-        if !ip.is_ipv4() {
-            false
-        } else {
-            self.ipnet.contains(&ip)
-        }
+        self.ipnet.contains(&ip)
     }
 
     #[allow(dead_code)]
-    pub fn gateway(&self) -> net::Ipv4Addr {
-        net::Ipv4Addr::from(self.gateway)
+    pub fn gateway(&self) -> net::IpAddr {
+        self.uint_to_ip(self.gateway)
     }
 
     #[allow(dead_code)]
@@ -126,32 +142,38 @@ impl FakeDns {
         src.store.copy_to(&mut self.store).await;
     }
 
+    /// The next address of the pool, taken from whichever host had it a
+    /// cycle ago.
     async fn get(&mut self, host: &str) -> net::IpAddr {
-        let current = self.offset;
-
-        loop {
-            self.offset = (self.offset + 1) % (self.max - self.min);
-
-            if self.offset == current {
-                self.offset = (self.offset + 1) % (self.max - self.min);
-                let ip = net::Ipv4Addr::from(self.min + self.offset - 1);
-                self.store.del_by_ip(std::net::IpAddr::V4(ip)).await;
-                break;
-            }
+        let size = self.max - self.min;
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => self.store.get_offset().await.unwrap_or_default() % size,
+        };
+        let next = (offset + 1) % size;
+        self.offset = Some(next);
+        self.store.put_offset(next).await;
 
-            let ip = net::Ipv4Addr::from(self.min + self.offset - 1);
-            if !self.store.exist(std::net::IpAddr::V4(ip)).await {
-                break;
-            }
+        let ip = self.uint_to_ip(self.min + offset);
+        if self.store.exist(ip).await {
+            self.store.del_by_ip(ip).await;
         }
+        self.store.put_by_ip(ip, host).await;
+        ip
+    }
 
-        let ip = net::Ipv4Addr::from(self.min + self.offset - 1);
-        self.store.put_by_ip(std::net::IpAddr::V4(ip), host).await;
-        std::net::IpAddr::V4(ip)
+    fn ip_to_uint(ip: &net::IpAddr) -> u128 {
+        match ip {
+            net::IpAddr::V4(v4) => BigEndian::read_u32(&v4.octets()) as u128,
+            net::IpAddr::V6(v6) => BigEndian::read_u128(&v6.octets()),
+        }
     }
 
-    fn ip_to_uint(ip: &net::Ipv4Addr) -> u32 {
-        BigEndian::read_u32(&ip.octets())
+    fn uint_to_ip(&self, n: u128) -> net::IpAddr {
+        match self.ipnet {
+            ipnet::IpNet::V4(_) => net::Ipv4Addr::from(n as u32).into(),
+            ipnet::IpNet::V6(_) => net::Ipv6Addr::from(n).into(),
+        }
     }
 }
 
@@ -159,9 +181,12 @@ impl FakeDns {
 mod tests {
     use std::{net, sync::Arc};
 
-    use crate::{app::dns::fakeip::mem_store::InMemStore, common::trie};
+    use crate::{
+        app::{dns::fakeip::mem_store::InMemStore, profile::ThreadSafeCacheFile},
+        common::trie,
+    };
 
-    use super::{FakeDns, Opts};
+    use super::{FakeDns, FileStore, Opts};
 
     #[tokio::test]
     async fn test_inmem_basic() {
@@ -194,6 +219,25 @@ mod tests {
         assert!(!pool.exist("::1".parse().unwrap()).await);
     }
 
+    #[tokio::test]
+    async fn test_inmem_v6() {
+        let ipnet = "fdfe:dcba:9876::/64".parse::<ipnet::IpNet>().unwrap();
+        let store = Box::new(InMemStore::new(10));
+        let mut pool = FakeDns::new(Opts {
+            ipnet,
+            skipped_hostnames: None,
+            store,
+        })
+        .unwrap();
+
+        let foo = pool.lookup("foo.com").await;
+        assert_eq!(foo, "fdfe:dcba:9876::2".parse::<net::IpAddr>().unwrap());
+        assert_eq!(pool.lookup("foo.com").await, foo);
+        assert_eq!(pool.reverse_lookup(foo).await, Some("foo.com".into()));
+        assert!(pool.is_fake_ip(foo).await);
+        assert!(!pool.is_fake_ip("198.18.0.2".parse().unwrap()).await);
+    }
+
     #[tokio::test]
     async fn test_inmem_cycle_used() {
         let store = Box::new(InMemStore::new(10));
@@ -219,6 +263,51 @@ mod tests {
         assert_eq!(next, bar);
     }
 
+    #[tokio::test]
+    async fn test_file_store() {
+        let path = std::env::temp_dir().join("clash-rs-fakeip-missing.db");
+        let cache = ThreadSafeCacheFile::new(path.to_str().unwrap(), false, false);
+        let pool = |v6: bool| {
+            FakeDns::new(Opts {
+                ipnet: if v6 {
+                    "fdfe:dcba:9876::/64".parse().unwrap()
+                } else {
+                    "198.18.0.0/16".parse().unwrap()
+                },
+                skipped_hostnames: None,
+                store: Box::new(FileStore::new(cache.clone(), v6)),
+            })
+            .unwrap()
+        };
+
+        let (mut v4, mut v6) = (pool(false), pool(true));
+        let foo = v4.lookup("foo.com").await;
+        let foo6 = v6.lookup("foo.com").await;
+        assert_eq!(v4.lookup("foo.com").await, foo);
+        assert_eq!(v6.lookup("foo.com").await, foo6);
+        assert_eq!(v6.reverse_lookup(foo6).await, Some("foo.com".into()));
+
+        // a new pool, as after a restart, keeps the mappings and goes on
+        // from where it stopped
+        let mut v4 = pool(false);
+        assert_eq!(v4.reverse_lookup(foo).await, Some("foo.com".into()));
+        assert_eq!(v4.lookup("foo.com").await, foo);
+        assert_eq!(
+            v4.lookup("bar.com").await,
+            net::IpAddr::from([198, 18, 0, 3])
+        );
+
+        // bounded, the addresses are reused in turn
+        for i in 0..8190 {
+            v4.lookup(&format!("{}.com", i)).await;
+        }
+        assert_eq!(v4.reverse_lookup(foo).await, Some("8189.com".into()));
+        assert_eq!(
+            v4.lookup("foo.com").await,
+            net::IpAddr::from([198, 18, 0, 3])
+        );
+    }
+
     #[tokio::test]
     async fn test_pool_skip() {
         let store = Box::new(InMemStore::new(10));
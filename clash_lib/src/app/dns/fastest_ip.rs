@@ -0,0 +1,173 @@
+//! Order the addresses of a multi-CDN domain by how fast they complete a TCP
+//! handshake, so the fastest is dialed instead of a random one.
+//!
+//! The handshakes are raced in the background, a lookup never waits on them:
+//! the answers keep their order until the domain is probed.
+
+use std::{
+    collections::HashSet,
+    net,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use tracing::debug;
+
+use crate::proxy::utils::new_tcp_stream_to;
+
+/// the ports most servers listen on
+const PROBE_PORTS: [u16; 2] = [443, 80];
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// how long an order is kept, the answers of CDNs change often
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// keyed by the host and whether it's the AAAA answer
+type Key = (String, bool);
+
+pub struct FastestIp {
+    /// the addresses probed, the fastest first
+    cache: Arc<Mutex<lru_time_cache::LruCache<Key, Vec<net::IpAddr>>>>,
+    probing: Arc<Mutex<HashSet<Key>>>,
+    ports: &'static [u16],
+}
+
+impl FastestIp {
+    pub fn new() -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(
+                lru_time_cache::LruCache::with_expiry_duration_and_capacity(CACHE_TTL, 1024),
+            )),
+            probing: Default::default(),
+            ports: &PROBE_PORTS,
+        }
+    }
+
+    /// Sort the addresses of `host` the fastest first, the unreachable ones
+    /// last, and tell whether the order is known. If not, they are probed
+    /// for the next time and keep their order.
+    pub fn sort(&self, host: &str, ips: &mut [net::IpAddr]) -> bool {
+        if ips.len() < 2 {
+            return false;
+        }
+
+        let key = (host.to_owned(), ips[0].is_ipv6());
+        let order = self.cache.lock().unwrap().get(&key).cloned();
+        match order {
+            // an answer with an address not probed then is probed again
+            Some(order) if ips.iter().all(|x| order.contains(x)) => {
+                ips.sort_by_key(|x| order.iter().position(|y| y == x));
+                true
+            }
+            _ => {
+                self.spawn_probe(key, ips.to_vec());
+                false
+            }
+        }
+    }
+
+    fn spawn_probe(&self, key: Key, ips: Vec<net::IpAddr>) {
+        if !self.probing.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let cache = self.cache.clone();
+        let probing = self.probing.clone();
+        let ports = self.ports;
+        tokio::spawn(async move {
+            let order = probe(&ips, ports).await;
+            debug!("fastest ips of {}: {:?}", key.0, order);
+            probing.lock().unwrap().remove(&key);
+            cache.lock().unwrap().insert(key, order);
+        });
+    }
+}
+
+/// The addresses by the time they complete a handshake on one of the ports,
+/// the ones not completing it in time last.
+async fn probe(ips: &[net::IpAddr], ports: &[u16]) -> Vec<net::IpAddr> {
+    let mut handshakes = ips
+        .iter()
+        .flat_map(|ip| ports.iter().map(move |port| (*ip, *port)))
+        .map(|(ip, port)| async move {
+            new_tcp_stream_to(
+                (ip, port).into(),
+                None,
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await
+            .ok()
+            .map(|_| ip)
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut order = Vec::new();
+    let _ = tokio::time::timeout(PROBE_TIMEOUT, async {
+        while let Some(r) = handshakes.next().await {
+            if let Some(ip) = r.filter(|x| !order.contains(x)) {
+                order.push(ip);
+            }
+        }
+    })
+    .await;
+    for ip in ips {
+        if !order.contains(ip) {
+            order.push(*ip);
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net, time::Duration};
+
+    use tokio::net::TcpListener;
+
+    use super::{probe, FastestIp, PROBE_TIMEOUT};
+
+    // a documentation address, nothing answers there
+    const BLACKHOLE: &str = "192.0.2.1";
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let blackhole: net::IpAddr = BLACKHOLE.parse().unwrap();
+        let local: net::IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(
+            probe(&[blackhole, local], &[port]).await,
+            vec![local, blackhole]
+        );
+        assert_eq!(probe(&[blackhole], &[port]).await, vec![blackhole]);
+    }
+
+    #[tokio::test]
+    async fn test_sort() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let fastest = FastestIp {
+            ports: Vec::leak(vec![port]),
+            ..FastestIp::new()
+        };
+        let blackhole: net::IpAddr = BLACKHOLE.parse().unwrap();
+        let local: net::IpAddr = "127.0.0.1".parse().unwrap();
+
+        // nothing waits on the probe
+        let mut ips = [blackhole, local];
+        assert!(!fastest.sort("example.com", &mut ips));
+        assert_eq!(ips, [blackhole, local]);
+
+        // probed by then
+        tokio::time::sleep(PROBE_TIMEOUT + Duration::from_millis(100)).await;
+        assert!(fastest.probing.lock().unwrap().is_empty());
+        assert!(fastest.sort("example.com", &mut ips));
+        assert_eq!(ips, [local, blackhole]);
+
+        // an address not probed
+        let mut ips = [local, "127.0.0.2".parse().unwrap()];
+        assert!(!fastest.sort("example.com", &mut ips));
+    }
+}
@@ -14,12 +14,23 @@ impl GeoIPFilter {
     }
 }
 
+/// Matches the public addresses outside of the country, the answers a
+/// domestic nameserver is poisoned with for the blocked domains.
 impl FallbackIPFilter for GeoIPFilter {
     fn apply(&self, ip: &net::IpAddr) -> bool {
-        self.1
-            .lookup(*ip)
-            .map(|x| x.country)
-            .is_ok_and(|x| x.is_some_and(|x| x.iso_code == Some(self.0.as_str())))
+        !is_private(ip)
+            && !self
+                .1
+                .country_code(*ip)
+                .is_ok_and(|x| x.is_some_and(|x| x.eq_ignore_ascii_case(&self.0)))
+    }
+}
+
+fn is_private(ip: &net::IpAddr) -> bool {
+    match ip {
+        net::IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        // the unique local ones, fc00::/7
+        net::IpAddr::V6(v6) => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00,
     }
 }
 
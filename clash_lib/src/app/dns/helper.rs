@@ -1,5 +1,5 @@
 use crate::dns::dns_client::{DNSNetMode, DnsClient, Opts};
-use crate::dns::{ClashResolver, ThreadSafeDNSClient};
+use crate::dns::{ClashResolver, Nat64, ThreadSafeDNSClient};
 use crate::dns_debug;
 use crate::proxy::utils::Interface;
 use std::sync::Arc;
@@ -10,6 +10,7 @@ use super::config::NameServer;
 pub async fn make_clients(
     servers: Vec<NameServer>,
     resolver: Option<Arc<dyn ClashResolver>>,
+    nat64: Option<Nat64>,
 ) -> Vec<ThreadSafeDNSClient> {
     let mut rv = Vec::new();
 
@@ -24,7 +25,8 @@ pub async fn make_clients(
                 .address
                 .strip_suffix(format!(":{}", port).as_str())
                 .unwrap_or_else(|| panic!("invalid address: {}", s.address));
-            (host, port)
+            // an IPv6 address is bracketed
+            (host.trim_start_matches('[').trim_end_matches(']'), port)
         };
 
         match DnsClient::new_client(Opts {
@@ -35,6 +37,11 @@ pub async fn make_clients(
                 .unwrap_or_else(|_| panic!("no port for DNS server: {}", s.address)),
             net: s.net.to_owned(),
             iface: s.interface.as_ref().map(|x| Interface::Name(x.to_owned())),
+            proxy: s.proxy.clone(),
+            nat64,
+            skip_cert_verify: s.skip_cert_verify,
+            fallback_tcp: s.fallback_tcp,
+            path: s.path.clone(),
         })
         .await
         {
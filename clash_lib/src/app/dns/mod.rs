@@ -1,30 +1,45 @@
 use async_trait::async_trait;
 
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
 
 use hickory_proto::op;
 use std::sync::Arc;
 
+use crate::{
+    app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
+    common::geosite::Geosite,
+};
+
 #[cfg(test)]
 use mockall::automock;
 
 mod config;
 mod dhcp;
 mod dns_client;
+mod doh;
 mod dummy_keys;
 mod fakeip;
+mod fastest_ip;
 mod filters;
 mod helper;
+mod http_proxy;
+mod nat64;
+mod policy;
 pub mod resolver;
+mod rewrite;
+mod route_hints;
 mod server;
 mod system;
+pub mod system_dns;
 
 pub use system::SystemResolver;
 
-pub use config::Config;
+pub use config::{Config, PolicyDomains};
+pub use nat64::Nat64;
 
 pub use resolver::Resolver;
-pub use server::get_dns_listener;
+pub use route_hints::RouteHints;
+pub use server::{get_dns_listener, handle_query, handle_query_upstream};
 
 #[macro_export]
 macro_rules! dns_debug {
@@ -84,6 +99,12 @@ pub trait ClashResolver: Sync + Send {
     ) -> anyhow::Result<Option<std::net::Ipv6Addr>>;
 
     async fn exchange(&self, message: op::Message) -> anyhow::Result<op::Message>;
+    /// [`Self::exchange`], with the nameserver that answered, `cache` for the
+    /// cached answers.
+    async fn exchange_upstream(
+        &self,
+        message: op::Message,
+    ) -> anyhow::Result<(op::Message, String)>;
 
     /// Only used for look up fake IP
     async fn reverse_lookup(&self, ip: std::net::IpAddr) -> Option<String>;
@@ -92,8 +113,21 @@ pub trait ClashResolver: Sync + Send {
 
     fn ipv6(&self) -> bool;
     fn set_ipv6(&self, enable: bool);
+    /// The NAT64 IPv4 destinations are dialed through on an IPv6-only host.
+    fn nat64(&self) -> Option<Nat64>;
+    /// The proxies the answers for some domains are routed to, see
+    /// `nameserver-policy`.
+    fn route_hints(&self) -> Option<Arc<RouteHints>>;
+    /// Hand the rule providers of the router to the `rule-set:` keys of
+    /// `nameserver-policy`.
+    fn bind_rule_providers(&self, providers: &HashMap<String, ThreadSafeRuleProvider>);
+    /// Load the `geosite:` keys of `nameserver-policy` from the database,
+    /// downloading it first.
+    async fn bind_geosite(&self, geosite: &Geosite) -> Result<(), crate::Error>;
 
     fn kind(&self) -> ResolverKind;
 
     fn fake_ip_enabled(&self) -> bool;
+    /// Whether `rewrite` answers for the host, which then gets no fake IP.
+    fn is_rewritten(&self, host: &str) -> bool;
 }
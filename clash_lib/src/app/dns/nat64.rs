@@ -0,0 +1,191 @@
+//! NAT64 for IPv6-only hosts
+//!
+//! An IPv4 destination is reached through the NAT64 gateway of the network
+//! by dialing its IPv4-embedded IPv6 address (RFC 6052), the prefix of which
+//! is configured or discovered with `ipv4only.arpa` (RFC 7050).
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use ipnet::Ipv6Net;
+use tracing::{debug, warn};
+
+use crate::Error;
+
+/// RFC 7050, the name every DNS64 resolver synthesizes AAAA records for
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa";
+/// the A records of `ipv4only.arpa`
+const WELL_KNOWN_IPV4: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+/// RFC 6052 2.2
+const PREFIX_LENS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Nat64 {
+    prefix: Ipv6Net,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Nat64Config {
+    /// discover the prefix at startup
+    Discover,
+    Prefix(Nat64),
+}
+
+impl FromStr for Nat64Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Self::Discover);
+        }
+        let prefix = s
+            .parse::<Ipv6Net>()
+            .map_err(|_| Error::InvalidConfig(format!("invalid nat64 prefix: {}", s)))?;
+        Nat64::new(prefix).map(Self::Prefix)
+    }
+}
+
+impl Nat64 {
+    pub fn new(prefix: Ipv6Net) -> Result<Self, Error> {
+        if !PREFIX_LENS.contains(&prefix.prefix_len()) {
+            return Err(Error::InvalidConfig(format!(
+                "nat64 prefix must be a /32, /40, /48, /56, /64 or /96, got {}",
+                prefix
+            )));
+        }
+        Ok(Self {
+            prefix: prefix.trunc(),
+        })
+    }
+
+    /// The prefix of the DNS64 resolver of the network.
+    pub async fn discover() -> Option<Self> {
+        let addrs = match tokio::net::lookup_host(format!("{}:0", IPV4ONLY_ARPA)).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("failed to discover the nat64 prefix: {}", e);
+                return None;
+            }
+        };
+        let nat64 = addrs.into_iter().find_map(|addr| match addr.ip() {
+            IpAddr::V6(v6) => Self::prefix_of(v6),
+            IpAddr::V4(_) => None,
+        });
+        match nat64 {
+            Some(nat64) => debug!("discovered nat64 prefix {}", nat64.prefix),
+            None => warn!("no nat64 prefix found, is the network IPv6-only with DNS64?"),
+        }
+        nat64
+    }
+
+    /// The prefix of an address synthesized for a well known IPv4 address.
+    fn prefix_of(ip: Ipv6Addr) -> Option<Self> {
+        PREFIX_LENS.iter().find_map(|len| {
+            let nat64 = Self::new(Ipv6Net::new(ip, *len).ok()?).ok()?;
+            nat64
+                .extract(ip)
+                .filter(|v4| WELL_KNOWN_IPV4.contains(v4))
+                .map(|_| nat64)
+        })
+    }
+
+    /// The octets of the IPv6 address holding the IPv4 one, bits 64 to 71
+    /// are skipped.
+    fn positions(&self) -> impl Iterator<Item = usize> {
+        (self.prefix.prefix_len() as usize / 8..16)
+            .filter(|i| *i != 8)
+            .take(4)
+    }
+
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.network().octets();
+        for (i, b) in self.positions().zip(ip.octets()) {
+            octets[i] = b;
+        }
+        octets.into()
+    }
+
+    /// The IPv4 address embedded, if `ip` has the prefix.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        if !self.prefix.contains(&ip) {
+            return None;
+        }
+        let octets = ip.octets();
+        let mut v4 = [0u8; 4];
+        for (b, i) in v4.iter_mut().zip(self.positions()) {
+            *b = octets[i];
+        }
+        Some(v4.into())
+    }
+
+    /// The address to dial for `ip`.
+    pub fn map(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(v4) => IpAddr::V6(self.synthesize(v4)),
+            v6 => v6,
+        }
+    }
+
+    /// The address a packet from `ip` came from, undoing [`Self::map`].
+    pub fn unmap(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(v6) => self.extract(v6).map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{Nat64, Nat64Config};
+
+    fn nat64(s: &str) -> Nat64 {
+        match s.parse::<Nat64Config>().unwrap() {
+            Nat64Config::Prefix(nat64) => nat64,
+            Nat64Config::Discover => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_synthesize() {
+        // RFC 6052 2.4
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::192.0.2.33"),
+        ] {
+            let nat64 = nat64(prefix);
+            let v6 = nat64.synthesize(v4);
+            assert_eq!(v6, expected.parse::<std::net::Ipv6Addr>().unwrap());
+            assert_eq!(nat64.extract(v6), Some(v4));
+        }
+        assert_eq!(
+            nat64("64:ff9b::/96").extract("2001:db8::1".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_prefix_of() {
+        assert_eq!(
+            Nat64::prefix_of("64:ff9b::c000:aa".parse().unwrap()),
+            Some(nat64("64:ff9b::/96"))
+        );
+        assert_eq!(Nat64::prefix_of("2001:db8::1".parse().unwrap()), None);
+        assert!("64:ff9b::/100".parse::<Nat64Config>().is_err());
+        assert_eq!(
+            "auto".parse::<Nat64Config>().unwrap(),
+            Nat64Config::Discover
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! The nameservers of `nameserver-policy`, picked by the domain of a query.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use tracing::warn;
+
+use crate::{
+    app::remote_content_manager::providers::rule_provider::{
+        RuleSetBehavior, ThreadSafeRuleProvider,
+    },
+    common::{
+        geosite::{Geosite, GeositeMatcher},
+        trie,
+    },
+    session::{Session, SocksAddr},
+    Error,
+};
+
+use super::ThreadSafeDNSClient;
+
+enum PolicySet {
+    /// the category, matching nothing until bound
+    Geosite(String),
+    /// the name of a rule provider, matching nothing until bound
+    RuleSet(String),
+}
+
+#[derive(Default)]
+pub struct Policy {
+    domains: trie::StringTrie<Vec<ThreadSafeDNSClient>>,
+    sets: Vec<(PolicySet, Vec<ThreadSafeDNSClient>)>,
+    /// the rule providers of the sets, loaded by the router
+    rule_providers: RwLock<HashMap<String, ThreadSafeRuleProvider>>,
+    /// the matchers of the geosite sets, keyed by their category
+    geosites: RwLock<HashMap<String, Arc<GeositeMatcher>>>,
+}
+
+impl Policy {
+    pub fn add_domain(&mut self, domain: &str, clients: Vec<ThreadSafeDNSClient>) {
+        self.domains.insert(domain, Arc::new(clients));
+    }
+
+    pub fn add_geosite(&mut self, category: &str, clients: Vec<ThreadSafeDNSClient>) {
+        self.sets
+            .push((PolicySet::Geosite(category.to_owned()), clients));
+    }
+
+    pub fn add_rule_set(&mut self, name: &str, clients: Vec<ThreadSafeDNSClient>) {
+        self.sets
+            .push((PolicySet::RuleSet(name.to_owned()), clients));
+    }
+
+    /// Take the providers of the `rule-set:` keys from those of the router.
+    pub fn bind_rule_providers(&self, providers: &HashMap<String, ThreadSafeRuleProvider>) {
+        let mut bound = self.rule_providers.write().unwrap();
+        bound.clear();
+        for (set, _) in self.sets.iter() {
+            let PolicySet::RuleSet(name) = set else {
+                continue;
+            };
+            match providers.get(name) {
+                Some(p) if matches!(p.behavior(), RuleSetBehavior::Ipcidr) => {
+                    warn!("nameserver-policy rule-set:{} has no domains", name);
+                }
+                Some(p) => {
+                    bound.insert(name.to_owned(), p.clone());
+                }
+                None => warn!("nameserver-policy rule-set:{} not found", name),
+            }
+        }
+    }
+
+    /// Take the matchers of the `geosite:` keys from the database,
+    /// downloading it first.
+    pub async fn bind_geosite(&self, geosite: &Geosite) -> Result<(), Error> {
+        let categories = self
+            .sets
+            .iter()
+            .filter_map(|(set, _)| match set {
+                PolicySet::Geosite(category) => Some(category),
+                PolicySet::RuleSet(_) => None,
+            })
+            .collect::<Vec<_>>();
+        if categories.is_empty() {
+            return Ok(());
+        }
+
+        geosite.ensure_downloaded().await?;
+        let mut bound = HashMap::new();
+        for category in categories {
+            let matcher = geosite.matcher(category).map_err(|e| {
+                Error::InvalidConfig(format!("nameserver-policy geosite:{}: {}", category, e))
+            })?;
+            bound.insert(category.to_owned(), matcher);
+        }
+        *self.geosites.write().unwrap() = bound;
+        Ok(())
+    }
+
+    /// The nameservers of the domain, if it's one of a policy.
+    pub fn lookup(&self, domain: &str) -> Option<&Vec<ThreadSafeDNSClient>> {
+        if let Some(clients) = self.domains.search(domain).and_then(|x| x.get_data()) {
+            return Some(clients);
+        }
+
+        let sess = Session {
+            destination: SocksAddr::Domain(domain.to_owned(), 0),
+            ..Default::default()
+        };
+        let providers = self.rule_providers.read().unwrap();
+        let geosites = self.geosites.read().unwrap();
+        self.sets
+            .iter()
+            .find(|(set, _)| match set {
+                PolicySet::Geosite(category) => {
+                    geosites.get(category).is_some_and(|x| x.matches(domain))
+                }
+                PolicySet::RuleSet(name) => providers.get(name).is_some_and(|x| x.search(&sess)),
+            })
+            .map(|(_, clients)| clients)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Policy;
+    use crate::{
+        app::dns::SystemResolver,
+        common::{geosite::Geosite, http::new_http_client},
+    };
+
+    #[tokio::test]
+    async fn test_bind_missing_geosite() {
+        let resolver = Arc::new(SystemResolver::new().unwrap());
+        let geosite = Geosite::new(
+            "/nonexistent/geosite.dat",
+            None,
+            new_http_client(resolver).unwrap(),
+        );
+
+        let mut policy = Policy::default();
+        policy.add_domain("+.example.com", vec![]);
+        assert!(policy.bind_geosite(&geosite).await.is_ok());
+
+        // an error as for an unknown rule-set, not a policy silently gone
+        policy.add_geosite("cn", vec![]);
+        assert!(policy.bind_geosite(&geosite).await.is_err());
+    }
+}
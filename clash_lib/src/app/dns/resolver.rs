@@ -3,15 +3,16 @@ use futures::{FutureExt, TryFutureExt};
 use rand::prelude::SliceRandom;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::Duration;
-use std::{net, sync::Arc};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, net, sync::Arc};
 use tokio::sync::RwLock;
 use tracing::{debug, instrument, warn};
 
 use hickory_proto::{op, rr};
 
 use crate::app::profile::ThreadSafeCacheFile;
-use crate::common::mmdb::Mmdb;
+use crate::app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider;
+use crate::common::{geosite::Geosite, mmdb::Mmdb};
 use crate::config::def::DNSMode;
 use crate::dns::helper::make_clients;
 use crate::dns::ThreadSafeDNSClient;
@@ -19,14 +20,24 @@ use crate::dns_debug;
 use crate::{common::trie, Error};
 
 use super::fakeip::{self, FileStore, InMemStore, ThreadSafeFakeDns};
+use super::fastest_ip::FastestIp;
+use super::nat64::{Nat64, Nat64Config};
+use super::policy::Policy;
+use super::rewrite::{Rewrite, RewriteAnswer};
+use super::route_hints::RouteHints;
 use super::system::SystemResolver;
 use super::{
     filters::{DomainFilter, FallbackDomainFilter, FallbackIPFilter, GeoIPFilter, IPNetFilter},
-    Config,
+    Config, PolicyDomains,
 };
 use super::{ClashResolver, ResolverKind, ThreadSafeDNSResolver};
 
 static TTL: Duration = Duration::from_secs(60);
+/// of the answers made from `hosts` and `rewrite`
+static HOSTS_TTL: u32 = 60;
+
+/// the answers, with when they were cached
+type AnswerCache = lru_time_cache::LruCache<String, (op::Message, Instant)>;
 
 pub struct Resolver {
     ipv6: AtomicBool,
@@ -37,10 +48,20 @@ pub struct Resolver {
     fallback_domain_filters: Option<Vec<Box<dyn FallbackDomainFilter>>>,
     fallback_ip_filters: Option<Vec<Box<dyn FallbackIPFilter>>>,
 
-    lru_cache: Option<Arc<RwLock<lru_time_cache::LruCache<String, op::Message>>>>,
-    policy: Option<trie::StringTrie<Vec<ThreadSafeDNSClient>>>,
+    lru_cache: Option<Arc<RwLock<AnswerCache>>>,
+    policy: Option<Policy>,
 
     fake_dns: Option<ThreadSafeFakeDns>,
+    /// AAAA fake addresses, only with `fake-ip-range-v6`
+    fake_dns_v6: Option<ThreadSafeFakeDns>,
+    /// pick the fastest of the answers instead of a random one
+    fastest_ip: Option<FastestIp>,
+    /// on an IPv6-only host, AAAA records are preferred
+    nat64: Option<Nat64>,
+    /// the answers for the domains with a `route` in `nameserver-policy`
+    route_hints: Option<Arc<RouteHints>>,
+    /// the static answers of `rewrite`
+    rewrite: Option<Rewrite>,
 }
 
 impl Resolver {
@@ -59,8 +80,13 @@ impl Resolver {
                     net: DNSNetMode::Udp,
                     address: "8.8.8.8:53".to_string(),
                     interface: None,
+                    proxy: None,
+                    skip_cert_verify: false,
+                    fallback_tcp: false,
+                    path: None,
                 }],
                 None,
+                None,
             )
             .await,
             fallback: None,
@@ -70,6 +96,11 @@ impl Resolver {
             policy: None,
 
             fake_dns: None,
+            fake_dns_v6: None,
+            fastest_ip: None,
+            nat64: None,
+            route_hints: None,
+            rewrite: None,
         }
     }
 
@@ -77,15 +108,24 @@ impl Resolver {
         cfg: &Config,
         store: ThreadSafeCacheFile,
         mmdb: Arc<Mmdb>,
-    ) -> ThreadSafeDNSResolver {
+    ) -> Result<ThreadSafeDNSResolver, Error> {
         if !cfg.enable {
-            return Arc::new(SystemResolver::new().expect("failed to create system resolver"));
+            return Ok(Arc::new(
+                SystemResolver::new().expect("failed to create system resolver"),
+            ));
         }
 
+        let nat64 = match cfg.nat64 {
+            Some(Nat64Config::Prefix(nat64)) => Some(nat64),
+            Some(Nat64Config::Discover) => Nat64::discover().await,
+            None => None,
+        };
+
+        // the upstreams are reached over IPv6 on an IPv6-only host
         let default_resolver = Arc::new(Resolver {
-            ipv6: AtomicBool::new(false),
+            ipv6: AtomicBool::new(nat64.is_some()),
             hosts: None,
-            main: make_clients(cfg.default_nameserver.clone(), None).await,
+            main: make_clients(cfg.default_nameserver.clone(), None, nat64).await,
             fallback: None,
             fallback_domain_filters: None,
             fallback_ip_filters: None,
@@ -93,14 +133,26 @@ impl Resolver {
             policy: None,
 
             fake_dns: None,
+            fake_dns_v6: None,
+            fastest_ip: None,
+            nat64,
+            route_hints: None,
+            rewrite: None,
         });
 
         let r = Resolver {
             ipv6: AtomicBool::new(cfg.ipv6),
-            main: make_clients(cfg.nameserver.clone(), Some(default_resolver.clone())).await,
+            main: make_clients(
+                cfg.nameserver.clone(),
+                Some(default_resolver.clone()),
+                nat64,
+            )
+            .await,
             hosts: cfg.hosts.clone(),
             fallback: if !cfg.fallback.is_empty() {
-                Some(make_clients(cfg.fallback.clone(), Some(default_resolver.clone())).await)
+                Some(
+                    make_clients(cfg.fallback.clone(), Some(default_resolver.clone()), nat64).await,
+                )
             } else {
                 None
             },
@@ -115,21 +167,20 @@ impl Resolver {
             } else {
                 None
             },
-            fallback_ip_filters: if cfg.fallback_filter.ip_cidr.is_some()
-                || cfg.fallback_filter.geo_ip
+            fallback_ip_filters: if cfg.fallback_filter.geo_ip
+                || !cfg.fallback_filter.ip_cidr.is_empty()
             {
                 let mut filters = vec![];
 
-                filters.push(
-                    Box::new(GeoIPFilter::new(&cfg.fallback_filter.geo_ip_code, mmdb))
-                        as Box<dyn FallbackIPFilter>,
-                );
+                if cfg.fallback_filter.geo_ip {
+                    filters.push(
+                        Box::new(GeoIPFilter::new(&cfg.fallback_filter.geo_ip_code, mmdb))
+                            as Box<dyn FallbackIPFilter>,
+                    );
+                }
 
-                if let Some(ipcidr) = &cfg.fallback_filter.ip_cidr {
-                    for subnet in ipcidr {
-                        filters
-                            .push(Box::new(IPNetFilter::new(*subnet)) as Box<dyn FallbackIPFilter>)
-                    }
+                for subnet in cfg.fallback_filter.ip_cidr.iter() {
+                    filters.push(Box::new(IPNetFilter::new(*subnet)) as Box<dyn FallbackIPFilter>)
                 }
 
                 Some(filters)
@@ -140,55 +191,76 @@ impl Resolver {
                 lru_time_cache::LruCache::with_expiry_duration_and_capacity(TTL, 4096),
             ))),
             policy: if !cfg.nameserver_policy.is_empty() {
-                let mut p = trie::StringTrie::new();
-                for (domain, ns) in &cfg.nameserver_policy {
-                    p.insert(
-                        domain.as_str(),
-                        Arc::new(
-                            make_clients(vec![ns.to_owned()], Some(default_resolver.clone())).await,
-                        ),
-                    );
+                let mut p = Policy::default();
+                for (domains, servers) in &cfg.nameserver_policy {
+                    let clients =
+                        make_clients(servers.clone(), Some(default_resolver.clone()), nat64).await;
+                    match domains {
+                        PolicyDomains::Domain(domain) => p.add_domain(domain, clients),
+                        PolicyDomains::Geosite(category) => p.add_geosite(category, clients),
+                        PolicyDomains::RuleSet(name) => p.add_rule_set(name, clients),
+                    }
                 }
                 Some(p)
             } else {
                 None
             },
             fake_dns: match cfg.enhance_mode {
-                DNSMode::FakeIp => Some(Arc::new(RwLock::new(
-                    fakeip::FakeDns::new(fakeip::Opts {
+                DNSMode::FakeIp => {
+                    Some(Arc::new(RwLock::new(fakeip::FakeDns::new(fakeip::Opts {
                         ipnet: cfg.fake_ip_range,
-                        skipped_hostnames: if !cfg.fake_ip_filter.is_empty() {
-                            let mut host = trie::StringTrie::new();
-                            for domain in cfg.fake_ip_filter.iter() {
-                                host.insert(domain.as_str(), Arc::new(true));
-                            }
-                            Some(host)
-                        } else {
-                            None
-                        },
+                        skipped_hostnames: cfg.fake_ip_filter.clone(),
                         store: if cfg.store_fake_ip {
-                            Box::new(FileStore::new(store))
+                            Box::new(FileStore::new(store.clone(), false))
                         } else {
                             Box::new(InMemStore::new(1000))
                         },
-                    })
-                    .unwrap(),
-                ))),
+                    })?)))
+                }
                 DNSMode::RedirHost => {
                     warn!("dns redir-host is not supported and will not do anything");
                     None
                 }
                 _ => None,
             },
+            fake_dns_v6: match (&cfg.enhance_mode, cfg.fake_ip_range_v6) {
+                (DNSMode::FakeIp, Some(ipnet)) => {
+                    Some(Arc::new(RwLock::new(fakeip::FakeDns::new(fakeip::Opts {
+                        ipnet,
+                        skipped_hostnames: cfg.fake_ip_filter.clone(),
+                        store: if cfg.store_fake_ip {
+                            Box::new(FileStore::new(store, true))
+                        } else {
+                            Box::new(InMemStore::new(1000))
+                        },
+                    })?)))
+                }
+                _ => None,
+            },
+            fastest_ip: cfg.fastest_ip.then(FastestIp::new),
+            nat64,
+            route_hints: (!cfg.route_hints.is_empty())
+                .then(|| Arc::new(RouteHints::new(&cfg.route_hints))),
+            rewrite: (!cfg.rewrite.is_empty()).then(|| cfg.rewrite.clone()),
         };
 
-        Arc::new(r)
+        Ok(Arc::new(r))
     }
 
     pub async fn batch_exchange(
         clients: &Vec<ThreadSafeDNSClient>,
         message: &op::Message,
     ) -> anyhow::Result<op::Message> {
+        Resolver::batch_exchange_upstream(clients, message)
+            .await
+            .map(|x| x.0)
+    }
+
+    /// The first answer of the clients, with the id of the client.
+    async fn batch_exchange_upstream(
+        clients: &Vec<ThreadSafeDNSClient>,
+        message: &op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
         let mut queries = Vec::new();
         for c in clients {
             queries.push(
@@ -198,6 +270,7 @@ impl Resolver {
                             debug!("DNS client {} resolve error: {}", c.id(), x.to_string())
                         })
                         .await
+                        .map(|x| (x, c.id()))
                 }
                 .boxed(),
             )
@@ -230,8 +303,8 @@ impl Resolver {
         m.add_query(q);
         m.set_recursion_desired(true);
 
-        match self.exchange(m).await {
-            Ok(result) => {
+        match self.exchange_upstream(m).await {
+            Ok((result, _)) => {
                 let ip_list = Resolver::ip_list_of_message(&result);
                 if !ip_list.is_empty() {
                     Ok(ip_list)
@@ -243,20 +316,62 @@ impl Resolver {
         }
     }
 
-    async fn exchange(&self, message: op::Message) -> anyhow::Result<op::Message> {
+    /// The answer to the message, with the nameserver that gave it.
+    async fn exchange_upstream(
+        &self,
+        message: op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
+        if let Some(answer) = self.rewrite_answer(&message).await {
+            return answer.map(|x| (x, String::from("rewrite")));
+        }
+        self.exchange_no_rewrite(message).await
+    }
+
+    async fn exchange_no_rewrite(
+        &self,
+        message: op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
         if let Some(q) = message.query() {
-            if let Some(lru) = &self.lru_cache {
-                if let Some(cached) = lru.read().await.peek(q.to_string().as_str()) {
-                    return Ok(cached.clone());
+            if let Some(answer) = self.hosts_answer(&message) {
+                return Ok((answer, String::from("hosts")));
+            }
+
+            let cached = match &self.lru_cache {
+                Some(lru) => lru
+                    .read()
+                    .await
+                    .peek(q.to_string().as_str())
+                    .and_then(|(msg, at)| Resolver::aged(msg, *at)),
+                None => None,
+            };
+            let mut rv = match cached {
+                Some(cached) => Ok((cached, String::from("cache"))),
+                None => self.exchange_no_cache(&message).await,
+            };
+            if let Ok((msg, _)) = &mut rv {
+                self.sort_answers(msg);
+            }
+
+            if let (Some(hints), Ok((msg, _))) = (&self.route_hints, &rv) {
+                if let Some(domain) = Resolver::domain_name_of_message(msg) {
+                    let ttl = msg.answers().iter().map(|x| x.ttl()).min().unwrap_or(0);
+                    hints.record(
+                        &domain,
+                        &Resolver::ip_list_of_message(msg),
+                        Duration::from_secs(ttl as u64),
+                    );
                 }
             }
-            self.exchange_no_cache(&message).await
+            rv
         } else {
             Err(anyhow!("invalid query"))
         }
     }
 
-    async fn exchange_no_cache(&self, message: &op::Message) -> anyhow::Result<op::Message> {
+    async fn exchange_no_cache(
+        &self,
+        message: &op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
         let q = message.query().unwrap();
 
         let query = async move {
@@ -265,15 +380,15 @@ impl Resolver {
             }
 
             if let Some(matched) = self.match_policy(message) {
-                return Resolver::batch_exchange(matched, message).await;
+                return Resolver::batch_exchange_upstream(matched, message).await;
             }
 
-            Resolver::batch_exchange(&self.main, message).await
+            Resolver::batch_exchange_upstream(&self.main, message).await
         };
 
         let rv = query.await;
 
-        if let Ok(msg) = &rv {
+        if let Ok((msg, _)) = &rv {
             if let Some(lru) = &self.lru_cache {
                 if !(q.query_type() == rr::RecordType::TXT
                     && q.name().to_ascii().starts_with("_acme-challenge."))
@@ -300,7 +415,9 @@ impl Resolver {
                             .unwrap_or_default()
                     };
 
-                    lru.write().await.insert(q.to_string(), msg.clone());
+                    lru.write()
+                        .await
+                        .insert(q.to_string(), (msg.clone(), Instant::now()));
                 }
             }
         }
@@ -308,46 +425,153 @@ impl Resolver {
         rv
     }
 
-    fn match_policy(&self, m: &op::Message) -> Option<&Vec<ThreadSafeDNSClient>> {
-        if let (Some(_fallback), Some(_fallback_domain_filters), Some(policy)) =
-            (&self.fallback, &self.fallback_domain_filters, &self.policy)
-        {
-            if let Some(domain) = Resolver::domain_name_of_message(m) {
-                return policy.search(&domain).map(|n| n.get_data().unwrap());
+    /// The cached answer with its TTLs counted down since `at`, none once
+    /// one of the answers has expired.
+    fn aged(msg: &op::Message, at: Instant) -> Option<op::Message> {
+        let elapsed = u32::try_from(at.elapsed().as_secs()).unwrap_or(u32::MAX);
+        if msg.answers().iter().any(|x| x.ttl() <= elapsed) {
+            return None;
+        }
+
+        let age = |records: Vec<rr::Record>| {
+            records
+                .into_iter()
+                .map(|mut x| {
+                    x.set_ttl(x.ttl().saturating_sub(elapsed));
+                    x
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut msg = msg.clone();
+        let answers = age(msg.take_answers());
+        msg.insert_answers(answers);
+        let name_servers = age(msg.take_name_servers());
+        msg.insert_name_servers(name_servers);
+        let additionals = age(msg.take_additionals());
+        msg.insert_additionals(additionals);
+        Some(msg)
+    }
+
+    /// The answer to an A or AAAA query for a host of `hosts`, empty if the
+    /// host is mapped to an address of the other family.
+    fn hosts_answer(&self, message: &op::Message) -> Option<op::Message> {
+        let q = message.query()?;
+        if !matches!(q.query_type(), rr::RecordType::A | rr::RecordType::AAAA) {
+            return None;
+        }
+        let domain = Resolver::domain_name_of_message(message)?;
+        let ip = self.hosts.as_ref()?.search(&domain)?.get_data()?;
+
+        let mut m = Resolver::local_response(message, q);
+        if let Some(rdata) = Resolver::addr_rdata(ip, q.query_type()) {
+            m.add_answer(rr::Record::from_rdata(q.name().clone(), HOSTS_TTL, rdata));
+        }
+        Some(m)
+    }
+
+    /// The answer of `rewrite` to the message. The records of a CNAME's
+    /// target are looked up too, unless the CNAME itself is asked.
+    async fn rewrite_answer(&self, message: &op::Message) -> Option<anyhow::Result<op::Message>> {
+        let q = message.query()?;
+        let domain = Resolver::domain_name_of_message(message)?;
+        let answer = self.rewrite.as_ref()?.lookup(&domain)?;
+        dns_debug!("dns rewrite {}: {:?}", domain, answer);
+
+        let mut m = Resolver::local_response(message, q);
+        match answer {
+            RewriteAnswer::Addrs(ips) => {
+                for ip in ips {
+                    if let Some(rdata) = Resolver::addr_rdata(ip, q.query_type()) {
+                        m.add_answer(rr::Record::from_rdata(q.name().clone(), HOSTS_TTL, rdata));
+                    }
+                }
+            }
+            RewriteAnswer::Rcode(rcode) => {
+                m.set_response_code(*rcode);
+            }
+            RewriteAnswer::Cname(target) => {
+                let cname = rr::RData::CNAME(rr::rdata::CNAME(target.clone()));
+                m.add_answer(rr::Record::from_rdata(q.name().clone(), HOSTS_TTL, cname));
+                if q.query_type() != rr::RecordType::CNAME {
+                    let mut follow = op::Message::new();
+                    follow.add_query(op::Query::query(target.clone(), q.query_type()));
+                    follow.set_recursion_desired(true);
+                    // not rewritten again, so rewrites can't loop
+                    match self.exchange_no_rewrite(follow).await {
+                        Ok((target, _)) => {
+                            m.set_response_code(target.response_code());
+                            m.add_answers(target.answers().iter().cloned());
+                        }
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
             }
         }
-        None
+        Some(Ok(m))
+    }
+
+    /// An empty response to the query, answered locally.
+    fn local_response(message: &op::Message, q: &op::Query) -> op::Message {
+        let mut m = op::Message::new();
+        m.set_id(message.id());
+        m.set_message_type(op::MessageType::Response);
+        m.set_op_code(message.op_code());
+        m.set_recursion_desired(message.recursion_desired());
+        m.set_recursion_available(true);
+        m.add_query(q.clone());
+        m
+    }
+
+    fn addr_rdata(ip: &net::IpAddr, query_type: rr::RecordType) -> Option<rr::RData> {
+        match (ip, query_type) {
+            (net::IpAddr::V4(v4), rr::RecordType::A) => Some(rr::RData::A((*v4).into())),
+            (net::IpAddr::V6(v6), rr::RecordType::AAAA) => Some(rr::RData::AAAA((*v6).into())),
+            _ => None,
+        }
+    }
+
+    fn match_policy(&self, m: &op::Message) -> Option<&Vec<ThreadSafeDNSClient>> {
+        let policy = self.policy.as_ref()?;
+        let domain = Resolver::domain_name_of_message(m)?;
+        policy.lookup(&domain)
     }
 
-    async fn ip_exchange(&self, message: &op::Message) -> anyhow::Result<op::Message> {
+    async fn ip_exchange(&self, message: &op::Message) -> anyhow::Result<(op::Message, String)> {
         if let Some(matched) = self.match_policy(message) {
-            return Resolver::batch_exchange(matched, message).await;
+            return Resolver::batch_exchange_upstream(matched, message).await;
         }
 
         if self.should_only_query_fallback(message) {
             // self.fallback guaranteed in the above check
-            return Resolver::batch_exchange(self.fallback.as_ref().unwrap(), message).await;
+            return Resolver::batch_exchange_upstream(self.fallback.as_ref().unwrap(), message)
+                .await;
         }
 
-        let main_query = Resolver::batch_exchange(&self.main, message);
+        let main_query = Resolver::batch_exchange_upstream(&self.main, message);
 
         if self.fallback.is_none() {
             return main_query.await;
         }
 
-        let fallback_query = Resolver::batch_exchange(self.fallback.as_ref().unwrap(), message);
+        // in parallel, not to wait for it after a poisoned answer
+        let fallback_query = tokio::spawn({
+            let fallback = self.fallback.clone().unwrap();
+            let message = message.clone();
+            async move { Resolver::batch_exchange_upstream(&fallback, &message).await }
+        });
 
         if let Ok(main_result) = main_query.await {
-            let ip_list = Resolver::ip_list_of_message(&main_result);
+            let ip_list = Resolver::ip_list_of_message(&main_result.0);
             if !ip_list.is_empty() {
                 // TODO: only check 1st?
                 if !self.should_ip_fallback(&ip_list[0]) {
+                    fallback_query.abort();
                     return Ok(main_result);
                 }
             }
         }
 
-        fallback_query.await
+        fallback_query.await?
     }
 
     fn should_only_query_fallback(&self, message: &op::Message) -> bool {
@@ -376,6 +600,47 @@ impl Resolver {
         false
     }
 
+    /// One of the addresses of the answer to dial, the fastest if known
+    fn pick_ip(&self, host: &str, ips: &[net::IpAddr]) -> net::IpAddr {
+        if let Some(fastest_ip) = &self.fastest_ip {
+            let mut ips = ips.to_vec();
+            if fastest_ip.sort(host, &mut ips) {
+                return ips[0];
+            }
+        }
+        *ips.choose(&mut rand::thread_rng()).unwrap()
+    }
+
+    /// Put the addresses of the answer the fastest first, if known.
+    fn sort_answers(&self, msg: &mut op::Message) {
+        let (Some(fastest_ip), Some(domain)) =
+            (&self.fastest_ip, Resolver::domain_name_of_message(msg))
+        else {
+            return;
+        };
+        let mut ips = Resolver::ip_list_of_message(msg);
+        if !fastest_ip.sort(&domain, &mut ips) {
+            return;
+        }
+        let mut answers = msg.take_answers();
+        // the CNAMEs stay in front
+        answers.sort_by_key(|r| match r.data() {
+            Some(rr::RData::A(v4)) => ips.iter().position(|x| *x == net::IpAddr::V4(**v4)),
+            Some(rr::RData::AAAA(v6)) => ips.iter().position(|x| *x == net::IpAddr::V6(**v6)),
+            _ => None,
+        });
+        msg.insert_answers(answers);
+    }
+
+    /// The fake ip pool of the IP family, the v4 one if there is no v6 pool.
+    /// Only call this when fake ip is enabled.
+    fn fake_pool_of(&self, ip: net::IpAddr) -> &ThreadSafeFakeDns {
+        match (ip, &self.fake_dns_v6) {
+            (net::IpAddr::V6(_), Some(v6)) => v6,
+            _ => self.fake_dns.as_ref().unwrap(),
+        }
+    }
+
     // helpers
     fn is_ip_request(q: &op::Query) -> bool {
         q.query_class() == rr::DNSClass::IN
@@ -409,6 +674,17 @@ impl Resolver {
 impl ClashResolver for Resolver {
     #[instrument(skip(self))]
     async fn resolve(&self, host: &str, enhanced: bool) -> anyhow::Result<Option<net::IpAddr>> {
+        if self.nat64.is_some() {
+            // IPv4 addresses only work through the NAT64
+            if let Ok(Some(v6)) = self.resolve_v6(host, enhanced).await {
+                return Ok(Some(v6.into()));
+            }
+            return self
+                .resolve_v4(host, enhanced)
+                .await
+                .map(|ip| ip.map(net::IpAddr::from));
+        }
+
         match self.ipv6.load(Relaxed) {
             true => {
                 let fut1 = self
@@ -437,14 +713,14 @@ impl ClashResolver for Resolver {
         host: &str,
         enhanced: bool,
     ) -> anyhow::Result<Option<net::Ipv4Addr>> {
-        if enhanced {
-            if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V4(v4) => *v4,
-                        _ => unreachable!("invalid IP family"),
-                    }));
-                }
+        // before the fake IPs, the mapped hosts keep their addresses, unless
+        // `rewrite` answers for them
+        if let Some(hosts) = self.hosts.as_ref().filter(|_| !self.is_rewritten(host)) {
+            if let Some(v) = hosts.search(host) {
+                return Ok(v.get_data().and_then(|v| match v {
+                    net::IpAddr::V4(v4) => Some(*v4),
+                    _ => None,
+                }));
             }
         }
 
@@ -452,7 +728,7 @@ impl ClashResolver for Resolver {
             return Ok(Some(ip));
         }
 
-        if enhanced && self.fake_ip_enabled() {
+        if enhanced && self.fake_ip_enabled() && !self.is_rewritten(host) {
             let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
             if !fake_dns.should_skip(host) {
                 let ip = fake_dns.lookup(host).await;
@@ -465,8 +741,8 @@ impl ClashResolver for Resolver {
         }
 
         match self.lookup_ip(host, rr::RecordType::A).await {
-            Ok(result) => match result.choose(&mut rand::thread_rng()).unwrap() {
-                net::IpAddr::V4(v4) => Ok(Some(*v4)),
+            Ok(result) => match self.pick_ip(host, &result) {
+                net::IpAddr::V4(v4) => Ok(Some(v4)),
                 _ => unreachable!("invalid IP family"),
             },
             Err(e) => Err(e),
@@ -482,14 +758,14 @@ impl ClashResolver for Resolver {
             return Err(Error::DNSError("ipv6 disabled".into()).into());
         }
 
-        if enhanced {
-            if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V6(v6) => *v6,
-                        _ => unreachable!("invalid IP family"),
-                    }));
-                }
+        // before the fake IPs, the mapped hosts keep their addresses, unless
+        // `rewrite` answers for them
+        if let Some(hosts) = self.hosts.as_ref().filter(|_| !self.is_rewritten(host)) {
+            if let Some(v) = hosts.search(host) {
+                return Ok(v.get_data().and_then(|v| match v {
+                    net::IpAddr::V6(v6) => Some(*v6),
+                    _ => None,
+                }));
             }
         }
 
@@ -497,9 +773,39 @@ impl ClashResolver for Resolver {
             return Ok(Some(ip));
         }
 
+        if enhanced && self.fake_ip_enabled() && !self.is_rewritten(host) {
+            match &self.fake_dns_v6 {
+                Some(fake_dns) => {
+                    let mut fake_dns = fake_dns.write().await;
+                    if !fake_dns.should_skip(host) {
+                        let ip = fake_dns.lookup(host).await;
+                        dns_debug!("fake dns lookup: {} -> {:?}", host, ip);
+                        match ip {
+                            net::IpAddr::V6(v6) => return Ok(Some(v6)),
+                            _ => unreachable!("invalid IP family"),
+                        }
+                    }
+                }
+                // no v6 pool, don't leak the real AAAA records so the client
+                // falls back to the v4 fake address
+                None => {
+                    if !self
+                        .fake_dns
+                        .as_ref()
+                        .unwrap()
+                        .read()
+                        .await
+                        .should_skip(host)
+                    {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
         match self.lookup_ip(host, rr::RecordType::AAAA).await {
-            Ok(result) => match result.choose(&mut rand::thread_rng()).unwrap() {
-                net::IpAddr::V6(v6) => Ok(Some(*v6)),
+            Ok(result) => match self.pick_ip(host, &result) {
+                net::IpAddr::V6(v6) => Ok(Some(v6)),
                 _ => unreachable!("invalid IP family"),
             },
 
@@ -508,7 +814,14 @@ impl ClashResolver for Resolver {
     }
 
     async fn exchange(&self, message: op::Message) -> anyhow::Result<op::Message> {
-        self.exchange(message).await
+        self.exchange_upstream(message).await.map(|x| x.0)
+    }
+
+    async fn exchange_upstream(
+        &self,
+        message: op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
+        self.exchange_upstream(message).await
     }
 
     fn ipv6(&self) -> bool {
@@ -519,6 +832,27 @@ impl ClashResolver for Resolver {
         self.ipv6.store(enable, Relaxed);
     }
 
+    fn nat64(&self) -> Option<Nat64> {
+        self.nat64
+    }
+
+    fn route_hints(&self) -> Option<Arc<RouteHints>> {
+        self.route_hints.clone()
+    }
+
+    fn bind_rule_providers(&self, providers: &HashMap<String, ThreadSafeRuleProvider>) {
+        if let Some(policy) = &self.policy {
+            policy.bind_rule_providers(providers);
+        }
+    }
+
+    async fn bind_geosite(&self, geosite: &Geosite) -> Result<(), Error> {
+        match &self.policy {
+            Some(policy) => policy.bind_geosite(geosite).await,
+            None => Ok(()),
+        }
+    }
+
     fn kind(&self) -> ResolverKind {
         ResolverKind::Clash
     }
@@ -527,12 +861,18 @@ impl ClashResolver for Resolver {
         self.fake_dns.is_some()
     }
 
+    fn is_rewritten(&self, host: &str) -> bool {
+        self.rewrite
+            .as_ref()
+            .is_some_and(|x| x.lookup(host.trim_end_matches('.')).is_some())
+    }
+
     async fn is_fake_ip(&self, ip: std::net::IpAddr) -> bool {
         if !self.fake_ip_enabled() {
             return false;
         }
 
-        let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
+        let mut fake_dns = self.fake_pool_of(ip).write().await;
         fake_dns.is_fake_ip(ip).await
     }
 
@@ -541,7 +881,7 @@ impl ClashResolver for Resolver {
             return false;
         }
 
-        let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
+        let mut fake_dns = self.fake_pool_of(ip).write().await;
         fake_dns.exist(ip).await
     }
 
@@ -551,23 +891,67 @@ impl ClashResolver for Resolver {
             return None;
         }
 
-        let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
+        let mut fake_dns = self.fake_pool_of(ip).write().await;
         fake_dns.reverse_lookup(ip).await
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::app::dns::rewrite::Rewrite;
+    use crate::common::trie::StringTrie;
     use crate::dns::dns_client::{DNSNetMode, DnsClient, Opts};
-    use crate::dns::{Resolver, ThreadSafeDNSClient};
+    use crate::dns::{ClashResolver, Resolver, ThreadSafeDNSClient};
     use hickory_client::{client, op};
     use hickory_proto::rr;
     use hickory_proto::udp::UdpClientStream;
     use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer};
+    use std::collections::HashMap;
     use std::sync::Arc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use tokio::net::UdpSocket;
 
+    #[test]
+    fn test_aged() {
+        let mut msg = op::Message::new();
+        msg.add_answer(rr::Record::from_rdata(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            60,
+            rr::RData::A(rr::rdata::A::new(1, 2, 3, 4)),
+        ));
+        let ago = |secs| {
+            Instant::now()
+                .checked_sub(Duration::from_secs(secs))
+                .unwrap()
+        };
+
+        let aged = Resolver::aged(&msg, ago(10)).unwrap();
+        assert_eq!(aged.answers()[0].ttl(), 50);
+        assert!(Resolver::aged(&msg, ago(60)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_before_hosts() {
+        let mut hosts = StringTrie::new();
+        for host in ["ads.example.com", "git.corp.lan"] {
+            hosts.insert(host, Arc::new("1.2.3.4".parse().unwrap()));
+        }
+        let entries = [(r"^ads\.", "NXDOMAIN"), (r"\.corp\.lan$", "10.0.0.1")]
+            .map(|(k, v)| HashMap::from([(k.to_owned(), v.to_owned())]));
+
+        let mut resolver = Resolver::new_default().await;
+        resolver.hosts = Some(hosts);
+        resolver.rewrite = Some(Rewrite::parse(&entries).unwrap());
+
+        assert!(resolver.is_rewritten("ads.example.com."));
+        assert!(!resolver.is_rewritten("example.com"));
+        assert!(resolver.resolve_v4("ads.example.com", false).await.is_err());
+        assert_eq!(
+            resolver.resolve_v4("git.corp.lan", false).await.unwrap(),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+
     #[tokio::test]
     async fn test_bad_labels_with_custom_resolver() {
         let name = rr::Name::from_str_relaxed("some_domain.understore")
@@ -607,6 +991,11 @@ mod tests {
             port: 53,
             net: DNSNetMode::Udp,
             iface: None,
+            proxy: None,
+            nat64: None,
+            skip_cert_verify: false,
+            fallback_tcp: false,
+            path: None,
         })
         .await
         .expect("build client");
@@ -623,6 +1012,11 @@ mod tests {
             port: 53,
             net: DNSNetMode::Tcp,
             iface: None,
+            proxy: None,
+            nat64: None,
+            skip_cert_verify: false,
+            fallback_tcp: false,
+            path: None,
         })
         .await
         .expect("build client");
@@ -639,6 +1033,11 @@ mod tests {
             port: 853,
             net: DNSNetMode::DoT,
             iface: None,
+            proxy: None,
+            nat64: None,
+            skip_cert_verify: false,
+            fallback_tcp: false,
+            path: None,
         })
         .await
         .expect("build client");
@@ -657,6 +1056,11 @@ mod tests {
             port: 443,
             net: DNSNetMode::DoH,
             iface: None,
+            proxy: None,
+            nat64: None,
+            skip_cert_verify: false,
+            fallback_tcp: false,
+            path: None,
         })
         .await
         .expect("build client");
@@ -673,6 +1077,11 @@ mod tests {
             port: 0,
             net: DNSNetMode::Dhcp,
             iface: None,
+            proxy: None,
+            nat64: None,
+            skip_cert_verify: false,
+            fallback_tcp: false,
+            path: None,
         })
         .await
         .expect("build client");
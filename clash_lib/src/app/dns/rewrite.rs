@@ -0,0 +1,125 @@
+//! `rewrite`, the static answers for the domains matching some patterns,
+//! made before any upstream is asked.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use hickory_proto::{op::ResponseCode, rr::Name};
+use regex::Regex;
+
+use crate::Error;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RewriteAnswer {
+    /// the A and AAAA records, the queries of other types answered empty
+    Addrs(Vec<IpAddr>),
+    /// a CNAME record, with the records of the target for the other types
+    Cname(Name),
+    /// no records, with the code, e.g. NXDOMAIN
+    Rcode(ResponseCode),
+}
+
+/// The patterns in the order of the config, the first match answers.
+#[derive(Clone, Debug, Default)]
+pub struct Rewrite {
+    rules: Vec<(Regex, RewriteAnswer)>,
+}
+
+impl Rewrite {
+    /// Each entry maps a pattern to an answer, a list keeps them in order.
+    pub fn parse(entries: &[HashMap<String, String>]) -> Result<Self, Error> {
+        let mut rules = vec![];
+        for (pattern, answer) in entries.iter().flatten() {
+            let re = Regex::new(pattern).map_err(|x| {
+                Error::InvalidConfig(format!("invalid dns rewrite pattern {}: {}", pattern, x))
+            })?;
+            rules.push((re, parse_answer(answer)?));
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The answer for the domain, without the trailing dot.
+    pub fn lookup(&self, domain: &str) -> Option<&RewriteAnswer> {
+        self.rules
+            .iter()
+            .find(|(re, _)| re.is_match(domain))
+            .map(|(_, answer)| answer)
+    }
+}
+
+/// `cname:<domain>`, a response code like `NXDOMAIN`, or addresses
+/// separated by commas.
+fn parse_answer(s: &str) -> Result<RewriteAnswer, Error> {
+    let invalid = || Error::InvalidConfig(format!("invalid dns rewrite answer: {}", s));
+    if let Some(target) = s.strip_prefix("cname:") {
+        let name = Name::from_str_relaxed(target.trim())
+            .and_then(|x| x.append_domain(&Name::root()))
+            .map_err(|_| invalid())?;
+        return Ok(RewriteAnswer::Cname(name));
+    }
+    let rcode = match s.trim().to_ascii_uppercase().as_str() {
+        "NOERROR" => Some(ResponseCode::NoError),
+        "NXDOMAIN" => Some(ResponseCode::NXDomain),
+        "SERVFAIL" => Some(ResponseCode::ServFail),
+        "REFUSED" => Some(ResponseCode::Refused),
+        _ => None,
+    };
+    if let Some(rcode) = rcode {
+        return Ok(RewriteAnswer::Rcode(rcode));
+    }
+    s.split(',')
+        .map(|x| x.trim().parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()
+        .map(RewriteAnswer::Addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use hickory_proto::op::ResponseCode;
+
+    use super::{Rewrite, RewriteAnswer};
+
+    #[test]
+    fn test_rewrite() {
+        let entries = [
+            (r"^ads\.", "NXDOMAIN"),
+            (r"(^|\.)corp\.lan$", "10.0.0.1, fd00::1"),
+            (r"^old\.example\.com$", "cname:new.example.com"),
+            (r"\.lan$", "refused"),
+        ]
+        .map(|(k, v)| HashMap::from([(k.to_owned(), v.to_owned())]));
+        let rewrite = Rewrite::parse(&entries).unwrap();
+
+        assert_eq!(
+            rewrite.lookup("ads.example.com"),
+            Some(&RewriteAnswer::Rcode(ResponseCode::NXDomain))
+        );
+        assert_eq!(
+            rewrite.lookup("git.corp.lan"),
+            Some(&RewriteAnswer::Addrs(vec![
+                "10.0.0.1".parse().unwrap(),
+                "fd00::1".parse().unwrap()
+            ]))
+        );
+        assert!(matches!(
+            rewrite.lookup("old.example.com"),
+            Some(RewriteAnswer::Cname(name)) if name.to_ascii() == "new.example.com."
+        ));
+        // the first match answers
+        assert_eq!(
+            rewrite.lookup("printer.lan"),
+            Some(&RewriteAnswer::Rcode(ResponseCode::Refused))
+        );
+        assert_eq!(rewrite.lookup("example.com"), None);
+
+        for (k, v) in [("(", "NXDOMAIN"), ("a", "not an address")] {
+            let entries = [HashMap::from([(k.to_owned(), v.to_owned())])];
+            assert!(Rewrite::parse(&entries).is_err());
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! The proxies the addresses of some domains are routed to, learned from the
+//! answers, so a connection to such an address is routed like the domain even
+//! when the client dialed the address, e.g. a CDN hosted service.
+
+use std::{
+    collections::HashMap,
+    net,
+    sync::Arc,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::common::trie;
+
+/// an address is remembered for the TTL of its answer, at least this long as
+/// the clients cache the answers anyway
+const MIN_HINT_TTL: Duration = Duration::from_secs(60);
+
+pub struct RouteHints {
+    domains: trie::StringTrie<String>,
+    /// the distinct proxies of the domains
+    targets: Vec<String>,
+    /// the proxy of each address, and until when
+    ips: Mutex<lru_time_cache::LruCache<net::IpAddr, (String, Instant)>>,
+}
+
+impl RouteHints {
+    /// `domains` maps a domain, like the keys of `nameserver-policy`, to a
+    /// proxy.
+    pub fn new(domains: &HashMap<String, String>) -> Self {
+        let mut trie = trie::StringTrie::new();
+        let mut targets = vec![];
+        for (domain, target) in domains {
+            trie.insert(domain.as_str(), Arc::new(target.to_owned()));
+            if !targets.contains(target) {
+                targets.push(target.to_owned());
+            }
+        }
+        Self {
+            domains: trie,
+            targets,
+            ips: Mutex::new(lru_time_cache::LruCache::with_capacity(4096)),
+        }
+    }
+
+    /// The distinct proxies of the domains.
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    /// Remember the addresses `host` resolved to for the TTL of the answer,
+    /// if it's one of the domains.
+    pub fn record(&self, host: &str, ips: &[net::IpAddr], ttl: Duration) {
+        let Some(target) = self.domains.search(host).and_then(|x| x.get_data()) else {
+            return;
+        };
+        let until = Instant::now() + ttl.max(MIN_HINT_TTL);
+        let mut cache = self.ips.lock().unwrap();
+        for ip in ips {
+            cache.insert(*ip, (target.to_owned(), until));
+        }
+    }
+
+    /// The proxy of the domain `ip` was an answer for, while the answer lives.
+    pub fn lookup(&self, ip: &net::IpAddr) -> Option<String> {
+        let mut cache = self.ips.lock().unwrap();
+        match cache.get(ip) {
+            Some((target, until)) if *until > Instant::now() => Some(target.clone()),
+            Some(_) => {
+                cache.remove(ip);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use super::RouteHints;
+
+    #[test]
+    fn test_route_hints() {
+        let hints = RouteHints::new(&HashMap::from([
+            ("+.nflxvideo.net".to_owned(), "US".to_owned()),
+            ("bbc.co.uk".to_owned(), "UK".to_owned()),
+        ]));
+        assert_eq!(hints.targets().len(), 2);
+
+        let ttl = Duration::from_secs(300);
+        hints.record(
+            "ipv4-c001.nflxvideo.net",
+            &["45.57.0.1".parse().unwrap()],
+            ttl,
+        );
+        hints.record("example.com", &["93.184.215.14".parse().unwrap()], ttl);
+        assert_eq!(
+            hints.lookup(&"45.57.0.1".parse().unwrap()).as_deref(),
+            Some("US")
+        );
+        assert_eq!(hints.lookup(&"93.184.215.14".parse().unwrap()), None);
+
+        // the answer expired
+        let ip = "212.58.244.1".parse().unwrap();
+        hints.record("bbc.co.uk", &[ip], ttl);
+        hints.ips.lock().unwrap().get_mut(&ip).unwrap().1 = Instant::now();
+        assert_eq!(hints.lookup(&ip), None);
+    }
+}
@@ -1,11 +1,14 @@
-use std::{net::IpAddr, time::Duration};
+use std::{net::IpAddr, path::Path, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 
 use hickory_proto::{
     op::{Header, Message, MessageType, OpCode, ResponseCode},
     rr::{
-        rdata::{A, AAAA},
+        rdata::{
+            svcb::{SvcParamKey, SVCB},
+            A, AAAA, HTTPS,
+        },
         RData, Record, RecordType,
     },
 };
@@ -18,7 +21,7 @@ use thiserror::Error;
 use tokio::net::{TcpListener, UdpSocket};
 use tracing::{debug, info, warn};
 
-use crate::Runner;
+use crate::{app::dispatcher::Dispatcher, common::tls, Runner};
 
 use super::{Config, ThreadSafeDNSResolver};
 
@@ -30,6 +33,8 @@ struct DnsListener {
 
 struct DnsHandler {
     resolver: ThreadSafeDNSResolver,
+    /// the domains its rules reject are answered NXDOMAIN
+    dispatcher: Option<Arc<Dispatcher>>,
 }
 
 #[derive(Error, Debug)]
@@ -48,72 +53,8 @@ impl DnsHandler {
         request: &Request,
         mut response_handle: R,
     ) -> Result<ResponseInfo, DNSError> {
-        if request.op_code() != OpCode::Query {
-            return Err(DNSError::InvalidOpQuery(format!(
-                "invalid OP code: {}",
-                request.op_code()
-            )));
-        }
-
-        if request.message_type() != MessageType::Query {
-            return Err(DNSError::InvalidOpQuery(format!(
-                "invalid message type: {}",
-                request.message_type()
-            )));
-        }
-
-        let builder = MessageResponseBuilder::from_message_request(request);
-        let mut header = Header::response_from_request(request.header());
-
-        if request.query().query_type() == RecordType::AAAA && !self.resolver.ipv6() {
-            header.set_authoritative(true);
-
-            let resp = builder.build_no_records(header);
-            return Ok(response_handle.send_response(resp).await?);
-        }
-
-        if self.resolver.fake_ip_enabled() {
-            let name = request.query().name();
-            let host = if name.is_fqdn() {
-                name.to_string().strip_suffix('.').unwrap().to_string()
-            } else {
-                name.to_string()
-            };
-
-            let builder = MessageResponseBuilder::from_message_request(request);
-            let mut header = Header::response_from_request(request.header());
-            header.set_authoritative(true);
-
-            match self.resolver.resolve(&host, true).await {
-                Ok(resp) => match resp {
-                    Some(ip) => {
-                        let rdata = match ip {
-                            IpAddr::V4(a) => RData::A(A(a)),
-                            IpAddr::V6(aaaa) => RData::AAAA(AAAA(aaaa)),
-                        };
-
-                        let records = vec![Record::from_rdata(
-                            name.into(),
-                            DEFAULT_DNS_SERVER_TTL,
-                            rdata,
-                        )];
-
-                        let resp = builder.build(header, records.iter(), &[], &[], &[]);
-                        return Ok(response_handle.send_response(resp).await?);
-                    }
-                    None => {
-                        let resp = builder.build_no_records(header);
-                        return Ok(response_handle.send_response(resp).await?);
-                    }
-                },
-                Err(e) => {
-                    debug!("dns resolve error: {}", e);
-                    return Err(DNSError::QueryFailed(e.to_string()));
-                }
-            }
-        }
-
         let mut m = Message::new();
+        m.set_id(request.id());
         m.set_op_code(request.op_code());
         m.set_message_type(request.message_type());
         m.set_recursion_desired(request.recursion_desired());
@@ -127,43 +68,195 @@ impl DnsHandler {
             m.set_edns(edns.clone());
         }
 
-        match self.resolver.exchange(m).await {
-            Ok(m) => {
-                header.set_recursion_available(m.recursion_available());
-                header.set_response_code(m.response_code());
-                header.set_authoritative(m.authoritative());
-
-                header.set_answer_count(m.answer_count());
-                header.set_name_server_count(m.name_server_count());
-                header.set_additional_count(m.additional_count());
-
-                let mut rv =
-                    builder.build(header, m.answers(), m.name_servers(), &[], m.additionals());
-
-                if let Some(edns) = request.edns() {
-                    if edns.dnssec_ok() {
-                        if let Some(edns) = m.extensions() {
-                            rv.set_edns(edns.clone());
-                        }
-                    }
+        let m = handle_query(&self.resolver, self.dispatcher.as_deref(), &m).await?;
+
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let mut header = Header::response_from_request(request.header());
+        header.set_recursion_available(m.recursion_available());
+        header.set_response_code(m.response_code());
+        header.set_authoritative(m.authoritative());
+
+        header.set_answer_count(m.answer_count());
+        header.set_name_server_count(m.name_server_count());
+        header.set_additional_count(m.additional_count());
+
+        let mut rv = builder.build(header, m.answers(), m.name_servers(), &[], m.additionals());
+
+        if let Some(edns) = request.edns() {
+            if edns.dnssec_ok() {
+                if let Some(edns) = m.extensions() {
+                    rv.set_edns(edns.clone());
                 }
+            }
+        }
+
+        debug!(
+            "answering dns query {} with answer {:?}",
+            request.query().name(),
+            m.answers(),
+        );
+
+        Ok(response_handle.send_response(rv).await?)
+    }
+}
 
-                debug!(
-                    "answering dns query {} with answer {:?}",
-                    request.query().name(),
-                    m.answers(),
-                );
+/// The answer of the DNS server to a query, also given to the queries the
+/// tun inbound hijacks. The domains the rules of the `dispatcher` reject
+/// are answered NXDOMAIN.
+pub async fn handle_query(
+    resolver: &ThreadSafeDNSResolver,
+    dispatcher: Option<&Dispatcher>,
+    request: &Message,
+) -> Result<Message, DNSError> {
+    handle_query_upstream(resolver, dispatcher, request)
+        .await
+        .map(|x| x.0)
+}
+
+/// [`handle_query`], with where the answer comes from: the nameserver,
+/// `cache`, `hosts` or `rewrite` as for the resolver, `fakeip` for a fake
+/// IP, and `local` for the other answers made here.
+pub async fn handle_query_upstream(
+    resolver: &ThreadSafeDNSResolver,
+    dispatcher: Option<&Dispatcher>,
+    request: &Message,
+) -> Result<(Message, String), DNSError> {
+    if request.op_code() != OpCode::Query {
+        return Err(DNSError::InvalidOpQuery(format!(
+            "invalid OP code: {}",
+            request.op_code()
+        )));
+    }
+
+    if request.message_type() != MessageType::Query {
+        return Err(DNSError::InvalidOpQuery(format!(
+            "invalid message type: {}",
+            request.message_type()
+        )));
+    }
 
-                Ok(response_handle.send_response(rv).await?)
+    let query = request
+        .query()
+        .ok_or_else(|| DNSError::InvalidOpQuery("no query".to_owned()))?;
+
+    // answered here, with no records unless some are added
+    let mut local = Message::new();
+    local.set_id(request.id());
+    local.set_message_type(MessageType::Response);
+    local.set_op_code(request.op_code());
+    local.set_recursion_desired(request.recursion_desired());
+    local.set_recursion_available(true);
+    local.set_authoritative(true);
+    local.add_query(query.clone());
+
+    let name = query.name();
+    let host = if name.is_fqdn() {
+        name.to_string().strip_suffix('.').unwrap().to_string()
+    } else {
+        name.to_string()
+    };
+    let answered_here = String::from("local");
+    if dispatcher.is_some_and(|x| x.rejects_domain(&host)) {
+        local.set_response_code(ResponseCode::NXDomain);
+        return Ok((local, answered_here));
+    }
+
+    let query_type = query.query_type();
+
+    if query_type == RecordType::AAAA && !resolver.ipv6() {
+        return Ok((local, answered_here));
+    }
+
+    // the rewritten hosts are answered by `exchange`, with their codes and
+    // CNAMEs
+    if resolver.fake_ip_enabled()
+        && (query_type == RecordType::A || query_type == RecordType::AAAA)
+        && !resolver.is_rewritten(&host)
+    {
+        let resolved = if query_type == RecordType::AAAA {
+            resolver
+                .resolve_v6(&host, true)
+                .await
+                .map(|x| x.map(IpAddr::V6))
+        } else {
+            resolver
+                .resolve_v4(&host, true)
+                .await
+                .map(|x| x.map(IpAddr::V4))
+        };
+
+        return match resolved {
+            Ok(Some(ip)) => {
+                let rdata = match ip {
+                    IpAddr::V4(a) => RData::A(A(a)),
+                    IpAddr::V6(aaaa) => RData::AAAA(AAAA(aaaa)),
+                };
+                local.add_answer(Record::from_rdata(
+                    name.clone(),
+                    DEFAULT_DNS_SERVER_TTL,
+                    rdata,
+                ));
+                let upstream = if resolver.is_fake_ip(ip).await {
+                    String::from("fakeip")
+                } else {
+                    answered_here
+                };
+                Ok((local, upstream))
             }
+            Ok(None) => Ok((local, answered_here)),
             Err(e) => {
                 debug!("dns resolve error: {}", e);
                 Err(DNSError::QueryFailed(e.to_string()))
             }
+        };
+    }
+
+    match resolver.exchange_upstream(request.clone()).await {
+        Ok((mut m, upstream)) => {
+            // the cached answers carry the ids of other queries
+            m.set_id(request.id());
+            if resolver.fake_ip_enabled() {
+                strip_ip_hints(&mut m);
+            }
+            Ok((m, upstream))
+        }
+        Err(e) => {
+            debug!("dns resolve error: {}", e);
+            Err(DNSError::QueryFailed(e.to_string()))
         }
     }
 }
 
+/// Drop the address hints of the SVCB and HTTPS records, which would bypass
+/// the fake IPs. The other parameters, e.g. the ECH config, are kept.
+fn strip_ip_hints(m: &mut Message) {
+    let strip = |svcb: &SVCB| {
+        let params = svcb
+            .svc_params()
+            .iter()
+            .filter(|(k, _)| !matches!(k, SvcParamKey::Ipv4Hint | SvcParamKey::Ipv6Hint))
+            .cloned()
+            .collect();
+        SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params)
+    };
+    let answers = m
+        .take_answers()
+        .into_iter()
+        .map(|mut r| {
+            let stripped = match r.data() {
+                Some(RData::HTTPS(x)) => Some(RData::HTTPS(HTTPS(strip(x)))),
+                Some(RData::SVCB(x)) => Some(RData::SVCB(strip(x))),
+                _ => None,
+            };
+            if stripped.is_some() {
+                r.set_data(stripped);
+            }
+            r
+        })
+        .collect::<Vec<_>>();
+    m.insert_answers(answers);
+}
+
 #[async_trait]
 impl RequestHandler for DnsHandler {
     async fn handle_request<R: ResponseHandler>(
@@ -193,15 +286,42 @@ impl RequestHandler for DnsHandler {
 
 static DEFAULT_DNS_SERVER_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> Option<Runner> {
+pub async fn get_dns_listener(
+    cfg: Config,
+    resolver: ThreadSafeDNSResolver,
+    dispatcher: Arc<Dispatcher>,
+    cwd: &Path,
+) -> Option<Runner> {
     if !cfg.enable {
         return None;
     }
 
-    let h = DnsHandler { resolver };
+    let mut listen = cfg.listen;
+    if let Some(t) = cfg.listen_tls {
+        let certificate_and_key =
+            match tls::cert_and_key(&cwd.join(&t.certificate), &cwd.join(&t.private_key)) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("dns server not started: {}", e);
+                    return None;
+                }
+            };
+        if let Some((_, c)) = listen.doh.as_mut() {
+            c.certificate_and_key = certificate_and_key.clone();
+            c.dns_hostname = t.hostname;
+        }
+        if let Some((_, c)) = listen.dot.as_mut() {
+            c.certificate_and_key = certificate_and_key;
+        }
+    }
+
+    let h = DnsHandler {
+        resolver,
+        dispatcher: cfg.rule_aware.then_some(dispatcher),
+    };
     let mut s = ServerFuture::new(h);
 
-    if let Some(addr) = cfg.listen.udp {
+    if let Some(addr) = listen.udp {
         UdpSocket::bind(addr)
             .await
             .map(|x| {
@@ -210,7 +330,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
             })
             .ok()?;
     }
-    if let Some(addr) = cfg.listen.tcp {
+    if let Some(addr) = listen.tcp {
         TcpListener::bind(addr)
             .await
             .map(|x| {
@@ -219,7 +339,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
             })
             .ok()?;
     }
-    if let Some(c) = cfg.listen.doh {
+    if let Some(c) = listen.doh {
         TcpListener::bind(c.0)
             .await
             .and_then(|x| {
@@ -234,7 +354,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
             })
             .ok()?;
     }
-    if let Some(c) = cfg.listen.dot {
+    if let Some(c) = listen.dot {
         TcpListener::bind(c.0)
             .await
             .and_then(|x| {
@@ -254,3 +374,62 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
         })
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{
+            rdata::{
+                svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue, SVCB},
+                A, HTTPS,
+            },
+            Name, RData, Record, RecordType,
+        },
+    };
+
+    use super::handle_query;
+    use crate::app::dns::{MockClashResolver, ThreadSafeDNSResolver};
+
+    #[tokio::test]
+    async fn test_https_without_ip_hints() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut request = Message::new();
+        request.add_query(Query::query(name.clone(), RecordType::HTTPS));
+
+        let svcb = SVCB::new(
+            1,
+            Name::root(),
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h2".to_owned()])),
+                ),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![A("1.2.3.4".parse().unwrap())])),
+                ),
+            ],
+        );
+        let mut answer = request.clone();
+        answer.add_answer(Record::from_rdata(name, 300, RData::HTTPS(HTTPS(svcb))));
+
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_fake_ip_enabled().return_const(true);
+        resolver
+            .expect_exchange_upstream()
+            .returning(move |_| Ok((answer.clone(), "8.8.8.8".to_owned())));
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+
+        let reply = handle_query(&resolver, None, &request).await.unwrap();
+        match reply.answers()[0].data() {
+            Some(RData::HTTPS(x)) => {
+                let keys = x.svc_params().iter().map(|(k, _)| *k).collect::<Vec<_>>();
+                assert_eq!(keys, vec![SvcParamKey::Alpn]);
+            }
+            x => panic!("unexpected answer {:?}", x),
+        }
+    }
+}
@@ -1,6 +1,13 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use rand::seq::IteratorRandom;
 
+use crate::{
+    app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
+    common::geosite::Geosite,
+};
+
 use super::{ClashResolver, ResolverKind};
 
 pub struct SystemResolver;
@@ -58,6 +65,13 @@ impl ClashResolver for SystemResolver {
         Err(anyhow::anyhow!("unsupported"))
     }
 
+    async fn exchange_upstream(
+        &self,
+        _: hickory_proto::op::Message,
+    ) -> anyhow::Result<(hickory_proto::op::Message, String)> {
+        Err(anyhow::anyhow!("unsupported"))
+    }
+
     fn ipv6(&self) -> bool {
         true
     }
@@ -66,6 +80,20 @@ impl ClashResolver for SystemResolver {
         // NOOP
     }
 
+    fn nat64(&self) -> Option<super::Nat64> {
+        None
+    }
+
+    fn route_hints(&self) -> Option<std::sync::Arc<super::RouteHints>> {
+        None
+    }
+
+    fn bind_rule_providers(&self, _: &HashMap<String, ThreadSafeRuleProvider>) {}
+
+    async fn bind_geosite(&self, _: &Geosite) -> Result<(), crate::Error> {
+        Ok(())
+    }
+
     fn kind(&self) -> ResolverKind {
         ResolverKind::System
     }
@@ -74,6 +102,10 @@ impl ClashResolver for SystemResolver {
         false
     }
 
+    fn is_rewritten(&self, _: &str) -> bool {
+        false
+    }
+
     async fn is_fake_ip(&self, _: std::net::IpAddr) -> bool {
         false
     }
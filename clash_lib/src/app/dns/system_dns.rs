@@ -0,0 +1,285 @@
+//! Point the DNS servers of the active network adapters at the DNS listener
+//! while running, on Windows, where the applications otherwise only use it
+//! through TUN.
+//!
+//! The servers the adapters had are kept in a file until they are restored,
+//! so the ones left pointing at the listener by a crash are restored on the
+//! next start.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+#[cfg(windows)]
+use tracing::info;
+use tracing::warn;
+
+use crate::Error;
+
+/// The DNS servers of an adapter before they were changed.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(not(windows), allow(dead_code))]
+struct Adapter {
+    index: u32,
+    /// the static IPv4 servers, empty if the adapter got them from DHCP
+    servers: Vec<String>,
+    /// the static IPv6 servers, empty if the adapter got them from DHCPv6
+    /// or the router advertisements
+    #[serde(default)]
+    servers_v6: Vec<String>,
+}
+
+/// Holds the adapters whose DNS servers were changed, restored by
+/// [`SystemDnsGuard::restore`] or else on drop.
+pub struct SystemDnsGuard {
+    adapters: Vec<Adapter>,
+    /// where the adapters are kept until they are restored
+    saved: PathBuf,
+}
+
+/// where the adapters are kept in the config home
+const SAVED: &str = "system-dns.json";
+
+/// Restore the adapters left by a run that didn't exit cleanly, then set the
+/// DNS servers of the active adapters to the `udp` listener. Returns `None`
+/// if not enabled.
+pub async fn setup(
+    enable: bool,
+    listen: Option<SocketAddr>,
+    cwd: &Path,
+) -> Result<Option<SystemDnsGuard>, Error> {
+    let saved = cwd.join(SAVED);
+    if let Some(guard) = SystemDnsGuard::load(&saved) {
+        warn!("restoring the DNS servers left set by the last run");
+        guard.restore().await;
+    }
+    if !enable {
+        return Ok(None);
+    }
+    let ip = match listen.map(|x| x.ip()) {
+        Some(ip) => ip,
+        None => {
+            return Err(Error::InvalidConfig(String::from(
+                "dns set-system-dns needs the udp listener",
+            )))
+        }
+    };
+    tokio::task::spawn_blocking(move || SystemDnsGuard::apply(ip, saved))
+        .await
+        .map_err(|x| Error::Operation(x.to_string()))?
+        .map(Some)
+}
+
+/// The IPv4 and the IPv6 servers for the listener on `ip`. Both are set so
+/// the servers of the router advertisements don't answer instead.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn servers(ip: IpAddr) -> (IpAddr, IpAddr) {
+    let v4 = match ip {
+        IpAddr::V4(x) if !x.is_unspecified() => ip,
+        _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    };
+    let v6 = match ip {
+        IpAddr::V6(x) if !x.is_unspecified() => ip,
+        _ => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    };
+    (v4, v6)
+}
+
+impl SystemDnsGuard {
+    /// The adapters kept in `saved`, if any.
+    fn load(saved: &Path) -> Option<Self> {
+        let adapters = std::fs::read(saved).ok()?;
+        let adapters = serde_json::from_slice(&adapters)
+            .inspect_err(|x| warn!("invalid {}: {}", saved.display(), x))
+            .unwrap_or_default();
+        Some(Self {
+            adapters,
+            saved: saved.to_owned(),
+        })
+    }
+
+    /// Restore the DNS servers of the adapters.
+    pub async fn restore(mut self) {
+        let _ = tokio::task::spawn_blocking(move || self.restore_blocking()).await;
+    }
+
+    fn restore_blocking(&mut self) {
+        #[cfg(windows)]
+        for adapter in self.adapters.drain(..) {
+            let static_servers = adapter
+                .servers
+                .iter()
+                .chain(&adapter.servers_v6)
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut script = format!(
+                "Set-DnsClientServerAddress -InterfaceIndex {} -ResetServerAddresses",
+                adapter.index
+            );
+            if !static_servers.is_empty() {
+                script.push_str(&format!(
+                    "; Set-DnsClientServerAddress -InterfaceIndex {} -ServerAddresses {}",
+                    adapter.index,
+                    static_servers.join(",")
+                ));
+            }
+            match powershell(&script) {
+                Ok(_) => info!("restored the DNS servers of adapter {}", adapter.index),
+                Err(e) => warn!(
+                    "failed to restore the DNS servers of adapter {} to {:?}: {}",
+                    adapter.index, static_servers, e
+                ),
+            }
+        }
+        self.adapters.clear();
+        if let Err(e) = std::fs::remove_file(&self.saved) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("failed to remove {}: {}", self.saved.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl SystemDnsGuard {
+    fn apply(ip: IpAddr, saved: PathBuf) -> Result<Self, Error> {
+        // the static servers are in the registry, the ones of DHCP aren't
+        let adapters = powershell(
+            "$r = 'HKLM:\\SYSTEM\\CurrentControlSet\\Services\\'; \
+             Get-NetAdapter | Where-Object Status -eq 'Up' | ForEach-Object { \
+             $i = '\\Parameters\\Interfaces\\' + $_.InterfaceGuid; \
+             $v4 = (Get-ItemProperty -Path ($r + 'Tcpip' + $i) -Name NameServer \
+             -ErrorAction SilentlyContinue).NameServer; \
+             $v6 = (Get-ItemProperty -Path ($r + 'Tcpip6' + $i) -Name NameServer \
+             -ErrorAction SilentlyContinue).NameServer; \
+             \"$($_.ifIndex)`t$v4`t$v6\" }",
+        )?;
+        let guard = Self {
+            adapters: parse_adapters(&adapters),
+            saved,
+        };
+        // kept before anything is changed, a failure below restores them
+        let json = serde_json::to_vec(&guard.adapters)
+            .map_err(|x| Error::Operation(format!("failed to save the DNS servers: {}", x)))?;
+        std::fs::write(&guard.saved, json)?;
+
+        let (v4, v6) = servers(ip);
+        for adapter in &guard.adapters {
+            info!(
+                "setting the DNS servers of adapter {} to {} and {}, they were {:?} and {:?}",
+                adapter.index, v4, v6, adapter.servers, adapter.servers_v6
+            );
+            powershell(&format!(
+                "Set-DnsClientServerAddress -InterfaceIndex {} -ServerAddresses {},{}",
+                adapter.index, v4, v6
+            ))?;
+        }
+        powershell("Clear-DnsClientCache")?;
+        Ok(guard)
+    }
+}
+
+#[cfg(not(windows))]
+impl SystemDnsGuard {
+    fn apply(_: IpAddr, saved: PathBuf) -> Result<Self, Error> {
+        warn!("dns set-system-dns is only supported on windows, skipping");
+        Ok(Self {
+            adapters: vec![],
+            saved,
+        })
+    }
+}
+
+/// Parse the `index\tv4 servers\tv6 servers` lines of the adapters, the
+/// servers separated by commas or spaces.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_adapters(output: &str) -> Vec<Adapter> {
+    let split = |x: &str| {
+        x.trim()
+            .split([',', ' '])
+            .filter(|x| !x.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    };
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let index = fields.next()?.trim().parse().ok()?;
+            let servers = split(fields.next()?);
+            Some(Adapter {
+                index,
+                servers,
+                servers_v6: fields.next().map(split).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn powershell(script: &str) -> Result<String, Error> {
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Operation(format!(
+            "`{}` failed: {}",
+            script,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+impl Drop for SystemDnsGuard {
+    fn drop(&mut self) {
+        if !self.adapters.is_empty() {
+            self.restore_blocking();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{parse_adapters, servers, setup, Adapter, SAVED};
+
+    #[test]
+    fn test_parse_adapters() {
+        let adapters = parse_adapters(
+            "12\t8.8.8.8,1.1.1.1\t2001:4860:4860::8888\r\n7\t\t\r\nbogus\n3\t9.9.9.9 1.0.0.1\n",
+        );
+        assert_eq!(adapters.len(), 3);
+        assert_eq!(adapters[0].index, 12);
+        assert_eq!(adapters[0].servers, vec!["8.8.8.8", "1.1.1.1"]);
+        assert_eq!(adapters[0].servers_v6, vec!["2001:4860:4860::8888"]);
+        assert!(adapters[1].servers.is_empty());
+        assert!(adapters[1].servers_v6.is_empty());
+        assert_eq!(adapters[2].servers, vec!["9.9.9.9", "1.0.0.1"]);
+        assert!(adapters[2].servers_v6.is_empty());
+    }
+
+    #[test]
+    fn test_servers() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert_eq!(servers(ip("0.0.0.0")), (ip("127.0.0.1"), ip("::1")));
+        assert_eq!(servers(ip("::")), (ip("127.0.0.1"), ip("::1")));
+        assert_eq!(servers(ip("127.0.0.2")), (ip("127.0.0.2"), ip("::1")));
+        assert_eq!(servers(ip("::2")), (ip("127.0.0.1"), ip("::2")));
+    }
+
+    #[tokio::test]
+    async fn test_restore_saved() {
+        let cwd = tempfile::tempdir().unwrap();
+        let saved = cwd.path().join(SAVED);
+        // no adapter, nothing is changed on windows
+        std::fs::write(&saved, serde_json::to_vec(&Vec::<Adapter>::new()).unwrap()).unwrap();
+
+        // the servers left by a crash are restored even if disabled now
+        assert!(setup(false, None, cwd.path()).await.unwrap().is_none());
+        assert!(!saved.exists());
+    }
+}
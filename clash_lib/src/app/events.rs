@@ -0,0 +1,72 @@
+//! Lifecycle events for the controller `/events` stream
+//!
+//! GUIs subscribe to react to state changes, e.g. a provider refresh or a
+//! selection made by another client, without polling the REST endpoints.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::trace;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+static EVENTS: Lazy<broadcast::Sender<Event>> =
+    Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    ProviderUpdated,
+    ProxySelected,
+    ConfigReloaded,
+    GeoUpdated,
+    DialFailed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Event {
+    /// increases by one for each event, a gap tells the subscriber it lagged
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub time: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+/// Publish an event to the current subscribers, it's dropped if there is none.
+pub fn publish<T: Serialize>(kind: EventKind, data: T) {
+    let evt = Event {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        kind,
+        time: Utc::now(),
+        data: serde_json::to_value(data).unwrap_or_default(),
+    };
+    trace!("publishing event {:?}", evt);
+    let _ = EVENTS.send(evt);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{publish, subscribe, EventKind};
+
+    #[tokio::test]
+    async fn test_publish() {
+        let mut rx = subscribe();
+        publish(EventKind::ProxySelected, json!({"group": "auto", "proxy": "a"}));
+
+        let evt = rx.recv().await.unwrap();
+        let v = serde_json::to_value(&evt).unwrap();
+        assert_eq!(v["type"], "proxy-selected");
+        assert_eq!(v["data"]["proxy"], "a");
+    }
+}
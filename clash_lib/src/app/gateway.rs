@@ -0,0 +1,282 @@
+//! Gateway mode
+//!
+//! Run clash-rs as the default gateway of a LAN: serve DNS on the LAN facing
+//! interface, optionally set up forwarding/NAT on Linux, and keep a per client
+//! traffic tally which is logged periodically and exposed via the API.
+
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+use tracing::{info, warn};
+
+use crate::{
+    app::dispatcher::StatisticsManager,
+    config::internal::config::{GatewayConfig, TunConfig},
+    Error,
+};
+
+const CLIENT_REPORT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Returns the first usable IPv4 address on the interface `iface`.
+pub fn lan_ipv4(iface: &str) -> Option<Ipv4Addr> {
+    lan_ipv4_net(iface).map(|(ip, _)| ip)
+}
+
+fn lan_ipv4_net(iface: &str) -> Option<(Ipv4Addr, Option<Ipv4Addr>)> {
+    NetworkInterface::show()
+        .ok()?
+        .into_iter()
+        .filter(|x| x.name == iface)
+        .flat_map(|x| x.addr)
+        .find_map(|x| match x {
+            Addr::V4(v4) if !v4.ip.is_unspecified() && !v4.ip.is_link_local() => {
+                Some((v4.ip, v4.netmask))
+            }
+            _ => None,
+        })
+}
+
+/// Holds the system changes made for gateway mode and the client reporter,
+/// reverted and stopped by [`GatewayGuard::teardown`] or else on drop.
+pub struct GatewayGuard {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    undo: Vec<Vec<String>>,
+    reporter: tokio::task::JoinHandle<()>,
+}
+
+/// What gateway mode changes, checked before anything is, so that a reload
+/// with a bad `gateway` fails before the running one is torn down.
+pub struct GatewayPlan {
+    iface: String,
+    /// the LAN to route, `None` without auto-route
+    lan: Option<ipnet::Ipv4Net>,
+    /// the tun device to route the LAN into
+    tun_dev: Option<String>,
+}
+
+/// Check the gateway config against the system. Returns `None` if gateway
+/// mode is disabled.
+pub fn plan(cfg: &GatewayConfig, tun: &TunConfig) -> Result<Option<GatewayPlan>, Error> {
+    if !cfg.enable {
+        return Ok(None);
+    }
+
+    let iface = cfg.interface.as_deref().ok_or_else(|| {
+        Error::InvalidConfig("gateway.interface is required when gateway is enabled".to_owned())
+    })?;
+
+    let (ip, netmask) = lan_ipv4_net(iface).ok_or_else(|| {
+        Error::InvalidConfig(format!(
+            "no ipv4 address found on gateway interface {}",
+            iface
+        ))
+    })?;
+
+    let lan = if cfg.auto_route {
+        Some(
+            ipnet::Ipv4Net::with_netmask(ip, netmask.unwrap_or(Ipv4Addr::new(255, 255, 255, 0)))
+                .map_err(|x| Error::InvalidConfig(format!("invalid gateway netmask: {}", x)))?
+                .trunc(),
+        )
+    } else {
+        None
+    };
+    let tun_dev = if tun.enable {
+        url::Url::parse(&tun.device_id)
+            .ok()
+            .filter(|u| u.scheme() == "dev")
+            .and_then(|u| u.host_str().map(ToOwned::to_owned))
+    } else {
+        None
+    };
+
+    Ok(Some(GatewayPlan {
+        iface: iface.to_owned(),
+        lan,
+        tun_dev,
+    }))
+}
+
+impl GatewayPlan {
+    /// Set up gateway mode and start the per client traffic reporter.
+    pub fn apply(self, statistics_manager: Arc<StatisticsManager>) -> Result<GatewayGuard, Error> {
+        info!("gateway mode enabled on {}", self.iface);
+
+        let mut guard = GatewayGuard {
+            undo: vec![],
+            reporter: tokio::spawn(report_clients(statistics_manager)),
+        };
+        if let Some(lan) = self.lan {
+            guard.auto_route(&self.iface, &lan.to_string(), self.tun_dev.as_deref())?;
+        }
+
+        Ok(guard)
+    }
+}
+
+/// Set up gateway mode and start the per client traffic reporter.
+/// Returns `None` if gateway mode is disabled.
+pub fn setup(
+    cfg: &GatewayConfig,
+    tun: &TunConfig,
+    statistics_manager: Arc<StatisticsManager>,
+) -> Result<Option<GatewayGuard>, Error> {
+    plan(cfg, tun)?
+        .map(|x| x.apply(statistics_manager))
+        .transpose()
+}
+
+async fn report_clients(statistics_manager: Arc<StatisticsManager>) {
+    let mut ticker = tokio::time::interval(CLIENT_REPORT_INTERVAL);
+    // the first tick completes immediately
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        for (client, stats) in statistics_manager.client_statistics().await {
+            info!(
+                "gateway client {}: {} connections, upload {} bytes, download {} bytes",
+                client, stats.connections, stats.upload, stats.download
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl GatewayGuard {
+    /// Enable IP forwarding, masquerade the LAN traffic, and route it into the
+    /// tun device if there is one.
+    fn auto_route(&mut self, iface: &str, lan: &str, tun_dev: Option<&str>) -> Result<(), Error> {
+        let forward = std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")?;
+        let forward = forward.trim();
+        if forward != "1" {
+            self.apply(
+                &["sysctl", "-w", "net.ipv4.ip_forward=1"],
+                &["sysctl", "-w", &format!("net.ipv4.ip_forward={}", forward)],
+            )?;
+        }
+
+        self.apply(
+            &[
+                "iptables", "-t", "nat", "-I", "POSTROUTING", "-s", lan, "!", "-d", lan, "-j",
+                "MASQUERADE",
+            ],
+            &[
+                "iptables", "-t", "nat", "-D", "POSTROUTING", "-s", lan, "!", "-d", lan, "-j",
+                "MASQUERADE",
+            ],
+        )?;
+
+        if let Some(dev) = tun_dev {
+            self.apply(
+                &["ip", "route", "replace", "default", "dev", dev, "table", "2468"],
+                &["ip", "route", "del", "default", "dev", dev, "table", "2468"],
+            )?;
+            self.apply(
+                &["ip", "rule", "add", "iif", iface, "lookup", "2468"],
+                &["ip", "rule", "del", "iif", iface, "lookup", "2468"],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, cmd: &[&str], undo: &[&str]) -> Result<(), Error> {
+        run(cmd)?;
+        self.undo.push(undo.iter().map(|x| x.to_string()).collect());
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl GatewayGuard {
+    fn auto_route(&mut self, _: &str, _: &str, _: Option<&str>) -> Result<(), Error> {
+        warn!("gateway auto-route is only supported on linux, skipping");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run<S: AsRef<std::ffi::OsStr>>(cmd: &[S]) -> Result<(), Error> {
+    let output = std::process::Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Operation(format!(
+            "`{}` failed: {}",
+            cmd.iter()
+                .map(|x| x.as_ref().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+impl GatewayGuard {
+    /// Revert the system changes and stop the client reporter.
+    pub async fn teardown(mut self) {
+        self.reporter.abort();
+        let _ = tokio::task::spawn_blocking(move || self.teardown_blocking()).await;
+    }
+
+    fn teardown_blocking(&mut self) {
+        #[cfg(target_os = "linux")]
+        for cmd in self.undo.iter().rev() {
+            if let Err(e) = run(cmd) {
+                warn!("failed to revert gateway setup: {}", e);
+            }
+        }
+        self.undo.clear();
+    }
+}
+
+impl Drop for GatewayGuard {
+    fn drop(&mut self) {
+        self.reporter.abort();
+        if !self.undo.is_empty() {
+            self.teardown_blocking();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::dispatcher::StatisticsManager,
+        config::internal::config::{GatewayConfig, TunConfig},
+    };
+
+    use super::setup;
+
+    fn config(interface: Option<&str>) -> GatewayConfig {
+        GatewayConfig {
+            enable: true,
+            interface: interface.map(ToOwned::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_setup() {
+        let mgr = StatisticsManager::new();
+        let tun = TunConfig::default();
+
+        let disabled = GatewayConfig {
+            enable: false,
+            ..config(Some("lo"))
+        };
+        assert!(setup(&disabled, &tun, mgr.clone()).unwrap().is_none());
+        assert!(setup(&config(None), &tun, mgr.clone()).is_err());
+        assert!(setup(&config(Some("nonexistent0")), &tun, mgr.clone()).is_err());
+
+        // nothing to revert without auto-route, the reporter is stopped
+        let guard = setup(&config(Some("lo")), &tun, mgr).unwrap().unwrap();
+        let reporter = guard.reporter.abort_handle();
+        assert!(!reporter.is_finished());
+        guard.teardown().await;
+        tokio::task::yield_now().await;
+        assert!(reporter.is_finished());
+    }
+}
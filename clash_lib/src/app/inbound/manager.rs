@@ -3,17 +3,28 @@ use tokio::sync::Mutex;
 
 use crate::app::dispatcher::Dispatcher;
 use crate::app::inbound::network_listener::{ListenerType, NetworkInboundListener};
+use crate::app::inbound::rate_limit::{RateLimiter, ThreadSafeRateLimiter};
 use crate::common::auth::ThreadSafeAuthenticator;
 use crate::config::internal::config::{BindAddress, Inbound};
+use crate::proxy::{http, trojan};
 use crate::{Error, Runner};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
+#[cfg(not(target_os = "linux"))]
+use tracing::warn;
 
 pub struct InboundManager {
     network_listeners: HashMap<ListenerType, NetworkInboundListener>,
     dispatcher: Arc<Dispatcher>,
     bind_address: BindAddress,
     authenticator: ThreadSafeAuthenticator,
+    /// the trojan listener is not one of the ports changeable by the API,
+    /// it's kept as is across rebuilds
+    trojan: Option<(u16, Arc<trojan::InboundOpts>)>,
+    proxy_protocol: HashSet<ListenerType>,
+    block_page: Option<Arc<http::BlockPage>>,
+    rate_limiter: Option<ThreadSafeRateLimiter>,
 }
 
 pub type ThreadSafeInboundManager = Arc<Mutex<InboundManager>>;
@@ -36,14 +47,30 @@ impl InboundManager {
         inbound: Inbound,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        cwd: &Path,
     ) -> Result<Self, Error> {
         let network_listeners = HashMap::new();
 
+        let trojan = match inbound.trojan {
+            Some(cfg) => Some((cfg.port, Arc::new(trojan::InboundOpts::new(cfg, cwd)?))),
+            None => None,
+        };
+        let block_page = match inbound.http_block_page {
+            Some(cfg) => Some(Arc::new(http::BlockPage::new(cfg, cwd)?)),
+            None => None,
+        };
+
         let mut s = Self {
             network_listeners,
             dispatcher,
             bind_address: inbound.bind_address,
             authenticator,
+            trojan,
+            proxy_protocol: inbound.proxy_protocol,
+            block_page,
+            rate_limiter: inbound
+                .rate_limit
+                .map(|cfg| Arc::new(RateLimiter::new(cfg))),
         };
 
         let ports = Ports {
@@ -69,11 +96,26 @@ impl InboundManager {
         }))
     }
 
+    /// the names and ports of the TCP listeners, for the self-check
+    pub fn get_listeners(&self) -> Vec<(String, u16)> {
+        let mut listeners = self
+            .network_listeners
+            .values()
+            .map(|x| (x.name.clone(), x.port))
+            .collect::<Vec<_>>();
+        listeners.sort();
+        listeners
+    }
+
     /// API handlers below
     pub fn get_bind_address(&self) -> &BindAddress {
         &self.bind_address
     }
 
+    pub fn rate_limiter(&self) -> Option<ThreadSafeRateLimiter> {
+        self.rate_limiter.clone()
+    }
+
     pub fn set_bind_address(&mut self, bind_address: BindAddress) {
         self.bind_address = bind_address;
     }
@@ -98,6 +140,10 @@ impl InboundManager {
                 ListenerType::Mixed => {
                     ports.mixed_port = Some(x.port);
                 }
+                ListenerType::TProxy => {
+                    ports.tproxy_port = Some(x.port);
+                }
+                ListenerType::Trojan => {}
             });
 
         ports
@@ -115,6 +161,10 @@ impl InboundManager {
                     listener_type: ListenerType::Http,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    trojan: None,
+                    proxy_protocol: self.proxy_protocol.contains(&ListenerType::Http),
+                    block_page: self.block_page.clone(),
+                    rate_limiter: self.rate_limiter.clone(),
                 },
             );
         }
@@ -129,6 +179,10 @@ impl InboundManager {
                     listener_type: ListenerType::Socks5,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    trojan: None,
+                    proxy_protocol: self.proxy_protocol.contains(&ListenerType::Socks5),
+                    block_page: None,
+                    rate_limiter: self.rate_limiter.clone(),
                 },
             );
         }
@@ -143,6 +197,51 @@ impl InboundManager {
                     listener_type: ListenerType::Mixed,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    trojan: None,
+                    proxy_protocol: self.proxy_protocol.contains(&ListenerType::Mixed),
+                    block_page: self.block_page.clone(),
+                    rate_limiter: self.rate_limiter.clone(),
+                },
+            );
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(tproxy_port) = ports.tproxy_port {
+            network_listeners.insert(
+                ListenerType::TProxy,
+                NetworkInboundListener {
+                    name: "TProxy".to_string(),
+                    bind_addr: self.bind_address.clone(),
+                    port: tproxy_port,
+                    listener_type: ListenerType::TProxy,
+                    dispatcher: self.dispatcher.clone(),
+                    authenticator: self.authenticator.clone(),
+                    trojan: None,
+                    proxy_protocol: false,
+                    block_page: None,
+                    rate_limiter: self.rate_limiter.clone(),
+                },
+            );
+        }
+        #[cfg(not(target_os = "linux"))]
+        if ports.tproxy_port.is_some() {
+            warn!("tproxy-port is only supported on linux, skipping");
+        }
+
+        if let Some((port, opts)) = &self.trojan {
+            network_listeners.insert(
+                ListenerType::Trojan,
+                NetworkInboundListener {
+                    name: "Trojan".to_string(),
+                    bind_addr: self.bind_address.clone(),
+                    port: *port,
+                    listener_type: ListenerType::Trojan,
+                    dispatcher: self.dispatcher.clone(),
+                    authenticator: self.authenticator.clone(),
+                    trojan: Some(opts.clone()),
+                    proxy_protocol: self.proxy_protocol.contains(&ListenerType::Trojan),
+                    block_page: None,
+                    rate_limiter: self.rate_limiter.clone(),
                 },
             );
         }
@@ -1,2 +1,3 @@
 pub mod manager;
 pub mod network_listener;
+pub mod rate_limit;
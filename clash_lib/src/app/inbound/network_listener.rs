@@ -1,9 +1,10 @@
+use crate::app::inbound::rate_limit::ThreadSafeRateLimiter;
 use crate::common::auth::ThreadSafeAuthenticator;
 use crate::config::internal::config::BindAddress;
 
-use crate::proxy::{http, mixed, socks, AnyInboundListener};
+use crate::proxy::{http, mixed, socks, trojan, AnyInboundListener};
 
-use crate::proxy::utils::Interface;
+use crate::proxy::utils::{new_tcp_listener, Interface, LISTENER_REUSE_PORT};
 use crate::{Dispatcher, Error, Runner};
 use futures::FutureExt;
 use network_interface::{Addr, NetworkInterfaceConfig};
@@ -17,6 +18,9 @@ pub enum ListenerType {
     Http,
     Socks5,
     Mixed,
+    Trojan,
+    /// Linux only
+    TProxy,
 }
 
 pub struct NetworkInboundListener {
@@ -26,6 +30,13 @@ pub struct NetworkInboundListener {
     pub listener_type: ListenerType,
     pub dispatcher: Arc<Dispatcher>,
     pub authenticator: ThreadSafeAuthenticator,
+    /// set for the trojan listener only
+    pub trojan: Option<Arc<trojan::InboundOpts>>,
+    /// expect a PROXY protocol header on the connections
+    pub proxy_protocol: bool,
+    /// served to the rejected plain HTTP requests, HTTP and mixed only
+    pub block_page: Option<Arc<http::BlockPage>>,
+    pub rate_limiter: Option<ThreadSafeRateLimiter>,
 }
 
 impl NetworkInboundListener {
@@ -100,27 +111,64 @@ impl NetworkInboundListener {
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.proxy_protocol,
+                self.block_page.clone(),
+                self.rate_limiter.clone(),
             ),
             ListenerType::Socks5 => socks::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.proxy_protocol,
+                self.rate_limiter.clone(),
             ),
             ListenerType::Mixed => mixed::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.proxy_protocol,
+                self.block_page.clone(),
+                self.rate_limiter.clone(),
             ),
+            ListenerType::Trojan => trojan::Listener::new(
+                (ip, self.port).into(),
+                self.dispatcher.clone(),
+                self.trojan
+                    .clone()
+                    .expect("trojan listener without options"),
+                self.proxy_protocol,
+                self.rate_limiter.clone(),
+            ),
+            #[cfg(target_os = "linux")]
+            ListenerType::TProxy => crate::proxy::tproxy::Listener::new(
+                (ip, self.port).into(),
+                self.dispatcher.clone(),
+                self.rate_limiter.clone(),
+            ),
+            #[cfg(not(target_os = "linux"))]
+            ListenerType::TProxy => unreachable!("tproxy is only listened on linux"),
         };
 
         if listener.handle_tcp() {
             let listener_type = self.listener_type.clone();
             info!("{} TCP listening at: {}:{}", self.name, ip, self.port);
 
+            let addr = (ip, self.port).into();
+            // bound right away when the port can be shared, so it's taken
+            // over before the previous listeners are stopped on a reload
+            let bound = LISTENER_REUSE_PORT.then(|| new_tcp_listener(addr));
             let tcp_listener = listener.clone();
             runners.push(
                 async move {
-                    tcp_listener.listen_tcp().await.map_err(|e| {
+                    let bound = match bound {
+                        Some(bound) => bound,
+                        None => new_tcp_listener(addr),
+                    };
+                    let r = match bound {
+                        Ok(bound) => tcp_listener.listen_tcp(bound).await,
+                        Err(e) => Err(e),
+                    };
+                    r.map_err(|e| {
                         warn!("handler of {:?} tcp listen failed: {}", listener_type, e);
                         e.into()
                     })
@@ -0,0 +1,187 @@
+//! Rate limiting of the new connections to the inbounds
+//!
+//! A source IP opening more connections than allowed in a window is banned
+//! for a while, its connections are closed as soon as accepted. Meant for
+//! the listeners exposed to the Internet, e.g. trojan, and the scanners
+//! hammering them.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::config::internal::config::InboundRateLimitConfig;
+
+/// the sources tracked at most, the least recent ones are forgotten
+const MAX_SOURCES: usize = 65536;
+/// the bans kept at most, new offenders are let through past this
+const MAX_BANS: usize = 65536;
+
+pub type ThreadSafeRateLimiter = Arc<RateLimiter>;
+
+#[derive(Serialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// seconds until the ban is lifted
+    pub remaining: u64,
+}
+
+struct State {
+    /// the start of the current window and the connections in it
+    windows: lru_time_cache::LruCache<IpAddr, (Instant, u32)>,
+    /// when the bans are lifted
+    bans: HashMap<IpAddr, Instant>,
+}
+
+pub struct RateLimiter {
+    connections: u32,
+    window: Duration,
+    ban: Duration,
+    exempt: Vec<IpNet>,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: InboundRateLimitConfig) -> Self {
+        Self {
+            connections: cfg.connections,
+            window: cfg.window,
+            ban: cfg.ban,
+            exempt: cfg.exempt,
+            state: Mutex::new(State {
+                windows: lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                    cfg.window,
+                    MAX_SOURCES,
+                ),
+                bans: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Count a new connection from `ip`, false if it's to be closed.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.exempt.iter().any(|x| x.contains(&ip)) {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match state.bans.get(&ip) {
+            Some(until) if *until > now => return false,
+            Some(_) => {
+                state.bans.remove(&ip);
+            }
+            None => {}
+        }
+
+        let count = match state.windows.get_mut(&ip) {
+            Some((start, count)) if now.duration_since(*start) < self.window => {
+                *count += 1;
+                *count
+            }
+            Some(window) => {
+                *window = (now, 1);
+                1
+            }
+            None => {
+                state.windows.insert(ip, (now, 1));
+                1
+            }
+        };
+        if count <= self.connections {
+            return true;
+        }
+
+        state.windows.remove(&ip);
+        state.bans.retain(|_, until| *until > now);
+        if state.bans.len() < MAX_BANS {
+            warn!(
+                "{} opened more than {} connections in {:?}, banned for {:?}",
+                ip, self.connections, self.window, self.ban
+            );
+            state.bans.insert(ip, now + self.ban);
+        }
+        false
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.bans.retain(|_, until| *until > now);
+        state
+            .bans
+            .iter()
+            .map(|(ip, until)| Ban {
+                ip: *ip,
+                remaining: until.duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Lift the ban of `ip`, false if it's not banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.state.lock().unwrap().bans.remove(&ip).is_some()
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().bans.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::internal::config::InboundRateLimitConfig;
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(InboundRateLimitConfig {
+            connections: 2,
+            window: Duration::from_secs(10),
+            ban: Duration::from_secs(60),
+            exempt: vec!["127.0.0.0/8".parse().unwrap()],
+        });
+        let ip = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        assert!(!limiter.allow_at(ip, now));
+        // still banned once the window is over
+        assert!(!limiter.allow_at(ip, now + Duration::from_secs(20)));
+        assert!(limiter.allow_at(ip, now + Duration::from_secs(61)));
+
+        for _ in 0..10 {
+            assert!(limiter.allow_at("127.0.0.1".parse().unwrap(), now));
+        }
+    }
+
+    #[test]
+    fn test_unban() {
+        let limiter = RateLimiter::new(InboundRateLimitConfig {
+            connections: 0,
+            window: Duration::from_secs(10),
+            ban: Duration::from_secs(60),
+            exempt: vec![],
+        });
+        let ip = "1.2.3.4".parse().unwrap();
+
+        assert!(!limiter.allow(ip));
+        assert_eq!(limiter.bans().len(), 1);
+        assert!(limiter.unban(ip));
+        assert!(!limiter.unban(ip));
+        assert!(limiter.bans().is_empty());
+    }
+}
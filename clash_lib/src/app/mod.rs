@@ -1,9 +1,13 @@
 pub mod api;
 pub mod dispatcher;
 pub mod dns;
+pub mod events;
+pub mod gateway;
 pub mod inbound;
 pub mod logging;
+pub mod ntp;
 pub mod outbound;
 pub mod profile;
 pub mod remote_content_manager;
 pub mod router;
+pub mod selfcheck;
@@ -0,0 +1,159 @@
+//! Clock skew detection
+//!
+//! Time sensitive protocols like VMess reject requests when the clocks of the
+//! client and the server differ too much, which only shows as an opaque auth
+//! error. Query an NTP server directly now and then, and warn when the system
+//! clock is off by more than the tolerance.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    config::internal::config::NtpConfig,
+    proxy::utils::{new_udp_socket, Interface},
+};
+
+/// seconds between 1900-01-01 (NTP epoch) and 1970-01-01 (UNIX epoch)
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start the periodic clock check in the background if enabled.
+pub fn spawn(cfg: NtpConfig, resolver: ThreadSafeDNSResolver, iface: Option<Interface>) {
+    if !cfg.enable {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(cfg.interval);
+        loop {
+            ticker.tick().await;
+            match query_offset(&cfg.server, cfg.port, &resolver, iface.as_ref()).await {
+                Ok(offset) => report(offset, cfg.tolerance, &cfg.server),
+                Err(e) => warn!("failed to query ntp server {}: {}", cfg.server, e),
+            }
+        }
+    });
+}
+
+fn report(offset: f64, tolerance: Duration, server: &str) {
+    if offset.abs() > tolerance.as_secs_f64() {
+        error!(
+            "system clock is off by {:.1}s according to {}, time sensitive protocols \
+             like VMess will fail to authenticate, please sync the system clock",
+            offset, server
+        );
+    } else {
+        info!("system clock offset {:.3}s according to {}", offset, server);
+    }
+}
+
+/// Returns how far the server clock is ahead of the local clock in seconds.
+async fn query_offset(
+    server: &str,
+    port: u16,
+    resolver: &ThreadSafeDNSResolver,
+    iface: Option<&Interface>,
+) -> io::Result<f64> {
+    let ip = resolver
+        .resolve_v4(server, false)
+        .await
+        .map_err(|x| io::Error::new(io::ErrorKind::Other, format!("dns failure: {}", x)))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no dns record"))?;
+    // through the NAT64 on an IPv6-only host
+    let (ip, src) = match resolver.nat64() {
+        Some(nat64) => (
+            IpAddr::V6(nat64.synthesize(ip)),
+            Some(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))),
+        ),
+        None => (IpAddr::V4(ip), None),
+    };
+
+    let socket = new_udp_socket(
+        src.as_ref(),
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        None,
+    )
+    .await?;
+
+    // LI = 0, VN = 3, Mode = 3 (client)
+    let mut req = [0u8; 48];
+    req[0] = 0x1b;
+
+    let t0 = now();
+    socket.send_to(&req, SocketAddr::new(ip, port)).await?;
+
+    let mut resp = [0u8; 48];
+    let n = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut resp))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "ntp query timed out"))??;
+    let t3 = now();
+
+    let offset = parse_offset(&resp[..n], t0, t3)?;
+    debug!("ntp offset from {}: {:.3}s", server, offset);
+    Ok(offset)
+}
+
+/// Compute the clock offset from an SNTP response (RFC 4330),
+/// `t0`/`t3` are the local send/receive times as UNIX seconds.
+fn parse_offset(resp: &[u8], t0: f64, t3: f64) -> io::Result<f64> {
+    if resp.len() < 48 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "short ntp response",
+        ));
+    }
+    // the mode must be 4 (server), stratum 0 is a kiss-o'-death
+    if resp[0] & 0x07 != 4 || resp[1] == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid ntp response",
+        ));
+    }
+
+    let t1 = read_timestamp(&resp[32..40]);
+    let t2 = read_timestamp(&resp[40..48]);
+
+    Ok(((t1 - t0) + (t2 - t3)) / 2.0)
+}
+
+fn read_timestamp(b: &[u8]) -> f64 {
+    let secs = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64;
+    let frac = u32::from_be_bytes([b[4], b[5], b[6], b[7]]) as f64 / (u32::MAX as f64 + 1.0);
+    secs + frac - NTP_UNIX_OFFSET
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_offset, NTP_UNIX_OFFSET};
+
+    #[test]
+    fn test_parse_offset() {
+        let server_time = (1_700_000_100.0 + NTP_UNIX_OFFSET) as u32;
+
+        let mut resp = [0u8; 48];
+        resp[0] = 0x24; // VN = 4, Mode = 4
+        resp[1] = 2;
+        resp[32..36].copy_from_slice(&server_time.to_be_bytes());
+        resp[40..44].copy_from_slice(&server_time.to_be_bytes());
+
+        let offset = parse_offset(&resp, 1_700_000_000.0, 1_700_000_000.0).unwrap();
+        assert!((offset - 100.0).abs() < 0.001);
+
+        resp[1] = 0;
+        assert!(parse_offset(&resp, 0.0, 0.0).is_err());
+    }
+}
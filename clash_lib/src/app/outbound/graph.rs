@@ -0,0 +1,179 @@
+//! The references between the proxy groups
+//!
+//! A group refers to proxies, to other groups and to proxy providers. The
+//! nesting of the groups is bounded when loaded, and the references are
+//! served as a graph for the tools drawing the configs.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{config::internal::proxy::OutboundGroupProtocol, Error};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Group,
+    Proxy,
+    Provider,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Node {
+    /// the name, prefixed with `provider:` for the providers
+    pub id: String,
+    pub name: String,
+    pub kind: NodeKind,
+    /// the protocol of the proxies and groups
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub proto: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+    #[serde(skip)]
+    node_ids: HashSet<String>,
+    #[serde(skip)]
+    edge_ids: HashSet<(String, String)>,
+}
+
+impl Graph {
+    /// Add a node if it's not there yet, its ID is returned.
+    pub fn add_node(&mut self, name: &str, kind: NodeKind, proto: Option<String>) -> String {
+        let id = Self::id(name, kind);
+        if self.node_ids.insert(id.clone()) {
+            self.nodes.push(Node {
+                id: id.clone(),
+                name: name.to_owned(),
+                kind,
+                proto,
+            });
+        }
+        id
+    }
+
+    pub fn add_edge(&mut self, from: &str, to: &str) {
+        if self.edge_ids.insert((from.to_owned(), to.to_owned())) {
+            self.edges.push(Edge {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            });
+        }
+    }
+
+    fn id(name: &str, kind: NodeKind) -> String {
+        match kind {
+            NodeKind::Provider => format!("provider:{}", name),
+            _ => name.to_owned(),
+        }
+    }
+}
+
+/// The references of a group, as configured.
+#[derive(Debug)]
+pub struct GroupRefs {
+    pub name: String,
+    pub proxies: Vec<String>,
+    pub providers: Vec<String>,
+}
+
+/// The references of the groups, checked to be nested `max_depth` deep at
+/// most, the groups must be free of cycles.
+pub fn group_refs(
+    groups: &[OutboundGroupProtocol],
+    max_depth: usize,
+) -> Result<Vec<GroupRefs>, Error> {
+    let by_name = groups
+        .iter()
+        .map(|x| (x.name(), x))
+        .collect::<HashMap<_, _>>();
+
+    let mut chains = HashMap::new();
+    let deepest = groups
+        .iter()
+        .map(|x| deepest_chain(x.name(), &by_name, &mut chains))
+        .max_by_key(|x| x.len())
+        .unwrap_or_default();
+    if deepest.len() > max_depth {
+        return Err(Error::InvalidConfig(format!(
+            "proxy group {} is nested {} deep ({}), more than max-group-depth {}",
+            deepest[0],
+            deepest.len(),
+            deepest.join(" -> "),
+            max_depth
+        )));
+    }
+
+    Ok(groups
+        .iter()
+        .map(|x| GroupRefs {
+            name: x.name().to_owned(),
+            proxies: x.proxies().cloned().unwrap_or_default(),
+            providers: x.use_provider().cloned().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// The longest chain of groups from `name`, `name` included.
+fn deepest_chain<'a>(
+    name: &'a str,
+    groups: &HashMap<&'a str, &'a OutboundGroupProtocol>,
+    chains: &mut HashMap<&'a str, Vec<&'a str>>,
+) -> Vec<&'a str> {
+    if let Some(chain) = chains.get(name) {
+        return chain.clone();
+    }
+
+    let mut deepest = vec![];
+    for proxy in groups[name].proxies().into_iter().flatten() {
+        if groups.contains_key(proxy.as_str()) {
+            let chain = deepest_chain(proxy, groups, chains);
+            if chain.len() > deepest.len() {
+                deepest = chain;
+            }
+        }
+    }
+    deepest.insert(0, name);
+    chains.insert(name, deepest.clone());
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::internal::proxy::{OutboundGroupProtocol, OutboundGroupSelect};
+
+    use super::group_refs;
+
+    fn select(name: &str, proxies: &[&str]) -> OutboundGroupProtocol {
+        OutboundGroupProtocol::Select(OutboundGroupSelect {
+            name: name.to_owned(),
+            proxies: Some(proxies.iter().map(|x| x.to_string()).collect()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let groups = vec![
+            select("a", &["b", "DIRECT"]),
+            select("b", &["c"]),
+            select("c", &["ss"]),
+            select("d", &["c"]),
+        ];
+
+        let refs = group_refs(&groups, 3).unwrap();
+        assert_eq!(refs.len(), 4);
+        assert_eq!(refs[0].proxies, vec!["b", "DIRECT"]);
+
+        let err = group_refs(&groups, 2).unwrap_err().to_string();
+        assert!(err.contains("a -> b -> c"), "{}", err);
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use erased_serde::Serialize;
 use hyper::Uri;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
@@ -17,18 +17,22 @@ use crate::app::profile::ThreadSafeCacheFile;
 use crate::app::remote_content_manager::healthcheck::HealthCheck;
 use crate::app::remote_content_manager::providers::file_vehicle;
 use crate::app::remote_content_manager::providers::http_vehicle;
-use crate::app::remote_content_manager::ProxyManager;
+use crate::app::remote_content_manager::{ProxyManager, SwitchHistory};
 
 use crate::app::remote_content_manager::providers::proxy_provider::PlainProvider;
 use crate::app::remote_content_manager::providers::proxy_provider::ProxySetProvider;
 use crate::app::remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider;
+use crate::common::errors::DialError;
+use crate::config::def::ChaosFault;
 use crate::config::internal::proxy::PROXY_GLOBAL;
 use crate::config::internal::proxy::{OutboundProxyProviderDef, PROXY_DIRECT, PROXY_REJECT};
+use crate::proxy::chaos::{self, ThreadSafeChaos};
 use crate::proxy::fallback;
 use crate::proxy::loadbalance;
 use crate::proxy::selector;
 
 use crate::proxy::selector::ThreadSafeSelectorControl;
+use crate::proxy::uot;
 use crate::proxy::urltest;
 use crate::proxy::{reject, relay};
 use crate::{
@@ -37,6 +41,8 @@ use crate::{
     Error,
 };
 
+use super::graph::{self, Graph, GroupRefs, NodeKind};
+use super::registry;
 use super::utils::proxy_groups_dag_sort;
 
 static RESERVED_PROVIDER_NAME: &str = "default";
@@ -46,6 +52,8 @@ pub struct OutboundManager {
     proxy_providers: HashMap<String, ThreadSafeProxyProvider>,
     proxy_manager: ProxyManager,
     selector_control: HashMap<String, ThreadSafeSelectorControl>,
+    chaos: Option<ThreadSafeChaos>,
+    group_refs: Vec<GroupRefs>,
 }
 
 static DEFAULT_LATENCY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
@@ -53,6 +61,7 @@ static DEFAULT_LATENCY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
 pub type ThreadSafeOutboundManager = Arc<OutboundManager>;
 
 impl OutboundManager {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         outbounds: Vec<OutboundProxyProtocol>,
         outbound_groups: Vec<OutboundGroupProtocol>,
@@ -61,11 +70,14 @@ impl OutboundManager {
         dns_resolver: ThreadSafeDNSResolver,
         cache_store: ThreadSafeCacheFile,
         cwd: String,
+        chaos: Option<HashMap<String, ChaosFault>>,
+        max_group_depth: usize,
     ) -> Result<Self, Error> {
         let mut handlers = HashMap::new();
         let mut provider_registry = HashMap::new();
         let mut selector_control = HashMap::new();
         let proxy_manager = ProxyManager::new(dns_resolver.clone());
+        let chaos = chaos.map(|x| Arc::new(chaos::Chaos::new(x)));
 
         debug!("initializing proxy providers");
         Self::load_proxy_providers(
@@ -78,6 +90,7 @@ impl OutboundManager {
         .await?;
 
         debug!("initializing handlers");
+        let groups = outbound_groups.clone();
         Self::load_handlers(
             outbounds,
             outbound_groups,
@@ -87,14 +100,19 @@ impl OutboundManager {
             &mut handlers,
             &mut selector_control,
             cache_store,
+            chaos.clone(),
         )
         .await?;
+        // the groups were checked for cycles once loaded
+        let group_refs = graph::group_refs(&groups, max_group_depth)?;
 
         Ok(Self {
             handlers,
             proxy_manager,
             selector_control,
             proxy_providers: provider_registry,
+            chaos,
+            group_refs,
         })
     }
 
@@ -102,6 +120,20 @@ impl OutboundManager {
         self.handlers.get(name).cloned()
     }
 
+    /// look up a proxy by name, including the ones from proxy providers
+    pub async fn find_proxy(&self, name: &str) -> Option<AnyOutboundHandler> {
+        if let Some(handler) = self.get_outbound(name) {
+            return Some(handler);
+        }
+        for provider in self.proxy_providers.values() {
+            let proxies = provider.read().await.proxies().await;
+            if let Some(handler) = proxies.into_iter().find(|x| x.name() == name) {
+                return Some(handler);
+            }
+        }
+        None
+    }
+
     /// this doesn't populate history/liveness information
     pub fn get_proxy_provider(&self, name: &str) -> Option<ThreadSafeProxyProvider> {
         self.proxy_providers.get(name).cloned()
@@ -123,8 +155,10 @@ impl OutboundManager {
             let alive = proxy_manager.alive(k).await;
             let history = proxy_manager.delay_history(k).await;
             let support_udp = v.support_udp().await;
+            let last_error = proxy_manager.last_dial_error(k);
 
             m.insert("history".to_string(), Box::new(history));
+            m.insert("lastError".to_string(), Box::new(last_error));
             m.insert("alive".to_string(), Box::new(alive));
             m.insert("name".to_string(), Box::new(k.to_owned()));
             m.insert("udp".to_string(), Box::new(support_udp));
@@ -146,8 +180,10 @@ impl OutboundManager {
         let alive = proxy_manager.alive(proxy.name()).await;
         let history = proxy_manager.delay_history(proxy.name()).await;
         let support_udp = proxy.support_udp().await;
+        let last_error = proxy_manager.last_dial_error(proxy.name());
 
         r.insert("history".to_string(), Box::new(history));
+        r.insert("lastError".to_string(), Box::new(last_error));
         r.insert("alive".to_string(), Box::new(alive));
         r.insert("name".to_string(), Box::new(proxy.name().to_owned()));
         r.insert("udp".to_string(), Box::new(support_udp));
@@ -163,13 +199,91 @@ impl OutboundManager {
         timeout: Duration,
     ) -> std::io::Result<(u16, u16)> {
         let proxy_manager = self.proxy_manager.clone();
-        proxy_manager.url_test(proxy, url, Some(timeout)).await
+        proxy_manager
+            .url_test_debounced(proxy, url, Some(timeout))
+            .await
+    }
+
+    /// Record that a connection went through the proxy, the health checks
+    /// probe the busy proxies more often.
+    pub fn report_used(&self, name: &str) {
+        self.proxy_manager.report_used(name);
+    }
+
+    /// Keep why a dial through the proxy failed, for the API.
+    pub fn report_dial_error(&self, name: &str, err: DialError) {
+        self.proxy_manager.report_dial_error(name, err);
+    }
+
+    /// How often and why the fallback or url-test group switched members.
+    pub fn switch_history(&self, group: &str) -> SwitchHistory {
+        self.proxy_manager.switch_history(group)
     }
 
     pub fn get_proxy_providers(&self) -> HashMap<String, ThreadSafeProxyProvider> {
         self.proxy_providers.clone()
     }
 
+    /// the injected faults, `None` if chaos is disabled
+    pub fn chaos(&self) -> Option<ThreadSafeChaos> {
+        self.chaos.clone()
+    }
+
+    /// The proxies and the groups, with the proxies, groups and providers
+    /// each group refers to, and the proxies of these providers.
+    pub async fn graph(&self) -> Graph {
+        let mut graph = Graph::default();
+        let is_group = |name: &str| self.group_refs.iter().any(|x| x.name == name);
+
+        for (name, handler) in self.handlers.iter() {
+            let kind = if is_group(name) {
+                NodeKind::Group
+            } else {
+                NodeKind::Proxy
+            };
+            graph.add_node(name, kind, Some(handler.proto().to_string()));
+        }
+
+        for group in self.group_refs.iter() {
+            let from = graph.add_node(&group.name, NodeKind::Group, None);
+            for proxy in group.proxies.iter() {
+                let kind = if is_group(proxy) {
+                    NodeKind::Group
+                } else {
+                    NodeKind::Proxy
+                };
+                let to = graph.add_node(proxy, kind, None);
+                graph.add_edge(&from, &to);
+            }
+            for provider in group.providers.iter() {
+                let to = graph.add_node(provider, NodeKind::Provider, None);
+                graph.add_edge(&from, &to);
+            }
+        }
+
+        let providers = self
+            .group_refs
+            .iter()
+            .flat_map(|x| x.providers.iter())
+            .collect::<HashSet<_>>();
+        for name in providers {
+            let Some(provider) = self.proxy_providers.get(name) else {
+                continue;
+            };
+            let from = graph.add_node(name, NodeKind::Provider, None);
+            for proxy in provider.read().await.proxies().await {
+                let to = graph.add_node(
+                    proxy.name(),
+                    NodeKind::Proxy,
+                    Some(proxy.proto().to_string()),
+                );
+                graph.add_edge(&from, &to);
+            }
+        }
+
+        graph
+    }
+
     // API handlers end
 
     #[allow(clippy::too_many_arguments)]
@@ -182,6 +296,7 @@ impl OutboundManager {
         handlers: &mut HashMap<String, AnyOutboundHandler>,
         selector_control: &mut HashMap<String, ThreadSafeSelectorControl>,
         cache_store: ThreadSafeCacheFile,
+        chaos: Option<ThreadSafeChaos>,
     ) -> Result<(), Error> {
         let mut proxy_providers = vec![];
 
@@ -195,14 +310,26 @@ impl OutboundManager {
                     handlers.insert(PROXY_REJECT.to_string(), reject::Handler::new());
                 }
 
+                OutboundProxyProtocol::DirectWith(d) => {
+                    handlers.insert(d.name.clone(), d.try_into()?);
+                }
+
                 OutboundProxyProtocol::Ss(s) => {
-                    handlers.insert(s.name.clone(), s.try_into()?);
+                    handlers.insert(s.name.clone(), uot::Handler::wrap_if(s.try_into()?, s.uot));
                 }
 
                 OutboundProxyProtocol::Vmess(v) => {
                     handlers.insert(v.name.clone(), v.try_into()?);
                 }
 
+                OutboundProxyProtocol::Vless(v) => {
+                    handlers.insert(v.name.clone(), v.try_into()?);
+                }
+
+                OutboundProxyProtocol::Socks5(s) => {
+                    handlers.insert(s.name.clone(), uot::Handler::wrap_if(s.try_into()?, s.uot));
+                }
+
                 OutboundProxyProtocol::Trojan(v) => {
                     handlers.insert(v.name.clone(), v.try_into()?);
                 }
@@ -215,15 +342,34 @@ impl OutboundManager {
                 OutboundProxyProtocol::Tor(tor) => {
                     handlers.insert(tor.name.clone(), tor.try_into()?);
                 }
+                OutboundProxyProtocol::Ssh(ssh) => {
+                    handlers.insert(ssh.name.clone(), ssh.try_into()?);
+                }
                 OutboundProxyProtocol::Tuic(tuic) => {
                     handlers.insert(tuic.name.clone(), tuic.try_into()?);
                 }
+                OutboundProxyProtocol::Masque(masque) => {
+                    handlers.insert(masque.name.clone(), masque.try_into()?);
+                }
+                OutboundProxyProtocol::Hysteria2(hysteria2) => {
+                    handlers.insert(hysteria2.name.clone(), hysteria2.try_into()?);
+                }
+                OutboundProxyProtocol::Plugin(p) => {
+                    handlers.insert(p.name.clone(), registry::create(p)?);
+                }
                 p => {
                     unimplemented!("proto {} not supported yet", p);
                 }
             }
         }
 
+        // before the groups take the handlers
+        if let Some(chaos) = chaos {
+            for handler in handlers.values_mut() {
+                *handler = chaos::Handler::new(handler.clone(), chaos.clone());
+            }
+        }
+
         let mut outbound_groups = outbound_groups;
         proxy_groups_dag_sort(&mut outbound_groups)?;
 
@@ -233,6 +379,8 @@ impl OutboundManager {
             proxies: &[String],
             interval: u64,
             lazy: bool,
+            idle_timeout: Option<u64>,
+            adaptive: bool,
             handlers: &HashMap<String, AnyOutboundHandler>,
             proxy_manager: ProxyManager,
             proxy_providers: &mut Vec<ThreadSafeProxyProvider>,
@@ -261,7 +409,9 @@ impl OutboundManager {
                 lazy,
                 proxy_manager.clone(),
             )
-            .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
+            .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?
+            .with_idle_timeout(idle_timeout.map(Duration::from_secs))
+            .with_adaptive(adaptive);
 
             let pd = Arc::new(RwLock::new(
                 PlainProvider::new(name.to_owned(), proxies, hc)
@@ -298,6 +448,8 @@ impl OutboundManager {
                             proxies,
                             0,
                             true,
+                            None,
+                            false,
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -347,6 +499,8 @@ impl OutboundManager {
                             proxies,
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
+                            proto.idle_timeout,
+                            proto.adaptive.unwrap_or_default(),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -398,6 +552,8 @@ impl OutboundManager {
                             proxies,
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
+                            proto.idle_timeout,
+                            proto.adaptive.unwrap_or_default(),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -418,6 +574,9 @@ impl OutboundManager {
                     let fallback = fallback::Handler::new(
                         fallback::HandlerOptions {
                             name: proto.name.clone(),
+                            max_failed_times: proto
+                                .max_failed_times
+                                .unwrap_or(fallback::DEFAULT_MAX_FAILED_TIMES),
                             ..Default::default()
                         },
                         providers,
@@ -448,6 +607,8 @@ impl OutboundManager {
                             proxies,
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
+                            proto.idle_timeout,
+                            proto.adaptive.unwrap_or_default(),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -468,9 +629,11 @@ impl OutboundManager {
                     let load_balance = loadbalance::Handler::new(
                         loadbalance::HandlerOptions {
                             name: proto.name.clone(),
+                            strategy: proto.strategy.unwrap_or_default(),
                             ..Default::default()
                         },
                         providers,
+                        proxy_manager.clone(),
                     );
 
                     handlers.insert(proto.name.clone(), Arc::new(load_balance));
@@ -497,6 +660,8 @@ impl OutboundManager {
                             proxies,
                             0,
                             true,
+                            None,
+                            false,
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -516,6 +681,7 @@ impl OutboundManager {
                     }
 
                     let stored_selection = cache_store.get_selected(&proto.name).await;
+                    let stored_order = cache_store.get_order(&proto.name).await;
 
                     let selector = selector::Handler::new(
                         selector::HandlerOptions {
@@ -525,6 +691,7 @@ impl OutboundManager {
                         },
                         providers,
                         stored_selection,
+                        stored_order,
                     )
                     .await;
 
@@ -552,6 +719,7 @@ impl OutboundManager {
         ));
 
         let stored_selection = cache_store.get_selected(PROXY_GLOBAL).await;
+        let stored_order = cache_store.get_order(PROXY_GLOBAL).await;
         let h = selector::Handler::new(
             selector::HandlerOptions {
                 name: PROXY_GLOBAL.to_owned(),
@@ -560,6 +728,7 @@ impl OutboundManager {
             },
             vec![pd.clone()],
             stored_selection,
+            stored_order,
         )
         .await;
 
@@ -595,7 +764,10 @@ impl OutboundManager {
                         http.health_check.lazy.unwrap_or_default(),
                         proxy_manager.clone(),
                     )
-                    .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
+                    .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?
+                    .with_idle_timeout(http.health_check.idle_timeout.map(Duration::from_secs))
+                    .with_adaptive(http.health_check.adaptive.unwrap_or_default())
+                    .with_budget(http.health_check.budget);
                     let provider = ProxySetProvider::new(
                         name.clone(),
                         Duration::from_secs(http.interval),
@@ -620,7 +792,10 @@ impl OutboundManager {
                         file.health_check.lazy.unwrap_or_default(),
                         proxy_manager.clone(),
                     )
-                    .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
+                    .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?
+                    .with_idle_timeout(file.health_check.idle_timeout.map(Duration::from_secs))
+                    .with_adaptive(file.health_check.adaptive.unwrap_or_default())
+                    .with_budget(file.health_check.budget);
 
                     let provider = ProxySetProvider::new(
                         name.clone(),
@@ -1,3 +1,5 @@
+pub mod graph;
 pub mod manager;
+pub mod registry;
 
 mod utils;
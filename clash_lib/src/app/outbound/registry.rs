@@ -0,0 +1,150 @@
+//! Proxy types implemented out of tree
+//!
+//! A crate embedding clash registers a factory for its `type:` before
+//! starting, the proxies of that type in the config and in the providers are
+//! then built by the factory instead of failing to parse.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use once_cell::sync::Lazy;
+use serde::{de::value::MapDeserializer, Deserialize};
+use serde_yaml::Value;
+
+use crate::{
+    config::internal::proxy::{OutboundPlugin, OutboundProxyProtocol},
+    proxy::AnyOutboundHandler,
+    Error,
+};
+
+/// the types of the proxies built in, they can't be replaced
+static BUILTIN_TYPES: Lazy<&'static [&'static str]> = Lazy::new(|| {
+    // the error of an unknown tag lists the variants of the enum
+    let tag = [("type", "")].into_iter();
+    match OutboundProxyProtocol::deserialize(MapDeserializer::<_, Variants>::new(tag)) {
+        Err(Variants(types)) => types,
+        Ok(_) => unreachable!("the empty type is not a proxy"),
+    }
+});
+
+#[derive(Debug)]
+struct Variants(&'static [&'static str]);
+
+impl std::fmt::Display for Variants {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl std::error::Error for Variants {}
+
+impl serde::de::Error for Variants {
+    fn custom<T: std::fmt::Display>(_: T) -> Self {
+        Self(&[])
+    }
+
+    fn unknown_variant(_: &str, expected: &'static [&'static str]) -> Self {
+        Self(expected)
+    }
+}
+
+static FACTORIES: Lazy<RwLock<HashMap<String, Arc<dyn OutboundFactory>>>> =
+    Lazy::new(Default::default);
+
+pub trait OutboundFactory: Send + Sync {
+    /// Build the handler of a proxy, `options` is its whole mapping in the
+    /// config, `name` and `type` included.
+    fn create(
+        &self,
+        name: &str,
+        options: &HashMap<String, Value>,
+    ) -> Result<AnyOutboundHandler, Error>;
+}
+
+/// Register the factory of the proxies of `type_name`, registering a type
+/// twice replaces the factory.
+pub fn register_outbound(type_name: &str, factory: Arc<dyn OutboundFactory>) -> Result<(), Error> {
+    if BUILTIN_TYPES.contains(&type_name) {
+        return Err(Error::InvalidConfig(format!(
+            "proxy type `{}` is built in",
+            type_name
+        )));
+    }
+    FACTORIES
+        .write()
+        .unwrap()
+        .insert(type_name.to_owned(), factory);
+    Ok(())
+}
+
+pub(crate) fn is_registered(type_name: &str) -> bool {
+    FACTORIES.read().unwrap().contains_key(type_name)
+}
+
+pub(crate) fn create(p: &OutboundPlugin) -> Result<AnyOutboundHandler, Error> {
+    let factory = FACTORIES
+        .read()
+        .unwrap()
+        .get(&p.typ)
+        .cloned()
+        .ok_or_else(|| Error::InvalidConfig(format!("unknown proxy type: {}", p.typ)))?;
+    factory.create(&p.name, &p.options)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use serde_yaml::Value;
+
+    use super::{register_outbound, OutboundFactory};
+    use crate::{
+        config::internal::proxy::OutboundProxyProtocol,
+        proxy::{direct, AnyOutboundHandler},
+        Error,
+    };
+
+    struct Factory;
+
+    impl OutboundFactory for Factory {
+        fn create(
+            &self,
+            _name: &str,
+            _options: &HashMap<String, Value>,
+        ) -> Result<AnyOutboundHandler, Error> {
+            Ok(direct::Handler::new())
+        }
+    }
+
+    #[test]
+    fn test_register_outbound() {
+        for builtin in [
+            "direct",
+            "ss",
+            "socks5",
+            "vless",
+            "ssh",
+            "tor",
+            "masque",
+            "hysteria2",
+        ] {
+            assert!(register_outbound(builtin, Arc::new(Factory)).is_err());
+        }
+
+        let proxy: HashMap<String, Value> =
+            serde_yaml::from_str("{name: custom, type: my-proto, key: value}").unwrap();
+        assert!(OutboundProxyProtocol::try_from(proxy.clone()).is_err());
+
+        register_outbound("my-proto", Arc::new(Factory)).unwrap();
+        match OutboundProxyProtocol::try_from(proxy).unwrap() {
+            OutboundProxyProtocol::Plugin(p) => {
+                assert_eq!(p.name, "custom");
+                assert_eq!(p.options["key"], Value::from("value"));
+                assert!(super::create(&p).is_ok());
+            }
+            _ => panic!("not a plugin"),
+        }
+    }
+}
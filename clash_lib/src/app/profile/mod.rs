@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use tracing::{error, trace};
@@ -6,15 +6,26 @@ use tracing::{error, trace};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Db {
     selected: HashMap<String, String>,
+    /// manual ordering of the group members
+    #[serde(default)]
+    order: HashMap<String, Vec<String>>,
     ip_to_host: HashMap<String, String>,
     host_to_ip: HashMap<String, String>,
+    /// the addresses of the IPv6 fake-ip pool
+    #[serde(default)]
+    host_to_ip6: HashMap<String, String>,
+    /// where the fake-ip pools go on allocating from
+    #[serde(default)]
+    fake_ip_offset: u64,
+    #[serde(default)]
+    fake_ip6_offset: u64,
 }
 
 #[derive(Clone)]
 pub struct ThreadSafeCacheFile(Arc<tokio::sync::RwLock<CacheFile>>);
 
 impl ThreadSafeCacheFile {
-    pub fn new(path: &str, store_selected: bool) -> Self {
+    pub fn new(path: &str, store_selected: bool, store_fake_ip: bool) -> Self {
         let store = Arc::new(tokio::sync::RwLock::new(CacheFile::new(
             path,
             store_selected,
@@ -23,7 +34,7 @@ impl ThreadSafeCacheFile {
         let path = path.to_string();
         let store_clone = store.clone();
 
-        if store_selected {
+        if store_selected || store_fake_ip {
             tokio::spawn(async move {
                 let store = store_clone;
                 loop {
@@ -68,6 +79,22 @@ impl ThreadSafeCacheFile {
         }
     }
 
+    pub async fn set_order(&self, group: &str, order: Vec<String>) {
+        let mut g = self.0.write().await;
+        if g.store_selected() {
+            g.db.order.insert(group.to_string(), order);
+        }
+    }
+
+    pub async fn get_order(&self, group: &str) -> Vec<String> {
+        let g = self.0.read().await;
+        if g.store_selected() {
+            g.db.order.get(group).cloned().unwrap_or_default()
+        } else {
+            vec![]
+        }
+    }
+
     #[allow(dead_code)]
     pub async fn get_selected_map(&self) -> HashMap<String, String> {
         let g = self.0.read().await;
@@ -78,21 +105,44 @@ impl ThreadSafeCacheFile {
         }
     }
 
-    pub async fn set_ip_to_host(&self, ip: &str, host: &str) {
+    pub async fn set_ip_to_host(&self, ip: IpAddr, host: &str) {
         self.0.write().await.set_ip_to_host(ip, host);
     }
 
-    pub async fn set_host_to_ip(&self, host: &str, ip: &str) {
+    pub async fn set_host_to_ip(&self, host: &str, ip: IpAddr) {
         self.0.write().await.set_host_to_ip(host, ip);
     }
 
-    pub async fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.0.read().await.get_fake_ip(ip_or_host)
+    /// The fake IP of `host` in the pool of the IPv6 or the IPv4 addresses.
+    pub async fn get_fake_ip(&self, host: &str, v6: bool) -> Option<IpAddr> {
+        self.0.read().await.get_fake_ip(host, v6)
+    }
+
+    pub async fn get_fake_ip_host(&self, ip: IpAddr) -> Option<String> {
+        self.0.read().await.get_fake_ip_host(ip)
     }
 
-    pub async fn delete_fake_ip_pair(&self, ip: &str, host: &str) {
+    pub async fn delete_fake_ip_pair(&self, ip: IpAddr, host: &str) {
         self.0.write().await.delete_fake_ip_pair(ip, host);
     }
+
+    pub async fn get_fake_ip_offset(&self, v6: bool) -> u64 {
+        let g = self.0.read().await;
+        if v6 {
+            g.db.fake_ip6_offset
+        } else {
+            g.db.fake_ip_offset
+        }
+    }
+
+    pub async fn set_fake_ip_offset(&self, v6: bool, offset: u64) {
+        let mut g = self.0.write().await;
+        if v6 {
+            g.db.fake_ip6_offset = offset;
+        } else {
+            g.db.fake_ip_offset = offset;
+        }
+    }
 }
 
 struct CacheFile {
@@ -110,8 +160,12 @@ impl CacheFile {
                     error!("failed to parse cache file: {}, initilizing a new one", e);
                     Db {
                         selected: HashMap::new(),
+                        order: HashMap::new(),
                         ip_to_host: HashMap::new(),
                         host_to_ip: HashMap::new(),
+                        host_to_ip6: HashMap::new(),
+                        fake_ip_offset: 0,
+                        fake_ip6_offset: 0,
                     }
                 }
             },
@@ -119,8 +173,12 @@ impl CacheFile {
                 error!("failed to read cache file: {}, initializing a new one", e);
                 Db {
                     selected: HashMap::new(),
+                    order: HashMap::new(),
                     ip_to_host: HashMap::new(),
                     host_to_ip: HashMap::new(),
+                    host_to_ip6: HashMap::new(),
+                    fake_ip_offset: 0,
+                    fake_ip6_offset: 0,
                 }
             }
         };
@@ -142,24 +200,46 @@ impl CacheFile {
         self.db.selected.clone()
     }
 
-    pub fn set_ip_to_host(&mut self, ip: &str, host: &str) {
+    fn host_to_ip(&self, v6: bool) -> &HashMap<String, String> {
+        if v6 {
+            &self.db.host_to_ip6
+        } else {
+            &self.db.host_to_ip
+        }
+    }
+
+    fn host_to_ip_mut(&mut self, v6: bool) -> &mut HashMap<String, String> {
+        if v6 {
+            &mut self.db.host_to_ip6
+        } else {
+            &mut self.db.host_to_ip
+        }
+    }
+
+    pub fn set_ip_to_host(&mut self, ip: IpAddr, host: &str) {
         self.db.ip_to_host.insert(ip.to_string(), host.to_string());
     }
 
-    pub fn set_host_to_ip(&mut self, host: &str, ip: &str) {
-        self.db.host_to_ip.insert(host.to_string(), ip.to_string());
+    pub fn set_host_to_ip(&mut self, host: &str, ip: IpAddr) {
+        self.host_to_ip_mut(ip.is_ipv6())
+            .insert(host.to_string(), ip.to_string());
     }
 
-    pub fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.db
-            .ip_to_host
-            .get(ip_or_host)
-            .or_else(|| self.db.host_to_ip.get(ip_or_host))
-            .cloned()
+    pub fn get_fake_ip(&self, host: &str, v6: bool) -> Option<IpAddr> {
+        self.host_to_ip(v6).get(host).and_then(|ip| ip.parse().ok())
+    }
+
+    pub fn get_fake_ip_host(&self, ip: IpAddr) -> Option<String> {
+        self.db.ip_to_host.get(&ip.to_string()).cloned()
     }
 
-    pub fn delete_fake_ip_pair(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.remove(ip);
-        self.db.host_to_ip.remove(host);
+    pub fn delete_fake_ip_pair(&mut self, ip: IpAddr, host: &str) {
+        let host_to_ip = self.host_to_ip_mut(ip.is_ipv6());
+        let ip = ip.to_string();
+        // the host may have been given another address since
+        if host_to_ip.get(host) == Some(&ip) {
+            host_to_ip.remove(host);
+        }
+        self.db.ip_to_host.remove(&ip);
     }
 }
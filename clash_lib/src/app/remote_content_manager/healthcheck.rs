@@ -1,5 +1,10 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use rand::{seq::SliceRandom, Rng};
 use tokio::time::Instant;
 use tracing::debug;
 
@@ -7,8 +12,70 @@ use crate::proxy::AnyOutboundHandler;
 
 use super::ProxyManager;
 
+/// with adaptive checks the idle proxies are probed up to this many times
+/// less often than the busy ones
+const MAX_BACKOFF: u32 = 8;
+/// the probes are spread by up to this share of their period, so the
+/// proxies of a group don't all fire at once
+const JITTER: f64 = 0.1;
+
+/// Refills the background probes allowed per hour evenly, and holds up to an
+/// hour's worth so the first round goes through at once.
+struct CheckBudget {
+    per_hour: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl CheckBudget {
+    fn new(per_hour: u32) -> Self {
+        Self {
+            per_hour: per_hour as f64,
+            tokens: per_hour as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take up to `n` probes at `now`, returns how many are allowed.
+    fn take(&mut self, n: usize, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.per_hour / 3600.0).min(self.per_hour);
+        self.last_refill = now;
+
+        let allowed = (self.tokens.floor() as usize).min(n);
+        self.tokens -= allowed as f64;
+        allowed
+    }
+}
+
+/// The proxies the budget allows to probe now, picked at random when it
+/// doesn't cover them all so none is left out for good.
+fn within_budget(
+    budget: &Option<Arc<Mutex<CheckBudget>>>,
+    mut proxies: Vec<AnyOutboundHandler>,
+    url: &str,
+) -> Vec<AnyOutboundHandler> {
+    let Some(budget) = budget else {
+        return proxies;
+    };
+    let allowed = budget.lock().unwrap().take(proxies.len(), Instant::now());
+    if allowed < proxies.len() {
+        debug!(
+            "healthcheck budget allows {} of {} probes: {}",
+            allowed,
+            proxies.len(),
+            url
+        );
+        proxies.shuffle(&mut rand::thread_rng());
+        proxies.truncate(allowed);
+    }
+    proxies
+}
+
 struct HealCheckInner {
     last_check: Instant,
+    last_touch: Instant,
     proxies: Vec<AnyOutboundHandler>,
     task_handle: Option<Arc<tokio::task::JoinHandle<()>>>,
 }
@@ -17,6 +84,9 @@ pub struct HealthCheck {
     url: String,
     interval: u64,
     lazy: bool,
+    idle_timeout: Option<Duration>,
+    adaptive: bool,
+    budget: Option<Arc<Mutex<CheckBudget>>>,
     proxy_manager: ProxyManager,
     inner: Arc<tokio::sync::RwLock<HealCheckInner>>,
 }
@@ -33,9 +103,13 @@ impl HealthCheck {
             url,
             interval,
             lazy,
+            idle_timeout: None,
+            adaptive: false,
+            budget: None,
             proxy_manager,
             inner: Arc::new(tokio::sync::RwLock::new(HealCheckInner {
                 last_check: tokio::time::Instant::now(),
+                last_touch: tokio::time::Instant::now(),
                 proxies,
                 task_handle: None,
             })),
@@ -43,15 +117,40 @@ impl HealthCheck {
         Ok(health_check)
     }
 
+    /// Pause the periodic checks once the proxies are not used for the
+    /// timeout, they resume on the next use.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout.filter(|x| !x.is_zero());
+        self
+    }
+
+    /// Probe each proxy on its own schedule, every `interval` while it
+    /// carries traffic and backing off up to `MAX_BACKOFF` times as it idles,
+    /// instead of the whole set every `interval`. Takes over from `lazy`.
+    pub fn with_adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Cap the background probes to this many per hour, for the metered
+    /// uplinks. The checks requested through the API are not counted.
+    pub fn with_budget(mut self, per_hour: Option<u32>) -> Self {
+        self.budget = per_hour.map(|x| Arc::new(Mutex::new(CheckBudget::new(x))));
+        self
+    }
+
     pub async fn kick_off(&self) {
         let proxy_manager = self.proxy_manager.clone();
         let interval = self.interval;
         let lazy = self.lazy;
+        let idle_timeout = self.idle_timeout;
+        let adaptive = self.adaptive;
+        let budget = self.budget.clone();
         let proxies = self.inner.read().await.proxies.clone();
 
         {
             let url = self.url.clone();
-            let proxies = proxies.clone();
+            let proxies = within_budget(&budget, proxies.clone(), &url);
             tokio::spawn(async move {
                 proxy_manager.check(&proxies, &url, None).await;
             });
@@ -61,14 +160,64 @@ impl HealthCheck {
         let proxy_manager = self.proxy_manager.clone();
         let url = self.url.clone();
         let task_handle = tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(tokio::time::Duration::from_secs(interval));
+            let period = Duration::from_secs(interval);
+            let mut ticker = if adaptive {
+                // fine grained enough for the busy proxies to be on time
+                tokio::time::interval((period / 4).max(Duration::from_secs(1)))
+            } else {
+                tokio::time::interval(period)
+            };
+            // the initial check above is the first round
+            let mut next_check: HashMap<String, Instant> = HashMap::new();
+            if adaptive {
+                let now = tokio::time::Instant::now();
+                for p in proxies.iter() {
+                    next_check.insert(p.name().to_owned(), now + adaptive_period(period, None));
+                }
+            }
             loop {
                 tokio::select! {
                     _ = ticker.tick() => {
                         debug!("healthcheck ticking: {}, lazy: {}", url, lazy);
                         let now = tokio::time::Instant::now();
-                        let last_check = inner.read().await.last_check;
-                        if !lazy || now.duration_since(last_check).as_secs() >= interval {
+                        let (last_check, last_touch) = {
+                            let r = inner.read().await;
+                            (r.last_check, r.last_touch)
+                        };
+                        if idle_timeout.is_some_and(|x| now.duration_since(last_touch) >= x) {
+                            debug!("healthcheck paused as the proxies are idle: {}", url);
+                        } else if adaptive {
+                            let due: Vec<_> = proxies
+                                .iter()
+                                .filter(|p| {
+                                    next_check.get(p.name()).map_or(true, |x| *x <= now)
+                                })
+                                .cloned()
+                                .collect();
+                            // the ones left out are due again on the next tick
+                            let due = within_budget(&budget, due, &url);
+                            if due.is_empty() {
+                                continue;
+                            }
+                            for p in due.iter() {
+                                let idle = proxy_manager
+                                    .last_used(p.name())
+                                    .map(|x| now.duration_since(x));
+                                next_check.insert(
+                                    p.name().to_owned(),
+                                    now + adaptive_period(period, idle),
+                                );
+                            }
+                            debug!(
+                                "healthcheck probing {} of {} proxies: {}",
+                                due.len(),
+                                proxies.len(),
+                                url
+                            );
+                            proxy_manager.check(&due, &url, None).await;
+                            inner.write().await.last_check = now;
+                        } else if !lazy || now.duration_since(last_check).as_secs() >= interval {
+                            let proxies = within_budget(&budget, proxies.clone(), &url);
                             proxy_manager.check(&proxies, &url, None).await;
                             let mut w = inner.write().await;
                             w.last_check = now;
@@ -82,7 +231,24 @@ impl HealthCheck {
     }
 
     pub async fn touch(&self) {
-        self.inner.write().await.last_check = tokio::time::Instant::now();
+        let now = tokio::time::Instant::now();
+        let mut w = self.inner.write().await;
+        let paused = self.auto()
+            && self
+                .idle_timeout
+                .is_some_and(|x| now.duration_since(w.last_touch) >= x);
+        w.last_check = now;
+        w.last_touch = now;
+
+        if paused {
+            debug!("healthcheck resumed on use: {}", self.url);
+            let proxies = within_budget(&self.budget, w.proxies.clone(), &self.url);
+            let proxy_manager = self.proxy_manager.clone();
+            let url = self.url.clone();
+            tokio::spawn(async move {
+                proxy_manager.check(&proxies, &url, None).await;
+            });
+        }
     }
 
     pub async fn check(&self) {
@@ -98,3 +264,52 @@ impl HealthCheck {
         self.interval != 0
     }
 }
+
+/// The time until the next probe of a proxy that carried its last connection
+/// `idle` ago, `None` if it never did.
+fn adaptive_period(interval: Duration, idle: Option<Duration>) -> Duration {
+    let max = interval * MAX_BACKOFF;
+    let period = idle.map_or(max, |x| x.clamp(interval, max));
+    period.mul_f64(rand::thread_rng().gen_range(1.0 - JITTER..=1.0 + JITTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{adaptive_period, CheckBudget};
+
+    #[test]
+    fn test_check_budget() {
+        let mut budget = CheckBudget::new(360);
+        let start = budget.last_refill;
+
+        assert_eq!(budget.take(300, start), 300);
+        assert_eq!(budget.take(300, start), 60);
+        assert_eq!(budget.take(300, start), 0);
+        // one every 10 seconds
+        assert_eq!(budget.take(300, start + Duration::from_secs(35)), 3);
+        // up to an hour's worth
+        assert_eq!(budget.take(1000, start + Duration::from_secs(7200)), 360);
+    }
+
+    #[test]
+    fn test_adaptive_period() {
+        let interval = Duration::from_secs(100);
+        let within = |period: Duration, secs: u64| {
+            let d = Duration::from_secs(secs);
+            period >= d.mul_f64(0.9) && period <= d.mul_f64(1.1)
+        };
+
+        assert!(within(adaptive_period(interval, Some(Duration::ZERO)), 100));
+        assert!(within(
+            adaptive_period(interval, Some(Duration::from_secs(300))),
+            300
+        ));
+        assert!(within(
+            adaptive_period(interval, Some(Duration::from_secs(10000))),
+            800
+        ));
+        assert!(within(adaptive_period(interval, None), 800));
+    }
+}
@@ -2,7 +2,7 @@ use std::{
     collections::{HashMap, VecDeque},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::Duration,
 };
@@ -12,11 +12,17 @@ use chrono::{DateTime, Utc};
 use futures::{stream::FuturesUnordered, StreamExt};
 use hyper::Request;
 use serde::Serialize;
-use tokio::sync::RwLock;
+use tokio::{
+    sync::{RwLock, Semaphore},
+    time::Instant,
+};
 use tracing::{debug, instrument, trace};
 
 use crate::{
-    common::{errors::new_io_error, timed_future::TimedFuture},
+    common::{
+        errors::{new_io_error, DialError, DialStage},
+        timed_future::TimedFuture,
+    },
     proxy::AnyOutboundHandler,
 };
 
@@ -28,6 +34,52 @@ pub mod healthcheck;
 mod http_client;
 pub mod providers;
 
+/// the background health checks of all groups and providers share this many
+/// probes in flight, so large subscriptions don't flood the uplink
+const MAX_CONCURRENT_CHECKS: usize = 16;
+/// a delay test requested again within this is answered with the result of
+/// the previous one, dashboards tend to retrigger them
+const DELAY_TEST_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// the member switches kept for each group
+const MAX_SWITCH_HISTORY: usize = 100;
+
+/// A delay test in flight or done, shared by the identical ones requested
+/// within the cooldown.
+type SharedDelayTest = Arc<tokio::sync::OnceCell<Result<(u16, u16), (std::io::ErrorKind, String)>>>;
+/// the delay tests of a proxy and a url, with when they started
+type RecentTests = HashMap<(String, String), (Instant, SharedDelayTest)>;
+
+/// Why a fallback or url-test group moved on to another member.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum SwitchReason {
+    /// the member timed out
+    Timeout,
+    /// the member failed otherwise, refused, TLS or protocol errors
+    HandshakeError,
+    /// another member was faster by more than the tolerance
+    ToleranceExceeded,
+    /// a member preferred by the group is alive again
+    Recovered,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupSwitch {
+    time: DateTime<Utc>,
+    from: String,
+    to: String,
+    reason: SwitchReason,
+}
+
+/// The member switches of a group, counted since the start and the latest
+/// ones.
+#[derive(Serialize, Clone, Default)]
+pub struct SwitchHistory {
+    counts: HashMap<SwitchReason, u64>,
+    history: VecDeque<GroupSwitch>,
+}
+
 #[derive(Clone, Serialize)]
 pub struct DelayHistory {
     time: DateTime<Utc>,
@@ -46,6 +98,15 @@ struct ProxyState {
 #[derive(Clone)]
 pub struct ProxyManager {
     proxy_state: Arc<RwLock<HashMap<String, ProxyState>>>,
+    /// when the proxies last carried a connection
+    last_used: Arc<Mutex<HashMap<String, Instant>>>,
+    check_budget: Arc<Semaphore>,
+    /// why the last dial through the proxies failed
+    last_error: Arc<Mutex<HashMap<String, DialError>>>,
+    /// keyed by the proxy and the url
+    recent_tests: Arc<Mutex<RecentTests>>,
+    /// keyed by the group
+    switches: Arc<Mutex<HashMap<String, SwitchHistory>>>,
     dns_resolver: ThreadSafeDNSResolver,
 
     connector_map: Arc<RwLock<HashMap<String, hyper_rustls::HttpsConnector<LocalConnector>>>>,
@@ -56,6 +117,11 @@ impl ProxyManager {
         Self {
             dns_resolver,
             proxy_state: Arc::new(RwLock::new(HashMap::new())),
+            last_used: Arc::new(Mutex::new(HashMap::new())),
+            check_budget: Arc::new(Semaphore::new(MAX_CONCURRENT_CHECKS)),
+            last_error: Default::default(),
+            recent_tests: Default::default(),
+            switches: Default::default(),
             connector_map: Arc::new(RwLock::new(HashMap::new())),
         }
     }
@@ -72,6 +138,7 @@ impl ProxyManager {
             let url = url.to_owned();
             let manager = self.clone();
             futs.push(tokio::spawn(async move {
+                let _permit = manager.check_budget.acquire().await;
                 manager
                     .url_test(proxy, url.as_str(), timeout)
                     .await
@@ -83,6 +150,67 @@ impl ProxyManager {
         let _: Vec<_> = futs.collect().await;
     }
 
+    /// The proxy connected, so its last failure is over.
+    pub fn report_used(&self, name: &str) {
+        self.last_error.lock().unwrap().remove(name);
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock().unwrap();
+        match last_used.get_mut(name) {
+            Some(x) => *x = now,
+            None => {
+                last_used.insert(name.to_owned(), now);
+            }
+        }
+    }
+
+    pub fn last_used(&self, name: &str) -> Option<Instant> {
+        self.last_used.lock().unwrap().get(name).copied()
+    }
+
+    pub fn report_dial_error(&self, name: &str, err: DialError) {
+        self.last_error.lock().unwrap().insert(name.to_owned(), err);
+    }
+
+    pub fn last_dial_error(&self, name: &str) -> Option<DialError> {
+        self.last_error.lock().unwrap().get(name).cloned()
+    }
+
+    /// Why the proxy is down, going by its last failed dial.
+    pub fn failure_reason(&self, name: &str) -> SwitchReason {
+        match self.last_dial_error(name).map(|x| x.stage) {
+            Some(DialStage::Timeout | DialStage::TcpTimeout) => SwitchReason::Timeout,
+            _ => SwitchReason::HandshakeError,
+        }
+    }
+
+    pub fn report_switch(&self, group: &str, from: &str, to: &str, reason: SwitchReason) {
+        debug!(
+            "`{}` switched from `{}` to `{}`: {:?}",
+            group, from, to, reason
+        );
+        let mut switches = self.switches.lock().unwrap();
+        let switches = switches.entry(group.to_owned()).or_default();
+        *switches.counts.entry(reason).or_default() += 1;
+        switches.history.push_back(GroupSwitch {
+            time: Utc::now(),
+            from: from.to_owned(),
+            to: to.to_owned(),
+            reason,
+        });
+        if switches.history.len() > MAX_SWITCH_HISTORY {
+            switches.history.pop_front();
+        }
+    }
+
+    pub fn switch_history(&self, group: &str) -> SwitchHistory {
+        self.switches
+            .lock()
+            .unwrap()
+            .get(group)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub async fn alive(&self, name: &str) -> bool {
         self.proxy_state
             .read()
@@ -120,6 +248,36 @@ impl ProxyManager {
             .unwrap_or(max)
     }
 
+    /// [`Self::url_test`] for the tests requested by the users, an identical
+    /// test within [`DELAY_TEST_COOLDOWN`] gets the result of the previous
+    /// one, waiting for it if it's still running.
+    pub async fn url_test_debounced(
+        &self,
+        proxy: AnyOutboundHandler,
+        url: &str,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16)> {
+        let test = {
+            let now = Instant::now();
+            let mut recent = self.recent_tests.lock().unwrap();
+            recent.retain(|_, (start, _)| now.duration_since(*start) < DELAY_TEST_COOLDOWN);
+            recent
+                .entry((proxy.name().to_owned(), url.to_owned()))
+                .or_insert_with(|| (now, Default::default()))
+                .1
+                .clone()
+        };
+
+        test.get_or_init(|| async {
+            self.url_test(proxy, url, timeout)
+                .await
+                .map_err(|e| (e.kind(), e.to_string()))
+        })
+        .await
+        .clone()
+        .map_err(|(kind, e)| std::io::Error::new(kind, e))
+    }
+
     #[instrument(skip(self, proxy))]
     pub async fn url_test(
         &self,
@@ -179,14 +337,20 @@ impl ProxyManager {
                                 res.status(),
                                 delay
                             );
+                            self.last_error.lock().unwrap().remove(&name);
                             Ok(delay)
                         }
                         Err(e) => {
                             debug!("urltest for proxy {} with url {} failed: {}", &name, url, e);
+                            self.report_dial_error(&name, DialError::new(&e));
                             Err(new_io_error(format!("{}: {}", url, e).as_str()))
                         }
                     },
-                    Err(_) => Err(new_io_error(format!("timeout for {}", url).as_str())),
+                    Err(_) => {
+                        let e = new_io_error(format!("timeout for {}", url).as_str());
+                        self.report_dial_error(&name, DialError::new(&e));
+                        Err(e)
+                    }
                 }?;
 
             let req2 = Request::get(url)
@@ -240,10 +404,13 @@ mod tests {
 
     use crate::{
         app::{dispatcher::ChainedStreamWrapper, dns::MockClashResolver, remote_content_manager},
+        common::errors::DialError,
         config::internal::proxy::PROXY_DIRECT,
         proxy::{direct, mocks::MockDummyOutboundHandler},
     };
 
+    use super::{SwitchReason, MAX_SWITCH_HISTORY};
+
     #[tokio::test]
     async fn test_proxy_manager_alive() {
         let mut mock_resolver = MockClashResolver::new();
@@ -251,6 +418,7 @@ mod tests {
             .expect_resolve()
             .returning(|_, _| Ok(Some(std::net::IpAddr::V4(Ipv4Addr::new(172, 217, 167, 67)))));
         mock_resolver.expect_ipv6().return_const(false);
+        mock_resolver.expect_nat64().return_const(None);
 
         let manager = remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
 
@@ -288,6 +456,32 @@ mod tests {
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 10);
     }
 
+    #[test]
+    fn test_switch_history() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(MockClashResolver::new()));
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        manager.report_dial_error("a", DialError::new(&timeout));
+        assert_eq!(manager.failure_reason("a"), SwitchReason::Timeout);
+        assert_eq!(manager.failure_reason("b"), SwitchReason::HandshakeError);
+        // cleared once the proxy connects again
+        manager.report_used("a");
+        assert!(manager.last_dial_error("a").is_none());
+
+        for _ in 0..=MAX_SWITCH_HISTORY {
+            manager.report_switch("g", "a", "b", SwitchReason::Timeout);
+        }
+        manager.report_switch("g", "b", "a", SwitchReason::Recovered);
+        let history = manager.switch_history("g");
+        assert_eq!(
+            history.counts[&SwitchReason::Timeout],
+            MAX_SWITCH_HISTORY as u64 + 1
+        );
+        assert_eq!(history.counts[&SwitchReason::Recovered], 1);
+        assert_eq!(history.history.len(), MAX_SWITCH_HISTORY);
+        assert_eq!(history.history.back().unwrap().to, "a");
+        assert!(manager.switch_history("other").history.is_empty());
+    }
+
     #[tokio::test]
     async fn test_proxy_manager_timeout() {
         let mut mock_resolver = MockClashResolver::new();
@@ -325,4 +519,37 @@ mod tests {
         assert!(manager.last_delay(PROXY_DIRECT).await == u16::MAX);
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 1);
     }
+
+    #[tokio::test]
+    async fn test_proxy_manager_debounce() {
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some(std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))));
+
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler
+            .expect_name()
+            .return_const(PROXY_DIRECT.to_owned());
+        mock_handler
+            .expect_connect_stream()
+            .times(1)
+            .returning(|_, _| Err(std::io::ErrorKind::ConnectionRefused.into()));
+
+        let mock_handler = Arc::new(mock_handler);
+
+        for _ in 0..3 {
+            let result = manager
+                .url_test_debounced(
+                    mock_handler.clone(),
+                    "http://www.gstatic.com/generate_204",
+                    Some(Duration::from_secs(3)),
+                )
+                .await;
+            assert!(result.is_err());
+        }
+        assert!(manager.delay_history(PROXY_DIRECT).await.len() == 1);
+    }
 }
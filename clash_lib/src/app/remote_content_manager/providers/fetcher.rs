@@ -10,7 +10,10 @@ use futures::future::BoxFuture;
 use tokio::sync::{Mutex, RwLock};
 use tracing::{debug, info, trace, warn};
 
-use crate::common::utils;
+use crate::{
+    app::events::{self, EventKind},
+    common::utils,
+};
 
 use super::{ProviderVehicleType, ThreadSafeProviderVehicle};
 
@@ -133,12 +136,16 @@ where
     }
 
     pub async fn update(&self) -> anyhow::Result<(T, bool)> {
-        Fetcher::<U, P>::update_inner(
+        let (elm, same) = Fetcher::<U, P>::update_inner(
             self.inner.clone(),
             self.vehicle.clone(),
             self.parser.clone(),
         )
-        .await
+        .await?;
+        if !same {
+            publish_updated(&self.name);
+        }
+        Ok((elm, same))
     }
 
     async fn update_inner(
@@ -215,6 +222,7 @@ where
                         trace!("fetcher {} no update", &name);
                         return;
                     }
+                    publish_updated(&name);
 
                     if let Some(on_update) = on_update {
                         info!("fetcher {} updated", &name);
@@ -236,6 +244,13 @@ where
     }
 }
 
+fn publish_updated(name: &str) {
+    events::publish(
+        EventKind::ProviderUpdated,
+        serde_json::json!({ "name": name }),
+    );
+}
+
 #[cfg(test)]
 mod tests {
     use std::{path::Path, sync::Arc, time::Duration};
@@ -1,6 +1,7 @@
 pub mod plain_provider;
 
 pub mod proxy_set_provider;
+pub mod sip008;
 
 pub use plain_provider::PlainProvider;
 pub use proxy_set_provider::ProxySetProvider;
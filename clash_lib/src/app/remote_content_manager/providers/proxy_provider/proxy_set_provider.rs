@@ -5,10 +5,11 @@ use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use super::ProxyProvider;
+use super::{sip008, ProxyProvider};
 use crate::{
+    app::outbound::registry,
     app::remote_content_manager::{
         healthcheck::HealthCheck,
         providers::{fetcher::Fetcher, ThreadSafeProviderVehicle},
@@ -16,7 +17,7 @@ use crate::{
     },
     common::errors::map_io_error,
     config::internal::proxy::OutboundProxyProtocol,
-    proxy::{direct, reject, AnyOutboundHandler},
+    proxy::{direct, reject, shadowsocks::is_native_plugin, uot, AnyOutboundHandler},
     Error,
 };
 
@@ -24,6 +25,8 @@ use crate::{
 struct ProviderScheme {
     #[serde(rename = "proxies")]
     proxies: Option<Vec<HashMap<String, Value>>>,
+    /// the shadowsocks servers of a SIP008 document instead
+    servers: Option<Vec<sip008::Server>>,
 }
 
 struct Inner {
@@ -90,21 +93,54 @@ impl ProxySetProvider {
                 let scheme: ProviderScheme = serde_yaml::from_slice(input).map_err(|x| {
                     Error::InvalidConfig(format!("proxy provider parse error {}: {}", n, x))
                 })?;
-                let proxies = scheme.proxies;
+                let proxies = scheme.proxies.or_else(|| {
+                    scheme.servers.map(|x| {
+                        x.into_iter()
+                            .filter_map(sip008::Server::into_proxy)
+                            .collect()
+                    })
+                });
                 if let Some(proxies) = proxies {
                     let proxies = proxies
                         .into_iter()
                         .filter_map(|x| OutboundProxyProtocol::try_from(x).ok())
+                        .filter(|x| match x {
+                            // the binaries are only run from the config file
+                            OutboundProxyProtocol::Ss(s)
+                                if s.plugin.as_deref().is_some_and(|x| !is_native_plugin(x)) =>
+                            {
+                                warn!(
+                                    "{}: sip003 plugin {} of {} is not allowed in a provider",
+                                    n,
+                                    s.plugin.as_deref().unwrap_or_default(),
+                                    s.name
+                                );
+                                false
+                            }
+                            _ => true,
+                        })
                         .map(|x| match x {
                             OutboundProxyProtocol::Direct => Ok(direct::Handler::new()),
                             OutboundProxyProtocol::Reject => Ok(reject::Handler::new()),
-                            OutboundProxyProtocol::Ss(s) => s.try_into(),
-                            OutboundProxyProtocol::Socks5(_) => todo!("socks5 not supported yet"),
+                            OutboundProxyProtocol::DirectWith(d) => d.try_into(),
+                            OutboundProxyProtocol::Ss(s) => {
+                                let enable_uot = s.uot;
+                                s.try_into().map(|h| uot::Handler::wrap_if(h, enable_uot))
+                            }
+                            OutboundProxyProtocol::Socks5(s) => {
+                                let enable_uot = s.uot;
+                                s.try_into().map(|h| uot::Handler::wrap_if(h, enable_uot))
+                            }
                             OutboundProxyProtocol::Trojan(tr) => tr.try_into(),
                             OutboundProxyProtocol::Vmess(vm) => vm.try_into(),
+                            OutboundProxyProtocol::Vless(vl) => vl.try_into(),
                             OutboundProxyProtocol::Wireguard(wg) => wg.try_into(),
                             OutboundProxyProtocol::Tor(tor) => tor.try_into(),
+                            OutboundProxyProtocol::Ssh(ssh) => ssh.try_into(),
                             OutboundProxyProtocol::Tuic(tuic) => tuic.try_into(),
+                            OutboundProxyProtocol::Masque(masque) => masque.try_into(),
+                            OutboundProxyProtocol::Hysteria2(hysteria2) => hysteria2.try_into(),
+                            OutboundProxyProtocol::Plugin(p) => registry::create(&p),
                         })
                         .collect::<Result<Vec<_>, _>>();
                     Ok(proxies?)
@@ -225,6 +261,13 @@ proxies:
     cipher: aes-256-gcm
     password: "password"
     udp: true
+  - name: "ss-plugin"
+    type: ss
+    server: localhost
+    port: 8388
+    cipher: aes-256-gcm
+    password: "password"
+    plugin: /bin/sh
 "#
             .as_bytes()
             .to_vec())
@@ -259,6 +302,7 @@ proxies:
 
         sleep(Duration::from_secs_f64(1.5)).await;
 
+        // the sip003 plugin is dropped
         assert_eq!(provider.proxies().await.len(), 1);
     }
 }
@@ -0,0 +1,168 @@
+//! SIP008 online configs, the server lists some shadowsocks providers
+//! distribute instead of the proxies of clash.
+//! https://shadowsocks.org/doc/sip008.html
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use tracing::warn;
+
+use crate::proxy::shadowsocks::decode_plugin_options;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Server {
+    pub remarks: Option<String>,
+    pub server: String,
+    pub server_port: u16,
+    pub password: String,
+    pub method: String,
+    pub plugin: Option<String>,
+    pub plugin_opts: Option<String>,
+}
+
+impl Server {
+    /// The server as a proxy of the `ss` type, none if it needs a plugin
+    /// binary, which are not run for the remote configs.
+    pub fn into_proxy(self) -> Option<HashMap<String, Value>> {
+        let name = self
+            .remarks
+            .filter(|x| !x.is_empty())
+            .unwrap_or_else(|| format!("{}:{}", self.server, self.server_port));
+
+        let mut proxy = HashMap::new();
+        proxy.insert("name".to_owned(), Value::from(name.as_str()));
+        proxy.insert("type".to_owned(), Value::from("ss"));
+        proxy.insert("server".to_owned(), Value::from(self.server));
+        proxy.insert("port".to_owned(), Value::from(self.server_port));
+        proxy.insert("cipher".to_owned(), Value::from(self.method));
+        proxy.insert("password".to_owned(), Value::from(self.password));
+
+        if let Some(plugin) = self.plugin.filter(|x| !x.is_empty()) {
+            let opts = decode_plugin_options(self.plugin_opts.as_deref().unwrap_or_default());
+            let Some((plugin, opts)) = native_plugin(&plugin, opts) else {
+                warn!("sip008 server {} needs the plugin binary {}", name, plugin);
+                return None;
+            };
+            proxy.insert("plugin".to_owned(), Value::from(plugin));
+            proxy.insert(
+                "plugin-opts".to_owned(),
+                Value::Mapping(opts.into_iter().map(|(k, v)| (k.into(), v)).collect()),
+            );
+        }
+        Some(proxy)
+    }
+}
+
+/// The plugins done natively, with their options renamed to those of clash.
+fn native_plugin(
+    plugin: &str,
+    mut opts: HashMap<String, Value>,
+) -> Option<(String, HashMap<String, Value>)> {
+    let mut native = HashMap::new();
+    match plugin {
+        "obfs-local" | "simple-obfs" => {
+            native.insert(
+                "mode".to_owned(),
+                opts.remove("obfs").unwrap_or("http".into()),
+            );
+            if let Some(host) = opts.remove("obfs-host") {
+                native.insert("host".to_owned(), host);
+            }
+            Some(("obfs".to_owned(), native))
+        }
+        "v2ray-plugin" => {
+            if opts
+                .get("mode")
+                .is_some_and(|x| x.as_str() != Some("websocket"))
+            {
+                // quic is only done by the binary
+                return None;
+            }
+            native.insert("mode".to_owned(), "websocket".into());
+            native.insert("path".to_owned(), opts.remove("path").unwrap_or("/".into()));
+            native.insert("tls".to_owned(), opts.remove("tls").unwrap_or(false.into()));
+            if let Some(host) = opts.remove("host") {
+                native.insert("host".to_owned(), host);
+            }
+            Some(("v2ray-plugin".to_owned(), native))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use crate::config::internal::proxy::OutboundProxyProtocol;
+
+    use super::Server;
+
+    #[test]
+    fn test_sip008_servers() {
+        let doc = r#"{
+            "version": 1,
+            "servers": [
+                {
+                    "id": "27b8a625-4f4b-4428-9f0f-8a2317db7c79",
+                    "remarks": "Name of the server",
+                    "server": "example.com",
+                    "server_port": 8388,
+                    "password": "example",
+                    "method": "chacha20-ietf-poly1305",
+                    "plugin": "obfs-local",
+                    "plugin_opts": "obfs=http;obfs-host=www.example.com"
+                },
+                {
+                    "server": "example.org",
+                    "server_port": 443,
+                    "password": "example",
+                    "method": "aes-256-gcm",
+                    "plugin": "v2ray-plugin",
+                    "plugin_opts": "tls;host=example.org"
+                },
+                {
+                    "server": "example.net",
+                    "server_port": 443,
+                    "password": "example",
+                    "method": "aes-256-gcm",
+                    "plugin": "/tmp/plugin",
+                    "plugin_opts": "mode=quic"
+                }
+            ],
+            "bytes_used": 274877906944
+        }"#;
+
+        #[derive(serde::Deserialize)]
+        struct Doc {
+            servers: Vec<Server>,
+        }
+        let doc: Doc = serde_yaml::from_str(doc).unwrap();
+        let proxies = doc
+            .servers
+            .into_iter()
+            .filter_map(Server::into_proxy)
+            .collect::<Vec<_>>();
+
+        // no binary is run for a remote config
+        assert_eq!(proxies.len(), 2);
+
+        assert_eq!(proxies[0]["name"], Value::from("Name of the server"));
+        assert_eq!(proxies[0]["plugin"], Value::from("obfs"));
+        assert_eq!(
+            proxies[0]["plugin-opts"]["host"],
+            Value::from("www.example.com")
+        );
+        assert_eq!(proxies[1]["name"], Value::from("example.org:443"));
+        assert_eq!(proxies[1]["plugin-opts"]["tls"], Value::from(true));
+        assert_eq!(proxies[1]["plugin-opts"]["path"], Value::from("/"));
+
+        for proxy in proxies {
+            assert!(matches!(
+                OutboundProxyProtocol::try_from(proxy),
+                Ok(OutboundProxyProtocol::Ss(_))
+            ));
+        }
+    }
+}
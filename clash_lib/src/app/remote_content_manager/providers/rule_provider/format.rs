@@ -0,0 +1,303 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::internal::rule::RuleType, Error};
+
+use super::RuleSetBehavior;
+
+/// magic header of the binary rule-set format, followed by a version byte
+const BINARY_MAGIC: &[u8; 4] = b"CRS\x01";
+
+#[derive(Serialize, Deserialize)]
+struct ProviderScheme {
+    payload: Vec<String>,
+}
+
+/// The on-disk format of a rule-set
+///
+/// - yaml: `payload:` list, the format used by clash
+/// - text: one rule per line, `#` starts a comment
+/// - binary: a compact length prefixed encoding that also records the behavior
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetFormat {
+    Yaml,
+    Text,
+    Binary,
+}
+
+impl Display for RuleSetFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleSetFormat::Yaml => write!(f, "yaml"),
+            RuleSetFormat::Text => write!(f, "text"),
+            RuleSetFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl FromStr for RuleSetFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(RuleSetFormat::Yaml),
+            "text" | "txt" | "list" => Ok(RuleSetFormat::Text),
+            "binary" | "bin" => Ok(RuleSetFormat::Binary),
+            _ => Err(Error::InvalidConfig(format!(
+                "unsupported rule-set format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl FromStr for RuleSetBehavior {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "domain" => Ok(RuleSetBehavior::Domain),
+            "ipcidr" => Ok(RuleSetBehavior::Ipcidr),
+            "classical" => Ok(RuleSetBehavior::Classical),
+            _ => Err(Error::InvalidConfig(format!(
+                "unsupported rule-set behavior: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Guess the format of the rule-set content.
+pub fn detect_format(input: &[u8]) -> RuleSetFormat {
+    if input.starts_with(BINARY_MAGIC) {
+        RuleSetFormat::Binary
+    } else if serde_yaml::from_slice::<ProviderScheme>(input).is_ok() {
+        RuleSetFormat::Yaml
+    } else {
+        RuleSetFormat::Text
+    }
+}
+
+/// Decode the rule-set content into rule lines,
+/// binary content also carries the behavior it was encoded with.
+pub fn decode(
+    input: &[u8],
+    format: RuleSetFormat,
+) -> Result<(Vec<String>, Option<RuleSetBehavior>), Error> {
+    match format {
+        RuleSetFormat::Yaml => {
+            let scheme: ProviderScheme = serde_yaml::from_slice(input)
+                .map_err(|x| Error::InvalidConfig(format!("invalid yaml rule-set: {}", x)))?;
+            Ok((scheme.payload, None))
+        }
+        RuleSetFormat::Text => {
+            let text = std::str::from_utf8(input)
+                .map_err(|x| Error::InvalidConfig(format!("invalid text rule-set: {}", x)))?;
+            Ok((
+                text.lines()
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                None,
+            ))
+        }
+        RuleSetFormat::Binary => decode_binary(input).map(|(rules, b)| (rules, Some(b))),
+    }
+}
+
+pub fn encode(
+    rules: &[String],
+    behavior: RuleSetBehavior,
+    format: RuleSetFormat,
+) -> Result<Vec<u8>, Error> {
+    match format {
+        RuleSetFormat::Yaml => serde_yaml::to_string(&ProviderScheme {
+            payload: rules.to_vec(),
+        })
+        .map(String::into_bytes)
+        .map_err(|x| Error::InvalidConfig(format!("failed to encode rule-set: {}", x))),
+        RuleSetFormat::Text => {
+            let mut out = rules.join("\n");
+            out.push('\n');
+            Ok(out.into_bytes())
+        }
+        RuleSetFormat::Binary => encode_binary(rules, behavior),
+    }
+}
+
+/// Convert the rule-set content to another format, validating the rules.
+/// The behavior could be omitted for binary input as it's recorded there.
+/// Returns the converted content and the number of rules.
+pub fn convert(
+    input: &[u8],
+    from: Option<RuleSetFormat>,
+    to: RuleSetFormat,
+    behavior: Option<RuleSetBehavior>,
+) -> Result<(Vec<u8>, usize), Error> {
+    let from = from.unwrap_or_else(|| detect_format(input));
+    let (rules, encoded_behavior) = decode(input, from)?;
+
+    let behavior = match (behavior, encoded_behavior) {
+        (Some(b), Some(e)) if b != e => {
+            return Err(Error::InvalidConfig(format!(
+                "behavior {} doesn't match the rule-set behavior {}",
+                b, e
+            )))
+        }
+        (Some(b), _) | (None, Some(b)) => b,
+        (None, None) => {
+            return Err(Error::InvalidConfig(
+                "behavior is required for non binary rule-set".to_owned(),
+            ))
+        }
+    };
+
+    validate(&rules, behavior)?;
+    Ok((encode(&rules, behavior, to)?, rules.len()))
+}
+
+/// Check every rule is valid for the behavior.
+pub fn validate(rules: &[String], behavior: RuleSetBehavior) -> Result<(), Error> {
+    for rule in rules {
+        let valid = match behavior {
+            RuleSetBehavior::Domain => !rule.is_empty() && !rule.contains([',', ' ']),
+            RuleSetBehavior::Ipcidr => rule.parse::<ipnet::IpNet>().is_ok(),
+            RuleSetBehavior::Classical => parse_classical_rule(rule).is_ok(),
+        };
+        if !valid {
+            return Err(Error::InvalidConfig(format!(
+                "invalid rule for behavior {}: {}",
+                behavior, rule
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The rule inside RULE-SET is slightly different from the rule in config,
+/// the target is always empty as it's held by the RULE-SET container.
+pub(super) fn parse_classical_rule(rule: &str) -> Result<RuleType, Error> {
+    let mut parsed = RuleType::new_nested(rule, "")?;
+    // the shortcuts of `script` are out of reach of the providers, a SCRIPT
+    // rule would never match
+    parsed.bind_scripts(&HashMap::new()).map_err(|_| {
+        Error::InvalidConfig(format!("SCRIPT is not allowed in rule sets: {}", rule))
+    })?;
+    Ok(parsed)
+}
+
+fn encode_binary(rules: &[String], behavior: RuleSetBehavior) -> Result<Vec<u8>, Error> {
+    let mut out = BINARY_MAGIC.to_vec();
+    out.push(match behavior {
+        RuleSetBehavior::Domain => 0,
+        RuleSetBehavior::Ipcidr => 1,
+        RuleSetBehavior::Classical => 2,
+    });
+    out.extend_from_slice(&(rules.len() as u32).to_be_bytes());
+    for rule in rules {
+        let len = u16::try_from(rule.len())
+            .map_err(|_| Error::InvalidConfig(format!("rule too long: {}", rule)))?;
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(rule.as_bytes());
+    }
+    Ok(out)
+}
+
+fn decode_binary(input: &[u8]) -> Result<(Vec<String>, RuleSetBehavior), Error> {
+    let invalid = || Error::InvalidConfig("truncated binary rule-set".to_owned());
+
+    let mut buf = input.strip_prefix(BINARY_MAGIC).ok_or_else(|| {
+        Error::InvalidConfig("not a binary rule-set or unsupported version".to_owned())
+    })?;
+
+    let (behavior, rest) = buf.split_first().ok_or_else(invalid)?;
+    let behavior = match behavior {
+        0 => RuleSetBehavior::Domain,
+        1 => RuleSetBehavior::Ipcidr,
+        2 => RuleSetBehavior::Classical,
+        x => {
+            return Err(Error::InvalidConfig(format!(
+                "unknown rule-set behavior in binary rule-set: {}",
+                x
+            )))
+        }
+    };
+    buf = rest;
+
+    if buf.len() < 4 {
+        return Err(invalid());
+    }
+    let count = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    buf = &buf[4..];
+
+    let mut rules = Vec::with_capacity(count.min(buf.len() / 2));
+    for _ in 0..count {
+        if buf.len() < 2 {
+            return Err(invalid());
+        }
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        buf = &buf[2..];
+        if buf.len() < len {
+            return Err(invalid());
+        }
+        let rule = std::str::from_utf8(&buf[..len])
+            .map_err(|x| Error::InvalidConfig(format!("invalid binary rule-set: {}", x)))?;
+        rules.push(rule.to_owned());
+        buf = &buf[len..];
+    }
+
+    Ok((rules, behavior))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, detect_format, encode, validate, RuleSetFormat};
+    use crate::app::remote_content_manager::providers::rule_provider::RuleSetBehavior;
+
+    #[test]
+    fn test_detect_and_round_trip() {
+        let rules = vec!["google.com".to_owned(), "+.youtube.com".to_owned()];
+
+        for format in [
+            RuleSetFormat::Yaml,
+            RuleSetFormat::Text,
+            RuleSetFormat::Binary,
+        ] {
+            let encoded = encode(&rules, RuleSetBehavior::Domain, format).unwrap();
+            assert_eq!(detect_format(&encoded), format);
+            let (decoded, _) = decode(&encoded, format).unwrap();
+            assert_eq!(decoded, rules);
+        }
+    }
+
+    #[test]
+    fn test_text_comments() {
+        let (rules, behavior) = decode(
+            b"# comment\n\n1.1.1.1/32\n 10.0.0.0/8 \n",
+            RuleSetFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(rules, vec!["1.1.1.1/32", "10.0.0.0/8"]);
+        assert!(behavior.is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&["10.0.0.0/8".to_owned()], RuleSetBehavior::Ipcidr).is_ok());
+        assert!(validate(&["google.com".to_owned()], RuleSetBehavior::Ipcidr).is_err());
+        assert!(validate(
+            &["DOMAIN-SUFFIX,google.com".to_owned()],
+            RuleSetBehavior::Classical
+        )
+        .is_ok());
+        assert!(validate(&["FOO,google.com".to_owned()], RuleSetBehavior::Classical).is_err());
+        assert!(validate(
+            &["AND,((SCRIPT,quic),(NETWORK,UDP))".to_owned()],
+            RuleSetBehavior::Classical
+        )
+        .is_err());
+    }
+}
@@ -1,5 +1,6 @@
-mod cidr_trie;
+pub mod format;
 mod provider;
 
 pub use provider::ThreadSafeRuleProvider;
+pub use format::RuleSetFormat;
 pub use provider::{RuleProviderImpl, RuleSetBehavior};
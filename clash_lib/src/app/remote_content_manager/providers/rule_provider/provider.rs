@@ -20,20 +20,14 @@ use crate::{
         },
         router::{map_rule_type, RuleMatcher},
     },
-    common::{errors::map_io_error, mmdb::Mmdb, trie},
-    config::internal::rule::RuleType,
+    common::{cidr_trie::CidrTrie, errors::map_io_error, geosite::Geosite, mmdb::Mmdb, trie},
     session::Session,
     Error,
 };
 
-use super::cidr_trie::CidrTrie;
+use super::format::{self, RuleSetFormat};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ProviderScheme {
-    pub payload: Vec<String>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum RuleSetBehavior {
     Domain,
@@ -53,7 +47,7 @@ impl Display for RuleSetBehavior {
 
 enum RuleContent {
     Domain(trie::StringTrie<bool>),
-    Ipcidr(Box<CidrTrie>),
+    Ipcidr(Box<CidrTrie<()>>),
     Classical(Vec<Box<dyn RuleMatcher>>),
 }
 
@@ -64,6 +58,10 @@ struct Inner {
 pub trait RuleProvider: Provider {
     fn search(&self, sess: &Session) -> bool;
     fn behavior(&self) -> RuleSetBehavior;
+    /// any rule of the set matches on the resolved address of a domain
+    fn should_resolve_ip(&self) -> bool;
+    /// any rule of the set matches on the process of the session
+    fn should_find_process(&self) -> bool;
 }
 
 pub type ThreadSafeRuleProvider = Arc<dyn RuleProvider + Send + Sync>;
@@ -81,9 +79,11 @@ impl RuleProviderImpl {
     pub fn new(
         name: String,
         behovior: RuleSetBehavior,
+        format: Option<RuleSetFormat>,
         interval: Duration,
         vehicle: ThreadSafeProviderVehicle,
         mmdb: Arc<Mmdb>,
+        geosite: Arc<Geosite>,
     ) -> Self {
         let inner = Arc::new(tokio::sync::RwLock::new(Inner {
             content: match behovior {
@@ -108,10 +108,20 @@ impl RuleProviderImpl {
 
         let n = name.clone();
         let parser: RuleParser = Box::new(move |input: &[u8]| -> anyhow::Result<RuleContent> {
-            let scheme: ProviderScheme = serde_yaml::from_slice(input).map_err(|x| {
-                Error::InvalidConfig(format!("proxy provider parse error {}: {}", n, x))
+            let format = format.unwrap_or_else(|| format::detect_format(input));
+            let (payload, encoded_behavior) = format::decode(input, format).map_err(|x| {
+                Error::InvalidConfig(format!("rule provider parse error {}: {}", n, x))
             })?;
-            let rules = make_rules(behovior, scheme.payload, mmdb.clone())?;
+            if let Some(b) = encoded_behavior {
+                if b != behovior {
+                    return Err(Error::InvalidConfig(format!(
+                        "rule provider {} is {}, but the content is {}",
+                        n, behovior, b
+                    ))
+                    .into());
+                }
+            }
+            let rules = make_rules(behovior, payload, mmdb.clone(), geosite.clone())?;
             Ok(rules)
         });
 
@@ -134,8 +144,7 @@ impl RuleProvider for RuleProviderImpl {
             Ok(inner) => match &inner.content {
                 RuleContent::Domain(trie) => trie.search(&sess.destination.host()).is_some(),
                 RuleContent::Ipcidr(trie) => trie.contains(
-                    sess.destination
-                        .ip()
+                    sess.destination_ip()
                         .unwrap_or(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
                 ),
                 RuleContent::Classical(rules) => {
@@ -156,6 +165,35 @@ impl RuleProvider for RuleProviderImpl {
     fn behavior(&self) -> RuleSetBehavior {
         self.behavior
     }
+
+    fn should_resolve_ip(&self) -> bool {
+        match self.behavior {
+            RuleSetBehavior::Domain => false,
+            RuleSetBehavior::Ipcidr => true,
+            RuleSetBehavior::Classical => self.any_classical(|r| r.should_resolve_ip()),
+        }
+    }
+
+    fn should_find_process(&self) -> bool {
+        match self.behavior {
+            RuleSetBehavior::Classical => self.any_classical(|r| r.should_find_process()),
+            _ => false,
+        }
+    }
+}
+
+impl RuleProviderImpl {
+    /// Whether any of the classical rules is so, or maybe if they are
+    /// being updated.
+    fn any_classical(&self, f: impl Fn(&Box<dyn RuleMatcher>) -> bool) -> bool {
+        match self.inner.try_read() {
+            Ok(inner) => match &inner.content {
+                RuleContent::Classical(rules) => rules.iter().any(f),
+                _ => false,
+            },
+            Err(_) => true,
+        }
+    }
 }
 
 #[async_trait]
@@ -214,13 +252,14 @@ fn make_rules(
     behavior: RuleSetBehavior,
     rules: Vec<String>,
     mmdb: Arc<Mmdb>,
+    geosite: Arc<Geosite>,
 ) -> Result<RuleContent, Error> {
     match behavior {
         RuleSetBehavior::Domain => Ok(RuleContent::Domain(make_domain_rules(rules)?)),
         RuleSetBehavior::Ipcidr => Ok(RuleContent::Ipcidr(Box::new(make_ip_cidr_rules(rules)?))),
-        RuleSetBehavior::Classical => {
-            Ok(RuleContent::Classical(make_classical_rules(rules, mmdb)?))
-        }
+        RuleSetBehavior::Classical => Ok(RuleContent::Classical(make_classical_rules(
+            rules, mmdb, geosite,
+        )?)),
     }
 }
 
@@ -232,10 +271,12 @@ fn make_domain_rules(rules: Vec<String>) -> Result<trie::StringTrie<bool>, Error
     Ok(trie)
 }
 
-fn make_ip_cidr_rules(rules: Vec<String>) -> Result<CidrTrie, Error> {
+fn make_ip_cidr_rules(rules: Vec<String>) -> Result<CidrTrie<()>, Error> {
     let mut trie = CidrTrie::new();
     for rule in rules {
-        trie.insert(&rule);
+        if let Ok(net) = rule.parse::<ipnet::IpNet>() {
+            trie.insert(net, ());
+        }
     }
     Ok(trie)
 }
@@ -243,23 +284,13 @@ fn make_ip_cidr_rules(rules: Vec<String>) -> Result<CidrTrie, Error> {
 fn make_classical_rules(
     rules: Vec<String>,
     mmdb: Arc<Mmdb>,
+    geosite: Arc<Geosite>,
 ) -> Result<Vec<Box<dyn RuleMatcher>>, Error> {
     let mut rv = vec![];
     for rule in rules {
-        let parts = rule.split(',').map(str::trim).collect::<Vec<&str>>();
-
-        // the rule inside RULE-SET is slightly different from the rule in config
-        // the target is always empty as it's holded in the RULE-SET container
-        // let's parse it manually
-        let rule_type = match parts.as_slice() {
-            [proto, payload] => RuleType::new(proto, payload, "", None),
-            [proto, payload, params @ ..] => {
-                RuleType::new(proto, payload, "", Some(params.to_vec()))
-            }
-            _ => Err(Error::InvalidConfig(format!("invalid rule line: {}", rule))),
-        }?;
+        let rule_type = format::parse_classical_rule(&rule)?;
 
-        let rule_matcher = map_rule_type(rule_type, mmdb.clone(), None);
+        let rule_matcher = map_rule_type(rule_type, mmdb.clone(), geosite.clone(), None, None);
         rv.push(rule_matcher);
     }
     Ok(rv)
@@ -1,11 +1,13 @@
 use crate::app::router::rules::domain::Domain;
 use crate::app::router::rules::domain_keyword::DomainKeyword;
 use crate::app::router::rules::domain_suffix::DomainSuffix;
-use crate::app::router::rules::ipcidr::IpCidr;
+use crate::app::router::rules::ipcidr::{IpCidr, IpCidrRun};
 use crate::app::router::rules::ruleset::RuleSet;
 use crate::Error;
 
+use crate::common::geosite::Geosite;
 use crate::common::mmdb::Mmdb;
+use crate::common::process;
 use crate::config::internal::config::RuleProviderDef;
 use crate::config::internal::rule::RuleType;
 use crate::session::{Session, SocksAddr};
@@ -25,26 +27,41 @@ use super::remote_content_manager::providers::rule_provider::{
 };
 use super::remote_content_manager::providers::{file_vehicle, http_vehicle};
 
+pub mod registry;
 mod rules;
-pub use rules::RuleMatcher;
+pub mod script;
+pub use rules::{schedule::Cron, RuleMatcher};
 
 pub struct Router {
     rules: Vec<Box<dyn RuleMatcher>>,
-    #[allow(dead_code)]
+    /// the rules of `listener-rules`, tried before `rules`
+    listener_rules: HashMap<String, Vec<Box<dyn RuleMatcher>>>,
+    /// the route hints of the resolver, one for each proxy, tried in place of
+    /// the final MATCH
+    hint_rules: Vec<Box<dyn RuleMatcher>>,
+    /// the runs of IP-CIDR rules, keyed by the index of their first rule
+    cidr_runs: HashMap<usize, IpCidrRun>,
     rule_provider_registry: HashMap<String, ThreadSafeRuleProvider>,
     dns_resolver: ThreadSafeDNSResolver,
 }
 
 pub type ThreadSafeRouter = Arc<Router>;
 
+/// the sets of `sub-rules` built, each shared by the rules jumping into it
+type SubRuleSets = HashMap<String, Arc<Vec<Box<dyn RuleMatcher>>>>;
+
 const MATCH: &str = "MATCH";
 
 impl Router {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         rules: Vec<RuleType>,
+        listener_rules: HashMap<String, Vec<RuleType>>,
+        mut sub_rules: HashMap<String, Vec<RuleType>>,
         rule_providers: HashMap<String, RuleProviderDef>,
         dns_resolver: ThreadSafeDNSResolver,
         mmdb: Arc<Mmdb>,
+        geosite: Arc<Geosite>,
         cwd: String,
     ) -> Self {
         let mut rule_provider_registry = HashMap::new();
@@ -54,29 +71,130 @@ impl Router {
             &mut rule_provider_registry,
             dns_resolver.clone(),
             mmdb.clone(),
+            geosite.clone(),
             cwd,
         )
         .await
         .ok();
 
-        Self {
-            rules: rules
+        if rules
+            .iter()
+            .chain(listener_rules.values().flatten())
+            .chain(sub_rules.values().flatten())
+            .any(uses_geosite)
+        {
+            if let Err(e) = geosite.ensure_downloaded().await {
+                error!("failed to load geosite: {}", e);
+            }
+        }
+
+        let cidr_runs = IpCidrRun::build(&rules);
+
+        let mut sets = HashMap::new();
+        let names = sub_rules.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            build_sub_rules(
+                &name,
+                &mut sub_rules,
+                &mut sets,
+                &mmdb,
+                &geosite,
+                &rule_provider_registry,
+            );
+        }
+
+        let map_rules = |rules: Vec<RuleType>| {
+            rules
                 .into_iter()
-                .map(|r| map_rule_type(r, mmdb.clone(), Some(&rule_provider_registry)))
+                .map(|r| {
+                    map_rule_type(
+                        r,
+                        mmdb.clone(),
+                        geosite.clone(),
+                        Some(&rule_provider_registry),
+                        Some(&sets),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let rules = map_rules(rules);
+        let listener_rules = listener_rules
+            .into_iter()
+            .map(|(name, rules)| (name, map_rules(rules)))
+            .collect::<HashMap<_, _>>();
+
+        let hint_rules = match dns_resolver.route_hints() {
+            Some(hints) => hints
+                .targets()
+                .iter()
+                .map(|target| {
+                    Box::new(rules::route_hint::RouteHint {
+                        hints: hints.clone(),
+                        target: target.to_owned(),
+                    }) as Box<dyn RuleMatcher>
+                })
                 .collect(),
+            None => vec![],
+        };
+
+        Self {
+            hint_rules,
+            cidr_runs,
+            rules,
+            listener_rules,
             dns_resolver,
             rule_provider_registry,
         }
     }
 
+    /// The process of the session, if a rule looked it up while matching,
+    /// for the connections API.
+    pub fn find_process(&self, sess: &mut Session) {
+        if sess.process_path.is_none() {
+            sess.process_path = process::cached_process_path(sess.network, sess.source);
+        }
+    }
+
     pub async fn match_route<'a>(
         &'a self,
-        sess: &'a Session,
-    ) -> (&str, Option<&Box<dyn RuleMatcher>>) {
+        sess: &Session,
+    ) -> (&'a str, Option<&'a Box<dyn RuleMatcher>>) {
+        // a connection by IP no rule took goes where the domain it was an
+        // answer for would
+        let match_hint = || {
+            let r = self.hint_rules.iter().find(|r| r.apply(sess))?;
+            info!(
+                "matched {} to target {}[{}]",
+                sess,
+                r.target(),
+                r.type_name()
+            );
+            Some((r.target(), Some(r)))
+        };
+
         let mut sess_resolved = false;
+        // the process is looked up once a rule needs it, as it's costly
+        let mut process_found = sess.process_path.is_some();
         let mut sess_dup = sess.clone();
 
-        for r in self.rules.iter() {
+        // the rules being walked, with the index of the next one; a matched
+        // SUB-RULE pushes its set, an exhausted set goes back to its parent
+        let mut stack: Vec<(&[Box<dyn RuleMatcher>], usize)> = vec![(self.rules.as_slice(), 0)];
+        // the rules of the listener go first, then back to the global ones
+        if let Some(rules) = self.listener_rules(sess) {
+            stack.push((rules.as_slice(), 0));
+        }
+        while let Some(&(rules, i)) = stack.last() {
+            if i >= rules.len() {
+                stack.pop();
+                continue;
+            }
+            let r = &rules[i];
+            if stack.len() == 1 && r.type_name() == "Match" {
+                if let Some(matched) = match_hint() {
+                    return matched;
+                }
+            }
             if sess.destination.is_domain() && r.should_resolve_ip() && !sess_resolved {
                 debug!(
                     "rule `{r}` resolving domain {} locally",
@@ -87,12 +205,35 @@ impl Router {
                     .resolve(sess.destination.domain().unwrap(), false)
                     .await
                 {
-                    sess_dup.destination = SocksAddr::from((ip, sess.destination.port()));
+                    sess_dup.resolved_ip = Some(ip);
                     sess_resolved = true;
                 }
             }
+            if r.should_find_process() && !process_found {
+                sess_dup.process_path = process::find_process_path(sess.network, sess.source).await;
+                process_found = true;
+            }
 
-            if r.apply(&sess_dup) {
+            // the runs are of the global rules only
+            let run = self.cidr_runs.get(&i).filter(|_| stack.len() == 1);
+            let matched = match run {
+                Some(run) => match run.lookup(&sess_dup) {
+                    Some(idx) => Some(&rules[idx]),
+                    None => {
+                        stack.last_mut().unwrap().1 = run.end;
+                        continue;
+                    }
+                },
+                None => r.apply(&sess_dup).then_some(r),
+            };
+            stack.last_mut().unwrap().1 = i + 1;
+
+            if let Some(r) = matched {
+                if let Some(sub_rules) = r.sub_rules() {
+                    debug!("{} entering sub-rules {}", &sess_dup, r.target());
+                    stack.push((sub_rules, 0));
+                    continue;
+                }
                 info!(
                     "matched {} to target {}[{}]",
                     &sess_dup,
@@ -103,7 +244,31 @@ impl Router {
             }
         }
 
-        (MATCH, None)
+        match_hint().unwrap_or((MATCH, None))
+    }
+
+    /// The rules of the listener of the session, by its port first.
+    fn listener_rules(&self, sess: &Session) -> Option<&Vec<Box<dyn RuleMatcher>>> {
+        sess.listener
+            .and_then(|x| {
+                self.listener_rules
+                    .get(&x.port.to_string())
+                    .or_else(|| self.listener_rules.get(x.name))
+            })
+            .or_else(|| sess.typ.listener().and_then(|x| self.listener_rules.get(x)))
+    }
+
+    /// The target of the first domain rule matching the host, for the DNS
+    /// server. The other rules are passed over, nothing is resolved.
+    pub fn match_domain(&self, host: &str) -> Option<&str> {
+        let sess = Session {
+            destination: SocksAddr::Domain(host.to_owned(), 0),
+            ..Default::default()
+        };
+        self.rules
+            .iter()
+            .find(|r| r.is_domain_rule() && r.apply(&sess))
+            .map(|r| r.target())
     }
 
     async fn load_rule_providers(
@@ -111,6 +276,7 @@ impl Router {
         rule_provider_registry: &mut HashMap<String, ThreadSafeRuleProvider>,
         resolver: ThreadSafeDNSResolver,
         mmdb: Arc<Mmdb>,
+        geosite: Arc<Geosite>,
         cwd: String,
     ) -> Result<(), Error> {
         for (name, provider) in rule_providers.into_iter() {
@@ -128,9 +294,11 @@ impl Router {
                     let provider = RuleProviderImpl::new(
                         name.clone(),
                         http.behavior,
+                        http.format,
                         Duration::from_secs(http.interval),
                         Arc::new(vehicle),
                         mmdb.clone(),
+                        geosite.clone(),
                     );
 
                     rule_provider_registry.insert(name, Arc::new(provider));
@@ -146,9 +314,11 @@ impl Router {
                     let provider = RuleProviderImpl::new(
                         name.clone(),
                         file.behavior,
+                        file.format,
                         Duration::from_secs(file.interval.unwrap_or_default()),
                         Arc::new(vehicle),
                         mmdb.clone(),
+                        geosite.clone(),
                     );
 
                     rule_provider_registry.insert(name, Arc::new(provider));
@@ -178,12 +348,61 @@ impl Router {
     pub fn get_all_rules(&self) -> &Vec<Box<dyn RuleMatcher>> {
         &self.rules
     }
+
+    pub fn get_rule_providers(&self) -> &HashMap<String, ThreadSafeRuleProvider> {
+        &self.rule_provider_registry
+    }
+}
+
+/// Whether the rule needs the geosite database.
+fn uses_geosite(rule: &RuleType) -> bool {
+    match rule {
+        RuleType::GeoSite { .. } | RuleType::ChinaDirect { .. } => true,
+        RuleType::Shaped { rule, .. } => uses_geosite(rule),
+        RuleType::Logic { rules, .. } => rules.iter().any(uses_geosite),
+        RuleType::SubRule { rule, .. } => uses_geosite(rule),
+        _ => false,
+    }
+}
+
+/// Build the set `name` of `sub_rules` into `sets`, the sets it jumps into
+/// first. A set is taken out of `sub_rules` once it's built.
+fn build_sub_rules(
+    name: &str,
+    sub_rules: &mut HashMap<String, Vec<RuleType>>,
+    sets: &mut SubRuleSets,
+    mmdb: &Arc<Mmdb>,
+    geosite: &Arc<Geosite>,
+    rule_provider_registry: &HashMap<String, ThreadSafeRuleProvider>,
+) {
+    // built already, the config has no loop
+    let Some(rules) = sub_rules.remove(name) else {
+        return;
+    };
+    for next in rules.iter().filter_map(RuleType::sub_rules) {
+        build_sub_rules(next, sub_rules, sets, mmdb, geosite, rule_provider_registry);
+    }
+    let set = rules
+        .into_iter()
+        .map(|r| {
+            map_rule_type(
+                r,
+                mmdb.clone(),
+                geosite.clone(),
+                Some(rule_provider_registry),
+                Some(&*sets),
+            )
+        })
+        .collect();
+    sets.insert(name.to_owned(), Arc::new(set));
 }
 
 pub fn map_rule_type(
     rule_type: RuleType,
     mmdb: Arc<Mmdb>,
+    geosite: Arc<Geosite>,
     rule_provider_registry: Option<&HashMap<String, ThreadSafeRuleProvider>>,
+    sub_rule_sets: Option<&SubRuleSets>,
 ) -> Box<dyn RuleMatcher> {
     match rule_type {
         RuleType::Domain { domain, target } => {
@@ -234,6 +453,22 @@ pub fn map_rule_type(
             no_resolve,
             mmdb: mmdb.clone(),
         }),
+        RuleType::GeoSite { target, category } => Box::new(rules::geosite::GeoSite {
+            matcher: geosite
+                .matcher(&category)
+                .map_err(|e| error!("GEOSITE,{} matches nothing: {}", category, e))
+                .ok(),
+            target,
+            category,
+        }),
+        RuleType::ChinaDirect { target } => Box::new(rules::china_direct::ChinaDirect {
+            target,
+            domains: geosite
+                .matcher("cn")
+                .map_err(|e| error!("china-direct matches no domain: {}", e))
+                .ok(),
+            mmdb: mmdb.clone(),
+        }),
         RuleType::SRCPort { target, port } => Box::new(rules::port::Port {
             port,
             target,
@@ -260,6 +495,10 @@ pub fn map_rule_type(
             target,
             name_only: false,
         }),
+        RuleType::Network { network, target } => {
+            Box::new(rules::network::Network { network, target })
+        }
+        RuleType::Schedule { cron, target } => Box::new(rules::schedule::Schedule { cron, target }),
         RuleType::RuleSet { rule_set, target } => match rule_provider_registry {
             Some(rule_provider_registry) => Box::new(RuleSet::new(
                 rule_set.clone(),
@@ -271,6 +510,302 @@ pub fn map_rule_type(
             )),
             None => unreachable!("you shouldn't next rule-set within another rule-set"),
         },
+        RuleType::Script {
+            shortcut,
+            code,
+            target,
+        } => Box::new(rules::script::Script {
+            expr: script::Expr::parse(&code)
+                .map_err(|e| error!("SCRIPT,{} matches nothing: {}", shortcut, e))
+                .ok(),
+            shortcut,
+            target,
+            mmdb,
+        }),
         RuleType::Match { target } => Box::new(Final { target }),
+        RuleType::Logic {
+            op,
+            rules: sub_rules,
+            payload,
+            target,
+        } => Box::new(rules::logic::Logic {
+            op,
+            rules: sub_rules
+                .into_iter()
+                .map(|r| {
+                    map_rule_type(
+                        r,
+                        mmdb.clone(),
+                        geosite.clone(),
+                        rule_provider_registry,
+                        sub_rule_sets,
+                    )
+                })
+                .collect(),
+            payload,
+            target,
+        }),
+        RuleType::Shaped { rule, class } => Box::new(rules::shaped::Shaped {
+            inner: map_rule_type(*rule, mmdb, geosite, rule_provider_registry, sub_rule_sets),
+            class,
+        }),
+        RuleType::SubRule {
+            rule,
+            sub_rules: name,
+        } => {
+            let inner = map_rule_type(*rule, mmdb, geosite, rule_provider_registry, sub_rule_sets);
+            let rules = match sub_rule_sets {
+                Some(sets) => sets
+                    .get(&name)
+                    .unwrap_or_else(|| panic!("sub-rules {} not found", name))
+                    .clone(),
+                None => unreachable!("you shouldn't nest a sub-rule within a rule-set"),
+            };
+            Box::new(rules::sub_rule::SubRule {
+                find_process: inner.should_find_process()
+                    || rules.iter().any(|r| r.should_find_process()),
+                inner,
+                name,
+                rules,
+            })
+        }
+        RuleType::Plugin { matcher, .. } => matcher,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use crate::{
+        app::dns::{MockClashResolver, RouteHints},
+        common::{geosite::Geosite, http::new_http_client, mmdb::Mmdb},
+        config::internal::rule::RuleType,
+        proxy::utils::test_utils::config_helper::test_config_base_dir,
+        session::{ListenerId, Network, Session, SocksAddr, Type},
+    };
+
+    use super::Router;
+
+    async fn router(
+        rules: &[&str],
+        listener_rules: HashMap<String, Vec<&str>>,
+        sub_rules: HashMap<String, Vec<&str>>,
+        hints: Option<Arc<RouteHints>>,
+    ) -> Router {
+        let parse = |rules: &[&str]| {
+            rules
+                .iter()
+                .map(|x| x.parse::<RuleType>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_route_hints().return_const(hints);
+        // every domain resolves to 1.1.1.1
+        resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some("1.1.1.1".parse().unwrap())));
+        let resolver = Arc::new(resolver);
+
+        let client = new_http_client(resolver.clone()).unwrap();
+        let mmdb = Mmdb::new(
+            test_config_base_dir().join("Country.mmdb"),
+            None,
+            client.clone(),
+        )
+        .await
+        .unwrap();
+        let geosite = Geosite::new(test_config_base_dir().join("geosite.dat"), None, client);
+
+        Router::new(
+            parse(rules),
+            listener_rules
+                .into_iter()
+                .map(|(k, v)| (k, parse(&v)))
+                .collect(),
+            sub_rules.into_iter().map(|(k, v)| (k, parse(&v))).collect(),
+            HashMap::new(),
+            resolver,
+            Arc::new(mmdb),
+            Arc::new(geosite),
+            String::new(),
+        )
+        .await
+    }
+
+    fn sess(destination: &str) -> Session {
+        Session {
+            destination: match destination.parse() {
+                Ok(addr) => SocksAddr::Ip(addr),
+                Err(_) => SocksAddr::Domain(destination.to_owned(), 443),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_hints_in_place_of_match() {
+        let hints = Arc::new(RouteHints::new(&HashMap::from([(
+            "+.nflxvideo.net".to_owned(),
+            "US".to_owned(),
+        )])));
+        hints.record(
+            "ipv4-c001.nflxvideo.net",
+            &["45.57.0.1".parse().unwrap(), "45.57.0.2".parse().unwrap()],
+            Duration::from_secs(300),
+        );
+        let router = router(
+            &["IP-CIDR,45.57.0.2/32,REJECT", "MATCH,DIRECT"],
+            HashMap::new(),
+            HashMap::new(),
+            Some(hints),
+        )
+        .await;
+
+        assert_eq!(router.match_route(&sess("45.57.0.1:443")).await.0, "US");
+        // the rules go first
+        assert_eq!(router.match_route(&sess("45.57.0.2:443")).await.0, "REJECT");
+        assert_eq!(router.match_route(&sess("45.57.0.3:443")).await.0, "DIRECT");
+    }
+
+    #[tokio::test]
+    async fn test_domain_rules_after_resolving() {
+        let router = router(
+            &[
+                "IP-CIDR,10.0.0.0/8,DIRECT",
+                "DOMAIN,one.one.one.one,PROXY",
+                "IP-CIDR,1.1.1.0/24,REJECT",
+                "MATCH,DIRECT",
+            ],
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        )
+        .await;
+
+        // resolved for the first rule, still matched by its domain
+        assert_eq!(
+            router.match_route(&sess("one.one.one.one")).await.0,
+            "PROXY"
+        );
+        assert_eq!(router.match_route(&sess("dns.google")).await.0, "REJECT");
+    }
+
+    #[tokio::test]
+    async fn test_match_domain() {
+        let router = router(
+            &[
+                "IP-CIDR,1.0.0.0/8,DIRECT",
+                "NETWORK,UDP,DIRECT",
+                "DOMAIN-SUFFIX,ads.example.com,REJECT",
+                "DOMAIN-KEYWORD,example,PROXY",
+                "MATCH,REJECT",
+            ],
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        )
+        .await;
+
+        // the IP and the network rules are passed over
+        assert_eq!(router.match_domain("x.ads.example.com"), Some("REJECT"));
+        assert_eq!(router.match_domain("www.example.com"), Some("PROXY"));
+        assert_eq!(router.match_domain("one.one.one.one"), None);
+    }
+
+    #[tokio::test]
+    async fn test_listener_rules() {
+        let router = router(
+            &["DST-PORT,53,DNS", "MATCH,DIRECT"],
+            HashMap::from([
+                ("socks".to_owned(), vec!["DOMAIN,example.com,SOCKS"]),
+                ("mixed".to_owned(), vec!["DOMAIN,example.com,MIXED"]),
+                ("7893".to_owned(), vec!["DOMAIN,example.com,PORT"]),
+                ("tun".to_owned(), vec!["DOMAIN,example.com,TUN"]),
+            ]),
+            HashMap::new(),
+            None,
+        )
+        .await;
+        let from = |typ, name, port| Session {
+            typ,
+            listener: Some(ListenerId { name, port }),
+            ..sess("example.com")
+        };
+
+        let socks = from(Type::Socks5, "socks", 7891);
+        assert_eq!(router.match_route(&socks).await.0, "SOCKS");
+        assert_eq!(
+            router
+                .match_route(&from(Type::Socks5, "mixed", 7892))
+                .await
+                .0,
+            "MIXED"
+        );
+        assert_eq!(
+            router
+                .match_route(&from(Type::Socks5, "mixed", 7893))
+                .await
+                .0,
+            "PORT"
+        );
+        let tun = Session {
+            typ: Type::Tun,
+            ..sess("example.com")
+        };
+        assert_eq!(router.match_route(&tun).await.0, "TUN");
+        // no listener rules for http
+        assert_eq!(
+            router.match_route(&from(Type::Http, "http", 7890)).await.0,
+            "DIRECT"
+        );
+
+        // falling through to the global rules
+        let mut dns = socks.clone();
+        dns.destination = SocksAddr::Domain("example.org".to_owned(), 53);
+        assert_eq!(router.match_route(&dns).await.0, "DNS");
+        let mut other = socks;
+        other.destination = SocksAddr::Domain("example.org".to_owned(), 443);
+        assert_eq!(router.match_route(&other).await.0, "DIRECT");
+    }
+
+    #[tokio::test]
+    async fn test_sub_rules() {
+        let router = router(
+            &[
+                "SUB-RULE,(NETWORK,TCP),a",
+                "SUB-RULE,(NETWORK,UDP),b",
+                "MATCH,DIRECT",
+            ],
+            HashMap::new(),
+            HashMap::from([
+                (
+                    "a".to_owned(),
+                    vec!["DOMAIN,example.com,PROXY", "SUB-RULE,(DST-PORT,443),b"],
+                ),
+                ("b".to_owned(), vec!["DOMAIN,example.org,REJECT"]),
+            ]),
+            None,
+        )
+        .await;
+
+        assert_eq!(router.match_route(&sess("example.com")).await.0, "PROXY");
+        assert_eq!(router.match_route(&sess("example.org")).await.0, "REJECT");
+        // falling through b, then a, to the global rules
+        assert_eq!(router.match_route(&sess("example.net")).await.0, "DIRECT");
+        let udp = |destination| Session {
+            network: Network::Udp,
+            ..sess(destination)
+        };
+        assert_eq!(router.match_route(&udp("example.com")).await.0, "DIRECT");
+        assert_eq!(router.match_route(&udp("example.org")).await.0, "REJECT");
+
+        // b is built once, for both of the rules jumping into it
+        let rules = router.get_all_rules();
+        let a = rules[0].sub_rules().unwrap();
+        assert!(std::ptr::eq(
+            a[1].sub_rules().unwrap(),
+            rules[1].sub_rules().unwrap()
+        ));
     }
 }
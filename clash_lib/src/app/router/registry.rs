@@ -0,0 +1,120 @@
+//! Rule keywords implemented out of tree
+//!
+//! Experimental conditions, e.g. on the TLS fingerprint of the client, are
+//! registered by the embedding crate before starting, the rule lines with
+//! their keyword are then built by the factory.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::Error;
+
+use super::RuleMatcher;
+
+const BUILTIN_KEYWORDS: [&str; 21] = [
+    "DOMAIN",
+    "DOMAIN-SUFFIX",
+    "DOMAIN-KEYWORD",
+    "GEOIP",
+    "GEOSITE",
+    "IP-CIDR",
+    "IP-CIDR6",
+    "SRC-IP-CIDR",
+    "SRC-PORT",
+    "DST-PORT",
+    "PROCESS-NAME",
+    "PROCESS-PATH",
+    "NETWORK",
+    "RULE-SET",
+    "SCRIPT",
+    "SCHEDULE",
+    "SUB-RULE",
+    "AND",
+    "OR",
+    "NOT",
+    "MATCH",
+];
+
+static FACTORIES: Lazy<RwLock<HashMap<String, Arc<dyn RuleFactory>>>> = Lazy::new(Default::default);
+
+pub trait RuleFactory: Send + Sync {
+    /// Build the matcher of a rule line, `params` are the fields after the
+    /// target, e.g. `no-resolve`. The matcher must route to `target`.
+    fn create(
+        &self,
+        payload: &str,
+        target: &str,
+        params: &[&str],
+    ) -> Result<Box<dyn RuleMatcher>, Error>;
+}
+
+/// Register the factory of the rules starting with `keyword`, registering
+/// a keyword twice replaces the factory.
+pub fn register_rule(keyword: &str, factory: Arc<dyn RuleFactory>) -> Result<(), Error> {
+    if BUILTIN_KEYWORDS.contains(&keyword) {
+        return Err(Error::InvalidConfig(format!(
+            "rule `{}` is built in",
+            keyword
+        )));
+    }
+    FACTORIES
+        .write()
+        .unwrap()
+        .insert(keyword.to_owned(), factory);
+    Ok(())
+}
+
+/// The matcher of a rule with a registered keyword, `None` if it's unknown.
+pub(crate) fn create(
+    keyword: &str,
+    payload: &str,
+    target: &str,
+    params: &[&str],
+) -> Option<Result<Box<dyn RuleMatcher>, Error>> {
+    let factory = FACTORIES.read().unwrap().get(keyword).cloned()?;
+    Some(factory.create(payload, target, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{register_rule, RuleFactory};
+    use crate::{
+        app::router::{rules::final_::Final, RuleMatcher},
+        config::internal::rule::RuleType,
+        Error,
+    };
+
+    struct Factory;
+
+    impl RuleFactory for Factory {
+        fn create(
+            &self,
+            _payload: &str,
+            target: &str,
+            _params: &[&str],
+        ) -> Result<Box<dyn RuleMatcher>, Error> {
+            Ok(Box::new(Final {
+                target: target.to_owned(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_register_rule() {
+        for keyword in ["DOMAIN", "SCHEDULE", "SUB-RULE"] {
+            assert!(register_rule(keyword, Arc::new(Factory)).is_err());
+        }
+        assert!("TLS-JA3,abc,DIRECT".parse::<RuleType>().is_err());
+
+        register_rule("TLS-JA3", Arc::new(Factory)).unwrap();
+        let rule = "TLS-JA3,abc,DIRECT".parse::<RuleType>().unwrap();
+        assert_eq!(rule.target(), "DIRECT");
+        assert_eq!(rule.to_string(), "TLS-JA3");
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use tracing::debug;
+
+use crate::{
+    common::{geosite::GeositeMatcher, mmdb},
+    session::{Session, SocksAddr},
+};
+
+use super::RuleMatcher;
+
+/// The `china-direct` preset: the domains of the `cn` geosite category and
+/// the IPs the mmdb puts in CN. An IP is only looked up when the destination
+/// is one, a domain is never resolved for it.
+#[derive(Clone)]
+pub struct ChinaDirect {
+    pub target: String,
+    /// none if the category couldn't be loaded, no domain is matched then
+    pub domains: Option<Arc<GeositeMatcher>>,
+    pub mmdb: Arc<mmdb::Mmdb>,
+}
+
+impl std::fmt::Display for ChinaDirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ChinaDirect({})", self.target)
+    }
+}
+
+impl RuleMatcher for ChinaDirect {
+    fn apply(&self, sess: &Session) -> bool {
+        match &sess.destination {
+            SocksAddr::Domain(domain, _) => {
+                self.domains.as_ref().is_some_and(|x| x.matches(domain))
+            }
+            SocksAddr::Ip(addr) => match self.mmdb.country_code(addr.ip()) {
+                Ok(iso_code) => iso_code.is_some_and(|x| x.eq_ignore_ascii_case("CN")),
+                Err(e) => {
+                    debug!("GeoIP lookup failed: {}", e);
+                    false
+                }
+            },
+        }
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        "cn".to_owned()
+    }
+
+    fn type_name(&self) -> &str {
+        "ChinaDirect"
+    }
+}
@@ -33,4 +33,8 @@ impl RuleMatcher for Domain {
     fn type_name(&self) -> &str {
         "Domain"
     }
+
+    fn is_domain_rule(&self) -> bool {
+        true
+    }
 }
@@ -35,4 +35,8 @@ impl RuleMatcher for DomainKeyword {
     fn type_name(&self) -> &str {
         "DomainKeyword"
     }
+
+    fn is_domain_rule(&self) -> bool {
+        true
+    }
 }
@@ -35,4 +35,8 @@ impl RuleMatcher for DomainSuffix {
     fn type_name(&self) -> &str {
         "DomainSuffix"
     }
+
+    fn is_domain_rule(&self) -> bool {
+        true
+    }
 }
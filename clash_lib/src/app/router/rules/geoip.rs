@@ -22,22 +22,21 @@ impl std::fmt::Display for GeoIP {
 
 impl RuleMatcher for GeoIP {
     fn apply(&self, sess: &Session) -> bool {
-        match sess.destination {
-            crate::session::SocksAddr::Ip(addr) => match self.mmdb.lookup(addr.ip()) {
-                Ok(country) => {
-                    country
-                        .country
-                        .map(|x| x.iso_code)
-                        .unwrap_or_default()
-                        .unwrap_or_default()
-                        == self.country_code
+        match sess.destination_ip() {
+            Some(ip) => match self.mmdb.country_code(ip) {
+                Ok(iso_code) => {
+                    let iso_code = iso_code.unwrap_or_default();
+                    // multiple countries could be given as `CN|HK|TW`
+                    self.country_code
+                        .split('|')
+                        .any(|x| x.trim().eq_ignore_ascii_case(&iso_code))
                 }
                 Err(e) => {
                     debug!("GeoIP lookup failed: {}", e);
                     false
                 }
             },
-            crate::session::SocksAddr::Domain(_, _) => false,
+            None => false,
         }
     }
     fn target(&self) -> &str {
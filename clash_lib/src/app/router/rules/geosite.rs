@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use crate::{common::geosite::GeositeMatcher, session::Session};
+
+use super::RuleMatcher;
+
+#[derive(Clone)]
+pub struct GeoSite {
+    pub target: String,
+    pub category: String,
+    /// none if the category couldn't be loaded, nothing is matched then
+    pub matcher: Option<Arc<GeositeMatcher>>,
+}
+
+impl std::fmt::Display for GeoSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GeoSite({} - {})", self.target, self.category)
+    }
+}
+
+impl RuleMatcher for GeoSite {
+    fn apply(&self, sess: &Session) -> bool {
+        match (&sess.destination, &self.matcher) {
+            (crate::session::SocksAddr::Domain(domain, _), Some(matcher)) => {
+                matcher.matches(domain)
+            }
+            _ => false,
+        }
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.category.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "GeoSite"
+    }
+
+    fn is_domain_rule(&self) -> bool {
+        true
+    }
+}
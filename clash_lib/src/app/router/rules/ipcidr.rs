@@ -1,5 +1,9 @@
+use std::collections::HashMap;
+
 use crate::app::router::rules::RuleMatcher;
-use crate::session::{Session, SocksAddr};
+use crate::common::cidr_trie::CidrTrie;
+use crate::config::internal::rule::RuleType;
+use crate::session::Session;
 
 #[derive(Clone)]
 pub struct IpCidr {
@@ -25,9 +29,9 @@ impl RuleMatcher for IpCidr {
     fn apply(&self, sess: &Session) -> bool {
         match self.match_src {
             true => self.ipnet.contains(&sess.source.ip()),
-            false => match &sess.destination {
-                SocksAddr::Ip(ip) => self.ipnet.contains(&ip.ip()),
-                SocksAddr::Domain(_, _) => false,
+            false => match sess.destination_ip() {
+                Some(ip) => self.ipnet.contains(&ip),
+                None => false,
             },
         }
     }
@@ -47,3 +51,125 @@ impl RuleMatcher for IpCidr {
         "IPCIDR"
     }
 }
+
+/// Consecutive IP-CIDR rules of the same kind, looked up in one trie walk
+/// instead of one by one. Being consecutive, the first matching rule of the
+/// run is the one the linear scan would have picked.
+pub struct IpCidrRun {
+    /// the index of the first rule past the run
+    pub end: usize,
+    match_src: bool,
+    trie: CidrTrie<usize>,
+}
+
+impl IpCidrRun {
+    /// Runs shorter than this are scanned linearly
+    const MIN_LEN: usize = 4;
+
+    /// The runs of `rules`, keyed by the index of their first rule
+    pub fn build(rules: &[RuleType]) -> HashMap<usize, IpCidrRun> {
+        let kind = |r: &RuleType| match r {
+            RuleType::IpCidr {
+                ipnet, no_resolve, ..
+            } => Some((*ipnet, false, *no_resolve)),
+            RuleType::SrcCidr {
+                ipnet, no_resolve, ..
+            } => Some((*ipnet, true, *no_resolve)),
+            _ => None,
+        };
+
+        let mut runs = HashMap::new();
+        let mut start = 0;
+        while start < rules.len() {
+            let (match_src, no_resolve) = match kind(&rules[start]) {
+                Some((_, match_src, no_resolve)) => (match_src, no_resolve),
+                None => {
+                    start += 1;
+                    continue;
+                }
+            };
+
+            let mut trie = CidrTrie::new();
+            let mut end = start;
+            while let Some((ipnet, s, n)) = rules.get(end).and_then(kind) {
+                if s != match_src || n != no_resolve {
+                    break;
+                }
+                trie.insert(ipnet, end);
+                end += 1;
+            }
+
+            if end - start >= Self::MIN_LEN {
+                runs.insert(
+                    start,
+                    IpCidrRun {
+                        end,
+                        match_src,
+                        trie,
+                    },
+                );
+            }
+            start = end;
+        }
+        runs
+    }
+
+    /// The index of the first rule of the run matching the session
+    pub fn lookup(&self, sess: &Session) -> Option<usize> {
+        let ip = match self.match_src {
+            true => sess.source.ip(),
+            false => sess.destination_ip()?,
+        };
+        self.trie.matches(ip).min().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::internal::rule::RuleType,
+        session::{Session, SocksAddr},
+    };
+
+    use super::IpCidrRun;
+
+    #[test]
+    fn test_runs() {
+        let cidr = |ipnet: &str| RuleType::IpCidr {
+            ipnet: ipnet.parse().unwrap(),
+            target: "DIRECT".to_owned(),
+            no_resolve: true,
+        };
+        let rules = vec![
+            RuleType::Match {
+                target: "DIRECT".to_owned(),
+            },
+            cidr("10.0.0.0/8"),
+            cidr("1.1.1.0/24"),
+            cidr("10.1.0.0/16"),
+            cidr("10.0.0.0/8"),
+            cidr("0.0.0.0/0"),
+            cidr("192.168.0.0/16"),
+        ];
+
+        let runs = IpCidrRun::build(&rules);
+        assert_eq!(runs.len(), 1);
+        let run = &runs[&1];
+        assert_eq!(run.end, 7);
+
+        let sess = |ip: &str| Session {
+            destination: SocksAddr::Ip((ip.parse::<std::net::IpAddr>().unwrap(), 80).into()),
+            ..Default::default()
+        };
+        assert_eq!(run.lookup(&sess("10.1.2.3")), Some(1));
+        assert_eq!(run.lookup(&sess("1.1.1.1")), Some(2));
+        assert_eq!(run.lookup(&sess("192.168.1.1")), Some(5));
+        assert_eq!(
+            run.lookup(&Session {
+                destination: SocksAddr::Domain("example.com".to_owned(), 80),
+                ..Default::default()
+            }),
+            None
+        );
+    }
+}
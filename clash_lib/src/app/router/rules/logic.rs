@@ -0,0 +1,112 @@
+use crate::{config::internal::rule::LogicOp, session::Session};
+
+use super::RuleMatcher;
+
+pub struct Logic {
+    pub op: LogicOp,
+    /// one rule for NOT
+    pub rules: Vec<Box<dyn RuleMatcher>>,
+    pub payload: String,
+    pub target: String,
+}
+
+impl std::fmt::Display for Logic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.target, self.op, self.payload)
+    }
+}
+
+impl RuleMatcher for Logic {
+    fn apply(&self, sess: &Session) -> bool {
+        match self.op {
+            LogicOp::And => self.rules.iter().all(|r| r.apply(sess)),
+            LogicOp::Or => self.rules.iter().any(|r| r.apply(sess)),
+            LogicOp::Not => !self.rules.iter().any(|r| r.apply(sess)),
+        }
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn payload(&self) -> String {
+        self.payload.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        match self.op {
+            LogicOp::And => "And",
+            LogicOp::Or => "Or",
+            LogicOp::Not => "Not",
+        }
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.rules.iter().any(|r| r.should_resolve_ip())
+    }
+
+    fn should_find_process(&self) -> bool {
+        self.rules.iter().any(|r| r.should_find_process())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::router::rules::{network::Network, port::Port, RuleMatcher},
+        config::internal::rule::LogicOp,
+        session::{Network as SessionNetwork, Session, SocksAddr},
+    };
+
+    use super::Logic;
+
+    fn logic(op: LogicOp, rules: Vec<Box<dyn RuleMatcher>>) -> Logic {
+        Logic {
+            op,
+            rules,
+            payload: String::new(),
+            target: "REJECT".to_owned(),
+        }
+    }
+
+    fn udp() -> Box<dyn RuleMatcher> {
+        Box::new(Network {
+            network: SessionNetwork::Udp,
+            target: String::new(),
+        })
+    }
+
+    fn port_443() -> Box<dyn RuleMatcher> {
+        Box::new(Port {
+            port: 443,
+            target: String::new(),
+            is_src: false,
+        })
+    }
+
+    #[test]
+    fn test_logic() {
+        let quic = Session {
+            network: SessionNetwork::Udp,
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            ..Default::default()
+        };
+        let dns = Session {
+            network: SessionNetwork::Udp,
+            destination: SocksAddr::Domain("example.com".to_owned(), 53),
+            ..Default::default()
+        };
+
+        let and = logic(LogicOp::And, vec![udp(), port_443()]);
+        assert!(and.apply(&quic));
+        assert!(!and.apply(&dns));
+
+        let or = logic(LogicOp::Or, vec![port_443(), udp()]);
+        assert!(or.apply(&dns));
+        assert!(!or.apply(&Session::default()));
+
+        let not = logic(LogicOp::Not, vec![port_443()]);
+        assert!(!not.apply(&quic));
+        assert!(not.apply(&dns));
+    }
+}
@@ -4,15 +4,24 @@ use erased_serde::Serialize;
 
 use crate::session::Session;
 
+pub mod china_direct;
 pub mod domain;
 pub mod domain_keyword;
 pub mod domain_suffix;
 pub mod final_;
 pub mod geoip;
+pub mod geosite;
 pub mod ipcidr;
+pub mod logic;
+pub mod network;
 pub mod port;
 pub mod process;
+pub mod route_hint;
 pub mod ruleset;
+pub mod schedule;
+pub mod script;
+pub mod shaped;
+pub mod sub_rule;
 
 pub trait RuleMatcher: Send + Sync + Unpin + Display {
     /// check if the rule should apply to the session
@@ -31,6 +40,27 @@ pub trait RuleMatcher: Send + Sync + Unpin + Display {
         false
     }
 
+    /// the rule matches on the process of the session
+    fn should_find_process(&self) -> bool {
+        false
+    }
+
+    /// the rule matches on the domain of the session only
+    fn is_domain_rule(&self) -> bool {
+        false
+    }
+
+    /// the traffic shaping class of the matched connections
+    fn shaping_class(&self) -> Option<&str> {
+        None
+    }
+
+    /// the rules walked in place of this one once it matches, for
+    /// `SUB-RULE`
+    fn sub_rules(&self) -> Option<&[Box<dyn RuleMatcher>]> {
+        None
+    }
+
     fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
         m.insert("type".to_string(), Box::new(self.type_name().to_owned()));
@@ -1,9 +1,11 @@
+use std::path::Path;
+
 use super::RuleMatcher;
 
 pub struct Process {
     pub name: String,
     pub target: String,
-    #[allow(dead_code)]
+    /// match the file name of the executable, not its whole path
     pub name_only: bool,
 }
 
@@ -14,9 +16,17 @@ impl std::fmt::Display for Process {
 }
 
 impl RuleMatcher for Process {
-    fn apply(&self, _sess: &crate::session::Session) -> bool {
-        // TODO: implement this
-        false
+    fn apply(&self, sess: &crate::session::Session) -> bool {
+        let Some(path) = sess.process_path.as_ref() else {
+            return false;
+        };
+        if self.name_only {
+            Path::new(path)
+                .file_name()
+                .is_some_and(|x| x.to_string_lossy() == self.name)
+        } else {
+            *path == self.name
+        }
     }
 
     fn target(&self) -> &str {
@@ -30,4 +40,33 @@ impl RuleMatcher for Process {
     fn type_name(&self) -> &str {
         "Process"
     }
+
+    fn should_find_process(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::router::rules::RuleMatcher, session::Session};
+
+    use super::Process;
+
+    #[test]
+    fn test_process() {
+        let sess = Session {
+            process_path: Some("/usr/bin/curl".to_owned()),
+            ..Default::default()
+        };
+        let rule = |name: &str, name_only| Process {
+            name: name.to_owned(),
+            target: "DIRECT".to_owned(),
+            name_only,
+        };
+
+        assert!(rule("curl", true).apply(&sess));
+        assert!(!rule("curl", false).apply(&sess));
+        assert!(rule("/usr/bin/curl", false).apply(&sess));
+        assert!(!rule("curl", true).apply(&Session::default()));
+    }
 }
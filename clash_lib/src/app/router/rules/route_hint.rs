@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use crate::app::dns::RouteHints;
+use crate::app::router::rules::RuleMatcher;
+use crate::session::Session;
+
+/// A connection by IP to an address a `nameserver-policy` domain with a
+/// `route` resolved to, checked when no rule but the final MATCH took it
+pub struct RouteHint {
+    pub hints: Arc<RouteHints>,
+    pub target: String,
+}
+
+impl std::fmt::Display for RouteHint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} route hint", self.target)
+    }
+}
+
+impl RuleMatcher for RouteHint {
+    fn apply(&self, sess: &Session) -> bool {
+        match sess.destination_ip() {
+            Some(ip) => self.hints.lookup(&ip).is_some_and(|x| x == self.target),
+            None => false,
+        }
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn payload(&self) -> String {
+        String::new()
+    }
+
+    fn type_name(&self) -> &str {
+        "RouteHint"
+    }
+}
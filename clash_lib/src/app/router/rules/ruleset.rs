@@ -1,4 +1,6 @@
-use crate::app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider;
+use crate::app::remote_content_manager::providers::rule_provider::{
+    RuleSetBehavior, ThreadSafeRuleProvider,
+};
 use crate::app::router::rules::RuleMatcher;
 use crate::session::Session;
 
@@ -41,4 +43,16 @@ impl RuleMatcher for RuleSet {
     fn type_name(&self) -> &str {
         "RuleSet"
     }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.rule_provider.should_resolve_ip()
+    }
+
+    fn should_find_process(&self) -> bool {
+        self.rule_provider.should_find_process()
+    }
+
+    fn is_domain_rule(&self) -> bool {
+        self.rule_provider.behavior() == RuleSetBehavior::Domain
+    }
 }
@@ -0,0 +1,195 @@
+use std::str::FromStr;
+
+use chrono::{Datelike, Local, Timelike};
+
+use crate::{app::router::rules::RuleMatcher, session::Session, Error};
+
+/// A cron like expression of the times a rule applies, the 5 fields of
+/// `minute hour day-of-month month day-of-week` in local time, e.g.
+/// `* 1-6 * * Mon-Fri` for 01:00 to 06:59 on weekdays.
+///
+/// A field is `*`, a value, a range `a-b`, any of them with a step `/n`,
+/// or a list of those separated with `|`, as commas separate the fields
+/// of the rule. As with cron, a time matches either day field if both are
+/// restricted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Cron {
+    pub fn matches<T: Datelike + Timelike>(&self, t: &T) -> bool {
+        let bit = |set: u64, x: u32| set & (1 << x) != 0;
+        let day = bit(self.days, t.day());
+        let weekday = bit(self.weekdays, t.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && bit(self.minutes, t.minute())
+            && bit(self.hours, t.hour())
+            && bit(self.months, t.month())
+    }
+}
+
+/// The values of a field as bits, and whether it is `*`.
+fn parse_field(
+    field: &str,
+    (min, max): (u32, u32),
+    names: &[&str],
+    name_base: u32,
+) -> Option<(u64, bool)> {
+    let value = |x: &str| -> Option<u32> {
+        let lower = x.to_ascii_lowercase();
+        let v = match names.iter().position(|n| *n == lower) {
+            Some(i) => i as u32 + name_base,
+            None => x.parse().ok()?,
+        };
+        (min..=max).contains(&v).then_some(v)
+    };
+
+    let mut set = 0u64;
+    for part in field.split('|') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|x| *x > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (value(a)?, value(b)?),
+                // `5/15` runs from 5 to the end
+                None if part.contains('/') => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return None;
+        }
+        for x in (start..=end).step_by(step as usize) {
+            set |= 1 << x;
+        }
+    }
+    Some((set, field == "*"))
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("invalid SCHEDULE: {}", s));
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid());
+        };
+
+        let (minutes, _) = parse_field(minute, (0, 59), &[], 0).ok_or_else(invalid)?;
+        let (hours, _) = parse_field(hour, (0, 23), &[], 0).ok_or_else(invalid)?;
+        let (days, any_day) = parse_field(day, (1, 31), &[], 0).ok_or_else(invalid)?;
+        let (months, _) = parse_field(month, (1, 12), &MONTHS, 1).ok_or_else(invalid)?;
+        let (mut weekdays, any_weekday) =
+            parse_field(weekday, (0, 7), &WEEKDAYS, 0).ok_or_else(invalid)?;
+        // 7 is sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Self {
+            expr: fields.join(" "),
+            minutes,
+            hours,
+            days,
+            months,
+            weekdays,
+            any_day,
+            any_weekday,
+        })
+    }
+}
+
+pub struct Schedule {
+    pub cron: Cron,
+    pub target: String,
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} schedule {}", self.target, self.cron.expr)
+    }
+}
+
+impl RuleMatcher for Schedule {
+    fn apply(&self, _: &Session) -> bool {
+        self.cron.matches(&Local::now())
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn payload(&self) -> String {
+        self.cron.expr.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "Schedule"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::Cron;
+
+    #[test]
+    fn test_cron() {
+        // a wednesday
+        let at = |h, m| {
+            NaiveDate::from_ymd_opt(2024, 5, 15)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap()
+        };
+
+        let off_peak: Cron = "* 0-6|23 * * Mon-Fri".parse().unwrap();
+        assert!(off_peak.matches(&at(23, 30)));
+        assert!(off_peak.matches(&at(6, 59)));
+        assert!(!off_peak.matches(&at(7, 0)));
+
+        let weekend: Cron = "* * * * sat|7".parse().unwrap();
+        assert!(!weekend.matches(&at(12, 0)));
+
+        let quarters: Cron = "*/15 * * * *".parse().unwrap();
+        assert!(quarters.matches(&at(12, 45)));
+        assert!(!quarters.matches(&at(12, 50)));
+
+        // either day field
+        let days: Cron = "* * 1 * wed".parse().unwrap();
+        assert!(days.matches(&at(12, 0)));
+        let may: Cron = "* * * may *".parse().unwrap();
+        assert!(may.matches(&at(12, 0)));
+
+        for bad in [
+            "* * * *",
+            "60 * * * *",
+            "* 5-1 * * *",
+            "* * * * fun",
+            "*/0 * * * *",
+        ] {
+            assert!(bad.parse::<Cron>().is_err(), "{}", bad);
+        }
+    }
+}
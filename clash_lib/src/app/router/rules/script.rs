@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use tracing::debug;
+
+use crate::{app::router::script::Expr, common::mmdb::Mmdb, session::Session};
+
+use super::RuleMatcher;
+
+pub struct Script {
+    pub shortcut: String,
+    /// none if the shortcut is missing or invalid, matching nothing
+    pub expr: Option<Expr>,
+    pub target: String,
+    pub mmdb: Arc<Mmdb>,
+}
+
+impl std::fmt::Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} script {}", self.target, self.shortcut)
+    }
+}
+
+impl RuleMatcher for Script {
+    fn apply(&self, sess: &Session) -> bool {
+        let Some(expr) = &self.expr else {
+            return false;
+        };
+        let geoip = |ip| self.mmdb.country_code(ip).ok().flatten();
+        expr.eval(sess, &geoip).unwrap_or_else(|e| {
+            debug!("SCRIPT,{} failed on {}: {}", self.shortcut, sess, e);
+            false
+        })
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn payload(&self) -> String {
+        self.shortcut.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "Script"
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.expr.as_ref().is_some_and(|x| x.uses("dst_ip"))
+    }
+
+    fn should_find_process(&self) -> bool {
+        self.expr
+            .as_ref()
+            .is_some_and(|x| x.uses("process_name") || x.uses("process_path"))
+    }
+}
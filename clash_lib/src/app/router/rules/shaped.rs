@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use erased_serde::Serialize;
+
+use crate::app::router::rules::RuleMatcher;
+use crate::session::Session;
+
+/// A rule with a traffic shaping class attached
+pub struct Shaped {
+    pub inner: Box<dyn RuleMatcher>,
+    pub class: String,
+}
+
+impl std::fmt::Display for Shaped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} class={}", self.inner, self.class)
+    }
+}
+
+impl RuleMatcher for Shaped {
+    fn apply(&self, sess: &Session) -> bool {
+        self.inner.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        self.inner.target()
+    }
+
+    fn payload(&self) -> String {
+        self.inner.payload()
+    }
+
+    fn type_name(&self) -> &str {
+        self.inner.type_name()
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.inner.should_resolve_ip()
+    }
+
+    fn should_find_process(&self) -> bool {
+        self.inner.should_find_process()
+    }
+
+    fn shaping_class(&self) -> Option<&str> {
+        Some(self.class.as_str())
+    }
+
+    fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.inner.as_map();
+        m.insert("class".to_string(), Box::new(self.class.clone()));
+        m
+    }
+}
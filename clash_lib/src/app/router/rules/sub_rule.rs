@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use crate::app::router::rules::RuleMatcher;
+use crate::session::Session;
+
+/// A rule jumping into a set of `sub-rules` once it matches, the router
+/// walks the set in its place
+pub struct SubRule {
+    pub inner: Box<dyn RuleMatcher>,
+    pub name: String,
+    /// shared by the rules jumping into the set
+    pub rules: Arc<Vec<Box<dyn RuleMatcher>>>,
+    /// whether a rule, of the set too, needs the process, known once built
+    /// as the sets can be jumped into from many places
+    pub find_process: bool,
+}
+
+impl std::fmt::Display for SubRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SUB-RULE ({}) {}", self.inner, self.name)
+    }
+}
+
+impl RuleMatcher for SubRule {
+    fn apply(&self, sess: &Session) -> bool {
+        self.inner.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        &self.name
+    }
+
+    fn payload(&self) -> String {
+        format!("({},{})", self.inner.type_name(), self.inner.payload())
+    }
+
+    fn type_name(&self) -> &str {
+        "SubRules"
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.inner.should_resolve_ip()
+    }
+
+    fn should_find_process(&self) -> bool {
+        self.find_process
+    }
+
+    fn sub_rules(&self) -> Option<&[Box<dyn RuleMatcher>]> {
+        Some(self.rules.as_slice())
+    }
+}
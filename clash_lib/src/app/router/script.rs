@@ -0,0 +1,581 @@
+//! The expressions of the `SCRIPT` rules, named in `script.shortcuts`, e.g.
+//! `network == 'udp' and dst_port == 443`. A small subset of Starlark:
+//! `and`, `or`, `not`, the comparisons, `in`, lists, strings, integers and
+//! `True`/`False`, over the fields of the session:
+//!
+//! - `host`: the domain of the destination, empty for an IP
+//! - `dst_ip`: the IP of the destination, or the one its domain resolves to
+//! - `dst_port`, `src_ip`, `src_port`
+//! - `network`: `tcp` or `udp`
+//! - `type`: the inbound, e.g. `socks5` or `tun`
+//! - `process_name`, `process_path`: empty when not found
+//!
+//! and the functions `in_cidr(ip, cidr)`, `geoip(ip)`, and the string
+//! methods `startswith` and `endswith`.
+
+use std::{fmt::Display, net::IpAddr, path::Path};
+
+use crate::session::{Network, Session};
+
+/// The country code of an IP, empty if unknown.
+pub type GeoIpLookup<'a> = &'a dyn Fn(IpAddr) -> Option<String>;
+
+const FIELDS: [&str; 9] = [
+    "host",
+    "dst_ip",
+    "dst_port",
+    "src_ip",
+    "src_port",
+    "network",
+    "type",
+    "process_name",
+    "process_path",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '[' | ']' | ',' | '.' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    ',' => Token::Comma,
+                    _ => Token::Dot,
+                });
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut lit = String::new();
+                loop {
+                    match chars.next() {
+                        Some(x) if x == c => break,
+                        Some('\\') => match chars.next() {
+                            Some(x) => lit.push(x),
+                            None => return Err("unterminated string".to_owned()),
+                        },
+                        Some(x) => lit.push(x),
+                        None => return Err("unterminated string".to_owned()),
+                    }
+                }
+                tokens.push(Token::Str(lit));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Op(match (c, eq) {
+                    ('=', true) => "==",
+                    ('!', true) => "!=",
+                    ('<', true) => "<=",
+                    ('>', true) => ">=",
+                    ('<', false) => "<",
+                    ('>', false) => ">",
+                    _ => return Err(format!("unexpected `{}`", c)),
+                }));
+            }
+            c if c.is_ascii_digit() => {
+                let mut num = String::new();
+                while let Some(x) = chars.next_if(|x| x.is_ascii_digit()) {
+                    num.push(x);
+                }
+                tokens.push(Token::Int(
+                    num.parse().map_err(|_| format!("invalid number {}", num))?,
+                ));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(x) = chars.next_if(|x| x.is_alphanumeric() || *x == '_') {
+                    ident.push(x);
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            _ => return Err(format!("unexpected `{}`", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "'{}'", s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Bool(true) => f.write_str("True"),
+            Value::Bool(false) => f.write_str("False"),
+            Value::List(l) => {
+                let items = l.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Func {
+    InCidr,
+    GeoIp,
+    StartsWith,
+    EndsWith,
+}
+
+impl Func {
+    fn name(&self) -> &'static str {
+        match self {
+            Func::InCidr => "in_cidr",
+            Func::GeoIp => "geoip",
+            Func::StartsWith => "startswith",
+            Func::EndsWith => "endswith",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Lit(Value),
+    Field(&'static str),
+    List(Vec<Node>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Cmp(&'static str, Box<Node>, Box<Node>),
+    In(Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+/// how deep the parentheses, lists, calls and `not`s may nest
+const MAX_DEPTH: usize = 32;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// Parse a nested part, so a long run of `(` can't exhaust the stack.
+    fn nested(&mut self, f: fn(&mut Self) -> Result<Node, String>) -> Result<Node, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("nested deeper than {}", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let node = f(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, t: &Token) -> bool {
+        if self.peek() == Some(t) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        self.eat(&Token::Ident(keyword.to_owned()))
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut lhs = self.and()?;
+        while self.eat_keyword("or") {
+            lhs = Node::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut lhs = self.not()?;
+        while self.eat_keyword("and") {
+            lhs = Node::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<Node, String> {
+        if self.eat_keyword("not") {
+            return Ok(Node::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.cmp()
+    }
+
+    fn cmp(&mut self) -> Result<Node, String> {
+        let lhs = self.postfix()?;
+        if let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            self.pos += 1;
+            return Ok(Node::Cmp(op, Box::new(lhs), Box::new(self.postfix()?)));
+        }
+        if self.eat_keyword("in") {
+            return Ok(Node::In(Box::new(lhs), Box::new(self.postfix()?)));
+        }
+        if self.peek() == Some(&Token::Ident("not".to_owned()))
+            && self.tokens.get(self.pos + 1) == Some(&Token::Ident("in".to_owned()))
+        {
+            self.pos += 2;
+            let rhs = self.postfix()?;
+            return Ok(Node::Not(Box::new(Node::In(Box::new(lhs), Box::new(rhs)))));
+        }
+        Ok(lhs)
+    }
+
+    fn postfix(&mut self) -> Result<Node, String> {
+        let mut node = self.primary()?;
+        while self.eat(&Token::Dot) {
+            let func = match self.next() {
+                Some(Token::Ident(x)) if x == "startswith" => Func::StartsWith,
+                Some(Token::Ident(x)) if x == "endswith" => Func::EndsWith,
+                Some(Token::Ident(x)) => return Err(format!("unknown method {}", x)),
+                _ => return Err("expected a method after `.`".to_owned()),
+            };
+            if !self.eat(&Token::LParen) {
+                return Err("expected `(`".to_owned());
+            }
+            let mut args = vec![node];
+            args.extend(self.args(&Token::RParen)?);
+            node = call(func, args)?;
+        }
+        Ok(node)
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Node::Lit(Value::Str(s))),
+            Some(Token::Int(i)) => Ok(Node::Lit(Value::Int(i))),
+            Some(Token::LParen) => {
+                let node = self.nested(Self::or)?;
+                if !self.eat(&Token::RParen) {
+                    return Err("expected `)`".to_owned());
+                }
+                Ok(node)
+            }
+            Some(Token::LBracket) => Ok(Node::List(self.args(&Token::RBracket)?)),
+            Some(Token::Ident(x)) if x == "True" => Ok(Node::Lit(Value::Bool(true))),
+            Some(Token::Ident(x)) if x == "False" => Ok(Node::Lit(Value::Bool(false))),
+            Some(Token::Ident(x)) if self.eat(&Token::LParen) => {
+                let func = match x.as_str() {
+                    "in_cidr" => Func::InCidr,
+                    "geoip" => Func::GeoIp,
+                    _ => return Err(format!("unknown function {}", x)),
+                };
+                let args = self.args(&Token::RParen)?;
+                call(func, args)
+            }
+            Some(Token::Ident(x)) => match FIELDS.iter().find(|f| **f == x) {
+                Some(f) => Ok(Node::Field(f)),
+                None => Err(format!("unknown name {}", x)),
+            },
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err("unexpected end".to_owned()),
+        }
+    }
+
+    /// The comma separated expressions up to `close`.
+    fn args(&mut self, close: &Token) -> Result<Vec<Node>, String> {
+        let mut args = vec![];
+        while !self.eat(close) {
+            args.push(self.nested(Self::or)?);
+            if !self.eat(&Token::Comma) && self.peek() != Some(close) {
+                return Err(format!("expected `,` or {:?}", close));
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// The call, its arguments checked as far as they're known.
+fn call(func: Func, args: Vec<Node>) -> Result<Node, String> {
+    let arity = match func {
+        Func::GeoIp => 1,
+        Func::InCidr | Func::StartsWith | Func::EndsWith => 2,
+    };
+    if args.len() != arity {
+        return Err(format!(
+            "{} takes {} arguments, got {}",
+            func.name(),
+            arity,
+            args.len()
+        ));
+    }
+    if let (Func::InCidr, Some(Node::Lit(Value::Str(cidr)))) = (func, args.get(1)) {
+        cidr.parse::<ipnet::IpNet>()
+            .map_err(|_| format!("invalid cidr {}", cidr))?;
+    }
+    Ok(Node::Call(func, args))
+}
+
+/// A parsed expression, evaluated against each session.
+#[derive(Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(code)?,
+            pos: 0,
+            depth: 0,
+        };
+        let root = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("unexpected {:?}", parser.tokens[parser.pos]));
+        }
+        Ok(Self { root })
+    }
+
+    /// Whether the expression looks at the field, e.g. `dst_ip` needs the
+    /// domain resolved first.
+    pub fn uses(&self, field: &str) -> bool {
+        fn walk(node: &Node, field: &str) -> bool {
+            match node {
+                Node::Lit(_) => false,
+                Node::Field(f) => *f == field,
+                Node::List(l) | Node::Call(_, l) => l.iter().any(|x| walk(x, field)),
+                Node::Not(x) => walk(x, field),
+                Node::And(a, b) | Node::Or(a, b) | Node::Cmp(_, a, b) | Node::In(a, b) => {
+                    walk(a, field) || walk(b, field)
+                }
+            }
+        }
+        walk(&self.root, field)
+    }
+
+    pub fn eval(&self, sess: &Session, geoip: GeoIpLookup) -> Result<bool, String> {
+        as_bool(eval(&self.root, sess, geoip)?)
+    }
+}
+
+fn as_bool(v: Value) -> Result<bool, String> {
+    match v {
+        Value::Bool(b) => Ok(b),
+        v => Err(format!("{} is not a boolean", v)),
+    }
+}
+
+fn as_str(v: Value) -> Result<String, String> {
+    match v {
+        Value::Str(s) => Ok(s),
+        v => Err(format!("{} is not a string", v)),
+    }
+}
+
+fn field(name: &str, sess: &Session) -> Value {
+    let str = |x: Option<String>| Value::Str(x.unwrap_or_default());
+    match name {
+        "host" => str(sess.destination.domain().map(|x| x.to_owned())),
+        "dst_ip" => str(sess.destination_ip().map(|x| x.to_string())),
+        "dst_port" => Value::Int(sess.destination.port() as i64),
+        "src_ip" => Value::Str(sess.source.ip().to_string()),
+        "src_port" => Value::Int(sess.source.port() as i64),
+        "network" => Value::Str(
+            match sess.network {
+                Network::Tcp => "tcp",
+                Network::Udp => "udp",
+            }
+            .to_owned(),
+        ),
+        "type" => Value::Str(format!("{:?}", sess.typ).to_lowercase()),
+        "process_name" => str(sess.process_path.as_ref().and_then(|x| {
+            Path::new(x)
+                .file_name()
+                .map(|x| x.to_string_lossy().into_owned())
+        })),
+        "process_path" => str(sess.process_path.clone()),
+        _ => unreachable!("unknown field {}", name),
+    }
+}
+
+fn eval(node: &Node, sess: &Session, geoip: GeoIpLookup) -> Result<Value, String> {
+    let sub = |x: &Node| eval(x, sess, geoip);
+    Ok(match node {
+        Node::Lit(v) => v.clone(),
+        Node::Field(f) => field(f, sess),
+        Node::List(l) => Value::List(l.iter().map(sub).collect::<Result<_, _>>()?),
+        Node::Not(x) => Value::Bool(!as_bool(sub(x)?)?),
+        Node::And(a, b) => Value::Bool(as_bool(sub(a)?)? && as_bool(sub(b)?)?),
+        Node::Or(a, b) => Value::Bool(as_bool(sub(a)?)? || as_bool(sub(b)?)?),
+        Node::Cmp(op, a, b) => {
+            let (a, b) = (sub(a)?, sub(b)?);
+            let ord = match (&a, &b) {
+                (Value::Int(x), Value::Int(y)) => Some(x.cmp(y)),
+                (Value::Str(x), Value::Str(y)) => Some(x.cmp(y)),
+                _ => None,
+            };
+            Value::Bool(match (*op, ord) {
+                ("==", _) => a == b,
+                ("!=", _) => a != b,
+                ("<", Some(o)) => o.is_lt(),
+                ("<=", Some(o)) => o.is_le(),
+                (">", Some(o)) => o.is_gt(),
+                (">=", Some(o)) => o.is_ge(),
+                _ => return Err(format!("can't compare {} and {}", a, b)),
+            })
+        }
+        Node::In(item, container) => match (sub(item)?, sub(container)?) {
+            (Value::Str(x), Value::Str(s)) => Value::Bool(s.contains(&x)),
+            (x, Value::List(l)) => Value::Bool(l.contains(&x)),
+            (_, v) => return Err(format!("{} is not a string or a list", v)),
+        },
+        Node::Call(func, args) => {
+            let mut args = args
+                .iter()
+                .map(sub)
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter();
+            let mut arg = || as_str(args.next().unwrap());
+            match func {
+                Func::InCidr => {
+                    let (ip, cidr) = (arg()?, arg()?);
+                    let cidr = cidr
+                        .parse::<ipnet::IpNet>()
+                        .map_err(|_| format!("invalid cidr {}", cidr))?;
+                    // an unresolved domain is in no network
+                    Value::Bool(ip.parse::<IpAddr>().is_ok_and(|ip| cidr.contains(&ip)))
+                }
+                Func::GeoIp => Value::Str(
+                    arg()?
+                        .parse::<IpAddr>()
+                        .ok()
+                        .and_then(geoip)
+                        .unwrap_or_default(),
+                ),
+                Func::StartsWith => {
+                    let (s, prefix) = (arg()?, arg()?);
+                    Value::Bool(s.starts_with(&prefix))
+                }
+                Func::EndsWith => {
+                    let (s, suffix) = (arg()?, arg()?);
+                    Value::Bool(s.ends_with(&suffix))
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::session::{Network, Session, SocksAddr};
+
+    use super::{tokenize, Expr, Token};
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("dst_port >= 443 and host.endswith(\"a.com\")").unwrap(),
+            vec![
+                Token::Ident("dst_port".to_owned()),
+                Token::Op(">="),
+                Token::Int(443),
+                Token::Ident("and".to_owned()),
+                Token::Ident("host".to_owned()),
+                Token::Dot,
+                Token::Ident("endswith".to_owned()),
+                Token::LParen,
+                Token::Str("a.com".to_owned()),
+                Token::RParen,
+            ]
+        );
+        assert!(tokenize("host == 'a.com").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let expr = Expr::parse("network == 'udp' and dst_port in [443, 8443]").unwrap();
+        assert!(!expr.uses("dst_ip"));
+        let expr = Expr::parse("not in_cidr(dst_ip, '10.0.0.0/8')").unwrap();
+        assert!(expr.uses("dst_ip"));
+
+        for invalid in [
+            "",
+            "hostname == 'a.com'",
+            "host ==",
+            "in_cidr(dst_ip, '10.0.0.0/33')",
+            "geoip(dst_ip, src_ip) == 'CN'",
+            "host.lower() == 'a.com'",
+            "(host == 'a.com'",
+            "host == 'a.com' host",
+        ] {
+            assert!(Expr::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let nested = |n| format!("{}True{}", "(".repeat(n), ")".repeat(n));
+        assert!(Expr::parse(&nested(10)).is_ok());
+        assert!(Expr::parse(&nested(10000)).is_err());
+        assert!(Expr::parse(&format!("{}True", "not ".repeat(10000))).is_err());
+    }
+
+    #[test]
+    fn test_eval() {
+        let sess = Session {
+            network: Network::Udp,
+            destination: SocksAddr::Ip("1.1.1.1:443".parse().unwrap()),
+            process_path: Some("/usr/bin/curl".to_owned()),
+            ..Default::default()
+        };
+        let geoip = |_: IpAddr| Some("AU".to_owned());
+        let eval = |code: &str| Expr::parse(code).unwrap().eval(&sess, &geoip);
+
+        assert_eq!(eval("network == 'udp' and dst_port == 443"), Ok(true));
+        assert_eq!(eval("in_cidr(dst_ip, '1.1.1.0/24')"), Ok(true));
+        assert_eq!(eval("geoip(dst_ip) in ['CN', 'HK']"), Ok(false));
+        assert_eq!(eval("process_name == 'curl' or host == ''"), Ok(true));
+        assert_eq!(
+            eval("not host.endswith('.cn') and dst_port >= 1024"),
+            Ok(false)
+        );
+        assert!(eval("dst_port > 'a'").is_err());
+        assert!(eval("dst_port").is_err());
+
+        // a domain resolved for the IP rules keeps its host
+        let sess = Session {
+            destination: SocksAddr::Domain("one.one.one.one".to_owned(), 443),
+            resolved_ip: Some("1.1.1.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let expr =
+            Expr::parse("host == 'one.one.one.one' and in_cidr(dst_ip, '1.1.1.0/24')").unwrap();
+        assert_eq!(expr.eval(&sess, &geoip), Ok(true));
+    }
+}
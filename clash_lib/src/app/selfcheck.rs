@@ -0,0 +1,323 @@
+//! The checks run once everything is started, so a broken setup shows at
+//! boot instead of on the first connection. The report is logged as a table
+//! and served at `GET /health`, next to the readiness and liveness probes.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use network_interface::{Addr, NetworkInterfaceConfig};
+use serde::Serialize;
+use tokio::{net::TcpStream, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::{
+    app::{dns::ThreadSafeDNSResolver, inbound::manager::ThreadSafeInboundManager},
+    config::internal::config::BindAddress,
+    proxy::utils::Interface,
+    GlobalState,
+};
+
+/// the listeners are bound by the spawned runners, give them a moment
+const SETTLE: Duration = Duration::from_secs(1);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// a busier runtime is considered stuck
+const MAX_SCHEDULING_DELAY: Duration = Duration::from_secs(1);
+/// resolved through the upstreams to tell if they answer
+const PROBE_DOMAIN: &str = "www.example.com";
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Failed,
+    /// not enabled in the config
+    Skipped,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    /// none of the checks failed
+    pub healthy: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn new(checks: Vec<Check>) -> Self {
+        Self {
+            healthy: checks.iter().all(|x| x.status != Status::Failed),
+            checks,
+        }
+    }
+
+    fn table(&self) -> String {
+        let width = self.checks.iter().map(|x| x.name.len()).max().unwrap_or(0);
+        self.checks
+            .iter()
+            .map(|x| {
+                let status = match x.status {
+                    Status::Ok => "ok",
+                    Status::Failed => "FAILED",
+                    Status::Skipped => "skipped",
+                };
+                format!("  {:<width$}  {:<7}  {}", x.name, status, x.detail)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// What the self-check looks at.
+pub struct Targets {
+    pub inbound_manager: ThreadSafeInboundManager,
+    pub global_state: Arc<Mutex<GlobalState>>,
+    pub mmdb: PathBuf,
+    pub resolver: ThreadSafeDNSResolver,
+    pub controller: Option<String>,
+}
+
+/// Run the checks, log the table and keep the report for `GET /health`.
+pub async fn run(targets: Targets) {
+    tokio::time::sleep(SETTLE).await;
+
+    let mut checks = check_listeners(&targets.inbound_manager).await;
+    checks.push(check_tun(&targets.global_state).await);
+    checks.push(check_mmdb(&targets.mmdb));
+    checks.push(check_dns(&targets.resolver).await);
+
+    match targets.controller {
+        Some(addr) => match addr.parse::<SocketAddr>() {
+            Ok(mut addr) => {
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(Ipv4Addr::LOCALHOST.into());
+                }
+                checks.push(probe_port("controller", addr).await);
+            }
+            Err(_) => checks.push(Check::new(
+                "controller",
+                Status::Failed,
+                format!("invalid address {}", addr),
+            )),
+        },
+        None => checks.push(Check::new("controller", Status::Skipped, "not enabled")),
+    }
+
+    let report = Report::new(checks);
+    if report.healthy {
+        info!("self-check passed:\n{}", report.table());
+    } else {
+        warn!("self-check failed:\n{}", report.table());
+    }
+    targets.global_state.lock().await.self_check = Some(report);
+}
+
+/// Whether the config is loaded and the listeners bound, for
+/// `GET /health/ready`.
+pub async fn ready(
+    inbound_manager: &ThreadSafeInboundManager,
+    global_state: &Mutex<GlobalState>,
+) -> Report {
+    // the state is locked while a config is reloaded
+    let config = match tokio::time::timeout(PROBE_TIMEOUT, global_state.lock()).await {
+        Ok(global_state) => Check::new(
+            "config",
+            Status::Ok,
+            format!("loaded from {}", global_state.cwd),
+        ),
+        Err(_) => Check::new("config", Status::Failed, "still reloading"),
+    };
+    let mut checks = vec![config];
+    checks.extend(check_listeners(inbound_manager).await);
+    Report::new(checks)
+}
+
+/// Whether the runtime still schedules the tasks and the resolver still
+/// answers, for `GET /health/live`.
+pub async fn live(resolver: &ThreadSafeDNSResolver) -> Report {
+    let start = Instant::now();
+    let runtime = match tokio::spawn(async {}).await {
+        Ok(_) if start.elapsed() < MAX_SCHEDULING_DELAY => Check::new(
+            "event loop",
+            Status::Ok,
+            format!("task scheduled in {:?}", start.elapsed()),
+        ),
+        Ok(_) => Check::new(
+            "event loop",
+            Status::Failed,
+            format!("task scheduled in {:?}", start.elapsed()),
+        ),
+        Err(e) => Check::new("event loop", Status::Failed, e.to_string()),
+    };
+    Report::new(vec![runtime, check_dns(resolver).await])
+}
+
+async fn check_listeners(inbound_manager: &ThreadSafeInboundManager) -> Vec<Check> {
+    let (listeners, bind_address) = {
+        let inbound_manager = inbound_manager.lock().await;
+        (
+            inbound_manager.get_listeners(),
+            inbound_manager.get_bind_address().clone(),
+        )
+    };
+
+    let mut checks = vec![];
+    for (name, port) in listeners {
+        let name = format!("inbound {}", name);
+        match probe_ip(&bind_address) {
+            Some(ip) => checks.push(probe_port(name, (ip, port).into()).await),
+            None => checks.push(Check::new(
+                name,
+                Status::Failed,
+                format!("no address to listen on at {}", bind_address),
+            )),
+        }
+    }
+    checks
+}
+
+/// The address the listeners can be reached at from this host.
+fn probe_ip(bind_address: &BindAddress) -> Option<IpAddr> {
+    match bind_address {
+        BindAddress::Any => Some(Ipv4Addr::LOCALHOST.into()),
+        BindAddress::One(Interface::IpAddr(ip)) => Some(*ip),
+        BindAddress::One(Interface::Name(iface)) => network_interface::NetworkInterface::show()
+            .ok()?
+            .into_iter()
+            .filter(|x| &x.name == iface)
+            .flat_map(|x| x.addr)
+            .find_map(|x| match x {
+                Addr::V4(v4) if !v4.ip.is_unspecified() && !v4.ip.is_link_local() => {
+                    Some(v4.ip.into())
+                }
+                _ => None,
+            }),
+    }
+}
+
+async fn probe_port(name: impl Into<String>, addr: SocketAddr) -> Check {
+    match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Check::new(name, Status::Ok, format!("listening at {}", addr)),
+        Ok(Err(e)) => Check::new(name, Status::Failed, format!("{}: {}", addr, e)),
+        Err(_) => Check::new(name, Status::Failed, format!("{}: timed out", addr)),
+    }
+}
+
+/// The device is created before the runner, so a missing permission fails
+/// the startup; a running device can still stop on its own.
+async fn check_tun(global_state: &Mutex<GlobalState>) -> Check {
+    let global_state = global_state.lock().await;
+    if !global_state.tun_enable {
+        return Check::new("tun", Status::Skipped, "not enabled");
+    }
+    match &global_state.tunnel_listener_handle {
+        Some(handle) if !handle.is_finished() => Check::new(
+            "tun",
+            Status::Ok,
+            format!("running on {}", global_state.tun_device),
+        ),
+        _ => Check::new(
+            "tun",
+            Status::Failed,
+            format!("{} stopped, see the logs", global_state.tun_device),
+        ),
+    }
+}
+
+fn check_mmdb(path: &Path) -> Check {
+    match std::fs::metadata(path) {
+        Ok(m) => Check::new(
+            "mmdb",
+            Status::Ok,
+            format!("{} ({} KB)", path.display(), m.len() / 1024),
+        ),
+        Err(e) => Check::new("mmdb", Status::Failed, format!("{}: {}", path.display(), e)),
+    }
+}
+
+async fn check_dns(resolver: &ThreadSafeDNSResolver) -> Check {
+    match tokio::time::timeout(PROBE_TIMEOUT, resolver.resolve(PROBE_DOMAIN, false)).await {
+        Ok(Ok(Some(ip))) => Check::new(
+            "dns upstream",
+            Status::Ok,
+            format!("{} resolved to {}", PROBE_DOMAIN, ip),
+        ),
+        Ok(Ok(None)) => Check::new(
+            "dns upstream",
+            Status::Failed,
+            format!("no answer for {}", PROBE_DOMAIN),
+        ),
+        Ok(Err(e)) => Check::new("dns upstream", Status::Failed, e.to_string()),
+        Err(_) => Check::new(
+            "dns upstream",
+            Status::Failed,
+            format!("{} timed out", PROBE_DOMAIN),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::app::dns::{MockClashResolver, ThreadSafeDNSResolver};
+
+    use super::{live, probe_port, Check, Report, Status};
+
+    #[tokio::test]
+    async fn test_probe_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let ok = probe_port("inbound HTTP", addr).await;
+        assert_eq!(ok.status, Status::Ok);
+
+        drop(listener);
+        let failed = probe_port("inbound HTTP", addr).await;
+        assert_eq!(failed.status, Status::Failed);
+
+        let report = Report::new(vec![
+            ok,
+            failed,
+            Check::new("tun", Status::Skipped, "not enabled"),
+        ]);
+        assert!(!report.healthy);
+        assert_eq!(report.table().lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_live() {
+        let mut resolver = MockClashResolver::new();
+        resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some("93.184.215.14".parse().unwrap())));
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+        assert!(live(&resolver).await.healthy);
+
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_resolve().returning(|_, _| Ok(None));
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+        let report = live(&resolver).await;
+        assert!(!report.healthy);
+        assert_eq!(report.checks[0].status, Status::Ok);
+    }
+}
@@ -0,0 +1,75 @@
+//! Certificates of the TLS server inbounds obtained and renewed with ACME
+//!
+//! The TLS-ALPN-01 challenge is answered on the listener itself, so only
+//! its port, usually 443, needs to be reachable. The account and the
+//! certificates are cached in a directory to survive restarts.
+
+use std::path::Path;
+
+use futures::StreamExt;
+use rustls_acme::{acme::ACME_TLS_ALPN_NAME, caches::DirCache, AcmeConfig};
+use tracing::{debug, info, warn};
+
+use crate::{common::tls, config::internal::config::AcmeOptions, Error};
+
+/// Renews the certificates as long as it's alive.
+pub struct Acme {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Acme {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A server config with the certificates of `opts.domains`, answering the
+/// challenges too, the connections negotiating [`is_challenge`] are done
+/// once the handshake is.
+pub fn server_config(
+    opts: &AcmeOptions,
+    cwd: &Path,
+    alpn: &[String],
+    client_ca: Option<&Path>,
+) -> Result<(rustls::ServerConfig, Acme), Error> {
+    if opts.domains.is_empty() {
+        return Err(Error::InvalidConfig(
+            "acme requires at least one domain".to_owned(),
+        ));
+    }
+
+    let mut state = AcmeConfig::new(opts.domains.clone())
+        .contact(opts.email.iter().map(|x| format!("mailto:{}", x)))
+        .cache(DirCache::new(cwd.join(&opts.dir)))
+        .directory_lets_encrypt(!opts.staging)
+        .state();
+
+    let mut cfg = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(tls::client_verifier(client_ca)?)
+        .with_cert_resolver(state.resolver());
+    cfg.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+    cfg.alpn_protocols.push(ACME_TLS_ALPN_NAME.to_vec());
+
+    let domains = opts.domains.join(", ");
+    let task = tokio::spawn(async move {
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => {
+                    debug!("acme event for {}: {:?}", domains, event);
+                    if matches!(event, rustls_acme::EventOk::DeployedNewCert) {
+                        info!("acme certificate for {} deployed", domains);
+                    }
+                }
+                Err(e) => warn!("acme for {} failed: {:?}", domains, e),
+            }
+        }
+    });
+
+    Ok((cfg, Acme { task }))
+}
+
+/// Whether the connection is the validation of a challenge.
+pub fn is_challenge(conn: &rustls::ServerConnection) -> bool {
+    conn.alpn_protocol() == Some(ACME_TLS_ALPN_NAME)
+}
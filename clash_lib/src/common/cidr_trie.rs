@@ -0,0 +1,222 @@
+//! A path compressed binary trie of IP prefixes
+//!
+//! A lookup walks at most one node per distinct prefix length on the path to
+//! the address, instead of testing every CIDR in turn, which matters for the
+//! country sized lists (thousands of prefixes) gateways route on.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+struct Node<T> {
+    /// the prefix bits, left aligned in 128 bits, IPv4 included
+    prefix: u128,
+    len: u8,
+    value: Option<T>,
+    children: [Option<Box<Node<T>>>; 2],
+}
+
+impl<T> Node<T> {
+    fn new(prefix: u128, len: u8, value: Option<T>) -> Self {
+        Self {
+            prefix,
+            len,
+            value,
+            children: [None, None],
+        }
+    }
+
+    fn covers(&self, key: u128) -> bool {
+        self.len == 0 || (self.prefix ^ key) >> (128 - self.len as u32) == 0
+    }
+}
+
+pub struct CidrTrie<T> {
+    v4: Option<Box<Node<T>>>,
+    v6: Option<Box<Node<T>>>,
+}
+
+impl<T> Default for CidrTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> CidrTrie<T> {
+    pub fn new() -> Self {
+        Self { v4: None, v6: None }
+    }
+
+    /// Insert a prefix, a prefix inserted twice keeps the first value.
+    pub fn insert(&mut self, net: IpNet, value: T) {
+        let (root, key, len) = match net.trunc() {
+            IpNet::V4(v4) => (
+                &mut self.v4,
+                (u32::from(v4.addr()) as u128) << 96,
+                v4.prefix_len(),
+            ),
+            IpNet::V6(v6) => (&mut self.v6, u128::from(v6.addr()), v6.prefix_len()),
+        };
+        insert(root, key, len, value);
+    }
+
+    /// All the values of the prefixes containing `ip`, shortest prefix first.
+    pub fn matches(&self, ip: IpAddr) -> Matches<'_, T> {
+        let (root, key) = match ip {
+            IpAddr::V4(v4) => (&self.v4, (u32::from(v4) as u128) << 96),
+            IpAddr::V6(v6) => (&self.v6, u128::from(v6)),
+        };
+        Matches {
+            node: root.as_deref(),
+            key,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.matches(ip).next().is_some()
+    }
+}
+
+pub struct Matches<'a, T> {
+    node: Option<&'a Node<T>>,
+    key: u128,
+}
+
+impl<'a, T> Iterator for Matches<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.node {
+            if !node.covers(self.key) {
+                self.node = None;
+                return None;
+            }
+            self.node = if node.len < 128 {
+                node.children[bit(self.key, node.len)].as_deref()
+            } else {
+                None
+            };
+            if let Some(value) = node.value.as_ref() {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+fn bit(key: u128, pos: u8) -> usize {
+    ((key >> (127 - pos as u32)) & 1) as usize
+}
+
+fn mask(key: u128, len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        key & (u128::MAX << (128 - len as u32))
+    }
+}
+
+fn insert<T>(slot: &mut Option<Box<Node<T>>>, key: u128, len: u8, value: T) {
+    let mut node = match slot.take() {
+        Some(node) => node,
+        None => {
+            *slot = Some(Box::new(Node::new(key, len, Some(value))));
+            return;
+        }
+    };
+
+    let common = ((node.prefix ^ key).leading_zeros() as u8)
+        .min(node.len)
+        .min(len);
+
+    if common == node.len && common == len {
+        if node.value.is_none() {
+            node.value = Some(value);
+        }
+    } else if common == node.len {
+        // the node is a prefix of the new one
+        insert(&mut node.children[bit(key, common)], key, len, value)
+    } else {
+        let mut parent = if common == len {
+            // the new one is a prefix of the node
+            Box::new(Node::new(key, len, Some(value)))
+        } else {
+            // they diverge, branch where they do
+            let mut branch = Box::new(Node::new(mask(key, common), common, None));
+            branch.children[bit(key, common)] = Some(Box::new(Node::new(key, len, Some(value))));
+            branch
+        };
+        let pos = bit(node.prefix, common);
+        parent.children[pos] = Some(node);
+        *slot = Some(parent);
+        return;
+    }
+
+    *slot = Some(node);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+
+    use super::CidrTrie;
+
+    #[test]
+    fn test_matches_like_linear_scan() {
+        let nets: Vec<IpNet> = [
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "10.1.2.0/24",
+            "10.128.0.0/9",
+            "192.168.1.0/24",
+            "192.168.0.0/16",
+            "0.0.0.0/0",
+            "1.2.3.4/32",
+            "2001:db8::/32",
+            "2001:db8:1::/48",
+            "::1/128",
+        ]
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+
+        let mut trie = CidrTrie::new();
+        for (i, net) in nets.iter().enumerate() {
+            trie.insert(*net, i);
+        }
+        // a duplicate keeps the first value
+        trie.insert("10.0.0.0/8".parse().unwrap(), 100);
+
+        for ip in [
+            "10.1.2.3",
+            "10.1.3.3",
+            "10.200.0.1",
+            "10.2.0.1",
+            "192.168.1.1",
+            "192.168.2.1",
+            "1.2.3.4",
+            "1.2.3.5",
+            "2001:db8:1::1",
+            "2001:db8:2::1",
+            "::1",
+            "::2",
+        ] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut got: Vec<usize> = trie.matches(ip).copied().collect();
+            got.sort();
+            let want: Vec<usize> = nets
+                .iter()
+                .enumerate()
+                .filter(|(_, net)| net.contains(&ip))
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(got, want, "{}", ip);
+        }
+
+        // the longest prefix last
+        assert_eq!(trie.matches("10.1.2.3".parse().unwrap()).last(), Some(&2));
+        assert!(!CidrTrie::<()>::new().contains("10.1.2.3".parse().unwrap()));
+    }
+}
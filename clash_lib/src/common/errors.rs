@@ -1,4 +1,7 @@
-use std::io;
+use std::{error::Error, io};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 
 pub fn new_io_error(msg: &str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, msg)
@@ -10,3 +13,211 @@ where
 {
     io::Error::new(io::ErrorKind::Other, format!("{:?}", anyhow::anyhow!(err)))
 }
+
+/// A failed dial with the member of a group it went through, so the
+/// error is kept for the proxy rather than for the group.
+#[derive(Debug)]
+struct MemberDialError {
+    proxy: String,
+    source: io::Error,
+}
+
+impl std::fmt::Display for MemberDialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Error for MemberDialError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Tag the error of a group member with its name, the innermost group
+/// tagging it.
+pub fn member_dial_error(proxy: &str, err: io::Error) -> io::Error {
+    if failed_member(&err).is_some() {
+        return err;
+    }
+    io::Error::new(
+        err.kind(),
+        MemberDialError {
+            proxy: proxy.to_owned(),
+            source: err,
+        },
+    )
+}
+
+/// The member a dial failed at, with its own error.
+pub fn failed_member(err: &io::Error) -> Option<(&str, &io::Error)> {
+    err.get_ref()
+        .and_then(|x| x.downcast_ref::<MemberDialError>())
+        .map(|x| (x.proxy.as_str(), &x.source))
+}
+
+/// Where a dial through an outbound failed.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DialStage {
+    DnsResolve,
+    TcpTimeout,
+    TcpRefused,
+    TlsVerify,
+    Tls,
+    ProtocolAuth,
+    /// the whole handshake or the request took too long
+    Timeout,
+    Other,
+}
+
+/// A failed dial as reported by the API, so the cause can be told without
+/// the debug logs.
+#[derive(Serialize, Clone, Debug)]
+pub struct DialError {
+    pub stage: DialStage,
+    /// the messages of the error and of its sources, outermost first
+    pub chain: Vec<String>,
+    pub time: DateTime<Utc>,
+}
+
+impl DialError {
+    pub fn new(err: &(dyn Error + 'static)) -> Self {
+        let sources = sources(err);
+        let stage = sources
+            .iter()
+            .find_map(|e| stage_of(*e))
+            .unwrap_or(DialStage::Other);
+
+        let mut chain: Vec<String> = vec![];
+        for e in sources {
+            let msg = e.to_string();
+            // a wrapping io::Error displays as the error it wraps
+            if chain.last() != Some(&msg) {
+                chain.push(msg);
+            }
+        }
+        Self {
+            stage,
+            chain,
+            time: Utc::now(),
+        }
+    }
+}
+
+/// The error and its sources, including the ones wrapped in an io::Error
+/// which doesn't give them as its source.
+fn sources<'a>(err: &'a (dyn Error + 'static)) -> Vec<&'a (dyn Error + 'static)> {
+    let mut sources = vec![];
+    let mut next = Some(err);
+    while let Some(e) = next {
+        sources.push(e);
+        next = match e.downcast_ref::<io::Error>().and_then(|x| x.get_ref()) {
+            Some(inner) => Some(inner as &(dyn Error + 'static)),
+            None => e.source(),
+        };
+    }
+    sources
+}
+
+fn stage_of(e: &(dyn Error + 'static)) -> Option<DialStage> {
+    if let Some(e) = e.downcast_ref::<rustls::Error>() {
+        return Some(match e {
+            rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented => {
+                DialStage::TlsVerify
+            }
+            _ => DialStage::Tls,
+        });
+    }
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => return Some(DialStage::TcpRefused),
+            io::ErrorKind::PermissionDenied => return Some(DialStage::ProtocolAuth),
+            io::ErrorKind::TimedOut if e.get_ref().is_none() => return Some(DialStage::TcpTimeout),
+            _ => {}
+        }
+    }
+    if e.is::<tokio::time::error::Elapsed>() {
+        return Some(DialStage::TcpTimeout);
+    }
+
+    // the protocols mostly report their failures as messages
+    let msg = e.to_string().to_lowercase();
+    if msg.contains("timed out") || msg.contains("timeout") {
+        Some(DialStage::Timeout)
+    } else if msg.contains("dns") || msg.contains("resolve") {
+        Some(DialStage::DnsResolve)
+    } else if msg.contains("certificate") {
+        Some(DialStage::TlsVerify)
+    } else if msg.contains("auth") || msg.contains("password") {
+        Some(DialStage::ProtocolAuth)
+    } else if msg.contains("tls") {
+        Some(DialStage::Tls)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{failed_member, member_dial_error, new_io_error, DialError, DialStage};
+
+    #[test]
+    fn test_dial_error_stage() {
+        let stage = |e: io::Error| DialError::new(&e).stage;
+
+        assert_eq!(
+            stage(new_io_error("can't resolve dns: example.com")),
+            DialStage::DnsResolve
+        );
+        assert_eq!(
+            stage(io::ErrorKind::ConnectionRefused.into()),
+            DialStage::TcpRefused
+        );
+        assert_eq!(stage(io::ErrorKind::TimedOut.into()), DialStage::TcpTimeout);
+        assert_eq!(
+            stage(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "outbound handshake timed out"
+            )),
+            DialStage::Timeout
+        );
+        assert_eq!(
+            stage(io::Error::new(
+                io::ErrorKind::InvalidData,
+                rustls::Error::InvalidCertificate(rustls::CertificateError::Expired),
+            )),
+            DialStage::TlsVerify
+        );
+        assert_eq!(
+            stage(new_io_error("hysteria2 authentication failed: 404")),
+            DialStage::ProtocolAuth
+        );
+        assert_eq!(stage(new_io_error("unexpected eof")), DialStage::Other);
+    }
+
+    #[test]
+    fn test_dial_error_chain() {
+        let inner = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Expired),
+        );
+        let e = DialError::new(&inner);
+        assert_eq!(e.chain.len(), 1);
+        assert_eq!(e.stage, DialStage::TlsVerify);
+    }
+
+    #[test]
+    fn test_member_dial_error() {
+        let e = member_dial_error("inner", io::ErrorKind::ConnectionRefused.into());
+        // the outer groups keep the member
+        let e = member_dial_error("group", e);
+        let (proxy, e) = failed_member(&e).unwrap();
+        assert_eq!(proxy, "inner");
+        assert_eq!(DialError::new(e).stage, DialStage::TcpRefused);
+
+        assert!(failed_member(&new_io_error("direct")).is_none());
+    }
+}
@@ -0,0 +1,372 @@
+//! Domain lists of the v2ray `geosite.dat`
+//!
+//! The database is only downloaded when a `GEOSITE` rule needs it, and only
+//! the categories used are decoded, once each, into a matcher shared by the
+//! rules. A category may be narrowed down by the attributes of its domains,
+//! e.g. `cn@ads` or `cn@!ads` for the domains with or without `ads`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use aho_corasick::AhoCorasick;
+use prost::Message;
+use regex::RegexSet;
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::{http::HttpClient, mmdb::Mmdb, trie},
+    Error,
+};
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteList {
+    /// decoded on demand
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    entry: Vec<Vec<u8>>,
+}
+
+/// The code of an entry, the domains are skipped.
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteCode {
+    #[prost(string, tag = "1")]
+    country_code: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoSite {
+    #[prost(string, tag = "1")]
+    country_code: String,
+    #[prost(message, repeated, tag = "2")]
+    domain: Vec<Domain>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Domain {
+    #[prost(enumeration = "DomainType", tag = "1")]
+    r#type: i32,
+    #[prost(string, tag = "2")]
+    value: String,
+    #[prost(message, repeated, tag = "3")]
+    attribute: Vec<Attribute>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Attribute {
+    #[prost(string, tag = "1")]
+    key: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum DomainType {
+    /// a keyword
+    Plain = 0,
+    Regex = 1,
+    /// the domain and its subdomains
+    RootDomain = 2,
+    Full = 3,
+}
+
+/// The domains of a category.
+pub struct GeositeMatcher {
+    full: HashSet<String>,
+    suffix: trie::StringTrie<bool>,
+    keyword: Option<AhoCorasick>,
+    regex: Option<RegexSet>,
+}
+
+impl GeositeMatcher {
+    fn new(domains: Vec<Domain>) -> Self {
+        let mut full = HashSet::new();
+        let mut suffix = trie::StringTrie::new();
+        let mut keywords = vec![];
+        let mut regexes = vec![];
+
+        for d in domains {
+            let value = d.value.to_ascii_lowercase();
+            match DomainType::try_from(d.r#type) {
+                Ok(DomainType::Full) => {
+                    full.insert(value);
+                }
+                Ok(DomainType::RootDomain) => {
+                    suffix.insert(&format!("+.{}", value), Arc::new(true));
+                }
+                Ok(DomainType::Plain) => keywords.push(value),
+                Ok(DomainType::Regex) => regexes.push(d.value),
+                Err(_) => debug!("unknown geosite domain type {}: {}", d.r#type, d.value),
+            }
+        }
+
+        let keyword = (!keywords.is_empty())
+            .then(|| AhoCorasick::new(&keywords))
+            .transpose()
+            .unwrap_or_else(|e| {
+                warn!("invalid geosite keywords: {}", e);
+                None
+            });
+        let regex = (!regexes.is_empty())
+            .then(|| RegexSet::new(&regexes))
+            .transpose()
+            .unwrap_or_else(|e| {
+                warn!("invalid geosite regexes: {}", e);
+                None
+            });
+
+        Self {
+            full,
+            suffix,
+            keyword,
+            regex,
+        }
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.full.contains(&domain)
+            || self.suffix.search(&domain).is_some()
+            || self.keyword.as_ref().is_some_and(|x| x.is_match(&domain))
+            || self.regex.as_ref().is_some_and(|x| x.is_match(&domain))
+    }
+}
+
+/// A category and the attributes its domains must, or must not, have.
+struct Category<'a> {
+    code: String,
+    attrs: Vec<(&'a str, bool)>,
+}
+
+impl<'a> Category<'a> {
+    fn parse(s: &'a str) -> Result<Self, Error> {
+        let mut parts = s.split('@');
+        let code = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let attrs = parts
+            .map(|x| match x.strip_prefix('!') {
+                Some(attr) => (attr, false),
+                None => (x, true),
+            })
+            .collect::<Vec<_>>();
+        if code.is_empty() || attrs.iter().any(|(x, _)| x.is_empty()) {
+            return Err(Error::InvalidConfig(format!(
+                "invalid geosite category: {}",
+                s
+            )));
+        }
+        Ok(Self { code, attrs })
+    }
+
+    fn includes(&self, domain: &Domain) -> bool {
+        self.attrs.iter().all(|(attr, wanted)| {
+            domain
+                .attribute
+                .iter()
+                .any(|x| x.key.eq_ignore_ascii_case(attr))
+                == *wanted
+        })
+    }
+}
+
+/// The encoded entries of the database, keyed by their lowercase code.
+type Entries = HashMap<String, Vec<u8>>;
+
+pub struct Geosite {
+    path: PathBuf,
+    download_url: Option<String>,
+    http_client: HttpClient,
+    entries: Mutex<Option<Arc<Entries>>>,
+    /// keyed by the category as written in the rules
+    matchers: Mutex<HashMap<String, Arc<GeositeMatcher>>>,
+}
+
+impl Geosite {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        download_url: Option<String>,
+        http_client: HttpClient,
+    ) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            download_url,
+            http_client,
+            entries: Mutex::new(None),
+            matchers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Download the database if it's not there yet.
+    pub async fn ensure_downloaded(&self) -> Result<(), Error> {
+        if self.path.exists() {
+            return Ok(());
+        }
+        let url = self.download_url.as_ref().ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "geosite `{}` not found and geosite_download_url is not set",
+                self.path.to_string_lossy()
+            ))
+        })?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".download");
+        let tmp = PathBuf::from(tmp);
+
+        info!("downloading geosite from {}", url);
+        if let Err(e) = Mmdb::download(url, &tmp, &self.http_client).await {
+            let _ = fs::remove_file(&tmp);
+            return Err(Error::InvalidConfig(format!(
+                "geosite download failed: {}",
+                e
+            )));
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// The matcher of a category, e.g. `google` or `cn@!ads`, compiled on
+    /// first use.
+    pub fn matcher(&self, category: &str) -> Result<Arc<GeositeMatcher>, Error> {
+        if let Some(m) = self.matchers.lock().unwrap().get(category) {
+            return Ok(m.clone());
+        }
+
+        let parsed = Category::parse(category)?;
+        let entries = self.entries()?;
+        let entry = entries.get(&parsed.code).ok_or_else(|| {
+            Error::InvalidConfig(format!("geosite category {} not found", parsed.code))
+        })?;
+        let site = GeoSite::decode(entry.as_slice())
+            .map_err(|e| Error::InvalidConfig(format!("invalid geosite entry: {}", e)))?;
+
+        let domains = site
+            .domain
+            .into_iter()
+            .filter(|x| parsed.includes(x))
+            .collect::<Vec<_>>();
+        debug!(
+            "geosite category {} of {} has {} domains",
+            category,
+            site.country_code,
+            domains.len()
+        );
+
+        let m = Arc::new(GeositeMatcher::new(domains));
+        self.matchers
+            .lock()
+            .unwrap()
+            .insert(category.to_owned(), m.clone());
+        Ok(m)
+    }
+
+    fn entries(&self) -> Result<Arc<Entries>, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(e) = entries.as_ref() {
+            return Ok(e.clone());
+        }
+
+        let buf = fs::read(&self.path).map_err(|e| {
+            Error::InvalidConfig(format!(
+                "cant open geosite `{}`: {}",
+                self.path.to_string_lossy(),
+                e
+            ))
+        })?;
+        let loaded = Arc::new(Self::index(&buf)?);
+        *entries = Some(loaded.clone());
+        Ok(loaded)
+    }
+
+    fn index(buf: &[u8]) -> Result<Entries, Error> {
+        let list = GeoSiteList::decode(buf)
+            .map_err(|e| Error::InvalidConfig(format!("invalid geosite: {}", e)))?;
+        list.entry
+            .into_iter()
+            .map(|x| {
+                let code = GeoSiteCode::decode(x.as_slice())
+                    .map_err(|e| Error::InvalidConfig(format!("invalid geosite entry: {}", e)))?
+                    .country_code
+                    .to_ascii_lowercase();
+                Ok((code, x))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::{Attribute, Domain, DomainType, GeoSite, GeoSiteList, Geosite};
+    use crate::common::http::new_http_client;
+
+    fn domain(t: DomainType, value: &str, attrs: &[&str]) -> Domain {
+        Domain {
+            r#type: t as i32,
+            value: value.to_owned(),
+            attribute: attrs
+                .iter()
+                .map(|x| Attribute { key: x.to_string() })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geosite() {
+        let list = GeoSiteList {
+            entry: vec![GeoSite {
+                country_code: "GOOGLE".to_owned(),
+                domain: vec![
+                    domain(DomainType::RootDomain, "google.com", &[]),
+                    domain(DomainType::Full, "www.youtube.com", &[]),
+                    domain(DomainType::Plain, "googleapis", &[]),
+                    domain(DomainType::Regex, r"^gstatic\.[a-z]+$", &[]),
+                    domain(DomainType::RootDomain, "doubleclick.net", &["ads"]),
+                ],
+            }
+            .encode_to_vec()],
+        };
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), list.encode_to_vec()).unwrap();
+
+        let resolver = std::sync::Arc::new(crate::app::dns::SystemResolver::new().unwrap());
+        let geosite = Geosite::new(file.path(), None, new_http_client(resolver).unwrap());
+
+        let m = geosite.matcher("google").unwrap();
+        for d in [
+            "google.com",
+            "mail.google.com",
+            "www.youtube.com",
+            "fonts.googleapis.cn",
+            "gstatic.cn",
+            "ad.doubleclick.net",
+        ] {
+            assert!(m.matches(d), "{}", d);
+        }
+        for d in [
+            "youtube.com",
+            "notgoogle.com",
+            "a.gstatic.cn",
+            "example.com",
+        ] {
+            assert!(!m.matches(d), "{}", d);
+        }
+
+        assert!(geosite
+            .matcher("google@ads")
+            .unwrap()
+            .matches("doubleclick.net"));
+        assert!(!geosite.matcher("google@ads").unwrap().matches("google.com"));
+        assert!(!geosite
+            .matcher("google@!ads")
+            .unwrap()
+            .matches("doubleclick.net"));
+        assert!(geosite
+            .matcher("google@!ads")
+            .unwrap()
+            .matches("google.com"));
+
+        assert!(geosite.matcher("facebook").is_err());
+        assert!(geosite.matcher("google@").is_err());
+    }
+}
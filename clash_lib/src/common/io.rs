@@ -1,16 +1,19 @@
 /// copy of https://github.com/eycorsican/leaf/blob/a77a1e497ae034f3a2a89c8628d5e7ebb2af47f0/leaf/src/common/io.rs
 use std::future::Future;
-use std::io;
+use std::io::{self, IoSlice};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
+use bytes::BufMut;
 use futures::ready;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 #[derive(Debug)]
 pub struct CopyBuffer {
     read_done: bool,
+    /// an error of the reader after some data, returned once it's written
+    read_err: Option<io::Error>,
     need_flush: bool,
     pos: usize,
     cap: usize,
@@ -23,6 +26,7 @@ impl CopyBuffer {
     pub fn new() -> Self {
         Self {
             read_done: false,
+            read_err: None,
             need_flush: false,
             pos: 0,
             cap: 0,
@@ -42,6 +46,7 @@ impl CopyBuffer {
         buf.resize(size, 0);
         Ok(Self {
             read_done: false,
+            read_err: None,
             need_flush: false,
             pos: 0,
             cap: 0,
@@ -66,32 +71,46 @@ impl CopyBuffer {
     {
         loop {
             // If our buffer is empty, then we need to read some data to
-            // continue.
+            // continue. Whatever is ready is read, up to the buffer size, so
+            // many small packets go out in one write rather than a write,
+            // and for the framed protocols a frame, each. Nothing is waited
+            // for, the write goes as soon as the reader has no more.
             if self.pos == self.cap && !self.read_done {
-                let me = &mut *self;
-                let mut buf = ReadBuf::new(&mut me.buf);
-
-                match reader.as_mut().poll_read(cx, &mut buf) {
-                    Poll::Ready(Ok(_)) => (),
-                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
-                    Poll::Pending => {
-                        // Try flushing when the reader has no progress to avoid deadlock
-                        // when the reader depends on buffered writer.
-                        if self.need_flush {
-                            ready!(writer.as_mut().poll_flush(cx))?;
-                            self.need_flush = false;
+                if let Some(err) = self.read_err.take() {
+                    return Poll::Ready(Err(err));
+                }
+                self.pos = 0;
+                self.cap = 0;
+                while self.cap < self.buf.len() {
+                    let me = &mut *self;
+                    let mut buf = ReadBuf::new(&mut me.buf[me.cap..]);
+
+                    match reader.as_mut().poll_read(cx, &mut buf) {
+                        Poll::Ready(Ok(_)) => (),
+                        Poll::Ready(Err(err)) if self.cap > 0 => {
+                            self.read_err = Some(err);
+                            break;
                         }
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending if self.cap > 0 => break,
+                        Poll::Pending => {
+                            // Try flushing when the reader has no progress to avoid deadlock
+                            // when the reader depends on buffered writer.
+                            if self.need_flush {
+                                ready!(writer.as_mut().poll_flush(cx))?;
+                                self.need_flush = false;
+                            }
 
-                        return Poll::Pending;
+                            return Poll::Pending;
+                        }
                     }
-                }
 
-                let n = buf.filled().len();
-                if n == 0 {
-                    self.read_done = true;
-                } else {
-                    self.pos = 0;
-                    self.cap = n;
+                    let n = buf.filled().len();
+                    if n == 0 {
+                        self.read_done = true;
+                        break;
+                    }
+                    self.cap += n;
                 }
             }
 
@@ -129,12 +148,56 @@ impl CopyBuffer {
     }
 }
 
+/// Put the slices of a vectored write, up to `limit` bytes, in `dst` for the
+/// streams framing each write: a frame for all of them rather than one each.
+/// Returns the number of bytes put.
+pub fn gather(bufs: &[IoSlice<'_>], limit: usize, dst: &mut impl BufMut) -> usize {
+    let mut n = 0;
+    for buf in bufs {
+        let take = buf.len().min(limit - n);
+        dst.put_slice(&buf[..take]);
+        n += take;
+        if n == limit {
+            break;
+        }
+    }
+    n
+}
+
+/// The reason a relayed connection was closed by us instead of the peers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reaped {
+    /// no data in either direction for the idle timeout
+    Idle,
+    /// one side closed, and the other side didn't follow within the timeout
+    HalfOpen,
+}
+
+impl std::fmt::Display for Reaped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reaped::Idle => write!(f, "connection idle timeout"),
+            Reaped::HalfOpen => write!(f, "half-open connection timeout"),
+        }
+    }
+}
+
 enum TransferState {
     Running(CopyBuffer),
     ShuttingDown(u64),
     Done,
 }
 
+impl TransferState {
+    fn transferred(&self) -> u64 {
+        match self {
+            TransferState::Running(buf) => buf.amount_transfered(),
+            TransferState::ShuttingDown(count) => *count,
+            TransferState::Done => 0,
+        }
+    }
+}
+
 struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     a: &'a mut A,
     b: &'a mut B,
@@ -146,6 +209,8 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     b_to_a_delay: Option<Pin<Box<tokio::time::Sleep>>>,
     a_to_b_timeout_duration: Duration,
     b_to_a_timeout_duration: Duration,
+    idle_timeout: Option<(Duration, Pin<Box<tokio::time::Sleep>>)>,
+    last_transferred: u64,
 }
 
 impl<'a, A, B> Future for CopyBidirectional<'a, A, B>
@@ -153,7 +218,7 @@ where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-    type Output = io::Result<(u64, u64)>;
+    type Output = io::Result<(u64, u64, Option<Reaped>)>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         // Unpack self into mut refs to each field to avoid borrow check issues.
@@ -168,6 +233,8 @@ where
             b_to_a_delay,
             a_to_b_timeout_duration,
             b_to_a_timeout_duration,
+            idle_timeout,
+            last_transferred,
         } = &mut *self;
 
         let mut a = Pin::new(a);
@@ -187,9 +254,11 @@ where
                             if let Some(delay) = a_to_b_delay {
                                 match delay.as_mut().poll(cx) {
                                     Poll::Ready(()) => {
-                                        *a_to_b =
-                                            TransferState::ShuttingDown(buf.amount_transfered());
-                                        continue;
+                                        return Poll::Ready(Ok((
+                                            *a_to_b_count + a_to_b.transferred(),
+                                            *b_to_a_count + b_to_a.transferred(),
+                                            Some(Reaped::HalfOpen),
+                                        )));
                                     }
                                     Poll::Pending => (),
                                 }
@@ -227,9 +296,11 @@ where
                             if let Some(delay) = b_to_a_delay {
                                 match delay.as_mut().poll(cx) {
                                     Poll::Ready(()) => {
-                                        *b_to_a =
-                                            TransferState::ShuttingDown(buf.amount_transfered());
-                                        continue;
+                                        return Poll::Ready(Ok((
+                                            *a_to_b_count + a_to_b.transferred(),
+                                            *b_to_a_count + b_to_a.transferred(),
+                                            Some(Reaped::HalfOpen),
+                                        )));
                                     }
                                     Poll::Pending => (),
                                 }
@@ -256,21 +327,46 @@ where
 
             match (&a_to_b, &b_to_a) {
                 (TransferState::Done, TransferState::Done) => break,
-                _ => return Poll::Pending,
+                _ => {
+                    if let Some((duration, delay)) = idle_timeout {
+                        let transferred = a_to_b.transferred()
+                            + b_to_a.transferred()
+                            + *a_to_b_count
+                            + *b_to_a_count;
+                        if transferred != *last_transferred {
+                            *last_transferred = transferred;
+                            delay
+                                .as_mut()
+                                .reset(tokio::time::Instant::now() + *duration);
+                        }
+                        if delay.as_mut().poll(cx).is_ready() {
+                            return Poll::Ready(Ok((
+                                *a_to_b_count + a_to_b.transferred(),
+                                *b_to_a_count + b_to_a.transferred(),
+                                Some(Reaped::Idle),
+                            )));
+                        }
+                    }
+                    return Poll::Pending;
+                }
             }
         }
 
-        Poll::Ready(Ok((*a_to_b_count, *b_to_a_count)))
+        Poll::Ready(Ok((*a_to_b_count, *b_to_a_count, None)))
     }
 }
 
+/// Copy between `a` and `b` until both sides are closed, or a timeout reaps
+/// the connection. Returns the bytes copied each way, and the reason if
+/// reaped.
 pub async fn copy_buf_bidirectional_with_timeout<A, B>(
     a: &mut A,
     b: &mut B,
     size: usize,
     a_to_b_timeout_duration: Duration,
     b_to_a_timeout_duration: Duration,
-) -> Result<(u64, u64), std::io::Error>
+    idle_timeout: Option<Duration>,
+) -> Result<(u64, u64, Option<Reaped>), std::io::Error>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -286,6 +382,137 @@ where
         b_to_a_delay: None,
         a_to_b_timeout_duration,
         b_to_a_timeout_duration,
+        idle_timeout: idle_timeout.map(|x| (x, Box::pin(tokio::time::sleep(x)))),
+        last_transferred: 0,
     }
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::poll_fn,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+    use super::{copy_buf_bidirectional_with_timeout, gather, CopyBuffer, Reaped};
+
+    /// records the writes
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl AsyncWrite for Writes {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_small_reads_coalesced() {
+        let mut reader = tokio_test::io::Builder::new()
+            .read(b"a")
+            .read(b"bc")
+            .read(b"def")
+            .wait(Duration::from_millis(10))
+            .read(b"ghij")
+            .build();
+        let mut writer = Writes::default();
+        let mut buf = CopyBuffer::new_with_capacity(8).unwrap();
+
+        let n = poll_fn(|cx| buf.poll_copy(cx, Pin::new(&mut reader), Pin::new(&mut writer)))
+            .await
+            .unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(writer.0, vec![b"abcdef".to_vec(), b"ghij".to_vec()]);
+    }
+
+    #[test]
+    fn test_gather() {
+        let bufs = [io::IoSlice::new(b"ab"), io::IoSlice::new(b"cde")];
+        let mut dst = Vec::new();
+        assert_eq!(gather(&bufs, 16, &mut dst), 5);
+        assert_eq!(dst, b"abcde");
+
+        dst.clear();
+        assert_eq!(gather(&bufs, 3, &mut dst), 3);
+        assert_eq!(dst, b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_read_error_after_data() {
+        let mut reader = tokio_test::io::Builder::new()
+            .read(b"abc")
+            .read_error(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            .build();
+        let mut writer = Writes::default();
+        let mut buf = CopyBuffer::new_with_capacity(8).unwrap();
+
+        let err = poll_fn(|cx| buf.poll_copy(cx, Pin::new(&mut reader), Pin::new(&mut writer)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        // what was read before is written
+        assert_eq!(writer.0, vec![b"abc".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_reaped() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, _b_peer) = tokio::io::duplex(64);
+
+        a_peer.write_all(b"hello").await.unwrap();
+
+        let copied = copy_buf_bidirectional_with_timeout(
+            &mut a,
+            &mut b,
+            64,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            Some(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(copied, (5, 0, Some(Reaped::Idle)));
+    }
+
+    #[tokio::test]
+    async fn test_half_open_reaped() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, mut b_peer) = tokio::io::duplex(64);
+
+        a_peer.write_all(b"hello").await.unwrap();
+        a_peer.shutdown().await.unwrap();
+        b_peer.write_all(b"hi").await.unwrap();
+
+        // b never closes after a did
+        let copied = copy_buf_bidirectional_with_timeout(
+            &mut a,
+            &mut b,
+            64,
+            Duration::from_millis(100),
+            Duration::from_millis(100),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(copied, (5, 2, Some(Reaped::HalfOpen)));
+    }
+}
@@ -1,11 +1,20 @@
-use std::{fs, io::Write, net::IpAddr, path::Path};
+use std::{
+    fs,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
 
 use async_recursion::async_recursion;
+use chrono::{DateTime, Utc};
 use hyper::body::HttpBody;
 use maxminddb::geoip2;
+use serde::Serialize;
 use tracing::{debug, info, warn};
 
 use crate::{
+    app::events::{self, EventKind},
     common::{
         errors::{map_io_error, new_io_error},
         http::HttpClient,
@@ -14,7 +23,21 @@ use crate::{
 };
 
 pub struct Mmdb {
-    reader: maxminddb::Reader<Vec<u8>>,
+    path: PathBuf,
+    download_url: Option<String>,
+    http_client: HttpClient,
+    /// swapped as a whole on update, lookups in flight keep the old one
+    reader: RwLock<Arc<maxminddb::Reader<Vec<u8>>>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MmdbInfo {
+    pub path: String,
+    pub database_type: String,
+    pub build_epoch: u64,
+    pub build_date: Option<DateTime<Utc>>,
+    pub ip_version: u16,
 }
 
 impl Mmdb {
@@ -24,8 +47,57 @@ impl Mmdb {
         http_client: HttpClient,
     ) -> Result<Mmdb, Error> {
         debug!("mmdb path: {}", path.as_ref().to_string_lossy());
-        let reader = Self::load_mmdb(path, download_url, &http_client).await?;
-        Ok(Self { reader })
+        let reader = Self::load_mmdb(&path, download_url.clone(), &http_client).await?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            download_url,
+            http_client,
+            reader: RwLock::new(Arc::new(reader)),
+        })
+    }
+
+    /// Download the database again and swap it in without a restart.
+    /// The current one is kept if the download is not a valid database.
+    pub async fn update(&self) -> Result<MmdbInfo, Error> {
+        let url = self.download_url.as_ref().ok_or_else(|| {
+            Error::InvalidConfig("mmdb update failed: mmdb_download_url is not set".to_owned())
+        })?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".download");
+        let tmp = PathBuf::from(tmp);
+
+        info!("updating mmdb from {}", url);
+        let reader = match Self::download(url, &tmp, &self.http_client).await {
+            Ok(_) => maxminddb::Reader::open_readfile(&tmp)
+                .map_err(|x| Error::InvalidConfig(format!("invalid mmdb downloaded: {}", x))),
+            Err(e) => Err(Error::InvalidConfig(format!("mmdb download failed: {}", e))),
+        };
+        let reader = match reader {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+
+        fs::rename(&tmp, &self.path)?;
+        *self.reader.write().unwrap() = Arc::new(reader);
+        Self::publish_updated(&self.path);
+
+        Ok(self.info())
+    }
+
+    pub fn info(&self) -> MmdbInfo {
+        let reader = self.reader.read().unwrap().clone();
+        let meta = &reader.metadata;
+        MmdbInfo {
+            path: self.path.to_string_lossy().to_string(),
+            database_type: meta.database_type.clone(),
+            build_epoch: meta.build_epoch,
+            build_date: DateTime::from_timestamp(meta.build_epoch as i64, 0),
+            ip_version: meta.ip_version,
+        }
     }
 
     async fn load_mmdb<P: AsRef<Path>>(
@@ -41,6 +113,7 @@ impl Mmdb {
                 Self::download(url, &mmdb_file, http_client)
                     .await
                     .map_err(|x| Error::InvalidConfig(format!("mmdb download failed: {}", x)))?;
+                Self::publish_updated(&mmdb_file);
             } else {
                 return Err(Error::InvalidConfig(format!(
                     "mmdb `{}` not found and mmdb_download_url is not set",
@@ -69,6 +142,7 @@ impl Mmdb {
                             .map_err(|x| {
                                 Error::InvalidConfig(format!("mmdb download failed: {}", x))
                             })?;
+                        Self::publish_updated(&mmdb_file);
                         Ok(maxminddb::Reader::open_readfile(&path).map_err(|x| {
                             Error::InvalidConfig(format!(
                                 "cant open mmdb `{}`: {}",
@@ -92,8 +166,19 @@ impl Mmdb {
         }
     }
 
+    fn publish_updated(path: &Path) {
+        events::publish(
+            EventKind::GeoUpdated,
+            serde_json::json!({ "kind": "mmdb", "path": path.to_string_lossy() }),
+        );
+    }
+
     #[async_recursion]
-    async fn download<P>(url: &str, path: P, http_client: &HttpClient) -> anyhow::Result<()>
+    pub(crate) async fn download<P>(
+        url: &str,
+        path: P,
+        http_client: &HttpClient,
+    ) -> anyhow::Result<()>
     where
         P: AsRef<Path> + std::marker::Send,
     {
@@ -131,9 +216,13 @@ impl Mmdb {
         Ok(())
     }
 
-    pub fn lookup(&self, ip: IpAddr) -> std::io::Result<geoip2::Country> {
-        self.reader
-            .lookup::<geoip2::Country>(ip)
-            .map_err(map_io_error)
+    /// The ISO code of the country of the IP, if the database has one.
+    pub fn country_code(&self, ip: IpAddr) -> std::io::Result<Option<String>> {
+        let reader = self.reader.read().unwrap().clone();
+        let country = reader.lookup::<geoip2::Country>(ip).map_err(map_io_error)?;
+        Ok(country
+            .country
+            .and_then(|x| x.iso_code)
+            .map(ToOwned::to_owned))
     }
 }
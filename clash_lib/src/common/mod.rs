@@ -1,9 +1,13 @@
+pub mod acme;
 pub mod auth;
+pub mod cidr_trie;
 pub mod crypto;
 pub mod errors;
+pub mod geosite;
 pub mod http;
 pub mod io;
 pub mod mmdb;
+pub mod process;
 pub mod timed_future;
 pub mod tls;
 pub mod trie;
@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::session::Network;
+
+/// the `st` of the listening TCP sockets
+const TCP_LISTEN: &str = "0A";
+
+/// Find the inode of the socket in the tables of procfs, then the process
+/// holding a descriptor of it.
+pub fn find_process_path(network: Network, src: SocketAddr) -> Option<String> {
+    let tables = match network {
+        Network::Tcp => ["/proc/net/tcp", "/proc/net/tcp6"],
+        Network::Udp => ["/proc/net/udp", "/proc/net/udp6"],
+    };
+    let inode = tables
+        .iter()
+        .filter_map(|x| fs::read_to_string(x).ok())
+        .find_map(|x| find_inode(&x, src))?;
+
+    let socket = format!("socket:[{}]", inode);
+    fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter(|x| {
+            x.file_name()
+                .to_string_lossy()
+                .bytes()
+                .all(|b| b.is_ascii_digit())
+        })
+        .find(|x| {
+            fs::read_dir(x.path().join("fd")).is_ok_and(|fds| {
+                fds.flatten().any(|fd| {
+                    fs::read_link(fd.path()).is_ok_and(|x| x.to_str() == Some(socket.as_str()))
+                })
+            })
+        })
+        .and_then(|x| fs::read_link(x.path().join("exe")).ok())
+        .map(|x| x.to_string_lossy().into_owned())
+}
+
+/// The inode of the socket bound to `src` in a table like `/proc/net/tcp`,
+/// the listening sockets aside as they open no connections:
+///
+/// ```text
+/// sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+/// 0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 12345
+/// ```
+fn find_inode(table: &str, src: SocketAddr) -> Option<u64> {
+    table.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.get(3) == Some(&TCP_LISTEN) {
+            return None;
+        }
+        let (ip, port) = parse_address(fields.get(1)?)?;
+        let inode = fields.get(9)?.parse::<u64>().ok()?;
+        let same_ip = ip.is_unspecified() || ip.to_canonical() == src.ip().to_canonical();
+        (port == src.port() && same_ip && inode != 0).then_some(inode)
+    })
+}
+
+/// The addresses are the hex of the words in the host byte order.
+fn parse_address(s: &str) -> Option<(IpAddr, u16)> {
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut words = vec![];
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        words.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match words.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(words).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(words).ok()?)),
+        _ => return None,
+    };
+    Some((ip, port))
+}
+
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::find_inode;
+
+    const TCP: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when \
+                       retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 \
+                       12345 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:D431 0100007F:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 \
+                       23456 1 0000000000000000 20 4 30 10 -1";
+
+    const UDP6: &str = "  sl  local_address remote_address st tx_queue rx_queue tr tm->when \
+                        retrnsmt   uid  timeout inode ref pointer drops
+  10: 00000000000000000000000000000000:14E9 00000000000000000000000000000000:0000 07 \
+                        00000000:00000000 00:00000000 00000000   101        0 34567 2 \
+                        0000000000000000 0";
+
+    #[test]
+    fn test_find_inode() {
+        // listening
+        assert_eq!(find_inode(TCP, "127.0.0.1:8080".parse().unwrap()), None);
+        assert_eq!(
+            find_inode(TCP, "127.0.0.1:54321".parse().unwrap()),
+            Some(23456)
+        );
+        assert_eq!(find_inode(TCP, "127.0.0.1:443".parse().unwrap()), None);
+        // bound to any address
+        assert_eq!(
+            find_inode(UDP6, "192.168.1.2:5353".parse().unwrap()),
+            Some(34567)
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::net::SocketAddr;
+
+use libproc::{
+    libproc::{
+        bsd_info::BSDInfo,
+        file_info::{pidfdinfo, ListFDs, ProcFDType},
+        net_info::{SocketFDInfo, SocketInfoKind},
+        proc_pid::{listpidinfo, pidinfo, pidpath},
+    },
+    processes::{pids_by_type, ProcFilter},
+};
+
+use crate::session::Network;
+
+/// Go through the sockets of the processes for the local port, which is
+/// enough to tell the client sockets apart.
+pub fn find_process_path(network: Network, src: SocketAddr) -> Option<String> {
+    pids_by_type(ProcFilter::All)
+        .ok()?
+        .into_iter()
+        .map(|x| x as i32)
+        .find(|&pid| owns_port(pid, network, src.port()))
+        .and_then(|pid| pidpath(pid).ok())
+}
+
+fn owns_port(pid: i32, network: Network, port: u16) -> bool {
+    let Ok(info) = pidinfo::<BSDInfo>(pid, 0) else {
+        return false;
+    };
+    let Ok(fds) = listpidinfo::<ListFDs>(pid, info.pbi_nfiles as usize) else {
+        return false;
+    };
+    fds.iter()
+        .filter(|fd| matches!(fd.proc_fdtype.into(), ProcFDType::Socket))
+        .any(|fd| {
+            let Ok(socket) = pidfdinfo::<SocketFDInfo>(pid, fd.proc_fd) else {
+                return false;
+            };
+            // the union is the one of the kind of the socket
+            let local_port = match (network, socket.psi.soi_kind.into()) {
+                (Network::Tcp, SocketInfoKind::Tcp) => unsafe {
+                    socket.psi.soi_proto.pri_tcp.tcpsi_ini.insi_lport
+                },
+                (Network::Udp, SocketInfoKind::In) => unsafe {
+                    socket.psi.soi_proto.pri_in.insi_lport
+                },
+                _ => return false,
+            };
+            u16::from_be(local_port as u16) == port
+        })
+}
@@ -0,0 +1,84 @@
+//! The local process which opened a connection, for the PROCESS-NAME and
+//! PROCESS-PATH rules. Only the connections from this host, e.g. through the
+//! TUN device, can be told.
+
+use std::{
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use lru_time_cache::LruCache;
+
+use crate::session::Network;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use linux as platform;
+
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+use macos as platform;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows as platform;
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    windows
+)))]
+mod platform {
+    use std::net::SocketAddr;
+
+    use crate::session::Network;
+
+    pub fn find_process_path(_network: Network, _src: SocketAddr) -> Option<String> {
+        None
+    }
+}
+
+/// the UDP packets of a session are routed one by one, don't look up the
+/// sockets of the system for each of them
+const CACHE_TTL: Duration = Duration::from_secs(10);
+const CACHE_CAPACITY: usize = 1024;
+
+type Cache = Mutex<LruCache<(Network, SocketAddr), Option<String>>>;
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+/// The path of the executable of the process owning the local socket `src`.
+pub async fn find_process_path(network: Network, src: SocketAddr) -> Option<String> {
+    let cache = CACHE.get_or_init(|| {
+        Mutex::new(LruCache::with_expiry_duration_and_capacity(
+            CACHE_TTL,
+            CACHE_CAPACITY,
+        ))
+    });
+    if let Some(path) = cache.lock().unwrap().get(&(network, src)) {
+        return path.clone();
+    }
+
+    let path = tokio::task::spawn_blocking(move || platform::find_process_path(network, src))
+        .await
+        .ok()
+        .flatten();
+    cache.lock().unwrap().insert((network, src), path.clone());
+    path
+}
+
+/// The path found by an earlier lookup of the socket, if any.
+pub fn cached_process_path(network: Network, src: SocketAddr) -> Option<String> {
+    CACHE
+        .get()?
+        .lock()
+        .unwrap()
+        .peek(&(network, src))
+        .cloned()
+        .flatten()
+}
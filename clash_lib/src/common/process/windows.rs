@@ -0,0 +1,100 @@
+use std::{
+    ffi::c_void,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, ERROR_INSUFFICIENT_BUFFER, NO_ERROR},
+    NetworkManagement::IpHelper::{
+        GetExtendedTcpTable, GetExtendedUdpTable, MIB_TCP6ROW_OWNER_PID, MIB_TCPROW_OWNER_PID,
+        MIB_UDP6ROW_OWNER_PID, MIB_UDPROW_OWNER_PID, TCP_TABLE_OWNER_PID_ALL, UDP_TABLE_OWNER_PID,
+    },
+    Networking::WinSock::{AF_INET, AF_INET6},
+    System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        PROCESS_QUERY_LIMITED_INFORMATION,
+    },
+};
+
+use crate::session::Network;
+
+/// Find the owner of the socket in the tables of the IP helper, then the
+/// image of the process.
+pub fn find_process_path(network: Network, src: SocketAddr) -> Option<String> {
+    let pid = match (network, src.ip().to_canonical()) {
+        (Network::Tcp, IpAddr::V4(ip)) => rows::<MIB_TCPROW_OWNER_PID>(network, AF_INET)?
+            .iter()
+            .find(|x| matches_v4(x.dwLocalAddr, x.dwLocalPort, ip, src.port()))
+            .map(|x| x.dwOwningPid),
+        (Network::Tcp, IpAddr::V6(ip)) => rows::<MIB_TCP6ROW_OWNER_PID>(network, AF_INET6)?
+            .iter()
+            .find(|x| matches_v6(x.ucLocalAddr, x.dwLocalPort, ip, src.port()))
+            .map(|x| x.dwOwningPid),
+        (Network::Udp, IpAddr::V4(ip)) => rows::<MIB_UDPROW_OWNER_PID>(network, AF_INET)?
+            .iter()
+            .find(|x| matches_v4(x.dwLocalAddr, x.dwLocalPort, ip, src.port()))
+            .map(|x| x.dwOwningPid),
+        (Network::Udp, IpAddr::V6(ip)) => rows::<MIB_UDP6ROW_OWNER_PID>(network, AF_INET6)?
+            .iter()
+            .find(|x| matches_v6(x.ucLocalAddr, x.dwLocalPort, ip, src.port()))
+            .map(|x| x.dwOwningPid),
+    }?;
+    process_path(pid)
+}
+
+/// The addresses and the ports are in the network byte order.
+fn matches_v4(addr: u32, port: u32, ip: Ipv4Addr, src_port: u16) -> bool {
+    let addr = Ipv4Addr::from(addr.to_ne_bytes());
+    u16::from_be(port as u16) == src_port && (addr.is_unspecified() || addr == ip)
+}
+
+fn matches_v6(addr: [u8; 16], port: u32, ip: Ipv6Addr, src_port: u16) -> bool {
+    let addr = Ipv6Addr::from(addr);
+    u16::from_be(port as u16) == src_port && (addr.is_unspecified() || addr == ip)
+}
+
+/// The rows of the TCP or UDP table of the address family, which is a count
+/// followed by the rows.
+fn rows<T: Copy>(network: Network, af: u16) -> Option<Vec<T>> {
+    let mut size = 0u32;
+    let mut buf: Vec<u32> = vec![];
+    loop {
+        let ptr = buf.as_mut_ptr() as *mut c_void;
+        let ret = unsafe {
+            match network {
+                Network::Tcp => {
+                    GetExtendedTcpTable(ptr, &mut size, 0, af as u32, TCP_TABLE_OWNER_PID_ALL, 0)
+                }
+                Network::Udp => {
+                    GetExtendedUdpTable(ptr, &mut size, 0, af as u32, UDP_TABLE_OWNER_PID, 0)
+                }
+            }
+        };
+        match ret {
+            NO_ERROR => break,
+            // the table can grow between the calls
+            ERROR_INSUFFICIENT_BUFFER => buf = vec![0; (size as usize).div_ceil(4)],
+            _ => return None,
+        }
+    }
+
+    let count = *buf.first()? as usize;
+    // the rows are 4 bytes aligned, right after the count
+    let rows = unsafe { std::slice::from_raw_parts(buf.as_ptr().add(1) as *const T, count) };
+    Some(rows.to_vec())
+}
+
+fn process_path(pid: u32) -> Option<String> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle == 0 {
+            return None;
+        }
+        let mut buf = [0u16; 1024];
+        let mut size = buf.len() as u32;
+        let ok =
+            QueryFullProcessImageNameW(handle, PROCESS_NAME_WIN32, buf.as_mut_ptr(), &mut size);
+        CloseHandle(handle);
+        (ok != 0).then(|| String::from_utf16_lossy(&buf[..size as usize]))
+    }
+}
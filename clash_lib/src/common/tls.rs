@@ -1,26 +1,234 @@
 use once_cell::sync::Lazy;
 use rustls::{
     client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientCertVerifier, NoClientAuth},
     DigitallySignedStruct, OwnedTrustAnchor, RootCertStore,
 };
-use tracing::warn;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
 use rustls::{Certificate, ServerName};
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{config::home::resolve_path, Error};
+
+/// The root store used when nothing else is configured,
+/// the system roots plus the bundled webpki roots.
+pub static GLOBAL_ROOT_STORE: Lazy<Arc<RootCertStore>> = Lazy::new(|| {
+    cert_store(&CertStoreOptions::default()).expect("default cert store must not fail")
+});
+
+/// The well known CA bundle locations of the common linux/BSD distributions,
+/// `SSL_CERT_FILE` takes precedence over them.
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/pki/tls/cacert.pem",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
 
-pub static GLOBAL_ROOT_STORE: Lazy<Arc<RootCertStore>> = Lazy::new(global_root_store);
+/// The system roots, `None` when the system has no CA bundle,
+/// which is usual for slim containers and embedded routers.
+static SYSTEM_ROOTS: Lazy<Option<Vec<Vec<u8>>>> = Lazy::new(load_system_roots);
+
+static CERT_STORES: Lazy<Mutex<HashMap<CertStoreOptions, Arc<RootCertStore>>>> =
+    Lazy::new(Default::default);
+
+/// Which roots a TLS client trusts
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustRoots {
+    /// the system roots plus the bundled roots,
+    /// only the bundled roots if the system has none
+    #[default]
+    Auto,
+    /// only the system roots
+    System,
+    /// only the roots bundled in the binary
+    Bundled,
+}
 
-fn global_root_store() -> Arc<RootCertStore> {
-    let mut root_store = RootCertStore::empty();
-    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+/// How to build the root store of a TLS client,
+/// the user supplied CAs are trusted in addition to the selected roots.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CertStoreOptions {
+    pub trust: TrustRoots,
+    /// path to a PEM file of extra CAs, relative to the config home
+    pub ca: Option<String>,
+    /// extra CAs in PEM
+    pub ca_str: Option<String>,
+}
+
+/// Get the root store for the options, stores are shared by everyone
+/// asking for the same options.
+pub fn cert_store(opts: &CertStoreOptions) -> Result<Arc<RootCertStore>, Error> {
+    if let Some(store) = CERT_STORES.lock().unwrap().get(opts) {
+        return Ok(store.clone());
+    }
+
+    let mut store = RootCertStore::empty();
+
+    match opts.trust {
+        TrustRoots::Auto => {
+            if let Some(certs) = SYSTEM_ROOTS.as_ref() {
+                store.add_parsable_certificates(&certs[..]);
+            }
+            add_bundled_roots(&mut store);
+        }
+        TrustRoots::System => {
+            let certs = SYSTEM_ROOTS.as_ref().ok_or_else(|| {
+                Error::InvalidConfig(
+                    "no system CA bundle found, set SSL_CERT_FILE or use the bundled roots"
+                        .to_owned(),
+                )
+            })?;
+            store.add_parsable_certificates(&certs[..]);
+        }
+        TrustRoots::Bundled => add_bundled_roots(&mut store),
+    }
+
+    if let Some(path) = &opts.ca {
+        let path = resolve_path(path);
+        let pem = std::fs::read(&path).map_err(|x| {
+            Error::InvalidConfig(format!("failed to read ca {}: {}", path.display(), x))
+        })?;
+        add_pem(&mut store, &pem, &path.display().to_string())?;
+    }
+    if let Some(pem) = &opts.ca_str {
+        add_pem(&mut store, pem.as_bytes(), "ca-str")?;
+    }
+
+    let store = Arc::new(store);
+    CERT_STORES
+        .lock()
+        .unwrap()
+        .insert(opts.clone(), store.clone());
+    Ok(store)
+}
+
+fn add_bundled_roots(store: &mut RootCertStore) {
+    store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
         OwnedTrustAnchor::from_subject_spki_name_constraints(
             ta.subject,
             ta.spki,
             ta.name_constraints,
         )
     }));
+}
 
-    Arc::new(root_store)
+fn add_pem(store: &mut RootCertStore, pem: &[u8], name: &str) -> Result<(), Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem))
+        .map_err(|x| Error::InvalidConfig(format!("invalid ca {}: {}", name, x)))?;
+    let (added, _) = store.add_parsable_certificates(&certs[..]);
+    if added == 0 {
+        return Err(Error::InvalidConfig(format!(
+            "no valid certificate found in ca {}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn load_system_roots() -> Option<Vec<Vec<u8>>> {
+    let env = std::env::var("SSL_CERT_FILE").ok();
+    for path in env
+        .iter()
+        .map(String::as_str)
+        .chain(SYSTEM_CA_FILES.iter().copied())
+    {
+        let pem = match std::fs::read(path) {
+            Ok(pem) => pem,
+            Err(_) => continue,
+        };
+        match rustls_pemfile::certs(&mut BufReader::new(pem.as_slice())) {
+            Ok(certs) if !certs.is_empty() => {
+                debug!("loaded {} system roots from {}", certs.len(), path);
+                return Some(certs);
+            }
+            _ => warn!("invalid system CA bundle {}, ignored", path),
+        }
+    }
+    None
+}
+
+/// The config of a TLS server from the PEM files of its certificate chain
+/// and private key, see [`client_verifier`] for `client_ca`.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    alpn: &[String],
+    client_ca: Option<&Path>,
+) -> Result<rustls::ServerConfig, Error> {
+    let (certs, private_key) = cert_and_key(cert, key)?;
+    let mut cfg = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_verifier(client_ca)?)
+        .with_single_cert(certs, private_key)
+        .map_err(|x| Error::InvalidConfig(format!("invalid certificate or key: {}", x)))?;
+    cfg.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+
+    Ok(cfg)
+}
+
+/// Verifies the client certificates against the CAs of the PEM file `ca`,
+/// no client authentication if `None`. The clients without a certificate
+/// still complete the handshake, the server tells them apart with
+/// `peer_certificates()`.
+pub fn client_verifier(ca: Option<&Path>) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let Some(ca) = ca else {
+        return Ok(NoClientAuth::boxed());
+    };
+    let pem = std::fs::read(ca)
+        .map_err(|x| Error::InvalidConfig(format!("failed to read {}: {}", ca.display(), x)))?;
+    let mut roots = RootCertStore::empty();
+    add_pem(&mut roots, &pem, &ca.display().to_string())?;
+    Ok(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+}
+
+/// The certificate chain and the private key of the PEM files.
+pub fn cert_and_key(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<Certificate>, rustls::PrivateKey), Error> {
+    let read = |path: &Path| {
+        std::fs::read(path)
+            .map_err(|x| Error::InvalidConfig(format!("failed to read {}: {}", path.display(), x)))
+    };
+
+    let certs =
+        rustls_pemfile::certs(&mut BufReader::new(read(cert)?.as_slice())).map_err(|x| {
+            Error::InvalidConfig(format!("invalid certificate {}: {}", cert.display(), x))
+        })?;
+    if certs.is_empty() {
+        return Err(Error::InvalidConfig(format!(
+            "no certificate found in {}",
+            cert.display()
+        )));
+    }
+
+    let key_pem = read(key)?;
+    let private_key = rustls_pemfile::read_all(&mut BufReader::new(key_pem.as_slice()))
+        .map_err(|x| Error::InvalidConfig(format!("invalid private key {}: {}", key.display(), x)))?
+        .into_iter()
+        .find_map(|x| match x {
+            rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(rustls::PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            Error::InvalidConfig(format!("no private key found in {}", key.display()))
+        })?;
+
+    Ok((certs.into_iter().map(Certificate).collect(), private_key))
 }
 
 /// Warning: NO validation on certs.
@@ -90,3 +298,39 @@ impl ServerCertVerifier for NoHostnameTlsVerifier {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{cert_store, client_verifier, CertStoreOptions, TrustRoots};
+
+    #[test]
+    fn test_cert_store() {
+        let opts = CertStoreOptions {
+            trust: TrustRoots::Bundled,
+            ..Default::default()
+        };
+        let store = cert_store(&opts).unwrap();
+        assert_eq!(store.len(), webpki_roots::TLS_SERVER_ROOTS.len());
+        assert!(std::sync::Arc::ptr_eq(&store, &cert_store(&opts).unwrap()));
+
+        let opts = CertStoreOptions {
+            trust: TrustRoots::Bundled,
+            ca_str: Some("not a pem".to_owned()),
+            ..Default::default()
+        };
+        assert!(cert_store(&opts).is_err());
+    }
+
+    #[test]
+    fn test_client_verifier() {
+        let verifier = client_verifier(None).unwrap();
+        assert!(!verifier.offer_client_auth());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "not a pem").unwrap();
+        assert!(client_verifier(Some(file.path())).is_err());
+        assert!(client_verifier(Some("/nonexistent/ca.crt".as_ref())).is_err());
+    }
+}
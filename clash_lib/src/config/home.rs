@@ -0,0 +1,154 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use crate::config::internal::{
+    config::{InboundTls, RuleProviderDef},
+    proxy::OutboundProxyProviderDef,
+    InternalConfig,
+};
+
+/// The directory every relative path in the config resolves against.
+///
+/// It's the `-d` directory if given, otherwise the directory of the config
+/// file, and the current directory for configs not loaded from a file.
+#[derive(Clone, Debug)]
+pub struct ConfigHome {
+    dir: PathBuf,
+}
+
+/// the home of the running config, for the files the proxies read as
+/// they're built out of reach of it
+static INSTALLED: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Resolve a path of a proxy against the installed home, or the current
+/// directory if none is.
+pub fn resolve_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    match INSTALLED.read().unwrap().as_ref() {
+        Some(dir) => dir.join(path),
+        None => path.as_ref().to_path_buf(),
+    }
+}
+
+impl ConfigHome {
+    pub fn new(dir: Option<&str>, config: &crate::Config) -> Self {
+        let dir = match (dir, config) {
+            (Some(dir), _) => PathBuf::from(dir),
+            (None, crate::Config::File(file)) => Path::new(file)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+            (None, _) => PathBuf::new(),
+        };
+
+        let dir = if dir.is_absolute() {
+            dir
+        } else {
+            std::env::current_dir()
+                .map(|cwd| cwd.join(&dir))
+                .unwrap_or(dir)
+        };
+
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Make it the home [`resolve_path`] resolves against.
+    pub fn install(&self) {
+        *INSTALLED.write().unwrap() = Some(self.dir.clone());
+    }
+
+    /// Resolve a path from the config, absolute paths are kept as is.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    /// The resolved paths of the files used by the config, keyed by where
+    /// they come from, e.g. `mmdb` or `rule-providers.<name>`.
+    pub fn resolved_paths(&self, config: &InternalConfig) -> BTreeMap<String, String> {
+        let mut paths = BTreeMap::new();
+        let mut add = |key: String, path: &str| {
+            paths.insert(key, self.resolve(path).to_string_lossy().to_string());
+        };
+
+        add("mmdb".to_owned(), &config.general.mmdb);
+        add("geosite".to_owned(), &config.general.geosite);
+        add("cache-db".to_owned(), "cache.db");
+        if let Some(ui) = &config.general.controller.external_ui {
+            add("external-ui".to_owned(), ui);
+        }
+        if let Some(trojan) = &config.general.inbound.trojan {
+            match &trojan.tls {
+                InboundTls::Files {
+                    certificate,
+                    private_key,
+                } => {
+                    add("trojan-inbound.certificate".to_owned(), certificate);
+                    add("trojan-inbound.private-key".to_owned(), private_key);
+                }
+                InboundTls::Acme(acme) => add("trojan-inbound.acme.dir".to_owned(), &acme.dir),
+            }
+        }
+        if let Some(tls) = &config.dns.listen_tls {
+            add("dns.listen-tls.certificate".to_owned(), &tls.certificate);
+            add("dns.listen-tls.private-key".to_owned(), &tls.private_key);
+        }
+        if let Some(page) = config
+            .general
+            .inbound
+            .http_block_page
+            .as_ref()
+            .and_then(|x| x.page.as_ref())
+        {
+            add("http-block-page.page".to_owned(), page);
+        }
+        for (name, provider) in &config.rule_providers {
+            let path = match provider {
+                RuleProviderDef::Http(http) => &http.path,
+                RuleProviderDef::File(file) => &file.path,
+            };
+            add(format!("rule-providers.{}", name), path);
+        }
+        for (name, provider) in &config.proxy_providers {
+            let path = match provider {
+                OutboundProxyProviderDef::Http(http) => &http.path,
+                OutboundProxyProviderDef::File(file) => &file.path,
+            };
+            add(format!("proxy-providers.{}", name), path);
+        }
+
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::ConfigHome;
+
+    #[test]
+    fn test_resolve() {
+        let home = ConfigHome::new(
+            None,
+            &crate::Config::File("/etc/clash/config.yaml".to_owned()),
+        );
+        assert_eq!(home.dir(), Path::new("/etc/clash"));
+        assert_eq!(
+            home.resolve("./ruleset/a.yaml"),
+            Path::new("/etc/clash/./ruleset/a.yaml")
+        );
+        assert_eq!(home.resolve("/tmp/a.yaml"), Path::new("/tmp/a.yaml"));
+
+        let home = ConfigHome::new(
+            Some("/opt/clash"),
+            &crate::Config::File("/etc/clash/config.yaml".to_owned()),
+        );
+        assert_eq!(home.dir(), Path::new("/opt/clash"));
+    }
+}
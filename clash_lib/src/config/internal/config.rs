@@ -1,18 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use std::fmt::Display;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use std::time::Duration;
 
+use ipnet::IpNet;
 use serde::de::value::MapDeserializer;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 
-use crate::app::remote_content_manager::providers::rule_provider::RuleSetBehavior;
+use crate::app::inbound::network_listener::ListenerType;
+use crate::app::remote_content_manager::providers::rule_provider::{
+    RuleSetBehavior, RuleSetFormat,
+};
+use crate::app::router::script;
 use crate::common::auth;
 use crate::config::def::{self};
 use crate::config::internal::proxy::{OutboundProxy, PROXY_DIRECT, PROXY_REJECT};
 use crate::config::internal::rule::RuleType;
+use crate::proxy::tun::TunStack;
 use crate::proxy::utils::Interface;
 use crate::{
     app::dns,
@@ -20,15 +28,27 @@ use crate::{
     Error,
 };
 
-use super::proxy::{map_serde_error, OutboundProxyProtocol, OutboundProxyProviderDef};
+use super::{
+    proxy::{map_serde_error, OutboundProxyProtocol, OutboundProxyProviderDef},
+    secret,
+};
 
 pub struct Config {
     pub general: General,
     pub dns: dns::Config,
     pub tun: TunConfig,
+    pub gateway: GatewayConfig,
+    pub ntp: NtpConfig,
     pub experimental: Option<def::Experimental>,
     pub profile: Profile,
     pub rules: Vec<RuleType>,
+    /// the rules tried first for the connections of an inbound, keyed by
+    /// the names of [`crate::session::Type::listener`]
+    pub listener_rules: HashMap<String, Vec<RuleType>>,
+    /// the named sets of `sub-rules`, each parsed once however many
+    /// `SUB-RULE` rules jump into it
+    pub sub_rules: HashMap<String, Vec<RuleType>>,
+    pub shaping: HashMap<String, ShapingLimit>,
     pub rule_providers: HashMap<String, RuleProviderDef>,
     pub users: Vec<auth::User>,
     /// a list maintaining the order from the config file
@@ -36,21 +56,136 @@ pub struct Config {
     pub proxies: HashMap<String, OutboundProxy>,
     pub proxy_groups: HashMap<String, OutboundProxy>,
     pub proxy_providers: HashMap<String, OutboundProxyProviderDef>,
+    /// the faults injected into the proxies, `None` if chaos is disabled
+    pub chaos: Option<HashMap<String, def::ChaosFault>>,
 }
 
 impl Config {
     fn validate(self) -> Result<Self, crate::Error> {
-        for r in self.rules.iter() {
-            if !self.proxies.contains_key(r.target()) && !self.proxy_groups.contains_key(r.target())
-            {
+        for r in self
+            .rules
+            .iter()
+            .chain(self.listener_rules.values().flatten())
+            .chain(self.sub_rules.values().flatten())
+        {
+            self.validate_rule(r)?;
+        }
+        // sorted for the same loop to be reported every time
+        let mut names = self.sub_rules.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut done = HashSet::new();
+        for name in names {
+            self.check_sub_rules_loop(name, &mut vec![], &mut done)?;
+        }
+        for (domain, target) in self.dns.route_hints.iter() {
+            if !self.proxies.contains_key(target) && !self.proxy_groups.contains_key(target) {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy `{}` routed to by the nameserver-policy of {} was not found",
+                    target, domain
+                )));
+            }
+        }
+        for (domains, _) in self.dns.nameserver_policy.iter() {
+            if let dns::PolicyDomains::RuleSet(name) = domains {
+                if !self.rule_providers.contains_key(name) {
+                    return Err(Error::InvalidConfig(format!(
+                        "rule provider `{}` referenced in nameserver-policy was not found",
+                        name
+                    )));
+                }
+            }
+        }
+        for (name, fault) in self.chaos.iter().flatten() {
+            if !self.proxies.contains_key(name) {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy `{}` referenced in chaos was not found",
+                    name
+                )));
+            }
+            if fault.drop > 100 {
                 return Err(Error::InvalidConfig(format!(
-                    "proxy `{}` referenced in a rule was not found",
-                    r.target()
+                    "chaos drop of `{}` is a percentage, got {}",
+                    name, fault.drop
                 )));
             }
         }
         Ok(self)
     }
+
+    fn validate_rule(&self, r: &RuleType) -> Result<(), crate::Error> {
+        if let RuleType::Shaped { class, .. } = r {
+            if !self.shaping.contains_key(class) {
+                return Err(Error::InvalidConfig(format!(
+                    "shaping class `{}` referenced in a rule was not found",
+                    class
+                )));
+            }
+        }
+        // the target of a SUB-RULE is the set it jumps into
+        if let Some(name) = r.sub_rules() {
+            if !self.sub_rules.contains_key(name) {
+                return Err(Error::InvalidConfig(format!(
+                    "sub-rules `{}` referenced in a rule was not found",
+                    name
+                )));
+            }
+            return Ok(());
+        }
+        if !self.proxies.contains_key(r.target()) && !self.proxy_groups.contains_key(r.target()) {
+            return Err(Error::InvalidConfig(format!(
+                "proxy `{}` referenced in a rule was not found",
+                r.target()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fail if the set of `sub-rules` jumps back into one of the sets of
+    /// `path`. The sets in `done` are known not to, each set is walked once.
+    fn check_sub_rules_loop(
+        &self,
+        name: &str,
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+    ) -> Result<(), crate::Error> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if path.iter().any(|x| x == name) {
+            return Err(Error::InvalidConfig(format!(
+                "sub-rules loop: {} -> {}",
+                path.join(" -> "),
+                name
+            )));
+        }
+        path.push(name.to_owned());
+        for next in self.sub_rules[name].iter().filter_map(RuleType::sub_rules) {
+            self.check_sub_rules_loop(next, path, done)?;
+        }
+        path.pop();
+        done.insert(name.to_owned());
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The china-list preset: the `cn` geosite category and the CN IPs of
+    /// the mmdb, checked with a lookup each after the rules written, so
+    /// these still win. It goes ahead of a final `MATCH`, which would
+    /// leave it nothing.
+    fn with_china_direct(mut self, cfg: def::ChinaDirect) -> Self {
+        if !cfg.enable {
+            return self;
+        }
+
+        let at = match self.rules.last() {
+            Some(RuleType::Match { .. }) => self.rules.len() - 1,
+            _ => self.rules.len(),
+        };
+        self.rules
+            .insert(at, RuleType::ChinaDirect { target: cfg.target });
+        self
+    }
 }
 
 impl TryFrom<def::Config> for Config {
@@ -58,6 +193,7 @@ impl TryFrom<def::Config> for Config {
 
     fn try_from(c: def::Config) -> Result<Self, Self::Error> {
         let mut proxy_names = vec![String::from(PROXY_DIRECT), String::from(PROXY_REJECT)];
+        let china_direct = c.china_direct.clone();
         #[allow(deprecated)]
         Self {
             general: General {
@@ -69,11 +205,28 @@ impl TryFrom<def::Config> for Config {
                     mixed_port: c.mixed_port,
                     authentication: c.authentication.clone(),
                     bind_address: c.bind_address.parse()?,
+                    trojan: c
+                        .trojan_inbound
+                        .as_ref()
+                        .map(parse_trojan_inbound)
+                        .transpose()?,
+                    proxy_protocol: c
+                        .proxy_protocol
+                        .iter()
+                        .map(|x| parse_listener_type(x))
+                        .collect::<Result<_, _>>()?,
+                    http_block_page: c.http_block_page.as_ref().map(|x| HttpBlockPageConfig {
+                        status: x.status,
+                        page: x.page.clone(),
+                    }),
+                    rate_limit: parse_inbound_rate_limit(&c.inbound_rate_limit)?,
                 },
                 controller: Controller {
                     external_controller: c.external_controller.clone(),
                     external_ui: c.external_ui.clone(),
                     secret: c.secret.clone(),
+                    tokens: parse_api_tokens(&c.controller)?,
+                    health_auth: c.controller.health_auth,
                 },
                 mode: c.mode,
                 log_level: c.log_level,
@@ -86,27 +239,107 @@ impl TryFrom<def::Config> for Config {
                     }
                 }),
                 routing_mask: c.routing_mask,
+                outbound_port_range: c
+                    .outbound_port_range
+                    .as_ref()
+                    .map(parse_port_range)
+                    .transpose()?,
                 mmdb: c.mmdb.to_owned(),
                 mmdb_download_url: c.mmdb_download_url.to_owned(),
+                geosite: c.geosite.to_owned(),
+                geosite_download_url: c.geosite_download_url.to_owned(),
+                max_group_depth: c.max_group_depth,
+                tcp_timeout: TcpTimeout {
+                    handshake: Some(c.tcp_timeout.handshake)
+                        .filter(|x| *x > 0)
+                        .map(Duration::from_secs),
+                    idle: Some(c.tcp_timeout.idle)
+                        .filter(|x| *x > 0)
+                        .map(Duration::from_secs),
+                    half_open: Duration::from_secs(c.tcp_timeout.half_open),
+                },
+                ftp_helper: c.ftp_helper,
             },
             dns: (&c).try_into()?,
             experimental: c.experimental,
             tun: match c.tun {
-                Some(mapping) => TunConfig::deserialize(MapDeserializer::new(mapping.into_iter()))
-                    .map_err(|e| Error::InvalidConfig(format!("invalid tun config: {}", e)))?,
+                Some(mapping) => {
+                    let tun = TunConfig::deserialize(MapDeserializer::new(mapping.into_iter()))
+                        .map_err(|e| Error::InvalidConfig(format!("invalid tun config: {}", e)))?;
+                    // the outbound traffic would loop back into the device
+                    if tun.enable
+                        && tun.auto_route
+                        && !tun.auto_detect_interface
+                        && c.interface.is_none()
+                    {
+                        return Err(Error::InvalidConfig(
+                            "tun auto-route needs auto-detect-interface or interface-name"
+                                .to_owned(),
+                        ));
+                    }
+                    tun
+                }
                 None => TunConfig::default(),
             },
+            ntp: NtpConfig {
+                enable: c.ntp.enable,
+                server: c.ntp.server.clone(),
+                port: c.ntp.port,
+                interval: Duration::from_secs(c.ntp.interval.max(60)),
+                tolerance: Duration::from_secs(c.ntp.tolerance),
+            },
+            gateway: GatewayConfig {
+                enable: c.gateway.enable,
+                interface: c.gateway.interface.clone(),
+                serve_dns: c.gateway.serve_dns,
+                auto_route: c.gateway.auto_route,
+            },
             profile: Profile {
                 store_selected: c.profile.store_selected,
             },
-            rules: c
-                .rule
+            rules: {
+                for (name, code) in c.script.shortcuts.iter() {
+                    script::Expr::parse(code).map_err(|e| {
+                        Error::InvalidConfig(format!("invalid script shortcut {}: {}", name, e))
+                    })?;
+                }
+                parse_rules(&c.rule, &c.script.shortcuts)?
+            },
+            listener_rules: c
+                .listener_rules
+                .iter()
+                .map(|(name, rules)| {
+                    let name = match name.as_str() {
+                        "http" | "mixed" | "trojan" | "tun" | "tproxy" => name.clone(),
+                        "socks" | "socks5" => "socks".to_owned(),
+                        port => port.parse::<u16>().map(|x| x.to_string()).map_err(|_| {
+                            Error::InvalidConfig(format!(
+                                "invalid listener-rules listener: {}, expected a port or http, \
+                                 socks, mixed, trojan, tun or tproxy",
+                                name
+                            ))
+                        })?,
+                    };
+                    let rules = parse_rules(rules, &c.script.shortcuts)?;
+                    Ok((name.to_owned(), rules))
+                })
+                .collect::<Result<HashMap<_, _>, Error>>()?,
+            sub_rules: c
+                .sub_rules
+                .iter()
+                .map(|(name, rules)| Ok((name.clone(), parse_rules(rules, &c.script.shortcuts)?)))
+                .collect::<Result<HashMap<_, _>, Error>>()?,
+            shaping: c
+                .shaping
                 .into_iter()
-                .map(|x| {
-                    x.parse::<RuleType>()
-                        .map_err(|x| Error::InvalidConfig(x.to_string()))
+                .map(|(name, class)| {
+                    ShapingLimit::try_from(class)
+                        .map(|x| (name.clone(), x))
+                        .map_err(|x| {
+                            Error::InvalidConfig(format!("invalid shaping class {}: {}", name, x))
+                        })
                 })
-                .collect::<Result<Vec<_>, _>>()?,
+                .collect::<Result<HashMap<_, _>, _>>()?,
             rule_providers: c
                 .rule_provider
                 .map(|m| {
@@ -146,7 +379,8 @@ impl TryFrom<def::Config> for Config {
                         OutboundProxy::ProxyServer(OutboundProxyProtocol::Reject),
                     ),
                 ]),
-                |mut rv, x| {
+                |mut rv, mut x| {
+                    secret::resolve(&mut x)?;
                     let proxy = OutboundProxy::ProxyServer(OutboundProxyProtocol::try_from(x)?);
                     let name = proxy.name();
                     if rv.contains_key(name.as_str()) {
@@ -202,16 +436,266 @@ impl TryFrom<def::Config> for Config {
                         .expect("proxy provider parse error")
                 })
                 .unwrap_or_default(),
+            chaos: c.chaos.enable.then_some(c.chaos.outbounds),
         }
+        .with_china_direct(china_direct)
         .validate()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::def;
+    use crate::{app::dns::PolicyDomains, config::internal::rule::RuleType, def};
+
+    use super::{parse_port_range, parse_rate, Config};
+
+    #[test]
+    fn china_direct_preset() {
+        let cfg = r#"
+        china-direct:
+          enable: true
+        rules:
+          - DOMAIN,example.cn,PROXY
+          - MATCH,PROXY
+        proxies:
+          - name: PROXY
+            type: socks5
+            server: 127.0.0.1
+            port: 1080
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.rules.len(), 3);
+        // after the rules written, ahead of the final MATCH
+        assert!(matches!(&cc.rules[0], RuleType::Domain { .. }));
+        assert!(matches!(
+            &cc.rules[1],
+            RuleType::ChinaDirect { target } if target == "DIRECT"
+        ));
+        assert!(matches!(&cc.rules[2], RuleType::Match { .. }));
+        assert!(cc.rule_providers.is_empty());
+    }
+
+    #[test]
+    fn script_shortcuts() {
+        let cfg = r#"
+        script:
+          shortcuts:
+            quic: network == 'udp' and dst_port == 443
+        rules:
+          - SCRIPT,quic,REJECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert!(matches!(
+            &cc.rules[0],
+            RuleType::Script { shortcut, code, .. }
+                if shortcut == "quic" && code.starts_with("network")
+        ));
 
-    use super::Config;
+        let cfg = r#"
+        rules:
+          - SCRIPT,quic,REJECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(Config::try_from(c).is_err());
+    }
+
+    #[test]
+    fn sub_rules() {
+        let cfg = r#"
+        sub-rules:
+          a:
+            - DOMAIN,example.com,DIRECT
+            - SUB-RULE,(NETWORK,UDP),b
+          b:
+            - MATCH,REJECT
+        rules:
+          - SUB-RULE,(DST-PORT,443),a
+          - MATCH,DIRECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.rules[0].sub_rules(), Some("a"));
+        assert_eq!(cc.sub_rules["a"][1].sub_rules(), Some("b"));
+        assert_eq!(cc.sub_rules["b"].len(), 1);
+
+        // a set jumped into from two others is parsed once
+        let cfg = r#"
+        sub-rules:
+          a:
+            - SUB-RULE,(NETWORK,UDP),c
+          b:
+            - SUB-RULE,(NETWORK,TCP),c
+          c:
+            - MATCH,REJECT
+        rules:
+          - SUB-RULE,(DST-PORT,443),a
+          - SUB-RULE,(DST-PORT,80),b
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.sub_rules.len(), 3);
+
+        let cfg = r#"
+        rules:
+          - SUB-RULE,(DST-PORT,443),missing
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = Config::try_from(c).err().expect("should be missing");
+        assert!(err.to_string().contains("missing"), "{}", err);
+
+        let cfg = r#"
+        sub-rules:
+          a:
+            - SUB-RULE,(NETWORK,UDP),b
+          b:
+            - SUB-RULE,(NETWORK,TCP),a
+        rules:
+          - SUB-RULE,(DST-PORT,443),a
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = Config::try_from(c).err().expect("should be a loop");
+        assert!(err.to_string().contains("a -> b -> a"), "{}", err);
+    }
+
+    #[test]
+    fn port_range() {
+        let range = |x: &str| parse_port_range(&def::PortRange::Range(x.to_owned()));
+        assert_eq!(range("40000-40100").unwrap(), 40000..=40100);
+        assert_eq!(range("5000").unwrap(), 5000..=5000);
+        assert_eq!(range(" 1 - 2 ").unwrap(), 1..=2);
+        for bad in ["0", "2-1", "1-", "70000", "a-b"] {
+            assert!(range(bad).is_err(), "{}", bad);
+        }
+        assert!(parse_port_range(&def::PortRange::Port(0)).is_err());
+    }
+
+    #[test]
+    fn outbound_port_range() {
+        for (v, want) in [("5000", 5000..=5000), ("40000-40100", 40000..=40100)] {
+            let cfg = format!("outbound-port-range: {}\n", v);
+            let c = cfg.parse::<def::Config>().expect("should parse");
+            let cc: Config = c.try_into().expect("should into");
+            assert_eq!(cc.general.outbound_port_range, Some(want));
+        }
+    }
+
+    #[test]
+    fn tun_auto_route() {
+        let tun = "tun:\n  enable: true\n  auto-route: true\n";
+        for (extra, ok) in [
+            ("", false),
+            ("  auto-detect-interface: true\n", true),
+            ("interface-name: en0\n", true),
+        ] {
+            let c = format!("{}{}", tun, extra)
+                .parse::<def::Config>()
+                .expect("should parse");
+            assert_eq!(Config::try_from(c).is_ok(), ok, "{}", extra);
+        }
+    }
+
+    #[test]
+    fn listener_rules() {
+        let cfg = r#"
+        listener-rules:
+          socks5:
+            - DST-PORT,53,REJECT
+          mixed:
+            - MATCH,DIRECT
+          5353:
+            - MATCH,REJECT
+        rules:
+          - MATCH,DIRECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.listener_rules["socks"].len(), 1);
+        assert_eq!(cc.listener_rules["mixed"].len(), 1);
+        assert_eq!(cc.listener_rules["5353"].len(), 1);
+
+        for bad in [
+            "dns:\n            - MATCH,DIRECT",
+            "70000:\n            - MATCH,DIRECT",
+            "tun:\n            - MATCH,US",
+        ] {
+            let cfg = format!("listener-rules:\n          {}\n", bad);
+            let c = cfg.parse::<def::Config>().expect("should parse");
+            assert!(Config::try_from(c).is_err());
+        }
+    }
+
+    #[test]
+    fn nameserver_policy_route() {
+        let cfg = r#"
+        dns:
+          nameserver-policy:
+            'www.baidu.com': 114.114.114.114
+            '+.nflxvideo.net':
+              server: 8.8.8.8
+              route: DIRECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.dns.nameserver_policy.len(), 2);
+        assert_eq!(cc.dns.route_hints["+.nflxvideo.net"], "DIRECT");
+
+        let cfg = r#"
+        dns:
+          nameserver-policy:
+            '+.nflxvideo.net':
+              server: 8.8.8.8
+              route: US
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(Config::try_from(c).is_err());
+    }
+
+    #[test]
+    fn nameserver_policy_sets() {
+        let cfg = r#"
+        dns:
+          nameserver-policy:
+            'rule-set:direct': 119.29.29.29
+            'geosite:cn': [114.114.114.114, 223.5.5.5]
+        rule-providers:
+          direct:
+            type: file
+            behavior: domain
+            path: ./direct.yaml
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        // in the order they're written
+        assert_eq!(
+            cc.dns.nameserver_policy[0].0,
+            PolicyDomains::RuleSet("direct".to_owned())
+        );
+        assert_eq!(
+            cc.dns.nameserver_policy[1].0,
+            PolicyDomains::Geosite("cn".to_owned())
+        );
+        assert_eq!(cc.dns.nameserver_policy[1].1.len(), 2);
+
+        let cfg = r#"
+        dns:
+          nameserver-policy:
+            'rule-set:direct':
+              server: 8.8.8.8
+              route: DIRECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(Config::try_from(c).is_err());
+
+        let cfg = r#"
+        dns:
+          nameserver-policy:
+            'rule-set:missing': 8.8.8.8
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(Config::try_from(c).is_err());
+    }
 
     #[test]
     fn from_def_config() {
@@ -223,6 +707,19 @@ mod tests {
         let cc: Config = c.try_into().expect("should into");
         assert_eq!(cc.general.inbound.port, Some(9090));
     }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1000").unwrap(), 1000);
+        assert_eq!(parse_rate("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_rate("2MB").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("1g").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("10X").is_err());
+        assert!(parse_rate("M").is_err());
+        assert_eq!(parse_rate("16777215G").unwrap(), ((1 << 24) - 1) << 30);
+        assert!(parse_rate("17179869184G").is_err());
+    }
 }
 
 pub struct General {
@@ -233,8 +730,251 @@ pub struct General {
     pub ipv6: bool,
     pub interface: Option<Interface>,
     pub routing_mask: Option<u32>,
+    pub outbound_port_range: Option<RangeInclusive<u16>>,
     pub mmdb: String,
     pub mmdb_download_url: Option<String>,
+    pub geosite: String,
+    pub geosite_download_url: Option<String>,
+    pub max_group_depth: usize,
+    pub tcp_timeout: TcpTimeout,
+    pub ftp_helper: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct TcpTimeout {
+    /// `None` if the outbounds connect without a deadline
+    pub handshake: Option<Duration>,
+    /// `None` if idle connections are kept forever
+    pub idle: Option<Duration>,
+    pub half_open: Duration,
+}
+
+/// Rate limits of a traffic shaping class in bytes per second
+#[derive(Clone, Copy, Default)]
+pub struct ShapingLimit {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl TryFrom<def::ShapingClass> for ShapingLimit {
+    type Error = crate::Error;
+
+    fn try_from(c: def::ShapingClass) -> Result<Self, Self::Error> {
+        Ok(Self {
+            upload: c.upload.as_deref().map(parse_rate).transpose()?,
+            download: c.download.as_deref().map(parse_rate).transpose()?,
+        })
+    }
+}
+
+/// Parse a rate like `512K` or `2M` into bytes per second,
+/// the suffixes are 1024 based and an optional trailing `B` is allowed.
+fn parse_rate(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let s = s.strip_suffix(['B', 'b']).unwrap_or(s);
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, ' '),
+    };
+    let multiplier = match unit {
+        ' ' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(Error::InvalidConfig(format!("invalid rate unit: {}", s))),
+    };
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|x| *x > 0)
+        .and_then(|x| x.checked_mul(multiplier))
+        .ok_or_else(|| Error::InvalidConfig(format!("invalid rate: {}", s)))
+}
+
+/// Parse a port range like `40000-40100`, or a single port.
+fn parse_port_range(c: &def::PortRange) -> Result<RangeInclusive<u16>, Error> {
+    let range = match c {
+        def::PortRange::Port(port) => Some(*port..=*port),
+        def::PortRange::Range(s) => {
+            let (start, end) = s.split_once('-').unwrap_or((s, s));
+            match (start.trim().parse(), end.trim().parse()) {
+                (Ok(start), Ok(end)) => Some(start..=end),
+                _ => None,
+            }
+        }
+    };
+    range
+        .filter(|x| *x.start() > 0 && !x.is_empty())
+        .ok_or_else(|| Error::InvalidConfig(format!("invalid outbound-port-range: {:?}", c)))
+}
+
+fn parse_rules(
+    rules: &[String],
+    shortcuts: &HashMap<String, String>,
+) -> Result<Vec<RuleType>, Error> {
+    rules
+        .iter()
+        .map(|x| {
+            let mut rule = x
+                .parse::<RuleType>()
+                .map_err(|x| Error::InvalidConfig(x.to_string()))?;
+            rule.bind_scripts(shortcuts)?;
+            Ok(rule)
+        })
+        .collect()
+}
+
+fn parse_listener_type(s: &str) -> Result<ListenerType, Error> {
+    match s {
+        "http" => Ok(ListenerType::Http),
+        "socks" | "socks5" => Ok(ListenerType::Socks5),
+        "mixed" => Ok(ListenerType::Mixed),
+        "trojan" => Ok(ListenerType::Trojan),
+        _ => Err(Error::InvalidConfig(format!("invalid listener: {}", s))),
+    }
+}
+
+fn parse_api_tokens(c: &def::ApiController) -> Result<HashMap<String, def::ApiTokenScope>, Error> {
+    let mut tokens = HashMap::new();
+    for x in &c.tokens {
+        if x.token.is_empty() {
+            return Err(Error::InvalidConfig(
+                "controller tokens must not be empty".to_owned(),
+            ));
+        }
+        if tokens.insert(x.token.clone(), x.scope).is_some() {
+            return Err(Error::InvalidConfig(
+                "controller tokens must be unique".to_owned(),
+            ));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_inbound_rate_limit(
+    c: &def::InboundRateLimit,
+) -> Result<Option<InboundRateLimitConfig>, Error> {
+    if !c.enable {
+        return Ok(None);
+    }
+    if c.connections == 0 || c.window == 0 {
+        return Err(Error::InvalidConfig(
+            "inbound-rate-limit connections and window must be positive".to_owned(),
+        ));
+    }
+    Ok(Some(InboundRateLimitConfig {
+        connections: c.connections,
+        window: Duration::from_secs(c.window),
+        ban: Duration::from_secs(c.ban),
+        exempt: c
+            .exempt
+            .iter()
+            .map(|x| {
+                x.parse::<IpNet>()
+                    .or_else(|_| x.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        Error::InvalidConfig(format!("invalid inbound-rate-limit exempt: {}", x))
+                    })
+            })
+            .collect::<Result<_, _>>()?,
+    }))
+}
+
+fn parse_trojan_inbound(c: &def::TrojanInbound) -> Result<TrojanInboundConfig, Error> {
+    if c.password.is_empty() {
+        return Err(Error::InvalidConfig(
+            "trojan inbound requires at least one password".to_owned(),
+        ));
+    }
+    Ok(TrojanInboundConfig {
+        port: c.port,
+        password: c.password.clone(),
+        tls: match (&c.certificate, &c.private_key, &c.acme) {
+            (Some(certificate), Some(private_key), None) => InboundTls::Files {
+                certificate: certificate.clone(),
+                private_key: private_key.clone(),
+            },
+            (None, None, Some(acme)) => InboundTls::Acme(AcmeOptions {
+                domains: acme.domains.clone(),
+                email: acme.email.clone(),
+                dir: acme.dir.clone(),
+                staging: acme.staging,
+            }),
+            _ => {
+                return Err(Error::InvalidConfig(
+                    "trojan inbound requires either certificate and private-key, or acme"
+                        .to_owned(),
+                ))
+            }
+        },
+        alpn: c.alpn.clone(),
+        client_ca: c.client_ca.clone(),
+        fallbacks: c
+            .fallbacks
+            .iter()
+            .map(|x| {
+                Ok(TrojanFallback {
+                    sni: x.sni.clone(),
+                    alpn: x.alpn.clone(),
+                    dest: x.dest.parse().map_err(|_| {
+                        Error::InvalidConfig(format!("invalid trojan fallback dest: {}", x.dest))
+                    })?,
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+    })
+}
+
+pub struct TrojanInboundConfig {
+    pub port: u16,
+    pub password: Vec<String>,
+    pub tls: InboundTls,
+    pub alpn: Vec<String>,
+    /// the CA of the client certificates, relative to the config directory
+    pub client_ca: Option<String>,
+    pub fallbacks: Vec<TrojanFallback>,
+}
+
+/// The certificate of a TLS server inbound
+pub enum InboundTls {
+    /// paths are relative to the config directory
+    Files {
+        certificate: String,
+        private_key: String,
+    },
+    Acme(AcmeOptions),
+}
+
+pub struct AcmeOptions {
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    /// the cache of the account and the certificates, relative to the
+    /// config directory
+    pub dir: String,
+    /// use the staging environment of Let's Encrypt
+    pub staging: bool,
+}
+
+pub struct TrojanFallback {
+    pub sni: Option<String>,
+    pub alpn: Option<String>,
+    pub dest: SocketAddr,
+}
+
+pub struct NtpConfig {
+    pub enable: bool,
+    pub server: String,
+    pub port: u16,
+    pub interval: Duration,
+    pub tolerance: Duration,
+}
+
+#[derive(Default)]
+pub struct GatewayConfig {
+    pub enable: bool,
+    pub interface: Option<String>,
+    pub serve_dns: bool,
+    pub auto_route: bool,
 }
 
 pub struct Profile {
@@ -250,12 +990,26 @@ pub struct TunConfig {
     /// tun device id, could be
     /// dev://utun886 # Linux
     /// fd://3 # file descriptor
-    #[serde(alias = "device-url")]
+    /// a device named by the system is created if not set
+    #[serde(alias = "device-url", default)]
     pub device_id: String,
     /// tun device address
     /// default: 198.18.0.0/16
     pub network: Option<String>,
     pub gateway: Option<IpAddr>,
+    /// route the traffic into the device, restored on shutdown
+    #[serde(default)]
+    pub auto_route: bool,
+    /// bind the outbound sockets to the interface of the default route if
+    /// `interface` is not set, so they don't loop back into the device
+    #[serde(default)]
+    pub auto_detect_interface: bool,
+    /// the UDP destinations answered by the DNS resolver, e.g. `any:53`
+    #[serde(default)]
+    pub dns_hijack: Vec<String>,
+    /// the user space TCP/IP stack, `system`, `gvisor` or `mixed`
+    #[serde(default)]
+    pub stack: TunStack,
 }
 
 #[derive(Clone, Default)]
@@ -303,6 +1057,26 @@ pub struct Inbound {
     pub mixed_port: Option<u16>,
     pub authentication: Vec<String>,
     pub bind_address: BindAddress,
+    pub trojan: Option<TrojanInboundConfig>,
+    /// listeners expecting a PROXY protocol header
+    pub proxy_protocol: HashSet<ListenerType>,
+    pub http_block_page: Option<HttpBlockPageConfig>,
+    pub rate_limit: Option<InboundRateLimitConfig>,
+}
+
+pub struct InboundRateLimitConfig {
+    /// the new connections allowed per source IP in a window
+    pub connections: u32,
+    pub window: Duration,
+    /// how long a source going over is banned
+    pub ban: Duration,
+    pub exempt: Vec<IpNet>,
+}
+
+pub struct HttpBlockPageConfig {
+    pub status: u16,
+    /// relative to the config directory
+    pub page: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -310,6 +1084,10 @@ pub struct Controller {
     pub external_controller: Option<String>,
     pub external_ui: Option<String>,
     pub secret: Option<String>,
+    /// scoped tokens besides the secret
+    pub tokens: HashMap<String, def::ApiTokenScope>,
+    /// the /health endpoints need a token too
+    pub health_auth: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -326,6 +1104,9 @@ pub struct HttpRuleProvider {
     pub interval: u64,
     pub behavior: RuleSetBehavior,
     pub path: String,
+    /// detected from the content if not set
+    #[serde(default)]
+    pub format: Option<RuleSetFormat>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -333,6 +1114,9 @@ pub struct FileRuleProvider {
     pub path: String,
     pub interval: Option<u64>,
     pub behavior: RuleSetBehavior,
+    /// detected from the content if not set
+    #[serde(default)]
+    pub format: Option<RuleSetFormat>,
 }
 
 impl TryFrom<HashMap<String, Value>> for RuleProviderDef {
@@ -1,5 +1,6 @@
 pub mod config;
 pub mod proxy;
 pub mod rule;
+pub mod secret;
 
 pub use config::Config as InternalConfig;
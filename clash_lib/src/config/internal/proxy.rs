@@ -1,3 +1,5 @@
+use crate::app::outbound::registry;
+use crate::common::tls::TrustRoots;
 use crate::common::utils::default_bool_true;
 use crate::config::utils;
 use crate::Error;
@@ -50,6 +52,9 @@ pub enum OutboundProxyProtocol {
     Direct,
     #[serde(skip)]
     Reject,
+    /// a named DIRECT with options
+    #[serde(rename = "direct")]
+    DirectWith(OutboundDirect),
     #[serde(rename = "ss")]
     Ss(OutboundShadowsocks),
     #[serde(rename = "socks5")]
@@ -58,12 +63,23 @@ pub enum OutboundProxyProtocol {
     Trojan(OutboundTrojan),
     #[serde(rename = "vmess")]
     Vmess(OutboundVmess),
+    #[serde(rename = "vless")]
+    Vless(OutboundVless),
     #[serde(rename = "wireguard")]
     Wireguard(OutboundWireguard),
     #[serde(rename = "tor")]
     Tor(OutboundTor),
+    #[serde(rename = "ssh")]
+    Ssh(OutboundSsh),
     #[serde(rename = "tuic")]
     Tuic(OutboundTuic),
+    #[serde(rename = "masque")]
+    Masque(OutboundMasque),
+    #[serde(rename = "hysteria2")]
+    Hysteria2(OutboundHysteria2),
+    /// a type registered by the embedding crate
+    #[serde(skip)]
+    Plugin(OutboundPlugin),
 }
 
 impl OutboundProxyProtocol {
@@ -71,13 +87,19 @@ impl OutboundProxyProtocol {
         match &self {
             OutboundProxyProtocol::Direct => PROXY_DIRECT,
             OutboundProxyProtocol::Reject => PROXY_REJECT,
+            OutboundProxyProtocol::DirectWith(direct) => &direct.name,
             OutboundProxyProtocol::Ss(ss) => &ss.name,
             OutboundProxyProtocol::Socks5(socks5) => &socks5.name,
             OutboundProxyProtocol::Trojan(trojan) => &trojan.name,
             OutboundProxyProtocol::Vmess(vmess) => &vmess.name,
+            OutboundProxyProtocol::Vless(vless) => &vless.name,
             OutboundProxyProtocol::Wireguard(wireguard) => &wireguard.name,
             OutboundProxyProtocol::Tor(tor) => &tor.name,
+            OutboundProxyProtocol::Ssh(ssh) => &ssh.name,
             OutboundProxyProtocol::Tuic(tuic) => &tuic.name,
+            OutboundProxyProtocol::Masque(masque) => &masque.name,
+            OutboundProxyProtocol::Hysteria2(hysteria2) => &hysteria2.name,
+            OutboundProxyProtocol::Plugin(plugin) => &plugin.name,
         }
     }
 }
@@ -93,6 +115,15 @@ impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
                 "missing field `name` in outbound proxy protocol".to_owned(),
             ))?
             .to_owned();
+        if let Some(typ) = mapping.get("type").and_then(|x| x.as_str()) {
+            if registry::is_registered(typ) {
+                return Ok(OutboundProxyProtocol::Plugin(OutboundPlugin {
+                    name,
+                    typ: typ.to_owned(),
+                    options: mapping,
+                }));
+            }
+        }
         OutboundProxyProtocol::deserialize(MapDeserializer::new(mapping.into_iter()))
             .map_err(map_serde_error(name))
     }
@@ -105,15 +136,40 @@ impl Display for OutboundProxyProtocol {
             OutboundProxyProtocol::Socks5(_) => write!(f, "Socks5"),
             OutboundProxyProtocol::Direct => write!(f, "{}", PROXY_DIRECT),
             OutboundProxyProtocol::Reject => write!(f, "{}", PROXY_REJECT),
+            OutboundProxyProtocol::DirectWith(_) => write!(f, "{}", PROXY_DIRECT),
             OutboundProxyProtocol::Trojan(_) => write!(f, "Trojan"),
             OutboundProxyProtocol::Vmess(_) => write!(f, "Vmess"),
+            OutboundProxyProtocol::Vless(_) => write!(f, "Vless"),
             OutboundProxyProtocol::Wireguard(_) => write!(f, "Wireguard"),
             OutboundProxyProtocol::Tor(_) => write!(f, "Tor"),
+            OutboundProxyProtocol::Ssh(_) => write!(f, "Ssh"),
             OutboundProxyProtocol::Tuic(_) => write!(f, "Tuic"),
+            OutboundProxyProtocol::Masque(_) => write!(f, "Masque"),
+            OutboundProxyProtocol::Hysteria2(_) => write!(f, "Hysteria2"),
+            OutboundProxyProtocol::Plugin(p) => write!(f, "{}", p.typ),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct OutboundPlugin {
+    pub name: String,
+    pub typ: String,
+    pub options: HashMap<String, Value>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundDirect {
+    pub name: String,
+    /// connect to this backend instead of the destination of the connection
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    /// send a PROXY protocol header of this version, 1 or 2, so the origin
+    /// server sees the address of the client
+    pub proxy_protocol: Option<u8>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
 pub struct OutboundShadowsocks {
     pub name: String,
@@ -126,18 +182,37 @@ pub struct OutboundShadowsocks {
     pub plugin: Option<String>,
     #[serde(alias = "plugin-opts")]
     pub plugin_opts: Option<HashMap<String, serde_yaml::Value>>,
+    /// pass the domain to the server to resolve, otherwise resolve it locally
+    #[serde(default = "default_bool_true", alias = "remote-dns-resolve")]
+    pub remote_dns_resolve: bool,
+    /// carry UDP over TCP (UoT v2), the server must support it
+    #[serde(default)]
+    pub uot: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
 pub struct OutboundSocks5 {
     pub name: String,
+    /// a host, or the path of a Unix socket or a named pipe, which can't
+    /// follow another proxy in a relay
     pub server: String,
+    /// not needed for a local socket
+    #[serde(default)]
     pub port: u16,
     pub username: Option<String>,
     pub password: Option<String>,
+    #[serde(default)]
     pub tls: bool,
+    #[serde(default, alias = "skip-cert-verify")]
     pub skip_cert_verity: bool,
+    /// required for tls to a local socket
+    pub sni: Option<String>,
+    /// UDP ASSOCIATE is not supported, see `uot`
+    #[serde(default)]
     pub udp: bool,
+    /// carry UDP over TCP (UoT v2), the server must support it
+    #[serde(default)]
+    pub uot: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -146,6 +221,9 @@ pub struct WsOpt {
     pub headers: Option<HashMap<String, String>>,
     pub max_early_data: Option<i32>,
     pub early_data_header_name: Option<String>,
+    /// seconds between the pings keeping an idle connection alive
+    #[serde(alias = "ping-interval")]
+    pub ping_interval: Option<u64>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -158,6 +236,8 @@ pub struct H2Opt {
 #[serde(rename_all = "kebab-case")]
 pub struct GrpcOpt {
     pub grpc_service_name: Option<String>,
+    /// seconds between the http/2 pings keeping an idle connection alive
+    pub ping_interval: Option<u64>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -170,10 +250,39 @@ pub struct OutboundTrojan {
     pub alpn: Option<Vec<String>>,
     pub sni: Option<String>,
     pub skip_cert_verify: Option<bool>,
+    /// the roots to trust, `auto`, `system` or `bundled`
+    pub trust: Option<TrustRoots>,
+    /// extra CAs to trust, as a PEM file path or in PEM
+    pub ca: Option<String>,
+    pub ca_str: Option<String>,
     pub udp: Option<bool>,
+    /// pass the domain to the server to resolve, otherwise resolve it locally
+    #[serde(default = "default_bool_true")]
+    pub remote_dns_resolve: bool,
     pub network: Option<String>,
     pub grpc_opts: Option<GrpcOpt>,
     pub ws_opts: Option<WsOpt>,
+    /// the mux of trojan-go
+    pub mux_opts: Option<TrojanMuxOpt>,
+    /// the shadowsocks layer of trojan-go, under the trojan request
+    pub ss_opts: Option<TrojanSsOpt>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TrojanMuxOpt {
+    /// the connections carried by a session at once, 8 by default
+    pub concurrency: Option<usize>,
+    /// seconds a session without connections is kept, 60 by default
+    pub idle_timeout: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TrojanSsOpt {
+    /// aes-128-gcm, aes-256-gcm or chacha20-ietf-poly1305
+    pub method: Option<String>,
+    pub password: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -187,8 +296,16 @@ pub struct OutboundVmess {
     pub alter_id: u16,
     pub cipher: Option<String>,
     pub udp: Option<bool>,
+    /// pass the domain to the server to resolve, otherwise resolve it locally
+    #[serde(default = "default_bool_true")]
+    pub remote_dns_resolve: bool,
     pub tls: Option<bool>,
     pub skip_cert_verify: Option<bool>,
+    /// the roots to trust, `auto`, `system` or `bundled`
+    pub trust: Option<TrustRoots>,
+    /// extra CAs to trust, as a PEM file path or in PEM
+    pub ca: Option<String>,
+    pub ca_str: Option<String>,
     #[serde(alias = "servername")]
     pub server_name: Option<String>,
     pub network: Option<String>,
@@ -197,13 +314,55 @@ pub struct OutboundVmess {
     pub grpc_opts: Option<GrpcOpt>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundVless {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub uuid: String,
+    /// `xtls-rprx-vision`, over TLS and TCP only
+    pub flow: Option<String>,
+    pub udp: Option<bool>,
+    /// pass the domain to the server to resolve, otherwise resolve it locally
+    #[serde(default = "default_bool_true")]
+    pub remote_dns_resolve: bool,
+    pub tls: Option<bool>,
+    pub skip_cert_verify: Option<bool>,
+    /// the roots to trust, `auto`, `system` or `bundled`
+    pub trust: Option<TrustRoots>,
+    /// extra CAs to trust, as a PEM file path or in PEM
+    pub ca: Option<String>,
+    pub ca_str: Option<String>,
+    #[serde(alias = "sni")]
+    pub servername: Option<String>,
+    pub alpn: Option<Vec<String>>,
+    /// the uTLS client hello to mimic, e.g. `chrome`
+    pub client_fingerprint: Option<String>,
+    pub reality_opts: Option<RealityOpt>,
+    pub network: Option<String>,
+    pub ws_opts: Option<WsOpt>,
+    pub grpc_opts: Option<GrpcOpt>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RealityOpt {
+    pub public_key: String,
+    pub short_id: Option<String>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundWireguard {
     pub name: String,
+    /// the peer may be given in `peers` instead, as Clash.Meta does
+    #[serde(default)]
     pub server: String,
+    #[serde(default)]
     pub port: u16,
     pub private_key: String,
+    #[serde(default)]
     pub public_key: String,
     pub preshared_key: Option<String>,
     pub mtu: Option<u16>,
@@ -213,13 +372,61 @@ pub struct OutboundWireguard {
     pub remote_dns_resolve: Option<bool>,
     pub dns: Option<Vec<String>>,
     pub allowed_ips: Option<Vec<String>>,
-    pub reserved_bits: Option<Vec<u8>>,
+    #[serde(alias = "reserved")]
+    pub reserved_bits: Option<WireguardReserved>,
+    /// a single peer is supported
+    pub peers: Option<Vec<WireguardPeer>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct WireguardPeer {
+    pub server: String,
+    pub port: u16,
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub allowed_ips: Option<Vec<String>>,
+    pub reserved: Option<WireguardReserved>,
+}
+
+/// The 3 reserved bytes of the messages, e.g. `[209, 98, 59]` or their
+/// base64 `0WI7`, some servers identify the clients by them.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WireguardReserved {
+    Bytes(Vec<u8>),
+    Base64(String),
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundTor {
     pub name: String,
+    /// the SocksPort of a Tor daemon, a host or a socket path; the embedded
+    /// client is used without it and `port`
+    pub server: Option<String>,
+    /// 9050 by default
+    pub port: Option<u16>,
+    /// log in to the SocksPort with different credentials for each
+    /// destination host, so unrelated sites don't share a circuit
+    #[serde(default = "default_bool_true")]
+    pub isolation: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundSsh {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    /// the PEM or OpenSSH key, or the path to it
+    pub private_key: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    /// as in `known_hosts`, e.g. `ssh-ed25519 AAAA...`, or the SHA256
+    /// fingerprints, required
+    pub host_key: Option<Vec<String>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -243,6 +450,13 @@ pub struct OutboundTuic {
     pub congestion_controller: Option<String>,
     /// bytes
     pub max_udp_relay_packet_size: Option<u64>,
+    /// discover the path MTU to send bigger datagrams, true by default
+    pub mtu_discovery: Option<bool>,
+    /// the MTU assumed until discovered, 1200 at least and by default
+    pub initial_mtu: Option<u16>,
+    /// split the UDP packets too big for a datagram in fragments, else
+    /// they are relayed on a QUIC stream, true by default
+    pub udp_fragment: Option<bool>,
     pub fast_open: Option<bool>,
     pub skip_cert_verify: Option<bool>,
     pub max_open_stream: Option<u64>,
@@ -255,6 +469,53 @@ pub struct OutboundTuic {
     pub receive_window: Option<u64>,
 }
 
+/// UDP only, over HTTP/3
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundMasque {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    /// defaults to `server`
+    pub sni: Option<String>,
+    pub skip_cert_verify: Option<bool>,
+    /// sent as the `authorization` header, e.g. `Bearer <token>`
+    pub authorization: Option<String>,
+    /// seconds between the QUIC pings keeping an idle connection alive,
+    /// 15 by default
+    pub keep_alive_interval: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundHysteria2 {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub password: String,
+    /// the upload bandwidth, the packets are sent at this rate whatever
+    /// the losses, left to the QUIC congestion control if not set
+    pub up: Option<Hysteria2Bandwidth>,
+    /// the download bandwidth, the server is asked to send at this rate
+    pub down: Option<Hysteria2Bandwidth>,
+    /// `salamander` to obfuscate the packets
+    pub obfs: Option<String>,
+    pub obfs_password: Option<String>,
+    /// defaults to `server`
+    pub sni: Option<String>,
+    pub skip_cert_verify: Option<bool>,
+    /// h3 by default
+    pub alpn: Option<Vec<String>>,
+}
+
+/// e.g. `100 Mbps`, `10 MBps` for bytes, or a number of Mbps
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Hysteria2Bandwidth {
+    Mbps(u64),
+    Text(String),
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum OutboundGroupProtocol {
@@ -290,6 +551,16 @@ impl OutboundGroupProtocol {
             OutboundGroupProtocol::Select(g) => g.proxies.as_ref(),
         }
     }
+
+    pub fn use_provider(&self) -> Option<&Vec<String>> {
+        match &self {
+            OutboundGroupProtocol::Relay(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::UrlTest(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::Fallback(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::LoadBalance(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::Select(g) => g.use_provider.as_ref(),
+        }
+    }
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundGroupProtocol {
@@ -340,6 +611,11 @@ pub struct OutboundGroupUrlTest {
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    /// pause health checks after no traffic for this many seconds
+    #[serde(rename = "idle-timeout")]
+    pub idle_timeout: Option<u64>,
+    /// probe the busy proxies every interval and the idle ones less often
+    pub adaptive: Option<bool>,
     pub tolerance: Option<u16>,
 }
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -354,6 +630,15 @@ pub struct OutboundGroupFallback {
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    /// pause health checks after no traffic for this many seconds
+    #[serde(rename = "idle-timeout")]
+    pub idle_timeout: Option<u64>,
+    /// probe the busy proxies every interval and the idle ones less often
+    pub adaptive: Option<bool>,
+    /// the dials through the current proxy that may fail in a row before
+    /// the members are checked again, 5 by default
+    #[serde(rename = "max-failed-times")]
+    pub max_failed_times: Option<u32>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -368,6 +653,11 @@ pub struct OutboundGroupLoadBalance {
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    /// pause health checks after no traffic for this many seconds
+    #[serde(rename = "idle-timeout")]
+    pub idle_timeout: Option<u64>,
+    /// probe the busy proxies every interval and the idle ones less often
+    pub adaptive: Option<bool>,
     pub strategy: Option<LoadBalanceStrategy>,
 }
 
@@ -378,6 +668,10 @@ pub enum LoadBalanceStrategy {
     ConsistentHashing,
     #[serde(rename = "round-robin")]
     RoundRobin,
+    /// by the source IP and the destination, kept on the same proxy while
+    /// it's alive
+    #[serde(rename = "sticky-sessions")]
+    StickySessions,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -425,6 +719,13 @@ pub struct HealthCheck {
     pub url: String,
     pub interval: u64,
     pub lazy: Option<bool>,
+    /// pause health checks after no traffic for this many seconds
+    #[serde(rename = "idle-timeout")]
+    pub idle_timeout: Option<u64>,
+    /// probe the busy proxies every interval and the idle ones less often
+    pub adaptive: Option<bool>,
+    /// the background probes allowed per hour, unlimited if not set
+    pub budget: Option<u32>,
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProviderDef {
@@ -1,5 +1,26 @@
+use crate::app::router::{registry, Cron, RuleMatcher};
+use crate::session::Network;
 use crate::Error;
-use std::{fmt::Display, str::FromStr};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+/// The operator of a logical rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogicOp {
+    And,
+    Or,
+    /// takes exactly one rule
+    Not,
+}
+
+impl Display for LogicOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogicOp::And => "AND",
+            LogicOp::Or => "OR",
+            LogicOp::Not => "NOT",
+        })
+    }
+}
 
 pub enum RuleType {
     Domain {
@@ -19,6 +40,11 @@ pub enum RuleType {
         country_code: String,
         no_resolve: bool,
     },
+    /// a category of the geosite database, e.g. `google` or `cn@!ads`
+    GeoSite {
+        target: String,
+        category: String,
+    },
     IpCidr {
         ipnet: ipnet::IpNet,
         target: String,
@@ -45,30 +71,115 @@ pub enum RuleType {
         process_path: String,
         target: String,
     },
+    Network {
+        network: Network,
+        target: String,
+    },
     RuleSet {
         rule_set: String,
         target: String,
     },
+    /// an expression named in `script.shortcuts`, the code is filled in
+    /// from there once the rules are parsed
+    Script {
+        shortcut: String,
+        code: String,
+        target: String,
+    },
+    /// the times of a cron like expression, e.g. `* 1-6 * * *`
+    Schedule {
+        cron: Cron,
+        target: String,
+    },
     Match {
         target: String,
     },
+    /// the `china-direct` preset, not written as a rule
+    ChinaDirect {
+        target: String,
+    },
+    /// e.g. `AND,((DOMAIN,baidu.com),(NETWORK,UDP)),REJECT`, the rules
+    /// inside can be logical ones too
+    Logic {
+        op: LogicOp,
+        rules: Vec<RuleType>,
+        /// the parenthesized rules as written
+        payload: String,
+        target: String,
+    },
+    /// a rule with a traffic shaping class attached, e.g. `class=bulk`
+    Shaped {
+        rule: Box<RuleType>,
+        class: String,
+    },
+    /// e.g. `SUB-RULE,(NETWORK,UDP),udp-rules`, jumping into the named set
+    /// of `sub-rules`
+    SubRule {
+        rule: Box<RuleType>,
+        sub_rules: String,
+    },
+    /// a keyword registered by the embedding crate, built when parsed
+    Plugin {
+        keyword: String,
+        target: String,
+        matcher: Box<dyn RuleMatcher>,
+    },
 }
 
 impl RuleType {
+    /// Fill in the code of the `SCRIPT` rules, nested ones included, from
+    /// the shortcuts.
+    pub fn bind_scripts(&mut self, shortcuts: &HashMap<String, String>) -> Result<(), Error> {
+        match self {
+            RuleType::Script { shortcut, code, .. } => {
+                *code = shortcuts.get(shortcut).cloned().ok_or_else(|| {
+                    Error::InvalidConfig(format!("script shortcut {} not found", shortcut))
+                })?;
+            }
+            RuleType::Logic { rules, .. } => {
+                for rule in rules {
+                    rule.bind_scripts(shortcuts)?;
+                }
+            }
+            RuleType::Shaped { rule, .. } => rule.bind_scripts(shortcuts)?,
+            RuleType::SubRule { rule, .. } => rule.bind_scripts(shortcuts)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The set of `sub-rules` the rule jumps into, if it's a `SUB-RULE`.
+    pub fn sub_rules(&self) -> Option<&str> {
+        match self {
+            RuleType::SubRule { sub_rules, .. } => Some(sub_rules),
+            RuleType::Shaped { rule, .. } => rule.sub_rules(),
+            _ => None,
+        }
+    }
+
     pub fn target(&self) -> &str {
         match self {
             RuleType::Domain { target, .. } => target,
             RuleType::DomainSuffix { target, .. } => target,
             RuleType::DomainKeyword { target, .. } => target,
             RuleType::GeoIP { target, .. } => target,
+            RuleType::GeoSite { target, .. } => target,
+            RuleType::ChinaDirect { target } => target,
             RuleType::IpCidr { target, .. } => target,
             RuleType::SrcCidr { target, .. } => target,
             RuleType::SRCPort { target, .. } => target,
             RuleType::DSTPort { target, .. } => target,
             RuleType::ProcessName { target, .. } => target,
             RuleType::ProcessPath { target, .. } => target,
+            RuleType::Network { target, .. } => target,
             RuleType::RuleSet { target, .. } => target,
+            RuleType::Script { target, .. } => target,
+            RuleType::Schedule { target, .. } => target,
             RuleType::Match { target } => target,
+            RuleType::Logic { target, .. } => target,
+            RuleType::Shaped { rule, .. } => rule.target(),
+            RuleType::SubRule { sub_rules, .. } => sub_rules,
+            RuleType::Plugin { target, .. } => target,
         }
     }
 }
@@ -80,14 +191,23 @@ impl Display for RuleType {
             RuleType::DomainSuffix { .. } => write!(f, "DOMAIN-SUFFIX"),
             RuleType::DomainKeyword { .. } => write!(f, "DOMAIN-KEYWORD"),
             RuleType::GeoIP { .. } => write!(f, "GEOIP"),
+            RuleType::GeoSite { .. } => write!(f, "GEOSITE"),
+            RuleType::ChinaDirect { .. } => write!(f, "CHINA-DIRECT"),
             RuleType::IpCidr { .. } => write!(f, "IP-CIDR"),
             RuleType::SrcCidr { .. } => write!(f, "SRC-IP-CIDR"),
             RuleType::SRCPort { .. } => write!(f, "SRC-PORT"),
             RuleType::DSTPort { .. } => write!(f, "DST-PORT"),
             RuleType::ProcessName { .. } => write!(f, "PROCESS-NAME"),
             RuleType::ProcessPath { .. } => write!(f, "PROCESS-PATH"),
+            RuleType::Network { .. } => write!(f, "NETWORK"),
             RuleType::RuleSet { .. } => write!(f, "RULE-SET"),
+            RuleType::Script { .. } => write!(f, "SCRIPT"),
+            RuleType::Schedule { .. } => write!(f, "SCHEDULE"),
             RuleType::Match { .. } => write!(f, "MATCH"),
+            RuleType::Logic { op, .. } => write!(f, "{}", op),
+            RuleType::Shaped { rule, .. } => write!(f, "{}", rule),
+            RuleType::SubRule { .. } => write!(f, "SUB-RULE"),
+            RuleType::Plugin { keyword, .. } => write!(f, "{}", keyword),
         }
     }
 }
@@ -112,6 +232,9 @@ impl RuleType {
                 domain_keyword: payload.to_string(),
                 target: target.to_string(),
             }),
+            "GEOIP" if payload.split('|').any(|x| x.trim().is_empty()) => Err(
+                Error::InvalidConfig(format!("invalid GEOIP country code: {}", payload)),
+            ),
             "GEOIP" => Ok(RuleType::GeoIP {
                 target: target.to_string(),
                 country_code: payload.to_string(),
@@ -121,6 +244,13 @@ impl RuleType {
                     false
                 },
             }),
+            "GEOSITE" if payload.is_empty() => Err(Error::InvalidConfig(
+                "GEOSITE requires a category".to_owned(),
+            )),
+            "GEOSITE" => Ok(RuleType::GeoSite {
+                target: target.to_string(),
+                category: payload.to_string(),
+            }),
             "IP-CIDR" | "IP-CIDR6" => Ok(RuleType::IpCidr {
                 ipnet: payload.parse()?,
                 target: target.to_string(),
@@ -159,35 +289,206 @@ impl RuleType {
                 process_path: payload.to_string(),
                 target: target.to_string(),
             }),
+            "NETWORK" => Ok(RuleType::Network {
+                network: match payload.to_ascii_uppercase().as_str() {
+                    "TCP" => Network::Tcp,
+                    "UDP" => Network::Udp,
+                    _ => {
+                        return Err(Error::InvalidConfig(format!(
+                            "invalid NETWORK: {}",
+                            payload
+                        )))
+                    }
+                },
+                target: target.to_string(),
+            }),
             "RULE-SET" => Ok(RuleType::RuleSet {
                 rule_set: payload.to_string(),
                 target: target.to_string(),
             }),
+            "SCRIPT" => Ok(RuleType::Script {
+                shortcut: payload.to_string(),
+                code: String::new(),
+                target: target.to_string(),
+            }),
+            "SCHEDULE" => Ok(RuleType::Schedule {
+                cron: payload.parse()?,
+                target: target.to_string(),
+            }),
             "MATCH" => Ok(RuleType::Match {
                 target: target.to_string(),
             }),
-            _ => Err(Error::InvalidConfig(format!(
-                "unsupported rule type: {}",
-                proto
-            ))),
+            _ => match registry::create(proto, payload, target, &params.unwrap_or_default()) {
+                Some(matcher) => Ok(RuleType::Plugin {
+                    keyword: proto.to_string(),
+                    target: target.to_string(),
+                    matcher: matcher?,
+                }),
+                None => Err(Error::InvalidConfig(format!(
+                    "unsupported rule type: {}",
+                    proto
+                ))),
+            },
+        }
+    }
+
+    /// A rule without a target of its own, inside a logical rule or a rule
+    /// set, e.g. `DOMAIN,baidu.com` or `OR,((NETWORK,UDP),(DST-PORT,53))`.
+    pub fn new_nested(rule: &str, target: &str) -> Result<Self, Error> {
+        if let Some((op, payload, rest)) = split_logic(rule)? {
+            if !rest.trim().is_empty() {
+                return Err(Error::InvalidConfig(format!("invalid rule: {}", rule)));
+            }
+            return Self::new_logic(op, payload, target);
+        }
+
+        let parts = rule.split(',').map(str::trim).collect::<Vec<&str>>();
+        match parts.as_slice() {
+            [proto, payload] => RuleType::new(proto, payload, target, None),
+            [proto, payload, params @ ..] => {
+                RuleType::new(proto, payload, target, Some(params.to_vec()))
+            }
+            _ => Err(Error::InvalidConfig(format!("invalid rule: {}", rule))),
+        }
+    }
+
+    /// The logical rule of the parenthesized rules of `payload`, e.g.
+    /// `((DOMAIN,baidu.com),(NETWORK,UDP))`.
+    fn new_logic(op: LogicOp, payload: &str, target: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidConfig(format!("invalid {} rule: {}", op, payload));
+
+        let mut inner = payload[1..payload.len() - 1].trim_start();
+        let mut rules = vec![];
+        while !inner.is_empty() {
+            let end = closing_paren(inner).ok_or_else(invalid)?;
+            rules.push(Self::new_nested(&inner[1..end], target)?);
+            inner = inner[end + 1..].trim_start();
+            if let Some(rest) = inner.strip_prefix(',') {
+                inner = rest.trim_start();
+            } else if !inner.is_empty() {
+                return Err(invalid());
+            }
+        }
+
+        match (op, rules.len()) {
+            (_, 0) | (LogicOp::Not, 2..) => Err(invalid()),
+            _ => Ok(RuleType::Logic {
+                op,
+                rules,
+                payload: payload.to_owned(),
+                target: target.to_owned(),
+            }),
+        }
+    }
+
+    /// `rest` follows the `SUB-RULE,` of the line, e.g.
+    /// `(DOMAIN-SUFFIX,example.com),my-sub-rules`.
+    fn new_sub_rule(line: &str, rest: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidConfig(format!("invalid SUB-RULE rule: {}", line));
+
+        let rest = rest.trim_start();
+        let end = closing_paren(rest).ok_or_else(invalid)?;
+        let sub_rules = rest[end + 1..]
+            .trim_start()
+            .strip_prefix(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.contains(','))
+            .ok_or_else(invalid)?;
+
+        Ok(RuleType::SubRule {
+            rule: Box::new(Self::new_nested(&rest[1..end], sub_rules)?),
+            sub_rules: sub_rules.to_owned(),
+        })
+    }
+}
+
+/// Split a logical rule line like `AND,((DOMAIN,a.com),(NETWORK,UDP)),DIRECT`
+/// into its operator, its parenthesized payload and what follows it, or
+/// `None` if the line isn't a logical rule.
+fn split_logic(line: &str) -> Result<Option<(LogicOp, &str, &str)>, Error> {
+    let Some((proto, rest)) = line.split_once(',') else {
+        return Ok(None);
+    };
+    let op = match proto.trim() {
+        "AND" => LogicOp::And,
+        "OR" => LogicOp::Or,
+        "NOT" => LogicOp::Not,
+        _ => return Ok(None),
+    };
+
+    let invalid = || Error::InvalidConfig(format!("invalid {} rule: {}", op, line));
+    let rest = rest.trim_start();
+    let end = closing_paren(rest).ok_or_else(invalid)?;
+    let after = rest[end + 1..].trim_start();
+    let after = match after.strip_prefix(',') {
+        Some(after) => after,
+        None if after.is_empty() => after,
+        None => return Err(invalid()),
+    };
+    Ok(Some((op, &rest[..=end], after)))
+}
+
+/// The index of the parenthesis closing the one `s` starts with.
+fn closing_paren(s: &str) -> Option<usize> {
+    if !s.starts_with('(') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
         }
     }
+    None
 }
 
 impl TryFrom<String> for RuleType {
     type Error = crate::Error;
 
     fn try_from(line: String) -> Result<Self, Self::Error> {
-        let parts = line.split(',').map(str::trim).collect::<Vec<&str>>();
+        if let Some(rest) = line.strip_prefix("SUB-RULE,") {
+            return RuleType::new_sub_rule(&line, rest);
+        }
+        // the payload of a logical rule has commas of its own
+        let (logic, rest) = match split_logic(&line)? {
+            Some((op, payload, rest)) => (Some((op, payload)), rest),
+            None => (None, line.as_str()),
+        };
+        let mut parts = rest.split(',').map(str::trim).collect::<Vec<&str>>();
 
-        match parts.as_slice() {
-            [proto, target] => RuleType::new(proto, "", target, None),
-            [proto, payload, target] => RuleType::new(proto, payload, target, None),
-            [proto, payload, target, params @ ..] => {
+        let mut class = None;
+        parts.retain(|x| match x.strip_prefix("class=") {
+            Some(c) => {
+                class = Some(c.to_owned());
+                false
+            }
+            None => true,
+        });
+
+        let rule = match (logic, parts.as_slice()) {
+            (Some((op, payload)), [target]) => RuleType::new_logic(op, payload, target),
+            (None, [proto, target]) => RuleType::new(proto, "", target, None),
+            (None, [proto, payload, target]) => RuleType::new(proto, payload, target, None),
+            (None, [proto, payload, target, params @ ..]) => {
                 RuleType::new(proto, payload, target, Some(params.to_vec()))
             }
             _ => Err(Error::InvalidConfig(format!("invalid rule line: {}", line))),
-        }
+        }?;
+
+        Ok(match class {
+            Some(class) => RuleType::Shaped {
+                rule: Box::new(rule),
+                class,
+            },
+            None => rule,
+        })
     }
 }
 
@@ -198,3 +499,92 @@ impl FromStr for RuleType {
         s.to_string().try_into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::session::Network;
+
+    use super::{LogicOp, RuleType};
+
+    #[test]
+    fn test_parse_logic() {
+        let rule: RuleType = "AND,((DOMAIN,baidu.com),(NETWORK,UDP)),REJECT,class=bulk"
+            .parse()
+            .unwrap();
+        let RuleType::Shaped { rule, .. } = rule else {
+            panic!("class lost");
+        };
+        let RuleType::Logic {
+            op, rules, target, ..
+        } = *rule
+        else {
+            panic!("not a logical rule");
+        };
+        assert_eq!(op, LogicOp::And);
+        assert_eq!(target, "REJECT");
+        assert!(matches!(&rules[0], RuleType::Domain { domain, .. } if domain == "baidu.com"));
+        assert!(matches!(
+            &rules[1],
+            RuleType::Network {
+                network: Network::Udp,
+                ..
+            }
+        ));
+
+        let rule: RuleType = "OR,((NOT,((DST-PORT,443))),(IP-CIDR,10.0.0.0/8,no-resolve)),DIRECT"
+            .parse()
+            .unwrap();
+        let RuleType::Logic { rules, .. } = rule else {
+            panic!("not a logical rule");
+        };
+        assert!(
+            matches!(&rules[0], RuleType::Logic { op: LogicOp::Not, rules, .. } if rules.len() == 1)
+        );
+        assert!(matches!(
+            &rules[1],
+            RuleType::IpCidr {
+                no_resolve: true,
+                ..
+            }
+        ));
+
+        for invalid in [
+            "AND,((DOMAIN,baidu.com),REJECT",
+            "NOT,((DOMAIN,a.com),(DOMAIN,b.com)),REJECT",
+            "OR,(),REJECT",
+            "AND,((DOMAIN,baidu.com))x,REJECT",
+        ] {
+            assert!(invalid.parse::<RuleType>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_sub_rule() {
+        let rule: RuleType = "SUB-RULE,(OR,((NETWORK,UDP),(DST-PORT,53))),dns"
+            .parse()
+            .unwrap();
+        let RuleType::SubRule {
+            rule, sub_rules, ..
+        } = rule
+        else {
+            panic!("not a sub-rule");
+        };
+        assert_eq!(sub_rules, "dns");
+        assert!(matches!(
+            *rule,
+            RuleType::Logic {
+                op: LogicOp::Or,
+                ..
+            }
+        ));
+
+        for invalid in [
+            "SUB-RULE,(NETWORK,UDP)",
+            "SUB-RULE,(NETWORK,UDP),",
+            "SUB-RULE,NETWORK,UDP,dns",
+            "SUB-RULE,(NETWORK,UDP),dns,REJECT",
+        ] {
+            assert!(invalid.parse::<RuleType>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Secrets of the proxies kept out of the config
+//!
+//! A secret field of a proxy, e.g. `password`, can be given as
+//! `password-file`, a file holding the secret, or `password-keyring`, the
+//! user of an entry of the `clash-rs` service in the OS keyring, so the
+//! config can be shared without them.
+//!
+//! ```yaml
+//! proxies:
+//!   - name: ss-hk
+//!     type: ss
+//!     password-file: /run/secrets/ss-hk
+//!   - name: wg
+//!     type: wireguard
+//!     private-key-keyring: wg
+//!     peers:
+//!       - preshared-key-file: ./secrets/wg-psk
+//! ```
+//!
+//! The files are relative to the config home, see
+//! [`ConfigHome`](crate::config::home::ConfigHome).
+//!
+//! Only the proxies of the config are resolved, never the ones from proxy
+//! providers, which would otherwise be able to read local files.
+
+use std::collections::HashMap;
+
+use serde_yaml::Value;
+
+use crate::{config::home::resolve_path, Error};
+
+/// the fields which may be kept out of the config
+const SECRET_FIELDS: &[&str] = &["password", "uuid", "private-key", "preshared-key"];
+/// the fields of a wireguard peer which may be kept out of the config
+const PEER_SECRET_FIELDS: &[&str] = &["preshared-key"];
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "clash-rs";
+
+/// Replace the `<field>-file` and `<field>-keyring` keys of a proxy, and of
+/// its wireguard `peers`, by the secrets they point at.
+pub fn resolve(mapping: &mut HashMap<String, Value>) -> Result<(), Error> {
+    let name = mapping
+        .get("name")
+        .and_then(|x| x.as_str())
+        .unwrap_or_default()
+        .to_owned();
+
+    resolve_fields(&name, mapping, SECRET_FIELDS)?;
+
+    if let Some(Value::Sequence(peers)) = mapping.get_mut("peers") {
+        for peer in peers.iter_mut().filter_map(|x| x.as_mapping_mut()) {
+            let mut fields = std::mem::take(peer)
+                .into_iter()
+                .filter_map(|(k, v)| Some((k.as_str()?.to_owned(), v)))
+                .collect();
+            resolve_fields(&name, &mut fields, PEER_SECRET_FIELDS)?;
+            *peer = fields
+                .into_iter()
+                .map(|(k, v)| (Value::String(k), v))
+                .collect();
+        }
+    }
+    Ok(())
+}
+
+fn resolve_fields(
+    name: &str,
+    mapping: &mut HashMap<String, Value>,
+    fields: &[&str],
+) -> Result<(), Error> {
+    for field in fields {
+        let file = mapping.remove(&format!("{}-file", field));
+        let keyring = mapping.remove(&format!("{}-keyring", field));
+
+        let secret = match (file, keyring) {
+            (None, None) => continue,
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy {}: {}-file and {}-keyring are exclusive",
+                    name, field, field
+                )))
+            }
+            (Some(path), None) => {
+                let path = resolve_path(secret_ref(name, field, "file", &path)?);
+                std::fs::read_to_string(&path)
+                    .map_err(|e| {
+                        Error::InvalidConfig(format!(
+                            "proxy {}: failed to read {}-file {}: {}",
+                            name,
+                            field,
+                            path.display(),
+                            e
+                        ))
+                    })?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_owned()
+            }
+            (None, Some(user)) => {
+                let user = secret_ref(name, field, "keyring", &user)?;
+                from_keyring(user).map_err(|e| {
+                    Error::InvalidConfig(format!(
+                        "proxy {}: failed to read {}-keyring {}: {}",
+                        name, field, user, e
+                    ))
+                })?
+            }
+        };
+
+        if mapping.contains_key(*field) {
+            return Err(Error::InvalidConfig(format!(
+                "proxy {}: {} is given both inline and from a file or the keyring",
+                name, field
+            )));
+        }
+        mapping.insert(field.to_string(), Value::String(secret));
+    }
+    Ok(())
+}
+
+fn secret_ref<'a>(name: &str, field: &str, kind: &str, v: &'a Value) -> Result<&'a str, Error> {
+    v.as_str().ok_or_else(|| {
+        Error::InvalidConfig(format!(
+            "proxy {}: {}-{} must be a string",
+            name, field, kind
+        ))
+    })
+}
+
+#[cfg(feature = "keyring")]
+fn from_keyring(user: &str) -> Result<String, String> {
+    keyring::Entry::new(KEYRING_SERVICE, user)
+        .and_then(|entry| entry.get_password())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "keyring"))]
+fn from_keyring(_user: &str) -> Result<String, String> {
+    Err("built without the keyring feature".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Write};
+
+    use serde_yaml::Value;
+
+    use super::resolve;
+
+    fn mapping(pairs: &[(&str, &str)]) -> HashMap<String, Value> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "s3cret").unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let mut m = mapping(&[("name", "ss"), ("password-file", path)]);
+        resolve(&mut m).unwrap();
+        assert_eq!(m.get("password").and_then(|x| x.as_str()), Some("s3cret"));
+        assert!(!m.contains_key("password-file"));
+
+        let mut m = mapping(&[("name", "ss"), ("password", "x"), ("password-file", path)]);
+        assert!(resolve(&mut m).is_err());
+
+        let mut m = mapping(&[("name", "ss"), ("password-file", "/nonexistent/secret")]);
+        assert!(resolve(&mut m).is_err());
+    }
+
+    #[test]
+    fn test_resolve_peers() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "psk").unwrap();
+
+        let mut m: HashMap<String, Value> = serde_yaml::from_str(&format!(
+            "name: wg\npeers:\n  - server: 10.0.0.1\n    preshared-key-file: {}\n",
+            file.path().display()
+        ))
+        .unwrap();
+        resolve(&mut m).unwrap();
+        let peer = &m["peers"][0];
+        assert_eq!(peer["preshared-key"].as_str(), Some("psk"));
+        assert_eq!(peer["server"].as_str(), Some("10.0.0.1"));
+        assert!(peer.get("preshared-key-file").is_none());
+
+        let mut m: HashMap<String, Value> =
+            serde_yaml::from_str("name: wg\npeers:\n  - preshared-key-file: /nonexistent/secret\n")
+                .unwrap();
+        assert!(resolve(&mut m).is_err());
+    }
+
+    #[test]
+    fn test_resolve_nothing() {
+        let mut m = mapping(&[("name", "ss"), ("password", "x")]);
+        resolve(&mut m).unwrap();
+        assert_eq!(m, mapping(&[("name", "ss"), ("password", "x")]));
+    }
+}
@@ -1,5 +1,8 @@
 pub mod def;
+pub mod home;
 pub mod internal;
+pub mod redact;
+pub mod share_link;
 mod utils;
 pub use def::DNSListen;
 pub use internal::InternalConfig as RuntimeConfig;
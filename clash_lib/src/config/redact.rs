@@ -0,0 +1,169 @@
+//! Masking the secrets of a config to share it
+//!
+//! The keys are kept, only the values of the secrets are replaced, so the
+//! structure of the config is left intact for bug reports. The addresses
+//! of the proxy servers and the URLs of the proxy providers, which often
+//! hold a token, are masked too.
+
+use serde_yaml::Value;
+
+const REDACTED: &str = "<redacted>";
+
+/// the keys of the secrets, wherever they are
+const SECRET_KEYS: &[&str] = &[
+    "secret",
+    "authentication",
+    "password",
+    "obfs-password",
+    "username",
+    "uuid",
+    "private-key",
+    "preshared-key",
+    "pre-shared-key",
+    "psk",
+    "authorization",
+    "auth",
+    "auth-str",
+    "token",
+];
+/// the keys identifying the servers, in the proxies
+const PROXY_KEYS: &[&str] = &["server", "ip"];
+/// in the proxy providers
+const PROVIDER_KEYS: &[&str] = &["url"];
+
+/// Mask the secrets of a config.
+pub fn redact(config: &mut Value) {
+    let Value::Mapping(config) = config else {
+        return;
+    };
+    for (k, v) in config.iter_mut() {
+        match k.as_str() {
+            Some(k) if is_secret(k, &[]) => mask(v),
+            Some("proxies") => redact_keys(v, PROXY_KEYS),
+            Some("proxy-providers") => {
+                if let Value::Mapping(providers) = v {
+                    for provider in providers.values_mut() {
+                        redact_keys(provider, PROVIDER_KEYS);
+                    }
+                }
+            }
+            _ => redact_keys(v, &[]),
+        }
+    }
+}
+
+/// Mask the secrets and the `extra` keys under `v`.
+fn redact_keys(v: &mut Value, extra: &[&str]) {
+    match v {
+        Value::Mapping(m) => {
+            for (k, v) in m.iter_mut() {
+                match k.as_str() {
+                    Some(k) if is_secret(k, extra) => mask(v),
+                    _ => redact_keys(v, extra),
+                }
+            }
+        }
+        Value::Sequence(s) => s.iter_mut().for_each(|x| redact_keys(x, extra)),
+        Value::Tagged(t) => redact_keys(&mut t.value, extra),
+        _ => {}
+    }
+}
+
+/// The keys are compared ignoring the case, as for the `Authorization` of
+/// the headers.
+fn is_secret(k: &str, extra: &[&str]) -> bool {
+    SECRET_KEYS
+        .iter()
+        .chain(extra)
+        .any(|x| x.eq_ignore_ascii_case(k))
+}
+
+fn mask(v: &mut Value) {
+    match v {
+        Value::Null => {}
+        Value::Mapping(m) => m.values_mut().for_each(mask),
+        Value::Sequence(s) => s.iter_mut().for_each(mask),
+        Value::Tagged(t) => mask(&mut t.value),
+        _ => *v = Value::String(REDACTED.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact;
+
+    #[test]
+    fn test_redact() {
+        let mut config: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+port: 7890
+secret: s3cr3t
+authentication: ["user:pass"]
+proxies:
+  - name: ss
+    type: ss
+    server: 1.2.3.4
+    port: 443
+    password: hunter2
+    plugin-opts:
+      host: example.com
+  - name: vmess
+    type: vmess
+    ws-opts:
+      headers:
+        Authorization: Bearer abc
+  - name: wg
+    type: wireguard
+    private-key: key
+    peers:
+      - server: 5.6.7.8
+proxy-providers:
+  sub:
+    type: http
+    url: https://sub.example.com/link?token=abc
+    path: ./sub.yaml
+rule-providers:
+  ads:
+    url: https://example.com/ads.yaml
+"#,
+        )
+        .unwrap();
+        redact(&mut config);
+
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+port: 7890
+secret: <redacted>
+authentication: [<redacted>]
+proxies:
+  - name: ss
+    type: ss
+    server: <redacted>
+    port: 443
+    password: <redacted>
+    plugin-opts:
+      host: example.com
+  - name: vmess
+    type: vmess
+    ws-opts:
+      headers:
+        Authorization: <redacted>
+  - name: wg
+    type: wireguard
+    private-key: <redacted>
+    peers:
+      - server: <redacted>
+proxy-providers:
+  sub:
+    type: http
+    url: <redacted>
+    path: ./sub.yaml
+rule-providers:
+  ads:
+    url: https://example.com/ads.yaml
+"#,
+        )
+        .unwrap();
+        assert_eq!(config, expected);
+    }
+}
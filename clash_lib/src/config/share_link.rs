@@ -0,0 +1,634 @@
+//! The share links of the ss, vmess, vless, trojan and hysteria2 proxies,
+//! converted from and to the proxies of the config.
+
+use std::collections::HashMap;
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_yaml::Value;
+use url::Url;
+
+use crate::{
+    app::remote_content_manager::providers::proxy_provider::sip008,
+    config::internal::proxy::{OutboundProxyProtocol, OutboundShadowsocks, WsOpt},
+    proxy::shadowsocks::encode_plugin_options,
+    Error,
+};
+
+/// The characters escaped in the user info and the name of a link.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The proxy of a share link, as it's written in `proxies`.
+pub fn import(link: &str) -> Result<HashMap<String, Value>, Error> {
+    let link = link.trim();
+    let invalid = |reason: &str| Error::InvalidConfig(format!("invalid link {}: {}", link, reason));
+
+    let scheme = link
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_ascii_lowercase())
+        .ok_or_else(|| invalid("no scheme"))?;
+    match scheme.as_str() {
+        "ss" => import_ss(link).ok_or_else(|| invalid("bad ss link")),
+        "vmess" => import_vmess(link).ok_or_else(|| invalid("bad vmess link")),
+        "vless" | "trojan" | "hysteria2" | "hy2" => {
+            let url = Url::parse(link).map_err(|e| invalid(&e.to_string()))?;
+            import_url(&url).ok_or_else(|| invalid("no server or port"))
+        }
+        _ => Err(invalid("unsupported scheme")),
+    }
+}
+
+/// The share link of a proxy of `proxies`.
+pub fn export(proxy: HashMap<String, Value>) -> Result<String, Error> {
+    match OutboundProxyProtocol::try_from(proxy)? {
+        OutboundProxyProtocol::Ss(s) => Ok(export_ss(&s)),
+        OutboundProxyProtocol::Vmess(v) => {
+            let net = v.network.as_deref().unwrap_or("tcp");
+            let (host, path) = match net {
+                "ws" => v.ws_opts.as_ref().map(ws_host_path).unwrap_or_default(),
+                "h2" => v
+                    .h2_opts
+                    .as_ref()
+                    .map(|x| (x.host.as_ref().map(|x| x.join(",")), x.path.clone()))
+                    .unwrap_or_default(),
+                "grpc" => (
+                    None,
+                    v.grpc_opts
+                        .as_ref()
+                        .and_then(|x| x.grpc_service_name.clone()),
+                ),
+                _ => (None, None),
+            };
+            let doc = serde_json::json!({
+                "v": "2",
+                "ps": v.name,
+                "add": v.server,
+                "port": v.port.to_string(),
+                "id": v.uuid,
+                "aid": v.alter_id.to_string(),
+                "scy": v.cipher.as_deref().unwrap_or("auto"),
+                "net": net,
+                "type": "none",
+                "host": host.unwrap_or_default(),
+                "path": path.unwrap_or_default(),
+                "tls": if v.tls.unwrap_or_default() { "tls" } else { "" },
+                "sni": v.server_name.unwrap_or_default(),
+            });
+            Ok(format!("vmess://{}", STANDARD.encode(doc.to_string())))
+        }
+        OutboundProxyProtocol::Vless(v) => {
+            let mut query = vec![("encryption", "none".to_owned())];
+            let security = match (&v.reality_opts, v.tls.unwrap_or_default()) {
+                (Some(_), _) => "reality",
+                (None, true) => "tls",
+                (None, false) => "none",
+            };
+            query.push(("security", security.to_owned()));
+            if let Some(flow) = v.flow {
+                query.push(("flow", flow));
+            }
+            if let Some(sni) = v.servername {
+                query.push(("sni", sni));
+            }
+            if let Some(alpn) = v.alpn {
+                query.push(("alpn", alpn.join(",")));
+            }
+            if let Some(fp) = v.client_fingerprint {
+                query.push(("fp", fp));
+            }
+            if let Some(reality) = v.reality_opts {
+                query.push(("pbk", reality.public_key));
+                if let Some(sid) = reality.short_id {
+                    query.push(("sid", sid));
+                }
+            }
+            if v.skip_cert_verify.unwrap_or_default() {
+                query.push(("allowInsecure", "1".to_owned()));
+            }
+            push_transport(
+                &mut query,
+                v.network.as_deref(),
+                v.ws_opts.as_ref(),
+                v.grpc_opts.and_then(|x| x.grpc_service_name),
+            );
+            Ok(build_link(
+                "vless", &v.uuid, &v.server, v.port, &query, &v.name,
+            ))
+        }
+        OutboundProxyProtocol::Trojan(t) => {
+            let mut query = vec![];
+            if let Some(sni) = t.sni {
+                query.push(("sni", sni));
+            }
+            if let Some(alpn) = t.alpn {
+                query.push(("alpn", alpn.join(",")));
+            }
+            if t.skip_cert_verify.unwrap_or_default() {
+                query.push(("allowInsecure", "1".to_owned()));
+            }
+            push_transport(
+                &mut query,
+                t.network.as_deref(),
+                t.ws_opts.as_ref(),
+                t.grpc_opts.and_then(|x| x.grpc_service_name),
+            );
+            Ok(build_link(
+                "trojan",
+                &t.password,
+                &t.server,
+                t.port,
+                &query,
+                &t.name,
+            ))
+        }
+        OutboundProxyProtocol::Hysteria2(h) => {
+            let mut query = vec![];
+            if let Some(sni) = h.sni {
+                query.push(("sni", sni));
+            }
+            if let Some(obfs) = h.obfs {
+                query.push(("obfs", obfs));
+            }
+            if let Some(password) = h.obfs_password {
+                query.push(("obfs-password", password));
+            }
+            if h.skip_cert_verify.unwrap_or_default() {
+                query.push(("insecure", "1".to_owned()));
+            }
+            Ok(build_link(
+                "hysteria2",
+                &h.password,
+                &h.server,
+                h.port,
+                &query,
+                &h.name,
+            ))
+        }
+        other => Err(Error::InvalidConfig(format!(
+            "a {} proxy has no share link",
+            other
+        ))),
+    }
+}
+
+/// `ss://base64(method:password)@server:port/?plugin=...#name` of SIP002,
+/// or the legacy `ss://base64(method:password@server:port)#name`.
+fn import_ss(link: &str) -> Option<HashMap<String, Value>> {
+    let rest = &link["ss://".len()..];
+    let (rest, name) = match rest.split_once('#') {
+        Some((rest, name)) => (rest, Some(decode(name))),
+        None => (rest, None),
+    };
+
+    let (method, password, server, port, plugin) = if !rest.contains('@') {
+        let decoded = decode_base64(rest)?;
+        let (userinfo, addr) = decoded.rsplit_once('@')?;
+        let (method, password) = userinfo.split_once(':')?;
+        let (server, port) = addr.rsplit_once(':')?;
+        (
+            method.to_owned(),
+            password.to_owned(),
+            server.trim_matches(['[', ']']).to_owned(),
+            port.parse().ok()?,
+            None,
+        )
+    } else {
+        let url = Url::parse(&format!("ss://{}", rest)).ok()?;
+        let (method, password) = match url.password() {
+            // the 2022 ciphers are percent-encoded instead
+            Some(password) => (decode(url.username()), decode(password)),
+            None => {
+                let userinfo = decode_base64(&decode(url.username()))?;
+                let (method, password) = userinfo.split_once(':')?;
+                (method.to_owned(), password.to_owned())
+            }
+        };
+        let plugin = url
+            .query_pairs()
+            .find(|(k, _)| k == "plugin")
+            .map(|(_, v)| v.into_owned());
+        (method, password, host(&url)?, url.port()?, plugin)
+    };
+
+    let (plugin, plugin_opts) = match plugin {
+        Some(plugin) => match plugin.split_once(';') {
+            Some((plugin, opts)) => (Some(plugin.to_owned()), Some(opts.to_owned())),
+            None => (Some(plugin), None),
+        },
+        None => (None, None),
+    };
+    // a plugin binary is not run for a link either
+    sip008::Server {
+        remarks: name,
+        server,
+        server_port: port,
+        password,
+        method,
+        plugin,
+        plugin_opts,
+    }
+    .into_proxy()
+}
+
+fn export_ss(s: &OutboundShadowsocks) -> String {
+    let userinfo = if s.cipher.starts_with("2022-") {
+        format!("{}:{}", encode(&s.cipher), encode(&s.password))
+    } else {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", s.cipher, s.password))
+    };
+
+    let plugin = s.plugin.as_ref().map(|plugin| {
+        let mut opts = s.plugin_opts.clone().unwrap_or_default();
+        let plugin = match plugin.as_str() {
+            "obfs" => {
+                let mut native = HashMap::new();
+                if let Some(mode) = opts.remove("mode") {
+                    native.insert("obfs".to_owned(), mode);
+                }
+                if let Some(host) = opts.remove("host") {
+                    native.insert("obfs-host".to_owned(), host);
+                }
+                opts = native;
+                "obfs-local"
+            }
+            "v2ray-plugin" => {
+                // websocket is the default of the binary
+                opts.remove("mode");
+                "v2ray-plugin"
+            }
+            plugin => plugin,
+        };
+        match encode_plugin_options(&opts) {
+            opts if opts.is_empty() => plugin.to_owned(),
+            opts => format!("{};{}", plugin, opts),
+        }
+    });
+
+    format!(
+        "ss://{}@{}:{}{}#{}",
+        userinfo,
+        bracket(&s.server),
+        s.port,
+        plugin
+            .map(|x| format!("/?plugin={}", encode(&x)))
+            .unwrap_or_default(),
+        encode(&s.name)
+    )
+}
+
+/// `vmess://base64(json)` of v2rayN.
+fn import_vmess(link: &str) -> Option<HashMap<String, Value>> {
+    let doc = decode_base64(&link["vmess://".len()..])?;
+    let doc: serde_json::Value = serde_json::from_str(&doc).ok()?;
+    // the numbers are strings for some
+    let field = |k: &str| match &doc[k] {
+        serde_json::Value::String(s) if !s.is_empty() => Some(s.to_owned()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+
+    let server = field("add")?;
+    let port: u16 = field("port")?.parse().ok()?;
+    let mut proxy = HashMap::new();
+    proxy.insert(
+        "name".to_owned(),
+        Value::from(field("ps").unwrap_or_else(|| format!("{}:{}", server, port))),
+    );
+    proxy.insert("type".to_owned(), Value::from("vmess"));
+    proxy.insert("server".to_owned(), Value::from(server));
+    proxy.insert("port".to_owned(), Value::from(port));
+    proxy.insert("uuid".to_owned(), Value::from(field("id")?));
+    proxy.insert(
+        "alter-id".to_owned(),
+        Value::from(
+            field("aid")
+                .and_then(|x| x.parse::<u16>().ok())
+                .unwrap_or(0),
+        ),
+    );
+    proxy.insert(
+        "cipher".to_owned(),
+        Value::from(field("scy").unwrap_or("auto".to_owned())),
+    );
+    proxy.insert("udp".to_owned(), Value::from(true));
+    if field("tls").as_deref() == Some("tls") {
+        proxy.insert("tls".to_owned(), Value::from(true));
+    }
+    if let Some(sni) = field("sni") {
+        proxy.insert("servername".to_owned(), Value::from(sni));
+    }
+
+    let (host, path) = (field("host"), field("path"));
+    match field("net").as_deref() {
+        Some("h2") => {
+            let mut opts = serde_yaml::Mapping::new();
+            if let Some(host) = host {
+                opts.insert(
+                    "host".into(),
+                    Value::from(host.split(',').collect::<Vec<_>>()),
+                );
+            }
+            opts.insert("path".into(), Value::from(path.unwrap_or("/".to_owned())));
+            proxy.insert("network".to_owned(), Value::from("h2"));
+            proxy.insert("h2-opts".to_owned(), Value::Mapping(opts));
+        }
+        network => insert_transport(&mut proxy, network, host, path),
+    }
+    Some(proxy)
+}
+
+/// The links of vless, trojan and hysteria2, with the credentials as the
+/// user and the options in the query.
+fn import_url(url: &Url) -> Option<HashMap<String, Value>> {
+    let server = host(url)?;
+    let port = match url.port() {
+        Some(port) => port,
+        None if url.scheme() == "trojan" => 443,
+        None => return None,
+    };
+    let userinfo = match url.password() {
+        Some(password) => format!("{}:{}", decode(url.username()), decode(password)),
+        None => decode(url.username()),
+    };
+    let query = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+    let param = |k: &str| query.get(k).filter(|x| !x.is_empty()).cloned();
+    let insecure = |k: &str| matches!(param(k).as_deref(), Some("1" | "true"));
+
+    let mut proxy = HashMap::new();
+    let name = url
+        .fragment()
+        .map(decode)
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| format!("{}:{}", server, port));
+    proxy.insert("name".to_owned(), Value::from(name));
+    proxy.insert("server".to_owned(), Value::from(server));
+    proxy.insert("port".to_owned(), Value::from(port));
+    let alpn = param("alpn").map(|x| Value::from(x.split(',').collect::<Vec<_>>()));
+
+    match url.scheme() {
+        "vless" => {
+            proxy.insert("type".to_owned(), Value::from("vless"));
+            proxy.insert("uuid".to_owned(), Value::from(userinfo));
+            proxy.insert("udp".to_owned(), Value::from(true));
+            let security = param("security");
+            if matches!(security.as_deref(), Some("tls" | "reality")) {
+                proxy.insert("tls".to_owned(), Value::from(true));
+            }
+            if security.as_deref() == Some("reality") {
+                let mut opts = serde_yaml::Mapping::new();
+                opts.insert("public-key".into(), Value::from(param("pbk")?));
+                if let Some(sid) = param("sid") {
+                    opts.insert("short-id".into(), Value::from(sid));
+                }
+                proxy.insert("reality-opts".to_owned(), Value::Mapping(opts));
+            }
+            if let Some(flow) = param("flow") {
+                proxy.insert("flow".to_owned(), Value::from(flow));
+            }
+            if let Some(sni) = param("sni") {
+                proxy.insert("servername".to_owned(), Value::from(sni));
+            }
+            if let Some(fp) = param("fp") {
+                proxy.insert("client-fingerprint".to_owned(), Value::from(fp));
+            }
+            if let Some(alpn) = alpn {
+                proxy.insert("alpn".to_owned(), alpn);
+            }
+            if insecure("allowInsecure") {
+                proxy.insert("skip-cert-verify".to_owned(), Value::from(true));
+            }
+            insert_transport(
+                &mut proxy,
+                param("type").as_deref(),
+                param("host"),
+                param("path").or(param("serviceName")),
+            );
+        }
+        "trojan" => {
+            proxy.insert("type".to_owned(), Value::from("trojan"));
+            proxy.insert("password".to_owned(), Value::from(userinfo));
+            proxy.insert("udp".to_owned(), Value::from(true));
+            if let Some(sni) = param("sni").or(param("peer")) {
+                proxy.insert("sni".to_owned(), Value::from(sni));
+            }
+            if let Some(alpn) = alpn {
+                proxy.insert("alpn".to_owned(), alpn);
+            }
+            if insecure("allowInsecure") {
+                proxy.insert("skip-cert-verify".to_owned(), Value::from(true));
+            }
+            insert_transport(
+                &mut proxy,
+                param("type").as_deref(),
+                param("host"),
+                param("path").or(param("serviceName")),
+            );
+        }
+        _ => {
+            proxy.insert("type".to_owned(), Value::from("hysteria2"));
+            proxy.insert("password".to_owned(), Value::from(userinfo));
+            if let Some(sni) = param("sni") {
+                proxy.insert("sni".to_owned(), Value::from(sni));
+            }
+            if let Some(obfs) = param("obfs") {
+                proxy.insert("obfs".to_owned(), Value::from(obfs));
+            }
+            if let Some(password) = param("obfs-password") {
+                proxy.insert("obfs-password".to_owned(), Value::from(password));
+            }
+            if insecure("insecure") {
+                proxy.insert("skip-cert-verify".to_owned(), Value::from(true));
+            }
+        }
+    }
+    Some(proxy)
+}
+
+/// The `ws-opts` or `grpc-opts` of a `ws` or `grpc` network, the path being
+/// the service name of grpc.
+fn insert_transport(
+    proxy: &mut HashMap<String, Value>,
+    network: Option<&str>,
+    host: Option<String>,
+    path: Option<String>,
+) {
+    match network {
+        Some("ws") => {
+            let mut opts = serde_yaml::Mapping::new();
+            opts.insert("path".into(), Value::from(path.unwrap_or("/".to_owned())));
+            if let Some(host) = host {
+                let mut headers = serde_yaml::Mapping::new();
+                headers.insert("Host".into(), Value::from(host));
+                opts.insert("headers".into(), Value::Mapping(headers));
+            }
+            proxy.insert("network".to_owned(), Value::from("ws"));
+            proxy.insert("ws-opts".to_owned(), Value::Mapping(opts));
+        }
+        Some("grpc") => {
+            let mut opts = serde_yaml::Mapping::new();
+            if let Some(name) = path {
+                opts.insert("grpc-service-name".into(), Value::from(name));
+            }
+            proxy.insert("network".to_owned(), Value::from("grpc"));
+            proxy.insert("grpc-opts".to_owned(), Value::Mapping(opts));
+        }
+        _ => {}
+    }
+}
+
+fn push_transport(
+    query: &mut Vec<(&str, String)>,
+    network: Option<&str>,
+    ws_opts: Option<&WsOpt>,
+    grpc_service_name: Option<String>,
+) {
+    match network {
+        Some("ws") => {
+            query.push(("type", "ws".to_owned()));
+            let (host, path) = ws_opts.map(ws_host_path).unwrap_or_default();
+            if let Some(host) = host {
+                query.push(("host", host));
+            }
+            if let Some(path) = path {
+                query.push(("path", path));
+            }
+        }
+        Some("grpc") => {
+            query.push(("type", "grpc".to_owned()));
+            if let Some(name) = grpc_service_name {
+                query.push(("serviceName", name));
+            }
+        }
+        _ => query.push(("type", "tcp".to_owned())),
+    }
+}
+
+fn ws_host_path(opts: &WsOpt) -> (Option<String>, Option<String>) {
+    (
+        opts.headers.as_ref().and_then(|x| x.get("Host").cloned()),
+        opts.path.clone(),
+    )
+}
+
+fn build_link(
+    scheme: &str,
+    userinfo: &str,
+    server: &str,
+    port: u16,
+    query: &[(&str, String)],
+    name: &str,
+) -> String {
+    let query = match query {
+        [] => String::new(),
+        query => format!(
+            "?{}",
+            url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(query)
+                .finish()
+        ),
+    };
+    format!(
+        "{}://{}@{}:{}{}#{}",
+        scheme,
+        encode(userinfo),
+        bracket(server),
+        port,
+        query,
+        encode(name)
+    )
+}
+
+/// The host of a link, without the brackets of an IPv6 address.
+fn host(url: &Url) -> Option<String> {
+    url.host_str()
+        .filter(|x| !x.is_empty())
+        .map(|x| x.trim_matches(['[', ']']).to_owned())
+}
+
+fn bracket(server: &str) -> String {
+    if server.contains(':') {
+        format!("[{}]", server)
+    } else {
+        server.to_owned()
+    }
+}
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, COMPONENT).to_string()
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+/// Base64 with or without padding, in either alphabet.
+fn decode_base64(s: &str) -> Option<String> {
+    let s = s.trim().trim_end_matches('=');
+    URL_SAFE_NO_PAD
+        .decode(s)
+        .or_else(|_| STANDARD_NO_PAD.decode(s))
+        .ok()
+        .and_then(|x| String::from_utf8(x).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use crate::config::internal::proxy::OutboundProxyProtocol;
+
+    use super::{export, import};
+
+    #[test]
+    fn test_share_links() {
+        let links = [
+            "ss://YWVzLTI1Ni1nY206cGFzcw@1.2.3.4:8388/?plugin=obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dexample.com#my%20ss",
+            "vless://b831381d-6324-4d53-ad4f-8cda48b30811@example.com:443?encryption=none&security=reality&flow=xtls-rprx-vision&sni=www.apple.com&fp=chrome&pbk=key&sid=ab&type=tcp#vless",
+            "trojan://pass%40word@example.com:443?sni=example.org&type=ws&host=example.org&path=%2Fws#trojan",
+            "hysteria2://secret@[2001:db8::1]:8443?sni=example.com&obfs=salamander&obfs-password=x&insecure=1#hy2",
+        ];
+        for link in links {
+            let proxy = import(link).unwrap();
+            assert!(OutboundProxyProtocol::try_from(proxy.clone()).is_ok());
+            let exported = export(proxy.clone()).unwrap();
+            assert_eq!(import(&exported).unwrap(), proxy, "{}", exported);
+        }
+
+        let ss = import(links[0]).unwrap();
+        assert_eq!(ss["name"], Value::from("my ss"));
+        assert_eq!(ss["cipher"], Value::from("aes-256-gcm"));
+        assert_eq!(ss["plugin"], Value::from("obfs"));
+        let trojan = import(links[2]).unwrap();
+        assert_eq!(trojan["password"], Value::from("pass@word"));
+        assert_eq!(trojan["ws-opts"]["path"], Value::from("/ws"));
+        let hy2 = import(links[3]).unwrap();
+        assert_eq!(hy2["server"], Value::from("2001:db8::1"));
+    }
+
+    #[test]
+    fn test_vmess_link() {
+        let doc = r#"{"v":"2","ps":"vm","add":"example.com","port":443,"id":"b831381d-6324-4d53-ad4f-8cda48b30811","aid":"0","scy":"auto","net":"ws","type":"none","host":"example.com","path":"/v","tls":"tls","sni":""}"#;
+        let link = format!(
+            "vmess://{}",
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, doc)
+        );
+        let proxy = import(&link).unwrap();
+        assert_eq!(proxy["port"], Value::from(443));
+        assert_eq!(
+            proxy["ws-opts"]["headers"]["Host"],
+            Value::from("example.com")
+        );
+        assert!(matches!(
+            OutboundProxyProtocol::try_from(proxy.clone()),
+            Ok(OutboundProxyProtocol::Vmess(_))
+        ));
+        assert_eq!(import(&export(proxy.clone()).unwrap()).unwrap(), proxy);
+    }
+}
@@ -7,6 +7,7 @@ use crate::app::inbound::manager::InboundManager;
 use crate::app::outbound::manager::OutboundManager;
 use crate::app::router::Router;
 use crate::config::def;
+use crate::config::home::ConfigHome;
 use crate::config::internal::proxy::OutboundProxy;
 use crate::config::internal::InternalConfig;
 use app::dispatcher::StatisticsManager;
@@ -14,12 +15,13 @@ use app::dns::SystemResolver;
 use app::profile;
 use common::auth;
 use common::http::new_http_client;
-use common::mmdb;
+use common::{geosite, mmdb};
 use config::def::LogLevel;
 use proxy::tun::get_tun_runner;
 
+use std::collections::BTreeMap;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, OnceLock};
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
@@ -27,10 +29,12 @@ use tokio::task::JoinHandle;
 use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 mod app;
 mod common;
 mod config;
+pub mod plugin;
 mod proxy;
 mod session;
 
@@ -38,6 +42,12 @@ pub use config::def::Config as ClashConfigDef;
 pub use config::def::DNS as ClashDNSConfigDef;
 pub use config::DNSListen as ClashDNSListen;
 pub use config::RuntimeConfig as ClashRuntimeConfig;
+pub use proxy::utils::{RawSocket, SocketProtector};
+
+/// for the benches only, not a public API
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use common::cidr_trie::CidrTrie;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -64,6 +74,8 @@ pub struct Options {
     pub cwd: Option<String>,
     pub rt: Option<TokioRuntime>,
     pub log_file: Option<String>,
+    /// run on every socket dialing out, see [`SocketProtector`]
+    pub protect_socket: Option<SocketProtector>,
 }
 
 pub enum TokioRuntime {
@@ -88,6 +100,95 @@ impl Config {
             Config::Str(s) => s.parse::<def::Config>()?.try_into(),
         }
     }
+
+    /// The config as written, none if it's given parsed already.
+    fn source(&self) -> Option<serde_yaml::Value> {
+        match self {
+            Config::Def(c) => serde_yaml::to_value(c).ok(),
+            Config::Internal(_) => None,
+            Config::File(file) => std::fs::read_to_string(file)
+                .ok()
+                .and_then(|x| serde_yaml::from_str(&x).ok()),
+            Config::Str(s) => serde_yaml::from_str(s).ok(),
+        }
+    }
+}
+
+/// Convert a rule-set file between the yaml, text and binary formats.
+/// The input format is detected if `from` is not given.
+/// Returns the number of rules written.
+pub fn convert_ruleset(
+    input: &Path,
+    output: &Path,
+    from: Option<&str>,
+    to: &str,
+    behavior: Option<&str>,
+) -> Result<usize, Error> {
+    use app::remote_content_manager::providers::rule_provider::{
+        format, RuleSetBehavior, RuleSetFormat,
+    };
+
+    let content = std::fs::read(input)?;
+    let (converted, count) = format::convert(
+        &content,
+        from.map(str::parse::<RuleSetFormat>).transpose()?,
+        to.parse::<RuleSetFormat>()?,
+        behavior.map(str::parse::<RuleSetBehavior>).transpose()?,
+    )?;
+    std::fs::write(output, converted)?;
+    Ok(count)
+}
+
+/// The proxy of a ss, vmess, vless, trojan or hysteria2 share link, as an
+/// entry of `proxies` in yaml.
+pub fn import_link(link: &str) -> Result<String, Error> {
+    let proxy = config::share_link::import(link)?;
+    // fail on what wouldn't load
+    config::internal::proxy::OutboundProxyProtocol::try_from(proxy.clone())?;
+
+    let mut keys = proxy.keys().collect::<Vec<_>>();
+    keys.sort_by_key(|k| {
+        let leading = ["name", "type", "server", "port"];
+        (
+            leading
+                .iter()
+                .position(|x| *x == k.as_str())
+                .unwrap_or(leading.len()),
+            (*k).clone(),
+        )
+    });
+    let entry = keys
+        .into_iter()
+        .map(|k| (serde_yaml::Value::from(k.as_str()), proxy[k].clone()))
+        .collect::<serde_yaml::Mapping>();
+    serde_yaml::to_string(&[entry]).map_err(|e| Error::Operation(e.to_string()))
+}
+
+/// The share link of the proxy `name` of the `proxies` of a config file.
+pub fn export_link(config: &Path, name: &str) -> Result<String, Error> {
+    let config = def::Config::try_from(config.to_path_buf())?;
+    let proxy = config
+        .proxy
+        .into_iter()
+        .find(|x| x.get("name").and_then(|x| x.as_str()) == Some(name))
+        .ok_or_else(|| Error::InvalidConfig(format!("proxy {} not found", name)))?;
+    config::share_link::export(proxy)
+}
+
+/// Bind the outbound sockets to the interface of the default route for
+/// `tun.auto-detect-interface`, unless `interface` is set.
+fn detect_outbound_interface(config: &mut InternalConfig) {
+    if !config.tun.enable || !config.tun.auto_detect_interface || config.general.interface.is_some()
+    {
+        return;
+    }
+    match proxy::tun::default_interface() {
+        Some(iface) => {
+            info!("binding the outbound sockets to {}", iface);
+            config.general.interface = Some(proxy::utils::Interface::Name(iface));
+        }
+        None => warn!("tun auto-detect-interface found no default route"),
+    }
 }
 
 pub struct GlobalState {
@@ -97,7 +198,22 @@ pub struct GlobalState {
     api_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     dns_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     reload_tx: mpsc::Sender<(Config, oneshot::Sender<()>)>,
+    /// the config home, see [`ConfigHome`]
     cwd: String,
+    /// the resolved paths of the files used by the running config
+    paths: BTreeMap<String, String>,
+    /// the running config as written, for the export
+    source: Option<serde_yaml::Value>,
+    tun_enable: bool,
+    tun_device: String,
+    dns_enable: bool,
+    /// the adapters whose DNS servers point at the listener, see
+    /// `dns.set-system-dns`
+    system_dns: Option<dns::system_dns::SystemDnsGuard>,
+    /// the system changes of the gateway mode, see `gateway`
+    gateway: Option<app::gateway::GatewayGuard>,
+    /// the report of the startup self-check, once it's done
+    self_check: Option<app::selfcheck::Report>,
 }
 
 pub struct RuntimeController {
@@ -138,10 +254,15 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
 
     let _ = RUNTIME_CONTROLLER.set(std::sync::RwLock::new(RuntimeController { shutdown_tx }));
+    proxy::utils::set_socket_protector(opts.protect_socket);
 
-    let config: InternalConfig = opts.config.try_parse()?;
+    let home = ConfigHome::new(opts.cwd.as_deref(), &opts.config);
+    home.install();
+    let source = opts.config.source();
+    let mut config: InternalConfig = opts.config.try_parse()?;
+    let paths = home.resolved_paths(&config);
 
-    let cwd = opts.cwd.unwrap_or_else(|| ".".to_string());
+    let cwd = home.dir().to_string_lossy().to_string();
 
     let (log_tx, _) = broadcast::channel(100);
 
@@ -158,6 +279,9 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         error!("panic hook: {:?}", info);
     }));
 
+    detect_outbound_interface(&mut config);
+    proxy::utils::set_source_ports(config.general.outbound_port_range.clone());
+
     let mut tasks = Vec::<Runner>::new();
     let mut runners = Vec::new();
 
@@ -172,19 +296,31 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         mmdb::Mmdb::new(
             cwd.join(&config.general.mmdb),
             config.general.mmdb_download_url,
-            client,
+            client.clone(),
         )
         .await?,
     );
+    let geosite = Arc::new(geosite::Geosite::new(
+        cwd.join(&config.general.geosite),
+        config.general.geosite_download_url,
+        client,
+    ));
 
     debug!("initializing cache store");
     let cache_store = profile::ThreadSafeCacheFile::new(
         cwd.join("cache.db").as_path().to_str().unwrap(),
         config.profile.store_selected,
+        config.dns.store_fake_ip,
     );
 
     let dns_resolver =
-        dns::Resolver::new_resolver(&config.dns, cache_store.clone(), mmdb.clone()).await;
+        dns::Resolver::new_resolver(&config.dns, cache_store.clone(), mmdb.clone()).await?;
+
+    app::ntp::spawn(
+        config.ntp,
+        dns_resolver.clone(),
+        config.general.interface.clone(),
+    );
 
     debug!("initializing outbound manager");
     let outbound_manager = Arc::new(
@@ -210,6 +346,8 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             dns_resolver.clone(),
             cache_store.clone(),
             cwd.to_string_lossy().to_string(),
+            config.chaos,
+            config.general.max_group_depth,
         )
         .await?,
     );
@@ -218,13 +356,18 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     let router = Arc::new(
         Router::new(
             config.rules,
+            config.listener_rules,
+            config.sub_rules,
             config.rule_providers,
             dns_resolver.clone(),
-            mmdb,
+            mmdb.clone(),
+            geosite.clone(),
             cwd.to_string_lossy().to_string(),
         )
         .await,
     );
+    dns_resolver.bind_rule_providers(router.get_rule_providers());
+    dns_resolver.bind_geosite(&geosite).await?;
 
     let statistics_manager = StatisticsManager::new();
 
@@ -233,9 +376,16 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         router.clone(),
         dns_resolver.clone(),
         config.general.mode,
+        config.general.interface.clone(),
+        config.shaping,
+        config.general.tcp_timeout,
+        config.general.ftp_helper,
         statistics_manager.clone(),
     ));
 
+    debug!("initializing gateway");
+    let gateway = app::gateway::setup(&config.gateway, &config.tun, statistics_manager.clone())?;
+
     let authenticator = Arc::new(auth::PlainAuthenticator::new(config.users));
 
     debug!("initializing inbound manager");
@@ -243,18 +393,29 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         config.general.inbound,
         dispatcher.clone(),
         authenticator,
+        &cwd,
     )?));
 
     let inbound_runner = inbound_manager.lock().await.get_runner()?;
     let inbound_listener_handle = tokio::spawn(inbound_runner);
 
-    let tun_runner = get_tun_runner(config.tun, dispatcher.clone(), dns_resolver.clone())?;
+    let (tun_enable, tun_device) = (config.tun.enable, config.tun.device_id.clone());
+    let dns_enable = config.dns.enable;
+    let tun_runner = get_tun_runner(
+        config.tun,
+        dispatcher.clone(),
+        dns_resolver.clone(),
+        config.dns.rule_aware,
+    )?;
     let tun_runner_handle = tun_runner.map(tokio::spawn);
 
     debug!("initializing dns listener");
-    let dns_listener_handle = dns::get_dns_listener(config.dns, dns_resolver.clone())
-        .await
-        .map(tokio::spawn);
+    let (set_system_dns, dns_udp) = (config.dns.set_system_dns, config.dns.listen.udp);
+    let dns_listener_handle =
+        dns::get_dns_listener(config.dns, dns_resolver.clone(), dispatcher.clone(), &cwd)
+            .await
+            .map(tokio::spawn);
+    let system_dns = dns::system_dns::setup(set_system_dns, dns_udp, &cwd).await?;
 
     let (reload_tx, mut reload_rx) = mpsc::channel(1);
 
@@ -266,8 +427,24 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         reload_tx,
         api_listener_handle: None,
         cwd: cwd.to_string_lossy().to_string(),
+        paths,
+        source,
+        tun_enable,
+        tun_device,
+        dns_enable,
+        system_dns,
+        gateway,
+        self_check: None,
     }));
 
+    let self_check = app::selfcheck::Targets {
+        inbound_manager: inbound_manager.clone(),
+        global_state: global_state.clone(),
+        mmdb: cwd.join(&config.general.mmdb),
+        resolver: dns_resolver.clone(),
+        controller: config.general.controller.external_controller.clone(),
+    };
+
     let api_runner = app::api::get_api_runner(
         config.general.controller,
         log_tx.clone(),
@@ -279,12 +456,14 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         statistics_manager,
         cache_store,
         router,
+        mmdb,
         cwd.to_string_lossy().to_string(),
     );
     if let Some(r) = api_runner {
         let api_listener_handle = tokio::spawn(r);
         global_state.lock().await.api_listener_handle = Some(api_listener_handle);
     }
+    tokio::spawn(app::selfcheck::run(self_check));
 
     runners.push(Box::pin(async move {
         shutdown_rx.recv().await;
@@ -301,16 +480,29 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         Ok(())
     }));
 
+    let state = global_state.clone();
     tasks.push(Box::pin(async move {
         while let Some((config, done)) = reload_rx.recv().await {
             info!("reloading config");
-            let config = match config.try_parse() {
+            let source = config.source();
+            let mut config = match config.try_parse() {
                 Ok(c) => c,
                 Err(e) => {
                     error!("failed to reload config: {}", e);
                     continue;
                 }
             };
+            detect_outbound_interface(&mut config);
+            // checked before anything is torn down
+            let gateway = match app::gateway::plan(&config.gateway, &config.tun) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("failed to reload config: {}", e);
+                    continue;
+                }
+            };
+            proxy::utils::set_source_ports(config.general.outbound_port_range.clone());
+            let paths = home.resolved_paths(&config);
 
             debug!("reloading dns resolver");
             let system_resolver =
@@ -323,19 +515,25 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                 mmdb::Mmdb::new(
                     cwd.join(&config.general.mmdb),
                     config.general.mmdb_download_url,
-                    client,
+                    client.clone(),
                 )
                 .await?,
             );
+            let geosite = Arc::new(geosite::Geosite::new(
+                cwd.join(&config.general.geosite),
+                config.general.geosite_download_url,
+                client,
+            ));
 
             debug!("reloading cache store");
             let cache_store = profile::ThreadSafeCacheFile::new(
                 cwd.join("cache.db").as_path().to_str().unwrap(),
                 config.profile.store_selected,
+                config.dns.store_fake_ip,
             );
 
             let dns_resolver =
-                dns::Resolver::new_resolver(&config.dns, cache_store.clone(), mmdb.clone()).await;
+                dns::Resolver::new_resolver(&config.dns, cache_store.clone(), mmdb.clone()).await?;
 
             debug!("reloading outbound manager");
             let outbound_manager = Arc::new(
@@ -361,6 +559,8 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                     dns_resolver.clone(),
                     cache_store.clone(),
                     cwd.to_string_lossy().to_string(),
+                    config.chaos,
+                    config.general.max_group_depth,
                 )
                 .await?,
             );
@@ -369,13 +569,18 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             let router = Arc::new(
                 Router::new(
                     config.rules,
+                    config.listener_rules,
+                    config.sub_rules,
                     config.rule_providers,
                     dns_resolver.clone(),
-                    mmdb,
+                    mmdb.clone(),
+                    geosite.clone(),
                     cwd.to_string_lossy().to_string(),
                 )
                 .await,
             );
+            dns_resolver.bind_rule_providers(router.get_rule_providers());
+            dns_resolver.bind_geosite(&geosite).await?;
 
             let statistics_manager = StatisticsManager::new();
 
@@ -384,6 +589,10 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                 router.clone(),
                 dns_resolver.clone(),
                 config.general.mode,
+                config.general.interface.clone(),
+                config.shaping,
+                config.general.tcp_timeout,
+                config.general.ftp_helper,
                 statistics_manager.clone(),
             ));
 
@@ -394,10 +603,15 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                 config.general.inbound,
                 dispatcher.clone(),
                 authenticator,
+                &cwd,
             )?));
 
             done.send(()).unwrap();
 
+            // the new listeners are bound before the old ones are stopped,
+            // so the ports are not left closed where they can be shared
+            let inbound_runner = inbound_manager.lock().await.get_runner()?;
+
             debug!("stopping listeners");
             let mut g = global_state.lock().await;
             if let Some(h) = g.inbound_listener_handle.take() {
@@ -405,6 +619,8 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             }
             if let Some(h) = g.tunnel_listener_handle.take() {
                 h.abort();
+                // the device and its routes are released once it's dropped
+                let _ = h.await;
             }
             if let Some(h) = g.dns_listener_handle.take() {
                 h.abort();
@@ -413,20 +629,40 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                 h.abort();
             }
 
-            let inbound_listener_handle = inbound_manager
-                .lock()
-                .await
-                .get_runner()
-                .map(tokio::spawn)?;
+            debug!("reloading gateway");
+            if let Some(x) = g.gateway.take() {
+                x.teardown().await;
+            }
+            if let Some(x) = gateway {
+                match x.apply(statistics_manager.clone()) {
+                    Ok(x) => g.gateway = Some(x),
+                    // the listeners are kept running without it
+                    Err(e) => error!("failed to set up the gateway: {}", e),
+                }
+            }
 
-            let tun_runner_handle =
-                get_tun_runner(config.tun, dispatcher.clone(), dns_resolver.clone())?
-                    .map(tokio::spawn);
+            let inbound_listener_handle = tokio::spawn(inbound_runner);
+
+            let (tun_enable, tun_device) = (config.tun.enable, config.tun.device_id.clone());
+            let dns_enable = config.dns.enable;
+            let tun_runner_handle = get_tun_runner(
+                config.tun,
+                dispatcher.clone(),
+                dns_resolver.clone(),
+                config.dns.rule_aware,
+            )?
+            .map(tokio::spawn);
 
             debug!("reloading dns listener");
-            let dns_listener_handle = dns::get_dns_listener(config.dns, dns_resolver.clone())
-                .await
-                .map(tokio::spawn);
+            let (set_system_dns, dns_udp) = (config.dns.set_system_dns, config.dns.listen.udp);
+            let dns_listener_handle =
+                dns::get_dns_listener(config.dns, dns_resolver.clone(), dispatcher.clone(), &cwd)
+                    .await
+                    .map(tokio::spawn);
+            if let Some(x) = g.system_dns.take() {
+                x.restore().await;
+            }
+            g.system_dns = dns::system_dns::setup(set_system_dns, dns_udp, &cwd).await?;
 
             debug!("reloading api listener");
             let api_listener_handle = app::api::get_api_runner(
@@ -440,6 +676,7 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                 statistics_manager,
                 cache_store,
                 router,
+                mmdb,
                 cwd.to_string_lossy().to_string(),
             )
             .map(tokio::spawn);
@@ -448,11 +685,28 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             g.tunnel_listener_handle = tun_runner_handle;
             g.dns_listener_handle = dns_listener_handle;
             g.api_listener_handle = api_listener_handle;
+            g.paths = paths;
+            g.source = source;
+            g.tun_enable = tun_enable;
+            g.tun_device = tun_device;
+            g.dns_enable = dns_enable;
+            drop(g);
+
+            app::events::publish(app::events::EventKind::ConfigReloaded, ());
         }
         Ok(())
     }));
 
-    futures::future::select_all(tasks).await.0.map_err(|x| {
+    let res = futures::future::select_all(tasks).await.0;
+    let mut g = state.lock().await;
+    if let Some(x) = g.system_dns.take() {
+        x.restore().await;
+    }
+    if let Some(x) = g.gateway.take() {
+        x.teardown().await;
+    }
+    drop(g);
+    res.map_err(|x| {
         error!("runtime error: {}, shutting down", x);
         x
     })
@@ -485,6 +739,7 @@ mod tests {
                 cwd: None,
                 rt: None,
                 log_file: None,
+                protect_socket: None,
             })
             .unwrap()
         });
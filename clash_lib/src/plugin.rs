@@ -0,0 +1,22 @@
+//! The API for the protocols and rules implemented out of tree
+//!
+//! Register the factories before starting clash, e.g.
+//! ```ignore
+//! clash_lib::plugin::register_outbound("my-proto", Arc::new(MyFactory))?;
+//! clash_lib::plugin::register_rule("TLS-JA3", Arc::new(Ja3Factory))?;
+//! clash_lib::start(opts)?;
+//! ```
+
+pub use crate::app::dispatcher::{
+    BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+    ChainedStream, ChainedStreamWrapper,
+};
+pub use crate::app::dns::ThreadSafeDNSResolver;
+pub use crate::app::outbound::registry::{register_outbound, OutboundFactory};
+pub use crate::app::router::registry::{register_rule, RuleFactory};
+pub use crate::app::router::RuleMatcher;
+pub use crate::proxy::utils::{new_tcp_stream, new_udp_socket, RemoteConnector};
+pub use crate::proxy::{
+    AnyOutboundHandler, AnyStream, ConnectorType, OutboundHandler, OutboundType,
+};
+pub use crate::session::{Network, Session, SocksAddr};
@@ -0,0 +1,210 @@
+//! Fault injection for testing the health mechanisms
+//!
+//! With `chaos` enabled every proxy is wrapped, and the faults configured
+//! for it, at startup or through the API, are applied to its handshakes:
+//! a share of them fails and the rest are delayed. The health checks go
+//! through the same path, so a fallback or url-test group should switch
+//! away from a proxy failing enough of them.
+
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use erased_serde::Serialize;
+use rand::Rng;
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::ThreadSafeDNSResolver,
+    },
+    config::def::ChaosFault,
+    session::Session,
+};
+
+use super::{
+    transport::tls::TlsEndpoint, utils::RemoteConnector, AnyOutboundHandler, ConnectorType,
+    OutboundHandler, OutboundType,
+};
+
+/// The faults of the proxies, keyed by the proxy name.
+#[derive(Default)]
+pub struct Chaos {
+    faults: std::sync::RwLock<HashMap<String, ChaosFault>>,
+}
+
+pub type ThreadSafeChaos = Arc<Chaos>;
+
+impl Chaos {
+    pub fn new(faults: HashMap<String, ChaosFault>) -> Self {
+        Self {
+            faults: std::sync::RwLock::new(faults),
+        }
+    }
+
+    pub fn faults(&self) -> HashMap<String, ChaosFault> {
+        self.faults.read().unwrap().clone()
+    }
+
+    pub fn set(&self, name: &str, fault: ChaosFault) {
+        self.faults.write().unwrap().insert(name.to_owned(), fault);
+    }
+
+    pub fn clear(&self, name: &str) -> bool {
+        self.faults.write().unwrap().remove(name).is_some()
+    }
+
+    /// Wait for the injected delay, or fail the handshake.
+    async fn inject(&self, name: &str) -> io::Result<()> {
+        let fault = match self.faults.read().unwrap().get(name) {
+            Some(fault) => fault.clone(),
+            None => return Ok(()),
+        };
+
+        let (dropped, delay) = {
+            let mut rng = rand::thread_rng();
+            let dropped = rng.gen_range(0..100) < fault.drop;
+            let jitter = if fault.jitter > 0 {
+                rng.gen_range(0..=fault.jitter)
+            } else {
+                0
+            };
+            (dropped, Duration::from_millis(fault.delay + jitter))
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        if dropped {
+            debug!("chaos: dropping a handshake of {}", name);
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("handshake of {} dropped by chaos", name),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct Handler {
+    inner: AnyOutboundHandler,
+    chaos: ThreadSafeChaos,
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(inner: AnyOutboundHandler, chaos: ThreadSafeChaos) -> AnyOutboundHandler {
+        Arc::new(Self { inner, chaos })
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.chaos.inject(self.name()).await?;
+        self.inner.connect_stream(sess, resolver).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.chaos.inject(self.name()).await?;
+        self.inner.connect_datagram(sess, resolver).await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        self.inner.support_connector().await
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        self.chaos.inject(self.name()).await?;
+        self.inner
+            .connect_stream_with_connector(sess, resolver, connector)
+            .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.chaos.inject(self.name()).await?;
+        self.inner
+            .connect_datagram_with_connector(sess, resolver, connector)
+            .await
+    }
+
+    fn remote_dns_resolve(&self) -> bool {
+        self.inner.remote_dns_resolve()
+    }
+
+    fn tls_endpoint(&self) -> Option<TlsEndpoint> {
+        self.inner.tls_endpoint()
+    }
+
+    async fn current_member(&self) -> Option<AnyOutboundHandler> {
+        self.inner.current_member().await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        self.inner.as_map().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::def::ChaosFault;
+
+    use super::Chaos;
+
+    #[tokio::test]
+    async fn test_inject() {
+        let chaos = Chaos::new(HashMap::from([(
+            "ss1".to_owned(),
+            ChaosFault {
+                drop: 100,
+                ..Default::default()
+            },
+        )]));
+
+        assert!(chaos.inject("ss1").await.is_err());
+        assert!(chaos.inject("ss2").await.is_ok());
+
+        chaos.set(
+            "ss1",
+            ChaosFault {
+                drop: 0,
+                ..Default::default()
+            },
+        );
+        assert!(chaos.inject("ss1").await.is_ok());
+        assert!(chaos.clear("ss1"));
+        assert!(!chaos.clear("ss1"));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{
+    config::internal::proxy::OutboundDirect,
+    proxy::{
+        direct::{Handler, HandlerOptions},
+        utils::proxy_protocol,
+        AnyOutboundHandler,
+    },
+    Error,
+};
+
+impl TryFrom<OutboundDirect> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundDirect) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundDirect> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundDirect) -> Result<Self, Self::Error> {
+        let backend = match (&s.server, s.port) {
+            (Some(server), Some(port)) => Some((server.to_owned(), port)),
+            (None, None) => None,
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "direct {}: server and port must be set together",
+                    s.name
+                )))
+            }
+        };
+        let proxy_protocol = match s.proxy_protocol {
+            None => None,
+            Some(1) => Some(proxy_protocol::Version::V1),
+            Some(2) => Some(proxy_protocol::Version::V2),
+            Some(v) => {
+                return Err(Error::InvalidConfig(format!(
+                    "direct {}: invalid proxy-protocol version {}",
+                    s.name, v
+                )))
+            }
+        };
+
+        Ok(Handler::new_with_opts(HandlerOptions {
+            name: s.name.to_owned(),
+            backend,
+            proxy_protocol,
+        }))
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{
+    config::internal::proxy::{Hysteria2Bandwidth, OutboundHysteria2},
+    proxy::{
+        hysteria2::{Handler, HandlerOptions},
+        AnyOutboundHandler,
+    },
+    Error,
+};
+
+impl TryFrom<OutboundHysteria2> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundHysteria2) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundHysteria2> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundHysteria2) -> Result<Self, Self::Error> {
+        let obfs_password = match s.obfs.as_deref() {
+            None | Some("") => None,
+            Some("salamander") => Some(s.obfs_password.clone().ok_or_else(|| {
+                Error::InvalidConfig(format!("hysteria2 {}: obfs-password is required", s.name))
+            })?),
+            Some(obfs) => {
+                return Err(Error::InvalidConfig(format!(
+                    "hysteria2 {}: unsupported obfs {}",
+                    s.name, obfs
+                )))
+            }
+        };
+        let bandwidth = |x: &Option<Hysteria2Bandwidth>| {
+            x.as_ref()
+                .map(|x| {
+                    parse_bandwidth(x).ok_or_else(|| {
+                        Error::InvalidConfig(format!(
+                            "hysteria2 {}: invalid bandwidth {:?}",
+                            s.name, x
+                        ))
+                    })
+                })
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            server: s.server.to_owned(),
+            port: s.port,
+            password: s.password.to_owned(),
+            sni: s.sni.clone().unwrap_or_else(|| s.server.to_owned()),
+            skip_cert_verify: s.skip_cert_verify.unwrap_or(false),
+            alpn: s
+                .alpn
+                .clone()
+                .unwrap_or_else(|| vec!["h3".to_owned()])
+                .into_iter()
+                .map(String::into_bytes)
+                .collect(),
+            up: bandwidth(&s.up)?,
+            down: bandwidth(&s.down)?,
+            obfs_password,
+        });
+        Ok(h)
+    }
+}
+
+/// The bandwidth in bytes per second.
+fn parse_bandwidth(b: &Hysteria2Bandwidth) -> Option<u64> {
+    const MBPS: u64 = 1_000_000 / 8;
+
+    let s = match b {
+        Hysteria2Bandwidth::Mbps(x) => return x.checked_mul(MBPS),
+        Hysteria2Bandwidth::Text(s) => s.trim(),
+    };
+    if let Ok(x) = s.parse::<u64>() {
+        return x.checked_mul(MBPS);
+    }
+
+    let digits = s.find(|c: char| !c.is_ascii_digit())?;
+    let value = s[..digits].parse::<u64>().ok()?;
+    let unit = s[digits..].trim_start();
+    if !unit.is_ascii() {
+        return None;
+    }
+    let (prefix, unit) = unit.split_at(unit.len().checked_sub(3)?);
+    let per_unit: u64 = match prefix.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1_000,
+        "M" => 1_000_000,
+        "G" => 1_000_000_000,
+        "T" => 1_000_000_000_000,
+        _ => return None,
+    };
+    match unit {
+        "bps" => value.checked_mul(per_unit).map(|x| x / 8),
+        "Bps" => value.checked_mul(per_unit),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_bandwidth;
+    use crate::config::internal::proxy::Hysteria2Bandwidth::{Mbps, Text};
+
+    #[test]
+    fn test_parse_bandwidth() {
+        assert_eq!(parse_bandwidth(&Mbps(100)), Some(12_500_000));
+        assert_eq!(parse_bandwidth(&Text("100".to_owned())), Some(12_500_000));
+        assert_eq!(
+            parse_bandwidth(&Text("100 Mbps".to_owned())),
+            Some(12_500_000)
+        );
+        assert_eq!(
+            parse_bandwidth(&Text("1gbps".to_owned())),
+            Some(125_000_000)
+        );
+        assert_eq!(
+            parse_bandwidth(&Text("10 MBps".to_owned())),
+            Some(10_000_000)
+        );
+        assert_eq!(parse_bandwidth(&Text("800 bps".to_owned())), Some(100));
+        assert_eq!(parse_bandwidth(&Text("100 Mb".to_owned())), None);
+        assert_eq!(parse_bandwidth(&Text("fast".to_owned())), None);
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use crate::{
+    config::internal::proxy::OutboundMasque,
+    proxy::{
+        masque::{Handler, HandlerOptions},
+        AnyOutboundHandler,
+    },
+};
+
+impl TryFrom<OutboundMasque> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundMasque) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundMasque> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundMasque) -> Result<Self, Self::Error> {
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            server: s.server.to_owned(),
+            port: s.port,
+            sni: s.sni.clone().unwrap_or_else(|| s.server.to_owned()),
+            skip_cert_verify: s.skip_cert_verify.unwrap_or(false),
+            authorization: s.authorization.clone(),
+            keep_alive_interval: Duration::from_secs(s.keep_alive_interval.unwrap_or(15)),
+        });
+        Ok(h)
+    }
+}
@@ -1,6 +1,12 @@
+pub mod direct;
+pub mod hysteria2;
+pub mod masque;
 pub mod shadowsocks;
+pub mod socks5;
+pub mod ssh;
 pub mod tor;
 pub mod trojan;
 pub mod tuic;
+pub mod vless;
 pub mod vmess;
 pub mod wireguard;
@@ -1,7 +1,9 @@
 use crate::{
     config::internal::proxy::OutboundShadowsocks,
     proxy::{
-        shadowsocks::{Handler, HandlerOptions, OBFSOption},
+        shadowsocks::{
+            check_password, cipher_kind, ExternalPlugin, Handler, HandlerOptions, OBFSOption,
+        },
         AnyOutboundHandler, CommonOption,
     },
     Error,
@@ -19,6 +21,15 @@ impl TryFrom<&OutboundShadowsocks> for AnyOutboundHandler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundShadowsocks) -> Result<Self, Self::Error> {
+        let cipher = cipher_kind(&s.cipher).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "shadowsocks {}: unsupported cipher {}",
+                s.name, s.cipher
+            ))
+        })?;
+        check_password(cipher, &s.password)
+            .map_err(|e| Error::InvalidConfig(format!("shadowsocks {}: {}", s.name, e)))?;
+
         let h = Handler::new(HandlerOptions {
             name: s.name.to_owned(),
             common_opts: CommonOption::default(),
@@ -55,16 +66,18 @@ impl TryFrom<&OutboundShadowsocks> for AnyOutboundHandler {
                         .try_into()
                         .map(OBFSOption::ShadowTls)
                         .ok(),
-                    _ => {
-                        return Err(Error::InvalidConfig(format!(
-                            "unsupported plugin: {}",
-                            plugin
-                        )));
-                    }
+                    // not implemented natively, run it as a SIP003 plugin binary
+                    _ => Some(OBFSOption::Sip003(ExternalPlugin::new(
+                        plugin.to_owned(),
+                        s.plugin_opts.as_ref(),
+                        s.server.to_owned(),
+                        s.port,
+                    ))),
                 },
                 None => None,
             },
             udp: s.udp,
+            remote_dns_resolve: s.remote_dns_resolve,
         });
         Ok(h)
     }
@@ -0,0 +1,88 @@
+use tracing::warn;
+
+use crate::{
+    config::internal::proxy::OutboundSocks5,
+    proxy::{
+        socks::{Handler, HandlerOptions},
+        transport::TLSOptions,
+        utils::is_local_socket,
+        AnyOutboundHandler, CommonOption,
+    },
+    Error,
+};
+
+impl TryFrom<OutboundSocks5> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundSocks5) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundSocks5> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundSocks5) -> Result<Self, Self::Error> {
+        if s.port == 0 && !is_local_socket(&s.server) {
+            return Err(Error::InvalidConfig(format!(
+                "socks5 {}: port is required for {}",
+                s.name, s.server
+            )));
+        }
+        if s.udp && !s.uot {
+            warn!(
+                "socks5 {}: UDP ASSOCIATE is not supported, enable uot to carry UDP",
+                s.name
+            );
+        }
+        // the path is no name for the certificate
+        if s.tls && s.sni.is_none() && is_local_socket(&s.server) {
+            return Err(Error::InvalidConfig(format!(
+                "socks5 {}: sni is required for tls to {}",
+                s.name, s.server
+            )));
+        }
+        if s.tls && s.skip_cert_verity {
+            warn!("skipping TLS cert verification for {}", s.server);
+        }
+
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            common_opts: CommonOption::default(),
+            server: s.server.to_owned(),
+            port: s.port,
+            user: s.username.clone(),
+            password: s.password.clone(),
+            tls: s.tls.then(|| TLSOptions {
+                skip_cert_verify: s.skip_cert_verity,
+                sni: s.sni.clone().unwrap_or(s.server.to_owned()),
+                alpn: None,
+                cert_store: Default::default(),
+                fingerprint: None,
+                reality: None,
+            }),
+            isolation: false,
+        });
+        Ok(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::internal::proxy::OutboundSocks5, proxy::AnyOutboundHandler};
+
+    #[test]
+    #[cfg(unix)]
+    fn test_local_socket_sni_required() {
+        let mut socks5 = OutboundSocks5 {
+            name: "sidecar".to_owned(),
+            server: "/run/sidecar.sock".to_owned(),
+            tls: true,
+            ..Default::default()
+        };
+        assert!(AnyOutboundHandler::try_from(&socks5).is_err());
+
+        socks5.sni = Some("sidecar.example.com".to_owned());
+        assert!(AnyOutboundHandler::try_from(&socks5).is_ok());
+    }
+}
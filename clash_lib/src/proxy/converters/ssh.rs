@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use crate::{
+    config::{home::resolve_path, internal::proxy::OutboundSsh},
+    proxy::{
+        ssh::{host_key_fingerprint, Handler, HandlerOptions},
+        AnyOutboundHandler, CommonOption,
+    },
+    Error,
+};
+
+impl TryFrom<OutboundSsh> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundSsh) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundSsh> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundSsh) -> Result<Self, Self::Error> {
+        let private_key = s
+            .private_key
+            .as_ref()
+            .map(|key| {
+                let key = if key.trim_start().starts_with("-----BEGIN") {
+                    key.to_owned()
+                } else {
+                    std::fs::read_to_string(resolve_path(key)).map_err(|x| {
+                        Error::InvalidConfig(format!(
+                            "ssh {}: failed to read private key {}: {}",
+                            s.name, key, x
+                        ))
+                    })?
+                };
+                russh_keys::decode_secret_key(&key, s.private_key_passphrase.as_deref())
+                    .map(Arc::new)
+                    .map_err(|x| {
+                        Error::InvalidConfig(format!("ssh {}: invalid private key: {}", s.name, x))
+                    })
+            })
+            .transpose()?;
+        if private_key.is_none() && s.password.is_none() {
+            return Err(Error::InvalidConfig(format!(
+                "ssh {}: password or private-key is required",
+                s.name
+            )));
+        }
+
+        let host_keys = s
+            .host_key
+            .iter()
+            .flatten()
+            .map(|key| {
+                host_key_fingerprint(key).ok_or_else(|| {
+                    Error::InvalidConfig(format!("ssh {}: invalid host key {}", s.name, key))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if host_keys.is_empty() {
+            return Err(Error::InvalidConfig(format!(
+                "ssh {}: host-key is required to verify {}",
+                s.name, s.server
+            )));
+        }
+
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            common_opts: CommonOption::default(),
+            server: s.server.to_owned(),
+            port: s.port,
+            username: s.username.to_owned(),
+            password: s.password.clone(),
+            private_key,
+            host_keys,
+        });
+        Ok(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::internal::proxy::OutboundSsh, proxy::AnyOutboundHandler};
+
+    #[test]
+    fn test_host_key_required() {
+        let mut ssh = OutboundSsh {
+            name: "ssh".to_owned(),
+            server: "10.0.0.14".to_owned(),
+            port: 22,
+            username: "root".to_owned(),
+            password: Some("password".to_owned()),
+            ..Default::default()
+        };
+        assert!(AnyOutboundHandler::try_from(&ssh).is_err());
+
+        ssh.host_key = Some(vec![
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFjyfgYO4dgAAM2k/xmTtgqfZJR23M91Rf89zKG+ge+I"
+                .to_owned(),
+        ]);
+        assert!(AnyOutboundHandler::try_from(&ssh).is_ok());
+    }
+}
@@ -1,11 +1,15 @@
 use crate::{
     config::internal::proxy::OutboundTor,
     proxy::{
+        socks,
         tor::{Handler, HandlerOptions},
-        AnyOutboundHandler,
+        AnyOutboundHandler, CommonOption,
     },
 };
 
+/// the default SocksPort of a Tor daemon
+const TOR_SOCKS_PORT: u16 = 9050;
+
 impl TryFrom<OutboundTor> for AnyOutboundHandler {
     type Error = crate::Error;
 
@@ -18,6 +22,19 @@ impl TryFrom<&OutboundTor> for AnyOutboundHandler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundTor) -> Result<Self, Self::Error> {
+        if s.server.is_some() || s.port.is_some() {
+            return Ok(socks::Handler::new(socks::HandlerOptions {
+                name: s.name.to_owned(),
+                common_opts: CommonOption::default(),
+                server: s.server.clone().unwrap_or("127.0.0.1".to_owned()),
+                port: s.port.unwrap_or(TOR_SOCKS_PORT),
+                user: None,
+                password: None,
+                tls: None,
+                isolation: s.isolation,
+            }));
+        }
+
         let h = Handler::new(HandlerOptions {
             name: s.name.to_owned(),
         });
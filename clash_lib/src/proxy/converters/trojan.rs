@@ -1,10 +1,13 @@
+use std::time::Duration;
+
 use tracing::warn;
 
 use crate::{
+    common::tls::{self, CertStoreOptions},
     config::internal::proxy::OutboundTrojan,
     proxy::{
         options::{GrpcOption, WsOption},
-        trojan::{Handler, Opts, Transport},
+        trojan::{Handler, MuxOption, Opts, ShadowsocksOption, Transport},
         AnyOutboundHandler, CommonOption,
     },
     Error,
@@ -27,6 +30,14 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
             warn!("skipping TLS cert verification for {}", s.server);
         }
 
+        let cert_store = CertStoreOptions {
+            trust: s.trust.unwrap_or_default(),
+            ca: s.ca.clone(),
+            ca_str: s.ca_str.clone(),
+        };
+        // a bad CA should fail the config rather than every connection
+        tls::cert_store(&cert_store)?;
+
         let h = Handler::new(Opts {
             name: s.name.to_owned(),
             common_opts: CommonOption::default(),
@@ -34,6 +45,7 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
             port: s.port,
             password: s.password.clone(),
             udp: s.udp.unwrap_or_default(),
+            remote_dns_resolve: s.remote_dns_resolve,
             sni: s
                 .sni
                 .as_ref()
@@ -41,6 +53,7 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                 .unwrap_or(s.server.to_owned()),
             alpn: s.alpn.as_ref().map(|x| x.to_owned()),
             skip_cert_verify,
+            cert_store,
             transport: s
                 .network
                 .as_ref()
@@ -62,6 +75,7 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                                     .as_ref()
                                     .map(|x| x.to_owned())
                                     .unwrap_or_default(),
+                                ping_interval: x.ping_interval.map(Duration::from_secs),
                             })
                         })
                         .ok_or(Error::InvalidConfig(
@@ -78,6 +92,7 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                                     .as_ref()
                                     .map(|x| x.to_owned())
                                     .unwrap_or_default(),
+                                ping_interval: x.ping_interval.map(Duration::from_secs),
                             })
                         })
                         .ok_or(Error::InvalidConfig(
@@ -89,6 +104,21 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                     ))),
                 })
                 .transpose()?,
+            mux: s.mux_opts.as_ref().map(|x| MuxOption {
+                concurrency: x.concurrency.unwrap_or(8).max(1),
+                idle_timeout: Duration::from_secs(x.idle_timeout.unwrap_or(60)),
+            }),
+            shadowsocks: s
+                .ss_opts
+                .as_ref()
+                .map(|x| {
+                    ShadowsocksOption::new(
+                        x.method.as_deref().unwrap_or("aes-128-gcm"),
+                        &x.password,
+                    )
+                    .map_err(Error::InvalidConfig)
+                })
+                .transpose()?,
         });
         Ok(h)
     }
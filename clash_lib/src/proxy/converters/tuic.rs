@@ -5,7 +5,8 @@ use quinn::VarInt;
 use crate::{
     config::internal::proxy::OutboundTuic,
     proxy::{
-        tuic::{types::CongestionControl, Handler, HandlerOptions},
+        transport::quic::CongestionControl,
+        tuic::{Handler, HandlerOptions},
         AnyOutboundHandler,
     },
 };
@@ -44,6 +45,9 @@ impl TryFrom<&OutboundTuic> for AnyOutboundHandler {
                 .map(|v| CongestionControl::from(v.as_str()))
                 .unwrap_or_default(),
             max_udp_relay_packet_size: s.max_udp_relay_packet_size.unwrap_or(1500),
+            mtu_discovery: s.mtu_discovery.unwrap_or(true),
+            initial_mtu: s.initial_mtu.unwrap_or(1200),
+            udp_fragment: s.udp_fragment.unwrap_or(true),
             max_open_stream: VarInt::from_u64(s.max_open_stream.unwrap_or(32))
                 .unwrap_or(VarInt::MAX),
             ip: s.ip.clone(),
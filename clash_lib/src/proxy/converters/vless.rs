@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::{
+    common::tls::{self, CertStoreOptions},
+    config::internal::proxy::OutboundVless,
+    proxy::{
+        options::{GrpcOption, WsOption},
+        transport::{
+            utls::{Fingerprint, RealityOptions},
+            TLSOptions,
+        },
+        vless::{Handler, HandlerOptions, Transport, VISION_FLOW},
+        AnyOutboundHandler, CommonOption,
+    },
+    Error,
+};
+
+impl TryFrom<OutboundVless> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundVless) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundVless> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundVless) -> Result<Self, Self::Error> {
+        let uuid = uuid::Uuid::parse_str(&s.uuid)
+            .map_err(|x| Error::InvalidConfig(format!("vless {}: invalid uuid: {}", s.name, x)))?;
+
+        let vision = match s.flow.as_deref() {
+            None | Some("") => false,
+            Some(VISION_FLOW) => true,
+            Some(flow) => {
+                return Err(Error::InvalidConfig(format!(
+                    "vless {}: unsupported flow {}",
+                    s.name, flow
+                )))
+            }
+        };
+        let network = s.network.as_deref().unwrap_or("tcp");
+        let tls_enabled = s.tls.unwrap_or_default();
+        let fingerprint = match s.client_fingerprint.as_deref() {
+            None | Some("") | Some("none") => None,
+            Some(x) => Some(
+                x.parse::<Fingerprint>()
+                    .map_err(|x| Error::InvalidConfig(format!("vless {}: {}", s.name, x)))?,
+            ),
+        };
+        let reality = s
+            .reality_opts
+            .as_ref()
+            .map(|x| RealityOptions::new(&x.public_key, x.short_id.as_deref()))
+            .transpose()
+            .map_err(|x| Error::InvalidConfig(format!("vless {}: {}", s.name, x)))?;
+        if reality.is_some() && !tls_enabled {
+            return Err(Error::InvalidConfig(format!(
+                "vless {}: reality-opts requires tls",
+                s.name
+            )));
+        }
+        if vision && (!tls_enabled || network != "tcp") {
+            return Err(Error::InvalidConfig(format!(
+                "vless {}: {} requires tls over the tcp network",
+                s.name, VISION_FLOW
+            )));
+        }
+
+        let transport = match network {
+            "tcp" => None,
+            "ws" => Some(
+                s.ws_opts
+                    .as_ref()
+                    .map(|x| {
+                        Transport::Ws(WsOption {
+                            path: x.path.clone().unwrap_or_default(),
+                            headers: x.headers.clone().unwrap_or_default(),
+                            max_early_data: x.max_early_data.unwrap_or_default() as usize,
+                            early_data_header_name: x
+                                .early_data_header_name
+                                .clone()
+                                .unwrap_or_default(),
+                            ping_interval: x.ping_interval.map(Duration::from_secs),
+                        })
+                    })
+                    .ok_or(Error::InvalidConfig(
+                        "ws_opts is required for ws".to_owned(),
+                    ))?,
+            ),
+            "grpc" => Some(
+                s.grpc_opts
+                    .as_ref()
+                    .map(|x| {
+                        Transport::Grpc(GrpcOption {
+                            host: s.servername.as_ref().unwrap_or(&s.server).to_owned(),
+                            service_name: x
+                                .grpc_service_name
+                                .clone()
+                                .unwrap_or("GunService".to_owned()),
+                            ping_interval: x.ping_interval.map(Duration::from_secs),
+                        })
+                    })
+                    .ok_or(Error::InvalidConfig(
+                        "grpc_opts is required for grpc".to_owned(),
+                    ))?,
+            ),
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "unsupported vless network: {}",
+                    network
+                )))
+            }
+        };
+
+        let tls = if tls_enabled {
+            let skip_cert_verify = s.skip_cert_verify.unwrap_or_default();
+            if skip_cert_verify {
+                warn!("skipping TLS cert verification for {}", s.server);
+            }
+
+            let cert_store = CertStoreOptions {
+                trust: s.trust.unwrap_or_default(),
+                ca: s.ca.clone(),
+                ca_str: s.ca_str.clone(),
+            };
+            // a bad CA should fail the config rather than every connection
+            tls::cert_store(&cert_store)?;
+
+            Some(TLSOptions {
+                skip_cert_verify,
+                sni: s.servername.clone().unwrap_or(
+                    s.ws_opts
+                        .as_ref()
+                        .and_then(|x| x.headers.as_ref()?.get("Host").cloned())
+                        .unwrap_or(s.server.to_owned()),
+                ),
+                alpn: match network {
+                    "ws" => Some(vec!["http/1.1".to_owned()]),
+                    "grpc" => Some(vec!["h2".to_owned()]),
+                    _ => s.alpn.clone(),
+                },
+                cert_store,
+                fingerprint,
+                reality,
+            })
+        } else {
+            None
+        };
+
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            common_opts: CommonOption::default(),
+            server: s.server.to_owned(),
+            port: s.port,
+            uuid,
+            vision,
+            udp: s.udp.unwrap_or_default(),
+            remote_dns_resolve: s.remote_dns_resolve,
+            transport,
+            tls,
+        });
+        Ok(h)
+    }
+}
@@ -1,6 +1,9 @@
+use std::time::Duration;
+
 use tracing::warn;
 
 use crate::{
+    common::tls::{self, CertStoreOptions},
     config::internal::proxy::OutboundVmess,
     proxy::{
         options::{GrpcOption, Http2Option, WsOption},
@@ -28,6 +31,16 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
             warn!("skipping TLS cert verification for {}", s.server);
         }
 
+        let cert_store = CertStoreOptions {
+            trust: s.trust.unwrap_or_default(),
+            ca: s.ca.clone(),
+            ca_str: s.ca_str.clone(),
+        };
+        // a bad CA should fail the config rather than every connection
+        if s.tls.unwrap_or_default() {
+            tls::cert_store(&cert_store)?;
+        }
+
         let h = Handler::new(HandlerOptions {
             name: s.name.to_owned(),
             common_opts: CommonOption::default(),
@@ -37,6 +50,7 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
             alter_id: s.alter_id,
             security: s.cipher.clone().unwrap_or_default(),
             udp: s.udp.unwrap_or(true),
+            remote_dns_resolve: s.remote_dns_resolve,
             transport: s
                 .network
                 .clone()
@@ -58,6 +72,7 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
                                     .as_ref()
                                     .map(|x| x.to_owned())
                                     .unwrap_or_default(),
+                                ping_interval: x.ping_interval.map(Duration::from_secs),
                             })
                         })
                         .ok_or(Error::InvalidConfig(
@@ -91,6 +106,7 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
                                     .to_owned()
                                     .unwrap_or(&"GunService".to_owned())
                                     .to_owned(),
+                                ping_interval: x.ping_interval.map(Duration::from_secs),
                             })
                         })
                         .ok_or(Error::InvalidConfig(
@@ -124,6 +140,9 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
                             _ => Err(Error::InvalidConfig(format!("unsupported network: {}", x))),
                         })
                         .transpose()?,
+                    cert_store,
+                    fingerprint: None,
+                    reality: None,
                 }),
                 false => None,
             },
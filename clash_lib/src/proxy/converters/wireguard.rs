@@ -1,9 +1,10 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use ipnet::IpNet;
 
 use crate::{
-    config::internal::proxy::OutboundWireguard,
+    config::internal::proxy::{OutboundWireguard, WireguardReserved},
     proxy::{
-        wg::{Handler, HandlerOpts},
+        wg::{keys::KeyBytes, Handler, HandlerOpts},
         AnyOutboundHandler,
     },
     Error,
@@ -21,11 +22,55 @@ impl TryFrom<&OutboundWireguard> for AnyOutboundHandler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundWireguard) -> Result<Self, Self::Error> {
+        let (server, port, public_key, preshared_key, allowed_ips, reserved) =
+            match s.peers.as_deref() {
+                None | Some([]) => (
+                    &s.server,
+                    s.port,
+                    &s.public_key,
+                    s.preshared_key.as_ref(),
+                    s.allowed_ips.as_ref(),
+                    s.reserved_bits.as_ref(),
+                ),
+                Some([peer]) => (
+                    &peer.server,
+                    peer.port,
+                    &peer.public_key,
+                    peer.preshared_key.as_ref().or(s.preshared_key.as_ref()),
+                    peer.allowed_ips.as_ref().or(s.allowed_ips.as_ref()),
+                    peer.reserved.as_ref().or(s.reserved_bits.as_ref()),
+                ),
+                Some(_) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "wireguard {}: only one peer is supported",
+                        s.name
+                    )))
+                }
+            };
+        if server.is_empty() || public_key.is_empty() {
+            return Err(Error::InvalidConfig(format!(
+                "wireguard {}: server and public-key are required",
+                s.name
+            )));
+        }
+        for (field, key) in [
+            ("private-key", Some(&s.private_key)),
+            ("public-key", Some(public_key)),
+            ("preshared-key", preshared_key),
+        ] {
+            if let Some(Err(e)) = key.map(|x| x.parse::<KeyBytes>()) {
+                return Err(Error::InvalidConfig(format!(
+                    "wireguard {}: invalid {}: {}",
+                    s.name, field, e
+                )));
+            }
+        }
+
         let h = Handler::new(HandlerOpts {
             name: s.name.to_owned(),
             common_opts: Default::default(),
-            server: s.server.to_owned(),
-            port: s.port,
+            server: server.to_owned(),
+            port,
             ip: s
                 .ip
                 .parse::<IpNet>()
@@ -56,15 +101,50 @@ impl TryFrom<&OutboundWireguard> for AnyOutboundHandler {
                 })
                 .transpose()?,
             private_key: s.private_key.to_owned(),
-            public_key: s.public_key.to_owned(),
-            preshared_key: s.preshared_key.as_ref().map(|x| x.to_owned()),
+            public_key: public_key.to_owned(),
+            preshared_key: preshared_key.cloned(),
             remote_dns_resolve: s.remote_dns_resolve.unwrap_or_default(),
             dns: s.dns.as_ref().map(|x| x.to_owned()),
             mtu: s.mtu,
             udp: s.udp.unwrap_or_default(),
-            allowed_ips: s.allowed_ips.as_ref().map(|x| x.to_owned()),
-            reserved_bits: s.reserved_bits.as_ref().map(|x| x.to_owned()),
+            allowed_ips: allowed_ips.cloned(),
+            reserved: reserved
+                .map(|x| parse_reserved(&s.name, x))
+                .transpose()?
+                .unwrap_or_default(),
         });
         Ok(h)
     }
 }
+
+fn parse_reserved(name: &str, reserved: &WireguardReserved) -> Result<[u8; 3], Error> {
+    let bytes = match reserved {
+        WireguardReserved::Bytes(bytes) => bytes.clone(),
+        WireguardReserved::Base64(s) => STANDARD.decode(s).map_err(|e| {
+            Error::InvalidConfig(format!("wireguard {}: invalid reserved: {}", name, e))
+        })?,
+    };
+    bytes
+        .try_into()
+        .map_err(|_| Error::InvalidConfig(format!("wireguard {}: reserved must be 3 bytes", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::internal::proxy::WireguardReserved;
+
+    use super::parse_reserved;
+
+    #[test]
+    fn test_parse_reserved() {
+        assert_eq!(
+            parse_reserved("wg", &WireguardReserved::Bytes(vec![209, 98, 59])).unwrap(),
+            [209, 98, 59]
+        );
+        assert_eq!(
+            parse_reserved("wg", &WireguardReserved::Base64("0WI7".to_owned())).unwrap(),
+            [209, 98, 59]
+        );
+        assert!(parse_reserved("wg", &WireguardReserved::Bytes(vec![1, 2])).is_err());
+    }
+}
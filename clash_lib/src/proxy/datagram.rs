@@ -210,6 +210,10 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
                 }
                 SocksAddr::Ip(addr) => *addr,
             };
+            let dst = match resolver.nat64() {
+                Some(nat64) => SocketAddr::new(nat64.map(dst.ip()), dst.port()),
+                None => dst,
+            };
 
             let n = ready!(inner.poll_send_to(cx, data.as_slice(), dst))?;
             let wrote_all = n == data.len();
@@ -242,12 +246,20 @@ impl Stream for OutboundDatagramImpl {
     type Item = UdpPacket;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let Self { ref mut inner, .. } = *self;
+        let Self {
+            ref mut inner,
+            ref resolver,
+            ..
+        } = *self;
         let mut mem = vec![0u8; 65535];
         let mut buf = ReadBuf::new(&mut mem);
         match ready!(inner.poll_recv_from(cx, &mut buf)) {
             Ok(src) => {
                 let data = buf.filled().to_vec();
+                let src = match resolver.nat64() {
+                    Some(nat64) => SocketAddr::new(nat64.unmap(src.ip()), src.port()),
+                    None => src,
+                };
                 Poll::Ready(Some(UdpPacket {
                     data,
                     src_addr: src.into(),
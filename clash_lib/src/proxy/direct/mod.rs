@@ -5,31 +5,112 @@ use crate::app::dispatcher::{
 use crate::app::dns::ThreadSafeDNSResolver;
 use crate::config::internal::proxy::PROXY_DIRECT;
 use crate::proxy::datagram::OutboundDatagramImpl;
-use crate::proxy::utils::{new_tcp_stream, new_udp_socket};
-use crate::proxy::{AnyOutboundHandler, OutboundHandler};
+use crate::proxy::utils::{new_tcp_stream, new_udp_socket, proxy_protocol};
+use crate::proxy::{AnyOutboundHandler, AnyStream, OutboundHandler};
 use crate::session::Session;
 
 use async_trait::async_trait;
-use serde::Serialize;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 
 use super::utils::RemoteConnector;
 use super::{ConnectorType, OutboundType};
 
-#[derive(Serialize)]
-pub struct Handler;
+pub struct HandlerOptions {
+    pub name: String,
+    /// connect to this instead of the destination of the session
+    pub backend: Option<(String, u16)>,
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+}
 
 impl Handler {
     #[allow(clippy::new_ret_no_self)]
     pub fn new() -> AnyOutboundHandler {
-        Arc::new(Self)
+        Self::new_with_opts(HandlerOptions {
+            name: PROXY_DIRECT.to_owned(),
+            backend: None,
+            proxy_protocol: None,
+        })
+    }
+
+    pub fn new_with_opts(opts: HandlerOptions) -> AnyOutboundHandler {
+        Arc::new(Self { opts })
+    }
+
+    async fn dial(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: Option<&dyn RemoteConnector>,
+    ) -> io::Result<AnyStream> {
+        let (host, port) = match &self.opts.backend {
+            Some((host, port)) => (host.clone(), *port),
+            None => (sess.destination.host(), sess.destination.port()),
+        };
+
+        // the header carries the address connected to, so it's resolved here
+        let (host, dst) = match self.opts.proxy_protocol {
+            Some(_) => {
+                let ip = resolver
+                    .resolve(&host, false)
+                    .await
+                    .map_err(|v| {
+                        io::Error::new(io::ErrorKind::Other, format!("dns failure: {}", v))
+                    })?
+                    .ok_or(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("can't resolve dns: {}", host),
+                    ))?;
+                (ip.to_string(), Some(SocketAddr::new(ip, port)))
+            }
+            None => (host, None),
+        };
+
+        let mut s = match connector {
+            Some(connector) => {
+                connector
+                    .connect_stream(
+                        resolver,
+                        host.as_str(),
+                        port,
+                        None,
+                        #[cfg(any(target_os = "linux", target_os = "android"))]
+                        None,
+                    )
+                    .await?
+            }
+            None => {
+                new_tcp_stream(
+                    resolver,
+                    host.as_str(),
+                    port,
+                    sess.iface.as_ref(),
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    None,
+                )
+                .await?
+            }
+        };
+
+        if let (Some(version), Some(dst)) = (self.opts.proxy_protocol, dst) {
+            s.write_all(&proxy_protocol::encode_header(version, sess.source, dst))
+                .await?;
+        }
+
+        Ok(s)
     }
 }
 
 #[async_trait]
 impl OutboundHandler for Handler {
     fn name(&self) -> &str {
-        PROXY_DIRECT
+        &self.opts.name
     }
 
     fn proto(&self) -> OutboundType {
@@ -37,7 +118,12 @@ impl OutboundHandler for Handler {
     }
 
     async fn support_udp(&self) -> bool {
-        true
+        // datagrams can't be redirected to the backend
+        self.opts.backend.is_none()
+    }
+
+    fn remote_dns_resolve(&self) -> bool {
+        false
     }
 
     async fn connect_stream(
@@ -45,15 +131,7 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedStream> {
-        let s = new_tcp_stream(
-            resolver,
-            sess.destination.host().as_str(),
-            sess.destination.port(),
-            None,
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .await?;
+        let s = self.dial(sess, resolver, None).await?;
 
         let s = ChainedStreamWrapper::new(s);
         s.append_to_chain(self.name()).await;
@@ -65,8 +143,12 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedDatagram> {
+        // the destinations are all IPv6 through the NAT64
+        let src = resolver
+            .nat64()
+            .map(|_| SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)));
         let d = new_udp_socket(
-            None,
+            src.as_ref(),
             sess.iface.as_ref(),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
@@ -89,16 +171,7 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> std::io::Result<BoxedChainedStream> {
-        let s = connector
-            .connect_stream(
-                resolver,
-                sess.destination.host().as_str(),
-                sess.destination.port(),
-                None,
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                None,
-            )
-            .await?;
+        let s = self.dial(sess, resolver, Some(connector)).await?;
         let s = ChainedStreamWrapper::new(s);
         s.append_to_chain(self.name()).await;
         Ok(Box::new(s))
@@ -1,4 +1,11 @@
-use std::{collections::HashMap, io};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
 
 use erased_serde::Serialize;
 use tracing::debug;
@@ -8,9 +15,10 @@ use crate::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
         remote_content_manager::{
-            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager,
+            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager, SwitchReason,
         },
     },
+    common::errors::member_dial_error,
     session::Session,
 };
 
@@ -19,10 +27,15 @@ use super::{
     AnyOutboundHandler, CommonOption, ConnectorType, OutboundHandler, OutboundType,
 };
 
+pub const DEFAULT_MAX_FAILED_TIMES: u32 = 5;
+
 #[derive(Default, Clone)]
 pub struct HandlerOptions {
     pub name: String,
     pub udp: bool,
+    /// the dials through the current proxy that may fail in a row before the
+    /// members are checked again, never if 0
+    pub max_failed_times: u32,
 
     pub common_option: CommonOption,
 }
@@ -31,6 +44,9 @@ pub struct Handler {
     opts: HandlerOptions,
     providers: Vec<ThreadSafeProxyProvider>,
     proxy_manager: ProxyManager,
+    failed_times: AtomicU32,
+    /// the member picked last
+    current: Mutex<Option<String>>,
 }
 
 impl Handler {
@@ -43,6 +59,8 @@ impl Handler {
             opts,
             providers,
             proxy_manager,
+            failed_times: AtomicU32::new(0),
+            current: Mutex::new(None),
         }
     }
 
@@ -52,13 +70,57 @@ impl Handler {
 
     async fn find_alive_proxy(&self, touch: bool) -> AnyOutboundHandler {
         let proxies = self.get_proxies(touch).await;
+        let mut picked = &proxies[0];
         for proxy in proxies.iter() {
             if self.proxy_manager.alive(proxy.name()).await {
                 debug!("`{}` fallback to `{}`", self.name(), proxy.name());
-                return proxy.clone();
+                picked = proxy;
+                break;
             }
         }
-        proxies[0].clone()
+        self.on_pick(picked.name()).await;
+        picked.clone()
+    }
+
+    /// Record a switch if the member isn't the one picked last.
+    async fn on_pick(&self, name: &str) {
+        let last = self.current.lock().unwrap().replace(name.to_owned());
+        let Some(last) = last.filter(|x| x != name) else {
+            return;
+        };
+        let reason = if self.proxy_manager.alive(&last).await {
+            SwitchReason::Recovered
+        } else {
+            self.proxy_manager.failure_reason(&last)
+        };
+        self.proxy_manager
+            .report_switch(self.name(), &last, name, reason);
+    }
+
+    /// Count the failed dials in a row, and check the members once there
+    /// are too many, so the group moves on without waiting for the interval.
+    fn on_dial<T>(&self, result: &io::Result<T>) {
+        if result.is_ok() {
+            self.failed_times.store(0, Ordering::Relaxed);
+            return;
+        }
+        let max = self.opts.max_failed_times;
+        if max == 0 || self.failed_times.fetch_add(1, Ordering::Relaxed) + 1 < max {
+            return;
+        }
+        self.failed_times.store(0, Ordering::Relaxed);
+
+        debug!(
+            "`{}` failed {} times in a row, checking the members",
+            self.name(),
+            max
+        );
+        let providers = self.providers.clone();
+        tokio::spawn(async move {
+            for provider in providers {
+                provider.read().await.healthcheck().await;
+            }
+        });
     }
 }
 
@@ -87,7 +149,12 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
         let proxy = self.find_alive_proxy(true).await;
-        match proxy.connect_stream(sess, resolver).await {
+        let result = proxy
+            .connect_stream(sess, resolver)
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e));
+        self.on_dial(&result);
+        match result {
             Ok(s) => {
                 s.append_to_chain(self.name()).await;
                 Ok(s)
@@ -103,7 +170,12 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
         let proxy = self.find_alive_proxy(true).await;
-        proxy.connect_datagram(sess, resolver).await
+        let result = proxy
+            .connect_datagram(sess, resolver)
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e));
+        self.on_dial(&result);
+        result
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -117,9 +189,15 @@ impl OutboundHandler for Handler {
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
         let proxy = self.find_alive_proxy(true).await;
-        proxy
+        let result = proxy
             .connect_stream_with_connector(sess, resolver, connector)
-            .await
+            .await;
+        self.on_dial(&result);
+        result
+    }
+
+    async fn current_member(&self) -> Option<AnyOutboundHandler> {
+        Some(self.find_alive_proxy(false).await)
     }
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
@@ -138,3 +216,46 @@ impl OutboundHandler for Handler {
         m
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc, time::Duration};
+
+    use tokio::sync::RwLock;
+
+    use crate::{
+        app::{dns::MockClashResolver, remote_content_manager::ProxyManager},
+        proxy::mocks::MockDummyProxyProvider,
+    };
+
+    #[tokio::test]
+    async fn test_check_after_failed_dials() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut mock_provider = MockDummyProxyProvider::new();
+        mock_provider
+            .expect_healthcheck()
+            .times(1)
+            .returning(move || tx.send(()).unwrap());
+
+        let handler = super::Handler::new(
+            super::HandlerOptions {
+                name: "fallback".to_owned(),
+                max_failed_times: 2,
+                ..Default::default()
+            },
+            vec![Arc::new(RwLock::new(mock_provider))],
+            ProxyManager::new(Arc::new(MockClashResolver::new())),
+        );
+
+        let failed: io::Result<()> = Err(io::ErrorKind::ConnectionRefused.into());
+        handler.on_dial(&failed);
+        handler.on_dial(&Ok(()));
+        handler.on_dial(&failed);
+        handler.on_dial(&failed);
+
+        tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! The response to a plain HTTP request rejected by a rule, so the user sees
+//! why the site is blocked instead of a connection reset.
+
+use std::path::Path;
+
+use hyper::{header, Body, Response, StatusCode};
+
+use crate::{config::internal::config::HttpBlockPageConfig, Error};
+
+pub struct BlockPage {
+    status: StatusCode,
+    /// HTML with `{host}` and `{rule}` placeholders
+    template: Option<String>,
+}
+
+impl BlockPage {
+    pub fn new(cfg: HttpBlockPageConfig, cwd: &Path) -> Result<Self, Error> {
+        let status = StatusCode::from_u16(cfg.status)
+            .ok()
+            .filter(|x| x.is_client_error() || x.is_server_error())
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!("invalid block page status: {}", cfg.status))
+            })?;
+        let template = cfg
+            .page
+            .map(|x| std::fs::read_to_string(cwd.join(x)))
+            .transpose()?;
+        Ok(Self { status, template })
+    }
+
+    pub fn response(&self, host: &str, rule: &str) -> Response<Body> {
+        let res = Response::builder().status(self.status);
+        match &self.template {
+            Some(template) => res
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(
+                    template
+                        .replace("{host}", &escape(host))
+                        .replace("{rule}", &escape(rule))
+                        .into(),
+                ),
+            None => res
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(format!("{} is blocked by rule {}\n", host, rule).into()),
+        }
+        .unwrap()
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use super::BlockPage;
+
+    #[tokio::test]
+    async fn test_response() {
+        let page = BlockPage {
+            status: StatusCode::FORBIDDEN,
+            template: Some("<p>{host} blocked by {rule}</p>".to_owned()),
+        };
+        let res = page.response("<ads>.example.com", "DOMAIN-SUFFIX,example.com");
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            body,
+            "<p>&lt;ads&gt;.example.com blocked by DOMAIN-SUFFIX,example.com</p>"
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::proxy::{AnyStream, ProxyError};
-use crate::session::{Network, Session, Type};
+use crate::session::{ListenerId, Network, Session, Type};
 use crate::Dispatcher;
 use futures::FutureExt;
 
@@ -16,12 +16,17 @@ use super::proxy::maybe_socks_addr;
 #[derive(Clone)]
 pub struct Connector {
     src: SocketAddr,
+    listener: ListenerId,
     dispatcher: Arc<Dispatcher>,
 }
 
 impl Connector {
-    pub fn new(src: SocketAddr, dispatcher: Arc<Dispatcher>) -> Self {
-        Self { src, dispatcher }
+    pub fn new(src: SocketAddr, listener: ListenerId, dispatcher: Arc<Dispatcher>) -> Self {
+        Self {
+            src,
+            listener,
+            dispatcher,
+        }
     }
 }
 
@@ -39,6 +44,7 @@ impl tower::Service<Uri> for Connector {
 
     fn call(&mut self, url: Uri) -> Self::Future {
         let src = self.src;
+        let listener = self.listener;
         let dispatcher = self.dispatcher.clone();
 
         let destination = maybe_socks_addr(&url);
@@ -51,6 +57,7 @@ impl tower::Service<Uri> for Connector {
                 typ: Type::Http,
                 source: src,
                 destination: destination.ok_or(ProxyError::InvalidUrl(url.to_string()))?,
+                listener: Some(listener),
                 ..Default::default()
             };
 
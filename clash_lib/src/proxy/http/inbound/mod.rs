@@ -1,13 +1,17 @@
 mod auth;
+mod block_page;
 mod connector;
 mod proxy;
 
+use crate::app::inbound::rate_limit::ThreadSafeRateLimiter;
 use crate::common::auth::ThreadSafeAuthenticator;
-use crate::proxy::utils::apply_tcp_options;
+use crate::proxy::utils::{apply_tcp_options, proxy_protocol};
 use crate::proxy::{AnyInboundListener, InboundListener};
+use crate::session::ListenerId;
 use crate::Dispatcher;
 use async_trait::async_trait;
 
+pub use block_page::BlockPage;
 pub use proxy::handle as handle_http;
 
 use std::io;
@@ -21,6 +25,9 @@ pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    proxy_protocol: bool,
+    block_page: Option<Arc<BlockPage>>,
+    rate_limiter: Option<ThreadSafeRateLimiter>,
 }
 
 impl Drop for Listener {
@@ -35,11 +42,17 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        proxy_protocol: bool,
+        block_page: Option<Arc<BlockPage>>,
+        rate_limiter: Option<ThreadSafeRateLimiter>,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
             dispatcher,
             authenticator,
+            proxy_protocol,
+            block_page,
+            rate_limiter,
         }) as _
     }
 }
@@ -54,19 +67,43 @@ impl InboundListener for Listener {
         false
     }
 
-    async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
-
+    async fn listen_tcp(&self, listener: TcpListener) -> std::io::Result<()> {
         loop {
-            let (socket, src_addr) = listener.accept().await?;
+            let (socket, _) = listener.accept().await?;
 
-            let socket = apply_tcp_options(socket)?;
+            let mut socket = apply_tcp_options(socket)?;
 
             let dispatcher = self.dispatcher.clone();
             let author = self.authenticator.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let block_page = self.block_page.clone();
+            let limiter = self.rate_limiter.clone();
+            let listener = ListenerId {
+                name: "http",
+                port: self.addr.port(),
+            };
 
             tokio::spawn(async move {
-                proxy::handle(Box::new(socket), src_addr, dispatcher, author).await
+                let src_addr = match proxy_protocol::source_addr(&mut socket, proxy_protocol).await
+                {
+                    Ok(src_addr) => src_addr,
+                    Err(e) => {
+                        warn!("failed to accept HTTP connection: {}", e);
+                        return;
+                    }
+                };
+                if limiter.as_ref().is_some_and(|x| !x.allow(src_addr.ip())) {
+                    return;
+                }
+                proxy::handle(
+                    Box::new(socket),
+                    src_addr,
+                    listener,
+                    dispatcher,
+                    author,
+                    block_page,
+                )
+                .await
             });
         }
     }
@@ -14,10 +14,10 @@ use crate::{
     app::dispatcher::Dispatcher,
     common::auth::ThreadSafeAuthenticator,
     proxy::{AnyStream, ProxyError},
-    session::{Network, Session, SocksAddr, Type},
+    session::{ListenerId, Network, Session, SocksAddr, Type},
 };
 
-use super::{auth::authenticate_req, connector::Connector};
+use super::{auth::authenticate_req, block_page::BlockPage, connector::Connector};
 
 pub fn maybe_socks_addr(r: &Uri) -> Option<SocksAddr> {
     let port = r
@@ -37,11 +37,31 @@ pub fn maybe_socks_addr(r: &Uri) -> Option<SocksAddr> {
     })
 }
 
+/// Make sure the request uri is in absolute-form so it can be routed, and
+/// strip the headers meant for the proxy only.
+///
+/// A request in origin-form is meant for a server, not a proxy, and its
+/// `Host` could well be the listener itself, which would loop back here.
+fn normalize_request(req: &mut Request<Body>) -> Result<(), String> {
+    if req.uri().authority().is_none() {
+        return Err(format!("invalid request uri: {}", req.uri()));
+    }
+
+    let headers = req.headers_mut();
+    headers.remove("proxy-connection");
+    headers.remove(hyper::header::PROXY_AUTHORIZATION);
+
+    Ok(())
+}
+
 async fn proxy(
-    req: Request<Body>,
+    mut req: Request<Body>,
     src: SocketAddr,
+    listener: ListenerId,
+    client: Client<Connector>,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    block_page: Option<Arc<BlockPage>>,
 ) -> Result<Response<Body>, ProxyError> {
     if authenticator.enabled() {
         if let Some(res) = authenticate_req(&req, authenticator) {
@@ -49,11 +69,6 @@ async fn proxy(
         }
     }
 
-    let client = Client::builder()
-        .http1_title_case_headers(true)
-        .http1_preserve_header_case(true)
-        .build(Connector::new(src, dispatcher.clone()));
-
     // TODO: handle other upgrades: https://github.com/hyperium/hyper/blob/master/examples/upgrades.rs
     if req.method() == Method::CONNECT {
         if let Some(addr) = maybe_socks_addr(req.uri()) {
@@ -65,6 +80,7 @@ async fn proxy(
                             typ: Type::HttpConnect,
                             source: src,
                             destination: addr,
+                            listener: Some(listener),
 
                             ..Default::default()
                         };
@@ -83,6 +99,32 @@ async fn proxy(
                 .unwrap())
         }
     } else {
+        if let Err(e) = normalize_request(&mut req) {
+            return Ok(Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(e.into())
+                .unwrap());
+        }
+
+        if let Some(block_page) = block_page {
+            if let Some(addr) = maybe_socks_addr(req.uri()) {
+                let sess = Session {
+                    network: Network::Tcp,
+                    typ: Type::Http,
+                    source: src,
+                    destination: addr,
+                    listener: Some(listener),
+                    ..Default::default()
+                };
+                if let Some(rule) = dispatcher.rejected_by(&sess).await {
+                    return Ok(block_page.response(&sess.destination.host(), &rule));
+                }
+            }
+        }
+
+        // the client is shared by all the requests on this connection,
+        // the upstream connections are pooled per host, and a new host is
+        // dispatched through the router again
         match client
             .request(req)
             .map_err(|x| ProxyError::General(x.to_string()))
@@ -102,8 +144,11 @@ async fn proxy(
 
 struct ProxyService {
     src: SocketAddr,
+    listener: ListenerId,
+    client: Client<Connector>,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    block_page: Option<Arc<BlockPage>>,
 }
 
 impl Service<Request<Body>> for ProxyService {
@@ -124,19 +169,29 @@ impl Service<Request<Body>> for ProxyService {
         Box::pin(proxy(
             req,
             self.src,
+            self.listener,
+            self.client.clone(),
             self.dispatcher.clone(),
             self.authenticator.clone(),
+            self.block_page.clone(),
         ))
     }
 }
 
-#[instrument(skip(stream, dispatcher, authenticator))]
+#[instrument(skip(stream, dispatcher, authenticator, block_page))]
 pub async fn handle(
     stream: AnyStream,
     src: SocketAddr,
+    listener: ListenerId,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    block_page: Option<Arc<BlockPage>>,
 ) {
+    let client = Client::builder()
+        .http1_title_case_headers(true)
+        .http1_preserve_header_case(true)
+        .build(Connector::new(src, listener, dispatcher.clone()));
+
     tokio::task::spawn(async move {
         if let Err(http_err) = Http::new()
             .http1_only(true)
@@ -145,8 +200,11 @@ pub async fn handle(
                 stream,
                 ProxyService {
                     src,
+                    listener,
+                    client,
                     dispatcher,
                     authenticator,
+                    block_page,
                 },
             )
             .with_upgrades()
@@ -156,3 +214,28 @@ pub async fn handle(
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request};
+
+    use super::normalize_request;
+
+    #[test]
+    fn test_normalize_request() {
+        let mut req = Request::get("http://example.com:8080/index.html?a=1")
+            .header("proxy-connection", "keep-alive")
+            .body(Body::empty())
+            .unwrap();
+        normalize_request(&mut req).unwrap();
+        assert_eq!(req.uri(), "http://example.com:8080/index.html?a=1");
+        assert!(req.headers().get("proxy-connection").is_none());
+
+        // even with a host, it could be the listener itself
+        let mut req = Request::get("/index.html")
+            .header("host", "127.0.0.1:7890")
+            .body(Body::empty())
+            .unwrap();
+        assert!(normalize_request(&mut req).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 mod inbound;
 
 pub use inbound::handle_http;
+pub use inbound::BlockPage;
 pub use inbound::Listener;
@@ -0,0 +1,149 @@
+//! Brutal, the congestion control of Hysteria sending at a fixed rate
+//!
+//! The window is the bandwidth-delay product at the rate, inflated by the
+//! share of the packets lost in the last seconds so that the rate holds
+//! despite the losses, up to a point.
+
+use std::{
+    any::Any,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use quinn_proto::{
+    congestion::{Controller, ControllerFactory},
+    RttEstimator,
+};
+
+/// the seconds of the acks and the losses counted
+const SLOTS: usize = 5;
+/// the loss rate is assumed nil below this many packets
+const MIN_SAMPLES: u64 = 50;
+const MIN_ACK_RATE: f64 = 0.8;
+const WINDOW_MULTIPLIER: f64 = 2.0;
+/// the RTT assumed until measured
+const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+/// The rate is shared with the connection, which may lower it to what
+/// the server can receive once authenticated.
+pub struct BrutalConfig {
+    /// bytes per second
+    rate: Arc<AtomicU64>,
+}
+
+impl BrutalConfig {
+    pub fn new(rate: Arc<AtomicU64>) -> Self {
+        Self { rate }
+    }
+}
+
+impl ControllerFactory for BrutalConfig {
+    fn build(&self, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(Brutal {
+            rate: self.rate.clone(),
+            mtu: current_mtu,
+            rtt: INITIAL_RTT,
+            start: now,
+            slots: [Slot::default(); SLOTS],
+            ack_rate: 1.0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    /// since the start of the connection
+    second: u64,
+    acked: u64,
+    lost: u64,
+}
+
+#[derive(Clone)]
+struct Brutal {
+    rate: Arc<AtomicU64>,
+    mtu: u16,
+    rtt: Duration,
+    start: Instant,
+    slots: [Slot; SLOTS],
+    ack_rate: f64,
+}
+
+impl Brutal {
+    fn slot(&mut self, now: Instant) -> &mut Slot {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        let slot = &mut self.slots[second as usize % SLOTS];
+        if slot.second != second {
+            *slot = Slot {
+                second,
+                ..Default::default()
+            };
+        }
+        slot
+    }
+
+    fn update_ack_rate(&mut self, now: Instant) {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        let (acked, lost) = self
+            .slots
+            .iter()
+            .filter(|x| second.saturating_sub(x.second) < SLOTS as u64)
+            .fold((0, 0), |(acked, lost), x| (acked + x.acked, lost + x.lost));
+        self.ack_rate = if acked + lost < MIN_SAMPLES {
+            1.0
+        } else {
+            (acked as f64 / (acked + lost) as f64).max(MIN_ACK_RATE)
+        };
+    }
+}
+
+impl Controller for Brutal {
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        _sent: Instant,
+        _bytes: u64,
+        _app_limited: bool,
+        rtt: &RttEstimator,
+    ) {
+        self.rtt = rtt.get();
+        self.slot(now).acked += 1;
+        self.update_ack_rate(now);
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        _sent: Instant,
+        _is_persistent_congestion: bool,
+        lost_bytes: u64,
+    ) {
+        let packets = lost_bytes.div_ceil(self.mtu as u64).max(1);
+        self.slot(now).lost += packets;
+        self.update_ack_rate(now);
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.mtu = new_mtu;
+    }
+
+    fn window(&self) -> u64 {
+        let rate = self.rate.load(Ordering::Relaxed) as f64;
+        let window = rate * self.rtt.as_secs_f64() * WINDOW_MULTIPLIER / self.ack_rate;
+        (window as u64).max(self.mtu as u64)
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+
+    fn initial_window(&self) -> u64 {
+        self.window()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
@@ -0,0 +1,252 @@
+//! The messages of the Hysteria2 protocol after the authentication
+
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use quinn::RecvStream;
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{common::errors::new_io_error, proxy::masque::h3};
+
+/// the first varint of the TCP request streams
+const FRAME_TCP_REQUEST: u64 = 0x401;
+
+/// the longest message of a TCP response accepted
+const MAX_MESSAGE_LEN: u64 = 2048;
+/// the longest padding of a TCP response accepted
+const MAX_PADDING_LEN: u64 = 4096;
+
+/// Random alphanumeric padding, hiding the length of the messages.
+pub fn padding(min: usize, max: usize) -> String {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(min..max);
+    (&mut rng)
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// The request opening a TCP stream to `addr`, a `host:port`.
+pub fn tcp_request(addr: &str) -> BytesMut {
+    let padding = padding(64, 512);
+    let mut buf = BytesMut::with_capacity(addr.len() + padding.len() + 16);
+    h3::put_varint(&mut buf, FRAME_TCP_REQUEST);
+    h3::put_varint(&mut buf, addr.len() as u64);
+    buf.put_slice(addr.as_bytes());
+    h3::put_varint(&mut buf, padding.len() as u64);
+    buf.put_slice(padding.as_bytes());
+    buf
+}
+
+/// Read the response to a TCP request, an error if the server refused it.
+pub async fn read_tcp_response(s: &mut RecvStream) -> io::Result<()> {
+    let mut status = [0u8; 1];
+    h3::read_exact(s, &mut status).await?;
+
+    let len = h3::read_varint(s).await?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(new_io_error("hysteria2 response message too long"));
+    }
+    let mut message = vec![0u8; len as usize];
+    h3::read_exact(s, &mut message).await?;
+
+    let len = h3::read_varint(s).await?;
+    if len > MAX_PADDING_LEN {
+        return Err(new_io_error("hysteria2 response padding too long"));
+    }
+    h3::read_exact(s, &mut vec![0u8; len as usize]).await?;
+
+    if status[0] != 0 {
+        return Err(new_io_error(&format!(
+            "hysteria2 server refused the connection: {}",
+            String::from_utf8_lossy(&message)
+        )));
+    }
+    Ok(())
+}
+
+/// A UDP packet, or a fragment of one, sent as a QUIC datagram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpMessage {
+    pub session_id: u32,
+    pub packet_id: u16,
+    pub frag_id: u8,
+    pub frag_count: u8,
+    /// `host:port`
+    pub addr: String,
+    pub data: Bytes,
+}
+
+impl UdpMessage {
+    fn header_len(&self) -> usize {
+        let mut varint = BytesMut::new();
+        h3::put_varint(&mut varint, self.addr.len() as u64);
+        8 + varint.len() + self.addr.len()
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.header_len() + self.data.len()
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        buf.put_u32(self.session_id);
+        buf.put_u16(self.packet_id);
+        buf.put_u8(self.frag_id);
+        buf.put_u8(self.frag_count);
+        h3::put_varint(&mut buf, self.addr.len() as u64);
+        buf.put_slice(self.addr.as_bytes());
+        buf.put_slice(&self.data);
+        buf.freeze()
+    }
+
+    pub fn decode(mut buf: Bytes) -> Option<Self> {
+        if buf.remaining() < 8 {
+            return None;
+        }
+        let session_id = buf.get_u32();
+        let packet_id = buf.get_u16();
+        let frag_id = buf.get_u8();
+        let frag_count = buf.get_u8();
+        let len = h3::get_varint(&mut buf)? as usize;
+        if buf.remaining() < len {
+            return None;
+        }
+        let addr = String::from_utf8(buf.split_to(len).to_vec()).ok()?;
+        Some(Self {
+            session_id,
+            packet_id,
+            frag_id,
+            frag_count,
+            addr,
+            data: buf,
+        })
+    }
+
+    /// Split the packet in fragments of `max_len` at most, none if it
+    /// can't be.
+    pub fn fragment(self, max_len: usize) -> Option<Vec<UdpMessage>> {
+        if self.encoded_len() <= max_len {
+            return Some(vec![self]);
+        }
+        let chunk = max_len.checked_sub(self.header_len()).filter(|x| *x > 0)?;
+        let count = self.data.len().div_ceil(chunk);
+        if count > u8::MAX as usize {
+            return None;
+        }
+        Some(
+            self.data
+                .chunks(chunk)
+                .enumerate()
+                .map(|(i, x)| UdpMessage {
+                    session_id: self.session_id,
+                    packet_id: self.packet_id,
+                    frag_id: i as u8,
+                    frag_count: count as u8,
+                    addr: self.addr.clone(),
+                    data: self.data.slice_ref(x),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Reassembles the fragmented packets of a session, one at a time as the
+/// fragments of different packets aren't expected to interleave.
+#[derive(Default)]
+pub struct Defragger {
+    packet_id: u16,
+    frags: Vec<Option<UdpMessage>>,
+    received: usize,
+}
+
+impl Defragger {
+    /// The packet once all its fragments are received.
+    pub fn feed(&mut self, msg: UdpMessage) -> Option<UdpMessage> {
+        if msg.frag_count <= 1 {
+            return Some(msg);
+        }
+        if msg.frag_id >= msg.frag_count {
+            return None;
+        }
+
+        if msg.packet_id != self.packet_id || self.frags.len() != msg.frag_count as usize {
+            // a new packet, the one in progress is lost
+            self.packet_id = msg.packet_id;
+            self.frags = vec![None; msg.frag_count as usize];
+            self.received = 0;
+        }
+        let id = msg.frag_id as usize;
+        if self.frags[id].is_none() {
+            self.frags[id] = Some(msg);
+            self.received += 1;
+        }
+        if self.received < self.frags.len() {
+            return None;
+        }
+
+        let frags = std::mem::take(&mut self.frags);
+        self.received = 0;
+        let mut data = BytesMut::new();
+        for frag in frags.iter().flatten() {
+            data.put_slice(&frag.data);
+        }
+        let first = frags.into_iter().next().flatten()?;
+        Some(UdpMessage {
+            frag_id: 0,
+            frag_count: 1,
+            data: data.freeze(),
+            ..first
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, Bytes};
+
+    use super::{tcp_request, Defragger, UdpMessage};
+    use crate::proxy::masque::h3::get_varint;
+
+    #[test]
+    fn test_tcp_request() {
+        let req = tcp_request("example.com:443");
+        let mut buf = &req[..];
+        assert_eq!(get_varint(&mut buf), Some(0x401));
+        assert_eq!(get_varint(&mut buf), Some(15));
+        assert_eq!(&buf[..15], b"example.com:443");
+        buf.advance(15);
+        let padding = get_varint(&mut buf).unwrap() as usize;
+        assert!((64..512).contains(&padding));
+        assert_eq!(buf.len(), padding);
+    }
+
+    #[test]
+    fn test_udp_fragment() {
+        let msg = UdpMessage {
+            session_id: 7,
+            packet_id: 3,
+            frag_id: 0,
+            frag_count: 1,
+            addr: "1.1.1.1:53".to_owned(),
+            data: Bytes::from((0..=255u8).cycle().take(1000).collect::<Vec<_>>()),
+        };
+        assert_eq!(UdpMessage::decode(msg.encode()), Some(msg.clone()));
+
+        let frags = msg.clone().fragment(300).unwrap();
+        assert_eq!(frags.len(), 4);
+        assert!(frags
+            .iter()
+            .all(|x| x.encoded_len() <= 300 && x.frag_count == 4));
+
+        let mut defragger = Defragger::default();
+        let mut out = None;
+        for frag in frags.into_iter().rev() {
+            out = defragger.feed(UdpMessage::decode(frag.encode()).unwrap());
+        }
+        assert_eq!(out, Some(msg.clone()));
+
+        assert!(msg.fragment(19).is_none());
+    }
+}
@@ -0,0 +1,465 @@
+//! Hysteria2, TCP and UDP over QUIC
+//!
+//! The client authenticates with an HTTP/3 request, then every TCP
+//! connection is a QUIC stream and the UDP packets are QUIC datagrams,
+//! fragmented if need be. With an upload bandwidth configured, the
+//! packets are sent at that rate regardless of the losses (Brutal).
+
+mod brutal;
+mod codec;
+mod salamander;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{Sink, SinkExt, Stream};
+use quinn::{EndpointConfig, RecvStream, Runtime, SendStream, TokioRuntime};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, Mutex as AsyncMutex},
+};
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+            ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::{map_io_error, new_io_error},
+    session::{Session, SocksAddr},
+};
+
+use self::{
+    brutal::BrutalConfig,
+    codec::{Defragger, UdpMessage},
+    salamander::Salamander,
+};
+
+use super::{
+    datagram::UdpPacket, masque::h3, transport::quic, AnyOutboundHandler, ConnectorType,
+    OutboundHandler, OutboundType,
+};
+
+/// the status of a successful authentication
+const STATUS_AUTH_OK: u16 = 233;
+/// how long the connection and the authentication may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct HandlerOptions {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub password: String,
+    pub sni: String,
+    pub skip_cert_verify: bool,
+    pub alpn: Vec<Vec<u8>>,
+    /// bytes per second, the congestion control is left to QUIC if 0
+    pub up: u64,
+    /// bytes per second, the rate the server is told to send at, 0 to let
+    /// it decide
+    pub down: u64,
+    /// the salamander password, the packets aren't obfuscated without
+    pub obfs_password: Option<String>,
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+    /// built on the first connection
+    crypto: OnceLock<Arc<rustls::ClientConfig>>,
+    conn: AsyncMutex<Option<Arc<Hysteria2Connection>>>,
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
+        Arc::new(Self {
+            opts,
+            crypto: OnceLock::new(),
+            conn: AsyncMutex::new(None),
+        })
+    }
+
+    fn crypto(&self) -> Arc<rustls::ClientConfig> {
+        self.crypto
+            .get_or_init(|| {
+                Arc::new(quic::client_crypto(
+                    &self.opts.alpn,
+                    self.opts.skip_cert_verify,
+                ))
+            })
+            .clone()
+    }
+
+    /// The session dialing a new connection picks its interface.
+    async fn get_conn(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<Arc<Hysteria2Connection>> {
+        let mut guard = self.conn.lock().await;
+        if let Some(conn) = guard.as_ref() {
+            if conn.conn.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+        }
+
+        let conn = tokio::time::timeout(CONNECT_TIMEOUT, self.connect(sess, resolver))
+            .await
+            .map_err(|_| new_io_error("hysteria2 connection timed out"))??;
+        let conn = Arc::new(conn);
+        tokio::spawn(demux(conn.conn.clone(), Arc::downgrade(&conn)));
+        *guard = Some(conn.clone());
+        Ok(conn)
+    }
+
+    async fn connect(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<Hysteria2Connection> {
+        let ip = resolver
+            .resolve(&self.opts.server, false)
+            .await
+            .map_err(map_io_error)?
+            .ok_or_else(|| new_io_error(&format!("can't resolve {}", self.opts.server)))?;
+        let server = SocketAddr::new(ip, self.opts.port);
+        let socket = quic::bind_socket(Some(server), sess.iface.as_ref())?;
+        let runtime = Arc::new(TokioRuntime);
+        let endpoint = match &self.opts.obfs_password {
+            Some(password) => quinn::Endpoint::new_with_abstract_socket(
+                EndpointConfig::default(),
+                None,
+                Salamander::new(runtime.wrap_udp_socket(socket)?, password),
+                runtime,
+            )?,
+            None => quinn::Endpoint::new(EndpointConfig::default(), None, socket, runtime)?,
+        };
+
+        // lowered to what the server can receive once authenticated
+        let rate = Arc::new(AtomicU64::new(self.opts.up));
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        if self.opts.obfs_password.is_some() {
+            // the probes would be obfuscated into bigger packets
+            transport.mtu_discovery_config(None);
+        }
+        if self.opts.up > 0 {
+            transport.congestion_controller_factory(BrutalConfig::new(rate.clone()));
+        }
+        let mut client_config = quinn::ClientConfig::new(self.crypto());
+        client_config.transport_config(Arc::new(transport));
+
+        let conn = endpoint
+            .connect_with(client_config, server, &self.opts.sni)
+            .map_err(|e| new_io_error(&format!("failed to connect to {}: {}", server, e)))?
+            .await?;
+        let control = h3::open_control_stream(&conn).await?;
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let down = self.opts.down.to_string();
+        let padding = codec::padding(64, 512);
+        send.write_all(&h3::headers_frame(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "hysteria"),
+            (":path", "/auth"),
+            ("hysteria-auth", &self.opts.password),
+            ("hysteria-cc-rx", &down),
+            ("hysteria-padding", &padding),
+        ]))
+        .await?;
+        send.finish().await?;
+
+        let resp = h3::read_response(&mut recv).await?;
+        if resp.status != STATUS_AUTH_OK {
+            conn.close(0u32.into(), b"");
+            return Err(new_io_error(&format!(
+                "hysteria2 authentication failed: {}",
+                resp.status
+            )));
+        }
+        // `auto` if the server leaves the rate to the client
+        let server_rx = resp
+            .header("hysteria-cc-rx")
+            .and_then(|x| x.parse::<u64>().ok())
+            .unwrap_or(0);
+        if server_rx > 0 && server_rx < self.opts.up {
+            rate.store(server_rx, Ordering::Relaxed);
+        }
+        let udp = resp.header("hysteria-udp") != Some("false");
+        debug!(
+            "hysteria2 connection to {} established, udp: {}, server rx: {}",
+            server, udp, server_rx
+        );
+
+        Ok(Hysteria2Connection {
+            conn,
+            udp,
+            sessions: Default::default(),
+            next_session_id: AtomicU32::new(0),
+            _endpoint: endpoint,
+            _control: control,
+        })
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::Hysteria2
+    }
+
+    async fn support_udp(&self) -> bool {
+        true
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let conn = self.get_conn(sess, resolver).await?;
+        let (mut send, mut recv) = conn.conn.open_bi().await?;
+        send.write_all(&codec::tcp_request(&sess.destination.to_string()))
+            .await?;
+        codec::read_tcp_response(&mut recv).await?;
+
+        let s = ChainedStreamWrapper::new(Hysteria2Stream { send, recv });
+        s.append_to_chain(self.name()).await;
+        Ok(Box::new(s))
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let conn = self.get_conn(sess, resolver).await?;
+        if !conn.udp {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the hysteria2 server doesn't relay UDP",
+            ));
+        }
+        let d =
+            ChainedDatagramWrapper::new(OutboundDatagramHysteria2::new(conn, sess.source.into()));
+        d.append_to_chain(self.name()).await;
+        Ok(Box::new(d))
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::None
+    }
+}
+
+struct UdpSession {
+    client: SocksAddr,
+    tx: mpsc::Sender<UdpPacket>,
+    defragger: Defragger,
+}
+
+struct Hysteria2Connection {
+    conn: quinn::Connection,
+    /// whether the server relays UDP
+    udp: bool,
+    sessions: Mutex<HashMap<u32, UdpSession>>,
+    next_session_id: AtomicU32,
+    _endpoint: quinn::Endpoint,
+    _control: SendStream,
+}
+
+impl Drop for Hysteria2Connection {
+    fn drop(&mut self) {
+        // neither the handler nor any session uses it anymore
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+/// The address of a UDP message, a `host:port`.
+fn parse_addr(addr: &str) -> Option<SocksAddr> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Some(addr.into());
+    }
+    let (host, port) = addr.rsplit_once(':')?;
+    SocksAddr::try_from((host.to_owned(), port.parse().ok()?)).ok()
+}
+
+/// Hand the datagrams received to the sessions.
+async fn demux(conn: quinn::Connection, hy2: Weak<Hysteria2Connection>) {
+    while let Ok(datagram) = conn.read_datagram().await {
+        let Some(msg) = UdpMessage::decode(datagram) else {
+            continue;
+        };
+        let Some(hy2) = hy2.upgrade() else {
+            break;
+        };
+        let mut sessions = hy2.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(&msg.session_id) else {
+            continue;
+        };
+        let Some(msg) = session.defragger.feed(msg) else {
+            continue;
+        };
+        let Some(src_addr) = parse_addr(&msg.addr) else {
+            debug!("invalid hysteria2 udp address: {}", msg.addr);
+            continue;
+        };
+        let _ = session.tx.try_send(UdpPacket {
+            data: msg.data.to_vec(),
+            src_addr,
+            dst_addr: session.client.clone(),
+        });
+    }
+    debug!("hysteria2 connection closed");
+}
+
+#[derive(Debug)]
+struct Hysteria2Stream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for Hysteria2Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for Hysteria2Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
+
+struct OutboundDatagramHysteria2 {
+    send_tx: tokio_util::sync::PollSender<UdpPacket>,
+    recv_rx: mpsc::Receiver<UdpPacket>,
+}
+
+impl OutboundDatagramHysteria2 {
+    fn new(conn: Arc<Hysteria2Connection>, client: SocksAddr) -> Self {
+        let (send_tx, mut send_rx) = mpsc::channel::<UdpPacket>(32);
+        let (recv_tx, recv_rx) = mpsc::channel(32);
+
+        let session_id = conn.next_session_id.fetch_add(1, Ordering::Relaxed);
+        conn.sessions.lock().unwrap().insert(
+            session_id,
+            UdpSession {
+                client,
+                tx: recv_tx,
+                defragger: Defragger::default(),
+            },
+        );
+
+        tokio::spawn(async move {
+            let mut packet_id = 0u16;
+            while let Some(pkt) = send_rx.recv().await {
+                let Some(max_len) = conn.conn.max_datagram_size() else {
+                    debug!("hysteria2 server doesn't accept datagrams");
+                    break;
+                };
+                packet_id = packet_id.wrapping_add(1);
+                let msg = UdpMessage {
+                    session_id,
+                    packet_id,
+                    frag_id: 0,
+                    frag_count: 1,
+                    addr: pkt.dst_addr.to_string(),
+                    data: pkt.data.into(),
+                };
+                let Some(frags) = msg.fragment(max_len) else {
+                    debug!("udp packet to {} too large for hysteria2", pkt.dst_addr);
+                    continue;
+                };
+                for frag in frags {
+                    if let Err(e) = conn.conn.send_datagram(frag.encode()) {
+                        debug!("failed to send hysteria2 datagram: {}", e);
+                        break;
+                    }
+                }
+                if conn.conn.close_reason().is_some() {
+                    break;
+                }
+            }
+
+            // the server forgets the session once idle
+            conn.sessions.lock().unwrap().remove(&session_id);
+        });
+
+        Self {
+            send_tx: tokio_util::sync::PollSender::new(send_tx),
+            recv_rx,
+        }
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramHysteria2 {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_ready_unpin(cx)
+            .map_err(|_| new_io_error("hysteria2 session closed"))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.send_tx
+            .start_send_unpin(item)
+            .map_err(|_| new_io_error("hysteria2 session closed"))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_flush_unpin(cx)
+            .map_err(|_| new_io_error("hysteria2 session closed"))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_close_unpin(cx)
+            .map_err(|_| new_io_error("hysteria2 session closed"))
+    }
+}
+
+impl Stream for OutboundDatagramHysteria2 {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv_rx.poll_recv(cx)
+    }
+}
@@ -0,0 +1,163 @@
+//! The salamander obfuscation of the QUIC packets
+//!
+//! Every packet is prefixed with a random salt and XORed with the
+//! BLAKE2b-256 of the password and the salt, so that it looks random and
+//! can't be told apart as QUIC.
+
+use std::{
+    fmt::Debug,
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    task::{ready, Context, Poll},
+};
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use quinn::AsyncUdpSocket;
+use quinn_udp::{RecvMeta, Transmit, UdpState};
+
+const SALT_LEN: usize = 8;
+
+pub struct Salamander {
+    inner: Box<dyn AsyncUdpSocket>,
+    password: Vec<u8>,
+}
+
+impl Debug for Salamander {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Salamander")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl Salamander {
+    pub fn new(inner: Box<dyn AsyncUdpSocket>, password: &str) -> Self {
+        Self {
+            inner,
+            password: password.as_bytes().to_vec(),
+        }
+    }
+
+    fn xor(&self, salt: &[u8], data: &mut [u8]) {
+        let key = Blake2b::<U32>::new()
+            .chain_update(&self.password)
+            .chain_update(salt)
+            .finalize();
+        for (b, k) in data.iter_mut().zip(key.iter().cycle()) {
+            *b ^= k;
+        }
+    }
+
+    /// Append the obfuscated `packet` to `out`.
+    fn obfuscate(&self, packet: &[u8], out: &mut Vec<u8>) {
+        let salt: [u8; SALT_LEN] = rand::random();
+        out.extend_from_slice(&salt);
+        let start = out.len();
+        out.extend_from_slice(packet);
+        self.xor(&salt, &mut out[start..]);
+    }
+
+    /// Deobfuscate the packet in place, moved to the start of `buf`, and
+    /// return its length, none if it's too short.
+    fn deobfuscate(&self, buf: &mut [u8]) -> Option<usize> {
+        if buf.len() < SALT_LEN {
+            return None;
+        }
+        let (salt, data) = buf.split_at_mut(SALT_LEN);
+        self.xor(salt, data);
+        buf.copy_within(SALT_LEN.., 0);
+        Some(buf.len() - SALT_LEN)
+    }
+}
+
+impl AsyncUdpSocket for Salamander {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        // redone if the socket isn't ready, which is rare enough
+        let transmits = transmits
+            .iter()
+            .map(|t| {
+                let segment = t.segment_size.unwrap_or(t.contents.len()).max(1);
+                let mut contents = Vec::with_capacity(
+                    t.contents.len() + t.contents.len().div_ceil(segment) * SALT_LEN,
+                );
+                for packet in t.contents.chunks(segment) {
+                    self.obfuscate(packet, &mut contents);
+                }
+                Transmit {
+                    destination: t.destination,
+                    ecn: t.ecn,
+                    contents: contents.into(),
+                    segment_size: t.segment_size.map(|x| x + SALT_LEN),
+                    src_ip: t.src_ip,
+                }
+            })
+            .collect::<Vec<_>>();
+        self.inner.poll_send(state, cx, &transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(self.inner.poll_recv(cx, bufs, meta))?;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()).take(n) {
+            // the packets coalesced by GRO are deobfuscated one by one
+            let stride = meta.stride.max(1);
+            let mut len = 0;
+            let mut offset = 0;
+            while offset < meta.len {
+                let end = (offset + stride).min(meta.len);
+                if let Some(n) = self.deobfuscate(&mut buf[offset..end]) {
+                    buf.copy_within(offset..offset + n, len);
+                    len += n;
+                }
+                offset = end;
+            }
+            meta.len = len;
+            meta.stride = stride.saturating_sub(SALT_LEN).max(1);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Salamander;
+
+    #[test]
+    fn test_salamander() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+        let socket = quinn::Runtime::wrap_udp_socket(&quinn::TokioRuntime, socket).unwrap();
+        let s = Salamander::new(socket, "cry me a river");
+
+        let packet = b"a quic packet".to_vec();
+        let mut buf = vec![];
+        s.obfuscate(&packet, &mut buf);
+        assert_eq!(buf.len(), packet.len() + 8);
+        assert_ne!(&buf[8..], packet.as_slice());
+
+        assert_eq!(s.deobfuscate(&mut buf), Some(packet.len()));
+        assert_eq!(&buf[..packet.len()], packet.as_slice());
+        assert_eq!(s.deobfuscate(&mut [0u8; 7]), None);
+    }
+}
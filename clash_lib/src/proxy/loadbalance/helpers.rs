@@ -1,20 +1,23 @@
-use std::io::Cursor;
+use std::{io::Cursor, time::Duration};
 
-use futures::future::BoxFuture;
 use murmur3::murmur3_32;
 use public_suffix::{EffectiveTLDProvider, DEFAULT_PROVIDER};
 
-use crate::{proxy::AnyOutboundHandler, session::Session};
+use crate::{common::errors::new_io_error, proxy::AnyOutboundHandler, session::Session};
 
+/// Picks a proxy of the group, given whether each of them is alive.
 pub type StrategyFn = Box<
-    dyn FnMut(
-            Vec<AnyOutboundHandler>,
-            &Session,
-        ) -> BoxFuture<'static, std::io::Result<AnyOutboundHandler>>
+    dyn FnMut(&[AnyOutboundHandler], &[bool], &Session) -> std::io::Result<AnyOutboundHandler>
         + Send
         + Sync,
 >;
 
+/// how many other buckets are tried when the hashed proxy is down
+const MAX_RETRY: u64 = 5;
+/// how long a source stays on the proxy it was given
+const STICKY_TTL: Duration = Duration::from_secs(10 * 60);
+const STICKY_CAPACITY: usize = 1000;
+
 fn get_key(sess: &Session) -> String {
     match &sess.destination {
         crate::session::SocksAddr::Ip(addr) => addr.ip().to_string(),
@@ -25,6 +28,10 @@ fn get_key(sess: &Session) -> String {
     }
 }
 
+fn hash(key: &str) -> u64 {
+    murmur3_32(&mut Cursor::new(key), 0).unwrap() as u64
+}
+
 fn jump_hash(key: u64, buckets: i32) -> i32 {
     let mut key = key;
     let mut b = -1i64;
@@ -37,29 +44,144 @@ fn jump_hash(key: u64, buckets: i32) -> i32 {
     b as i32
 }
 
+/// The bucket of the key, or of the next keys if its proxy is down, so the
+/// other keys stay where they are.
+fn pick_hashed(key: u64, alive: &[bool]) -> usize {
+    let buckets = alive.len() as i32;
+    (0..MAX_RETRY)
+        .map(|i| jump_hash(key.wrapping_add(i), buckets) as usize)
+        .find(|&index| alive[index])
+        .unwrap_or_else(|| jump_hash(key, buckets) as usize)
+}
+
+fn no_proxy() -> std::io::Error {
+    new_io_error("no proxy found")
+}
+
 pub fn strategy_rr() -> StrategyFn {
     let mut index = 0;
-    Box::new(move |proxies: Vec<AnyOutboundHandler>, _: &Session| {
+    Box::new(move |proxies, alive, _| {
         let len = proxies.len();
-        index = (index + 1) % len;
-        Box::pin(futures::future::ok(proxies[index].clone()))
+        if len == 0 {
+            return Err(no_proxy());
+        }
+        index = (1..=len)
+            .map(|i| (index + i) % len)
+            .find(|&i| alive[i])
+            .unwrap_or((index + 1) % len);
+        Ok(proxies[index].clone())
     })
 }
 
 pub fn strategy_consistent_hashring() -> StrategyFn {
-    let max_retry = 5;
-    Box::new(move |proxies, sess| {
-        let key = murmur3_32(&mut Cursor::new(get_key(sess)), 0).unwrap() as u64;
-        let buckets = proxies.len() as i32;
-        for _ in 0..max_retry {
-            let index = jump_hash(key, buckets);
-            if let Some(proxy) = proxies.get(index as usize) {
-                return Box::pin(futures::future::ok(proxy.clone()));
+    Box::new(move |proxies, alive, sess| {
+        if proxies.is_empty() {
+            return Err(no_proxy());
+        }
+        let index = pick_hashed(hash(&get_key(sess)), alive);
+        Ok(proxies[index].clone())
+    })
+}
+
+/// The connections of a source to a destination go through the same proxy
+/// as long as it's alive, and through the hashed one the first time.
+pub fn strategy_sticky_sessions() -> StrategyFn {
+    let mut cache = lru_time_cache::LruCache::<u64, usize>::with_expiry_duration_and_capacity(
+        STICKY_TTL,
+        STICKY_CAPACITY,
+    );
+    Box::new(move |proxies, alive, sess| {
+        if proxies.is_empty() {
+            return Err(no_proxy());
+        }
+        let key = hash(&format!("{}{}", sess.source.ip(), get_key(sess)));
+        if let Some(&index) = cache.get(&key) {
+            if index < proxies.len() && alive[index] {
+                return Ok(proxies[index].clone());
             }
         }
-        Box::pin(futures::future::err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no proxy found",
-        )))
+        let index = pick_hashed(key, alive);
+        cache.insert(key, index);
+        Ok(proxies[index].clone())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        proxy::{mocks::MockDummyOutboundHandler, AnyOutboundHandler},
+        session::{Session, SocksAddr},
+    };
+
+    use super::{strategy_consistent_hashring, strategy_rr, strategy_sticky_sessions};
+
+    fn proxies(n: usize) -> Vec<AnyOutboundHandler> {
+        (0..n)
+            .map(|_| Arc::new(MockDummyOutboundHandler::new()) as AnyOutboundHandler)
+            .collect()
+    }
+
+    fn position(proxies: &[AnyOutboundHandler], proxy: &AnyOutboundHandler) -> usize {
+        proxies.iter().position(|x| Arc::ptr_eq(x, proxy)).unwrap()
+    }
+
+    fn sess(src: &str, dst: &str) -> Session {
+        Session {
+            source: src.parse().unwrap(),
+            destination: SocksAddr::Domain(dst.to_owned(), 443),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rr_skips_dead() {
+        let proxies = proxies(3);
+        let alive = [true, false, true];
+        let mut rr = strategy_rr();
+        let s = sess("10.0.0.1:1000", "example.com");
+
+        let picked = (0..4)
+            .map(|_| position(&proxies, &rr(&proxies, &alive, &s).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![2, 0, 2, 0]);
+
+        assert!(rr(&[], &[], &s).is_err());
+    }
+
+    #[test]
+    fn test_consistent_hashing() {
+        let proxies = proxies(4);
+        let mut hashing = strategy_consistent_hashring();
+        let s = sess("10.0.0.1:1000", "www.example.com");
+
+        let first = position(&proxies, &hashing(&proxies, &[true; 4], &s).unwrap());
+        // the same eTLD+1 from another source
+        let other = sess("10.0.0.2:1000", "api.example.com");
+        let second = position(&proxies, &hashing(&proxies, &[true; 4], &other).unwrap());
+        assert_eq!(first, second);
+
+        let mut alive = [true; 4];
+        alive[first] = false;
+        let moved = position(&proxies, &hashing(&proxies, &alive, &s).unwrap());
+        assert_ne!(moved, first);
+    }
+
+    #[test]
+    fn test_sticky_sessions() {
+        let proxies = proxies(4);
+        let mut sticky = strategy_sticky_sessions();
+        let s = sess("10.0.0.1:1000", "example.com");
+
+        let first = position(&proxies, &sticky(&proxies, &[true; 4], &s).unwrap());
+        let mut alive = [true; 4];
+        alive[first] = false;
+        let moved = position(&proxies, &sticky(&proxies, &alive, &s).unwrap());
+        assert_ne!(moved, first);
+
+        // stays on the new one once the first is back
+        let again = position(&proxies, &sticky(&proxies, &[true; 4], &s).unwrap());
+        assert_eq!(again, moved);
+    }
+}
@@ -10,13 +10,18 @@ use crate::{
     app::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
-        remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
+        remote_content_manager::{
+            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager,
+        },
     },
+    common::errors::member_dial_error,
     config::internal::proxy::LoadBalanceStrategy,
     session::Session,
 };
 
-use self::helpers::{strategy_consistent_hashring, strategy_rr, StrategyFn};
+use self::helpers::{
+    strategy_consistent_hashring, strategy_rr, strategy_sticky_sessions, StrategyFn,
+};
 
 use super::{
     utils::{provider_helper::get_proxies_from_providers, RemoteConnector},
@@ -40,20 +45,27 @@ pub struct Handler {
     opts: HandlerOptions,
 
     providers: Vec<ThreadSafeProxyProvider>,
+    proxy_manager: ProxyManager,
 
     inner: Arc<Mutex<HandlerInner>>,
 }
 
 impl Handler {
-    pub fn new(opts: HandlerOptions, providers: Vec<ThreadSafeProxyProvider>) -> Self {
+    pub fn new(
+        opts: HandlerOptions,
+        providers: Vec<ThreadSafeProxyProvider>,
+        proxy_manager: ProxyManager,
+    ) -> Self {
         let strategy_fn = match opts.strategy {
             LoadBalanceStrategy::ConsistentHashing => strategy_consistent_hashring(),
             LoadBalanceStrategy::RoundRobin => strategy_rr(),
+            LoadBalanceStrategy::StickySessions => strategy_sticky_sessions(),
         };
 
         Self {
             opts,
             providers,
+            proxy_manager,
             inner: Arc::new(Mutex::new(HandlerInner { strategy_fn })),
         }
     }
@@ -61,6 +73,22 @@ impl Handler {
     async fn get_proxies(&self, touch: bool) -> Vec<AnyOutboundHandler> {
         get_proxies_from_providers(&self.providers, touch).await
     }
+
+    /// Pick a proxy with the strategy, among the alive ones if any.
+    async fn pick_proxy(&self, sess: &Session) -> io::Result<AnyOutboundHandler> {
+        let proxies = self.get_proxies(true).await;
+        let mut alive = Vec::with_capacity(proxies.len());
+        for proxy in proxies.iter() {
+            alive.push(self.proxy_manager.alive(proxy.name()).await);
+        }
+        if !alive.contains(&true) {
+            alive.fill(true);
+        }
+
+        let proxy = (self.inner.lock().await.strategy_fn)(&proxies, &alive, sess)?;
+        debug!("{} use proxy {}", self.name(), proxy.name());
+        Ok(proxy)
+    }
 }
 
 #[async_trait::async_trait]
@@ -87,10 +115,12 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
-        match proxy.connect_stream(sess, resolver).await {
+        let proxy = self.pick_proxy(sess).await?;
+        match proxy
+            .connect_stream(sess, resolver)
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e))
+        {
             Ok(s) => {
                 s.append_to_chain(self.name()).await;
                 Ok(s)
@@ -105,10 +135,11 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
-        proxy.connect_datagram(sess, resolver).await
+        let proxy = self.pick_proxy(sess).await?;
+        proxy
+            .connect_datagram(sess, resolver)
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e))
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -121,9 +152,7 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
+        let proxy = self.pick_proxy(sess).await?;
         proxy
             .connect_stream_with_connector(sess, resolver, connector)
             .await
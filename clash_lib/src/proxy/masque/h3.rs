@@ -0,0 +1,307 @@
+//! Just enough HTTP/3 for CONNECT-UDP and the Hysteria2 authentication
+//!
+//! The requests are QPACK encoded with literal names only, so no dynamic
+//! table is ever needed. Of the responses, the `:status` is read, which
+//! servers send as a static table entry, and the fields with a literal
+//! name, as long as they are not huffman coded.
+
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use quinn::{RecvStream, SendStream};
+
+use crate::common::errors::new_io_error;
+
+const STREAM_TYPE_CONTROL: u64 = 0x00;
+
+const FRAME_DATA: u64 = 0x00;
+const FRAME_HEADERS: u64 = 0x01;
+const FRAME_SETTINGS: u64 = 0x04;
+
+/// RFC 9297
+const SETTINGS_H3_DATAGRAM: u64 = 0x33;
+
+/// a response HEADERS frame larger than this is refused
+const MAX_HEADERS_LEN: u64 = 16384;
+
+/// The QUIC variable-length integer.
+pub fn put_varint(buf: &mut impl BufMut, v: u64) {
+    if v < 1 << 6 {
+        buf.put_u8(v as u8);
+    } else if v < 1 << 14 {
+        buf.put_u16(0x4000 | v as u16);
+    } else if v < 1 << 30 {
+        buf.put_u32(0x8000_0000 | v as u32);
+    } else {
+        buf.put_u64(0xc000_0000_0000_0000 | v);
+    }
+}
+
+pub fn get_varint(buf: &mut impl Buf) -> Option<u64> {
+    if !buf.has_remaining() {
+        return None;
+    }
+    let len = 1 << (buf.chunk()[0] >> 6);
+    if buf.remaining() < len {
+        return None;
+    }
+    let v = match len {
+        1 => buf.get_u8() as u64,
+        2 => (buf.get_u16() & 0x3fff) as u64,
+        4 => (buf.get_u32() & 0x3fff_ffff) as u64,
+        _ => buf.get_u64() & 0x3fff_ffff_ffff_ffff,
+    };
+    Some(v)
+}
+
+pub async fn read_varint(s: &mut RecvStream) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    read_exact(s, &mut buf[..1]).await?;
+    let len = 1 << (buf[0] >> 6);
+    read_exact(s, &mut buf[1..len]).await?;
+    get_varint(&mut &buf[..len]).ok_or_else(|| new_io_error("invalid varint"))
+}
+
+pub async fn read_exact(s: &mut RecvStream, buf: &mut [u8]) -> io::Result<()> {
+    s.read_exact(buf)
+        .await
+        .map_err(|e| new_io_error(&format!("failed to read the http/3 stream: {}", e)))
+}
+
+/// The HPACK/QPACK prefixed integer, `flags` are the bits above the prefix.
+fn put_prefixed_int(buf: &mut BytesMut, flags: u8, prefix: u8, v: usize) {
+    let max = (1usize << prefix) - 1;
+    if v < max {
+        buf.put_u8(flags | v as u8);
+        return;
+    }
+    buf.put_u8(flags | max as u8);
+    let mut v = v - max;
+    while v >= 0x80 {
+        buf.put_u8(0x80 | (v & 0x7f) as u8);
+        v >>= 7;
+    }
+    buf.put_u8(v as u8);
+}
+
+fn get_prefixed_int(buf: &mut &[u8], prefix: u8) -> Option<usize> {
+    let max = (1usize << prefix) - 1;
+    let first = *buf.first()? as usize & max;
+    buf.advance(1);
+    if first < max {
+        return Some(first);
+    }
+    let mut v = max;
+    for shift in (0..28).step_by(7) {
+        let b = *buf.first()?;
+        buf.advance(1);
+        v += ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+/// Open the control stream, which has to stay open for the connection.
+pub async fn open_control_stream(conn: &quinn::Connection) -> io::Result<SendStream> {
+    let mut settings = BytesMut::new();
+    put_varint(&mut settings, SETTINGS_H3_DATAGRAM);
+    put_varint(&mut settings, 1);
+
+    let mut buf = BytesMut::new();
+    put_varint(&mut buf, STREAM_TYPE_CONTROL);
+    put_varint(&mut buf, FRAME_SETTINGS);
+    put_varint(&mut buf, settings.len() as u64);
+    buf.put_slice(&settings);
+
+    let mut s = conn.open_uni().await?;
+    s.write_all(&buf).await?;
+    Ok(s)
+}
+
+/// A HEADERS frame of the given fields.
+pub fn headers_frame(fields: &[(&str, &str)]) -> BytesMut {
+    // required insert count and base, both 0 without the dynamic table
+    let mut block = BytesMut::from(&[0u8, 0][..]);
+    for (name, value) in fields {
+        // literal field line with literal name, not huffman coded
+        put_prefixed_int(&mut block, 0x20, 3, name.len());
+        block.put_slice(name.as_bytes());
+        put_prefixed_int(&mut block, 0x00, 7, value.len());
+        block.put_slice(value.as_bytes());
+    }
+
+    let mut buf = BytesMut::new();
+    put_varint(&mut buf, FRAME_HEADERS);
+    put_varint(&mut buf, block.len() as u64);
+    buf.put_slice(&block);
+    buf
+}
+
+/// The fields of a response read.
+pub struct Response {
+    pub status: u16,
+    /// the fields with a literal name, the names are lowercase
+    pub headers: Vec<(String, String)>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Read the response HEADERS.
+pub async fn read_response(s: &mut RecvStream) -> io::Result<Response> {
+    loop {
+        let typ = read_varint(s).await?;
+        let len = read_varint(s).await?;
+        if len > MAX_HEADERS_LEN {
+            return Err(new_io_error("http/3 frame too large"));
+        }
+        let mut payload = vec![0u8; len as usize];
+        read_exact(s, &mut payload).await?;
+
+        match typ {
+            FRAME_HEADERS => {
+                return parse_response(&payload)
+                    .ok_or_else(|| new_io_error("unsupported http/3 response headers"))
+            }
+            FRAME_DATA => return Err(new_io_error("http/3 DATA before HEADERS")),
+            // reserved and unknown frames are ignored
+            _ => continue,
+        }
+    }
+}
+
+/// The `:status` of a static table index.
+fn static_status(index: usize) -> Option<u16> {
+    match index {
+        24 => Some(103),
+        25 => Some(200),
+        26 => Some(304),
+        27 => Some(404),
+        28 => Some(503),
+        63 => Some(100),
+        64 => Some(204),
+        65 => Some(206),
+        66 => Some(302),
+        67 => Some(400),
+        68 => Some(403),
+        69 => Some(421),
+        70 => Some(425),
+        71 => Some(500),
+        _ => None,
+    }
+}
+
+/// A string literal, none inside if it's huffman coded.
+fn get_string(buf: &mut &[u8], prefix: u8) -> Option<Option<String>> {
+    let huffman = *buf.first()? & (1 << prefix) != 0;
+    let len = get_prefixed_int(buf, prefix)?;
+    let s = buf.get(..len)?;
+    let s = (!huffman)
+        .then(|| std::str::from_utf8(s).ok().map(str::to_owned))
+        .flatten();
+    buf.advance(len);
+    Some(s)
+}
+
+/// The fields of a QPACK field section.
+fn parse_response(mut block: &[u8]) -> Option<Response> {
+    get_prefixed_int(&mut block, 8)?;
+    get_prefixed_int(&mut block, 7)?;
+
+    let mut status = None;
+    let mut headers = vec![];
+    while let Some(&first) = block.first() {
+        if first & 0xc0 == 0xc0 {
+            // indexed field line, static table
+            let index = get_prefixed_int(&mut block, 6)?;
+            status = static_status(index).or(status);
+        } else if first & 0xd0 == 0x50 {
+            // literal field line with a static name reference
+            let index = get_prefixed_int(&mut block, 4)?;
+            let value = get_string(&mut block, 7)?;
+            if static_status(index).is_some() {
+                status = Some(value?.parse().ok()?);
+            }
+        } else if first & 0xe0 == 0x20 {
+            // literal field line with a literal name
+            let name = get_string(&mut block, 3)?;
+            let value = get_string(&mut block, 7)?;
+            match (name, value) {
+                (Some(name), Some(value)) if name == ":status" => {
+                    status = Some(value.parse().ok()?)
+                }
+                (Some(name), Some(value)) => headers.push((name.to_ascii_lowercase(), value)),
+                _ => {}
+            }
+        } else {
+            // a reference to the dynamic table, which is never allowed
+            return None;
+        }
+    }
+    Some(Response {
+        status: status?,
+        headers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{get_prefixed_int, get_varint, headers_frame, parse_response, put_varint};
+
+    #[test]
+    fn test_varint() {
+        for v in [0, 63, 64, 16383, 16384, 1 << 30, (1 << 62) - 1] {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, v);
+            assert_eq!(get_varint(&mut &buf[..]), Some(v));
+        }
+        // RFC 9000 A.1
+        assert_eq!(get_varint(&mut &[0x7b, 0xbd][..]), Some(15293));
+    }
+
+    #[test]
+    fn test_headers_frame() {
+        let frame = headers_frame(&[(":method", "CONNECT")]);
+        assert_eq!(
+            &frame[..],
+            b"\x01\x13\x00\x00\x27\x00:method\x07CONNECT".as_slice()
+        );
+        let mut rest = &frame[4..];
+        assert_eq!(get_prefixed_int(&mut rest, 3), Some(7));
+    }
+
+    fn parse_status(block: &[u8]) -> Option<u16> {
+        parse_response(block).map(|x| x.status)
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(&[0, 0, 0xd9]), Some(200));
+        assert_eq!(parse_status(&[0, 0, 0xff, 0x05]), Some(403));
+        assert_eq!(parse_status(b"\x00\x00\x5f\x09\x03407"), Some(407));
+        assert_eq!(parse_status(&[0, 0, 0xd1]), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let frame = headers_frame(&[(":status", "233"), ("Hysteria-UDP", "true")]);
+        let resp = parse_response(&frame[2..]).unwrap();
+        assert_eq!(resp.status, 233);
+        assert_eq!(resp.header("hysteria-udp"), Some("true"));
+
+        // huffman coded values are skipped
+        let resp = parse_response(b"\x00\x00\xd9\x23abc\x83xyz").unwrap();
+        assert_eq!(resp.status, 200);
+        assert!(resp.headers.is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+//! MASQUE, proxying UDP in HTTP/3 (RFC 9298)
+//!
+//! Every destination of a UDP session is a CONNECT-UDP request on a QUIC
+//! connection shared by the sessions, and the packets go as HTTP datagrams
+//! (RFC 9297) of the request. TCP is not proxied.
+
+pub(crate) mod h3;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use futures::{Sink, SinkExt, Stream};
+use quinn::{EndpointConfig, RecvStream, SendStream, TokioRuntime, VarInt};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::{map_io_error, new_io_error},
+    session::{Session, SocksAddr},
+};
+
+use super::{
+    datagram::UdpPacket, transport::quic, AnyOutboundHandler, ConnectorType, OutboundHandler,
+    OutboundType,
+};
+
+/// how long a CONNECT-UDP request waits for the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// the HTTP datagrams with this context ID carry UDP payloads
+const CONTEXT_UDP: u64 = 0;
+
+pub struct HandlerOptions {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub sni: String,
+    pub skip_cert_verify: bool,
+    /// the value of the `authorization` header of the requests
+    pub authorization: Option<String>,
+    /// the interval of the QUIC pings while the connection is idle
+    pub keep_alive_interval: Duration,
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+    /// built on the first connection
+    client_config: OnceLock<quinn::ClientConfig>,
+    conn: AsyncMutex<Option<Arc<MasqueConnection>>>,
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
+        Arc::new(Self {
+            opts,
+            client_config: OnceLock::new(),
+            conn: AsyncMutex::new(None),
+        })
+    }
+
+    fn client_config(&self) -> quinn::ClientConfig {
+        self.client_config
+            .get_or_init(|| {
+                let crypto = quic::client_crypto(&[b"h3".to_vec()], self.opts.skip_cert_verify);
+                let mut transport = quinn::TransportConfig::default();
+                transport.keep_alive_interval(Some(self.opts.keep_alive_interval));
+                let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+                client_config.transport_config(Arc::new(transport));
+                client_config
+            })
+            .clone()
+    }
+
+    /// The session dialing a new connection picks its interface.
+    async fn get_conn(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<Arc<MasqueConnection>> {
+        let mut guard = self.conn.lock().await;
+        if let Some(conn) = guard.as_ref() {
+            if conn.conn.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+        }
+
+        let ip = resolver
+            .resolve(&self.opts.server, false)
+            .await
+            .map_err(map_io_error)?
+            .ok_or_else(|| new_io_error(&format!("can't resolve {}", self.opts.server)))?;
+        let server = SocketAddr::new(ip, self.opts.port);
+        let endpoint = quinn::Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            quic::bind_socket(Some(server), sess.iface.as_ref())?,
+            Arc::new(TokioRuntime),
+        )?;
+        let conn = endpoint
+            .connect_with(self.client_config(), server, &self.opts.sni)
+            .map_err(|e| new_io_error(&format!("failed to connect to {}: {}", server, e)))?
+            .await?;
+        let control = h3::open_control_stream(&conn).await?;
+        debug!("masque connection to {} established", server);
+
+        let conn = Arc::new(MasqueConnection {
+            conn,
+            flows: Default::default(),
+            _endpoint: endpoint,
+            _control: control,
+        });
+        tokio::spawn(demux(conn.conn.clone(), Arc::downgrade(&conn)));
+        *guard = Some(conn.clone());
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::Masque
+    }
+
+    async fn support_udp(&self) -> bool {
+        true
+    }
+
+    async fn connect_stream(
+        &self,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "masque only proxies UDP",
+        ))
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let conn = self.get_conn(sess, resolver).await?;
+        let d = ChainedDatagramWrapper::new(OutboundDatagramMasque::new(
+            conn,
+            sess.source.into(),
+            format!("{}:{}", self.opts.sni, self.opts.port),
+            self.opts.authorization.clone(),
+        ));
+        d.append_to_chain(self.name()).await;
+        Ok(Box::new(d))
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::None
+    }
+}
+
+/// A CONNECT-UDP request in progress.
+struct Flow {
+    target: SocksAddr,
+    client: SocksAddr,
+    tx: mpsc::Sender<UdpPacket>,
+}
+
+struct MasqueConnection {
+    conn: quinn::Connection,
+    /// keyed by the quarter stream ID of the request
+    flows: Mutex<HashMap<u64, Flow>>,
+    _endpoint: quinn::Endpoint,
+    _control: SendStream,
+}
+
+impl Drop for MasqueConnection {
+    fn drop(&mut self) {
+        // neither the handler nor any session uses it anymore
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+/// Hand the datagrams received to the sessions.
+async fn demux(conn: quinn::Connection, masque: Weak<MasqueConnection>) {
+    while let Ok(datagram) = conn.read_datagram().await {
+        let mut buf = &datagram[..];
+        let (Some(qsid), Some(CONTEXT_UDP)) = (h3::get_varint(&mut buf), h3::get_varint(&mut buf))
+        else {
+            continue;
+        };
+        let Some(masque) = masque.upgrade() else {
+            break;
+        };
+        let flows = masque.flows.lock().unwrap();
+        if let Some(flow) = flows.get(&qsid) {
+            let _ = flow.tx.try_send(UdpPacket {
+                data: buf.to_vec(),
+                src_addr: flow.target.clone(),
+                dst_addr: flow.client.clone(),
+            });
+        }
+    }
+    debug!("masque connection closed");
+}
+
+impl MasqueConnection {
+    /// Send a CONNECT-UDP request to `target`, the streams of the request
+    /// have to be kept open for the flow.
+    async fn open_flow(
+        &self,
+        flow: Flow,
+        authority: &str,
+        authorization: Option<&str>,
+    ) -> io::Result<(u64, SendStream, RecvStream)> {
+        let (mut send, mut recv) = self.conn.open_bi().await?;
+
+        // RFC 9298 default template, IPv6 colons percent-encoded
+        let path = format!(
+            "/.well-known/masque/udp/{}/{}/",
+            flow.target.host().replace(':', "%3A"),
+            flow.target.port()
+        );
+        let mut fields = vec![
+            (":method", "CONNECT"),
+            (":protocol", "connect-udp"),
+            (":scheme", "https"),
+            (":authority", authority),
+            (":path", path.as_str()),
+            ("capsule-protocol", "?1"),
+        ];
+        if let Some(authorization) = authorization {
+            fields.push(("authorization", authorization));
+        }
+        send.write_all(&h3::headers_frame(&fields)).await?;
+
+        let status = h3::read_response(&mut recv).await?.status;
+        if !(200..300).contains(&status) {
+            return Err(new_io_error(&format!(
+                "masque server refused to proxy to {}: {}",
+                flow.target, status
+            )));
+        }
+
+        let qsid = VarInt::from(send.id()).into_inner() / 4;
+        self.flows.lock().unwrap().insert(qsid, flow);
+        Ok((qsid, send, recv))
+    }
+}
+
+struct OutboundDatagramMasque {
+    send_tx: tokio_util::sync::PollSender<UdpPacket>,
+    recv_rx: mpsc::Receiver<UdpPacket>,
+}
+
+impl OutboundDatagramMasque {
+    fn new(
+        conn: Arc<MasqueConnection>,
+        client: SocksAddr,
+        authority: String,
+        authorization: Option<String>,
+    ) -> Self {
+        let (send_tx, mut send_rx) = mpsc::channel::<UdpPacket>(32);
+        let (recv_tx, recv_rx) = mpsc::channel(32);
+
+        tokio::spawn(async move {
+            // the requests of the session, keyed by the destination
+            let mut requests = HashMap::<String, (u64, SendStream, RecvStream)>::new();
+
+            while let Some(pkt) = send_rx.recv().await {
+                let key = pkt.dst_addr.to_string();
+                let qsid = match requests.get(&key) {
+                    Some((qsid, ..)) => *qsid,
+                    None => {
+                        let flow = Flow {
+                            target: pkt.dst_addr.clone(),
+                            client: client.clone(),
+                            tx: recv_tx.clone(),
+                        };
+                        let open = conn.open_flow(flow, &authority, authorization.as_deref());
+                        match tokio::time::timeout(REQUEST_TIMEOUT, open).await {
+                            Ok(Ok(request)) => {
+                                let qsid = request.0;
+                                requests.insert(key, request);
+                                qsid
+                            }
+                            Ok(Err(e)) => {
+                                debug!("failed to proxy to {}: {}", pkt.dst_addr, e);
+                                continue;
+                            }
+                            Err(_) => {
+                                debug!("masque request to {} timed out", pkt.dst_addr);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                let mut buf = BytesMut::with_capacity(pkt.data.len() + 9);
+                h3::put_varint(&mut buf, qsid);
+                h3::put_varint(&mut buf, CONTEXT_UDP);
+                buf.put_slice(&pkt.data);
+                if let Err(e) = conn.conn.send_datagram(buf.freeze()) {
+                    debug!("failed to send masque datagram: {}", e);
+                    if conn.conn.close_reason().is_some() {
+                        break;
+                    }
+                }
+            }
+
+            // the requests are closed with their streams dropped
+            let mut flows = conn.flows.lock().unwrap();
+            for (qsid, ..) in requests.values() {
+                flows.remove(qsid);
+            }
+        });
+
+        Self {
+            send_tx: tokio_util::sync::PollSender::new(send_tx),
+            recv_rx,
+        }
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramMasque {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_ready_unpin(cx)
+            .map_err(|_| new_io_error("masque session closed"))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.send_tx
+            .start_send_unpin(item)
+            .map_err(|_| new_io_error("masque session closed"))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_flush_unpin(cx)
+            .map_err(|_| new_io_error("masque session closed"))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_close_unpin(cx)
+            .map_err(|_| new_io_error("masque session closed"))
+    }
+}
+
+impl Stream for OutboundDatagramMasque {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv_rx.poll_recv(cx)
+    }
+}
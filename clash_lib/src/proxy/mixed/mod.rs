@@ -1,6 +1,7 @@
+use crate::app::inbound::rate_limit::ThreadSafeRateLimiter;
 use crate::common::auth::ThreadSafeAuthenticator;
 use crate::proxy::{AnyInboundListener, InboundListener};
-use crate::session::{Network, Session};
+use crate::session::{ListenerId, Network, Session};
 use crate::Dispatcher;
 use async_trait::async_trait;
 use std::net::SocketAddr;
@@ -9,13 +10,16 @@ use std::sync::Arc;
 use tokio::net::TcpListener;
 use tracing::warn;
 
-use super::utils::apply_tcp_options;
+use super::utils::{apply_tcp_options, proxy_protocol};
 use super::{http, socks};
 
 pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    proxy_protocol: bool,
+    block_page: Option<Arc<http::BlockPage>>,
+    rate_limiter: Option<ThreadSafeRateLimiter>,
 }
 
 impl Drop for Listener {
@@ -30,11 +34,17 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        proxy_protocol: bool,
+        block_page: Option<Arc<http::BlockPage>>,
+        rate_limiter: Option<ThreadSafeRateLimiter>,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
             dispatcher,
             authenticator,
+            proxy_protocol,
+            block_page,
+            rate_limiter,
         }) as _
     }
 }
@@ -49,42 +59,73 @@ impl InboundListener for Listener {
         false
     }
 
-    async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
-
+    async fn listen_tcp(&self, listener: TcpListener) -> std::io::Result<()> {
         loop {
             let (socket, _) = listener.accept().await?;
             let mut socket = apply_tcp_options(socket)?;
 
-            let mut p = [0; 1];
-            let n = socket.peek(&mut p).await?;
-            if n != 1 {
-                warn!("failed to peek socket on mixed listener {}", self.addr);
-                continue;
-            }
-
             let dispatcher = self.dispatcher.clone();
             let authenticator = self.authenticator.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let block_page = self.block_page.clone();
+            let limiter = self.rate_limiter.clone();
+            let addr = self.addr;
+            let listener = ListenerId {
+                name: "mixed",
+                port: addr.port(),
+            };
+
+            // the header and the first byte are read off the accept loop,
+            // a slow client must not hold up the others
+            tokio::spawn(async move {
+                let src = match proxy_protocol::source_addr(&mut socket, proxy_protocol).await {
+                    Ok(src) => src,
+                    Err(e) => {
+                        warn!(
+                            "failed to accept connection on mixed listener {}: {}",
+                            addr, e
+                        );
+                        return;
+                    }
+                };
+                if limiter.as_ref().is_some_and(|x| !x.allow(src.ip())) {
+                    return;
+                }
 
-            match p[0] {
-                socks::SOCKS5_VERSION => {
-                    let mut sess = Session {
-                        network: Network::Tcp,
-                        source: socket.peer_addr()?,
-
-                        ..Default::default()
-                    };
-
-                    tokio::spawn(async move {
-                        socks::handle_tcp(&mut sess, &mut socket, dispatcher, authenticator).await
-                    });
+                let mut p = [0; 1];
+                if !matches!(socket.peek(&mut p).await, Ok(1)) {
+                    warn!("failed to peek socket on mixed listener {}", addr);
+                    return;
                 }
 
-                _ => {
-                    let src = socket.peer_addr()?;
-                    http::handle_http(Box::new(socket), src, dispatcher, authenticator).await;
+                match p[0] {
+                    socks::SOCKS4_VERSION | socks::SOCKS5_VERSION => {
+                        let mut sess = Session {
+                            network: Network::Tcp,
+                            source: src,
+                            listener: Some(listener),
+
+                            ..Default::default()
+                        };
+
+                        let _ =
+                            socks::handle_tcp(&mut sess, &mut socket, dispatcher, authenticator)
+                                .await;
+                    }
+
+                    _ => {
+                        http::handle_http(
+                            Box::new(socket),
+                            src,
+                            listener,
+                            dispatcher,
+                            authenticator,
+                            block_page,
+                        )
+                        .await;
+                    }
                 }
-            }
+            });
         }
     }
 
@@ -23,20 +23,28 @@ pub mod direct;
 pub mod reject;
 
 pub mod http;
+pub mod hysteria2;
+pub mod masque;
 pub mod mixed;
 
 pub(crate) mod datagram;
 mod options;
 
+pub mod chaos;
 pub mod converters;
 #[cfg(feature = "shadowsocks")]
 pub mod shadowsocks;
 pub mod socks;
+pub mod ssh;
 pub mod tor;
+#[cfg(target_os = "linux")]
+pub mod tproxy;
 pub mod trojan;
 pub mod tuic;
 pub mod tun;
+pub mod uot;
 pub mod utils;
+pub mod vless;
 pub mod vmess;
 pub mod wg;
 
@@ -46,7 +54,7 @@ pub mod relay;
 pub mod selector;
 pub mod urltest;
 
-mod transport;
+pub(crate) mod transport;
 
 #[cfg(test)]
 pub mod mocks;
@@ -101,7 +109,9 @@ pub trait InboundListener: Send + Sync + Unpin {
     fn handle_tcp(&self) -> bool;
     /// support udp or not
     fn handle_udp(&self) -> bool;
-    async fn listen_tcp(&self) -> io::Result<()>;
+    /// serve the connections of the listener, bound to the address of the
+    /// inbound beforehand so it can take over from the previous one
+    async fn listen_tcp(&self, listener: tokio::net::TcpListener) -> io::Result<()>;
     async fn listen_udp(&self) -> io::Result<()>;
 }
 
@@ -111,10 +121,15 @@ pub type AnyInboundListener = Arc<dyn InboundListener>;
 pub enum OutboundType {
     Shadowsocks,
     Vmess,
+    Vless,
     Trojan,
     WireGuard,
     Tor,
+    Ssh,
     Tuic,
+    Masque,
+    Hysteria2,
+    Socks5,
 
     #[serde(rename = "URLTest")]
     UrlTest,
@@ -125,6 +140,9 @@ pub enum OutboundType {
 
     Direct,
     Reject,
+
+    /// registered by the embedding crate
+    Plugin,
 }
 
 impl Display for OutboundType {
@@ -132,10 +150,15 @@ impl Display for OutboundType {
         match self {
             OutboundType::Shadowsocks => write!(f, "Shadowsocks"),
             OutboundType::Vmess => write!(f, "Vmess"),
+            OutboundType::Vless => write!(f, "Vless"),
             OutboundType::Trojan => write!(f, "Trojan"),
             OutboundType::WireGuard => write!(f, "WireGuard"),
             OutboundType::Tor => write!(f, "Tor"),
+            OutboundType::Ssh => write!(f, "Ssh"),
             OutboundType::Tuic => write!(f, "Tuic"),
+            OutboundType::Masque => write!(f, "Masque"),
+            OutboundType::Hysteria2 => write!(f, "Hysteria2"),
+            OutboundType::Socks5 => write!(f, "Socks5"),
             OutboundType::UrlTest => write!(f, "URLTest"),
             OutboundType::Selector => write!(f, "Selector"),
             OutboundType::Relay => write!(f, "Relay"),
@@ -143,6 +166,7 @@ impl Display for OutboundType {
             OutboundType::Fallback => write!(f, "Fallback"),
             OutboundType::Direct => write!(f, "Direct"),
             OutboundType::Reject => write!(f, "Reject"),
+            OutboundType::Plugin => write!(f, "Plugin"),
         }
     }
 }
@@ -208,6 +232,23 @@ pub trait OutboundHandler: Sync + Send + Unpin {
         ))
     }
 
+    /// whether a domain destination is passed to the proxy server to resolve,
+    /// otherwise it's resolved locally
+    fn remote_dns_resolve(&self) -> bool {
+        true
+    }
+
+    /// the TLS handshake target of the outbound, for diagnostics
+    fn tls_endpoint(&self) -> Option<transport::tls::TlsEndpoint> {
+        None
+    }
+
+    /// the member a group sends the traffic to now, none for a proxy or a
+    /// group picking one per connection
+    async fn current_member(&self) -> Option<AnyOutboundHandler> {
+        None
+    }
+
     /// for API
     /// the map only contains basic information
     /// to populate history/liveness information, use the proxy_manager
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 pub struct HttpOption {
     pub method: String,
@@ -14,6 +14,8 @@ pub struct Http2Option {
 pub struct GrpcOption {
     pub host: String,
     pub service_name: String,
+    /// the interval of the http/2 pings on the connection
+    pub ping_interval: Option<Duration>,
 }
 
 pub struct WsOption {
@@ -21,4 +23,6 @@ pub struct WsOption {
     pub headers: HashMap<String, String>,
     pub max_early_data: usize,
     pub early_data_header_name: String,
+    /// the interval of the websocket pings while the connection is open
+    pub ping_interval: Option<Duration>,
 }
@@ -19,10 +19,7 @@ use crate::{
 };
 
 use super::{
-    utils::{
-        provider_helper::get_proxies_from_providers, DirectConnector, ProxyConnector,
-        RemoteConnector,
-    },
+    utils::{provider_helper::get_proxies_from_providers, ProxyConnector, RemoteConnector},
     AnyOutboundHandler, CommonOption, ConnectorType, OutboundHandler, OutboundType,
 };
 
@@ -49,6 +46,55 @@ impl Handler {
     async fn get_proxies(&self, touch: bool) -> Vec<AnyOutboundHandler> {
         get_proxies_from_providers(&self.providers, touch).await
     }
+
+    /// The connector through the proxies in order, each one dialing its
+    /// server through the ones before. The entry dials as it would alone.
+    async fn connector(
+        &self,
+        proxies: &[AnyOutboundHandler],
+        udp: bool,
+    ) -> io::Result<Box<dyn RemoteConnector>> {
+        let network = if udp { "udp" } else { "tcp" };
+        let mut connector: Option<Box<dyn RemoteConnector>> = None;
+        for proxy in proxies {
+            debug!(
+                "{} relay `{}` via proxy `{}`",
+                network,
+                self.name(),
+                proxy.name()
+            );
+            connector = Some(match connector {
+                None => Box::new(ProxyConnector::entry(proxy.clone())),
+                Some(connector) => {
+                    self.check_hop(proxy, udp).await?;
+                    Box::new(ProxyConnector::new(proxy.clone(), connector))
+                }
+            });
+        }
+        connector.ok_or_else(|| new_io_error("no proxy available"))
+    }
+
+    /// Fail early with the name of a proxy that can't be dialed through
+    /// the ones before it.
+    async fn check_hop(&self, proxy: &AnyOutboundHandler, udp: bool) -> io::Result<()> {
+        if dials_through(proxy, udp).await {
+            return Ok(());
+        }
+        Err(new_io_error(&format!(
+            "{} of relay {} can't be dialed through another proxy{}",
+            proxy.name(),
+            self.name(),
+            if udp { " for udp" } else { "" }
+        )))
+    }
+}
+
+/// Whether the proxy can connect to its server through another one.
+async fn dials_through(proxy: &AnyOutboundHandler, udp: bool) -> bool {
+    matches!(
+        (proxy.support_connector().await, udp),
+        (ConnectorType::All, _) | (ConnectorType::Tcp, false) | (ConnectorType::Udp, true)
+    )
 }
 
 #[async_trait]
@@ -62,7 +108,18 @@ impl OutboundHandler for Handler {
     }
 
     async fn support_udp(&self) -> bool {
-        false
+        let proxies = self.get_proxies(false).await;
+        match proxies.last() {
+            Some(last) if last.support_udp().await => {
+                for proxy in proxies.iter().skip(1) {
+                    if !dials_through(proxy, true).await {
+                        return false;
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
     }
 
     async fn connect_stream(
@@ -81,12 +138,9 @@ impl OutboundHandler for Handler {
                 proxy.connect_stream(sess, resolver).await
             }
             _ => {
-                let mut connector: Box<dyn RemoteConnector> = Box::new(DirectConnector::new());
                 let (proxies, last) = proxies.split_at(proxies.len() - 1);
-                for proxy in proxies {
-                    debug!("tcp relay `{}` via proxy `{}`", self.name(), proxy.name());
-                    connector = Box::new(ProxyConnector::new(proxy.clone(), connector));
-                }
+                let connector = self.connector(proxies, false).await?;
+                self.check_hop(&last[0], false).await?;
 
                 debug!("relay `{}` via proxy `{}`", self.name(), last[0].name());
                 let s = last[0]
@@ -116,12 +170,10 @@ impl OutboundHandler for Handler {
                 proxy.connect_datagram(sess, resolver).await
             }
             _ => {
-                let mut connector: Box<dyn RemoteConnector> = Box::new(DirectConnector::new());
                 let (proxies, last) = proxies.split_at(proxies.len() - 1);
-                for proxy in proxies {
-                    debug!("udp relay `{}` via proxy `{}`", self.name(), proxy.name());
-                    connector = Box::new(ProxyConnector::new(proxy.clone(), connector));
-                }
+                let connector = self.connector(proxies, true).await?;
+                self.check_hop(&last[0], true).await?;
+
                 let d = last[0]
                     .connect_datagram_with_connector(sess, resolver, connector.as_ref())
                     .await?;
@@ -190,6 +242,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin_opts: Default::default(),
             udp: false,
+            remote_dns_resolve: true,
         };
         let port = ss_opts.port;
         let ss_handler = crate::proxy::shadowsocks::Handler::new(ss_opts);
@@ -221,6 +274,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin_opts: Default::default(),
             udp: false,
+            remote_dns_resolve: true,
         };
         let port = ss_opts.port;
         let ss_handler = crate::proxy::shadowsocks::Handler::new(ss_opts);
@@ -9,8 +9,10 @@ use crate::{
     app::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
+        events::{self, EventKind},
         remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
     },
+    common::errors::member_dial_error,
     session::Session,
     Error,
 };
@@ -24,12 +26,16 @@ use super::{
 pub trait SelectorControl {
     async fn select(&mut self, name: &str) -> Result<(), Error>;
     async fn current(&self) -> String;
+    /// Put the members in `order` first, the rest follow in config order.
+    async fn set_order(&mut self, order: Vec<String>) -> Result<(), Error>;
 }
 
 pub type ThreadSafeSelectorControl = Arc<Mutex<dyn SelectorControl + Send + Sync>>;
 
 struct HandlerInner {
     current: String,
+    /// the manual ordering of the members, set by the API
+    order: Vec<String>,
 }
 
 #[derive(Default, Clone)]
@@ -52,9 +58,10 @@ impl Handler {
         opts: HandlerOptions,
         providers: Vec<ThreadSafeProxyProvider>,
         seleted: Option<String>,
+        order: Vec<String>,
     ) -> Self {
         let provider = providers.first().unwrap();
-        let proxies = provider.read().await.proxies().await;
+        let proxies = apply_order(provider.read().await.proxies().await, &order);
         let current = proxies.first().unwrap().name().to_owned();
 
         Self {
@@ -62,12 +69,18 @@ impl Handler {
             providers,
             inner: Arc::new(RwLock::new(HandlerInner {
                 current: seleted.unwrap_or(current),
+                order,
             })),
         }
     }
 
-    async fn selected_proxy(&self, touch: bool) -> AnyOutboundHandler {
+    async fn ordered_proxies(&self, touch: bool) -> Vec<AnyOutboundHandler> {
         let proxies = get_proxies_from_providers(&self.providers, touch).await;
+        apply_order(proxies, &self.inner.read().await.order)
+    }
+
+    async fn selected_proxy(&self, touch: bool) -> AnyOutboundHandler {
+        let proxies = self.ordered_proxies(touch).await;
         let current = &self.inner.read().await.current;
         for proxy in proxies.iter() {
             if proxy.name() == current {
@@ -87,6 +100,10 @@ impl SelectorControl for Handler {
         let proxies = get_proxies_from_providers(&self.providers, false).await;
         if proxies.iter().any(|x| x.name() == name) {
             name.clone_into(&mut self.inner.write().await.current);
+            events::publish(
+                EventKind::ProxySelected,
+                serde_json::json!({ "group": self.name(), "proxy": name }),
+            );
             Ok(())
         } else {
             Err(Error::Operation(format!("proxy {} not found", name)))
@@ -96,6 +113,34 @@ impl SelectorControl for Handler {
     async fn current(&self) -> String {
         self.inner.read().await.current.to_owned()
     }
+
+    async fn set_order(&mut self, order: Vec<String>) -> Result<(), Error> {
+        let proxies = get_proxies_from_providers(&self.providers, false).await;
+        for (i, name) in order.iter().enumerate() {
+            if !proxies.iter().any(|x| x.name() == name.as_str()) {
+                return Err(Error::Operation(format!("proxy {} not found", name)));
+            }
+            if order[..i].contains(name) {
+                return Err(Error::Operation(format!("proxy {} listed twice", name)));
+            }
+        }
+        self.inner.write().await.order = order;
+        Ok(())
+    }
+}
+
+/// Sort the proxies by their position in `order`, the ones not in it keep
+/// their relative order at the end. Stale names in `order` are ignored.
+fn apply_order(mut proxies: Vec<AnyOutboundHandler>, order: &[String]) -> Vec<AnyOutboundHandler> {
+    if !order.is_empty() {
+        proxies.sort_by_key(|x| {
+            order
+                .iter()
+                .position(|name| name.as_str() == x.name())
+                .unwrap_or(order.len())
+        });
+    }
+    proxies
 }
 
 #[async_trait]
@@ -117,11 +162,11 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let s = self
-            .selected_proxy(true)
-            .await
+        let proxy = self.selected_proxy(true).await;
+        let s = proxy
             .connect_stream(sess, resolver)
-            .await;
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e));
 
         match s {
             Ok(s) => {
@@ -137,10 +182,11 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        self.selected_proxy(true)
-            .await
+        let proxy = self.selected_proxy(true).await;
+        proxy
             .connect_datagram(sess, resolver)
             .await
+            .map_err(|e| member_dial_error(proxy.name(), e))
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -176,8 +222,12 @@ impl OutboundHandler for Handler {
     }
 
     /// for API
+    async fn current_member(&self) -> Option<AnyOutboundHandler> {
+        Some(self.selected_proxy(false).await)
+    }
+
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
-        let all = get_proxies_from_providers(&self.providers, false).await;
+        let all = self.ordered_proxies(false).await;
 
         let mut m = HashMap::new();
         m.insert("type".to_string(), Box::new(self.proto()) as _);
@@ -199,6 +249,7 @@ mod tests {
     use crate::proxy::{
         mocks::{MockDummyOutboundHandler, MockDummyProxyProvider},
         selector::ThreadSafeSelectorControl,
+        OutboundHandler,
     };
 
     #[tokio::test]
@@ -224,6 +275,7 @@ mod tests {
             },
             vec![Arc::new(RwLock::new(mock_provider))],
             None,
+            vec![],
         )
         .await;
 
@@ -254,8 +306,29 @@ mod tests {
             outbound_handler.selected_proxy(false).await.name(),
             "provider2".to_owned()
         );
+        assert_eq!(
+            outbound_handler.current_member().await.unwrap().name(),
+            "provider2"
+        );
 
         let fail = selector_control.lock().await.select("provider3").await;
         assert!(fail.is_err());
+
+        selector_control
+            .lock()
+            .await
+            .set_order(vec!["provider2".to_owned()])
+            .await
+            .unwrap();
+        let all = outbound_handler.ordered_proxies(false).await;
+        assert_eq!(all[0].name(), "provider2");
+        assert_eq!(all[1].name(), "provider1");
+
+        assert!(selector_control
+            .lock()
+            .await
+            .set_order(vec!["provider3".to_owned()])
+            .await
+            .is_err());
     }
 }
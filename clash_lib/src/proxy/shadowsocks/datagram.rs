@@ -5,7 +5,7 @@ use std::{
 };
 
 use futures::{ready, Sink, Stream};
-use shadowsocks::ProxySocket;
+use shadowsocks::{relay::udprelay::options::UdpSocketControlData, ProxySocket};
 use tokio::io::ReadBuf;
 use tracing::{debug, instrument, trace};
 
@@ -15,6 +15,8 @@ use crate::{
     session::SocksAddr,
 };
 
+use super::replay::SessionFilter;
+
 #[must_use = "sinks do nothing unless polled"]
 pub struct OutboundDatagramShadowsocks {
     inner: ProxySocket,
@@ -23,6 +25,9 @@ pub struct OutboundDatagramShadowsocks {
     pkt: Option<UdpPacket>,
     buf: Vec<u8>,
     resolver: ThreadSafeDNSResolver,
+    /// the session of the 2022 ciphers, ignored by the others
+    control: UdpSocketControlData,
+    server_sessions: SessionFilter,
 }
 
 impl OutboundDatagramShadowsocks {
@@ -32,6 +37,8 @@ impl OutboundDatagramShadowsocks {
         remote_addr: (String, u16),
         resolver: ThreadSafeDNSResolver,
     ) -> AnyOutboundDatagram {
+        let mut control = UdpSocketControlData::default();
+        control.client_session_id = rand::random();
         let s = Self {
             inner,
             flushed: true,
@@ -39,6 +46,8 @@ impl OutboundDatagramShadowsocks {
             remote_addr: remote_addr.try_into().expect("must into socks addr"),
             buf: vec![0u8; 65535],
             resolver,
+            control,
+            server_sessions: SessionFilter::default(),
         };
         Box::new(s) as _
     }
@@ -62,6 +71,7 @@ impl Sink<UdpPacket> for OutboundDatagramShadowsocks {
         let pin = self.get_mut();
         pin.pkt = Some(item);
         pin.flushed = false;
+        pin.control.packet_id += 1;
         Ok(())
     }
 
@@ -77,6 +87,7 @@ impl Sink<UdpPacket> for OutboundDatagramShadowsocks {
             ref remote_addr,
             ref mut flushed,
             ref mut resolver,
+            ref control,
             ..
         } = *self;
 
@@ -110,7 +121,7 @@ impl Sink<UdpPacket> for OutboundDatagramShadowsocks {
             let addr: shadowsocks::relay::Address =
                 (pkt.dst_addr.host(), pkt.dst_addr.port()).into();
 
-            let n = ready!(inner.poll_send_to(dst, &addr, data, cx))?;
+            let n = ready!(inner.poll_send_to_with_ctrl(dst, &addr, control, data, cx))?;
 
             debug!(
                 "send udp packet to remote ss server, len: {}, remote_addr: {}, dst_addr: {}",
@@ -153,21 +164,34 @@ impl Stream for OutboundDatagramShadowsocks {
         let Self {
             ref mut buf,
             ref inner,
+            ref mut server_sessions,
             ..
         } = *self;
 
-        let mut buf = ReadBuf::new(buf);
-
-        let rv = ready!(inner.poll_recv_from(cx, &mut buf));
-        debug!("recv udp packet from remote ss server: {:?}", rv);
-
-        match rv {
-            Ok((n, src, _, _)) => Poll::Ready(Some(UdpPacket {
-                data: buf.filled()[..n].to_vec(),
-                src_addr: src.into(),
-                dst_addr: SocksAddr::any_ipv4(),
-            })),
-            Err(_) => Poll::Ready(None),
+        loop {
+            let mut buf = ReadBuf::new(buf);
+            let rv = ready!(inner.poll_recv_from_with_ctrl(cx, &mut buf));
+            debug!("recv udp packet from remote ss server: {:?}", rv);
+
+            match rv {
+                Ok((n, src, _, _, control)) => {
+                    if let Some(control) = control {
+                        if !server_sessions.check(control.server_session_id, control.packet_id) {
+                            debug!(
+                                "drop replayed udp packet {} of session {}",
+                                control.packet_id, control.server_session_id
+                            );
+                            continue;
+                        }
+                    }
+                    return Poll::Ready(Some(UdpPacket {
+                        data: buf.filled()[..n].to_vec(),
+                        src_addr: src.into(),
+                        dst_addr: SocksAddr::any_ipv4(),
+                    }));
+                }
+                Err(_) => return Poll::Ready(None),
+            }
         }
     }
 }
@@ -1,10 +1,13 @@
 mod datagram;
+mod replay;
 mod shadow_tls;
 mod simple_obfs;
+mod sip003;
 mod stream;
 mod v2ray;
 
 use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use futures::TryFutureExt;
 use shadowsocks::{
     config::ServerType, context::Context, crypto::CipherKind,
@@ -27,8 +30,10 @@ use std::{collections::HashMap, io, sync::Arc};
 
 use self::{datagram::OutboundDatagramShadowsocks, stream::ShadowSocksStream};
 
+pub use sip003::{decode_plugin_options, encode_plugin_options, ExternalPlugin};
+
 use super::{
-    utils::{new_tcp_stream, new_udp_socket, RemoteConnector},
+    utils::{new_tcp_stream, new_udp_socket, resolve_destination, RemoteConnector},
     AnyOutboundHandler, AnyStream, ConnectorType, OutboundType,
 };
 
@@ -166,6 +171,53 @@ pub enum OBFSOption {
     Simple(SimpleOBFSOption),
     V2Ray(V2RayOBFSOption),
     ShadowTls(ShadowTlsOption),
+    /// a SIP003 plugin binary, spawned as a child process
+    Sip003(ExternalPlugin),
+}
+
+/// Whether the plugin is done natively, any other is a SIP003 binary.
+pub fn is_native_plugin(plugin: &str) -> bool {
+    matches!(plugin, "obfs" | "v2ray-plugin" | "shadow-tls")
+}
+
+/// The cipher named `cipher` in the config.
+pub fn cipher_kind(cipher: &str) -> Option<CipherKind> {
+    Some(match cipher {
+        "aes-128-gcm" => CipherKind::AES_128_GCM,
+        "aes-256-gcm" => CipherKind::AES_256_GCM,
+        "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
+        "2022-blake3-aes-128-gcm" => CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+        "2022-blake3-aes-256-gcm" => CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+        "2022-blake3-chacha20-poly1305" => CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305,
+        _ => return None,
+    })
+}
+
+/// The password of the 2022 ciphers is the base64 of a key of the size of
+/// the cipher. With the AES ones, it may be preceded by the identity keys
+/// of the relays in front of the server, colon separated (SIP023).
+pub fn check_password(cipher: CipherKind, password: &str) -> Result<(), String> {
+    if !cipher.is_aead_2022() {
+        return Ok(());
+    }
+    let keys = password.split(':').collect::<Vec<_>>();
+    if keys.len() > 1 && cipher == CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305 {
+        return Err(format!("{} has no identity headers", cipher));
+    }
+    for key in keys {
+        let key = STANDARD
+            .decode(key)
+            .map_err(|e| format!("the key must be base64: {}", e))?;
+        if key.len() != cipher.key_len() {
+            return Err(format!(
+                "the key of {} must be {} bytes, got {}",
+                cipher,
+                cipher.key_len(),
+                key.len()
+            ));
+        }
+    }
+    Ok(())
 }
 
 pub struct HandlerOptions {
@@ -177,6 +229,7 @@ pub struct HandlerOptions {
     pub cipher: String,
     pub plugin_opts: Option<OBFSOption>,
     pub udp: bool,
+    pub remote_dns_resolve: bool,
 }
 
 pub struct Handler {
@@ -189,12 +242,24 @@ impl Handler {
         Arc::new(Self { opts })
     }
 
+    fn server_config(&self) -> io::Result<ServerConfig> {
+        let cipher = cipher_kind(&self.opts.cipher)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unsupported cipher"))?;
+        Ok(ServerConfig::new(
+            (self.opts.server.to_owned(), self.opts.port),
+            self.opts.password.to_owned(),
+            cipher,
+        ))
+    }
+
     async fn proxy_stream(
         &self,
         s: AnyStream,
         sess: &Session,
-        #[allow(unused_variables)] _resolver: ThreadSafeDNSResolver,
+        resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<AnyStream> {
+        let sess = resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
+
         let stream: AnyStream = match &self.opts.plugin_opts {
             Some(plugin) => match plugin {
                 OBFSOption::Simple(opts) => {
@@ -217,21 +282,14 @@ impl Handler {
 
                     (shadow_tls::Connector::wrap(opts, s).await?) as _
                 }
+                // the plugin process already did the transformation
+                OBFSOption::Sip003(_) => s,
             },
             None => s,
         };
 
         let ctx = Context::new_shared(ServerType::Local);
-        let cfg = ServerConfig::new(
-            (self.opts.server.to_owned(), self.opts.port),
-            self.opts.password.to_owned(),
-            match self.opts.cipher.as_str() {
-                "aes-128-gcm" => CipherKind::AES_128_GCM,
-                "aes-256-gcm" => CipherKind::AES_256_GCM,
-                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "unsupported cipher")),
-            },
-        );
+        let cfg = self.server_config()?;
 
         let stream = ProxyClientStream::from_stream(
             ctx,
@@ -240,7 +298,7 @@ impl Handler {
             (sess.destination.host(), sess.destination.port()),
         );
 
-        Ok(Box::new(ShadowSocksStream(stream)))
+        Ok(Box::new(ShadowSocksStream::new(stream)))
     }
 }
 
@@ -258,26 +316,32 @@ impl OutboundHandler for Handler {
         self.opts.udp
     }
 
+    fn remote_dns_resolve(&self) -> bool {
+        self.opts.remote_dns_resolve
+    }
+
     async fn connect_stream(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
+        let (server, port) = match &self.opts.plugin_opts {
+            Some(OBFSOption::Sip003(plugin)) => ("127.0.0.1", plugin.local_port().await?),
+            _ => (self.opts.server.as_str(), self.opts.port),
+        };
+
         let stream = new_tcp_stream(
             resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
+            server,
+            port,
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
         .map_err(|x| {
             io::Error::new(
                 io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
+                format!("dial outbound {}:{}: {}", server, port, x),
             )
         })
         .await?;
@@ -290,23 +354,14 @@ impl OutboundHandler for Handler {
 
     async fn connect_datagram(
         &self,
-        #[allow(unused_variables)] sess: &Session,
+        sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
         let ctx = Context::new_shared(ServerType::Local);
-        let cfg = ServerConfig::new(
-            (self.opts.server.to_owned(), self.opts.port),
-            self.opts.password.to_owned(),
-            match self.opts.cipher.as_str() {
-                "aes-128-gcm" => CipherKind::AES_128_GCM,
-                "aes-256-gcm" => CipherKind::AES_256_GCM,
-                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "unsupported cipher")),
-            },
-        );
+        let cfg = self.server_config()?;
         let socket = new_udp_socket(
             None,
-            self.opts.common_opts.iface.as_ref(),
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
@@ -333,12 +388,19 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
+        if let Some(OBFSOption::Sip003(_)) = &self.opts.plugin_opts {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "sip003 plugins can not be used in a relay chain",
+            ));
+        }
+
         let stream = connector
             .connect_stream(
                 resolver.clone(),
                 self.opts.server.as_str(),
                 self.opts.port,
-                self.opts.common_opts.iface.as_ref(),
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 None,
             )
@@ -365,13 +427,23 @@ mod tests {
     const PASSWORD: &str = "FzcLbKs2dY9mhL";
     const CIPHER: &str = "aes-256-gcm";
     const SHADOW_TLS_PASSWORD: &str = "password";
+    const CIPHER_2022: &str = "2022-blake3-aes-256-gcm";
+    const KEY_2022: &str = "FnQ5wSMEqOqR+3rDqL/1QWvqDbeKpqkxWcKtzEjLdCQ=";
 
     async fn get_ss_runner(port: u16) -> anyhow::Result<DockerTestRunner> {
+        get_ss_runner_with(port, CIPHER, PASSWORD).await
+    }
+
+    async fn get_ss_runner_with(
+        port: u16,
+        cipher: &str,
+        password: &str,
+    ) -> anyhow::Result<DockerTestRunner> {
         let host = format!("0.0.0.0:{}", port);
         DockerTestRunnerBuilder::new()
             .image(IMAGE_SS_RUST)
             .entrypoint(&["ssserver"])
-            .cmd(&["-s", &host, "-m", CIPHER, "-k", PASSWORD, "-U"])
+            .cmd(&["-s", &host, "-m", cipher, "-k", password, "-U"])
             .build()
             .await
     }
@@ -389,12 +461,39 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin_opts: Default::default(),
             udp: false,
+            remote_dns_resolve: true,
         };
         let port = opts.port;
         let handler = Handler::new(opts);
         run_test_suites_and_cleanup(handler, get_ss_runner(port).await?, Suite::all()).await
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_ss_2022() -> anyhow::Result<()> {
+        let _ = tracing_subscriber::fmt().try_init();
+        assert!(check_password(cipher_kind(CIPHER_2022).unwrap(), KEY_2022).is_ok());
+        let opts = HandlerOptions {
+            name: "test-ss-2022".to_owned(),
+            common_opts: Default::default(),
+            server: LOCAL_ADDR.to_owned(),
+            port: 10002,
+            password: KEY_2022.to_owned(),
+            cipher: CIPHER_2022.to_owned(),
+            plugin_opts: Default::default(),
+            udp: true,
+            remote_dns_resolve: true,
+        };
+        let port = opts.port;
+        let handler = Handler::new(opts);
+        run_test_suites_and_cleanup(
+            handler,
+            get_ss_runner_with(port, CIPHER_2022, KEY_2022).await?,
+            Suite::all(),
+        )
+        .await
+    }
+
     async fn get_shadowtls_runner(
         ss_port: u16,
         stls_port: u16,
@@ -439,6 +538,7 @@ mod tests {
                 strict: true,
             })),
             udp: false,
+            remote_dns_resolve: true,
         };
         let handler: Arc<dyn OutboundHandler> = Handler::new(opts);
         // we need to store all the runners in a container, to make sure all of them can be destroyed after the test
@@ -0,0 +1,117 @@
+//! The replay protection of the Shadowsocks 2022 UDP sessions
+//!
+//! The packets of a session are numbered, so a sliding window of the last
+//! packet ids seen tells the replayed ones apart while still allowing
+//! some reordering (RFC 6479).
+
+const WORD_BITS: u64 = u64::BITS as u64;
+const WORDS: usize = 64;
+/// how far behind the newest packet an older one is still accepted
+const WINDOW_SIZE: u64 = (WORDS as u64 - 1) * WORD_BITS;
+
+#[derive(Clone)]
+pub struct ReplayWindow {
+    /// the biggest packet id seen
+    last: u64,
+    bitmap: [u64; WORDS],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self {
+            last: 0,
+            bitmap: [0; WORDS],
+        }
+    }
+}
+
+impl ReplayWindow {
+    /// Record the packet id, false if it was seen already or is too old.
+    pub fn check(&mut self, id: u64) -> bool {
+        if id.saturating_add(WINDOW_SIZE) < self.last {
+            return false;
+        }
+
+        let word = id / WORD_BITS;
+        if id > self.last {
+            // clear the words skipped over
+            let last_word = self.last / WORD_BITS;
+            let skipped = (word - last_word).min(WORDS as u64);
+            for i in 1..=skipped {
+                self.bitmap[((last_word + i) % WORDS as u64) as usize] = 0;
+            }
+            self.last = id;
+        }
+
+        let slot = &mut self.bitmap[(word % WORDS as u64) as usize];
+        let bit = 1 << (id % WORD_BITS);
+        if *slot & bit != 0 {
+            return false;
+        }
+        *slot |= bit;
+        true
+    }
+}
+
+/// The windows of the current server session of a UDP association and of
+/// the previous one, whose packets may still be in flight when the server
+/// starts a new session.
+#[derive(Default)]
+pub struct SessionFilter {
+    current: Option<(u64, ReplayWindow)>,
+    previous: Option<(u64, ReplayWindow)>,
+}
+
+impl SessionFilter {
+    /// Record the packet of a server session, false if it's a replay.
+    pub fn check(&mut self, session_id: u64, packet_id: u64) -> bool {
+        let windows = [&mut self.current, &mut self.previous];
+        for (id, window) in windows.into_iter().flatten() {
+            if *id == session_id {
+                return window.check(packet_id);
+            }
+        }
+
+        let mut window = ReplayWindow::default();
+        window.check(packet_id);
+        self.previous = self.current.replace((session_id, window));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayWindow, SessionFilter, WINDOW_SIZE};
+
+    #[test]
+    fn test_replay_window() {
+        let mut w = ReplayWindow::default();
+        assert!(w.check(1));
+        assert!(!w.check(1));
+        assert!(w.check(3));
+        assert!(w.check(2));
+        assert!(!w.check(2));
+
+        let far = 10 + WINDOW_SIZE;
+        assert!(w.check(far));
+        assert!(w.check(10));
+        assert!(!w.check(9));
+        assert!(!w.check(far));
+        assert!(w.check(far + 100_000));
+        assert!(w.check(far + 99_999));
+        assert!(!w.check(far));
+    }
+
+    #[test]
+    fn test_session_filter() {
+        let mut f = SessionFilter::default();
+        assert!(f.check(1, 1));
+        assert!(!f.check(1, 1));
+        assert!(f.check(2, 1));
+        assert!(f.check(1, 2));
+        assert!(!f.check(1, 2));
+        assert!(f.check(3, 1));
+        // the first session is forgotten
+        assert!(f.check(1, 1));
+    }
+}
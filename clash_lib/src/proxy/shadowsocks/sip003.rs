@@ -0,0 +1,253 @@
+//! SIP003 external plugin support
+//! https://shadowsocks.org/doc/sip003.html
+//!
+//! The plugin binary is started on first use, listens on a local port and
+//! forwards to the remote server. It's restarted if found dead on the next
+//! dial, and killed when the handler is dropped.
+
+use std::{
+    collections::HashMap,
+    io,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::TcpStream,
+    process::{Child, Command},
+    sync::Mutex,
+};
+use tracing::{debug, info, warn};
+
+const MIN_RESTART_INTERVAL: Duration = Duration::from_secs(3);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct Running {
+    child: Child,
+    port: u16,
+    started_at: Instant,
+}
+
+pub struct ExternalPlugin {
+    path: String,
+    options: String,
+    remote_host: String,
+    remote_port: u16,
+    state: Mutex<Option<Running>>,
+}
+
+impl std::fmt::Debug for ExternalPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExternalPlugin")
+            .field("path", &self.path)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl ExternalPlugin {
+    pub fn new(
+        path: String,
+        opts: Option<&HashMap<String, serde_yaml::Value>>,
+        remote_host: String,
+        remote_port: u16,
+    ) -> Self {
+        Self {
+            path,
+            options: opts.map(encode_plugin_options).unwrap_or_default(),
+            remote_host,
+            remote_port,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Returns the local port the plugin listens on,
+    /// (re)starting the plugin process if it's not running.
+    pub async fn local_port(&self) -> io::Result<u16> {
+        let mut state = self.state.lock().await;
+
+        if let Some(running) = state.as_mut() {
+            match running.child.try_wait() {
+                Ok(None) => return Ok(running.port),
+                Ok(Some(status)) => {
+                    warn!("sip003 plugin {} exited with {}", self.path, status)
+                }
+                Err(e) => warn!("failed to check sip003 plugin {}: {}", self.path, e),
+            }
+
+            if running.started_at.elapsed() < MIN_RESTART_INTERVAL {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("sip003 plugin {} is crashing, retry later", self.path),
+                ));
+            }
+        }
+
+        let running = self.spawn().await?;
+        let port = running.port;
+        *state = Some(running);
+        Ok(port)
+    }
+
+    async fn spawn(&self) -> io::Result<Running> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+
+        let child = Command::new(&self.path)
+            .env("SS_REMOTE_HOST", &self.remote_host)
+            .env("SS_REMOTE_PORT", self.remote_port.to_string())
+            .env("SS_LOCAL_HOST", "127.0.0.1")
+            .env("SS_LOCAL_PORT", port.to_string())
+            .env("SS_PLUGIN_OPTIONS", &self.options)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|x| {
+                io::Error::new(
+                    x.kind(),
+                    format!("failed to start sip003 plugin {}: {}", self.path, x),
+                )
+            })?;
+
+        // wait for the plugin to be ready, the child is killed when dropped
+        let started_at = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            if started_at.elapsed() >= STARTUP_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("sip003 plugin {} is not listening on {}", self.path, port),
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        info!(
+            "sip003 plugin {} started on 127.0.0.1:{}, pid: {:?}",
+            self.path,
+            port,
+            child.id()
+        );
+
+        Ok(Running {
+            child,
+            port,
+            started_at,
+        })
+    }
+}
+
+/// Decode the `k1=v1;k2=v2` options of a plugin, a bare key is `true`.
+pub fn decode_plugin_options(options: &str) -> HashMap<String, serde_yaml::Value> {
+    let mut opts = HashMap::new();
+    let (mut key, mut value) = (String::new(), None::<String>);
+    let mut flush = |key: &mut String, value: &mut Option<String>| {
+        if !key.is_empty() {
+            let v = match value.take() {
+                Some(v) => serde_yaml::Value::String(v),
+                None => serde_yaml::Value::Bool(true),
+            };
+            opts.insert(std::mem::take(key), v);
+        }
+    };
+
+    let mut chars = options.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => chars.next().unwrap_or('\\'),
+            ';' => {
+                flush(&mut key, &mut value);
+                continue;
+            }
+            '=' if value.is_none() => {
+                value = Some(String::new());
+                continue;
+            }
+            c => c,
+        };
+        match value.as_mut() {
+            Some(v) => v.push(c),
+            None => key.push(c),
+        }
+    }
+    flush(&mut key, &mut value);
+    opts
+}
+
+/// Encode the options as `k1=v1;k2=v2`, escaping `\`, `=` and `;`.
+/// A value of `true` yields a bare key, e.g. `tls` for v2ray-plugin.
+pub fn encode_plugin_options(opts: &HashMap<String, serde_yaml::Value>) -> String {
+    fn escape(s: &str) -> String {
+        s.replace('\\', "\\\\")
+            .replace('=', "\\=")
+            .replace(';', "\\;")
+    }
+
+    let mut keys = opts.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let encoded = keys
+        .into_iter()
+        .filter_map(|k| {
+            let v = match &opts[k] {
+                serde_yaml::Value::Bool(true) => return Some(escape(k)),
+                serde_yaml::Value::Bool(false) | serde_yaml::Value::Null => return None,
+                serde_yaml::Value::String(s) => s.to_owned(),
+                serde_yaml::Value::Number(n) => n.to_string(),
+                v => {
+                    warn!("unsupported sip003 plugin option {}: {:?}", k, v);
+                    return None;
+                }
+            };
+            Some(format!("{}={}", escape(k), escape(&v)))
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+
+    debug!("sip003 plugin options: {}", encoded);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{decode_plugin_options, encode_plugin_options, ExternalPlugin};
+
+    #[test]
+    fn test_encode_plugin_options() {
+        let mut opts = HashMap::new();
+        opts.insert(
+            "mode".to_owned(),
+            serde_yaml::Value::String("websocket".to_owned()),
+        );
+        opts.insert("tls".to_owned(), serde_yaml::Value::Bool(true));
+        opts.insert("mux".to_owned(), serde_yaml::Value::Bool(false));
+        opts.insert(
+            "path".to_owned(),
+            serde_yaml::Value::String("/a;b=c".to_owned()),
+        );
+
+        assert_eq!(
+            encode_plugin_options(&opts),
+            "mode=websocket;path=/a\\;b\\=c;tls"
+        );
+
+        opts.remove("mux");
+        assert_eq!(
+            decode_plugin_options("mode=websocket;path=/a\\;b\\=c;tls"),
+            opts
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_plugin_not_listening() {
+        let plugin = ExternalPlugin::new("true".to_owned(), None, "example.com".to_owned(), 443);
+        assert_eq!(
+            plugin.local_port().await.unwrap_err().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -1,11 +1,30 @@
-use std::{fmt::Debug, pin::Pin};
+use std::{fmt::Debug, io::IoSlice, pin::Pin, task::Poll};
 
+use futures::ready;
 use shadowsocks::ProxyClientStream;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::proxy::AnyStream;
+use crate::{common::io::gather, proxy::AnyStream};
+
+/// the largest payload of an AEAD chunk
+const MAX_CHUNK_PAYLOAD: usize = 0x3fff;
+
+pub struct ShadowSocksStream {
+    inner: ProxyClientStream<AnyStream>,
+    /// the slices of a vectored write, kept until their chunk is written as
+    /// the inner stream counts on the same data when it's polled again
+    gathered: Vec<u8>,
+}
+
+impl ShadowSocksStream {
+    pub fn new(inner: ProxyClientStream<AnyStream>) -> Self {
+        Self {
+            inner,
+            gathered: Vec::new(),
+        }
+    }
+}
 
-pub struct ShadowSocksStream(pub ProxyClientStream<AnyStream>);
 impl Debug for ShadowSocksStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("ShadowSocksStream").finish()
@@ -18,7 +37,7 @@ impl AsyncRead for ShadowSocksStream {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
     }
 }
 
@@ -28,20 +47,39 @@ impl AsyncWrite for ShadowSocksStream {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    /// The slices go in one chunk rather than a chunk each.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        if this.gathered.is_empty() && gather(bufs, MAX_CHUNK_PAYLOAD, &mut this.gathered) == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let res = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.gathered));
+        this.gathered.clear();
+        Poll::Ready(res)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
@@ -1,10 +1,12 @@
 mod datagram;
+mod socks4;
 mod stream;
 
+use crate::app::inbound::rate_limit::ThreadSafeRateLimiter;
 use crate::common::auth::ThreadSafeAuthenticator;
-use crate::proxy::utils::apply_tcp_options;
+use crate::proxy::utils::{apply_tcp_options, proxy_protocol};
 use crate::proxy::{AnyInboundListener, InboundListener};
-use crate::session::{Network, Session, Type};
+use crate::session::{ListenerId, Network, Session, Type};
 use crate::Dispatcher;
 use async_trait::async_trait;
 use std::net::SocketAddr;
@@ -15,6 +17,7 @@ use tracing::warn;
 
 pub use datagram::Socks5UDPCodec;
 
+pub const SOCKS4_VERSION: u8 = 0x04;
 pub const SOCKS5_VERSION: u8 = 0x05;
 
 pub(crate) mod auth_methods {
@@ -26,18 +29,18 @@ pub(crate) mod auth_methods {
 pub(crate) mod response_code {
     pub const SUCCEEDED: u8 = 0x00;
     pub const FAILURE: u8 = 0x01;
-    // pub const RULE_FAILURE: u8 = 0x02;
+    pub const RULE_FAILURE: u8 = 0x02;
     // pub const NETWORK_UNREACHABLE: u8 = 0x03;
     // pub const HOST_UNREACHABLE: u8 = 0x04;
     // pub const CONNECTION_REFUSED: u8 = 0x05;
-    // pub const TTL_EXPIRED: u8 = 0x06;
+    pub const TTL_EXPIRED: u8 = 0x06;
     pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
     // pub const ADDR_TYPE_NOT_SUPPORTED: u8 = 0x08;
 }
 
 pub(crate) mod socks_command {
     pub const CONNECT: u8 = 0x01;
-    // pub const BIND: u8 = 0x02;
+    pub const BIND: u8 = 0x02;
     pub const UDP_ASSOCIATE: u8 = 0x3;
 }
 
@@ -45,6 +48,8 @@ pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    proxy_protocol: bool,
+    rate_limiter: Option<ThreadSafeRateLimiter>,
 }
 
 impl Drop for Listener {
@@ -59,11 +64,15 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        proxy_protocol: bool,
+        rate_limiter: Option<ThreadSafeRateLimiter>,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
             dispatcher,
             authenticator,
+            proxy_protocol,
+            rate_limiter,
         }) as _
     }
 }
@@ -78,26 +87,35 @@ impl InboundListener for Listener {
         false
     }
 
-    async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
-
+    async fn listen_tcp(&self, listener: TcpListener) -> std::io::Result<()> {
         loop {
             let (socket, _) = listener.accept().await?;
 
             let mut socket = apply_tcp_options(socket)?;
 
-            let mut sess = Session {
-                network: Network::Tcp,
-                typ: Type::Socks5,
-                source: socket.peer_addr()?,
-
-                ..Default::default()
-            };
-
             let dispatcher = self.dispatcher.clone();
             let authenticator = self.authenticator.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let limiter = self.rate_limiter.clone();
+            let listener = ListenerId {
+                name: "socks",
+                port: self.addr.port(),
+            };
 
             tokio::spawn(async move {
+                let source = proxy_protocol::source_addr(&mut socket, proxy_protocol).await?;
+                if limiter.as_ref().is_some_and(|x| !x.allow(source.ip())) {
+                    return Ok(());
+                }
+                let mut sess = Session {
+                    network: Network::Tcp,
+                    typ: Type::Socks5,
+                    source,
+                    listener: Some(listener),
+
+                    ..Default::default()
+                };
+
                 handle_tcp(&mut sess, &mut socket, dispatcher, authenticator).await
             });
         }
@@ -0,0 +1,126 @@
+use crate::common::auth::ThreadSafeAuthenticator;
+use crate::common::errors::new_io_error;
+use crate::session::{Session, SocksAddr, Type};
+use crate::Dispatcher;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{instrument, trace};
+
+use super::SOCKS4_VERSION;
+
+mod reply_code {
+    pub const GRANTED: u8 = 0x5a;
+    pub const REJECTED: u8 = 0x5b;
+}
+
+const CONNECT: u8 = 0x01;
+const MAX_FIELD_LEN: usize = 255;
+
+/// Handle a SOCKS4/SOCKS4a request, only CONNECT is supported
+/// https://www.openssh.com/txt/socks4.protocol
+/// https://www.openssh.com/txt/socks4a.protocol
+#[instrument(skip(sess, s, dispatcher, authenticator))]
+pub async fn handle_tcp<'a>(
+    sess: &'a mut Session,
+    s: &'a mut TcpStream,
+    dispatcher: Arc<Dispatcher>,
+    authenticator: ThreadSafeAuthenticator,
+) -> io::Result<()> {
+    /*
+    +----+----+----+----+----+----+----+----+----+----+....+----+
+    | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    +----+----+----+----+----+----+----+----+----+----+....+----+
+       1    1      2              4           variable       1
+     */
+    let mut buf = [0u8; 8];
+    s.read_exact(&mut buf).await?;
+
+    if buf[0] != SOCKS4_VERSION {
+        return Err(new_io_error("unsupported SOCKS version"));
+    }
+
+    let cmd = buf[1];
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+    let _user_id = read_null_terminated(s).await?;
+
+    // SOCKS4a: 0.0.0.x with x != 0 means the domain name follows the user id
+    let octets = ip.octets();
+    let dst = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        SocksAddr::Domain(read_null_terminated(s).await?, port)
+    } else {
+        SocksAddr::Ip(SocketAddr::new(ip.into(), port))
+    };
+
+    // SOCKS4 has no password, so it can't pass the authentication
+    if authenticator.enabled() {
+        reply(s, reply_code::REJECTED).await?;
+        return Err(new_io_error("auth required, which is not supported by SOCKS4"));
+    }
+
+    if cmd != CONNECT {
+        reply(s, reply_code::REJECTED).await?;
+        return Err(new_io_error("unsupported SOCKS4 command"));
+    }
+
+    trace!("Got a SOCKS4 CONNECT request from {}", s.peer_addr()?);
+
+    reply(s, reply_code::GRANTED).await?;
+
+    sess.typ = Type::Socks4;
+    sess.destination = dst;
+
+    dispatcher.dispatch_stream(sess.to_owned(), s).await;
+
+    Ok(())
+}
+
+async fn read_null_terminated<R: AsyncRead + Unpin>(s: &mut R) -> io::Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        let b = s.read_u8().await?;
+        if b == 0 {
+            break;
+        }
+        if buf.len() >= MAX_FIELD_LEN {
+            return Err(new_io_error("malformed SOCKS4 request"));
+        }
+        buf.push(b);
+    }
+    String::from_utf8(buf).map_err(|_| new_io_error("malformed SOCKS4 request"))
+}
+
+/*
++----+----+----+----+----+----+----+----+
+| VN | CD | DSTPORT |      DSTIP        |
++----+----+----+----+----+----+----+----+
+   1    1      2              4
+ */
+async fn reply(s: &mut TcpStream, code: u8) -> io::Result<()> {
+    s.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).await?;
+    if code != reply_code::GRANTED {
+        s.shutdown().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_null_terminated;
+
+    #[tokio::test]
+    async fn test_read_null_terminated() {
+        let mut input: &[u8] = b"user\0example.com\0";
+        assert_eq!(read_null_terminated(&mut input).await.unwrap(), "user");
+        assert_eq!(
+            read_null_terminated(&mut input).await.unwrap(),
+            "example.com"
+        );
+        assert!(read_null_terminated(&mut input).await.is_err());
+    }
+}
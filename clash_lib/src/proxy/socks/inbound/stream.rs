@@ -2,7 +2,9 @@ use crate::common::auth::ThreadSafeAuthenticator;
 use crate::common::errors::new_io_error;
 use crate::proxy::datagram::InboundUdp;
 use crate::proxy::socks::inbound::datagram::Socks5UDPCodec;
-use crate::proxy::socks::inbound::{auth_methods, response_code, socks_command, SOCKS5_VERSION};
+use crate::proxy::socks::inbound::{
+    auth_methods, response_code, socks4, socks_command, SOCKS4_VERSION, SOCKS5_VERSION,
+};
 use crate::proxy::utils::new_udp_socket;
 use crate::session::{Network, Session, SocksAddr, Type};
 use crate::Dispatcher;
@@ -10,11 +12,15 @@ use bytes::{BufMut, BytesMut};
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, str};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream};
 use tokio_util::udp::UdpFramed;
-use tracing::{instrument, trace, warn};
+use tracing::{debug, instrument, trace, warn};
+
+/// how long a BIND waits for the remote to connect
+const BIND_TIMEOUT: Duration = Duration::from_secs(120);
 
 #[instrument(skip(sess, s, dispatcher, authenticator))]
 pub async fn handle_tcp<'a>(
@@ -23,6 +29,12 @@ pub async fn handle_tcp<'a>(
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 ) -> io::Result<()> {
+    let mut ver = [0u8; 1];
+    s.peek(&mut ver).await?;
+    if ver[0] == SOCKS4_VERSION {
+        return socks4::handle_tcp(sess, s, dispatcher, authenticator).await;
+    }
+
     // handshake
     let mut buf = BytesMut::new();
     {
@@ -136,6 +148,42 @@ pub async fn handle_tcp<'a>(
 
             Ok(())
         }
+        socks_command::BIND => {
+            trace!("Got a BIND request from {}", s.peer_addr()?);
+
+            sess.destination = dst;
+            if !dispatcher.allows_bind(sess).await {
+                reply(s, response_code::RULE_FAILURE, SocksAddr::any_ipv4()).await?;
+                return Err(new_io_error("BIND is only allowed to go out directly"));
+            }
+
+            // on the address the client reached us at, which is the one
+            // facing the remote in most setups
+            let listener = TcpListener::bind(SocketAddr::new(s.local_addr()?.ip(), 0)).await?;
+            reply(s, response_code::SUCCEEDED, listener.local_addr()?.into()).await?;
+
+            let remote =
+                match tokio::time::timeout(BIND_TIMEOUT, accept_from(&listener, &sess.destination))
+                    .await
+                {
+                    Ok(Ok(remote)) => remote,
+                    Ok(Err(e)) => {
+                        reply(s, response_code::FAILURE, SocksAddr::any_ipv4()).await?;
+                        return Err(e);
+                    }
+                    Err(_) => {
+                        reply(s, response_code::TTL_EXPIRED, SocksAddr::any_ipv4()).await?;
+                        return Err(new_io_error("no connection to the BIND address in time"));
+                    }
+                };
+            reply(s, response_code::SUCCEEDED, remote.peer_addr()?.into()).await?;
+
+            dispatcher
+                .dispatch_accepted(sess.to_owned(), s, remote)
+                .await;
+
+            Ok(())
+        }
         socks_command::UDP_ASSOCIATE => {
             let udp_addr = SocketAddr::new(s.local_addr()?.ip(), 0);
             let udp_inbound = new_udp_socket(
@@ -168,6 +216,7 @@ pub async fn handle_tcp<'a>(
                 typ: Type::Socks5,
                 packet_mark: None,
                 iface: None,
+                listener: sess.listener,
                 ..Default::default()
             };
 
@@ -210,3 +259,63 @@ pub async fn handle_tcp<'a>(
         }
     }
 }
+
+/// Accept the connection from the host the client expects it from, if the
+/// BIND request named an IP, the others are dropped.
+async fn accept_from(listener: &TcpListener, expected: &SocksAddr) -> io::Result<TcpStream> {
+    loop {
+        let (remote, peer) = listener.accept().await?;
+        match expected {
+            SocksAddr::Ip(addr) if !addr.ip().is_unspecified() && addr.ip() != peer.ip() => {
+                debug!(
+                    "dropping connection to the BIND address from {}, expecting {}",
+                    peer, addr
+                );
+            }
+            _ => return Ok(remote),
+        }
+    }
+}
+
+async fn reply(s: &mut TcpStream, code: u8, bnd: SocksAddr) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    buf.put_u8(SOCKS5_VERSION);
+    buf.put_u8(code);
+    buf.put_u8(0x0);
+    bnd.write_buf(&mut buf);
+    s.write_all(&buf).await?;
+    if code != response_code::SUCCEEDED {
+        s.shutdown().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::session::SocksAddr;
+
+    use super::accept_from;
+
+    #[tokio::test]
+    async fn test_accept_from() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // not from the expected host
+        let expected = SocksAddr::Ip("127.0.0.2:21".parse().unwrap());
+        let accept = tokio::spawn(async move { accept_from(&listener, &expected).await });
+        let _s = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!accept.is_finished());
+        accept.abort();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let expected = SocksAddr::Ip("127.0.0.1:21".parse().unwrap());
+        let accept = tokio::spawn(async move { accept_from(&listener, &expected).await });
+        let _s = TcpStream::connect(addr).await.unwrap();
+        assert!(accept.await.unwrap().is_ok());
+    }
+}
@@ -1,6 +1,8 @@
 mod inbound;
+mod outbound;
 
 pub use inbound::handle_tcp;
 pub use inbound::Listener;
 pub use inbound::Socks5UDPCodec;
-pub use inbound::SOCKS5_VERSION;
+pub use inbound::{SOCKS4_VERSION, SOCKS5_VERSION};
+pub use outbound::{Handler, HandlerOptions};
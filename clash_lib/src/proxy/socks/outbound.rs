@@ -0,0 +1,319 @@
+use std::{io, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::{errors::new_io_error, utils},
+    proxy::{
+        transport::{self, tls::TlsEndpoint, TLSOptions},
+        utils::{connect_local_socket, is_local_socket, new_tcp_stream, RemoteConnector},
+        AnyOutboundHandler, AnyStream, CommonOption, ConnectorType, OutboundHandler, OutboundType,
+    },
+    session::{Session, SocksAddr},
+};
+
+use super::inbound::{auth_methods, response_code, socks_command, SOCKS5_VERSION};
+
+const USER_PASS_VERSION: u8 = 0x01;
+
+pub struct HandlerOptions {
+    pub name: String,
+    pub common_opts: CommonOption,
+    /// a host, or the path of a local socket
+    pub server: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TLSOptions>,
+    /// log in with a user and password of each destination host instead,
+    /// so a Tor SocksPort (IsolateSOCKSAuth) keeps unrelated sites on
+    /// circuits of their own
+    pub isolation: bool,
+}
+
+/// A SOCKS5 outbound, CONNECT only: UDP goes over TCP with `uot`.
+pub struct Handler {
+    opts: HandlerOptions,
+    /// keeps the credentials of the hosts from being guessed
+    secret: [u8; 16],
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
+        let mut secret = [0u8; 16];
+        utils::rand_fill(&mut secret);
+        Arc::new(Self { opts, secret })
+    }
+
+    /// The user and password to log in with for `dst`.
+    fn credentials(&self, dst: &SocksAddr) -> (Option<String>, Option<String>) {
+        if !self.opts.isolation {
+            return (self.opts.user.clone(), self.opts.password.clone());
+        }
+        let digest = utils::md5(&[&self.secret[..], dst.host().as_bytes()].concat());
+        (
+            Some(utils::encode_hex(&digest[..8])),
+            Some(utils::encode_hex(&digest[8..])),
+        )
+    }
+
+    async fn inner_proxy_stream(&self, s: AnyStream, dst: &SocksAddr) -> io::Result<AnyStream> {
+        let mut s = match &self.opts.tls {
+            Some(tls) => transport::tls::wrap_stream(s, tls.clone(), None).await?,
+            None => s,
+        };
+        let (user, password) = self.credentials(dst);
+        handshake(&mut s, dst, user.as_deref(), password.as_deref()).await?;
+        Ok(s)
+    }
+}
+
+async fn handshake(
+    s: &mut AnyStream,
+    dst: &SocksAddr,
+    user: Option<&str>,
+    password: Option<&str>,
+) -> io::Result<()> {
+    let method = if user.is_some() {
+        auth_methods::USER_PASS
+    } else {
+        auth_methods::NO_AUTH
+    };
+    s.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+
+    let mut reply = [0u8; 2];
+    s.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(new_io_error(&format!(
+            "socks5: unsupported version {}",
+            reply[0]
+        )));
+    }
+    match reply[1] {
+        auth_methods::NO_AUTH => {}
+        auth_methods::USER_PASS if user.is_some() => {
+            let user = user.unwrap_or_default().as_bytes();
+            let password = password.unwrap_or_default().as_bytes();
+            if user.len() > 255 || password.len() > 255 {
+                return Err(new_io_error("socks5: username or password too long"));
+            }
+            let mut buf = BytesMut::new();
+            buf.put_u8(USER_PASS_VERSION);
+            buf.put_u8(user.len() as u8);
+            buf.put_slice(user);
+            buf.put_u8(password.len() as u8);
+            buf.put_slice(password);
+            s.write_all(&buf).await?;
+
+            s.read_exact(&mut reply).await?;
+            if reply[1] != response_code::SUCCEEDED {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "socks5 authentication failed",
+                ));
+            }
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "socks5: no acceptable auth method",
+            ))
+        }
+    }
+
+    let mut buf = BytesMut::new();
+    buf.put_slice(&[SOCKS5_VERSION, socks_command::CONNECT, 0x00]);
+    dst.write_buf(&mut buf);
+    s.write_all(&buf).await?;
+
+    let mut reply = [0u8; 3];
+    s.read_exact(&mut reply).await?;
+    if reply[1] != response_code::SUCCEEDED {
+        return Err(new_io_error(&format!(
+            "socks5: connect to {} failed with {}",
+            dst, reply[1]
+        )));
+    }
+    // the address the server bound, not needed
+    SocksAddr::read_from(s).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::Socks5
+    }
+
+    async fn support_udp(&self) -> bool {
+        false
+    }
+
+    fn tls_endpoint(&self) -> Option<TlsEndpoint> {
+        if is_local_socket(&self.opts.server) {
+            return None;
+        }
+        self.opts.tls.as_ref().map(|tls| TlsEndpoint {
+            server: self.opts.server.clone(),
+            port: self.opts.port,
+            iface: self.opts.common_opts.iface.clone(),
+            tls: tls.clone(),
+        })
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let s = if is_local_socket(&self.opts.server) {
+            connect_local_socket(&self.opts.server).await
+        } else {
+            new_tcp_stream(
+                resolver,
+                self.opts.server.as_str(),
+                self.opts.port,
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await
+        }
+        .map_err(|x| {
+            new_io_error(&format!(
+                "dial outbound {}:{}: {}",
+                self.opts.server, self.opts.port, x
+            ))
+        })?;
+
+        let s = self.inner_proxy_stream(s, &sess.destination).await?;
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn connect_datagram(
+        &self,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        Err(new_io_error(
+            "socks5 outbound does not support UDP, enable uot instead",
+        ))
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::Tcp
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        if is_local_socket(&self.opts.server) {
+            return Err(new_io_error(&format!(
+                "{}: the local socket {} can't be dialed through a relay",
+                self.opts.name, self.opts.server
+            )));
+        }
+        let s = connector
+            .connect_stream(
+                resolver,
+                self.opts.server.as_str(),
+                self.opts.port,
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await?;
+
+        let s = self.inner_proxy_stream(s, &sess.destination).await?;
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        proxy::{AnyStream, CommonOption},
+        session::SocksAddr,
+    };
+
+    use super::{handshake, Handler, HandlerOptions};
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+
+            let mut buf = [0u8; 11];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut buf = [0u8; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 0]);
+            let dst = SocksAddr::read_from(&mut server).await.unwrap();
+            server
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            dst
+        });
+
+        let mut client: AnyStream = Box::new(client);
+        let dst = SocksAddr::Domain("example.com".to_owned(), 443);
+        handshake(&mut client, &dst, Some("user"), Some("pass"))
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), dst);
+    }
+
+    #[test]
+    fn test_isolation() {
+        let h = Handler {
+            opts: HandlerOptions {
+                name: "tor".to_owned(),
+                common_opts: CommonOption::default(),
+                server: "127.0.0.1".to_owned(),
+                port: 9050,
+                user: None,
+                password: None,
+                tls: None,
+                isolation: true,
+            },
+            secret: [7; 16],
+        };
+        let a = h.credentials(&SocksAddr::Domain("a.com".to_owned(), 443));
+        assert!(a.0.is_some() && a.1.is_some());
+        assert_eq!(a, h.credentials(&SocksAddr::Domain("a.com".to_owned(), 80)));
+        assert_ne!(
+            a,
+            h.credentials(&SocksAddr::Domain("b.com".to_owned(), 443))
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! SSH, TCP over the `direct-tcpip` channels of a session
+//!
+//! The TCP connections are channels multiplexed over a single SSH
+//! session, which is opened on the first connection and again once closed.
+
+mod stream;
+
+use std::{io, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use russh::client;
+use russh_keys::key::{KeyPair, PublicKey};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::{map_io_error, new_io_error},
+    session::Session,
+};
+
+use self::stream::SshStream;
+
+use super::{
+    utils::new_tcp_stream, AnyOutboundHandler, CommonOption, ConnectorType, OutboundHandler,
+    OutboundType,
+};
+
+/// how long the connection and the authentication may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct HandlerOptions {
+    pub name: String,
+    pub common_opts: CommonOption,
+    pub server: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    /// tried before the password if both are set
+    pub private_key: Option<Arc<KeyPair>>,
+    /// the fingerprints of the keys the server may present
+    pub host_keys: Vec<String>,
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+    session: Mutex<Option<Arc<client::Handle<Client>>>>,
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
+        Arc::new(Self {
+            opts,
+            session: Mutex::new(None),
+        })
+    }
+
+    /// The session dialing a new connection picks its interface, unless the
+    /// proxy has one.
+    async fn get_session(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<Arc<client::Handle<Client>>> {
+        let mut guard = self.session.lock().await;
+        if let Some(session) = guard.as_ref() {
+            if !session.is_closed() {
+                return Ok(session.clone());
+            }
+        }
+
+        let session = tokio::time::timeout(CONNECT_TIMEOUT, self.connect(sess, resolver))
+            .await
+            .map_err(|_| new_io_error("ssh connection timed out"))??;
+        let session = Arc::new(session);
+        *guard = Some(session.clone());
+        Ok(session)
+    }
+
+    async fn connect(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<client::Handle<Client>> {
+        let stream = new_tcp_stream(
+            resolver,
+            self.opts.server.as_str(),
+            self.opts.port,
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .map_err(|x| {
+            new_io_error(&format!(
+                "dial outbound {}:{}: {}",
+                self.opts.server, self.opts.port, x
+            ))
+        })?;
+
+        let config = client::Config {
+            keepalive_interval: Some(KEEP_ALIVE_INTERVAL),
+            ..Default::default()
+        };
+        let client = Client {
+            server: self.opts.server.clone(),
+            host_keys: self.opts.host_keys.clone(),
+        };
+        let mut session = client::connect_stream(Arc::new(config), stream, client)
+            .await
+            .map_err(map_io_error)?;
+
+        let mut authenticated = false;
+        if let Some(key) = &self.opts.private_key {
+            authenticated = session
+                .authenticate_publickey(&self.opts.username, key.clone())
+                .await
+                .map_err(map_io_error)?;
+        }
+        if !authenticated {
+            if let Some(password) = &self.opts.password {
+                authenticated = session
+                    .authenticate_password(&self.opts.username, password)
+                    .await
+                    .map_err(map_io_error)?;
+            }
+        }
+        if !authenticated {
+            return Err(new_io_error(&format!(
+                "ssh authentication of {} to {} failed",
+                self.opts.username, self.opts.server
+            )));
+        }
+        debug!(
+            "ssh session to {}:{} established",
+            self.opts.server, self.opts.port
+        );
+        Ok(session)
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::Ssh
+    }
+
+    async fn support_udp(&self) -> bool {
+        false
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let session = self.get_session(sess, resolver).await?;
+        let channel = session
+            .channel_open_direct_tcpip(
+                sess.destination.host(),
+                sess.destination.port() as u32,
+                sess.source.ip().to_string(),
+                sess.source.port() as u32,
+            )
+            .await
+            .map_err(map_io_error)?;
+
+        let s = ChainedStreamWrapper::new(SshStream::new(channel.into_stream()));
+        s.append_to_chain(self.name()).await;
+        Ok(Box::new(s))
+    }
+
+    async fn connect_datagram(
+        &self,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        Err(new_io_error("SSH outbound handler does not support UDP"))
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::None
+    }
+}
+
+pub(super) struct Client {
+    server: String,
+    host_keys: Vec<String>,
+}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        let fingerprint = server_public_key.fingerprint();
+        let known = self.host_keys.contains(&fingerprint);
+        if !known {
+            warn!(
+                "ssh host key SHA256:{} of {} is not one of the host-key",
+                fingerprint, self.server
+            );
+        }
+        Ok(known)
+    }
+}
+
+/// The SHA256 fingerprint of a host key, given as in `known_hosts`, e.g.
+/// `ssh-ed25519 AAAA...`, or as its fingerprint `SHA256:...`.
+pub fn host_key_fingerprint(key: &str) -> Option<String> {
+    let key = key.trim();
+    if let Some(fingerprint) = key.strip_prefix("SHA256:") {
+        return Some(fingerprint.trim_end_matches('=').to_owned());
+    }
+    let mut fields = key.split_whitespace();
+    let data = match (fields.next(), fields.next()) {
+        (Some(_algo), Some(data)) => data,
+        (Some(data), None) => data,
+        _ => return None,
+    };
+    russh_keys::parse_public_key_base64(data)
+        .ok()
+        .map(|x| x.fingerprint())
+}
+
+#[cfg(test)]
+mod tests {
+    use russh::client::Handler as _;
+
+    use super::{host_key_fingerprint, Client};
+
+    #[test]
+    fn test_host_key_fingerprint() {
+        let fingerprint = "jJ5GqXUlO5/5W2ILRlmeMJ/S7J5tqaQODbPQN9o9GeM";
+        let key = "AAAAC3NzaC1lZDI1NTE5AAAAIFjyfgYO4dgAAM2k/xmTtgqfZJR23M91Rf89zKG+ge+I";
+
+        assert_eq!(
+            host_key_fingerprint(&format!("ssh-ed25519 {}", key)).as_deref(),
+            Some(fingerprint)
+        );
+        assert_eq!(host_key_fingerprint(key).as_deref(), Some(fingerprint));
+        assert_eq!(
+            host_key_fingerprint(&format!("SHA256:{}", fingerprint)).as_deref(),
+            Some(fingerprint)
+        );
+        assert_eq!(host_key_fingerprint("ssh-ed25519 AAAA"), None);
+        assert_eq!(host_key_fingerprint(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_check_server_key() {
+        let key = russh_keys::parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIFjyfgYO4dgAAM2k/xmTtgqfZJR23M91Rf89zKG+ge+I",
+        )
+        .unwrap();
+        let client = |host_keys| Client {
+            server: "10.0.0.14".to_owned(),
+            host_keys,
+        };
+
+        assert!(client(vec![key.fingerprint()])
+            .check_server_key(&key)
+            .await
+            .unwrap());
+        assert!(!client(vec!["other".to_owned()])
+            .check_server_key(&key)
+            .await
+            .unwrap());
+    }
+}
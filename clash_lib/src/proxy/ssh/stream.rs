@@ -0,0 +1,63 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use russh::{client::Msg, ChannelStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A channel of the session. The lock only makes it `Sync`, it's never
+/// contended since the stream is polled through `&mut`.
+pub(super) struct SshStream(Mutex<ChannelStream<Msg>>);
+
+impl SshStream {
+    pub(super) fn new(stream: ChannelStream<Msg>) -> Self {
+        Self(Mutex::new(stream))
+    }
+
+    fn inner(self: Pin<&mut Self>) -> Pin<&mut ChannelStream<Msg>> {
+        Pin::new(
+            self.get_mut()
+                .0
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+impl Debug for SshStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshStream").finish()
+    }
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.inner().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_shutdown(cx)
+    }
+}
@@ -4,6 +4,7 @@ use std::sync::Arc;
 
 use arti_client::{StreamPrefs, TorClientConfig};
 use async_trait::async_trait;
+use tokio::sync::OnceCell;
 
 use crate::{
     app::{
@@ -27,7 +28,8 @@ pub struct HandlerOptions {
 pub struct Handler {
     opts: HandlerOptions,
 
-    client: arti_client::TorClient<tor_rtcompat::PreferredRuntime>,
+    /// created on the first connection, it sets up its state directory
+    client: OnceCell<arti_client::TorClient<tor_rtcompat::PreferredRuntime>>,
 }
 
 impl Handler {
@@ -35,13 +37,23 @@ impl Handler {
     pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
         Arc::new(Self {
             opts,
-            client: arti_client::TorClient::builder()
-                .config(TorClientConfig::default())
-                .bootstrap_behavior(arti_client::BootstrapBehavior::OnDemand)
-                .create_unbootstrapped()
-                .unwrap(),
+            client: OnceCell::new(),
         })
     }
+
+    async fn client(
+        &self,
+    ) -> std::io::Result<&arti_client::TorClient<tor_rtcompat::PreferredRuntime>> {
+        self.client
+            .get_or_try_init(|| async {
+                arti_client::TorClient::builder()
+                    .config(TorClientConfig::default())
+                    .bootstrap_behavior(arti_client::BootstrapBehavior::OnDemand)
+                    .create_unbootstrapped()
+                    .map_err(|x| new_io_error(&x.to_string()))
+            })
+            .await
+    }
 }
 #[async_trait]
 impl OutboundHandler for Handler {
@@ -63,7 +75,8 @@ impl OutboundHandler for Handler {
         _resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedStream> {
         let s = self
-            .client
+            .client()
+            .await?
             .connect_with_prefs(
                 (sess.destination.host(), sess.destination.port()),
                 #[cfg(feature = "onion")]
@@ -0,0 +1,170 @@
+//! The TPROXY inbound of Linux, taking the connections and datagrams the
+//! `TPROXY` target of iptables or nftables diverts to its port, with their
+//! original destinations.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use socket2::SockRef;
+use tokio::{io::Interest, net::TcpListener};
+use tracing::{trace, warn};
+
+use crate::{
+    app::inbound::rate_limit::ThreadSafeRateLimiter,
+    proxy::{
+        datagram::UdpPacket,
+        tun::datagram::TunDatagram,
+        utils::{
+            apply_tcp_options,
+            tproxy::{new_udp_listener, recv_from_orig_dst, set_transparent, ReplySockets},
+        },
+        AnyInboundListener, InboundListener,
+    },
+    session::{ListenerId, Network, Session, SocksAddr, Type},
+    Dispatcher,
+};
+
+pub struct Listener {
+    addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    rate_limiter: Option<ThreadSafeRateLimiter>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!("TProxy inbound listener on {} stopped", self.addr);
+    }
+}
+
+impl Listener {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        addr: SocketAddr,
+        dispatcher: Arc<Dispatcher>,
+        rate_limiter: Option<ThreadSafeRateLimiter>,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            dispatcher,
+            rate_limiter,
+        }) as _
+    }
+
+    fn listener(&self) -> ListenerId {
+        ListenerId {
+            name: "tproxy",
+            port: self.addr.port(),
+        }
+    }
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        true
+    }
+
+    async fn listen_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        // checked as the connections come, so it can be set once listening
+        set_transparent(&SockRef::from(&listener), self.addr.is_ipv6())?;
+
+        loop {
+            let (socket, source) = listener.accept().await?;
+            if self
+                .rate_limiter
+                .as_ref()
+                .is_some_and(|x| !x.allow(source.ip()))
+            {
+                continue;
+            }
+
+            // the local address of a diverted connection is where the
+            // client was heading
+            let destination = socket.local_addr()?;
+            let socket = apply_tcp_options(socket)?;
+
+            let sess = Session {
+                network: Network::Tcp,
+                typ: Type::TProxy,
+                source,
+                destination: destination.into(),
+                listener: Some(self.listener()),
+                ..Default::default()
+            };
+
+            let dispatcher = self.dispatcher.clone();
+            tokio::spawn(async move {
+                dispatcher.dispatch_stream(sess, socket).await;
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> io::Result<()> {
+        let socket = new_udp_listener(self.addr)?;
+        let resolver = self.dispatcher.resolver();
+
+        // dispatcher <-> clients, as the tun inbound does
+        let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+        let (d_tx, d_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+
+        let sess = Session {
+            network: Network::Udp,
+            typ: Type::TProxy,
+            listener: Some(self.listener()),
+            ..Default::default()
+        };
+        let closer = self
+            .dispatcher
+            .dispatch_datagram(sess, Box::new(TunDatagram::new(l_tx, d_rx)));
+
+        // dispatcher -> clients, out of the addresses they sent to
+        let replies = tokio::spawn(async move {
+            let sockets = ReplySockets::new();
+            while let Some(pkt) = l_rx.recv().await {
+                trace!("tproxy <- dispatcher: {:?}", pkt);
+                let from = match pkt.src_addr {
+                    SocksAddr::Ip(addr) => addr,
+                    SocksAddr::Domain(host, port) => {
+                        match resolver.resolve(&host, resolver.fake_ip_enabled()).await {
+                            Ok(Some(ip)) => (ip, port).into(),
+                            _ => {
+                                warn!("failed to resolve domain of tproxy reply: {}", host);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                let to = pkt.dst_addr.must_into_socket_addr();
+                if let Err(e) = sockets.send_to(&pkt.data, from, to).await {
+                    warn!("failed to send tproxy reply from {} to {}: {}", from, to, e);
+                }
+            }
+        });
+
+        // clients -> dispatcher
+        let mut buf = vec![0u8; 65535];
+        let rv = loop {
+            let (n, src, dst) = match socket
+                .async_io(Interest::READABLE, || recv_from_orig_dst(&socket, &mut buf))
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => break Err(e),
+            };
+
+            let pkt = UdpPacket::new(buf[..n].to_vec(), src.into(), dst.into());
+            trace!("tproxy -> dispatcher: {:?}", pkt);
+            if d_tx.send(pkt).await.is_err() {
+                break Ok(());
+            }
+        };
+
+        closer.send(0).ok();
+        replies.abort();
+        rv
+    }
+}
@@ -10,7 +10,7 @@ use http::{Request, Uri, Version};
 use prost::encoding::decode_varint;
 use prost::encoding::encode_varint;
 use tokio::sync::{mpsc, Mutex};
-use tracing::warn;
+use tracing::{debug, warn};
 
 use std::fmt::Debug;
 use std::io;
@@ -18,17 +18,27 @@ use std::io::{Error, ErrorKind};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 #[derive(Clone)]
 pub struct GrpcStreamBuilder {
     pub host: String,
     pub path: http::uri::PathAndQuery,
+    pub ping_interval: Option<Duration>,
 }
 
 impl GrpcStreamBuilder {
-    pub fn new(host: String, path: http::uri::PathAndQuery) -> Self {
-        Self { host, path }
+    pub fn new(
+        host: String,
+        path: http::uri::PathAndQuery,
+        ping_interval: Option<Duration>,
+    ) -> Self {
+        Self {
+            host,
+            path,
+            ping_interval,
+        }
     }
 
     fn req(&self) -> io::Result<Request<()>> {
@@ -50,7 +60,7 @@ impl GrpcStreamBuilder {
     }
 
     pub async fn proxy_stream(&self, stream: AnyStream) -> io::Result<AnyStream> {
-        let (client, h2) = h2::client::Builder::new()
+        let (client, mut h2) = h2::client::Builder::new()
             .initial_connection_window_size(0x7FFFFFFF)
             .initial_window_size(0x7FFFFFFF)
             .initial_max_send_streams(1024)
@@ -62,12 +72,27 @@ impl GrpcStreamBuilder {
 
         let req = self.req()?;
         let (resp, send_stream) = client.send_request(req, false).map_err(map_io_error)?;
+        let ping_pong = self.ping_interval.and_then(|_| h2.ping_pong());
         tokio::spawn(async move {
             if let Err(e) = h2.await {
                 //TODO: collect this somewhere?
                 warn!("http2 got err:{:?}", e);
             }
         });
+        if let (Some(period), Some(mut ping_pong)) = (self.ping_interval, ping_pong) {
+            // stops with the connection, when a ping fails
+            tokio::spawn(async move {
+                let mut ticker =
+                    tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = ping_pong.ping(h2::Ping::opaque()).await {
+                        debug!("grpc keepalive stopped: {}", e);
+                        break;
+                    }
+                }
+            });
+        }
 
         let (init_sender, init_ready) = mpsc::channel(1);
         let recv_stream = Arc::new(Mutex::new(None));
@@ -2,6 +2,8 @@ mod grpc;
 mod h2;
 #[path = "tls.rs"]
 mod internal_tls;
+pub mod quic;
+pub mod utls;
 mod ws;
 
 pub use ws::WebsocketStreamBuilder;
@@ -11,6 +13,8 @@ pub use grpc::GrpcStreamBuilder;
 pub use self::h2::Http2Config;
 
 pub mod tls {
-    pub use super::internal_tls::wrap_stream;
+    pub use super::internal_tls::{
+        client_config, probe, wrap_stream, TlsEndpoint, TlsHandshakeReport,
+    };
 }
 pub use internal_tls::TLSOptions;
@@ -0,0 +1,121 @@
+//! The QUIC client setup shared by the outbounds over QUIC
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+};
+
+use quinn::{
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+    TransportConfig,
+};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::{
+    common::tls::{DummyTlsVerifier, GLOBAL_ROOT_STORE},
+    proxy::utils::{must_bind_socket_on_interface, protect_socket, Interface},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionControl {
+    #[default]
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+impl From<&str> for CongestionControl {
+    fn from(s: &str) -> Self {
+        if s.eq_ignore_ascii_case("cubic") {
+            Self::Cubic
+        } else if s.eq_ignore_ascii_case("new_reno") || s.eq_ignore_ascii_case("newreno") {
+            Self::NewReno
+        } else if s.eq_ignore_ascii_case("bbr") {
+            Self::Bbr
+        } else {
+            tracing::warn!("Unknown congestion controller {s}. Use default controller");
+            Self::default()
+        }
+    }
+}
+
+impl CongestionControl {
+    /// Set the controller of the connections made with `transport`.
+    pub fn apply(self, transport: &mut TransportConfig) {
+        match self {
+            Self::Cubic => {
+                transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Self::NewReno => {
+                transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Self::Bbr => transport.congestion_controller_factory(Arc::new(BbrConfig::default())),
+        };
+    }
+}
+
+/// The TLS 1.3 config of a QUIC client, trusting the global root store.
+pub fn client_crypto(alpn: &[Vec<u8>], skip_cert_verify: bool) -> rustls::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+        .with_no_client_auth();
+    crypto.alpn_protocols = alpn.to_vec();
+    if skip_cert_verify {
+        crypto
+            .dangerous()
+            .set_certificate_verifier(Arc::new(DummyTlsVerifier {}));
+    }
+    crypto
+}
+
+/// Bind a UDP socket of the family of `server`, or IPv4 falling back to
+/// IPv6 when the server is unknown yet, on `iface` if any.
+pub fn bind_socket(server: Option<SocketAddr>, iface: Option<&Interface>) -> io::Result<UdpSocket> {
+    let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+    match server {
+        Some(server) if server.is_ipv6() => bind_on(v6, iface),
+        Some(_) => bind_on(v4, iface),
+        None => bind_on(v4, iface).or_else(|err| bind_on(v6, iface).map_err(|_| err)),
+    }
+}
+
+fn bind_on(addr: SocketAddr, iface: Option<&Interface>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    protect_socket(&socket)?;
+    match iface {
+        // binds to the address of the interface
+        Some(iface @ Interface::IpAddr(_)) => must_bind_socket_on_interface(&socket, iface)?,
+        Some(iface) => {
+            must_bind_socket_on_interface(&socket, iface)?;
+            socket.bind(&addr.into())?;
+        }
+        None => socket.bind(&addr.into())?,
+    }
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CongestionControl;
+
+    #[test]
+    fn test_congestion_control() {
+        assert_eq!(CongestionControl::from("BBR"), CongestionControl::Bbr);
+        assert_eq!(
+            CongestionControl::from("new_reno"),
+            CongestionControl::NewReno
+        );
+        assert_eq!(
+            CongestionControl::from("newreno"),
+            CongestionControl::NewReno
+        );
+        assert_eq!(CongestionControl::from("vegas"), CongestionControl::Cubic);
+    }
+}
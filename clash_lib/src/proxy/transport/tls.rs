@@ -1,31 +1,110 @@
-use std::io;
+use std::{io, sync::Arc, time::Instant};
 
 use serde::Serialize;
 
-use crate::proxy::AnyStream;
+use crate::{
+    common::{
+        errors::new_io_error,
+        tls::{self, CertStoreOptions},
+    },
+    proxy::{utils::Interface, AnyStream},
+};
+
+use super::utls::{self, Fingerprint, RealityOptions};
 
 #[derive(Serialize, Clone)]
 pub struct TLSOptions {
     pub skip_cert_verify: bool,
     pub sni: String,
     pub alpn: Option<Vec<String>>,
+    pub cert_store: CertStoreOptions,
+    /// the browser client hello to mimic, the handshake is done by `utls`
+    /// instead of rustls then, TLS 1.3 only
+    pub fingerprint: Option<Fingerprint>,
+    /// REALITY, over the fingerprint, chrome unless another is set
+    #[serde(skip)]
+    pub reality: Option<RealityOptions>,
 }
 
-pub async fn wrap_stream(
-    stream: AnyStream,
-    opt: TLSOptions,
-    expected_alpn: Option<&str>,
-) -> io::Result<AnyStream> {
-    use std::sync::Arc;
+/// Where and how an outbound does its TLS handshake, for diagnostics
+#[derive(Clone)]
+pub struct TlsEndpoint {
+    pub server: String,
+    pub port: u16,
+    pub iface: Option<Interface>,
+    pub tls: TLSOptions,
+}
 
-    use crate::common::tls::{self, GLOBAL_ROOT_STORE};
+/// The result of a live TLS handshake against a proxy server
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsHandshakeReport {
+    pub sni: String,
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub alpn: Option<String>,
+    /// whether the client hello was the one of the fingerprint rather than
+    /// the rustls one
+    pub fingerprint_applied: bool,
+    pub handshake_ms: u128,
+}
 
+/// Do a TLS handshake over the stream with the options the outbound uses,
+/// and report what was negotiated.
+pub async fn probe(stream: AnyStream, opt: TLSOptions) -> io::Result<TlsHandshakeReport> {
+    if opt.fingerprint.is_some() || opt.reality.is_some() {
+        let start = Instant::now();
+        let s = utls::connect(stream, &opt).await?;
+        let handshake_ms = start.elapsed().as_millis();
+        let session = s.session();
+        return Ok(TlsHandshakeReport {
+            sni: opt.sni,
+            version: Some("TLSv1_3".to_owned()),
+            cipher: session.cipher_suite().map(|x| x.to_owned()),
+            alpn: session
+                .alpn_protocol()
+                .map(|x| String::from_utf8_lossy(x).to_string()),
+            fingerprint_applied: true,
+            handshake_ms,
+        });
+    }
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config(&opt)?));
+    let dns_name = rustls::ServerName::try_from(opt.sni.as_str()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid server name: {}", opt.sni),
+        )
+    })?;
+
+    let start = Instant::now();
+    let s = connector.connect(dns_name, stream).await?;
+    let handshake_ms = start.elapsed().as_millis();
+    let (_, conn) = s.get_ref();
+
+    Ok(TlsHandshakeReport {
+        sni: opt.sni,
+        version: conn.protocol_version().map(|x| format!("{:?}", x)),
+        cipher: conn
+            .negotiated_cipher_suite()
+            .map(|x| format!("{:?}", x.suite())),
+        alpn: conn
+            .alpn_protocol()
+            .map(|x| String::from_utf8_lossy(x).to_string()),
+        fingerprint_applied: false,
+        handshake_ms,
+    })
+}
+
+pub fn client_config(opt: &TLSOptions) -> io::Result<rustls::ClientConfig> {
+    let roots = tls::cert_store(&opt.cert_store).map_err(|x| new_io_error(&x.to_string()))?;
     let mut tls_config = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+        .with_root_certificates(roots)
         .with_no_client_auth();
     tls_config.alpn_protocols = opt
         .alpn
+        .clone()
         .unwrap_or_default()
         .into_iter()
         .map(|x| x.as_bytes().to_vec())
@@ -38,7 +117,32 @@ pub async fn wrap_stream(
     }
 
     tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    Ok(tls_config)
+}
+
+pub async fn wrap_stream(
+    stream: AnyStream,
+    opt: TLSOptions,
+    expected_alpn: Option<&str>,
+) -> io::Result<AnyStream> {
+    if opt.fingerprint.is_some() || opt.reality.is_some() {
+        let s = utls::connect(stream, &opt).await?;
+        if let Some(expected_alpn) = expected_alpn {
+            if s.session().alpn_protocol() != Some(expected_alpn.as_bytes()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "unexpected alpn protocol: {:?}, expected: {:?}",
+                        s.session().alpn_protocol(),
+                        expected_alpn
+                    ),
+                ));
+            }
+        }
+        return Ok(Box::new(s));
+    }
 
+    let tls_config = client_config(&opt)?;
     let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
     let dns_name = rustls::ServerName::try_from(opt.sni.as_str())
         .unwrap_or_else(|_| panic!("invalid server name: {}", opt.sni));
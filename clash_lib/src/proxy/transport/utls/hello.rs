@@ -0,0 +1,391 @@
+//! The client hellos of the browsers, after the uTLS parrots.
+//!
+//! Only X25519 is offered a key share, and the extensions the handshake
+//! can't follow through are left out, e.g. the zlib certificate
+//! compression of Safari.
+
+use std::{net::IpAddr, str::FromStr};
+
+use rand::{seq::SliceRandom, Rng};
+use serde::Serialize;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_STATUS_REQUEST: u16 = 5;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+pub const EXT_ALPN: u16 = 16;
+const EXT_SCT: u16 = 18;
+const EXT_PADDING: u16 = 21;
+const EXT_EXTENDED_MASTER_SECRET: u16 = 23;
+const EXT_COMPRESS_CERTIFICATE: u16 = 27;
+const EXT_RECORD_SIZE_LIMIT: u16 = 28;
+const EXT_DELEGATED_CREDENTIALS: u16 = 34;
+const EXT_SESSION_TICKET: u16 = 35;
+pub const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 45;
+pub const EXT_KEY_SHARE: u16 = 51;
+pub const EXT_APPLICATION_SETTINGS: u16 = 17513;
+const EXT_ECH: u16 = 0xfe0d;
+const EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+
+pub const X25519: u16 = 0x001d;
+const SECP256R1: u16 = 0x0017;
+const SECP384R1: u16 = 0x0018;
+const SECP521R1: u16 = 0x0019;
+const FFDHE2048: u16 = 0x0100;
+const FFDHE3072: u16 = 0x0101;
+
+pub const TLS13: u16 = 0x0304;
+const TLS12: u16 = 0x0303;
+const TLS11: u16 = 0x0302;
+const TLS10: u16 = 0x0301;
+
+pub const CERT_COMPRESSION_BROTLI: u16 = 2;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+/// The browser whose client hello is mimicked, `client-fingerprint`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fingerprint {
+    Chrome,
+    Firefox,
+    Safari,
+    /// one of the others, picked for each connection
+    Random,
+}
+
+impl FromStr for Fingerprint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chrome" | "edge" | "360" | "qq" | "android" => Ok(Self::Chrome),
+            "firefox" => Ok(Self::Firefox),
+            "safari" | "ios" => Ok(Self::Safari),
+            "random" | "randomized" => Ok(Self::Random),
+            _ => Err(format!("unknown client-fingerprint {}", s)),
+        }
+    }
+}
+
+/// What goes in the client hello besides the fingerprint.
+pub struct Hello<'a> {
+    pub random: &'a [u8; 32],
+    pub session_id: &'a [u8; 32],
+    pub server_name: &'a str,
+    pub alpn: &'a [Vec<u8>],
+    pub key_share: &'a [u8; 32],
+}
+
+/// The GREASE values of a hello, RFC 8701.
+struct Grease {
+    cipher: u16,
+    group: u16,
+    version: u16,
+    ext1: u16,
+    ext2: u16,
+}
+
+impl Grease {
+    fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut value = || {
+            let x: u16 = rng.gen_range(0..16);
+            0x0a0a | x << 12 | x << 4
+        };
+        let (cipher, group, version, ext1) = (value(), value(), value(), value());
+        let mut ext2 = value();
+        // the two extensions can't be the same
+        if ext2 == ext1 {
+            ext2 ^= 0x1010;
+        }
+        Self {
+            cipher,
+            group,
+            version,
+            ext1,
+            ext2,
+        }
+    }
+}
+
+type Extension = (u16, Vec<u8>);
+
+/// The client hello handshake message.
+pub fn client_hello(fingerprint: Fingerprint, hello: &Hello) -> Vec<u8> {
+    let fingerprint = match fingerprint {
+        Fingerprint::Random => *[
+            Fingerprint::Chrome,
+            Fingerprint::Firefox,
+            Fingerprint::Safari,
+        ]
+        .choose(&mut rand::thread_rng())
+        .unwrap(),
+        x => x,
+    };
+    let g = Grease::new();
+
+    let (ciphers, extensions, padding) = match fingerprint {
+        Fingerprint::Chrome | Fingerprint::Random => (chrome_ciphers(&g), chrome(&g, hello), false),
+        Fingerprint::Firefox => (FIREFOX_CIPHERS.to_vec(), firefox(hello), false),
+        Fingerprint::Safari => (safari_ciphers(&g), safari(&g, hello), true),
+    };
+
+    let mut body = vec![];
+    body.extend_from_slice(&TLS12.to_be_bytes());
+    body.extend_from_slice(hello.random);
+    body.push(32);
+    body.extend_from_slice(hello.session_id);
+    put_u16s(&mut body, &ciphers, 2);
+    // the null compression only
+    body.extend_from_slice(&[1, 0]);
+
+    let mut exts = vec![];
+    for (typ, data) in &extensions {
+        put_extension(&mut exts, *typ, data);
+    }
+    if padding {
+        // BoringSSL pads the hellos between 256 and 511 bytes to 512
+        let len = 4 + body.len() + 2 + exts.len();
+        if len > 0xff && len < 0x200 {
+            let n = match 0x200 - len {
+                x if x >= 5 => x - 4,
+                _ => 1,
+            };
+            put_extension(&mut exts, EXT_PADDING, &vec![0; n]);
+        }
+    }
+    put_vec(&mut body, &exts, 2);
+
+    let mut msg = vec![HANDSHAKE_CLIENT_HELLO];
+    put_vec(&mut msg, &body, 3);
+    msg
+}
+
+fn chrome_ciphers(g: &Grease) -> Vec<u16> {
+    vec![
+        g.cipher, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+        0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ]
+}
+
+const FIREFOX_CIPHERS: &[u16] = &[
+    0x1301, 0x1303, 0x1302, 0xc02b, 0xc02f, 0xcca9, 0xcca8, 0xc02c, 0xc030, 0xc00a, 0xc009, 0xc013,
+    0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+];
+
+fn safari_ciphers(g: &Grease) -> Vec<u16> {
+    vec![
+        g.cipher, 0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b, 0xcca9, 0xc030, 0xc02f, 0xcca8, 0xc00a,
+        0xc009, 0xc014, 0xc013, 0x009d, 0x009c, 0x0035, 0x002f, 0xc008, 0xc012, 0x000a,
+    ]
+}
+
+/// Chrome 120, which shuffles its extensions but the GREASE ones.
+fn chrome(g: &Grease, hello: &Hello) -> Vec<Extension> {
+    let mut exts: Vec<Extension> = [
+        server_name(hello),
+        Some((EXT_EXTENDED_MASTER_SECRET, vec![])),
+        Some((EXT_RENEGOTIATION_INFO, vec![0])),
+        Some(u16s(
+            EXT_SUPPORTED_GROUPS,
+            &[g.group, X25519, SECP256R1, SECP384R1],
+            2,
+        )),
+        Some((EXT_EC_POINT_FORMATS, vec![1, 0])),
+        Some((EXT_SESSION_TICKET, vec![])),
+        alpn(hello.alpn),
+        Some((EXT_STATUS_REQUEST, vec![1, 0, 0, 0, 0])),
+        Some(u16s(
+            EXT_SIGNATURE_ALGORITHMS,
+            &[
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+            ],
+            2,
+        )),
+        Some((EXT_SCT, vec![])),
+        Some(key_share(Some(g.group), hello.key_share)),
+        Some((EXT_PSK_KEY_EXCHANGE_MODES, vec![1, 1])),
+        Some(u16s(EXT_SUPPORTED_VERSIONS, &[g.version, TLS13, TLS12], 1)),
+        Some(u16s(
+            EXT_COMPRESS_CERTIFICATE,
+            &[CERT_COMPRESSION_BROTLI],
+            1,
+        )),
+        application_settings(hello.alpn),
+        Some(grease_ech()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    exts.shuffle(&mut rand::thread_rng());
+    exts.insert(0, (g.ext1, vec![]));
+    exts.push((g.ext2, vec![0]));
+    exts
+}
+
+/// Firefox 120.
+fn firefox(hello: &Hello) -> Vec<Extension> {
+    [
+        server_name(hello),
+        Some((EXT_EXTENDED_MASTER_SECRET, vec![])),
+        Some((EXT_RENEGOTIATION_INFO, vec![0])),
+        Some(u16s(
+            EXT_SUPPORTED_GROUPS,
+            &[
+                X25519, SECP256R1, SECP384R1, SECP521R1, FFDHE2048, FFDHE3072,
+            ],
+            2,
+        )),
+        Some((EXT_EC_POINT_FORMATS, vec![1, 0])),
+        Some((EXT_SESSION_TICKET, vec![])),
+        alpn(hello.alpn),
+        Some((EXT_STATUS_REQUEST, vec![1, 0, 0, 0, 0])),
+        Some(u16s(
+            EXT_DELEGATED_CREDENTIALS,
+            &[0x0403, 0x0503, 0x0603, 0x0203],
+            2,
+        )),
+        Some(key_share(None, hello.key_share)),
+        Some(u16s(EXT_SUPPORTED_VERSIONS, &[TLS13, TLS12], 1)),
+        Some(u16s(
+            EXT_SIGNATURE_ALGORITHMS,
+            &[
+                0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601, 0x0203,
+                0x0201,
+            ],
+            2,
+        )),
+        Some((EXT_PSK_KEY_EXCHANGE_MODES, vec![1, 1])),
+        Some((EXT_RECORD_SIZE_LIMIT, vec![0x40, 0x01])),
+        Some(grease_ech()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Safari 16, also iOS.
+fn safari(g: &Grease, hello: &Hello) -> Vec<Extension> {
+    [
+        Some((g.ext1, vec![])),
+        server_name(hello),
+        Some((EXT_EXTENDED_MASTER_SECRET, vec![])),
+        Some((EXT_RENEGOTIATION_INFO, vec![0])),
+        Some(u16s(
+            EXT_SUPPORTED_GROUPS,
+            &[g.group, X25519, SECP256R1, SECP384R1, SECP521R1],
+            2,
+        )),
+        Some((EXT_EC_POINT_FORMATS, vec![1, 0])),
+        alpn(hello.alpn),
+        Some((EXT_STATUS_REQUEST, vec![1, 0, 0, 0, 0])),
+        Some(u16s(
+            EXT_SIGNATURE_ALGORITHMS,
+            &[
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0203, 0x0805, 0x0805, 0x0501, 0x0806, 0x0601,
+                0x0201,
+            ],
+            2,
+        )),
+        Some((EXT_SCT, vec![])),
+        Some(key_share(Some(g.group), hello.key_share)),
+        Some((EXT_PSK_KEY_EXCHANGE_MODES, vec![1, 1])),
+        Some(u16s(
+            EXT_SUPPORTED_VERSIONS,
+            &[g.version, TLS13, TLS12, TLS11, TLS10],
+            1,
+        )),
+        Some((g.ext2, vec![0])),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Left out for an IP address.
+fn server_name(hello: &Hello) -> Option<Extension> {
+    if hello.server_name.is_empty() || hello.server_name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    let mut name = vec![0];
+    put_vec(&mut name, hello.server_name.as_bytes(), 2);
+    let mut data = vec![];
+    put_vec(&mut data, &name, 2);
+    Some((EXT_SERVER_NAME, data))
+}
+
+fn alpn(protocols: &[Vec<u8>]) -> Option<Extension> {
+    if protocols.is_empty() {
+        return None;
+    }
+    let mut list = vec![];
+    for p in protocols {
+        put_vec(&mut list, p, 1);
+    }
+    let mut data = vec![];
+    put_vec(&mut data, &list, 2);
+    Some((EXT_ALPN, data))
+}
+
+/// ALPS of Chrome, for HTTP/2 only.
+fn application_settings(protocols: &[Vec<u8>]) -> Option<Extension> {
+    if !protocols.iter().any(|x| x == b"h2") {
+        return None;
+    }
+    let mut list = vec![];
+    put_vec(&mut list, b"h2", 1);
+    let mut data = vec![];
+    put_vec(&mut data, &list, 2);
+    Some((EXT_APPLICATION_SETTINGS, data))
+}
+
+fn key_share(grease: Option<u16>, key: &[u8; 32]) -> Extension {
+    let mut shares = vec![];
+    if let Some(grease) = grease {
+        shares.extend_from_slice(&grease.to_be_bytes());
+        put_vec(&mut shares, &[0], 2);
+    }
+    shares.extend_from_slice(&X25519.to_be_bytes());
+    put_vec(&mut shares, key, 2);
+    let mut data = vec![];
+    put_vec(&mut data, &shares, 2);
+    (EXT_KEY_SHARE, data)
+}
+
+/// The GREASE encrypted client hello of BoringSSL: an outer hello for a
+/// random config, HKDF-SHA256 and AES-128-GCM.
+fn grease_ech() -> Extension {
+    let mut rng = rand::thread_rng();
+    let mut data = vec![0, 0, 1, 0, 1, rng.gen()];
+    let enc: [u8; 32] = rng.gen();
+    put_vec(&mut data, &enc, 2);
+    let mut payload = vec![0u8; *[128, 160, 192, 224].choose(&mut rng).unwrap() + 16];
+    rng.fill(&mut payload[..]);
+    put_vec(&mut data, &payload, 2);
+    (EXT_ECH, data)
+}
+
+fn u16s(typ: u16, values: &[u16], len_bytes: usize) -> Extension {
+    let mut data = vec![];
+    put_u16s(&mut data, values, len_bytes);
+    (typ, data)
+}
+
+fn put_u16s(buf: &mut Vec<u8>, values: &[u16], len_bytes: usize) {
+    let data: Vec<u8> = values.iter().flat_map(|x| x.to_be_bytes()).collect();
+    put_vec(buf, &data, len_bytes);
+}
+
+fn put_extension(buf: &mut Vec<u8>, typ: u16, data: &[u8]) {
+    buf.extend_from_slice(&typ.to_be_bytes());
+    put_vec(buf, data, 2);
+}
+
+/// The data after its big endian length of `len_bytes` bytes.
+pub fn put_vec(buf: &mut Vec<u8>, data: &[u8], len_bytes: usize) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes()[4 - len_bytes..]);
+    buf.extend_from_slice(data);
+}
@@ -0,0 +1,258 @@
+//! TLS with the client hello of a browser, `client-fingerprint`, and
+//! REALITY on top of it.
+//!
+//! rustls doesn't let the client hello be shaped, so the handshake is done
+//! here: TLS 1.3 only, with X25519 and the TLS 1.3 cipher suites, which is
+//! what the browsers end up with against any server worth mimicking. The
+//! certificates are still checked with rustls.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::proxy::AnyStream;
+
+use super::TLSOptions;
+
+mod hello;
+mod reality;
+mod session;
+
+pub use hello::Fingerprint;
+pub use reality::RealityOptions;
+pub use session::ClientSession;
+
+const READ_BUF_SIZE: usize = 16 * 1024 + 256 + 5;
+
+/// Do the handshake over the stream.
+pub async fn connect(mut io: AnyStream, opt: &TLSOptions) -> io::Result<UtlsStream> {
+    let mut session = ClientSession::new(opt)?;
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    while session.is_handshaking() {
+        if session.wants_write() {
+            io.write_all(&session.take_tls()).await?;
+            continue;
+        }
+        let n = io.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        session.feed(&buf[..n])?;
+    }
+    // the finished of the client
+    io.write_all(&session.take_tls()).await?;
+
+    Ok(UtlsStream {
+        io,
+        session,
+        buf,
+        out: BytesMut::new(),
+    })
+}
+
+#[derive(Debug)]
+pub struct UtlsStream {
+    io: AnyStream,
+    session: ClientSession,
+    buf: Vec<u8>,
+    /// the TLS bytes to write out
+    out: BytesMut,
+}
+
+impl UtlsStream {
+    pub fn session(&self) -> &ClientSession {
+        &self.session
+    }
+
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for UtlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            // the reader may never write, so the responses queued below go
+            // out from here; what doesn't fit waits for the next call
+            if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            match this.session.read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            let mut rb = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut rb))?;
+            let n = rb.filled().len();
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            this.session.feed(&this.buf[..n])?;
+            // e.g. the key updates
+            if this.session.wants_write() {
+                this.out.extend_from_slice(&this.session.take_tls());
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UtlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_write_out(cx))?;
+        let n = buf.len().min(16 * 1024);
+        this.session.write_all(&buf[..n])?;
+        this.out.extend_from_slice(&this.session.take_tls());
+
+        // the data is taken, the records go out now or on the next call
+        if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_out(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.session.send_close_notify();
+        this.out.extend_from_slice(&this.session.take_tls());
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{connect, Fingerprint};
+    use crate::{
+        common::tls::{CertStoreOptions, TrustRoots},
+        proxy::transport::TLSOptions,
+    };
+
+    /// A rustls server for example.org, and the PEM of its CA.
+    fn server() -> (tokio_rustls::TlsAcceptor, String) {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let cert = Certificate::from_params(CertificateParams::new(vec!["example.org".to_owned()]))
+            .unwrap();
+
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    cert.serialize_der_with_signer(&ca).unwrap(),
+                )],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        (
+            tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+            ca.serialize_pem().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        for fingerprint in [
+            Fingerprint::Chrome,
+            Fingerprint::Firefox,
+            Fingerprint::Safari,
+            Fingerprint::Random,
+        ] {
+            let (acceptor, ca) = server();
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let echo = tokio::spawn(async move {
+                let mut s = acceptor.accept(server).await.unwrap();
+                let mut buf = vec![0u8; 100 * 1024];
+                let n = s.read_exact(&mut buf).await.unwrap();
+                s.write_all(&buf[..n]).await.unwrap();
+                s.shutdown().await.unwrap();
+            });
+
+            let opt = TLSOptions {
+                skip_cert_verify: false,
+                sni: "example.org".to_owned(),
+                alpn: Some(vec!["h2".to_owned(), "http/1.1".to_owned()]),
+                cert_store: CertStoreOptions {
+                    trust: TrustRoots::Bundled,
+                    ca: None,
+                    ca_str: Some(ca),
+                },
+                fingerprint: Some(fingerprint),
+                reality: None,
+            };
+            let mut s = connect(Box::new(client), &opt).await.unwrap();
+            assert_eq!(s.session().alpn_protocol(), Some(&b"http/1.1"[..]));
+            assert!(s.session().cipher_suite().is_some());
+
+            // more than a record
+            let data: Vec<u8> = (0..100 * 1024).map(|x| x as u8).collect();
+            s.write_all(&data).await.unwrap();
+            let mut echoed = vec![];
+            s.read_to_end(&mut echoed).await.unwrap();
+            assert_eq!(echoed, data);
+            echo.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_untrusted() {
+        let (acceptor, _) = server();
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { acceptor.accept(server).await });
+
+        let opt = TLSOptions {
+            skip_cert_verify: false,
+            sni: "example.org".to_owned(),
+            alpn: None,
+            cert_store: CertStoreOptions {
+                trust: TrustRoots::Bundled,
+                ca: None,
+                ca_str: None,
+            },
+            fingerprint: Some(Fingerprint::Chrome),
+            reality: None,
+        };
+        assert!(connect(Box::new(client), &opt).await.is_err());
+    }
+}
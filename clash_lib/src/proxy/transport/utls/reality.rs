@@ -0,0 +1,226 @@
+//! REALITY, where the server passes the client hellos it can't
+//! authenticate through to a real site.
+//!
+//! The client seals a short id and the time in the session id of its hello,
+//! under a key agreed between its X25519 key share and the public key of the
+//! server:
+//!
+//! ```text
+//! auth key:   HKDF-SHA256(X25519(client key share, server key),
+//!                         salt = random[..20], info = "REALITY")
+//! session id: AES-256-GCM(auth key, nonce = random[20..],
+//!                         | version [3] | 0 | unix time u32 | short id [8] |,
+//!                         aad = the client hello with a zero session id)
+//! ```
+//!
+//! The server answers with a certificate of its own whose ed25519 public
+//! key, HMAC-SHA512 with the auth key, is the signature.
+
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::common::{crypto::aes_gcm_encrypt, errors::new_io_error, utils::decode_hex};
+
+/// the Xray version the client claims
+const VERSION: [u8; 3] = [1, 8, 0];
+
+/// where the session id is in the client hello message
+const SESSION_ID: std::ops::Range<usize> = 39..71;
+const RANDOM: std::ops::Range<usize> = 6..38;
+
+const ED25519_OID: &[u8] = &[0x2b, 0x65, 0x70];
+
+#[derive(Debug, Clone)]
+pub struct RealityOptions {
+    pub public_key: [u8; 32],
+    pub short_id: [u8; 8],
+}
+
+impl RealityOptions {
+    /// The public key in URL safe base64, the short id in up to 16 hex
+    /// digits.
+    pub fn new(public_key: &str, short_id: Option<&str>) -> Result<Self, String> {
+        let public_key = URL_SAFE_NO_PAD
+            .decode(public_key.trim_end_matches('='))
+            .ok()
+            .and_then(|x| <[u8; 32]>::try_from(x).ok())
+            .ok_or_else(|| format!("invalid REALITY public-key {}", public_key))?;
+
+        let mut id = [0u8; 8];
+        let short_id = short_id.unwrap_or_default();
+        let decoded = (short_id.len() % 2 == 0 && short_id.len() <= 16 && short_id.is_ascii())
+            .then(|| decode_hex(short_id).ok())
+            .flatten()
+            .ok_or_else(|| format!("invalid REALITY short-id {}", short_id))?;
+        id[..decoded.len()].copy_from_slice(&decoded);
+
+        Ok(Self {
+            public_key,
+            short_id: id,
+        })
+    }
+}
+
+/// Seal the session id of the client hello, and return the auth key.
+pub fn seal(hello: &mut [u8], secret: &StaticSecret, opt: &RealityOptions) -> io::Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(&PublicKey::from(opt.public_key));
+    let random = hello[RANDOM].to_vec();
+
+    let mut auth_key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&random[..20]), shared.as_bytes())
+        .expand(b"REALITY", &mut auth_key)
+        .map_err(|x| new_io_error(&x.to_string()))?;
+
+    let mut plain = [0u8; 16];
+    plain[..3].copy_from_slice(&VERSION);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    plain[4..8].copy_from_slice(&(now as u32).to_be_bytes());
+    plain[8..].copy_from_slice(&opt.short_id);
+
+    hello[SESSION_ID].fill(0);
+    let sealed = aes_gcm_encrypt(&auth_key, &random[20..], &plain, Some(hello))
+        .map_err(|x| new_io_error(&x.to_string()))?;
+    hello[SESSION_ID].copy_from_slice(&sealed);
+    Ok(auth_key)
+}
+
+/// Whether the certificate is the one of the REALITY server.
+pub fn verify(auth_key: &[u8], cert: &[u8]) -> bool {
+    let Some((public_key, signature)) = ed25519_key_and_signature(cert) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha512>::new_from_slice(auth_key).expect("hmac takes any key");
+    mac.update(public_key);
+    mac.verify_slice(signature).is_ok()
+}
+
+/// The ed25519 public key and the signature of a DER certificate.
+fn ed25519_key_and_signature(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (cert, _) = der(cert, 0x30)?;
+    let (tbs, rest) = der(cert, 0x30)?;
+    let (_, rest) = der(rest, 0x30)?;
+    let (signature, _) = der(rest, 0x03)?;
+
+    // version, serial number, signature, issuer, validity and subject
+    let mut rest = tbs;
+    if rest.first() == Some(&0xa0) {
+        rest = der(rest, 0xa0)?.1;
+    }
+    rest = der(rest, 0x02)?.1;
+    for _ in 0..4 {
+        rest = der(rest, 0x30)?.1;
+    }
+    let (spki, _) = der(rest, 0x30)?;
+    let (algorithm, rest) = der(spki, 0x30)?;
+    let (oid, _) = der(algorithm, 0x06)?;
+    let (public_key, _) = der(rest, 0x03)?;
+    if oid != ED25519_OID {
+        return None;
+    }
+    // past the unused bits of the bit strings
+    Some((public_key.get(1..)?, signature.get(1..)?))
+}
+
+/// The content of the DER element with the tag, and what follows it.
+fn der(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    if *input.first()? != tag {
+        return None;
+    }
+    let (len, header) = match *input.get(1)? {
+        x if x < 0x80 => (x as usize, 2),
+        x @ 0x81..=0x83 => {
+            let n = (x & 0x7f) as usize;
+            let len = input
+                .get(2..2 + n)?
+                .iter()
+                .fold(0, |acc, x| acc << 8 | *x as usize);
+            (len, 2 + n)
+        }
+        _ => return None,
+    };
+    let end = header.checked_add(len)?;
+    Some((input.get(header..end)?, &input[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use hkdf::Hkdf;
+    use hmac::{Hmac, Mac};
+    use sha2::{Sha256, Sha512};
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{seal, verify, RealityOptions, RANDOM, SESSION_ID};
+    use crate::common::crypto::aes_gcm_decrypt;
+
+    #[test]
+    fn test_options() {
+        let opt = RealityOptions::new("Tnct1iynOqQeQIhf0zS4oTURvaIobLX7troSjmPbBVI", Some("0123"))
+            .unwrap();
+        assert_eq!(opt.short_id, [1, 0x23, 0, 0, 0, 0, 0, 0]);
+        assert!(RealityOptions::new("Tnct1iyn", None).is_err());
+        assert!(
+            RealityOptions::new("Tnct1iynOqQeQIhf0zS4oTURvaIobLX7troSjmPbBVI", Some("012"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_seal() {
+        let server = StaticSecret::from([7u8; 32]);
+        let opt = RealityOptions {
+            public_key: PublicKey::from(&server).to_bytes(),
+            short_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let client = StaticSecret::from([9u8; 32]);
+
+        let mut hello = vec![0u8; 100];
+        hello[38] = 32;
+        hello[RANDOM].copy_from_slice(&[0x42; 32]);
+        hello[SESSION_ID].copy_from_slice(&[0xff; 32]);
+        let auth_key = seal(&mut hello, &client, &opt).unwrap();
+
+        // the server side
+        let shared = server.diffie_hellman(&PublicKey::from(&client));
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&hello[6..26]), shared.as_bytes())
+            .expand(b"REALITY", &mut key)
+            .unwrap();
+        assert_eq!(key, auth_key);
+
+        let session_id = hello[SESSION_ID].to_vec();
+        hello[SESSION_ID].fill(0);
+        let plain = aes_gcm_decrypt(&key, &hello[26..38], &session_id, Some(&hello)).unwrap();
+        assert_eq!(&plain[..4], &[1, 8, 0, 0]);
+        assert_eq!(&plain[8..], &opt.short_id);
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut params = rcgen::CertificateParams::new(vec!["example.org".to_owned()]);
+        params.alg = &rcgen::PKCS_ED25519;
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let mut der = cert.serialize_der().unwrap();
+
+        let auth_key = [5u8; 32];
+        assert!(!verify(&auth_key, &der));
+
+        // the signature is the last 64 bytes
+        let mut mac = Hmac::<Sha512>::new_from_slice(&auth_key).unwrap();
+        mac.update(cert.get_key_pair().public_key_raw());
+        let len = der.len();
+        der[len - 64..].copy_from_slice(&mac.finalize().into_bytes());
+        assert!(verify(&auth_key, &der));
+        assert!(!verify(&[6u8; 32], &der));
+    }
+}
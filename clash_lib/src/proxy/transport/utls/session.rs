@@ -0,0 +1,827 @@
+//! A TLS 1.3 client session, sans IO like the rustls ones: the records
+//! received are fed in, the records to send are taken out.
+
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rustls::{
+    client::{ServerCertVerifier, WebPkiVerifier},
+    internal::msgs::codec::{Codec, Reader},
+    Certificate, DigitallySignedStruct, RootCertStore, ServerName,
+};
+use sha2::{Digest, Sha256, Sha384};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    common::{crypto::AeadCipherHelper, tls, utils::rand_fill},
+    proxy::transport::TLSOptions,
+};
+
+use super::{
+    hello::{
+        self, client_hello, Fingerprint, Hello, CERT_COMPRESSION_BROTLI, EXT_ALPN,
+        EXT_APPLICATION_SETTINGS, EXT_KEY_SHARE, EXT_SUPPORTED_VERSIONS, TLS13, X25519,
+    },
+    reality,
+};
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_ALERT: u8 = 21;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_NEW_SESSION_TICKET: u8 = 4;
+const HANDSHAKE_ENCRYPTED_EXTENSIONS: u8 = 8;
+const HANDSHAKE_CERTIFICATE: u8 = 11;
+const HANDSHAKE_CERTIFICATE_REQUEST: u8 = 13;
+const HANDSHAKE_CERTIFICATE_VERIFY: u8 = 15;
+const HANDSHAKE_FINISHED: u8 = 20;
+const HANDSHAKE_KEY_UPDATE: u8 = 24;
+const HANDSHAKE_COMPRESSED_CERTIFICATE: u8 = 25;
+
+const MAX_PLAINTEXT: usize = 1 << 14;
+const MAX_CIPHERTEXT: usize = MAX_PLAINTEXT + 256;
+const MAX_HANDSHAKE: usize = 1 << 17;
+const TAG_LEN: usize = 16;
+
+/// the random of a server hello asking for another client hello
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// The TLS 1.3 cipher suites, which all of the fingerprints offer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Suite {
+    Aes128GcmSha256,
+    Aes256GcmSha384,
+    Chacha20Poly1305Sha256,
+}
+
+impl Suite {
+    fn from_u16(x: u16) -> Option<Self> {
+        match x {
+            0x1301 => Some(Self::Aes128GcmSha256),
+            0x1302 => Some(Self::Aes256GcmSha384),
+            0x1303 => Some(Self::Chacha20Poly1305Sha256),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Aes128GcmSha256 => "TLS13_AES_128_GCM_SHA256",
+            Self::Aes256GcmSha384 => "TLS13_AES_256_GCM_SHA384",
+            Self::Chacha20Poly1305Sha256 => "TLS13_CHACHA20_POLY1305_SHA256",
+        }
+    }
+
+    fn sha384(self) -> bool {
+        self == Self::Aes256GcmSha384
+    }
+
+    fn hash_len(self) -> usize {
+        if self.sha384() {
+            48
+        } else {
+            32
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        if self.sha384() {
+            Sha384::digest(data).to_vec()
+        } else {
+            Sha256::digest(data).to_vec()
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        if self.sha384() {
+            let mut mac = Hmac::<Sha384>::new_from_slice(key).expect("hmac takes any key");
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        } else {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes any key");
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+
+    fn extract(self, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+        if self.sha384() {
+            Hkdf::<Sha384>::extract(Some(salt), ikm).0.to_vec()
+        } else {
+            Hkdf::<Sha256>::extract(Some(salt), ikm).0.to_vec()
+        }
+    }
+
+    /// HKDF-Expand-Label of RFC 8446.
+    fn expand_label(self, secret: &[u8], label: &str, context: &[u8], len: usize) -> Vec<u8> {
+        let mut info = (len as u16).to_be_bytes().to_vec();
+        hello::put_vec(&mut info, format!("tls13 {}", label).as_bytes(), 1);
+        hello::put_vec(&mut info, context, 1);
+
+        let mut okm = vec![0u8; len];
+        if self.sha384() {
+            Hkdf::<Sha384>::from_prk(secret)
+                .and_then(|x| {
+                    x.expand(&info, &mut okm)
+                        .map_err(|_| hkdf::InvalidPrkLength)
+                })
+                .expect("the secrets are of the hash length");
+        } else {
+            Hkdf::<Sha256>::from_prk(secret)
+                .and_then(|x| {
+                    x.expand(&info, &mut okm)
+                        .map_err(|_| hkdf::InvalidPrkLength)
+                })
+                .expect("the secrets are of the hash length");
+        }
+        okm
+    }
+
+    fn derive_secret(self, secret: &[u8], label: &str, messages: &[u8]) -> Vec<u8> {
+        self.expand_label(secret, label, &self.hash(messages), self.hash_len())
+    }
+}
+
+enum RecordAead {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    Chacha20Poly1305(ChaCha20Poly1305),
+}
+
+/// The key of the records of a direction.
+struct RecordKey {
+    suite: Suite,
+    secret: Vec<u8>,
+    aead: RecordAead,
+    iv: [u8; 12],
+    seq: u64,
+}
+
+impl RecordKey {
+    fn new(suite: Suite, secret: Vec<u8>) -> Self {
+        let key_len = match suite {
+            Suite::Aes128GcmSha256 => 16,
+            _ => 32,
+        };
+        let key = suite.expand_label(&secret, "key", &[], key_len);
+        let aead = match suite {
+            Suite::Aes128GcmSha256 => {
+                RecordAead::Aes128Gcm(Box::new(Aes128Gcm::new_with_slice(&key)))
+            }
+            Suite::Aes256GcmSha384 => {
+                RecordAead::Aes256Gcm(Box::new(Aes256Gcm::new_with_slice(&key)))
+            }
+            Suite::Chacha20Poly1305Sha256 => {
+                RecordAead::Chacha20Poly1305(ChaCha20Poly1305::new_with_slice(&key))
+            }
+        };
+        let mut iv = [0u8; 12];
+        iv.copy_from_slice(&suite.expand_label(&secret, "iv", &[], 12));
+        Self {
+            suite,
+            secret,
+            aead,
+            iv,
+            seq: 0,
+        }
+    }
+
+    /// The key after a key update.
+    fn next(&self) -> Self {
+        let secret =
+            self.suite
+                .expand_label(&self.secret, "traffic upd", &[], self.suite.hash_len());
+        Self::new(self.suite, secret)
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.iv;
+        for (x, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *x ^= s;
+        }
+        self.seq += 1;
+        nonce
+    }
+
+    /// Append the record of the content.
+    fn seal(&mut self, typ: u8, data: &[u8], out: &mut Vec<u8>) {
+        let len = data.len() + 1 + TAG_LEN;
+        let header = [CONTENT_APPLICATION_DATA, 3, 3, (len >> 8) as u8, len as u8];
+        out.extend_from_slice(&header);
+        let start = out.len();
+        out.extend_from_slice(data);
+        out.push(typ);
+        out.resize(start + len, 0);
+
+        let nonce = self.nonce();
+        let buf = &mut out[start..];
+        match &self.aead {
+            RecordAead::Aes128Gcm(x) => x.encrypt_in_place_with_slice(&nonce, &header, buf),
+            RecordAead::Aes256Gcm(x) => x.encrypt_in_place_with_slice(&nonce, &header, buf),
+            RecordAead::Chacha20Poly1305(x) => x.encrypt_in_place_with_slice(&nonce, &header, buf),
+        }
+    }
+
+    /// Decrypt the payload of a record, and return its content type and
+    /// length.
+    fn open(&mut self, header: &[u8], payload: &mut [u8]) -> io::Result<(u8, usize)> {
+        if payload.len() < TAG_LEN {
+            return Err(invalid_data("TLS record too short"));
+        }
+        let nonce = self.nonce();
+        match &self.aead {
+            RecordAead::Aes128Gcm(x) => x.decrypt_in_place_with_slice(&nonce, header, payload),
+            RecordAead::Aes256Gcm(x) => x.decrypt_in_place_with_slice(&nonce, header, payload),
+            RecordAead::Chacha20Poly1305(x) => {
+                x.decrypt_in_place_with_slice(&nonce, header, payload)
+            }
+        }
+        .map_err(|_| invalid_data("TLS record failed to decrypt"))?;
+
+        let plain = &payload[..payload.len() - TAG_LEN];
+        let len = plain
+            .iter()
+            .rposition(|x| *x != 0)
+            .ok_or_else(|| invalid_data("TLS record without a content type"))?;
+        Ok((plain[len], len))
+    }
+}
+
+/// Reads the fields of a handshake message.
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid_data("malformed TLS handshake message"));
+        }
+        let (x, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(x)
+    }
+
+    fn uint(&mut self, n: usize) -> io::Result<usize> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0, |acc, x| acc << 8 | *x as usize))
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.uint(2).map(|x| x as u16)
+    }
+
+    /// The data after its length of `n` bytes.
+    fn vec(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let len = self.uint(n)?;
+        self.take(len)
+    }
+
+    /// The type and data of each extension.
+    fn extensions(&mut self) -> io::Result<Vec<(u16, &'a [u8])>> {
+        let mut p = Parser(self.vec(2)?);
+        let mut exts = vec![];
+        while !p.0.is_empty() {
+            exts.push((p.u16()?, p.vec(2)?));
+        }
+        Ok(exts)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    ServerHello,
+    EncryptedExtensions,
+    Certificate,
+    CertificateVerify,
+    Finished,
+    Done,
+}
+
+/// The handshake secrets, once the server hello is in.
+struct Handshake {
+    suite: Suite,
+    secret: Vec<u8>,
+    client: Vec<u8>,
+    server: Vec<u8>,
+}
+
+pub struct ClientSession {
+    state: State,
+    server_name: ServerName,
+    verifier: Arc<dyn ServerCertVerifier>,
+    key_share: StaticSecret,
+    session_id: Vec<u8>,
+    /// the REALITY auth key
+    reality: Option<[u8; 32]>,
+    /// the handshake messages so far
+    transcript: Vec<u8>,
+    handshake: Option<Handshake>,
+    cert: Option<Certificate>,
+    alpn: Option<Vec<u8>>,
+    alps: bool,
+
+    read_key: Option<RecordKey>,
+    write_key: Option<RecordKey>,
+    /// the bytes received, short of a whole record
+    incoming: Vec<u8>,
+    /// the handshake messages received, short of a whole one
+    messages: Vec<u8>,
+    outgoing: Vec<u8>,
+    plaintext: BytesMut,
+    closed: bool,
+    close_notify_sent: bool,
+}
+
+impl Debug for ClientSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientSession")
+            .field("state", &self.state)
+            .field("server_name", &self.server_name)
+            .field("reality", &self.reality.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientSession {
+    /// Start the handshake, the client hello is ready to be sent.
+    pub fn new(opt: &TLSOptions) -> io::Result<Self> {
+        let server_name = ServerName::try_from(opt.sni.as_str()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid server name: {}", opt.sni),
+            )
+        })?;
+        let verifier: Arc<dyn ServerCertVerifier> = if opt.skip_cert_verify {
+            Arc::new(tls::DummyTlsVerifier)
+        } else {
+            let roots = tls::cert_store(&opt.cert_store)
+                .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x.to_string()))?;
+            Arc::new(WebPkiVerifier::new(roots, None))
+        };
+
+        let key_share = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let mut random = [0u8; 32];
+        rand_fill(&mut random);
+        let mut session_id = [0u8; 32];
+        rand_fill(&mut session_id);
+        let alpn: Vec<Vec<u8>> = opt
+            .alpn
+            .iter()
+            .flatten()
+            .map(|x| x.as_bytes().to_vec())
+            .collect();
+
+        let mut msg = client_hello(
+            opt.fingerprint.unwrap_or(Fingerprint::Chrome),
+            &Hello {
+                random: &random,
+                session_id: &session_id,
+                server_name: &opt.sni,
+                alpn: &alpn,
+                key_share: PublicKey::from(&key_share).as_bytes(),
+            },
+        );
+        let reality = opt
+            .reality
+            .as_ref()
+            .map(|x| reality::seal(&mut msg, &key_share, x))
+            .transpose()?;
+
+        // the first record says TLS 1.0, as the browsers do
+        let mut outgoing = vec![CONTENT_HANDSHAKE, 3, 1];
+        hello::put_vec(&mut outgoing, &msg, 2);
+
+        Ok(Self {
+            state: State::ServerHello,
+            server_name,
+            verifier,
+            key_share,
+            session_id: msg[39..71].to_vec(),
+            reality,
+            transcript: msg,
+            handshake: None,
+            cert: None,
+            alpn: None,
+            alps: false,
+            read_key: None,
+            write_key: None,
+            incoming: vec![],
+            messages: vec![],
+            outgoing,
+            plaintext: BytesMut::new(),
+            closed: false,
+            close_notify_sent: false,
+        })
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.state != State::Done
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Take the records to send.
+    pub fn take_tls(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn.as_deref()
+    }
+
+    pub fn cipher_suite(&self) -> Option<&'static str> {
+        self.handshake.as_ref().map(|x| x.suite.name())
+    }
+
+    /// Take the bytes received, the whole records among them are processed
+    /// and the rest is kept for later.
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+        self.incoming.extend_from_slice(data);
+        let mut start = 0;
+        let rv = loop {
+            let buf = &self.incoming[start..];
+            if buf.len() < 5 {
+                break Ok(());
+            }
+            let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if len > MAX_CIPHERTEXT {
+                break Err(invalid_data("TLS record too large"));
+            }
+            if buf.len() < 5 + len {
+                break Ok(());
+            }
+            let mut record = buf[..5 + len].to_vec();
+            start += 5 + len;
+            if let Err(e) = self.on_record(&mut record) {
+                break Err(e);
+            }
+        };
+        self.incoming.drain(..start);
+        rv
+    }
+
+    /// The plaintext received, `WouldBlock` if there's none for now and 0
+    /// once the server closed.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.plaintext.is_empty() {
+            return if self.closed {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+        let n = buf.len().min(self.plaintext.len());
+        buf[..n].copy_from_slice(&self.plaintext[..n]);
+        self.plaintext.advance(n);
+        Ok(n)
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let key = self
+            .write_key
+            .as_mut()
+            .filter(|_| self.state == State::Done)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "TLS handshake not done"))?;
+        for chunk in buf.chunks(MAX_PLAINTEXT) {
+            key.seal(CONTENT_APPLICATION_DATA, chunk, &mut self.outgoing);
+        }
+        Ok(())
+    }
+
+    /// Queue a close_notify, once.
+    pub fn send_close_notify(&mut self) {
+        if self.close_notify_sent {
+            return;
+        }
+        if let Some(key) = self.write_key.as_mut() {
+            // warning, close_notify
+            key.seal(CONTENT_ALERT, &[1, 0], &mut self.outgoing);
+            self.close_notify_sent = true;
+        }
+    }
+
+    fn on_record(&mut self, record: &mut [u8]) -> io::Result<()> {
+        let (header, payload) = record.split_at_mut(5);
+        let handshaking = self.is_handshaking();
+        match (header[0], self.read_key.as_mut()) {
+            // sent for the middleboxes, RFC 8446 D.4
+            (CONTENT_CHANGE_CIPHER_SPEC, _) if handshaking => Ok(()),
+            (CONTENT_APPLICATION_DATA, Some(key)) => {
+                let (typ, len) = key.open(header, payload)?;
+                self.on_message(typ, &payload[..len])
+            }
+            (typ @ (CONTENT_ALERT | CONTENT_HANDSHAKE), None) => self.on_message(typ, payload),
+            (typ, _) => Err(invalid_data(format!("unexpected TLS record {}", typ))),
+        }
+    }
+
+    fn on_message(&mut self, typ: u8, data: &[u8]) -> io::Result<()> {
+        match typ {
+            CONTENT_HANDSHAKE => {
+                self.messages.extend_from_slice(data);
+                while self.messages.len() >= 4 {
+                    let len = Parser(&self.messages[1..4]).uint(3)?;
+                    if len > MAX_HANDSHAKE {
+                        return Err(invalid_data("TLS handshake message too large"));
+                    }
+                    if self.messages.len() < 4 + len {
+                        break;
+                    }
+                    let msg: Vec<u8> = self.messages.drain(..4 + len).collect();
+                    let keys_changed = self.on_handshake(&msg)?;
+                    if keys_changed && !self.messages.is_empty() {
+                        return Err(invalid_data("TLS handshake message across a key change"));
+                    }
+                }
+                Ok(())
+            }
+            CONTENT_APPLICATION_DATA if self.state == State::Done => {
+                self.plaintext.extend_from_slice(data);
+                Ok(())
+            }
+            CONTENT_ALERT if data.len() == 2 => {
+                // close_notify
+                if data[1] == 0 {
+                    self.closed = true;
+                    Ok(())
+                } else {
+                    Err(invalid_data(format!("received TLS alert {}", data[1])))
+                }
+            }
+            _ => Err(invalid_data(format!("unexpected TLS message {}", typ))),
+        }
+    }
+
+    /// Handle a handshake message, true if the keys of the server changed.
+    fn on_handshake(&mut self, msg: &[u8]) -> io::Result<bool> {
+        match (self.state, msg[0]) {
+            (State::ServerHello, HANDSHAKE_SERVER_HELLO) => {
+                self.server_hello(msg)?;
+                return Ok(true);
+            }
+            (State::EncryptedExtensions, HANDSHAKE_ENCRYPTED_EXTENSIONS) => {
+                for (typ, data) in Parser(&msg[4..]).extensions()? {
+                    match typ {
+                        EXT_ALPN => {
+                            let mut p = Parser(data);
+                            self.alpn = Some(Parser(p.vec(2)?).vec(1)?.to_vec());
+                        }
+                        EXT_APPLICATION_SETTINGS => self.alps = true,
+                        _ => {}
+                    }
+                }
+                self.state = State::Certificate;
+            }
+            (State::Certificate, HANDSHAKE_CERTIFICATE_REQUEST) => {
+                return Err(invalid_data("TLS client certificates are not supported"));
+            }
+            (State::Certificate, HANDSHAKE_CERTIFICATE) => self.certificate(&msg[4..])?,
+            (State::Certificate, HANDSHAKE_COMPRESSED_CERTIFICATE) => {
+                let mut p = Parser(&msg[4..]);
+                if p.u16()? != CERT_COMPRESSION_BROTLI {
+                    return Err(invalid_data("unexpected TLS certificate compression"));
+                }
+                let len = p.uint(3)?;
+                if len > MAX_HANDSHAKE {
+                    return Err(invalid_data("TLS certificate too large"));
+                }
+                let mut certs = Vec::with_capacity(len);
+                brotli::Decompressor::new(p.vec(3)?, 4096)
+                    .take(len as u64 + 1)
+                    .read_to_end(&mut certs)?;
+                if certs.len() != len {
+                    return Err(invalid_data("malformed TLS compressed certificate"));
+                }
+                self.certificate(&certs)?;
+            }
+            (State::CertificateVerify, HANDSHAKE_CERTIFICATE_VERIFY) => {
+                self.certificate_verify(&msg[4..])?
+            }
+            (State::Finished, HANDSHAKE_FINISHED) => {
+                self.transcript.extend_from_slice(msg);
+                self.finished(&msg[4..])?;
+                return Ok(true);
+            }
+            (State::Done, HANDSHAKE_NEW_SESSION_TICKET) => return Ok(false),
+            (State::Done, HANDSHAKE_KEY_UPDATE) => {
+                let read = self.read_key.as_ref().expect("keys are set when done");
+                self.read_key = Some(read.next());
+                // update_requested
+                if msg.get(4) == Some(&1) {
+                    let write = self.write_key.as_mut().expect("keys are set when done");
+                    write.seal(
+                        CONTENT_HANDSHAKE,
+                        &[HANDSHAKE_KEY_UPDATE, 0, 0, 1, 0],
+                        &mut self.outgoing,
+                    );
+                    *write = write.next();
+                }
+                return Ok(true);
+            }
+            (state, typ) => {
+                return Err(invalid_data(format!(
+                    "unexpected TLS handshake message {} in {:?}",
+                    typ, state
+                )))
+            }
+        }
+        self.transcript.extend_from_slice(msg);
+        Ok(false)
+    }
+
+    fn server_hello(&mut self, msg: &[u8]) -> io::Result<()> {
+        let mut p = Parser(&msg[4..]);
+        p.take(2)?;
+        if p.take(32)? == HELLO_RETRY_REQUEST {
+            return Err(invalid_data(
+                "TLS server asked for another key share than X25519",
+            ));
+        }
+        if p.vec(1)? != self.session_id {
+            return Err(invalid_data("TLS server hello of another session"));
+        }
+        let suite = Suite::from_u16(p.u16()?)
+            .ok_or_else(|| invalid_data("TLS server chose a cipher suite not offered"))?;
+        p.take(1)?;
+
+        let (mut version, mut share) = (None, None);
+        for (typ, data) in p.extensions()? {
+            let mut p = Parser(data);
+            match typ {
+                EXT_SUPPORTED_VERSIONS => version = Some(p.u16()?),
+                EXT_KEY_SHARE if p.u16()? == X25519 => share = Some(p.vec(2)?),
+                _ => {}
+            }
+        }
+        if version != Some(TLS13) {
+            return Err(invalid_data(
+                "TLS server doesn't do TLS 1.3, which the client fingerprints need",
+            ));
+        }
+        let share = share
+            .and_then(|x| <[u8; 32]>::try_from(x).ok())
+            .ok_or_else(|| invalid_data("TLS server hello without an X25519 key share"))?;
+        let shared = self.key_share.diffie_hellman(&PublicKey::from(share));
+        if !shared.was_contributory() {
+            return Err(invalid_data("TLS server key share is of low order"));
+        }
+
+        self.transcript.extend_from_slice(msg);
+        let zeros = vec![0u8; suite.hash_len()];
+        let early = suite.extract(&zeros, &zeros);
+        let derived = suite.derive_secret(&early, "derived", &[]);
+        let secret = suite.extract(&derived, shared.as_bytes());
+        let handshake = Handshake {
+            suite,
+            client: suite.derive_secret(&secret, "c hs traffic", &self.transcript),
+            server: suite.derive_secret(&secret, "s hs traffic", &self.transcript),
+            secret,
+        };
+        self.read_key = Some(RecordKey::new(suite, handshake.server.clone()));
+        self.handshake = Some(handshake);
+        self.state = State::EncryptedExtensions;
+        Ok(())
+    }
+
+    fn certificate(&mut self, body: &[u8]) -> io::Result<()> {
+        let mut p = Parser(body);
+        p.vec(1)?;
+        let mut list = Parser(p.vec(3)?);
+        let mut certs = vec![];
+        while !list.0.is_empty() {
+            certs.push(Certificate(list.vec(3)?.to_vec()));
+            list.vec(2)?;
+        }
+        let end_entity = certs
+            .first()
+            .ok_or_else(|| invalid_data("TLS server sent no certificate"))?;
+
+        match &self.reality {
+            Some(auth_key) => {
+                if !reality::verify(auth_key, &end_entity.0) {
+                    return Err(invalid_data(
+                        "REALITY authentication failed, the server passed the connection through",
+                    ));
+                }
+            }
+            None => {
+                self.verifier
+                    .verify_server_cert(
+                        end_entity,
+                        &certs[1..],
+                        &self.server_name,
+                        &mut std::iter::empty(),
+                        &[],
+                        SystemTime::now(),
+                    )
+                    .map_err(|x| invalid_data(x.to_string()))?;
+            }
+        }
+        self.cert = Some(certs.swap_remove(0));
+        self.state = State::CertificateVerify;
+        Ok(())
+    }
+
+    fn certificate_verify(&mut self, body: &[u8]) -> io::Result<()> {
+        let handshake = self.handshake.as_ref().expect("after the server hello");
+        let dss = DigitallySignedStruct::read(&mut Reader::init(body))
+            .map_err(|_| invalid_data("malformed TLS certificate verify"))?;
+
+        let mut signed = vec![0x20; 64];
+        signed.extend_from_slice(b"TLS 1.3, server CertificateVerify\0");
+        signed.extend_from_slice(&handshake.suite.hash(&self.transcript));
+
+        let cert = self.cert.as_ref().expect("after the certificate");
+        // the REALITY certificate is checked already but not its signature
+        let verified = if self.reality.is_some() {
+            WebPkiVerifier::new(RootCertStore::empty(), None)
+                .verify_tls13_signature(&signed, cert, &dss)
+        } else {
+            self.verifier.verify_tls13_signature(&signed, cert, &dss)
+        };
+        verified.map_err(|x| invalid_data(x.to_string()))?;
+        self.state = State::Finished;
+        Ok(())
+    }
+
+    /// Check the finished of the server and send the one of the client.
+    fn finished(&mut self, verify_data: &[u8]) -> io::Result<()> {
+        let handshake = self.handshake.as_ref().expect("after the server hello");
+        let suite = handshake.suite;
+        let without_finished = &self.transcript[..self.transcript.len() - 4 - verify_data.len()];
+        let key = suite.expand_label(&handshake.server, "finished", &[], suite.hash_len());
+        let expected = suite.hmac(&key, &suite.hash(without_finished));
+        if expected.len() != verify_data.len()
+            || expected
+                .iter()
+                .zip(verify_data)
+                .fold(0, |acc, (x, y)| acc | (x ^ y))
+                != 0
+        {
+            return Err(invalid_data("TLS server finished doesn't verify"));
+        }
+
+        let zeros = vec![0u8; suite.hash_len()];
+        let derived = suite.derive_secret(&handshake.secret, "derived", &[]);
+        let master = suite.extract(&derived, &zeros);
+        let client = suite.derive_secret(&master, "c ap traffic", &self.transcript);
+        let server = suite.derive_secret(&master, "s ap traffic", &self.transcript);
+
+        self.outgoing
+            .extend_from_slice(&[CONTENT_CHANGE_CIPHER_SPEC, 3, 3, 0, 1, 1]);
+        let mut write = RecordKey::new(suite, handshake.client.clone());
+        if self.alps {
+            // the client encrypted extensions, with empty application
+            // settings
+            let msg = [
+                HANDSHAKE_ENCRYPTED_EXTENSIONS,
+                0,
+                0,
+                6,
+                0,
+                4,
+                (EXT_APPLICATION_SETTINGS >> 8) as u8,
+                EXT_APPLICATION_SETTINGS as u8,
+                0,
+                0,
+            ];
+            write.seal(CONTENT_HANDSHAKE, &msg, &mut self.outgoing);
+            self.transcript.extend_from_slice(&msg);
+        }
+        let key = suite.expand_label(&handshake.client, "finished", &[], suite.hash_len());
+        let mut msg = vec![HANDSHAKE_FINISHED];
+        hello::put_vec(
+            &mut msg,
+            &suite.hmac(&key, &suite.hash(&self.transcript)),
+            3,
+        );
+        write.seal(CONTENT_HANDSHAKE, &msg, &mut self.outgoing);
+
+        self.write_key = Some(RecordKey::new(suite, client));
+        self.read_key = Some(RecordKey::new(suite, server));
+        self.transcript = vec![];
+        self.state = State::Done;
+        Ok(())
+    }
+}
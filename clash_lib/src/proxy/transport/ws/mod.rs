@@ -1,7 +1,7 @@
 mod websocket;
 mod websocket_early_data;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use http::{Request, StatusCode};
 use tokio_tungstenite::{
@@ -21,9 +21,11 @@ pub struct WebsocketStreamBuilder {
     ws_config: Option<WebSocketConfig>,
     max_early_data: usize,
     early_data_header_name: String,
+    ping_interval: Option<Duration>,
 }
 
 impl WebsocketStreamBuilder {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         server: String,
         port: u16,
@@ -32,6 +34,7 @@ impl WebsocketStreamBuilder {
         ws_config: Option<WebSocketConfig>,
         max_early_data: usize,
         early_data_header_name: String,
+        ping_interval: Option<Duration>,
     ) -> Self {
         Self {
             server,
@@ -41,6 +44,7 @@ impl WebsocketStreamBuilder {
             ws_config,
             max_early_data,
             early_data_header_name,
+            ping_interval,
         }
     }
 
@@ -71,6 +75,7 @@ impl WebsocketStreamBuilder {
                 self.ws_config,
                 self.early_data_header_name.clone(),
                 self.max_early_data,
+                self.ping_interval,
             );
             Ok(Box::new(early_data_conn))
         } else {
@@ -84,7 +89,10 @@ impl WebsocketStreamBuilder {
                     "invalid response",
                 ));
             }
-            Ok(Box::new(WebsocketConn::from_websocket(stream)))
+            Ok(Box::new(WebsocketConn::from_websocket(
+                stream,
+                self.ping_interval,
+            )))
         }
     }
 }
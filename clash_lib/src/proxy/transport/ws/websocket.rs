@@ -1,8 +1,11 @@
-use std::{fmt::Debug, pin::Pin, task::Poll};
+use std::{fmt::Debug, pin::Pin, task::Poll, time::Duration};
 
 use bytes::BytesMut;
 use futures::{ready, Sink, Stream};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{Instant, Interval, MissedTickBehavior},
+};
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 
 use crate::{
@@ -13,6 +16,8 @@ use crate::{
 pub struct WebsocketConn {
     inner: WebSocketStream<AnyStream>,
     read_buffer: BytesMut,
+    /// pings keeping the mappings of the NATs on the way while idle
+    ping: Option<Interval>,
 }
 
 impl Debug for WebsocketConn {
@@ -25,11 +30,46 @@ impl Debug for WebsocketConn {
 }
 
 impl WebsocketConn {
-    pub fn from_websocket(stream: WebSocketStream<AnyStream>) -> Self {
+    pub fn from_websocket(
+        stream: WebSocketStream<AnyStream>,
+        ping_interval: Option<Duration>,
+    ) -> Self {
+        let ping = ping_interval.map(|period| {
+            let mut ping = tokio::time::interval_at(Instant::now() + period, period);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ping
+        });
         Self {
             inner: stream,
             read_buffer: BytesMut::new(),
+            ping,
+        }
+    }
+
+    /// Send a ping if it's time to. A ping is skipped rather than waited for
+    /// if the sink is busy, as the connection isn't idle then anyway.
+    fn poll_ping(&mut self, cx: &mut std::task::Context<'_>) -> std::io::Result<()> {
+        let Some(ping) = self.ping.as_mut() else {
+            return Ok(());
+        };
+        while ping.poll_tick(cx).is_ready() {
+            let mut inner = Pin::new(&mut self.inner);
+            match inner.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    inner
+                        .as_mut()
+                        .start_send(Message::Ping(vec![]))
+                        .map_err(map_io_error)?;
+                    // a pending flush is finished by the next read or write
+                    if let Poll::Ready(Err(e)) = inner.poll_flush(cx) {
+                        return Err(map_io_error(e));
+                    }
+                }
+                Poll::Ready(Err(e)) => return Err(map_io_error(e)),
+                Poll::Pending => {}
+            }
         }
+        Ok(())
     }
 }
 
@@ -39,38 +79,41 @@ impl AsyncRead for WebsocketConn {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        if !self.read_buffer.is_empty() {
-            let to_read = std::cmp::min(buf.remaining(), self.read_buffer.len());
-            let for_read = self.read_buffer.split_to(to_read);
+        let this = self.get_mut();
+        this.poll_ping(cx)?;
+
+        if !this.read_buffer.is_empty() {
+            let to_read = std::cmp::min(buf.remaining(), this.read_buffer.len());
+            let for_read = this.read_buffer.split_to(to_read);
             buf.put_slice(&for_read[..to_read]);
             return std::task::Poll::Ready(Ok(()));
         }
-        Poll::Ready(ready!(Pin::new(&mut self.inner).poll_next(cx)).map_or(
-            Err(std::io::Error::new(
-                std::io::ErrorKind::BrokenPipe,
-                "ws broken pipe",
-            )),
-            |item| {
-                item.map_or(
-                    Err(std::io::Error::new(
+        loop {
+            let msg = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(msg)) => msg,
+                _ => {
+                    return Poll::Ready(Err(std::io::Error::new(
                         std::io::ErrorKind::BrokenPipe,
                         "ws broken pipe",
-                    )),
-                    |msg| match msg {
-                        Message::Binary(data) => {
-                            let to_read = std::cmp::min(buf.remaining(), data.len());
-                            buf.put_slice(&data[..to_read]);
-                            if to_read < data.len() {
-                                self.read_buffer.extend_from_slice(&data[to_read..]);
-                            }
-                            Ok(())
-                        }
-                        Message::Close(_) => Ok(()),
-                        _ => Err(new_io_error("ws invalid message type")),
-                    },
-                )
-            },
-        ))
+                    )))
+                }
+            };
+            match msg {
+                Message::Binary(data) => {
+                    let to_read = std::cmp::min(buf.remaining(), data.len());
+                    buf.put_slice(&data[..to_read]);
+                    if to_read < data.len() {
+                        this.read_buffer.extend_from_slice(&data[to_read..]);
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                // the pings are answered by tungstenite, and the pongs are
+                // answers to ours
+                Message::Ping(_) | Message::Pong(_) => continue,
+                _ => return Poll::Ready(Err(new_io_error("ws invalid message type"))),
+            }
+        }
     }
 }
 
@@ -3,6 +3,7 @@ use std::{
     fmt::Debug,
     pin::Pin,
     task::{Poll, Waker},
+    time::Duration,
 };
 
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
@@ -31,6 +32,7 @@ pub struct WebsocketEarlyDataConn {
     early_data_header_name: String,
     early_data_len: usize,
     early_data_flushed: bool,
+    ping_interval: Option<Duration>,
 }
 
 impl Debug for WebsocketEarlyDataConn {
@@ -44,6 +46,7 @@ impl Debug for WebsocketEarlyDataConn {
             .field("early_data_header_name", &self.early_data_header_name)
             .field("early_data_len", &self.early_data_len)
             .field("early_data_flushed", &self.early_data_flushed)
+            .field("ping_interval", &self.ping_interval)
             .finish()
     }
 }
@@ -55,6 +58,7 @@ impl WebsocketEarlyDataConn {
         ws_config: Option<WebSocketConfig>,
         early_data_header_name: String,
         early_data_len: usize,
+        ping_interval: Option<Duration>,
     ) -> Self {
         Self {
             stream: Some(stream),
@@ -66,6 +70,7 @@ impl WebsocketEarlyDataConn {
             early_data_header_name,
             early_data_len,
             early_data_flushed: false,
+            ping_interval,
         }
     }
 
@@ -73,11 +78,13 @@ impl WebsocketEarlyDataConn {
         stream: AnyStream,
         req: Request<()>,
         config: Option<WebSocketConfig>,
+        ping_interval: Option<Duration>,
     ) -> Pin<Box<dyn std::future::Future<Output = std::io::Result<AnyStream>> + Send + Sync>> {
         async fn run(
             stream: AnyStream,
             req: Request<()>,
             config: Option<WebSocketConfig>,
+            ping_interval: Option<Duration>,
         ) -> std::io::Result<AnyStream> {
             let (stream, resp) = client_async_with_config(req, stream, config)
                 .await
@@ -85,11 +92,11 @@ impl WebsocketEarlyDataConn {
             if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
                 return Err(new_io_error("msg: websocket early data handshake failed"));
             }
-            let rv = Box::new(WebsocketConn::from_websocket(stream));
+            let rv = Box::new(WebsocketConn::from_websocket(stream, ping_interval));
             Ok(rv)
         }
 
-        Box::pin(run(stream, req, config))
+        Box::pin(run(stream, req, config, ping_interval))
     }
 }
 
@@ -149,7 +156,8 @@ impl AsyncWrite for WebsocketEarlyDataConn {
 
                     let stream = self.as_mut().stream.take().expect("msg: bad state");
                     let config = self.as_mut().ws_config.take();
-                    self.as_mut().stream_future = Some(Self::proxy_stream(stream, req, config));
+                    self.as_mut().stream_future =
+                        Some(Self::proxy_stream(stream, req, config, self.ping_interval));
                 }
             }
         }
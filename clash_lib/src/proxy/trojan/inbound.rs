@@ -0,0 +1,330 @@
+//! Trojan server
+//!
+//! Connections that don't start with the request of a known user after the
+//! TLS handshake are handed to a fallback picked by the SNI and ALPN of the
+//! handshake, usually a local web server, so the port looks like a plain
+//! HTTPS site to whoever probes it. With a `client-ca`, so are the ones
+//! without a client certificate signed by it.
+
+use std::{collections::HashSet, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha224};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, warn};
+
+use crate::{
+    app::inbound::rate_limit::ThreadSafeRateLimiter,
+    common::{acme, errors::new_io_error, tls, utils},
+    config::internal::config::{InboundTls, TrojanFallback, TrojanInboundConfig},
+    proxy::{
+        utils::{apply_tcp_options, new_tcp_stream_to, proxy_protocol},
+        AnyInboundListener, InboundListener,
+    },
+    session::{ListenerId, Network, Session, SocksAddr, Type},
+    Dispatcher, Error,
+};
+
+const HASH_LEN: usize = 56;
+const CRLF: [u8; 2] = *b"\r\n";
+const CMD_CONNECT: u8 = 0x01;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct InboundOpts {
+    tls: Arc<rustls::ServerConfig>,
+    /// renewing the certificate if obtained with ACME
+    _acme: Option<acme::Acme>,
+    /// the clients must present a certificate signed by the `client-ca`
+    client_auth: bool,
+    /// hex encoded SHA224 of the passwords
+    passwords: HashSet<Vec<u8>>,
+    fallbacks: Vec<TrojanFallback>,
+}
+
+impl InboundOpts {
+    pub fn new(cfg: TrojanInboundConfig, cwd: &Path) -> Result<Self, Error> {
+        let client_ca = cfg.client_ca.as_ref().map(|x| cwd.join(x));
+        let (tls, acme) = match &cfg.tls {
+            InboundTls::Files {
+                certificate,
+                private_key,
+            } => (
+                tls::server_config(
+                    &cwd.join(certificate),
+                    &cwd.join(private_key),
+                    &cfg.alpn,
+                    client_ca.as_deref(),
+                )?,
+                None,
+            ),
+            InboundTls::Acme(opts) => {
+                let (tls, acme) = acme::server_config(opts, cwd, &cfg.alpn, client_ca.as_deref())?;
+                (tls, Some(acme))
+            }
+        };
+        Ok(Self {
+            tls: Arc::new(tls),
+            _acme: acme,
+            client_auth: client_ca.is_some(),
+            passwords: cfg
+                .password
+                .iter()
+                .map(|x| utils::encode_hex(&Sha224::digest(x.as_bytes())[..]).into_bytes())
+                .collect(),
+            fallbacks: cfg.fallbacks,
+        })
+    }
+
+    fn fallback(&self, sni: Option<&str>, alpn: Option<&[u8]>) -> Option<&TrojanFallback> {
+        self.fallbacks.iter().find(|x| {
+            x.sni
+                .as_deref()
+                .map_or(true, |x| sni.is_some_and(|sni| sni.eq_ignore_ascii_case(x)))
+                && x.alpn
+                    .as_deref()
+                    .map_or(true, |x| alpn == Some(x.as_bytes()))
+        })
+    }
+}
+
+pub struct Listener {
+    addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    opts: Arc<InboundOpts>,
+    proxy_protocol: bool,
+    rate_limiter: Option<ThreadSafeRateLimiter>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!("Trojan inbound listener on {} stopped", self.addr);
+    }
+}
+
+impl Listener {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        addr: SocketAddr,
+        dispatcher: Arc<Dispatcher>,
+        opts: Arc<InboundOpts>,
+        proxy_protocol: bool,
+        rate_limiter: Option<ThreadSafeRateLimiter>,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            dispatcher,
+            opts,
+            proxy_protocol,
+            rate_limiter,
+        }) as _
+    }
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        false
+    }
+
+    async fn listen_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (socket, peer_addr) = listener.accept().await?;
+            let mut socket = apply_tcp_options(socket)?;
+
+            let dispatcher = self.dispatcher.clone();
+            let opts = self.opts.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let limiter = self.rate_limiter.clone();
+            let listener = ListenerId {
+                name: "trojan",
+                port: self.addr.port(),
+            };
+
+            tokio::spawn(async move {
+                let src_addr = match proxy_protocol::source_addr(&mut socket, proxy_protocol).await
+                {
+                    Ok(src_addr) => src_addr,
+                    Err(e) => {
+                        warn!(
+                            "failed to accept trojan connection from {}: {}",
+                            peer_addr, e
+                        );
+                        return;
+                    }
+                };
+                if limiter.as_ref().is_some_and(|x| !x.allow(src_addr.ip())) {
+                    return;
+                }
+                if let Err(e) = handle(socket, src_addr, listener, dispatcher, opts).await {
+                    debug!("trojan inbound connection from {} failed: {}", src_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "unsupported"))
+    }
+}
+
+async fn handle(
+    socket: TcpStream,
+    src_addr: SocketAddr,
+    listener: ListenerId,
+    dispatcher: Arc<Dispatcher>,
+    opts: Arc<InboundOpts>,
+) -> io::Result<()> {
+    let acceptor = TlsAcceptor::from(opts.tls.clone());
+    let mut s = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out"))??;
+    if acme::is_challenge(s.get_ref().1) {
+        debug!("acme challenge validated by {}", src_addr);
+        return Ok(());
+    }
+    if opts.client_auth && s.get_ref().1.peer_certificates().is_none() {
+        // like a wrong password, the port still looks like a web site
+        debug!("no client certificate from {}", src_addr);
+        return fallback(s, &[], &opts).await;
+    }
+
+    /*
+    +-----------------------+---------+----------------+---------+----------+
+    | hex(SHA224(password)) |  CRLF   | Trojan Request |  CRLF   | Payload  |
+    +-----------------------+---------+----------------+---------+----------+
+    |          56           | X'0D0A' |    Variable    | X'0D0A' | Variable |
+    +-----------------------+---------+----------------+---------+----------+
+     */
+    let mut head = Vec::with_capacity(HASH_LEN + CRLF.len());
+    let known_user = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        read_head(&mut s, &opts.passwords, &mut head),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        // the peer is waiting for the server to talk, e.g. a prober
+        Err(_) => false,
+    };
+    if !known_user {
+        return fallback(s, &head, &opts).await;
+    }
+
+    let cmd = s.read_u8().await?;
+    let dst = SocksAddr::read_from(&mut s).await?;
+    let mut crlf = [0u8; 2];
+    s.read_exact(&mut crlf).await?;
+    if crlf != CRLF {
+        return Err(new_io_error("invalid trojan request"));
+    }
+
+    if cmd != CMD_CONNECT {
+        return Err(new_io_error("unsupported trojan command"));
+    }
+
+    let sess = Session {
+        network: Network::Tcp,
+        typ: Type::Trojan,
+        source: src_addr,
+        destination: dst,
+        listener: Some(listener),
+        ..Default::default()
+    };
+    dispatcher.dispatch_stream(sess, s).await;
+
+    Ok(())
+}
+
+/// Read the password hash and the CRLF following it, stops at the first
+/// byte that can't be part of them. Returns whether it's a known user.
+async fn read_head<S: AsyncRead + Unpin>(
+    s: &mut S,
+    passwords: &HashSet<Vec<u8>>,
+    head: &mut Vec<u8>,
+) -> io::Result<bool> {
+    while head.len() < HASH_LEN {
+        let b = s.read_u8().await?;
+        head.push(b);
+        if !matches!(b, b'0'..=b'9' | b'a'..=b'f') {
+            return Ok(false);
+        }
+    }
+    if !passwords.contains(&head[..]) {
+        return Ok(false);
+    }
+    for expected in CRLF {
+        let b = s.read_u8().await?;
+        head.push(b);
+        if b != expected {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Relay the decrypted stream to the fallback matching the handshake,
+/// `head` is what has been read already.
+async fn fallback(mut s: TlsStream<TcpStream>, head: &[u8], opts: &InboundOpts) -> io::Result<()> {
+    let (_, conn) = s.get_ref();
+    let dest = match opts.fallback(conn.server_name(), conn.alpn_protocol()) {
+        Some(fallback) => fallback.dest,
+        None => return Err(new_io_error("not a trojan request and no fallback matched")),
+    };
+    debug!("falling back to {}", dest);
+
+    let mut remote = new_tcp_stream_to(
+        dest,
+        None,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        None,
+    )
+    .await?;
+    remote.write_all(head).await?;
+    tokio::io::copy_bidirectional(&mut s, &mut remote).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use sha2::{Digest, Sha224};
+
+    use super::read_head;
+    use crate::common::utils;
+
+    #[tokio::test]
+    async fn test_read_head() {
+        let hash = utils::encode_hex(&Sha224::digest(b"password")[..]);
+        let passwords = HashSet::from([hash.clone().into_bytes()]);
+
+        let req = format!("{}\r\n\x01", hash);
+        let mut head = vec![];
+        assert!(read_head(&mut req.as_bytes(), &passwords, &mut head)
+            .await
+            .unwrap());
+
+        // stops right at the first byte of a HTTP request
+        let mut head = vec![];
+        let mut req: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(!read_head(&mut req, &passwords, &mut head).await.unwrap());
+        assert_eq!(head, b"G");
+
+        let wrong = utils::encode_hex(&Sha224::digest(b"wrong")[..]);
+        let req = format!("{}\r\n", wrong);
+        let mut head = vec![];
+        assert!(!read_head(&mut req.as_bytes(), &passwords, &mut head)
+            .await
+            .unwrap());
+        assert_eq!(head.len(), 56);
+    }
+}
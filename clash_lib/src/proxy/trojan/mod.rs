@@ -13,17 +13,21 @@ use crate::app::dispatcher::ChainedDatagram;
 use crate::app::dispatcher::ChainedDatagramWrapper;
 use crate::app::dispatcher::ChainedStream;
 use crate::app::dispatcher::ChainedStreamWrapper;
+use crate::common::tls::CertStoreOptions;
 use crate::common::utils;
 use crate::{
     app::{dispatcher::BoxedChainedStream, dns::ThreadSafeDNSResolver},
-    session::Session,
+    session::{Session, SocksAddr},
 };
 
 use self::datagram::OutboundDatagramTrojan;
+use self::mux::MuxPool;
+use self::shadowsocks::ShadowsocksStream;
 
 use super::transport;
+use super::transport::tls::TlsEndpoint;
 use super::transport::TLSOptions;
-use super::utils::RemoteConnector;
+use super::utils::{resolve_destination, RemoteConnector};
 use super::ConnectorType;
 use super::{
     options::{GrpcOption, WsOption},
@@ -32,9 +36,21 @@ use super::{
 };
 
 mod datagram;
+mod inbound;
+mod mux;
+mod shadowsocks;
+
+pub use inbound::{InboundOpts, Listener};
+pub use mux::MuxOption;
+pub use shadowsocks::ShadowsocksOption;
 
 static DEFAULT_ALPN: [&str; 2] = ["h2", "http/1.1"];
 
+const CMD_CONNECT: u8 = 0x01;
+const CMD_ASSOCIATE: u8 = 0x03;
+/// trojan-go opens a mux session with this command
+const CMD_MUX: u8 = 0x7f;
+
 pub enum Transport {
     Ws(WsOption),
     Grpc(GrpcOption),
@@ -47,33 +63,35 @@ pub struct Opts {
     pub port: u16,
     pub password: String,
     pub udp: bool,
+    pub remote_dns_resolve: bool,
     pub sni: String,
     pub alpn: Option<Vec<String>>,
     pub skip_cert_verify: bool,
+    pub cert_store: CertStoreOptions,
     pub transport: Option<Transport>,
+    /// the trojan-go mux, not used through a connector
+    pub mux: Option<MuxOption>,
+    /// the trojan-go shadowsocks layer, under the trojan request
+    pub shadowsocks: Option<ShadowsocksOption>,
 }
 
 pub struct Handler {
     opts: Opts,
+    mux: Option<MuxPool>,
 }
 
 impl Handler {
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(opts: Opts) -> AnyOutboundHandler {
-        Arc::new(Self { opts })
+    pub fn new(mut opts: Opts) -> AnyOutboundHandler {
+        let mux = opts.mux.take().map(MuxPool::new);
+        Arc::new(Self { opts, mux })
     }
 
-    /// TCP: 0x01,
-    /// UDP: 0x03,
-    async fn inner_proxy_stream(
-        &self,
-        s: AnyStream,
-        sess: &Session,
-        udp: bool,
-    ) -> io::Result<AnyStream> {
-        let tls_opt = TLSOptions {
+    fn tls_options(&self) -> TLSOptions {
+        TLSOptions {
             skip_cert_verify: self.opts.skip_cert_verify,
             sni: self.opts.sni.clone(),
+            cert_store: self.opts.cert_store.clone(),
             alpn: self.opts.alpn.clone().or(Some(
                 DEFAULT_ALPN
                     .iter()
@@ -81,11 +99,68 @@ impl Handler {
                     .map(|x| x.to_owned())
                     .collect::<Vec<String>>(),
             )),
-        };
+            fingerprint: None,
+            reality: None,
+        }
+    }
 
-        let s = transport::tls::wrap_stream(s, tls_opt, None).await?;
+    async fn dial(&self, sess: &Session, resolver: ThreadSafeDNSResolver) -> io::Result<AnyStream> {
+        new_tcp_stream(
+            resolver,
+            self.opts.server.as_str(),
+            self.opts.port,
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .map_err(|x| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "dial outbound {}:{}: {}",
+                    self.opts.server, self.opts.port, x
+                ),
+            )
+        })
+        .await
+    }
+
+    /// A stream of a mux session, starting with the command and the
+    /// destination as the trojan request does.
+    async fn mux_stream(
+        &self,
+        mux: &MuxPool,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        cmd: u8,
+    ) -> io::Result<AnyStream> {
+        let mut s = mux
+            .open(|| async move {
+                let s = self.dial(sess, resolver).await?;
+                let dst = SocksAddr::Domain("MUX_CONN".to_owned(), 0);
+                self.inner_proxy_stream(s, CMD_MUX, &dst).await
+            })
+            .await?;
+
+        let mut buf = BytesMut::new();
+        buf.put_u8(cmd);
+        sess.destination.write_buf(&mut buf);
+        s.write_all(&buf).await?;
+        Ok(s)
+    }
+
+    /// TCP: 0x01,
+    /// UDP: 0x03,
+    /// MUX: 0x7f
+    async fn inner_proxy_stream(
+        &self,
+        s: AnyStream,
+        cmd: u8,
+        dst: &SocksAddr,
+    ) -> io::Result<AnyStream> {
+        let s = transport::tls::wrap_stream(s, self.tls_options(), None).await?;
 
-        let mut s = if let Some(transport) = self.opts.transport.as_ref() {
+        let s = if let Some(transport) = self.opts.transport.as_ref() {
             match transport {
                 Transport::Ws(ws_opts) => {
                     let ws_builder = transport::WebsocketStreamBuilder::new(
@@ -96,6 +171,7 @@ impl Handler {
                         None,
                         ws_opts.max_early_data,
                         ws_opts.early_data_header_name.clone(),
+                        ws_opts.ping_interval,
                     );
 
                     ws_builder.proxy_stream(s).await?
@@ -108,6 +184,7 @@ impl Handler {
                             .to_owned()
                             .try_into()
                             .expect("invalid gRPC service path"),
+                        grpc_opts.ping_interval,
                     );
                     grpc_builder.proxy_stream(s).await?
                 }
@@ -116,13 +193,18 @@ impl Handler {
             s
         };
 
+        let mut s: AnyStream = match self.opts.shadowsocks.as_ref() {
+            Some(opts) => Box::new(ShadowsocksStream::new(s, opts)),
+            None => s,
+        };
+
         let mut buf = BytesMut::new();
         let password = Sha224::digest(self.opts.password.as_bytes());
         let password = utils::encode_hex(&password[..]);
         buf.put_slice(password.as_bytes());
         buf.put_slice(b"\r\n");
-        buf.put_u8(if udp { 0x03 } else { 0x01 });
-        sess.destination.write_buf(&mut buf);
+        buf.put_u8(cmd);
+        dst.write_buf(&mut buf);
         buf.put_slice(b"\r\n");
         s.write_all(&buf).await?;
 
@@ -144,31 +226,33 @@ impl OutboundHandler for Handler {
         self.opts.udp
     }
 
+    fn remote_dns_resolve(&self) -> bool {
+        self.opts.remote_dns_resolve
+    }
+
+    fn tls_endpoint(&self) -> Option<TlsEndpoint> {
+        Some(TlsEndpoint {
+            server: self.opts.server.clone(),
+            port: self.opts.port,
+            iface: self.opts.common_opts.iface.clone(),
+            tls: self.tls_options(),
+        })
+    }
+
     async fn connect_stream(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = new_tcp_stream(
-            resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
-
-        let stream = self.inner_proxy_stream(stream, sess, false).await?;
+        let sess = &resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
+        let stream = match &self.mux {
+            Some(mux) => self.mux_stream(mux, sess, resolver, CMD_CONNECT).await?,
+            None => {
+                let s = self.dial(sess, resolver).await?;
+                self.inner_proxy_stream(s, CMD_CONNECT, &sess.destination)
+                    .await?
+            }
+        };
 
         let chained = ChainedStreamWrapper::new(stream);
         chained.append_to_chain(self.name()).await;
@@ -180,26 +264,14 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = new_tcp_stream(
-            resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
-
-        let stream = self.inner_proxy_stream(stream, sess, true).await?;
+        let stream = match &self.mux {
+            Some(mux) => self.mux_stream(mux, sess, resolver, CMD_ASSOCIATE).await?,
+            None => {
+                let s = self.dial(sess, resolver).await?;
+                self.inner_proxy_stream(s, CMD_ASSOCIATE, &sess.destination)
+                    .await?
+            }
+        };
 
         let d = OutboundDatagramTrojan::new(stream, sess.destination.clone());
 
@@ -218,18 +290,21 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
+        let sess = &resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
         let stream = connector
             .connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
-                self.opts.common_opts.iface.as_ref(),
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 None,
             )
             .await?;
 
-        let s = self.inner_proxy_stream(stream, sess, false).await?;
+        let s = self
+            .inner_proxy_stream(stream, CMD_CONNECT, &sess.destination)
+            .await?;
         let chained = ChainedStreamWrapper::new(s);
         chained.append_to_chain(self.name()).await;
         Ok(Box::new(chained))
@@ -246,13 +321,15 @@ impl OutboundHandler for Handler {
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
-                self.opts.common_opts.iface.as_ref(),
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 None,
             )
             .await?;
 
-        let stream = self.inner_proxy_stream(stream, sess, true).await?;
+        let stream = self
+            .inner_proxy_stream(stream, CMD_ASSOCIATE, &sess.destination)
+            .await?;
 
         let d = OutboundDatagramTrojan::new(stream, sess.destination.clone());
 
@@ -310,9 +387,11 @@ mod tests {
             port: 10002,
             password: "example".to_owned(),
             udp: true,
+            remote_dns_resolve: true,
             sni: "example.org".to_owned(),
             alpn: None,
             skip_cert_verify: true,
+            cert_store: Default::default(),
             transport: Some(Transport::Ws(WsOption {
                 path: "".to_owned(),
                 headers: [("Host".to_owned(), "example.org".to_owned())]
@@ -321,7 +400,10 @@ mod tests {
                 // ignore the rest by setting max_early_data to 0
                 max_early_data: 0,
                 early_data_header_name: "".to_owned(),
+                ping_interval: None,
             })),
+            mux: None,
+            shadowsocks: None,
         };
         let handler = Handler::new(opts);
         // ignore the udp test
@@ -355,13 +437,18 @@ mod tests {
             port: 10002,
             password: "example".to_owned(),
             udp: true,
+            remote_dns_resolve: true,
             sni: "example.org".to_owned(),
             alpn: None,
             skip_cert_verify: true,
+            cert_store: Default::default(),
             transport: Some(Transport::Grpc(GrpcOption {
                 host: "example.org".to_owned(),
                 service_name: "example".to_owned(),
+                ping_interval: None,
             })),
+            mux: None,
+            shadowsocks: None,
         };
         let handler = Handler::new(opts);
         run_test_suites_and_cleanup(handler, get_grpc_runner().await?, Suite::all()).await
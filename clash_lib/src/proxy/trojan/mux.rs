@@ -0,0 +1,376 @@
+//! The smux v1 multiplexing of trojan-go: a trojan request with the 0x7f
+//! command opens a session carrying many streams, each starting with the
+//! command and the destination of a trojan request, without the password.
+//!
+//! frame: | version u8 | cmd u8 | length u16 LE | stream id u32 LE | data |
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::debug;
+
+use crate::proxy::AnyStream;
+
+const VERSION: u8 = 1;
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const HEADER_LEN: usize = 8;
+const MAX_FRAME: usize = 32768;
+/// the NOP frames keep the session alive, and tell when it's idle
+const KEEPALIVE: Duration = Duration::from_secs(10);
+/// the frames a slow stream can hold, it's closed past them rather than
+/// holding up the other streams of the session
+const STREAM_BUFFER: usize = 64;
+
+pub struct MuxOption {
+    /// the streams carried by a session at once
+    pub concurrency: usize,
+    /// how long a session without streams is kept
+    pub idle_timeout: Duration,
+}
+
+/// The open sessions, shared by the connections of an outbound.
+pub struct MuxPool {
+    opts: MuxOption,
+    sessions: Mutex<Vec<Arc<MuxSession>>>,
+}
+
+impl MuxPool {
+    pub fn new(opts: MuxOption) -> Self {
+        Self {
+            opts,
+            sessions: Mutex::new(vec![]),
+        }
+    }
+
+    /// A stream of an open session with room for it, or of a new session
+    /// over the trojan connection `dial` opens.
+    pub async fn open<F, Fut>(&self, dial: F) -> io::Result<AnyStream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<AnyStream>>,
+    {
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|x| !x.is_closed());
+            sessions
+                .iter()
+                .find(|x| x.streams() < self.opts.concurrency)
+                .cloned()
+        };
+        if let Some(session) = session {
+            // it may close in between, going idle
+            match session.open_stream().await {
+                Ok(s) => return Ok(s),
+                Err(e) => debug!("trojan mux session gone: {}", e),
+            }
+        }
+
+        let session = MuxSession::new(dial().await?, self.opts.idle_timeout);
+        self.sessions.lock().unwrap().push(session.clone());
+        session.open_stream().await
+    }
+}
+
+struct MuxSession {
+    frames: mpsc::Sender<Bytes>,
+    streams: Mutex<HashMap<u32, mpsc::Sender<Bytes>>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+impl MuxSession {
+    fn new(conn: AnyStream, idle_timeout: Duration) -> Arc<Self> {
+        let (r, w) = tokio::io::split(conn);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let session = Arc::new(Self {
+            frames: tx,
+            streams: Mutex::new(HashMap::new()),
+            // the client opens the odd ones
+            next_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(write_loop(session.clone(), w, rx, idle_timeout));
+        tokio::spawn(read_loop(session.clone(), r));
+        session
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn streams(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    async fn send(&self, frame: Bytes) -> io::Result<()> {
+        self.frames
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "trojan mux session closed"))
+    }
+
+    async fn open_stream(self: &Arc<Self>) -> io::Result<AnyStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "trojan mux session closed",
+            ));
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let (data_tx, mut data_rx) = mpsc::channel::<Bytes>(STREAM_BUFFER);
+        self.streams.lock().unwrap().insert(id, data_tx);
+        if let Err(e) = self.send(encode(CMD_SYN, id, &[])).await {
+            self.streams.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        let (local, remote) = tokio::io::duplex(MAX_FRAME);
+        let (mut remote_r, mut remote_w) = tokio::io::split(remote);
+
+        tokio::spawn(async move {
+            while let Some(data) = data_rx.recv().await {
+                if remote_w.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = remote_w.shutdown().await;
+        });
+
+        let session = self.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_FRAME];
+            loop {
+                match remote_r.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if session.send(encode(CMD_PSH, id, &buf[..n])).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = session.send(encode(CMD_FIN, id, &[])).await;
+        });
+
+        Ok(Box::new(local))
+    }
+}
+
+fn encode(cmd: u8, id: u32, data: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + data.len());
+    buf.put_u8(VERSION);
+    buf.put_u8(cmd);
+    buf.put_u16_le(data.len() as u16);
+    buf.put_u32_le(id);
+    buf.put_slice(data);
+    buf.freeze()
+}
+
+/// The command, the length and the stream id of a frame header.
+fn decode(header: &[u8; HEADER_LEN]) -> io::Result<(u8, usize, u32)> {
+    if header[0] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("trojan mux: unsupported version {}", header[0]),
+        ));
+    }
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok((header[1], len, id))
+}
+
+async fn read_loop(session: Arc<MuxSession>, mut r: ReadHalf<AnyStream>) {
+    let res: io::Result<()> = async {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            r.read_exact(&mut header).await?;
+            let (cmd, len, id) = decode(&header)?;
+            let mut data = vec![0u8; len];
+            r.read_exact(&mut data).await?;
+            match cmd {
+                CMD_PSH => {
+                    let stream = session.streams.lock().unwrap().get(&id).cloned();
+                    if let Some(Err(e)) = stream.map(|x| x.try_send(data.into())) {
+                        if let TrySendError::Full(_) = e {
+                            debug!("trojan mux stream {} too slow, closing it", id);
+                            let _ = session.frames.try_send(encode(CMD_FIN, id, &[]));
+                        }
+                        session.streams.lock().unwrap().remove(&id);
+                    }
+                }
+                CMD_FIN => {
+                    session.streams.lock().unwrap().remove(&id);
+                }
+                // the server doesn't open streams
+                _ => {}
+            }
+        }
+    }
+    .await;
+    if let Err(e) = res {
+        debug!("trojan mux session ended: {}", e);
+    }
+    session.closed.store(true, Ordering::Relaxed);
+    session.streams.lock().unwrap().clear();
+}
+
+async fn write_loop(
+    session: Arc<MuxSession>,
+    mut w: WriteHalf<AnyStream>,
+    mut frames: mpsc::Receiver<Bytes>,
+    idle_timeout: Duration,
+) {
+    let mut keepalive =
+        tokio::time::interval_at(tokio::time::Instant::now() + KEEPALIVE, KEEPALIVE);
+    let mut idle_since = Instant::now();
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            _ = keepalive.tick() => {
+                if session.is_closed() {
+                    break;
+                }
+                if session.streams() > 0 {
+                    idle_since = Instant::now();
+                } else if idle_since.elapsed() >= idle_timeout {
+                    break;
+                }
+                encode(CMD_NOP, 0, &[])
+            }
+        };
+        if w.write_all(&frame).await.is_err() || w.flush().await.is_err() {
+            break;
+        }
+    }
+    session.closed.store(true, Ordering::Relaxed);
+    // the server closes the connection, ending the read loop
+    let _ = w.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::{decode, encode, MuxOption, MuxPool, CMD_FIN, CMD_PSH, CMD_SYN, HEADER_LEN};
+
+    #[test]
+    fn test_frame() {
+        let frame = encode(CMD_PSH, 3, b"hello");
+        assert_eq!(&frame[..HEADER_LEN], &[1, 2, 5, 0, 3, 0, 0, 0]);
+        let (cmd, len, id) = decode(frame[..HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!((cmd, len, id), (CMD_PSH, 5, 3));
+        assert!(decode(&[2, 0, 0, 0, 1, 0, 0, 0]).is_err());
+    }
+
+    async fn read_frame(server: &mut DuplexStream) -> (u8, u32, Vec<u8>) {
+        let mut header = [0u8; HEADER_LEN];
+        server.read_exact(&mut header).await.unwrap();
+        let (cmd, len, id) = decode(&header).unwrap();
+        let mut data = vec![0u8; len];
+        server.read_exact(&mut data).await.unwrap();
+        (cmd, id, data)
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let pool = MuxPool::new(MuxOption {
+            concurrency: 8,
+            idle_timeout: Duration::from_secs(60),
+        });
+
+        let mut a = pool
+            .open(|| async { Ok(Box::new(client) as _) })
+            .await
+            .unwrap();
+        // the connection is taken, a new session would fail to dial
+        let mut b = pool
+            .open(|| async { Err(std::io::ErrorKind::Other.into()) })
+            .await
+            .unwrap();
+
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 1, vec![]));
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 3, vec![]));
+
+        b.write_all(b"to b").await.unwrap();
+        assert_eq!(
+            read_frame(&mut server).await,
+            (CMD_PSH, 3, b"to b".to_vec())
+        );
+
+        server
+            .write_all(&encode(CMD_PSH, 1, b"from a"))
+            .await
+            .unwrap();
+        server.write_all(&encode(CMD_FIN, 1, &[])).await.unwrap();
+        let mut got = vec![];
+        a.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"from a");
+
+        drop(b);
+        assert_eq!(read_frame(&mut server).await, (CMD_FIN, 3, vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream() {
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let pool = MuxPool::new(MuxOption {
+            concurrency: 8,
+            idle_timeout: Duration::from_secs(60),
+        });
+        let mut slow = pool
+            .open(|| async { Ok(Box::new(client) as _) })
+            .await
+            .unwrap();
+        let mut b = pool
+            .open(|| async { Err(std::io::ErrorKind::Other.into()) })
+            .await
+            .unwrap();
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 1, vec![]));
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 3, vec![]));
+
+        // more than the slow stream holds, nothing reads it
+        let data = vec![0u8; 1000];
+        for _ in 0..200 {
+            server.write_all(&encode(CMD_PSH, 1, &data)).await.unwrap();
+        }
+        server
+            .write_all(&encode(CMD_PSH, 3, b"to b"))
+            .await
+            .unwrap();
+
+        assert_eq!(read_frame(&mut server).await, (CMD_FIN, 1, vec![]));
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(1), b.read_exact(&mut buf))
+            .await
+            .expect("held up by the slow stream")
+            .unwrap();
+        assert_eq!(&buf, b"to b");
+
+        // what it held, then closed
+        let mut got = vec![];
+        slow.read_to_end(&mut got).await.unwrap();
+        assert!(!got.is_empty() && got.len() < 200 * data.len());
+    }
+}
@@ -0,0 +1,170 @@
+//! The shadowsocks AEAD layer of trojan-go, under the trojan request, so a
+//! CDN terminating the TLS of the websocket can't read the traffic.
+//!
+//! It's the TCP stream of shadowsocks with no address header, the
+//! [`CryptoStream`] of the shadowsocks crate.
+
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use shadowsocks::{
+    config::ServerType,
+    context::{self, SharedContext},
+    crypto::{v1::openssl_bytes_to_key, CipherKind},
+    relay::tcprelay::crypto_io::{CryptoRead, CryptoStream, CryptoWrite, StreamType},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::AnyStream;
+
+#[derive(Clone)]
+pub struct ShadowsocksOption {
+    pub method: CipherKind,
+    key: Vec<u8>,
+    /// shared by the streams, it only generates their salts
+    ctx: SharedContext,
+}
+
+impl ShadowsocksOption {
+    /// The AEAD methods of trojan-go, `aes-128-gcm`, `aes-256-gcm` or
+    /// `chacha20-ietf-poly1305`.
+    pub fn new(method: &str, password: &str) -> Result<Self, String> {
+        let method = match method.to_lowercase().parse() {
+            Ok(
+                x @ (CipherKind::AES_128_GCM
+                | CipherKind::AES_256_GCM
+                | CipherKind::CHACHA20_POLY1305),
+            ) => x,
+            _ => return Err(format!("unsupported trojan shadowsocks method: {}", method)),
+        };
+        let mut key = vec![0u8; method.key_len()];
+        openssl_bytes_to_key(password.as_bytes(), &mut key);
+        Ok(Self {
+            method,
+            key,
+            ctx: context::Context::new_shared(ServerType::Local),
+        })
+    }
+}
+
+pub struct ShadowsocksStream {
+    inner: CryptoStream<AnyStream>,
+    ctx: SharedContext,
+}
+
+impl Debug for ShadowsocksStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShadowsocksStream")
+            .field("method", &self.inner.method())
+            .finish()
+    }
+}
+
+impl ShadowsocksStream {
+    pub fn new(inner: AnyStream, opts: &ShadowsocksOption) -> Self {
+        Self {
+            inner: CryptoStream::from_stream(
+                &opts.ctx,
+                inner,
+                StreamType::Client,
+                opts.method,
+                &opts.key,
+            ),
+            ctx: opts.ctx.clone(),
+        }
+    }
+}
+
+impl AsyncRead for ShadowsocksStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner)
+            .poll_read_decrypted(cx, &this.ctx, buf)
+            .map_err(Into::into)
+    }
+}
+
+impl AsyncWrite for ShadowsocksStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_write_encrypted(cx, buf)
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut().inner.poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut().inner.poll_shutdown(cx).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{ShadowsocksOption, ShadowsocksStream};
+
+    #[test]
+    fn test_option() {
+        let key = |method| ShadowsocksOption::new(method, "example").map(|x| x.key);
+        // EVP_BytesToKey: md5(password), then md5(md5(password) + password)
+        assert_eq!(
+            crate::common::utils::encode_hex(&key("AES-256-GCM").unwrap()),
+            "1a79a4d60de6718e8e5b326e338ae5333c4a05affed953b4516a4d49e7428ec8"
+        );
+        assert_eq!(key("aes-128-gcm").unwrap().len(), 16);
+        for method in ["aes-128-cfb", "2022-blake3-aes-128-gcm", "none"] {
+            assert!(key(method).is_err(), "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        for method in ["aes-128-gcm", "aes-256-gcm", "chacha20-ietf-poly1305"] {
+            let opts = ShadowsocksOption::new(method, "example").unwrap();
+            let (a, b) = tokio::io::duplex(1024);
+            let mut a = ShadowsocksStream::new(Box::new(a), &opts);
+            let mut b = ShadowsocksStream::new(Box::new(b), &opts);
+
+            // larger than a chunk, and than the duplex buffer
+            let data = (0..40000).map(|x| x as u8).collect::<Vec<_>>();
+            let expected = data.clone();
+            let writer = tokio::spawn(async move {
+                a.write_all(&data).await.unwrap();
+                a.shutdown().await.unwrap();
+            });
+            let mut got = vec![];
+            b.read_to_end(&mut got).await.unwrap();
+            writer.await.unwrap();
+            assert_eq!(got, expected);
+        }
+
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = ShadowsocksStream::new(
+            Box::new(a),
+            &ShadowsocksOption::new("aes-128-gcm", "example").unwrap(),
+        );
+        let mut b = ShadowsocksStream::new(
+            Box::new(b),
+            &ShadowsocksOption::new("aes-128-gcm", "other").unwrap(),
+        );
+        a.write_all(b"hello").await.unwrap();
+        a.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        assert!(b.read_exact(&mut buf).await.is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use bytes::Bytes;
 use quinn::ZeroRttAccepted;
@@ -54,7 +54,18 @@ impl TuicConnection {
     ) -> anyhow::Result<()> {
         let addr_display = addr.to_string();
 
-        match self.udp_relay_mode {
+        let mode = match self.udp_relay_mode {
+            UdpRelayMode::Native if !self.udp_fragment && !self.fits_datagram(&pkt, &addr) => {
+                tracing::debug!(
+                    "[udp] [{assoc_id:#06x}] {} bytes to {addr_display} too big for a datagram",
+                    pkt.len()
+                );
+                UdpRelayMode::Quic
+            }
+            mode => mode,
+        };
+
+        match mode {
             UdpRelayMode::Native => {
                 tracing::info!("[udp] [{assoc_id:#06x}] [to-native] to {addr_display}");
                 match self.inner.packet_native(pkt, addr, assoc_id) {
@@ -82,6 +93,14 @@ impl TuicConnection {
         }
     }
 
+    /// Whether the packet is sent unfragmented, the size of the datagrams
+    /// follows the path MTU.
+    fn fits_datagram(&self, pkt: &[u8], addr: &Address) -> bool {
+        self.conn
+            .max_datagram_size()
+            .is_some_and(|max| packet_header_len(addr) + pkt.len() <= max)
+    }
+
     pub async fn incoming_udp(&self, pkt: Packet) {
         let assoc_id = pkt.assoc_id();
         let pkt_id = pkt.pkt_id();
@@ -177,3 +196,17 @@ impl TuicConnection {
         }
     }
 }
+
+/// The length of the header of a TUIC v5 packet command to `addr`.
+fn packet_header_len(addr: &Address) -> usize {
+    // version, command, associate ID, packet ID, fragment total, fragment
+    // ID and size
+    let header = 1 + 1 + 2 + 2 + 1 + 1 + 2;
+    header
+        + match addr {
+            Address::None => 1,
+            Address::DomainAddress(domain, _) => 1 + 1 + domain.len() + 2,
+            Address::SocketAddress(SocketAddr::V4(_)) => 1 + 4 + 2,
+            Address::SocketAddress(SocketAddr::V6(_)) => 1 + 16 + 2,
+        }
+}
@@ -6,10 +6,8 @@ pub(crate) mod types;
 use crate::proxy::tuic::types::SocketAdderTrans;
 use anyhow::Result;
 use axum::async_trait;
-use quinn::{EndpointConfig, TokioRuntime};
-use std::net::SocketAddr;
+use quinn::{EndpointConfig, MtuDiscoveryConfig, TokioRuntime};
 use std::{
-    net::{Ipv4Addr, Ipv6Addr, UdpSocket},
     sync::{
         atomic::{AtomicU16, Ordering},
         Arc,
@@ -27,8 +25,10 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::tls::GLOBAL_ROOT_STORE,
-    proxy::tuic::types::{ServerAddr, TuicEndpoint},
+    proxy::{
+        tuic::types::{ServerAddr, TuicEndpoint},
+        utils::Interface,
+    },
     session::Session,
 };
 
@@ -36,18 +36,20 @@ use crate::session::SocksAddr as ClashSocksAddr;
 use quinn::ClientConfig as QuinnConfig;
 use quinn::Endpoint as QuinnEndpoint;
 use quinn::TransportConfig as QuinnTransportConfig;
-use quinn::{congestion::CubicConfig, VarInt};
-use tokio::sync::Mutex as AsyncMutex;
-
-use rustls::client::ClientConfig as TlsConfig;
+use quinn::VarInt;
+use tokio::sync::{Mutex as AsyncMutex, OnceCell};
 
-use self::types::{CongestionControl, TuicConnection, UdpSession};
+use self::types::{TuicConnection, UdpSession};
 
+use super::transport::quic::{self, CongestionControl};
 use super::ConnectorType;
 use super::{
     datagram::UdpPacket, AnyOutboundDatagram, AnyOutboundHandler, OutboundHandler, OutboundType,
 };
 
+/// the smallest MTU of the QUIC paths
+const MIN_MTU: u16 = 1200;
+
 #[derive(Debug, Clone)]
 pub struct HandlerOptions {
     pub name: String,
@@ -63,6 +65,12 @@ pub struct HandlerOptions {
     pub request_timeout: Duration,
     pub congestion_controller: CongestionControl,
     pub max_udp_relay_packet_size: u64,
+    pub mtu_discovery: bool,
+    /// the MTU assumed until discovered
+    pub initial_mtu: u16,
+    /// fragment the packets too big for a datagram rather than relaying
+    /// them on a stream, native UDP relay only
+    pub udp_fragment: bool,
     pub max_open_stream: VarInt,
     pub gc_interval: Duration,
     pub gc_lifetime: Duration,
@@ -77,7 +85,10 @@ pub struct HandlerOptions {
 
 pub struct Handler {
     opts: HandlerOptions,
-    ep: TuicEndpoint,
+    udp_relay_mode: types::UdpRelayMode,
+    /// bound on the first connection, a config of many nodes doesn't hold
+    /// a socket for each
+    ep: OnceCell<TuicEndpoint>,
     conn: AsyncMutex<Option<Arc<TuicConnection>>>,
     next_assoc_id: AtomicU16,
 }
@@ -125,15 +136,30 @@ impl OutboundHandler for Handler {
 impl Handler {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(opts: HandlerOptions) -> Result<AnyOutboundHandler, crate::Error> {
-        let mut crypto = TlsConfig::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[&rustls::version::TLS13])
-            .unwrap()
-            .with_root_certificates(GLOBAL_ROOT_STORE.clone())
-            .with_no_client_auth();
+        let udp_relay_mode = opts
+            .udp_relay_mode
+            .parse::<types::UdpRelayMode>()
+            .map_err(|e| crate::Error::InvalidConfig(format!("tuic {}: {}", opts.name, e)))?;
+        if opts.initial_mtu < MIN_MTU {
+            return Err(crate::Error::InvalidConfig(format!(
+                "tuic {}: initial-mtu must be at least {}",
+                opts.name, MIN_MTU
+            )));
+        }
+
+        Ok(Arc::new(Self {
+            opts,
+            udp_relay_mode,
+            ep: OnceCell::new(),
+            conn: AsyncMutex::new(None),
+            next_assoc_id: AtomicU16::new(0),
+        }))
+    }
+
+    fn new_endpoint(&self, iface: Option<&Interface>) -> Result<TuicEndpoint> {
+        let opts = &self.opts;
+        let mut crypto = quic::client_crypto(&opts.alpn, opts.skip_cert_verify);
         // TODO(error-handling) if alpn not match the following error will be throw: aborted by peer: the cryptographic handshake failed: error 120: peer doesn't support any known protocol
-        crypto.alpn_protocols.clone_from(&opts.alpn);
         crypto.enable_early_data = true;
         crypto.enable_sni = !opts.disable_sni;
         let mut quinn_config = QuinnConfig::new(Arc::new(crypto));
@@ -144,13 +170,12 @@ impl Handler {
             .send_window(opts.send_window)
             .stream_receive_window(opts.receive_window)
             .max_idle_timeout(None)
-            .congestion_controller_factory(Arc::new(CubicConfig::default()));
+            .initial_mtu(opts.initial_mtu)
+            .mtu_discovery_config(opts.mtu_discovery.then(MtuDiscoveryConfig::default));
+        opts.congestion_controller
+            .apply(&mut quinn_transport_config);
         quinn_config.transport_config(Arc::new(quinn_transport_config));
-        // Try to create an IPv4 socket as the placeholder first, if it fails, try IPv6.
-        let socket =
-            UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).or_else(|err| {
-                UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).map_err(|_| err)
-            })?;
+        let socket = quic::bind_socket(None, iface)?;
 
         let mut endpoint = QuinnEndpoint::new(
             EndpointConfig::default(),
@@ -159,35 +184,37 @@ impl Handler {
             Arc::new(TokioRuntime),
         )?;
         endpoint.set_default_client_config(quinn_config);
-        let endpoint = TuicEndpoint {
+        Ok(TuicEndpoint {
             ep: endpoint,
             server: ServerAddr::new(opts.server.clone(), opts.port, None),
             uuid: opts.uuid,
             password: Arc::from(opts.password.clone().into_bytes().into_boxed_slice()),
-            udp_relay_mode: types::UdpRelayMode::Native,
+            udp_relay_mode: self.udp_relay_mode,
+            udp_fragment: opts.udp_fragment,
             zero_rtt_handshake: opts.reduce_rtt,
             heartbeat: opts.heartbeat_interval,
             gc_interval: opts.gc_interval,
             gc_lifetime: opts.gc_lifetime,
-        };
-        Ok(Arc::new(Self {
-            opts,
-            ep: endpoint,
-            conn: AsyncMutex::new(None),
-            next_assoc_id: AtomicU16::new(0),
-        }))
+        })
     }
-    async fn get_conn(&self) -> Result<Arc<TuicConnection>> {
+
+    /// The session dialing a new connection picks its interface.
+    async fn get_conn(&self, sess: &Session) -> Result<Arc<TuicConnection>> {
+        let iface = sess.iface.as_ref();
         let fut = async {
+            let ep = self
+                .ep
+                .get_or_try_init(|| async { self.new_endpoint(iface) })
+                .await?;
             let mut guard = self.conn.lock().await;
             if guard.is_none() {
                 // init
-                *guard = Some(self.ep.connect().await?);
+                *guard = Some(ep.connect(iface).await?);
             }
             let conn = guard.take().unwrap();
             let conn = if conn.check_open().is_err() {
                 // reconnect
-                self.ep.connect().await?
+                ep.connect(iface).await?
             } else {
                 conn
             };
@@ -202,7 +229,7 @@ impl Handler {
         sess: &Session,
         _resolver: ThreadSafeDNSResolver,
     ) -> Result<BoxedChainedStream> {
-        let conn = self.get_conn().await?;
+        let conn = self.get_conn(sess).await?;
         let dest = sess.destination.clone().into_tuic();
         let tuic_tcp = conn.connect_tcp(dest).await?.compat();
 
@@ -216,7 +243,7 @@ impl Handler {
         sess: &Session,
         _resolver: ThreadSafeDNSResolver,
     ) -> Result<BoxedChainedDatagram> {
-        let conn = self.get_conn().await?;
+        let conn = self.get_conn(sess).await?;
 
         let assos_id = self.next_assoc_id.fetch_add(1, Ordering::Relaxed);
         let quic_udp = TuicDatagramOutbound::new(assos_id, conn, sess.source.into());
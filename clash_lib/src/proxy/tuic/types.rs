@@ -5,7 +5,7 @@ use quinn::{Endpoint as QuinnEndpoint, ZeroRttAccepted};
 use register_count::Counter;
 use std::collections::HashMap;
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::{atomic::AtomicU32, Arc},
     time::Duration,
@@ -14,7 +14,7 @@ use tokio::sync::RwLock as AsyncRwLock;
 use tuic_quinn::Connection as InnerConnection;
 use uuid::Uuid;
 
-use crate::proxy::datagram::UdpPacket;
+use crate::proxy::{datagram::UdpPacket, transport::quic, utils::Interface};
 
 pub struct TuicEndpoint {
     pub ep: QuinnEndpoint,
@@ -22,13 +22,15 @@ pub struct TuicEndpoint {
     pub uuid: Uuid,
     pub password: Arc<[u8]>,
     pub udp_relay_mode: UdpRelayMode,
+    pub udp_fragment: bool,
     pub zero_rtt_handshake: bool,
     pub heartbeat: Duration,
     pub gc_interval: Duration,
     pub gc_lifetime: Duration,
 }
 impl TuicEndpoint {
-    pub async fn connect(&self) -> Result<Arc<TuicConnection>> {
+    /// A socket of the other family is bound on `iface`.
+    pub async fn connect(&self, iface: Option<&Interface>) -> Result<Arc<TuicConnection>> {
         let mut last_err = None;
 
         for addr in self.server.resolve().await? {
@@ -39,14 +41,8 @@ impl TuicEndpoint {
                     addr.is_ipv6() && self.ep.local_addr().map_or(false, |addr| addr.is_ipv6());
 
                 if !match_ipv4 && !match_ipv6 {
-                    let bind_addr = if addr.is_ipv4() {
-                        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
-                    } else {
-                        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
-                    };
-
                     self.ep
-                        .rebind(UdpSocket::bind(bind_addr).map_err(|err| {
+                        .rebind(quic::bind_socket(Some(addr), iface).map_err(|err| {
                             anyhow!("failed to create endpoint UDP socket {}", err)
                         })?)
                         .map_err(|err| anyhow!("failed to rebind endpoint UDP socket {}", err))?;
@@ -72,6 +68,7 @@ impl TuicEndpoint {
                         conn,
                         zero_rtt_accepted,
                         self.udp_relay_mode,
+                        self.udp_fragment,
                         self.uuid,
                         self.password.clone(),
                         self.heartbeat,
@@ -97,6 +94,9 @@ pub struct TuicConnection {
     pub max_concurrent_uni_streams: Arc<AtomicU32>,
     pub max_concurrent_bi_streams: Arc<AtomicU32>,
     pub udp_relay_mode: UdpRelayMode,
+    /// whether the packets too big for a datagram are fragmented, or else
+    /// relayed on a stream
+    pub udp_fragment: bool,
     pub udp_sessions: Arc<AsyncRwLock<HashMap<u16, UdpSession>>>,
 }
 
@@ -117,6 +117,7 @@ impl TuicConnection {
         conn: QuinnConnection,
         zero_rtt_accepted: Option<ZeroRttAccepted>,
         udp_relay_mode: UdpRelayMode,
+        udp_fragment: bool,
         uuid: Uuid,
         password: Arc<[u8]>,
         heartbeat: Duration,
@@ -129,6 +130,7 @@ impl TuicConnection {
             uuid,
             password,
             udp_relay_mode,
+            udp_fragment,
             remote_uni_stream_cnt: Counter::new(),
             remote_bi_stream_cnt: Counter::new(),
             // TODO: seems tuic dynamicly adjust the size of max concurrent streams, is it necessary to configure the stream size?
@@ -227,33 +229,6 @@ impl FromStr for UdpRelayMode {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum CongestionControl {
-    Cubic,
-    NewReno,
-    Bbr,
-}
-impl From<&str> for CongestionControl {
-    fn from(s: &str) -> Self {
-        if s.eq_ignore_ascii_case("cubic") {
-            Self::Cubic
-        } else if s.eq_ignore_ascii_case("new_reno") || s.eq_ignore_ascii_case("newreno") {
-            Self::NewReno
-        } else if s.eq_ignore_ascii_case("bbr") {
-            Self::Bbr
-        } else {
-            tracing::warn!("Unknown congestion controller {s}. Use default controller");
-            Self::default()
-        }
-    }
-}
-
-impl Default for CongestionControl {
-    fn default() -> Self {
-        Self::Cubic
-    }
-}
-
 pub trait SocketAdderTrans {
     fn into_tuic(self) -> tuic::Address;
 }
@@ -1,13 +1,25 @@
-use super::{datagram::TunDatagram, netstack};
-use std::{net::SocketAddr, sync::Arc};
+use super::{
+    datagram::TunDatagram,
+    route::RouteGuard,
+    stack::{Stack, TunStream, UdpMessage},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use futures::{SinkExt, StreamExt};
-use tracing::{error, info, trace, warn};
+use futures::{stream::BoxStream, SinkExt, StreamExt};
+use hickory_proto::op;
+use ipnet::Ipv4Net;
+use tracing::{debug, error, info, trace, warn};
 use tun::{Device, TunPacket};
 use url::Url;
 
 use crate::{
-    app::{dispatcher::Dispatcher, dns::ThreadSafeDNSResolver},
+    app::{
+        dispatcher::Dispatcher,
+        dns::{self, ThreadSafeDNSResolver},
+    },
     common::errors::map_io_error,
     config::internal::config::TunConfig,
     proxy::datagram::UdpPacket,
@@ -15,8 +27,10 @@ use crate::{
     Error, Runner,
 };
 
+const DEFAULT_NETWORK: &str = "198.18.0.0/16";
+
 async fn handle_inbound_stream(
-    stream: netstack::TcpStream,
+    stream: Box<dyn TunStream>,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
@@ -33,15 +47,16 @@ async fn handle_inbound_stream(
 }
 
 async fn handle_inbound_datagram(
-    socket: Box<netstack::UdpSocket>,
+    mut udp_rx: BoxStream<'static, UdpMessage>,
+    mut udp_tx: impl futures::Sink<UdpMessage, Error = std::io::Error> + Send + Unpin + 'static,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
+    dns_hijack: Vec<DnsHijack>,
+    dns_rule_aware: bool,
 ) {
-    let local_addr = socket.local_addr();
-    // tun i/o
-
-    let (ls, mut lr) = socket.split();
-    let ls = Arc::new(ls);
+    // tun i/o, the replies of the dispatcher and the hijacked DNS go
+    // through a single writer
+    let (s_tx, mut s_rx) = tokio::sync::mpsc::channel::<UdpMessage>(32);
 
     // dispatcher <-> tun communications
     let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
@@ -51,7 +66,7 @@ async fn handle_inbound_datagram(
 
     // for dispatcher - the dispatcher would receive packets from this channel, which is from the stack
     // and send back packets to this channel, which is to the tun
-    let udp_stream = TunDatagram::new(l_tx, d_rx, local_addr);
+    let udp_stream = TunDatagram::new(l_tx, d_rx);
 
     let sess = Session {
         network: Network::Udp,
@@ -60,6 +75,16 @@ async fn handle_inbound_datagram(
     };
 
     let closer = dispatcher.dispatch_datagram(sess, Box::new(udp_stream));
+    let (hijack_tx, hijack_resolver) = (s_tx.clone(), resolver.clone());
+
+    // -> tun
+    let fut0 = tokio::spawn(async move {
+        while let Some(msg) = s_rx.recv().await {
+            if let Err(e) = udp_tx.send(msg).await {
+                warn!("failed to send udp packet to netstack: {}", e);
+            }
+        }
+    });
 
     // dispatcher -> tun
     let fut1 = tokio::spawn(async move {
@@ -82,19 +107,32 @@ async fn handle_inbound_datagram(
                     }
                 }
             };
-            if let Err(e) = ls.send_to(
-                &pkt.data[..],
-                &src_addr,
-                &pkt.dst_addr.must_into_socket_addr(),
-            ) {
-                warn!("failed to send udp packet to netstack: {}", e);
+            let msg = (pkt.data, src_addr, pkt.dst_addr.must_into_socket_addr());
+            if s_tx.send(msg).await.is_err() {
+                break;
             }
         }
     });
 
     // tun -> dispatcher
     let fut2 = tokio::spawn(async move {
-        while let Ok((data, src_addr, dst_addr)) = lr.recv_from().await {
+        while let Some((data, src_addr, dst_addr)) = udp_rx.next().await {
+            if dns_hijack.iter().any(|x| x.matches(&dst_addr)) {
+                let (tx, resolver) = (hijack_tx.clone(), hijack_resolver.clone());
+                let dispatcher = dns_rule_aware.then(|| dispatcher.clone());
+                tokio::spawn(async move {
+                    match exchange_hijacked(&data, &resolver, dispatcher.as_deref()).await {
+                        Ok(reply) => {
+                            let _ = tx.send((reply, dst_addr, src_addr)).await;
+                        }
+                        Err(e) => {
+                            debug!("failed to answer hijacked dns query to {}: {}", dst_addr, e)
+                        }
+                    }
+                });
+                continue;
+            }
+
             let pkt = UdpPacket {
                 data,
                 src_addr: src_addr.into(),
@@ -114,13 +152,65 @@ async fn handle_inbound_datagram(
         closer.send(0).ok();
     });
 
-    let _ = futures::future::join(fut1, fut2).await;
+    let _ = futures::future::join3(fut0, fut1, fut2).await;
+}
+
+/// A destination of `dns-hijack`, any address if `ip` is not set.
+struct DnsHijack {
+    ip: Option<IpAddr>,
+    port: u16,
 }
 
+impl DnsHijack {
+    fn matches(&self, dst: &SocketAddr) -> bool {
+        self.port == dst.port() && self.ip.map_or(true, |x| x == dst.ip())
+    }
+}
+
+/// `any:53`, `198.18.0.2:53` or `10.0.0.5`, optionally with `udp://`.
+fn parse_dns_hijack(s: &str) -> Result<DnsHijack, Error> {
+    let invalid = || Error::InvalidConfig(format!("invalid tun dns-hijack: {}", s));
+    let addr = match s.split_once("://") {
+        Some(("udp", addr)) => addr,
+        Some(_) => return Err(invalid()),
+        None => s,
+    };
+    if let Some(port) = addr.strip_prefix("any:") {
+        return Ok(DnsHijack {
+            ip: None,
+            port: port.parse().map_err(|_| invalid())?,
+        });
+    }
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(DnsHijack {
+            ip: Some(addr.ip()),
+            port: addr.port(),
+        });
+    }
+    Ok(DnsHijack {
+        ip: Some(addr.parse().map_err(|_| invalid())?),
+        port: 53,
+    })
+}
+
+/// Answered as the DNS server does, with the fake IPs and the filters.
+async fn exchange_hijacked(
+    data: &[u8],
+    resolver: &ThreadSafeDNSResolver,
+    dispatcher: Option<&Dispatcher>,
+) -> anyhow::Result<Vec<u8>> {
+    let msg = op::Message::from_vec(data)?;
+    let reply = dns::handle_query(resolver, dispatcher, &msg).await?;
+    Ok(reply.to_vec()?)
+}
+
+/// The hijacked DNS queries are matched against the rules with
+/// `dns_rule_aware`, as those of the DNS server if it's `rule-aware`.
 pub fn get_runner(
     cfg: TunConfig,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
+    dns_rule_aware: bool,
 ) -> Result<Option<Runner>, Error> {
     if !cfg.enable {
         trace!("tun is disabled");
@@ -128,31 +218,60 @@ pub fn get_runner(
     }
 
     let device_id = cfg.device_id;
-
-    let u =
-        Url::parse(&device_id).map_err(|x| Error::InvalidConfig(format!("tun device {}", x)))?;
+    let dns_hijack = cfg
+        .dns_hijack
+        .iter()
+        .map(|x| parse_dns_hijack(x))
+        .collect::<Result<Vec<_>, _>>()?;
 
     let mut tun_cfg = tun::Configuration::default();
 
-    match u.scheme() {
-        "fd" => {
-            let fd = u
-                .host()
-                .expect("tun fd must be provided")
-                .to_string()
-                .parse()
-                .map_err(|x| Error::InvalidConfig(format!("tun fd {}", x)))?;
-            tun_cfg.raw_fd(fd);
-        }
-        "dev" => {
-            let dev = u.host().expect("tun dev must be provided").to_string();
-            tun_cfg.name(dev);
+    // the address is left to the owner of a device given by fd, and to the
+    // one of a named device unless the network is set
+    let mut set_address = cfg.network.is_some();
+    if device_id.is_empty() {
+        set_address = true;
+    } else {
+        let u = Url::parse(&device_id)
+            .map_err(|x| Error::InvalidConfig(format!("tun device {}", x)))?;
+        match u.scheme() {
+            "fd" => {
+                let fd = u
+                    .host()
+                    .expect("tun fd must be provided")
+                    .to_string()
+                    .parse()
+                    .map_err(|x| Error::InvalidConfig(format!("tun fd {}", x)))?;
+                tun_cfg.raw_fd(fd);
+                set_address = false;
+            }
+            "dev" => {
+                let dev = u.host().expect("tun dev must be provided").to_string();
+                tun_cfg.name(dev);
+            }
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "invalid device id: {}",
+                    device_id
+                )));
+            }
         }
-        _ => {
-            return Err(Error::InvalidConfig(format!(
-                "invalid device id: {}",
-                device_id
-            )));
+    }
+
+    if set_address {
+        let network = cfg
+            .network
+            .as_deref()
+            .unwrap_or(DEFAULT_NETWORK)
+            .parse::<Ipv4Net>()
+            .map_err(|x| Error::InvalidConfig(format!("tun network {}", x)))?;
+        let address = network
+            .hosts()
+            .next()
+            .ok_or_else(|| Error::InvalidConfig(format!("tun network {} has no hosts", network)))?;
+        tun_cfg.address(address).netmask(network.netmask());
+        if let Some(IpAddr::V4(gateway)) = cfg.gateway {
+            tun_cfg.destination(gateway);
         }
     }
 
@@ -163,17 +282,39 @@ pub fn get_runner(
     let tun_name = tun.get_ref().name().map_err(map_io_error)?;
     info!("tun started at {}", tun_name);
 
-    let (stack, mut tcp_listener, udp_socket) =
-        netstack::NetStack::with_buffer_size(512, 256).map_err(map_io_error)?;
+    let route_guard = if cfg.auto_route {
+        Some(RouteGuard::setup(&tun_name)?)
+    } else {
+        None
+    };
+
+    let Stack {
+        sink: mut stack_sink,
+        stream: mut stack_stream,
+        tcp: mut tcp_listener,
+        udp_rx,
+        udp_tx,
+        runner: stack_runner,
+    } = Stack::new(cfg.stack).map_err(map_io_error)?;
+    debug!("tun stack: {:?}", cfg.stack);
 
     Ok(Some(Box::pin(async move {
+        // the routes are removed once the runner is dropped
+        let _route_guard = route_guard;
         let framed = tun.into_framed();
 
         let (mut tun_sink, mut tun_stream) = framed.split();
-        let (mut stack_sink, mut stack_stream) = stack.split();
 
         let mut futs: Vec<Runner> = vec![];
 
+        if let Some(stack_runner) = stack_runner {
+            futs.push(Box::pin(async move {
+                stack_runner
+                    .await
+                    .map_err(|x| Error::Operation(format!("tun stack stopped unexpectedly: {}", x)))
+            }));
+        }
+
         // dispatcher -> stack -> tun
         futs.push(Box::pin(async move {
             while let Some(pkt) = stack_stream.next().await {
@@ -229,7 +370,15 @@ pub fn get_runner(
         }));
 
         futs.push(Box::pin(async move {
-            handle_inbound_datagram(udp_socket, dispatcher, resolver).await;
+            handle_inbound_datagram(
+                udp_rx,
+                udp_tx,
+                dispatcher,
+                resolver,
+                dns_hijack,
+                dns_rule_aware,
+            )
+            .await;
             Err(Error::Operation("tun stopped unexpectedly 3".to_string()))
         }));
 
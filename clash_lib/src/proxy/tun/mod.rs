@@ -1,4 +1,8 @@
 pub mod inbound;
 pub use netstack_lwip as netstack;
-mod datagram;
+pub(crate) mod datagram;
+mod route;
+mod stack;
 pub use inbound::get_runner as get_tun_runner;
+pub use route::default_interface;
+pub use stack::TunStack;
@@ -0,0 +1,263 @@
+//! Route all the traffic into the tun device while running, and find the
+//! interface of the default route for the outbound sockets to bypass it.
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use tracing::info;
+use tracing::warn;
+
+use crate::Error;
+
+/// The table of the routes into the device on Linux, the one of
+/// `gateway.auto-route` is 2468.
+#[cfg(target_os = "linux")]
+const ROUTE_TABLE: &str = "2469";
+
+/// Holds the routes into the device, removed on drop.
+pub struct RouteGuard {
+    #[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+    teardown: Vec<Vec<String>>,
+}
+
+/// The interface of the default route, before the device takes it over.
+pub fn default_interface() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let table = std::fs::read_to_string("/proc/net/route").ok()?;
+        parse_proc_route(&table)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let output = std::process::Command::new("netstat")
+            .args(["-rn", "-f", "inet"])
+            .output()
+            .ok()?;
+        parse_netstat_default(&String::from_utf8_lossy(&output.stdout))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        warn!("tun auto-detect-interface is only supported on linux and macos, skipping");
+        None
+    }
+}
+
+/// The interface of the default route of `/proc/net/route`, the one with
+/// the lowest metric if there are a few.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_route(table: &str) -> Option<String> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+            match fields[..] {
+                [iface, "00000000", _, _, _, _, metric, "00000000", ..] => {
+                    Some((metric.parse::<u32>().ok()?, iface.to_owned()))
+                }
+                _ => None,
+            }
+        })
+        .min()
+        .map(|(_, iface)| iface)
+}
+
+/// The interface of the first default route of `netstat -rn`, but those of
+/// the tun devices, which hold the routes of a running device, e.g. while
+/// reloading.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_netstat_default(table: &str) -> Option<String> {
+    table.lines().find_map(|line| {
+        // Destination Gateway Flags Netif Expire
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["default", _, _, netif, ..] if !netif.starts_with("utun") => Some(netif.to_owned()),
+            _ => None,
+        }
+    })
+}
+
+impl RouteGuard {
+    /// Route the traffic into the device, but that of the sockets bound to
+    /// another interface. The IPv6 routes are skipped with a warning on a
+    /// host without IPv6.
+    pub fn setup(device: &str) -> Result<Self, Error> {
+        let mut guard = Self { teardown: vec![] };
+        guard.auto_route(device)?;
+        if let Err(e) = guard.auto_route_v6(device) {
+            warn!("the IPv6 traffic is not routed into tun {}: {}", device, e);
+        }
+        Ok(guard)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn auto_route(&mut self, device: &str) -> Result<(), Error> {
+        let (route, rule) = (["ip", "route"], ["ip", "rule"]);
+        self.apply(
+            &route,
+            ("replace", "del"),
+            &["default", "dev", device, "table", ROUTE_TABLE],
+        )?;
+        // the routes of the main table but the default one still apply, e.g.
+        // those of the LAN
+        self.apply(
+            &rule,
+            ("add", "del"),
+            &[
+                "lookup",
+                "main",
+                "suppress_prefixlength",
+                "0",
+                "pref",
+                "9000",
+            ],
+        )?;
+        self.apply(
+            &rule,
+            ("add", "del"),
+            &["lookup", ROUTE_TABLE, "pref", "9001"],
+        )?;
+        info!("routing the traffic into tun {}", device);
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn auto_route(&mut self, device: &str) -> Result<(), Error> {
+        // the two halves are more specific than the default route, which is
+        // left as is for the sockets bound to its interface
+        for net in ["0.0.0.0/1", "128.0.0.0/1"] {
+            self.apply(
+                &["route", "-n"],
+                ("add", "delete"),
+                &["-net", net, "-interface", device],
+            )?;
+        }
+        info!("routing the traffic into tun {}", device);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn auto_route_v6(&mut self, device: &str) -> Result<(), Error> {
+        let (route, rule) = (["ip", "-6", "route"], ["ip", "-6", "rule"]);
+        self.apply(
+            &route,
+            ("replace", "del"),
+            &["default", "dev", device, "table", ROUTE_TABLE],
+        )?;
+        self.apply(
+            &rule,
+            ("add", "del"),
+            &[
+                "lookup",
+                "main",
+                "suppress_prefixlength",
+                "0",
+                "pref",
+                "9000",
+            ],
+        )?;
+        self.apply(
+            &rule,
+            ("add", "del"),
+            &["lookup", ROUTE_TABLE, "pref", "9001"],
+        )
+    }
+
+    #[cfg(target_os = "macos")]
+    fn auto_route_v6(&mut self, device: &str) -> Result<(), Error> {
+        for net in ["::/1", "8000::/1"] {
+            self.apply(
+                &["route", "-n"],
+                ("add", "delete"),
+                &["-inet6", "-net", net, "-interface", device],
+            )?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn auto_route(&mut self, _: &str) -> Result<(), Error> {
+        warn!("tun auto-route is only supported on linux and macos, skipping");
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn auto_route_v6(&mut self, _: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Run `cmd verb args`, and `cmd undo args` on drop.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn apply(
+        &mut self,
+        cmd: &[&str],
+        (verb, undo): (&str, &str),
+        args: &[&str],
+    ) -> Result<(), Error> {
+        run(&[cmd, &[verb], args].concat())?;
+        self.teardown.push(
+            [cmd, &[undo], args]
+                .concat()
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run<S: AsRef<std::ffi::OsStr>>(cmd: &[S]) -> Result<(), Error> {
+    let output = std::process::Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Operation(format!(
+            "`{}` failed: {}",
+            cmd.iter()
+                .map(|x| x.as_ref().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        for cmd in self.teardown.drain(..).rev() {
+            if let Err(e) = run(&cmd) {
+                warn!("failed to remove the tun routes: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_netstat_default, parse_proc_route};
+
+    #[test]
+    fn test_parse_proc_route() {
+        let table =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+                     eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                     eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n";
+        assert_eq!(parse_proc_route(table).as_deref(), Some("eth0"));
+        assert_eq!(parse_proc_route("Iface\tDestination\n"), None);
+    }
+
+    #[test]
+    fn test_parse_netstat_default() {
+        let table = "Routing tables\n\n\
+                     Internet:\n\
+                     Destination        Gateway            Flags        Netif Expire\n\
+                     0/1                198.18.0.1         UGScg        utun4\n\
+                     default            link#20            UCSIg        utun4\n\
+                     default            192.168.1.1        UGScg          en0\n\
+                     127                127.0.0.1          UCS            lo0\n";
+        assert_eq!(parse_netstat_default(table).as_deref(), Some("en0"));
+        assert_eq!(parse_netstat_default("Destination Gateway\n"), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! The user space TCP/IP stacks turning the packets of the device into
+//! connections, and back.
+
+use std::{io, net::SocketAddr, pin::Pin};
+
+use futures::{future::BoxFuture, stream::BoxStream, Sink, SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::common::errors::{map_io_error, new_io_error};
+
+use super::netstack;
+
+/// The `stack` of the tun inbound.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunStack {
+    /// the lwIP stack
+    #[default]
+    System,
+    /// the smoltcp stack, named after the gVisor one of other clash cores
+    Gvisor,
+    /// TCP by the lwIP stack and the rest by the smoltcp one
+    Mixed,
+}
+
+/// A UDP payload with its source and destination.
+pub type UdpMessage = (Vec<u8>, SocketAddr, SocketAddr);
+
+/// A connection accepted by either stack.
+pub trait TunStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TunStream for T {}
+
+type BoxSink<T> = Pin<Box<dyn Sink<T, Error = io::Error> + Send>>;
+
+pub struct Stack {
+    /// packets from the device
+    pub sink: BoxSink<Vec<u8>>,
+    /// packets to the device
+    pub stream: BoxStream<'static, io::Result<Vec<u8>>>,
+    /// the accepted TCP connections, with their source and destination
+    pub tcp: BoxStream<'static, (Box<dyn TunStream>, SocketAddr, SocketAddr)>,
+    /// the received UDP messages
+    pub udp_rx: BoxStream<'static, UdpMessage>,
+    /// the UDP messages to send back
+    pub udp_tx: BoxSink<UdpMessage>,
+    /// drives the smoltcp stack, if used
+    pub runner: Option<BoxFuture<'static, io::Result<()>>>,
+}
+
+impl Stack {
+    pub fn new(kind: TunStack) -> io::Result<Self> {
+        match kind {
+            TunStack::System => lwip(),
+            TunStack::Gvisor => smoltcp(true),
+            TunStack::Mixed => mixed(),
+        }
+    }
+}
+
+fn lwip() -> io::Result<Stack> {
+    let (stack, tcp_listener, udp_socket) =
+        netstack::NetStack::with_buffer_size(512, 256).map_err(map_io_error)?;
+    let (sink, stream) = stack.split();
+    let (udp_tx, udp_rx) = udp_socket.split();
+    let udp_tx = futures::sink::unfold(udp_tx, |tx, (data, src, dst): UdpMessage| async move {
+        tx.send_to(&data, &src, &dst)?;
+        Ok::<_, io::Error>(tx)
+    });
+    let udp_rx = futures::stream::unfold(udp_rx, |mut rx| async move {
+        let msg = rx.recv_from().await.ok()?;
+        Some((msg, rx))
+    });
+
+    Ok(Stack {
+        sink: Box::pin(sink),
+        stream: Box::pin(stream),
+        tcp: tcp_listener
+            .map(|(s, local, remote)| (Box::new(s) as Box<dyn TunStream>, local, remote))
+            .boxed(),
+        udp_rx: udp_rx.boxed(),
+        udp_tx: Box::pin(udp_tx),
+        runner: None,
+    })
+}
+
+fn smoltcp(tcp: bool) -> io::Result<Stack> {
+    let (stack, runner, udp_socket, tcp_listener) = netstack_smoltcp::StackBuilder::default()
+        .enable_tcp(tcp)
+        .enable_udp(true)
+        .build()?;
+    let udp_socket = udp_socket.ok_or_else(|| new_io_error("smoltcp udp is not enabled"))?;
+    let (sink, stream) = stack.split();
+    let (udp_rx, udp_tx) = udp_socket.split();
+
+    Ok(Stack {
+        sink: Box::pin(sink),
+        stream: stream.boxed(),
+        tcp: match tcp_listener {
+            Some(listener) => listener
+                .map(|(s, local, remote)| (Box::new(s) as Box<dyn TunStream>, local, remote))
+                .boxed(),
+            None => futures::stream::pending().boxed(),
+        },
+        udp_rx: udp_rx.boxed(),
+        udp_tx: Box::pin(udp_tx),
+        runner: runner.map(|x| Box::pin(x) as BoxFuture<_>),
+    })
+}
+
+/// TCP to lwIP and the rest to smoltcp, by the protocol of the packets.
+fn mixed() -> io::Result<Stack> {
+    let lwip = lwip()?;
+    let smoltcp = smoltcp(false)?;
+    let sink = futures::sink::unfold(
+        (lwip.sink, smoltcp.sink),
+        |(mut tcp, mut other), pkt: Vec<u8>| async move {
+            if is_tcp(&pkt) {
+                tcp.send(pkt).await?;
+            } else {
+                other.send(pkt).await?;
+            }
+            Ok::<_, io::Error>((tcp, other))
+        },
+    );
+
+    Ok(Stack {
+        sink: Box::pin(sink),
+        stream: futures::stream::select(lwip.stream, smoltcp.stream).boxed(),
+        tcp: lwip.tcp,
+        udp_rx: smoltcp.udp_rx,
+        udp_tx: smoltcp.udp_tx,
+        runner: smoltcp.runner,
+    })
+}
+
+/// Whether an IP packet carries TCP.
+fn is_tcp(pkt: &[u8]) -> bool {
+    const TCP: u8 = 6;
+    match pkt.first().map(|x| x >> 4) {
+        Some(4) => pkt.get(9) == Some(&TCP),
+        Some(6) => ipv6_protocol(pkt) == Some(TCP),
+        _ => false,
+    }
+}
+
+/// The upper layer protocol of an IPv6 packet, past its extension headers.
+fn ipv6_protocol(pkt: &[u8]) -> Option<u8> {
+    let mut next = *pkt.get(6)?;
+    let mut offset = 40;
+    loop {
+        let len = match next {
+            // hop-by-hop, routing, destination options, mobility, HIP and
+            // shim6, in units of 8 bytes past the first 8
+            0 | 43 | 60 | 135 | 139 | 140 => (*pkt.get(offset + 1)? as usize + 1) * 8,
+            // fragment
+            44 => 8,
+            // authentication, in units of 4 bytes less 2
+            51 => (*pkt.get(offset + 1)? as usize + 2) * 4,
+            _ => return Some(next),
+        };
+        next = *pkt.get(offset)?;
+        offset += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_tcp;
+
+    #[test]
+    fn test_is_tcp() {
+        let mut v4 = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6];
+        assert!(is_tcp(&v4));
+        v4[9] = 17;
+        assert!(!is_tcp(&v4));
+
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 20, 6, 64];
+        assert!(is_tcp(&v6));
+        v6[6] = 17;
+        assert!(!is_tcp(&v6));
+
+        // hop-by-hop and then fragment headers before the TCP one
+        let mut v6 = vec![0u8; 40];
+        v6[0] = 0x60;
+        v6[6] = 0;
+        v6.extend_from_slice(&[44, 0, 5, 2, 0, 0, 1, 0]);
+        v6.extend_from_slice(&[6, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(is_tcp(&v6));
+        v6[48] = 17;
+        assert!(!is_tcp(&v6));
+        // truncated
+        assert!(!is_tcp(&v6[..44]));
+
+        assert!(!is_tcp(&[]));
+    }
+}
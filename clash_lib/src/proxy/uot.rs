@@ -0,0 +1,351 @@
+//! UDP over TCP (UoT v2, as implemented by sing-box)
+//!
+//! The UDP packets are carried over a TCP stream to the magic address
+//! `sp.v2.udp-over-tcp.arpa` through the proxy, which the server recognises
+//! and relays the packets as UDP. This gets UDP through middleboxes that
+//! drop or throttle it.
+//!
+//! ```text
+//! request: | is_connect u8 | destination (SOCKS5 address) |
+//! packet:  | address | length u16 | payload |
+//! address: | family u8 (0: IPv4, 1: IPv6, 2: domain) | address | port u16 |
+//! ```
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, BytesMut};
+use erased_serde::Serialize;
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    session::{Session, SocksAddr},
+};
+
+use super::{
+    datagram::UdpPacket,
+    transport::tls::TlsEndpoint,
+    utils::RemoteConnector,
+    AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType,
+};
+
+pub const UOT_MAGIC_ADDRESS: &str = "sp.v2.udp-over-tcp.arpa";
+
+const FAMILY_V4: u8 = 0x00;
+const FAMILY_V6: u8 = 0x01;
+const FAMILY_DOMAIN: u8 = 0x02;
+
+/// Carry the UDP traffic of the inner outbound over TCP,
+/// TCP connections are passed through as is.
+pub struct Handler {
+    inner: AnyOutboundHandler,
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(inner: AnyOutboundHandler) -> AnyOutboundHandler {
+        Arc::new(Self { inner })
+    }
+
+    /// Wrap the outbound if `uot` is enabled for it.
+    pub fn wrap_if(inner: AnyOutboundHandler, uot: bool) -> AnyOutboundHandler {
+        if uot {
+            Self::new(inner)
+        } else {
+            inner
+        }
+    }
+
+    fn uot_session(sess: &Session) -> Session {
+        let mut sess = sess.clone();
+        sess.destination = SocksAddr::Domain(UOT_MAGIC_ADDRESS.to_owned(), 0);
+        sess
+    }
+
+    async fn handshake(
+        &self,
+        mut stream: BoxedChainedStream,
+        sess: &Session,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let mut req = BytesMut::new();
+        // the packets carry their own destination
+        req.put_u8(0);
+        sess.destination.write_buf(&mut req);
+        stream.write_all(&req).await?;
+
+        let d = ChainedDatagramWrapper::new(OutboundDatagramUot {
+            inner: Framed::new(stream, UotCodec),
+        });
+        d.append_to_chain(self.name()).await;
+        Ok(Box::new(d))
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    async fn support_udp(&self) -> bool {
+        true
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner.connect_stream(sess, resolver).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let stream = self
+            .inner
+            .connect_stream(&Self::uot_session(sess), resolver)
+            .await?;
+        self.handshake(stream, sess).await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        // UDP goes over the TCP connector
+        match self.inner.support_connector().await {
+            ConnectorType::Tcp | ConnectorType::All => ConnectorType::All,
+            _ => ConnectorType::None,
+        }
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner
+            .connect_stream_with_connector(sess, resolver, connector)
+            .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let stream = self
+            .inner
+            .connect_stream_with_connector(&Self::uot_session(sess), resolver, connector)
+            .await?;
+        self.handshake(stream, sess).await
+    }
+
+    fn remote_dns_resolve(&self) -> bool {
+        self.inner.remote_dns_resolve()
+    }
+
+    fn tls_endpoint(&self) -> Option<TlsEndpoint> {
+        self.inner.tls_endpoint()
+    }
+
+    async fn current_member(&self) -> Option<AnyOutboundHandler> {
+        self.inner.current_member().await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.inner.as_map().await;
+        m.insert("uot".to_string(), Box::new(true) as _);
+        m
+    }
+}
+
+pub struct OutboundDatagramUot {
+    inner: Framed<BoxedChainedStream, UotCodec>,
+}
+
+impl Stream for OutboundDatagramUot {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.inner.poll_next_unpin(cx)) {
+            Some(Ok(pkt)) => Poll::Ready(Some(pkt)),
+            Some(Err(e)) => {
+                debug!("failed to read uot packet: {}", e);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramUot {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+pub struct UotCodec;
+
+impl Encoder<UdpPacket> for UotCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: UdpPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let len = u16::try_from(item.data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "uot packet too large"))?;
+
+        match &item.dst_addr {
+            SocksAddr::Ip(SocketAddr::V4(addr)) => {
+                dst.put_u8(FAMILY_V4);
+                dst.put_slice(&addr.ip().octets());
+            }
+            SocksAddr::Ip(SocketAddr::V6(addr)) => {
+                dst.put_u8(FAMILY_V6);
+                dst.put_slice(&addr.ip().octets());
+            }
+            SocksAddr::Domain(domain, _) => {
+                let domain_len = u8::try_from(domain.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "domain too long"))?;
+                dst.put_u8(FAMILY_DOMAIN);
+                dst.put_u8(domain_len);
+                dst.put_slice(domain.as_bytes());
+            }
+        }
+        dst.put_u16(item.dst_addr.port());
+        dst.put_u16(len);
+        dst.put_slice(&item.data);
+
+        Ok(())
+    }
+}
+
+impl Decoder for UotCodec {
+    type Item = UdpPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let family = match src.first() {
+            Some(family) => *family,
+            None => return Ok(None),
+        };
+        let addr_len = match family {
+            FAMILY_V4 => 4,
+            FAMILY_V6 => 16,
+            FAMILY_DOMAIN => match src.get(1) {
+                Some(len) => 1 + *len as usize,
+                None => return Ok(None),
+            },
+            x => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid uot address family: {}", x),
+                ))
+            }
+        };
+
+        // family + address + port + length
+        let header_len = 1 + addr_len + 2 + 2;
+        if src.len() < header_len {
+            return Ok(None);
+        }
+        let data_len = u16::from_be_bytes([src[header_len - 2], src[header_len - 1]]) as usize;
+        if src.len() < header_len + data_len {
+            src.reserve(header_len + data_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(1);
+        let addr = match family {
+            FAMILY_V4 => {
+                let ip = Ipv4Addr::from(src.get_u32());
+                SocksAddr::Ip((ip, src.get_u16()).into())
+            }
+            FAMILY_V6 => {
+                let ip = Ipv6Addr::from(src.get_u128());
+                SocksAddr::Ip((ip, src.get_u16()).into())
+            }
+            _ => {
+                let len = src.get_u8() as usize;
+                let domain = String::from_utf8(src.split_to(len).to_vec()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid uot domain")
+                })?;
+                SocksAddr::Domain(domain, src.get_u16())
+            }
+        };
+        src.advance(2);
+        let data = src.split_to(data_len).to_vec();
+
+        Ok(Some(UdpPacket {
+            data,
+            src_addr: addr,
+            dst_addr: SocksAddr::any_ipv4(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::UotCodec;
+    use crate::{proxy::datagram::UdpPacket, session::SocksAddr};
+
+    #[test]
+    fn test_codec_round_trip() {
+        let mut buf = BytesMut::new();
+        for dst in [
+            SocksAddr::Ip("1.1.1.1:53".parse().unwrap()),
+            SocksAddr::Ip("[2606:4700::1111]:53".parse().unwrap()),
+            SocksAddr::Domain("example.com".to_owned(), 443),
+        ] {
+            let pkt = UdpPacket::new(b"hello".to_vec(), SocksAddr::any_ipv4(), dst.clone());
+            UotCodec.encode(pkt, &mut buf).unwrap();
+
+            // a partial frame is not decoded
+            let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+            assert!(UotCodec.decode(&mut partial).unwrap().is_none());
+
+            let decoded = UotCodec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(decoded.src_addr, dst);
+            assert_eq!(decoded.data, b"hello");
+            assert!(buf.is_empty());
+        }
+    }
+}
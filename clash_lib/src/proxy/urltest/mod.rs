@@ -10,9 +10,10 @@ use crate::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
         remote_content_manager::{
-            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager,
+            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager, SwitchReason,
         },
     },
+    common::errors::member_dial_error,
     session::Session,
 };
 
@@ -73,17 +74,9 @@ impl Handler {
         let mut fastest = proxies
             .first()
             .unwrap_or_else(|| panic!("no proxy found for {}", self.name()));
-
         let mut fastest_delay = proxy_manager.last_delay(fastest.name()).await;
-        let mut fast_not_exist = true;
 
         for proxy in proxies.iter().skip(1) {
-            if inner.fastest_proxy.is_some()
-                && proxy.name() == inner.fastest_proxy.as_ref().unwrap().name()
-            {
-                fast_not_exist = false;
-            }
-
             if !proxy_manager.alive(proxy.name()).await {
                 continue;
             }
@@ -93,17 +86,6 @@ impl Handler {
                 fastest = proxy;
                 fastest_delay = delay;
             }
-
-            if inner.fastest_proxy.is_some()
-                || fast_not_exist
-                || proxy_manager.alive(fastest.name()).await
-                || proxy_manager
-                    .last_delay(inner.fastest_proxy.as_ref().unwrap().name())
-                    .await
-                    > fastest_delay + self.tolerance
-            {
-                inner.fastest_proxy = Some(fastest.clone());
-            }
         }
 
         trace!(
@@ -113,11 +95,42 @@ impl Handler {
             fastest_delay
         );
 
-        return inner
+        // stay on the current member unless it's down, or slower than the
+        // fastest by more than the tolerance
+        let current = inner
             .fastest_proxy
             .as_ref()
-            .unwrap_or(proxies.first().unwrap())
-            .clone();
+            .and_then(|x| proxies.iter().find(|p| p.name() == x.name()));
+        match current {
+            Some(current) if current.name() == fastest.name() => {}
+            Some(current) => {
+                let reason = if !proxy_manager.alive(current.name()).await {
+                    Some(proxy_manager.failure_reason(current.name()))
+                } else if proxy_manager.last_delay(current.name()).await
+                    > fastest_delay.saturating_add(self.tolerance)
+                {
+                    Some(SwitchReason::ToleranceExceeded)
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    proxy_manager.report_switch(
+                        self.name(),
+                        current.name(),
+                        fastest.name(),
+                        reason,
+                    );
+                    inner.fastest_proxy = Some(fastest.clone());
+                }
+            }
+            // the first pick, or the member is gone from the providers
+            None => inner.fastest_proxy = Some(fastest.clone()),
+        }
+
+        inner
+            .fastest_proxy
+            .clone()
+            .unwrap_or_else(|| fastest.clone())
     }
 }
 
@@ -144,11 +157,11 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let s = self
-            .fastest(false)
-            .await
+        let proxy = self.fastest(false).await;
+        let s = proxy
             .connect_stream(sess, resolver)
-            .await?;
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e))?;
         s.append_to_chain(self.name()).await;
         Ok(s)
     }
@@ -159,11 +172,11 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let d = self
-            .fastest(false)
-            .await
+        let proxy = self.fastest(false).await;
+        let d = proxy
             .connect_datagram(sess, resolver)
-            .await?;
+            .await
+            .map_err(|e| member_dial_error(proxy.name(), e))?;
         d.append_to_chain(self.name()).await;
         Ok(d)
     }
@@ -200,6 +213,10 @@ impl OutboundHandler for Handler {
             .await
     }
 
+    async fn current_member(&self) -> Option<AnyOutboundHandler> {
+        Some(self.fastest(false).await)
+    }
+
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let all = get_proxies_from_providers(&self.providers, false).await;
 
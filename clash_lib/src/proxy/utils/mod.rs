@@ -1,5 +1,7 @@
 use std::{
+    borrow::Cow,
     fmt::Display,
+    io,
     net::{IpAddr, SocketAddr},
 };
 
@@ -8,13 +10,23 @@ pub mod test_utils;
 
 pub mod provider_helper;
 mod proxy_connector;
+pub mod proxy_protocol;
 mod socket_helpers;
+#[cfg(target_os = "linux")]
+pub mod tproxy;
 
 pub use proxy_connector::*;
 
 use serde::{Deserialize, Serialize};
 pub use socket_helpers::*;
 
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    common::errors::new_io_error,
+    proxy::uot::UOT_MAGIC_ADDRESS,
+    session::{Session, SocksAddr},
+};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Interface {
     IpAddr(IpAddr),
@@ -52,3 +64,29 @@ impl Interface {
         }
     }
 }
+
+/// The session to hand to the proxy server, the domain destination is
+/// resolved locally unless the proxy server resolves it.
+pub async fn resolve_destination<'a>(
+    sess: &'a Session,
+    remote_dns_resolve: bool,
+    resolver: &ThreadSafeDNSResolver,
+) -> io::Result<Cow<'a, Session>> {
+    let (host, port) = match &sess.destination {
+        // the UoT magic address is for the proxy server itself
+        SocksAddr::Domain(host, port) if !remote_dns_resolve && host != UOT_MAGIC_ADDRESS => {
+            (host, *port)
+        }
+        _ => return Ok(Cow::Borrowed(sess)),
+    };
+
+    let ip = resolver
+        .resolve(host, false)
+        .await
+        .map_err(|x| new_io_error(&format!("failed to resolve {}: {}", host, x)))?
+        .ok_or_else(|| new_io_error(&format!("no dns record for {}", host)))?;
+
+    let mut sess = sess.clone();
+    sess.destination = SocksAddr::Ip(SocketAddr::new(ip, port));
+    Ok(Cow::Owned(sess))
+}
@@ -10,11 +10,11 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    proxy::{datagram::OutboundDatagramImpl, AnyOutboundDatagram, AnyOutboundHandler, AnyStream},
+    proxy::{AnyOutboundDatagram, AnyOutboundHandler, AnyStream},
     session::{Network, Session, SocksAddr, Type},
 };
 
-use super::{new_tcp_stream, new_udp_socket, Interface};
+use super::Interface;
 
 /// allows a proxy to get a connection to a remote server
 #[async_trait]
@@ -38,65 +38,27 @@ pub trait RemoteConnector: Send + Sync {
     ) -> std::io::Result<AnyOutboundDatagram>;
 }
 
-pub struct DirectConnector;
-
-impl DirectConnector {
-    pub fn new() -> Self {
-        Self
-    }
-}
-
-#[async_trait]
-impl RemoteConnector for DirectConnector {
-    async fn connect_stream(
-        &self,
-        resolver: ThreadSafeDNSResolver,
-        address: &str,
-        port: u16,
-        iface: Option<&Interface>,
-        #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
-    ) -> std::io::Result<AnyStream> {
-        new_tcp_stream(
-            resolver,
-            address,
-            port,
-            iface,
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            packet_mark,
-        )
-        .await
-    }
-
-    async fn connect_datagram(
-        &self,
-        resolver: ThreadSafeDNSResolver,
-        src: Option<&SocketAddr>,
-        _destination: &SocksAddr,
-        iface: Option<&Interface>,
-        #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
-    ) -> std::io::Result<AnyOutboundDatagram> {
-        let dgram = new_udp_socket(
-            src,
-            iface,
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            packet_mark,
-        )
-        .await
-        .map(|x| OutboundDatagramImpl::new(x, resolver))?;
-
-        let dgram = ChainedDatagramWrapper::new(dgram);
-        Ok(Box::new(dgram))
-    }
-}
-
 pub struct ProxyConnector {
     proxy: AnyOutboundHandler,
-    connector: Box<dyn RemoteConnector>,
+    /// the proxy dials its server itself if none
+    connector: Option<Box<dyn RemoteConnector>>,
 }
 
 impl ProxyConnector {
     pub fn new(proxy: AnyOutboundHandler, connector: Box<dyn RemoteConnector>) -> Self {
-        Self { proxy, connector }
+        Self {
+            proxy,
+            connector: Some(connector),
+        }
+    }
+
+    /// The first hop of a chain, which dials its server as it would alone,
+    /// so it can be any proxy.
+    pub fn entry(proxy: AnyOutboundHandler) -> Self {
+        Self {
+            proxy,
+            connector: None,
+        }
     }
 }
 
@@ -127,10 +89,14 @@ impl RemoteConnector for ProxyConnector {
             port
         );
 
-        let s = self
-            .proxy
-            .connect_stream_with_connector(&sess, resolver, self.connector.as_ref())
-            .await?;
+        let s = match &self.connector {
+            Some(connector) => {
+                self.proxy
+                    .connect_stream_with_connector(&sess, resolver, connector.as_ref())
+                    .await?
+            }
+            None => self.proxy.connect_stream(&sess, resolver).await?,
+        };
 
         let stream = ChainedStreamWrapper::new(s);
         stream.append_to_chain(self.proxy.name()).await;
@@ -154,10 +120,14 @@ impl RemoteConnector for ProxyConnector {
             packet_mark,
             ..Default::default()
         };
-        let s = self
-            .proxy
-            .connect_datagram_with_connector(&sess, resolver, self.connector.as_ref())
-            .await?;
+        let s = match &self.connector {
+            Some(connector) => {
+                self.proxy
+                    .connect_datagram_with_connector(&sess, resolver, connector.as_ref())
+                    .await?
+            }
+            None => self.proxy.connect_datagram(&sess, resolver).await?,
+        };
 
         let stream = ChainedDatagramWrapper::new(s);
         stream.append_to_chain(self.proxy.name()).await;
@@ -0,0 +1,241 @@
+//! Haproxy PROXY protocol v1/v2 headers, parsed for inbounds sitting behind
+//! a load balancer or a port forwarder, and written by the outbounds
+//! forwarding to origin servers that want to see the real client
+//! https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
+
+use crate::common::errors::new_io_error;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// The source of an accepted connection, taken from its PROXY protocol
+/// header if enabled on the listener, the header is required then.
+pub async fn source_addr(s: &mut TcpStream, proxy_protocol: bool) -> io::Result<SocketAddr> {
+    let peer = s.peer_addr()?;
+    if !proxy_protocol {
+        return Ok(peer);
+    }
+    let src = tokio::time::timeout(HEADER_TIMEOUT, read_header(s))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out reading PROXY protocol header",
+            )
+        })??;
+    Ok(src.unwrap_or(peer))
+}
+
+/// Consume the header, returns the original source address, `None` for the
+/// connections made by the proxy itself, e.g. health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<Option<SocketAddr>> {
+    // both versions are at least this long
+    let mut start = [0u8; 12];
+    s.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(s).await
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(new_io_error("PROXY protocol header too long"));
+            }
+            line.push(s.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(new_io_error("missing PROXY protocol header"))
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>`
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let invalid = || new_io_error("invalid PROXY protocol v1 header");
+
+    let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let src = parts
+                .next()
+                .and_then(|x| x.parse::<IpAddr>().ok())
+                .ok_or_else(invalid)?;
+            let _dst = parts.next().ok_or_else(invalid)?;
+            let port = parts
+                .next()
+                .and_then(|x| x.parse::<u16>().ok())
+                .ok_or_else(invalid)?;
+            Ok(Some(SocketAddr::new(src, port)))
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(invalid()),
+    }
+}
+
+/*
++------------+---------+-----------+--------+-----------+
+| signature  | ver cmd | fam proto |  len   | addresses |
++------------+---------+-----------+--------+-----------+
+|     12     |    1    |     1     |   2    |  Variable |
++------------+---------+-----------+--------+-----------+
+ */
+async fn read_v2<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut hdr = [0u8; 4];
+    s.read_exact(&mut hdr).await?;
+    if hdr[0] >> 4 != 2 {
+        return Err(new_io_error("unsupported PROXY protocol version"));
+    }
+
+    let len = u16::from_be_bytes([hdr[2], hdr[3]]) as usize;
+    let mut body = vec![0u8; len];
+    s.read_exact(&mut body).await?;
+
+    // LOCAL
+    if hdr[0] & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match hdr[1] >> 4 {
+        // AF_INET: src, dst, src port, dst port
+        0x1 if len >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        0x2 if len >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into().expect("must be 16 bytes");
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX have no address for us
+        0x0 | 0x3 => Ok(None),
+        _ => Err(new_io_error("invalid PROXY protocol v2 header")),
+    }
+}
+
+/// The header of a connection from `src` to `dst`, a mix of IPv4 and IPv6
+/// is sent as IPv6 with the IPv4 one mapped.
+pub fn encode_header(version: Version, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (IpAddr::V6(to_v6(s)), IpAddr::V6(to_v6(d))),
+    };
+
+    match version {
+        Version::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                src_ip,
+                dst_ip,
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            // version 2, PROXY
+            buf.push(0x21);
+            match (src_ip, dst_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    // AF_INET, STREAM
+                    buf.push(0x11);
+                    buf.extend_from_slice(&12u16.to_be_bytes());
+                    buf.extend_from_slice(&s.octets());
+                    buf.extend_from_slice(&d.octets());
+                }
+                (s, d) => {
+                    // AF_INET6, STREAM
+                    buf.push(0x21);
+                    buf.extend_from_slice(&36u16.to_be_bytes());
+                    buf.extend_from_slice(&to_v6(s).octets());
+                    buf.extend_from_slice(&to_v6(d).octets());
+                }
+            }
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+            buf
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_header, read_header, Version, V2_SIGNATURE};
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut s: &[u8] = b"PROXY TCP4 192.168.1.2 10.0.0.1 56324 443\r\nGET /";
+        let src = read_header(&mut s).await.unwrap();
+        assert_eq!(src, Some("192.168.1.2:56324".parse().unwrap()));
+        assert_eq!(s, b"GET /");
+
+        let mut s: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut s).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[192, 168, 1, 2, 10, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend_from_slice(b"\x05");
+        let mut s = v2.as_slice();
+        let src = read_header(&mut s).await.unwrap();
+        assert_eq!(src, Some("192.168.1.2:56324".parse().unwrap()));
+        assert_eq!(s, b"\x05");
+
+        let mut s: &[u8] = b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+        assert!(read_header(&mut s).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encode_header() {
+        let src = "192.168.1.2:56324".parse().unwrap();
+        let dst = "10.0.0.1:443".parse().unwrap();
+        assert_eq!(
+            encode_header(Version::V1, src, dst),
+            b"PROXY TCP4 192.168.1.2 10.0.0.1 56324 443\r\n"
+        );
+
+        for version in [Version::V1, Version::V2] {
+            let buf = encode_header(version, src, dst);
+            let mut s = buf.as_slice();
+            assert_eq!(read_header(&mut s).await.unwrap(), Some(src));
+
+            let v6 = "[2001:db8::1]:443".parse().unwrap();
+            let buf = encode_header(version, src, v6);
+            let mut s = buf.as_slice();
+            assert_eq!(
+                read_header(&mut s).await.unwrap(),
+                Some("[::ffff:192.168.1.2]:56324".parse().unwrap())
+            );
+        }
+    }
+}
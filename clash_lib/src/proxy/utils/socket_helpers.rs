@@ -1,12 +1,15 @@
 use std::{
     io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
     time::Duration,
 };
 
+use network_interface::NetworkInterfaceConfig;
 use socket2::TcpKeepalive;
 use tokio::{
-    net::{TcpSocket, TcpStream, UdpSocket},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
     time::timeout,
 };
 
@@ -15,7 +18,97 @@ use tracing::debug;
 use tracing::warn;
 
 use super::Interface;
-use crate::{app::dns::ThreadSafeDNSResolver, proxy::AnyStream};
+use crate::{app::dns::ThreadSafeDNSResolver, common::utils::rand_range, proxy::AnyStream};
+
+#[cfg(unix)]
+pub type RawSocket = std::os::fd::RawFd;
+#[cfg(windows)]
+pub type RawSocket = std::os::windows::io::RawSocket;
+
+/// A callback of the host app run on every socket dialing out, before it
+/// sends anything, e.g. to exempt it from the app's own VPN with Android's
+/// `VpnService.protect()`. An error fails the dial.
+pub type SocketProtector = Arc<dyn Fn(RawSocket) -> io::Result<()> + Send + Sync>;
+
+static SOCKET_PROTECTOR: RwLock<Option<SocketProtector>> = RwLock::new(None);
+
+/// Install the socket protector, or remove it.
+pub fn set_socket_protector(protector: Option<SocketProtector>) {
+    *SOCKET_PROTECTOR.write().unwrap() = protector;
+}
+
+/// Hand the socket to the protector, if any.
+pub fn protect_socket(socket: &socket2::Socket) -> io::Result<()> {
+    let Some(protector) = SOCKET_PROTECTOR.read().unwrap().clone() else {
+        return Ok(());
+    };
+    #[cfg(unix)]
+    let raw = std::os::fd::AsRawFd::as_raw_fd(socket);
+    #[cfg(windows)]
+    let raw = std::os::windows::io::AsRawSocket::as_raw_socket(socket);
+    protector(raw)
+}
+
+/// The local ports the outbound sockets are bound to, from
+/// `outbound-port-range`. Any port the OS picks if unset.
+static SOURCE_PORTS: RwLock<Option<RangeInclusive<u16>>> = RwLock::new(None);
+
+/// How many ports of the range are tried before giving up on a dial.
+const SOURCE_PORT_ATTEMPTS: u32 = 64;
+
+/// Restrict the local ports of the outbound sockets, or lift it.
+pub fn set_source_ports(ports: Option<RangeInclusive<u16>>) {
+    *SOURCE_PORTS.write().unwrap() = ports;
+}
+
+/// The ports of the source port range to try in turn, from a random one as
+/// some may be taken. None if unset.
+fn source_ports() -> Option<impl Iterator<Item = u16>> {
+    let ports = SOURCE_PORTS.read().unwrap().clone()?;
+    let span = (*ports.end() - *ports.start()) as u32 + 1;
+    let first = rand_range(0..span);
+    Some(
+        (0..span.min(SOURCE_PORT_ATTEMPTS))
+            .map(move |i| *ports.start() + ((first + i) % span) as u16),
+    )
+}
+
+/// The address a socket binds to with a source port: the one of `iface` if
+/// it's one, `ip` otherwise.
+fn source_addr(ip: IpAddr, port: u16, iface: Option<&Interface>) -> SocketAddr {
+    match iface {
+        Some(Interface::IpAddr(ip)) => SocketAddr::new(*ip, port),
+        _ => SocketAddr::new(ip, port),
+    }
+}
+
+/// Bind the socket to a port of the source port range, if any, on `ip` or
+/// the address of `iface`. Returns whether it did bind.
+fn bind_source_port(
+    socket: &socket2::Socket,
+    ip: IpAddr,
+    iface: Option<&Interface>,
+) -> io::Result<bool> {
+    let Some(ports) = source_ports() else {
+        return Ok(false);
+    };
+    let mut last_err = None;
+    for port in ports {
+        match socket.bind(&source_addr(ip, port, iface).into()) {
+            Ok(()) => return Ok(true),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+fn unspecified(v6: bool) -> IpAddr {
+    if v6 {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    }
+}
 
 pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     #[cfg(not(target_os = "windows"))]
@@ -41,11 +134,94 @@ pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     }
 }
 
-fn must_bind_socket_on_interface(socket: &socket2::Socket, iface: &Interface) -> io::Result<()> {
+/// Whether the listeners are bound with SO_REUSEPORT, so a new listener
+/// can take over a port before the old one closes.
+pub const LISTENER_REUSE_PORT: bool = cfg!(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos"))
+));
+
+/// Bind a TCP listener, with SO_REUSEPORT where available.
+pub fn new_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // as tokio does, the ports in TIME_WAIT are reused on unix
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Simple glob matching, supports `*` and `?`.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut pi, mut ni) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ni));
+            pi += 1;
+        } else if let Some((bp, bn)) = backtrack {
+            pi = bp + 1;
+            ni = bn + 1;
+            backtrack = Some((bp, bn + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|x| *x == '*')
+}
+
+/// Resolve an interface name pattern like `en*` to an interface that is
+/// currently present and has an address.
+/// Names without wildcards are returned as is.
+fn resolve_interface_name(name: &str) -> io::Result<String> {
+    if !name.contains(['*', '?']) {
+        return Ok(name.to_owned());
+    }
+
+    let mut candidates = network_interface::NetworkInterface::show()
+        .map_err(|x| io::Error::new(io::ErrorKind::Other, format!("list ifaces: {:?}", x)))?
+        .into_iter()
+        .filter(|x| {
+            wildcard_match(name, &x.name)
+                && x.addr
+                    .iter()
+                    .any(|a| !a.ip().is_loopback() && !a.ip().is_unspecified())
+        })
+        .map(|x| x.name)
+        .collect::<Vec<_>>();
+    // keep the pick stable when multiple interfaces match
+    candidates.sort();
+    candidates.dedup();
+
+    candidates.into_iter().next().ok_or(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no interface matching {} is available", name),
+    ))
+}
+
+pub(crate) fn must_bind_socket_on_interface(
+    socket: &socket2::Socket,
+    iface: &Interface,
+) -> io::Result<()> {
     match iface {
         // TODO: should this be ever used vs. calling .bind(2) from the caller side?
         Interface::IpAddr(ip) => socket.bind(&SocketAddr::new(*ip, 0).into()),
         Interface::Name(name) => {
+            let name = resolve_interface_name(name)?;
             #[cfg(target_vendor = "apple")]
             {
                 socket.bind_device_by_index_v4(std::num::NonZeroU32::new(unsafe {
@@ -68,6 +244,33 @@ fn must_bind_socket_on_interface(socket: &socket2::Socket, iface: &Interface) ->
     }
 }
 
+/// Whether the server of an outbound is a local socket rather than a host:
+/// a Unix socket path, or a named pipe on Windows. Only the outbounds taking
+/// one dial it, with [`connect_local_socket`], the others resolve it as a
+/// host.
+pub fn is_local_socket(address: &str) -> bool {
+    if cfg!(windows) {
+        address.starts_with(r"\\.\pipe\")
+    } else {
+        address.starts_with('/')
+    }
+}
+
+/// Connect to a local daemon, e.g. Tor or a sidecar, at its socket. The
+/// interface and the packet mark don't apply.
+pub async fn connect_local_socket(path: &str) -> io::Result<AnyStream> {
+    debug!("dialing local socket {}", path);
+    #[cfg(unix)]
+    let stream = timeout(
+        Duration::from_secs(10),
+        tokio::net::UnixStream::connect(path),
+    )
+    .await??;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+    Ok(Box::new(stream))
+}
+
 pub async fn new_tcp_stream<'a>(
     resolver: ThreadSafeDNSResolver,
     address: &'a str,
@@ -83,29 +286,102 @@ pub async fn new_tcp_stream<'a>(
             io::ErrorKind::Other,
             format!("can't resolve dns: {}", address),
         ))?;
+    let dial_addr = match resolver.nat64() {
+        Some(nat64) => nat64.map(dial_addr),
+        None => dial_addr,
+    };
 
     debug!(
         "dialing {}[{}]:{} via {:?}",
         address, dial_addr, port, iface
     );
 
-    let socket = match (dial_addr, resolver.ipv6()) {
-        (IpAddr::V4(_), _) => {
-            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?
-        }
-        (IpAddr::V6(_), true) => {
-            socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?
-        }
-        (IpAddr::V6(_), false) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("ipv6 is disabled, can't dial {}", address),
-            ))
-        }
+    if dial_addr.is_ipv6() && !resolver.ipv6() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("ipv6 is disabled, can't dial {}", address),
+        ));
+    }
+
+    let stream = new_tcp_stream_to(
+        (dial_addr, port).into(),
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        packet_mark,
+    )
+    .await?;
+
+    debug!("connected to {}[{}]:{}", address, dial_addr, port);
+    Ok(Box::new(stream))
+}
+
+/// Connect to the address, through the socket protector and from a port of
+/// the source port range, if any.
+pub async fn new_tcp_stream_to(
+    addr: SocketAddr,
+    iface: Option<&Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
+) -> io::Result<TcpStream> {
+    let Some(ports) = source_ports() else {
+        return connect_tcp(
+            addr,
+            None,
+            iface,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            packet_mark,
+        )
+        .await;
     };
+    // the ports are shared by the connections to different servers, one
+    // taken by a connection to the same server fails only the connect
+    let mut last_err = None;
+    for port in ports {
+        match connect_tcp(
+            addr,
+            Some(port),
+            iface,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            packet_mark,
+        )
+        .await
+        {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                ) =>
+            {
+                debug!("source port {} to {} taken: {}", port, addr, e);
+                last_err = Some(e);
+            }
+            rv => return rv,
+        }
+    }
+    Err(last_err.unwrap())
+}
 
-    if let Some(iface) = iface {
-        must_bind_socket_on_interface(&socket, iface)?;
+async fn connect_tcp(
+    addr: SocketAddr,
+    source_port: Option<u16>,
+    iface: Option<&Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
+) -> io::Result<TcpStream> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+
+    protect_socket(&socket)?;
+    if let Some(port) = source_port {
+        socket.set_reuse_address(true)?;
+        socket.bind(&source_addr(unspecified(addr.is_ipv6()), port, iface).into())?;
+    }
+    match iface {
+        // already bound to the address, with the source port
+        Some(Interface::IpAddr(_)) if source_port.is_some() => {}
+        Some(iface) => must_bind_socket_on_interface(&socket, iface)?,
+        None => {}
     }
 
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -117,14 +393,11 @@ pub async fn new_tcp_stream<'a>(
     socket.set_nodelay(true)?;
     socket.set_nonblocking(true)?;
 
-    let stream = timeout(
+    timeout(
         Duration::from_secs(10),
-        TcpSocket::from_std_stream(socket.into()).connect((dial_addr, port).into()),
+        TcpSocket::from_std_stream(socket.into()).connect(addr),
     )
-    .await??;
-
-    debug!("connected to {}[{}]:{}", address, dial_addr, port);
-    Ok(Box::new(stream))
+    .await?
 }
 
 pub async fn new_udp_socket(
@@ -143,12 +416,21 @@ pub async fn new_udp_socket(
         None => socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?,
     };
 
-    if let Some(src) = src {
-        socket.bind(&(*src).into())?;
-    }
+    protect_socket(&socket)?;
+    // a source without a port takes one of the range
+    let bound = match src {
+        Some(src) if src.port() == 0 && bind_source_port(&socket, src.ip(), iface)? => true,
+        Some(src) => {
+            socket.bind(&(*src).into())?;
+            false
+        }
+        None => bind_source_port(&socket, unspecified(false), iface)?,
+    };
 
-    if let Some(iface) = iface {
-        must_bind_socket_on_interface(&socket, iface)?;
+    match iface {
+        Some(Interface::IpAddr(_)) if bound => {}
+        Some(iface) => must_bind_socket_on_interface(&socket, iface)?,
+        None => {}
     }
 
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -168,6 +450,148 @@ mod tests {
 
     use tokio::{net::TcpSocket, time::timeout};
 
+    use super::wildcard_match;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("en*", "en0"));
+        assert!(wildcard_match("en*", "en"));
+        assert!(wildcard_match("wlan?", "wlan1"));
+        assert!(wildcard_match("*tun*", "utun3"));
+        assert!(wildcard_match("eth0", "eth0"));
+        assert!(!wildcard_match("en*", "eth0"));
+        assert!(!wildcard_match("wlan?", "wlan10"));
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    #[serial_test::serial]
+    async fn test_socket_protector() {
+        use std::{
+            os::fd::AsRawFd,
+            sync::{Arc, Mutex},
+        };
+
+        use super::{new_tcp_stream_to, new_udp_socket, set_socket_protector};
+
+        let protected = Arc::new(Mutex::new(vec![]));
+        let p = protected.clone();
+        set_socket_protector(Some(Arc::new(move |fd| {
+            p.lock().unwrap().push(fd);
+            Ok(())
+        })));
+
+        let socket = new_udp_socket(
+            None,
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = new_tcp_stream_to(
+            listener.local_addr().unwrap(),
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .unwrap();
+        set_socket_protector(None);
+        let protected = protected.lock().unwrap();
+        assert!(protected.contains(&socket.as_raw_fd()));
+        assert!(protected.contains(&stream.as_raw_fd()));
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_source_ports() {
+        use super::{new_tcp_stream_to, new_udp_socket, set_source_ports};
+
+        let port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        set_source_ports(Some(port..=port));
+        let socket = new_udp_socket(
+            None,
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .map(|x| x.local_addr().unwrap().port());
+        // an explicit source without a port too
+        let v6 = new_udp_socket(
+            Some(&"[::]:0".parse().unwrap()),
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .map(|x| x.local_addr().unwrap().port());
+        set_source_ports(None);
+        assert_eq!(socket.unwrap(), port);
+        assert_eq!(v6.unwrap(), port);
+
+        // the second connection to the same server takes the other port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        set_source_ports(Some(port..=port + 1));
+        let mut ports = vec![];
+        for _ in 0..2 {
+            let s = new_tcp_stream_to(
+                listener.local_addr().unwrap(),
+                None,
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await;
+            ports.push(s.unwrap().local_addr().unwrap().port());
+        }
+        set_source_ports(None);
+        ports.sort();
+        assert_eq!(ports, vec![port, port + 1]);
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_local_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::connect_local_socket;
+
+        let path = std::env::temp_dir().join(format!("clash-rs-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            s.write_all(b"hello").await.unwrap();
+        });
+
+        let mut s = connect_local_socket(path.to_str().unwrap()).await.unwrap();
+        let mut buf = [0u8; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_listener_rebind() {
+        use super::{new_tcp_listener, LISTENER_REUSE_PORT};
+
+        let old = new_tcp_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = old.local_addr().unwrap();
+        let new = new_tcp_listener(addr);
+        assert_eq!(new.is_ok(), LISTENER_REUSE_PORT);
+    }
+
     #[tokio::test]
     #[ignore = "not a real test"]
     async fn test_connect_tcp() {
@@ -46,10 +46,11 @@ pub async fn load_config() -> anyhow::Result<(
     let cache_store = profile::ThreadSafeCacheFile::new(
         root.join("cache.db").as_path().to_str().unwrap(),
         config.profile.store_selected,
+        config.dns.store_fake_ip,
     );
 
     let dns_resolver: Arc<dyn ClashResolver> =
-        dns::Resolver::new_resolver(&config.dns, cache_store.clone(), mmdb.clone()).await;
+        dns::Resolver::new_resolver(&config.dns, cache_store.clone(), mmdb.clone()).await?;
 
     Ok((config, dns_resolver))
 }
@@ -0,0 +1,238 @@
+//! The sockets of the TPROXY inbound
+//!
+//! The listeners take the connections and datagrams to any address with
+//! IP_TRANSPARENT, the original destination being the local address of a
+//! connection, and that of the IP_ORIGDSTADDR message of a datagram.
+//!
+//! A TPROXY'd datagram arrives with the address the client sent it to as
+//! its destination, and the client only accepts answers coming from that
+//! very address, which game launchers and STUN check. So the replies are
+//! sent from sockets bound to the original destinations with
+//! IP_TRANSPARENT, which needs CAP_NET_ADMIN.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+/// a reply socket is closed once no reply went out of it for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_SOCKETS: usize = 1024;
+
+/// A socket sending from `addr` no matter whether it's local.
+pub fn new_reply_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    set_transparent(&socket, addr.is_ipv6())?;
+    // the replies to other clients go out of the same address
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// A UDP socket taking the datagrams to any address at `addr`, an IPv4
+/// one, telling their original destinations to [`recv_from_orig_dst`].
+pub fn new_udp_listener(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    set_transparent(&socket, false)?;
+    set_option(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+pub fn set_transparent(socket: &Socket, v6: bool) -> io::Result<()> {
+    if v6 {
+        set_option(socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        socket.set_ip_transparent(true)
+    }
+}
+
+/// Turn on an option socket2 has no setter for.
+fn set_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if rv == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Receive a datagram of a socket of [`new_udp_listener`], with its source
+/// and its original destination. Would block if there is none.
+pub fn recv_from_orig_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    // u64s for the alignment of the headers
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut src as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of_val(&src) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut dst = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        if hdr.cmsg_level == libc::SOL_IP && hdr.cmsg_type == libc::IP_ORIGDSTADDR {
+            let addr = unsafe {
+                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in)
+            };
+            dst = Some(socket_addr_v4(&addr));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    let dst = dst.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "no original destination of datagram")
+    })?;
+    Ok((n as usize, socket_addr_v4(&src), dst))
+}
+
+fn socket_addr_v4(addr: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    )
+    .into()
+}
+
+/// The reply sockets, keyed by the address they send from.
+pub struct ReplySockets {
+    sockets: Mutex<lru_time_cache::LruCache<SocketAddr, Arc<UdpSocket>>>,
+}
+
+impl Default for ReplySockets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplySockets {
+    pub fn new() -> Self {
+        Self {
+            sockets: Mutex::new(lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                IDLE_TIMEOUT,
+                MAX_SOCKETS,
+            )),
+        }
+    }
+
+    /// Send `buf` to the client `to` as if it came from `from`, the original
+    /// destination of the client's datagram.
+    pub async fn send_to(&self, buf: &[u8], from: SocketAddr, to: SocketAddr) -> io::Result<usize> {
+        let from = same_family(from, to);
+        let socket = {
+            let mut sockets = self.sockets.lock().unwrap();
+            match sockets.get(&from) {
+                Some(socket) => socket.clone(),
+                None => {
+                    debug!("new tproxy reply socket from {}", from);
+                    let socket = Arc::new(new_reply_socket(from)?);
+                    sockets.insert(from, socket.clone());
+                    socket
+                }
+            }
+        };
+        socket.send_to(buf, to).await
+    }
+}
+
+/// `from` as an address a socket talking to `to` can bind to, a remote
+/// reached over IPv4 is mapped into IPv6 for IPv6 clients and vice versa.
+fn same_family(from: SocketAddr, to: SocketAddr) -> SocketAddr {
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(v4), IpAddr::V6(_)) => SocketAddr::new(v4.to_ipv6_mapped().into(), from.port()),
+        (IpAddr::V6(v6), IpAddr::V4(_)) => match v6.to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), from.port()),
+            None => from,
+        },
+        _ => from,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recv_from_orig_dst, same_family, set_option};
+
+    #[test]
+    fn test_same_family() {
+        let v4 = "1.2.3.4:53".parse().unwrap();
+        let v6_client = "[fd00::2]:5000".parse().unwrap();
+        let v4_client = "192.168.1.2:5000".parse().unwrap();
+
+        assert_eq!(same_family(v4, v4_client), v4);
+        assert_eq!(
+            same_family(v4, v6_client),
+            "[::ffff:1.2.3.4]:53".parse().unwrap()
+        );
+        assert_eq!(
+            same_family("[::ffff:1.2.3.4]:53".parse().unwrap(), v4_client),
+            v4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recv_orig_dst() {
+        use socket2::{Domain, Protocol, Socket, Type};
+        use tokio::{io::Interest, net::UdpSocket};
+
+        // no IP_TRANSPARENT, which needs privileges, the local address is
+        // told all the same
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        set_option(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let local: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&local.into()).unwrap();
+        let socket = UdpSocket::from_std(socket.into()).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", addr).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, src, dst) = socket
+            .async_io(Interest::READABLE, || recv_from_orig_dst(&socket, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(dst, addr);
+    }
+}
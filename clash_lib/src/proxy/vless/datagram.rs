@@ -0,0 +1,252 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::debug;
+
+use crate::{
+    proxy::{datagram::UdpPacket, AnyStream},
+    session::SocksAddr,
+};
+
+use super::{put_address_port, read_address_port};
+
+const STATUS_NEW: u8 = 1;
+const STATUS_KEEP: u8 = 2;
+const STATUS_END: u8 = 3;
+const STATUS_KEEP_ALIVE: u8 = 4;
+
+const OPTION_DATA: u8 = 1;
+
+const NETWORK_UDP: u8 = 2;
+
+/// The UDP session of a VLESS connection, carried by XUDP so that one
+/// connection reaches any destination.
+///
+/// ```text
+/// frame: | metadata length u16 | metadata | data length u16 | data |
+/// metadata: | session id u16 | status u8 | option u8 |
+///           | network u8 | port u16 | address type u8 | address |
+/// ```
+///
+/// The first frame opens the session (`New`), the next ones keep it
+/// (`Keep`) and name the destination of their packet. The server names the
+/// source of the packets it sends back the same way.
+pub struct OutboundDatagramVless {
+    inner: Framed<AnyStream, XudpCodec>,
+    /// the source of the packets the server sends without one
+    remote_addr: SocksAddr,
+}
+
+impl OutboundDatagramVless {
+    pub fn new(inner: AnyStream, remote_addr: SocksAddr) -> Self {
+        Self {
+            inner: Framed::new(inner, XudpCodec::default()),
+            remote_addr,
+        }
+    }
+}
+
+impl Stream for OutboundDatagramVless {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.inner.poll_next_unpin(cx)) {
+            Some(Ok((src, data))) => {
+                if let Some(src) = src {
+                    self.remote_addr = src;
+                }
+                Poll::Ready(Some(UdpPacket {
+                    data,
+                    src_addr: self.remote_addr.clone(),
+                    dst_addr: SocksAddr::any_ipv4(),
+                }))
+            }
+            Some(Err(e)) => {
+                debug!("failed to read vless packet: {}", e);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramVless {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin((item.dst_addr, item.data))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+/// Frames the packets of a single XUDP session, whose id is 0.
+#[derive(Default)]
+pub struct XudpCodec {
+    opened: bool,
+}
+
+impl Encoder<(SocksAddr, Vec<u8>)> for XudpCodec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        (dst, data): (SocksAddr, Vec<u8>),
+        buf: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let len = u16::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "vless packet too large"))?;
+
+        let mut meta = BytesMut::new();
+        meta.put_u16(0);
+        meta.put_u8(if self.opened { STATUS_KEEP } else { STATUS_NEW });
+        meta.put_u8(OPTION_DATA);
+        meta.put_u8(NETWORK_UDP);
+        put_address_port(&mut meta, &dst);
+        self.opened = true;
+
+        buf.put_u16(meta.len() as u16);
+        buf.put_slice(&meta);
+        buf.put_u16(len);
+        buf.put_slice(&data);
+        Ok(())
+    }
+}
+
+impl Decoder for XudpCodec {
+    /// the source of the packet if the frame names it, and the packet
+    type Item = (Option<SocksAddr>, Vec<u8>);
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            let meta_len = u16::from_be_bytes([src[0], src[1]]) as usize;
+            if meta_len < 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid xudp metadata length {}", meta_len),
+                ));
+            }
+            let mut frame_len = 2 + meta_len;
+            if src.len() < frame_len {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
+            }
+            let (status, option) = (src[4], src[5]);
+            if option & OPTION_DATA != 0 {
+                if src.len() < frame_len + 2 {
+                    return Ok(None);
+                }
+                frame_len += 2 + u16::from_be_bytes([src[frame_len], src[frame_len + 1]]) as usize;
+                if src.len() < frame_len {
+                    src.reserve(frame_len - src.len());
+                    return Ok(None);
+                }
+            }
+
+            let mut frame = src.split_to(frame_len);
+            frame.advance(2);
+            let mut meta = frame.split_to(meta_len);
+            meta.advance(4);
+
+            match status {
+                STATUS_NEW | STATUS_KEEP => {}
+                STATUS_KEEP_ALIVE => continue,
+                STATUS_END => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "xudp session ended by the server",
+                    ))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown xudp status {}", status),
+                    ))
+                }
+            }
+            if option & OPTION_DATA == 0 {
+                continue;
+            }
+
+            // the rest of a `New` frame is the global id, ignored
+            let src_addr = if meta.first() == Some(&NETWORK_UDP) {
+                meta.advance(1);
+                Some(read_address_port(&mut meta)?)
+            } else {
+                None
+            };
+            frame.advance(2);
+            return Ok(Some((src_addr, frame.to_vec())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::XudpCodec;
+    use crate::session::SocksAddr;
+
+    #[test]
+    fn test_codec_round_trip() {
+        let a = SocksAddr::Ip("1.2.3.4:53".parse().unwrap());
+        let b = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        let mut codec = XudpCodec::default();
+        let mut buf = BytesMut::new();
+        codec
+            .encode((a.clone(), b"hello".to_vec()), &mut buf)
+            .unwrap();
+        assert_eq!(
+            &buf[..],
+            &[0, 12, 0, 0, 1, 1, 2, 0, 53, 1, 1, 2, 3, 4, 0, 5, b'h', b'e', b'l', b'l', b'o']
+        );
+        codec.encode((b.clone(), vec![]), &mut buf).unwrap();
+        // a keep alive in between
+        buf.extend_from_slice(&[0, 4, 0, 0, 4, 0]);
+        // data without an address
+        buf.extend_from_slice(&[0, 4, 0, 0, 2, 1, 0, 1, b'!']);
+
+        let mut partial = buf.split_to(20);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some((Some(a), b"hello".to_vec()))
+        );
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some((Some(b), vec![])));
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some((None, b"!".to_vec()))
+        );
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+
+        partial.extend_from_slice(&[0, 4, 0, 0, 3, 0]);
+        assert!(codec.decode(&mut partial).is_err());
+        assert!(codec
+            .decode(&mut BytesMut::from(&[0u8, 2, 0, 0][..]))
+            .is_err());
+    }
+}
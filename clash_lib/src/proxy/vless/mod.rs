@@ -0,0 +1,469 @@
+//! VLESS, with the XTLS Vision flow
+//!
+//! ```text
+//! request:  | version u8 | uuid [16] | addons length u8 | addons |
+//!           | command u8 | port u16 | address type u8 | address |
+//! response: | version u8 | addons length u8 | addons |
+//! ```
+//!
+//! The addons are a protobuf message whose first field is the flow. The UDP
+//! sessions are XUDP ones, see `datagram`.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::TryFutureExt;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+            ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::new_io_error,
+    session::{Session, SocksAddr},
+};
+
+use self::{datagram::OutboundDatagramVless, stream::VlessStream};
+
+use super::{
+    options::{GrpcOption, WsOption},
+    transport::{self, tls::TlsEndpoint, TLSOptions},
+    utils::{new_tcp_stream, resolve_destination, RemoteConnector},
+    AnyOutboundHandler, AnyStream, CommonOption, ConnectorType, OutboundHandler, OutboundType,
+};
+
+mod datagram;
+mod stream;
+mod vision;
+
+pub const VISION_FLOW: &str = "xtls-rprx-vision";
+
+const VERSION: u8 = 0;
+
+const CMD_TCP: u8 = 1;
+const CMD_MUX: u8 = 3;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 2;
+const ATYP_IPV6: u8 = 3;
+
+pub enum Transport {
+    Ws(WsOption),
+    Grpc(GrpcOption),
+}
+
+pub struct HandlerOptions {
+    pub name: String,
+    pub common_opts: CommonOption,
+    pub server: String,
+    pub port: u16,
+    pub uuid: Uuid,
+    /// the TCP connections use the Vision flow, which requires TLS
+    pub vision: bool,
+    pub udp: bool,
+    pub remote_dns_resolve: bool,
+    pub transport: Option<Transport>,
+    pub tls: Option<TLSOptions>,
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+}
+
+impl Handler {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
+        Arc::new(Self { opts })
+    }
+
+    async fn inner_proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        udp: bool,
+    ) -> io::Result<AnyStream> {
+        // the UDP sessions go without the flow, the servers accept them so
+        if self.opts.vision && !udp {
+            let tls = self
+                .opts
+                .tls
+                .as_ref()
+                .ok_or_else(|| new_io_error("vision requires tls"))?;
+            let header =
+                request_header(&self.opts.uuid, Some(VISION_FLOW), false, &sess.destination);
+            return vision::connect(s, tls, self.opts.uuid, &header).await;
+        }
+
+        let mut s = match self.opts.tls.as_ref() {
+            Some(tls) => transport::tls::wrap_stream(s, tls.clone(), None).await?,
+            None => s,
+        };
+
+        s = match self.opts.transport.as_ref() {
+            Some(Transport::Ws(ws_opts)) => {
+                let ws_builder = transport::WebsocketStreamBuilder::new(
+                    self.opts.server.clone(),
+                    self.opts.port,
+                    ws_opts.path.clone(),
+                    ws_opts.headers.clone(),
+                    None,
+                    ws_opts.max_early_data,
+                    ws_opts.early_data_header_name.clone(),
+                    ws_opts.ping_interval,
+                );
+                ws_builder.proxy_stream(s).await?
+            }
+            Some(Transport::Grpc(grpc_opts)) => {
+                let grpc_builder = transport::GrpcStreamBuilder::new(
+                    grpc_opts.host.clone(),
+                    grpc_opts
+                        .service_name
+                        .to_owned()
+                        .try_into()
+                        .expect("invalid gRPC service path"),
+                    grpc_opts.ping_interval,
+                );
+                grpc_builder.proxy_stream(s).await?
+            }
+            None => s,
+        };
+
+        s.write_all(&request_header(
+            &self.opts.uuid,
+            None,
+            udp,
+            &sess.destination,
+        ))
+        .await?;
+        Ok(Box::new(VlessStream::new(s)))
+    }
+
+    async fn proxy_datagram(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let stream = self.inner_proxy_stream(s, sess, true).await?;
+        let d = OutboundDatagramVless::new(stream, sess.destination.clone());
+
+        let chained = ChainedDatagramWrapper::new(d);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+}
+
+/// Encode the request header, with the flow in the addons if any. UDP goes
+/// over XUDP, whose request is the mux command without an address.
+fn request_header(uuid: &Uuid, flow: Option<&str>, udp: bool, dst: &SocksAddr) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put_u8(VERSION);
+    buf.put_slice(uuid.as_bytes());
+    match flow {
+        Some(flow) => {
+            // field 1, wire type 2 (length delimited)
+            buf.put_u8(2 + flow.len() as u8);
+            buf.put_u8(0x0a);
+            buf.put_u8(flow.len() as u8);
+            buf.put_slice(flow.as_bytes());
+        }
+        None => buf.put_u8(0),
+    }
+    if udp {
+        buf.put_u8(CMD_MUX);
+    } else {
+        buf.put_u8(CMD_TCP);
+        put_address_port(&mut buf, dst);
+    }
+    buf
+}
+
+/// `| port u16 | address type u8 | address |`, as in the request header.
+fn put_address_port(buf: &mut BytesMut, addr: &SocksAddr) {
+    buf.put_u16(addr.port());
+    match addr {
+        SocksAddr::Ip(std::net::SocketAddr::V4(addr)) => {
+            buf.put_u8(ATYP_IPV4);
+            buf.put_slice(&addr.ip().octets());
+        }
+        SocksAddr::Ip(std::net::SocketAddr::V6(addr)) => {
+            buf.put_u8(ATYP_IPV6);
+            buf.put_slice(&addr.ip().octets());
+        }
+        SocksAddr::Domain(domain, _) => {
+            buf.put_u8(ATYP_DOMAIN);
+            buf.put_u8(domain.len() as u8);
+            buf.put_slice(domain.as_bytes());
+        }
+    }
+}
+
+fn read_address_port(buf: &mut BytesMut) -> io::Result<SocksAddr> {
+    let short = || io::Error::new(io::ErrorKind::InvalidData, "vless address too short");
+    if buf.len() < 4 {
+        return Err(short());
+    }
+    let port = buf.get_u16();
+    match buf.get_u8() {
+        ATYP_IPV4 if buf.len() >= 4 => {
+            let mut ip = [0u8; 4];
+            buf.copy_to_slice(&mut ip);
+            Ok(SocksAddr::from((Ipv4Addr::from(ip), port)))
+        }
+        ATYP_IPV6 if buf.len() >= 16 => {
+            let mut ip = [0u8; 16];
+            buf.copy_to_slice(&mut ip);
+            Ok(SocksAddr::from((Ipv6Addr::from(ip), port)))
+        }
+        ATYP_DOMAIN if buf.first().is_some_and(|x| buf.len() > *x as usize) => {
+            let len = buf.get_u8() as usize;
+            let domain = String::from_utf8_lossy(&buf.split_to(len)).into_owned();
+            Ok(SocksAddr::Domain(domain, port))
+        }
+        ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN => Err(short()),
+        x => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown vless address type {}", x),
+        )),
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::Vless
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.opts.udp
+    }
+
+    fn remote_dns_resolve(&self) -> bool {
+        self.opts.remote_dns_resolve
+    }
+
+    fn tls_endpoint(&self) -> Option<TlsEndpoint> {
+        Some(TlsEndpoint {
+            server: self.opts.server.clone(),
+            port: self.opts.port,
+            iface: self.opts.common_opts.iface.clone(),
+            tls: self.opts.tls.clone()?,
+        })
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let sess = &resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
+        debug!("Connecting to {} via VLESS", sess);
+        let stream = new_tcp_stream(
+            resolver,
+            self.opts.server.as_str(),
+            self.opts.port,
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .map_err(|x| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "dial outbound {}:{}: {}",
+                    self.opts.server, self.opts.port, x
+                ),
+            )
+        })
+        .await?;
+
+        let s = self.inner_proxy_stream(stream, sess, false).await?;
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let stream = new_tcp_stream(
+            resolver,
+            self.opts.server.as_str(),
+            self.opts.port,
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .map_err(|x| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "dial outbound {}:{}: {}",
+                    self.opts.server, self.opts.port, x
+                ),
+            )
+        })
+        .await?;
+
+        self.proxy_datagram(stream, sess).await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::All
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        let sess = &resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
+        let stream = connector
+            .connect_stream(
+                resolver,
+                self.opts.server.as_str(),
+                self.opts.port,
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await?;
+
+        let s = self.inner_proxy_stream(stream, sess, false).await?;
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let stream = connector
+            .connect_stream(
+                resolver,
+                self.opts.server.as_str(),
+                self.opts.port,
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await?;
+
+        self.proxy_datagram(stream, sess).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{request_header, VISION_FLOW};
+    use crate::session::SocksAddr;
+
+    #[test]
+    fn test_request_header() {
+        let uuid = Uuid::parse_str("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap();
+
+        let dst = SocksAddr::Domain("example.com".to_owned(), 443);
+        let header = request_header(&uuid, Some(VISION_FLOW), false, &dst);
+        let mut expected = vec![0];
+        expected.extend_from_slice(uuid.as_bytes());
+        expected.extend_from_slice(&[18, 0x0a, 16]);
+        expected.extend_from_slice(VISION_FLOW.as_bytes());
+        expected.extend_from_slice(&[1, 0x01, 0xbb, 2, 11]);
+        expected.extend_from_slice(b"example.com");
+        assert_eq!(&header[..], &expected[..]);
+
+        let dst = SocksAddr::Ip("1.2.3.4:53".parse().unwrap());
+        let header = request_header(&uuid, None, true, &dst);
+        assert_eq!(&header[17..], &[0, 3]);
+    }
+
+    #[cfg(not(ci))]
+    async fn get_reality_runner(
+    ) -> anyhow::Result<crate::proxy::utils::test_utils::docker_runner::DockerTestRunner> {
+        use crate::proxy::utils::test_utils::{
+            config_helper::test_config_base_dir, consts::IMAGE_XRAY,
+            docker_runner::DockerTestRunnerBuilder,
+        };
+
+        let test_config_dir = test_config_base_dir();
+        let conf = test_config_dir.join("vless-reality.json");
+        let cert = test_config_dir.join("example.org.pem");
+        let key = test_config_dir.join("example.org-key.pem");
+
+        DockerTestRunnerBuilder::new()
+            .image(IMAGE_XRAY)
+            .mounts(&[
+                (conf.to_str().unwrap(), "/etc/xray/config.json"),
+                (cert.to_str().unwrap(), "/etc/ssl/v2ray/fullchain.pem"),
+                (key.to_str().unwrap(), "/etc/ssl/v2ray/privkey.pem"),
+            ])
+            .build()
+            .await
+    }
+
+    #[cfg(not(ci))]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_vless_reality() -> anyhow::Result<()> {
+        use super::{Handler, HandlerOptions};
+        use crate::proxy::{
+            transport::{
+                utls::{Fingerprint, RealityOptions},
+                TLSOptions,
+            },
+            utils::test_utils::{run_test_suites_and_cleanup, Suite},
+        };
+
+        let opts = HandlerOptions {
+            name: "test-vless-reality".to_owned(),
+            common_opts: Default::default(),
+            server: "127.0.0.1".to_owned(),
+            port: 10002,
+            uuid: Uuid::parse_str("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap(),
+            vision: true,
+            udp: true,
+            remote_dns_resolve: true,
+            transport: None,
+            tls: Some(TLSOptions {
+                // the certificate is the one of REALITY, not checked against
+                // the roots
+                skip_cert_verify: false,
+                sni: "example.org".to_owned(),
+                alpn: None,
+                cert_store: Default::default(),
+                fingerprint: Some(Fingerprint::Chrome),
+                reality: Some(
+                    RealityOptions::new(
+                        "Tnct1iynOqQeQIhf0zS4oTURvaIobLX7troSjmPbBVI",
+                        Some("0123456789abcdef"),
+                    )
+                    .unwrap(),
+                ),
+            }),
+        };
+        let handler = Handler::new(opts);
+        run_test_suites_and_cleanup(handler, get_reality_runner().await?, Suite::all()).await
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::AnyStream;
+
+use super::VERSION;
+
+/// Strips the response header off the first bytes the server sends.
+#[derive(Debug, Default)]
+pub struct ResponseHeader {
+    /// the bytes of the header left to skip, once its length is known
+    remaining: Option<usize>,
+    /// the version is checked, the addons length is next
+    version_read: bool,
+}
+
+impl ResponseHeader {
+    pub fn done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    /// Consume the header bytes at the start of `buf`, and return how many.
+    pub fn strip(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() && !self.done() {
+            match self.remaining.as_mut() {
+                Some(remaining) => {
+                    let skip = (*remaining).min(buf.len() - n);
+                    *remaining -= skip;
+                    n += skip;
+                }
+                None if !self.version_read => {
+                    if buf[n] != VERSION {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("unexpected vless response version: {}", buf[n]),
+                        ));
+                    }
+                    self.version_read = true;
+                    n += 1;
+                }
+                None => {
+                    self.remaining = Some(buf[n] as usize);
+                    n += 1;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[derive(Debug)]
+pub struct VlessStream {
+    inner: AnyStream,
+    header: ResponseHeader,
+}
+
+impl VlessStream {
+    pub fn new(inner: AnyStream) -> Self {
+        Self {
+            inner,
+            header: ResponseHeader::default(),
+        }
+    }
+}
+
+impl AsyncRead for VlessStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.header.done() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+
+            // read into the caller's buffer and take the header out of it
+            let before = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            let read = buf.filled().len() - before;
+            if read == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let n = this.header.strip(&buf.filled()[before..])?;
+            buf.filled_mut()[before..].copy_within(n.., 0);
+            buf.set_filled(before + read - n);
+            if read > n {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VlessStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
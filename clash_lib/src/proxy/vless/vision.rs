@@ -0,0 +1,712 @@
+//! The XTLS Vision flow
+//!
+//! The proxied connection is often TLS itself, and the lengths of its
+//! handshake records seen through the outer TLS give it away. Vision pads
+//! the first packets in both directions, and once the inner TLS 1.3
+//! handshake is over the inner records, encrypted already, are copied as
+//! they are on the underlying connection instead of being encrypted again.
+//!
+//! ```text
+//! frame: | uuid [16], the first frame only | command u8 |
+//!        | content length u16 | padding length u16 | content | padding |
+//! ```
+//!
+//! The outer TLS is driven here record by record, so that nothing past the
+//! switch to the direct copy is read into the TLS session. It is rustls, or
+//! the `utls` session for the fingerprints and REALITY.
+
+use std::{
+    fmt::Debug,
+    future::poll_fn,
+    io::{self, Read, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use rand::Rng;
+use rustls::{ClientConnection, ProtocolVersion, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::proxy::{
+    transport::{tls::client_config, utls::ClientSession, TLSOptions},
+    AnyStream,
+};
+
+use super::stream::ResponseHeader;
+
+const CMD_CONTINUE: u8 = 0;
+const CMD_END: u8 = 1;
+const CMD_DIRECT: u8 = 2;
+
+/// the frames fit a buffer of this size
+const FRAME_SIZE: usize = 8192;
+/// uuid, command and lengths
+const FRAME_HEADER_LEN: usize = 16 + 5;
+const MAX_CONTENT_LEN: usize = FRAME_SIZE - FRAME_HEADER_LEN;
+
+/// how many packets are looked at for the inner TLS handshake
+const PACKETS_TO_FILTER: i32 = 8;
+
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const TLS_CLIENT_HANDSHAKE_START: [u8; 2] = [0x16, 0x03];
+const TLS_SERVER_HANDSHAKE_START: [u8; 3] = [0x16, 0x03, 0x03];
+const TLS_APPLICATION_DATA_START: [u8; 3] = [0x17, 0x03, 0x03];
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+/// the supported_versions extension of a TLS 1.3 server hello
+const TLS13_SUPPORTED_VERSIONS: [u8; 6] = [0x00, 0x2b, 0x00, 0x02, 0x03, 0x04];
+const TLS_AES_128_CCM_8_SHA256: u16 = 0x1305;
+
+/// Do the outer TLS handshake and send the request header.
+pub async fn connect(
+    mut io: AnyStream,
+    tls: &TLSOptions,
+    uuid: Uuid,
+    header: &[u8],
+) -> io::Result<AnyStream> {
+    let server_name = ServerName::try_from(tls.sni.as_str()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid server name: {}", tls.sni),
+        )
+    })?;
+    let mut conn: Box<dyn TlsSession> = if tls.fingerprint.is_some() || tls.reality.is_some() {
+        Box::new(ClientSession::new(tls)?)
+    } else {
+        Box::new(
+            ClientConnection::new(Arc::new(client_config(tls)?), server_name)
+                .map_err(|x| io::Error::new(io::ErrorKind::Other, x))?,
+        )
+    };
+
+    let mut records = RecordReader::default();
+    let mut out = Vec::new();
+    while conn.is_handshaking() {
+        if conn.wants_write() {
+            conn.write_tls(&mut out)?;
+            io.write_all(&out).await?;
+            out.clear();
+            continue;
+        }
+        if !poll_fn(|cx| records.poll_fill(cx, &mut io)).await? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        conn.feed(records.take())?;
+    }
+    if !conn.is_tls13() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "vision requires TLS 1.3 to the server",
+        ));
+    }
+
+    conn.write_all(header)?;
+    while conn.wants_write() {
+        conn.write_tls(&mut out)?;
+    }
+    io.write_all(&out).await?;
+
+    Ok(Box::new(VisionStream {
+        io,
+        conn,
+        records,
+        out: BytesMut::new(),
+        plain: BytesMut::new(),
+        header: ResponseHeader::default(),
+        traffic: TrafficState::default(),
+        uuid,
+        uuid_written: false,
+        unpadding: Unpadding::new(uuid),
+        read_padding: true,
+        read_direct: false,
+        write_padding: true,
+        write_direct: false,
+    }))
+}
+
+/// The outer TLS session, fed a whole record at a time.
+trait TlsSession: Debug + Send + Sync {
+    fn is_handshaking(&self) -> bool;
+    fn wants_write(&self) -> bool;
+    fn write_tls(&mut self, out: &mut dyn Write) -> io::Result<()>;
+    fn feed(&mut self, record: &[u8]) -> io::Result<()>;
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn send_close_notify(&mut self);
+    fn is_tls13(&self) -> bool;
+}
+
+impl TlsSession for ClientConnection {
+    fn is_handshaking(&self) -> bool {
+        rustls::CommonState::is_handshaking(self)
+    }
+
+    fn wants_write(&self) -> bool {
+        rustls::CommonState::wants_write(self)
+    }
+
+    fn write_tls(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        rustls::ConnectionCommon::write_tls(self, out).map(|_| ())
+    }
+
+    fn feed(&mut self, mut record: &[u8]) -> io::Result<()> {
+        while !record.is_empty() {
+            self.read_tls(&mut record)?;
+        }
+        self.process_new_packets()
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader().read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer().write_all(buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        rustls::CommonState::send_close_notify(self)
+    }
+
+    fn is_tls13(&self) -> bool {
+        self.protocol_version() == Some(ProtocolVersion::TLSv1_3)
+    }
+}
+
+impl TlsSession for ClientSession {
+    fn is_handshaking(&self) -> bool {
+        ClientSession::is_handshaking(self)
+    }
+
+    fn wants_write(&self) -> bool {
+        ClientSession::wants_write(self)
+    }
+
+    fn write_tls(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.take_tls())
+    }
+
+    fn feed(&mut self, record: &[u8]) -> io::Result<()> {
+        ClientSession::feed(self, record)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        ClientSession::read(self, buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(16 * 1024);
+        ClientSession::write_all(self, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        ClientSession::write_all(self, buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        ClientSession::send_close_notify(self)
+    }
+
+    /// the session does TLS 1.3 only
+    fn is_tls13(&self) -> bool {
+        true
+    }
+}
+
+/// Reads the TLS records one at a time, never past the current one.
+#[derive(Debug, Default)]
+struct RecordReader {
+    buf: Vec<u8>,
+    filled: usize,
+}
+
+impl RecordReader {
+    fn wanted(&self) -> usize {
+        if self.filled < TLS_RECORD_HEADER_LEN {
+            TLS_RECORD_HEADER_LEN
+        } else {
+            TLS_RECORD_HEADER_LEN + u16::from_be_bytes([self.buf[3], self.buf[4]]) as usize
+        }
+    }
+
+    /// Read until a whole record is buffered, false on EOF before any.
+    fn poll_fill(&mut self, cx: &mut Context<'_>, io: &mut AnyStream) -> Poll<io::Result<bool>> {
+        loop {
+            let wanted = self.wanted();
+            if self.filled >= TLS_RECORD_HEADER_LEN && self.filled == wanted {
+                return Poll::Ready(Ok(true));
+            }
+            if self.buf.len() < wanted {
+                self.buf.resize(wanted, 0);
+            }
+
+            let mut rb = ReadBuf::new(&mut self.buf[self.filled..wanted]);
+            ready!(Pin::new(&mut *io).poll_read(cx, &mut rb))?;
+            let n = rb.filled().len();
+            if n == 0 {
+                return if self.filled == 0 {
+                    Poll::Ready(Ok(false))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            self.filled += n;
+        }
+    }
+
+    fn take(&mut self) -> &[u8] {
+        let n = self.filled;
+        self.filled = 0;
+        &self.buf[..n]
+    }
+}
+
+/// What is learnt of the inner TLS from its first packets.
+#[derive(Debug)]
+struct TrafficState {
+    packets_to_filter: i32,
+    is_tls: bool,
+    is_tls12_or_above: bool,
+    /// the inner TLS is 1.3 with a cipher the direct copy works with
+    enable_xtls: bool,
+    remaining_server_hello: i32,
+    cipher: u16,
+}
+
+impl Default for TrafficState {
+    fn default() -> Self {
+        Self {
+            packets_to_filter: PACKETS_TO_FILTER,
+            is_tls: false,
+            is_tls12_or_above: false,
+            enable_xtls: false,
+            remaining_server_hello: -1,
+            cipher: 0,
+        }
+    }
+}
+
+impl TrafficState {
+    /// Look for the inner hellos in a packet.
+    fn filter(&mut self, b: &[u8]) {
+        self.packets_to_filter -= 1;
+        if b.len() >= 6 {
+            if b[..3] == TLS_SERVER_HANDSHAKE_START && b[5] == TLS_HANDSHAKE_SERVER_HELLO {
+                self.remaining_server_hello = i32::from(u16::from_be_bytes([b[3], b[4]])) + 5;
+                self.is_tls12_or_above = true;
+                self.is_tls = true;
+                if b.len() >= 79 && self.remaining_server_hello >= 79 {
+                    let session_id_len = b[43] as usize;
+                    let at = 43 + session_id_len + 1;
+                    if b.len() >= at + 2 {
+                        self.cipher = u16::from_be_bytes([b[at], b[at + 1]]);
+                    }
+                }
+            } else if b[..2] == TLS_CLIENT_HANDSHAKE_START && b[5] == TLS_HANDSHAKE_CLIENT_HELLO {
+                self.is_tls = true;
+            }
+        }
+
+        if self.remaining_server_hello > 0 {
+            let end = (self.remaining_server_hello as usize).min(b.len());
+            self.remaining_server_hello -= b.len() as i32;
+            if b[..end]
+                .windows(TLS13_SUPPORTED_VERSIONS.len())
+                .any(|x| x == TLS13_SUPPORTED_VERSIONS)
+            {
+                self.enable_xtls = (0x1301..=0x1305).contains(&self.cipher)
+                    && self.cipher != TLS_AES_128_CCM_8_SHA256;
+                self.packets_to_filter = 0;
+            } else if self.remaining_server_hello <= 0 {
+                self.packets_to_filter = 0;
+            }
+        }
+    }
+}
+
+/// Takes the frames apart, across the reads.
+#[derive(Debug)]
+struct Unpadding {
+    uuid: Uuid,
+    /// -1 before the first frame of a padded run
+    remaining_command: i32,
+    remaining_content: i32,
+    remaining_padding: i32,
+    current_command: u8,
+}
+
+impl Unpadding {
+    fn new(uuid: Uuid) -> Self {
+        Self {
+            uuid,
+            remaining_command: -1,
+            remaining_content: -1,
+            remaining_padding: -1,
+            current_command: CMD_CONTINUE,
+        }
+    }
+
+    fn unpad(&mut self, mut b: &[u8], out: &mut BytesMut) {
+        if self.remaining_command == -1
+            && self.remaining_content == -1
+            && self.remaining_padding == -1
+        {
+            if b.len() >= FRAME_HEADER_LEN && b[..16] == self.uuid.as_bytes()[..] {
+                b.advance(16);
+                self.remaining_command = 5;
+            } else {
+                out.put_slice(b);
+                return;
+            }
+        }
+
+        while !b.is_empty() {
+            if self.remaining_command > 0 {
+                let x = b.get_u8();
+                match self.remaining_command {
+                    5 => self.current_command = x,
+                    4 => self.remaining_content = i32::from(x) << 8,
+                    3 => self.remaining_content |= i32::from(x),
+                    2 => self.remaining_padding = i32::from(x) << 8,
+                    _ => self.remaining_padding |= i32::from(x),
+                }
+                self.remaining_command -= 1;
+            } else if self.remaining_content > 0 {
+                let n = (self.remaining_content as usize).min(b.len());
+                out.put_slice(&b[..n]);
+                b.advance(n);
+                self.remaining_content -= n as i32;
+            } else {
+                let n = (self.remaining_padding.max(0) as usize).min(b.len());
+                b.advance(n);
+                self.remaining_padding -= n as i32;
+            }
+
+            if self.remaining_command <= 0
+                && self.remaining_content <= 0
+                && self.remaining_padding <= 0
+            {
+                if self.current_command == CMD_CONTINUE {
+                    self.remaining_command = 5;
+                } else {
+                    self.remaining_command = -1;
+                    self.remaining_content = -1;
+                    self.remaining_padding = -1;
+                    out.put_slice(b);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VisionStream {
+    io: AnyStream,
+    conn: Box<dyn TlsSession>,
+    records: RecordReader,
+    /// the TLS bytes to write out
+    out: BytesMut,
+    /// the payload read, for the caller
+    plain: BytesMut,
+
+    header: ResponseHeader,
+    traffic: TrafficState,
+    uuid: Uuid,
+    uuid_written: bool,
+    unpadding: Unpadding,
+
+    read_padding: bool,
+    read_direct: bool,
+    write_padding: bool,
+    write_direct: bool,
+}
+
+impl VisionStream {
+    /// Take the TLS records the session has to send.
+    fn queue_tls(&mut self) -> io::Result<()> {
+        let mut w = (&mut self.out).writer();
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut w)?;
+        }
+        Ok(())
+    }
+
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Handle the plaintext of a record from the server.
+    fn on_plaintext(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if !self.header.done() {
+            let n = self.header.strip(data)?;
+            data = &data[n..];
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let mut unpadded = BytesMut::new();
+        if self.read_padding || self.traffic.packets_to_filter > 0 {
+            self.unpadding.unpad(data, &mut unpadded);
+            let u = &self.unpadding;
+            if u.remaining_content > 0
+                || u.remaining_padding > 0
+                || u.current_command == CMD_CONTINUE
+            {
+                self.read_padding = true;
+            } else if u.current_command == CMD_END {
+                self.read_padding = false;
+            } else if u.current_command == CMD_DIRECT {
+                self.read_padding = false;
+                self.read_direct = true;
+            } else {
+                debug!("unknown vision command: {}", u.current_command);
+            }
+        } else {
+            unpadded.put_slice(data);
+        }
+
+        if self.traffic.packets_to_filter > 0 {
+            self.traffic.filter(&unpadded);
+        }
+        self.plain.unsplit(unpadded);
+        Ok(())
+    }
+
+    /// Frame the content, with the padding.
+    fn pad(&mut self, content: &[u8], command: u8, long_padding: bool) -> BytesMut {
+        let mut rng = rand::thread_rng();
+        let mut padding_len = if content.len() < 900 && long_padding {
+            rng.gen_range(0..500) + 900 - content.len()
+        } else {
+            rng.gen_range(0..256)
+        };
+        padding_len = padding_len.min(MAX_CONTENT_LEN - content.len());
+
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + content.len() + padding_len);
+        if !self.uuid_written {
+            frame.put_slice(self.uuid.as_bytes());
+            self.uuid_written = true;
+        }
+        frame.put_u8(command);
+        frame.put_u16(content.len() as u16);
+        frame.put_u16(padding_len as u16);
+        frame.put_slice(content);
+        frame.put_bytes(0, padding_len);
+        frame
+    }
+
+    /// Pad the content and pick the command, see `VisionWriter` of Xray.
+    fn frame(&mut self, content: &[u8]) -> BytesMut {
+        if self.traffic.packets_to_filter > 0 {
+            self.traffic.filter(content);
+        }
+
+        let end_command = if self.traffic.enable_xtls {
+            CMD_DIRECT
+        } else {
+            CMD_END
+        };
+        if self.traffic.is_tls && content.len() >= 6 && content[..3] == TLS_APPLICATION_DATA_START {
+            // the inner handshake is over
+            self.write_padding = false;
+            self.write_direct = self.traffic.enable_xtls;
+            return self.pad(content, end_command, true);
+        }
+        if !self.traffic.is_tls12_or_above && self.traffic.packets_to_filter <= 1 {
+            // not TLS, or too old to copy directly
+            self.write_padding = false;
+            return self.pad(content, CMD_END, self.traffic.is_tls);
+        }
+        self.pad(content, CMD_CONTINUE, self.traffic.is_tls)
+    }
+}
+
+impl AsyncRead for VisionStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let mut record = [0u8; 16 * 1024];
+        loop {
+            if !this.plain.is_empty() {
+                let n = this.plain.len().min(buf.remaining());
+                buf.put_slice(&this.plain[..n]);
+                this.plain.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            // what's left in the session goes first, even after the switch
+            match this.conn.read(&mut record) {
+                Ok(0) => return Poll::Ready(Ok(())),
+                Ok(n) => {
+                    this.on_plaintext(&record[..n])?;
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            if this.read_direct {
+                return Pin::new(&mut this.io).poll_read(cx, buf);
+            }
+            if !ready!(this.records.poll_fill(cx, &mut this.io))? {
+                return Poll::Ready(Ok(()));
+            }
+            this.conn.feed(this.records.take())?;
+            // e.g. the key updates
+            this.queue_tls()?;
+        }
+    }
+}
+
+impl AsyncWrite for VisionStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // the records queued go before anything else, the raw copy included
+        ready!(this.poll_write_out(cx))?;
+        if this.write_direct {
+            return Pin::new(&mut this.io).poll_write(cx, buf);
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = if this.write_padding {
+            let content = &buf[..buf.len().min(MAX_CONTENT_LEN)];
+            let frame = this.frame(content);
+            this.conn.write_all(&frame)?;
+            content.len()
+        } else {
+            this.conn.write(buf)?
+        };
+        this.queue_tls()?;
+
+        // the data is taken, the records go out now or on the next call
+        if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_out(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_direct {
+            self.conn.send_close_notify();
+            self.queue_tls()?;
+        }
+        ready!(self.poll_write_out(cx))?;
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use uuid::Uuid;
+
+    use super::{TrafficState, Unpadding, CMD_CONTINUE, CMD_DIRECT, CMD_END};
+
+    fn frame(uuid: Option<&Uuid>, command: u8, content: &[u8], padding: usize) -> Vec<u8> {
+        let mut b = vec![];
+        if let Some(uuid) = uuid {
+            b.extend_from_slice(uuid.as_bytes());
+        }
+        b.push(command);
+        b.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        b.extend_from_slice(&(padding as u16).to_be_bytes());
+        b.extend_from_slice(content);
+        b.resize(b.len() + padding, 0xff);
+        b
+    }
+
+    #[test]
+    fn test_unpadding() {
+        let uuid = Uuid::new_v4();
+        let mut u = Unpadding::new(uuid);
+
+        let mut data = frame(Some(&uuid), CMD_CONTINUE, b"hello", 10);
+        data.extend(frame(None, CMD_END, b" world", 3));
+        data.extend_from_slice(b"!");
+
+        // split anywhere past the first frame header
+        let mut out = BytesMut::new();
+        for chunk in data.chunks(23) {
+            u.unpad(chunk, &mut out);
+        }
+        assert_eq!(&out[..], b"hello world!");
+        assert_eq!(u.current_command, CMD_END);
+
+        // the padding is over, the rest passes through
+        out.clear();
+        u.unpad(b"raw", &mut out);
+        assert_eq!(&out[..], b"raw");
+    }
+
+    #[test]
+    fn test_unpadding_direct() {
+        let uuid = Uuid::new_v4();
+        let mut u = Unpadding::new(uuid);
+        let mut out = BytesMut::new();
+        u.unpad(&frame(Some(&uuid), CMD_DIRECT, b"data", 0), &mut out);
+        assert_eq!(&out[..], b"data");
+        assert_eq!(u.current_command, CMD_DIRECT);
+        assert_eq!(u.remaining_command, -1);
+    }
+
+    #[test]
+    fn test_filter_server_hello() {
+        // a TLS 1.3 server hello with TLS_AES_128_GCM_SHA256
+        let mut hello = vec![
+            0x16, 0x03, 0x03, 0x00, 0x5a, 0x02, 0x00, 0x00, 0x56, 0x03, 0x03,
+        ];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.push(32);
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0x13, 0x01, 0x00, 0x00, 0x2e]);
+        hello.extend_from_slice(&[0x00, 0x2b, 0x00, 0x02, 0x03, 0x04]);
+        hello.resize(95, 0);
+
+        let mut t = TrafficState::default();
+        t.filter(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00]);
+        assert!(t.is_tls);
+        assert!(!t.is_tls12_or_above);
+
+        t.filter(&hello);
+        assert!(t.is_tls12_or_above);
+        assert_eq!(t.cipher, 0x1301);
+        assert!(t.enable_xtls);
+        assert_eq!(t.packets_to_filter, 0);
+    }
+}
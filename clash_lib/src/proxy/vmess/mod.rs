@@ -23,7 +23,7 @@ use self::vmess_impl::OutboundDatagramVmess;
 use super::{
     options::{GrpcOption, Http2Option, HttpOption, WsOption},
     transport::{self, Http2Config},
-    utils::{new_tcp_stream, RemoteConnector},
+    utils::{new_tcp_stream, resolve_destination, RemoteConnector},
     AnyOutboundHandler, AnyStream, CommonOption, ConnectorType, OutboundHandler, OutboundType,
 };
 
@@ -44,6 +44,7 @@ pub struct HandlerOptions {
     pub alter_id: u16,
     pub security: String,
     pub udp: bool,
+    pub remote_dns_resolve: bool,
     pub transport: Option<VmessTransport>,
     pub tls: Option<transport::TLSOptions>,
 }
@@ -76,6 +77,7 @@ impl Handler {
                     None,
                     opt.max_early_data,
                     opt.early_data_header_name.clone(),
+                    opt.ping_interval,
                 );
 
                 if let Some(tls_opt) = &self.opts.tls {
@@ -117,6 +119,7 @@ impl Handler {
                         .to_owned()
                         .try_into()
                         .expect("invalid gRPC service path"),
+                    opt.ping_interval,
                 );
                 grpc_builder.proxy_stream(stream).await?
             }
@@ -159,17 +162,35 @@ impl OutboundHandler for Handler {
         self.opts.udp
     }
 
+    fn remote_dns_resolve(&self) -> bool {
+        self.opts.remote_dns_resolve
+    }
+
+    fn tls_endpoint(&self) -> Option<transport::tls::TlsEndpoint> {
+        let mut tls = self.opts.tls.clone()?;
+        if let Some(VmessTransport::H2(_)) = self.opts.transport {
+            tls.alpn = Some(vec!["h2".to_string()]);
+        }
+        Some(transport::tls::TlsEndpoint {
+            server: self.opts.server.clone(),
+            port: self.opts.port,
+            iface: self.opts.common_opts.iface.clone(),
+            tls,
+        })
+    }
+
     async fn connect_stream(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
+        let sess = &resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
         debug!("Connecting to {} via VMess", sess);
         let stream = new_tcp_stream(
             resolver,
             self.opts.server.as_str(),
             self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
@@ -199,7 +220,7 @@ impl OutboundHandler for Handler {
             resolver.clone(),
             self.opts.server.as_str(),
             self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
+            self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
@@ -247,12 +268,13 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
+        let sess = &resolve_destination(sess, self.opts.remote_dns_resolve, &resolver).await?;
         let stream = connector
             .connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
-                self.opts.common_opts.iface.as_ref(),
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 None,
             )
@@ -275,7 +297,7 @@ impl OutboundHandler for Handler {
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
-                self.opts.common_opts.iface.as_ref(),
+                self.opts.common_opts.iface.as_ref().or(sess.iface.as_ref()),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 None,
             )
@@ -338,10 +360,14 @@ mod tests {
             alter_id: 0,
             security: "auto".into(),
             udp: true,
+            remote_dns_resolve: true,
             tls: Some(transport::TLSOptions {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                cert_store: Default::default(),
+                fingerprint: None,
+                reality: None,
             }),
             transport: Some(VmessTransport::Ws(WsOption {
                 path: "".to_owned(),
@@ -351,6 +377,7 @@ mod tests {
                 // ignore the rest by setting max_early_data to 0
                 max_early_data: 0,
                 early_data_header_name: "".to_owned(),
+                ping_interval: None,
             })),
         };
         let handler = Handler::new(opts);
@@ -387,14 +414,19 @@ mod tests {
             alter_id: 0,
             security: "auto".into(),
             udp: true,
+            remote_dns_resolve: true,
             tls: Some(transport::TLSOptions {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                cert_store: Default::default(),
+                fingerprint: None,
+                reality: None,
             }),
             transport: Some(VmessTransport::Grpc(GrpcOption {
                 host: "example.org".to_owned(),
                 service_name: "example!".to_owned(),
+                ping_interval: None,
             })),
         };
         let handler = Handler::new(opts);
@@ -430,10 +462,14 @@ mod tests {
             alter_id: 0,
             security: "auto".into(),
             udp: false,
+            remote_dns_resolve: true,
             tls: Some(transport::TLSOptions {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                cert_store: Default::default(),
+                fingerprint: None,
+                reality: None,
             }),
             transport: Some(VmessTransport::H2(Http2Option {
                 host: vec!["example.org".into()],
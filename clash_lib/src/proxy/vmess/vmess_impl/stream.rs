@@ -1,4 +1,4 @@
-use std::{fmt::Debug, mem::MaybeUninit, pin::Pin, task::Poll, time::SystemTime};
+use std::{fmt::Debug, io::IoSlice, mem::MaybeUninit, pin::Pin, task::Poll, time::SystemTime};
 
 use aes_gcm::Aes128Gcm;
 use bytes::{BufMut, BytesMut};
@@ -12,6 +12,7 @@ use crate::{
     common::{
         crypto::{self, AeadCipherHelper},
         errors::map_io_error,
+        io::gather,
         utils,
     },
     proxy::vmess::vmess_impl::MAX_CHUNK_SIZE,
@@ -481,9 +482,18 @@ where
     S: AsyncWrite + Unpin + Send,
 {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    /// The slices are sealed in one chunk rather than a chunk each.
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
     ) -> Poll<Result<usize, std::io::Error>> {
         loop {
             match self.write_state {
@@ -495,7 +505,10 @@ where
                     }
 
                     let max_payload_size = CHUNK_SIZE - overhead_len;
-                    let consume_len = std::cmp::min(buf.len(), max_payload_size);
+                    let consume_len = std::cmp::min(
+                        bufs.iter().map(|x| x.len()).sum::<usize>(),
+                        max_payload_size,
+                    );
                     let payload_len = consume_len + overhead_len;
 
                     let size_bytes = 2;
@@ -504,7 +517,7 @@ where
 
                     let mut piece2 = this.write_buf.split_off(size_bytes);
 
-                    piece2.put_slice(&buf[..consume_len]);
+                    gather(bufs, consume_len, &mut piece2);
                     if let Some(ref mut cipher) = this.aead_write_cipher {
                         piece2
                             .extend_from_slice(vec![0u8; cipher.security.overhead_len()].as_ref());
@@ -551,6 +564,10 @@ where
         }
     }
 
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -33,7 +33,7 @@ use tracing::debug;
 
 mod device;
 mod events;
-mod keys;
+pub(crate) mod keys;
 mod ports;
 mod stack;
 mod wireguard;
@@ -53,7 +53,8 @@ pub struct HandlerOpts {
     pub mtu: Option<u16>,
     pub udp: bool,
     pub allowed_ips: Option<Vec<String>>,
-    pub reserved_bits: Option<Vec<u8>>,
+    /// the reserved bytes of the messages
+    pub reserved: [u8; 3],
 }
 
 struct Inner {
@@ -78,7 +79,13 @@ impl Handler {
         })
     }
 
-    async fn initialize_inner(&self, resolver: ThreadSafeDNSResolver) -> Result<&Inner, Error> {
+    /// The session setting the tunnel up picks its interface, unless the
+    /// proxy has one.
+    async fn initialize_inner(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Result<&Inner, Error> {
         self.inner
             .get_or_try_init(|| async {
                 let recv_pair = tokio::sync::mpsc::channel(1024);
@@ -127,16 +134,14 @@ impl Handler {
                         source_peer_ipv6: self.opts.ipv6,
                         keepalive_seconds: Some(10),
                         allowed_ips,
-                        reserved_bits: match &self.opts.reserved_bits {
-                            Some(bits) => {
-                                if bits.len() >= 3 {
-                                    [bits[0], bits[1], bits[2]]
-                                } else {
-                                    [0, 0, 0]
-                                }
-                            }
-                            None => [0, 0, 0],
-                        },
+                        reserved_bits: self.opts.reserved,
+                        iface: self
+                            .opts
+                            .common_opts
+                            .iface
+                            .as_ref()
+                            .or(sess.iface.as_ref())
+                            .cloned(),
                     },
                     recv_pair.0,
                     send_pair.1,
@@ -208,6 +213,10 @@ impl OutboundHandler for Handler {
         self.opts.udp
     }
 
+    fn remote_dns_resolve(&self) -> bool {
+        self.opts.remote_dns_resolve && self.opts.dns.as_ref().is_some_and(|x| !x.is_empty())
+    }
+
     /// connect to remote target via TCP
     async fn connect_stream(
         &self,
@@ -215,7 +224,7 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
         let inner = self
-            .initialize_inner(resolver.clone())
+            .initialize_inner(sess, resolver.clone())
             .await
             .map_err(map_io_error)?;
 
@@ -262,11 +271,11 @@ impl OutboundHandler for Handler {
     /// connect to remote target via UDP
     async fn connect_datagram(
         &self,
-        _sess: &Session,
+        sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
         let inner = self
-            .initialize_inner(resolver)
+            .initialize_inner(sess, resolver)
             .await
             .map_err(map_io_error)?;
 
@@ -338,7 +347,7 @@ mod tests {
             mtu: Some(1000),
             udp: true,
             allowed_ips: Some(vec!["0.0.0.0/0".to_owned()]),
-            reserved_bits: None,
+            reserved: [0, 0, 0],
         };
         let handler = Handler::new(opts);
 
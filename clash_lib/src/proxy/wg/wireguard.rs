@@ -23,7 +23,10 @@ use tokio::{
 };
 use tracing::{enabled, error, trace, trace_span, warn, Instrument};
 
-use crate::{proxy::utils::new_udp_socket, Error};
+use crate::{
+    proxy::utils::{new_udp_socket, Interface},
+    Error,
+};
 
 use super::events::PortProtocol;
 
@@ -61,6 +64,8 @@ pub struct Config {
     pub keepalive_seconds: Option<u16>,
     pub allowed_ips: Vec<IpNet>,
     pub reserved_bits: [u8; 3],
+    /// the interface the tunnel goes out of
+    pub iface: Option<Interface>,
 }
 
 impl WireguardTunnel {
@@ -82,7 +87,7 @@ impl WireguardTunnel {
 
         let udp = new_udp_socket(
             None,
-            None,
+            config.iface.as_ref(),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
@@ -7,12 +7,12 @@ use std::{
 
 use crate::proxy::utils::Interface;
 use bytes::{Buf, BufMut};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 use erased_serde::Serialize as ESerialize;
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum SocksAddr {
     Ip(SocketAddr),
     Domain(String, u16),
@@ -342,22 +342,40 @@ impl TryFrom<SocksAddr> for SocketAddr {
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize)]
 pub enum Network {
     Tcp,
     Udp,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Type {
     Http,
     HttpConnect,
+    Socks4,
     Socks5,
+    Trojan,
     Tun,
+    TProxy,
 
     Ignore,
 }
 
+impl Type {
+    /// The inbound as named in `listener-rules`, the mixed listener being
+    /// http or socks by the protocol spoken.
+    pub fn listener(&self) -> Option<&'static str> {
+        match self {
+            Type::Http | Type::HttpConnect => Some("http"),
+            Type::Socks4 | Type::Socks5 => Some("socks"),
+            Type::Trojan => Some("trojan"),
+            Type::Tun => Some("tun"),
+            Type::TProxy => Some("tproxy"),
+            Type::Ignore => None,
+        }
+    }
+}
+
 impl Display for Network {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(match self {
@@ -381,6 +399,34 @@ pub struct Session {
     pub packet_mark: Option<u32>,
     /// The bind interface
     pub iface: Option<Interface>,
+    /// Where the domain destination was resolved, for connection metadata
+    pub dns_resolve_mode: Option<DnsResolveMode>,
+    /// The executable of the local process which opened the connection,
+    /// looked up when a rule matches on it
+    pub process_path: Option<String>,
+    /// The address a domain destination was resolved to, for the IP rules,
+    /// the domain is kept for the others
+    pub resolved_ip: Option<IpAddr>,
+    /// The listener which accepted the connection
+    pub listener: Option<ListenerId>,
+}
+
+/// A listener as keyed in `listener-rules`, by its name or its port
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerId {
+    /// http, socks, mixed, trojan or tproxy
+    pub name: &'static str,
+    pub port: u16,
+}
+
+/// Where the domain destination of a proxied connection was resolved
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsResolveMode {
+    /// resolved by us, the proxy server got an IP
+    Local,
+    /// the domain was passed to the proxy server to resolve
+    Remote,
 }
 
 impl Session {
@@ -399,11 +445,24 @@ impl Session {
             Box::new(self.destination.port()) as _,
         );
         rv.insert("host".to_string(), Box::new(self.destination.host()) as _);
+        if let Some(mode) = self.dns_resolve_mode {
+            rv.insert("dnsResolveMode".to_string(), Box::new(mode) as _);
+        }
+        if let Some(path) = &self.process_path {
+            rv.insert("processPath".to_string(), Box::new(path.clone()) as _);
+        }
 
         rv
     }
 }
 
+impl Session {
+    /// The IP of the destination, or the one its domain was resolved to.
+    pub fn destination_ip(&self) -> Option<IpAddr> {
+        self.destination.ip().or(self.resolved_ip)
+    }
+}
+
 impl Default for Session {
     fn default() -> Self {
         Self {
@@ -413,6 +472,10 @@ impl Default for Session {
             destination: SocksAddr::any_ipv4(),
             packet_mark: None,
             iface: None,
+            dns_resolve_mode: None,
+            process_path: None,
+            resolved_ip: None,
+            listener: None,
         }
     }
 }
@@ -435,6 +498,10 @@ impl Debug for Session {
             .field("destination", &self.destination)
             .field("packet_mark", &self.packet_mark)
             .field("iface", &self.iface)
+            .field("dns_resolve_mode", &self.dns_resolve_mode)
+            .field("process_path", &self.process_path)
+            .field("resolved_ip", &self.resolved_ip)
+            .field("listener", &self.listener)
             .finish()
     }
 }
@@ -448,6 +515,10 @@ impl Clone for Session {
             destination: self.destination.clone(),
             packet_mark: self.packet_mark,
             iface: self.iface.as_ref().cloned(),
+            dns_resolve_mode: self.dns_resolve_mode,
+            process_path: self.process_path.clone(),
+            resolved_ip: self.resolved_ip,
+            listener: self.listener,
         }
     }
 }